@@ -0,0 +1,287 @@
+//!
+//! Activity of the users for their profile pages and the dashboards of their teams, kept up to
+//! date from the ref updates. Every update is recorded in the feed of its pusher, and counted
+//! per day as a push, and as a merge when it updates the default branch. Like the contribution
+//! graphs of the code hosting sites, a commit counts for its author once it reaches the default
+//! branch, on the day it was authored. Authors are matched to users by their email, the commits
+//! of unknown authors are not counted.
+//!
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate};
+use serde::Serialize;
+
+use callisto::{mega_user_activity, mega_user_contribution};
+use common::errors::MegaError;
+use common::utils::ZERO_ID;
+use jupiter::context::Context;
+use jupiter::events::{RefUpdateEvent, RefUpdateObserver};
+use venus::hash::SHA1;
+use venus::internal::revwalk::CommitGraph;
+use venus::repo::Repo;
+
+use crate::compare;
+
+const DEFAULT_BRANCH: &str = "refs/heads/main";
+
+/// Feed items returned when the request sets no limit, and the most it can ask for.
+pub const DEFAULT_PAGE_SIZE: u64 = 50;
+pub const MAX_PAGE_SIZE: u64 = 200;
+
+/// Records the activity of every ref update.
+pub struct ActivityRecorder;
+
+#[async_trait]
+impl RefUpdateObserver for ActivityRecorder {
+    fn name(&self) -> &'static str {
+        "user_activity"
+    }
+
+    async fn on_ref_update(
+        &self,
+        context: &Context,
+        event: &RefUpdateEvent,
+    ) -> Result<(), MegaError> {
+        let is_default = event.ref_name == DEFAULT_BRANCH;
+        let authors = if is_default {
+            merged_authors(context, event).await?
+        } else {
+            Vec::new()
+        };
+        let activity = mega_user_activity::Model {
+            id: event.id,
+            user_name: event.pusher.clone(),
+            repo_path: event.repo_path.clone(),
+            ref_name: event.ref_name.clone(),
+            old_id: event.old_id.clone(),
+            new_id: event.new_id.clone(),
+            commit_count: authors.len() as i32,
+            created_at: event.created_at,
+        };
+        let contributions = contributions(
+            event.pusher.as_deref(),
+            event.created_at.date(),
+            is_default,
+            &authors,
+        );
+        context
+            .services
+            .activity_storage
+            .record_activity(activity, contributions)
+            .await?;
+        Ok(())
+    }
+}
+
+/// The users who authored the commits `event` added to the default branch, with the day of
+/// each commit.
+async fn merged_authors(
+    context: &Context,
+    event: &RefUpdateEvent,
+) -> Result<Vec<(String, NaiveDate)>, MegaError> {
+    let Ok(new) = SHA1::from_str(&event.new_id) else {
+        return Ok(Vec::new());
+    };
+    let old: Vec<SHA1> = Some(event.old_id.as_str())
+        .filter(|id| *id != ZERO_ID)
+        .and_then(|id| SHA1::from_str(id).ok())
+        .into_iter()
+        .collect();
+    let repo = context
+        .services
+        .mega_storage
+        .find_git_repo(&event.repo_path)
+        .await?
+        .map(Repo::from)
+        .unwrap_or_else(Repo::empty);
+    let graph = CommitGraph::new(compare::load_history(context, &repo, &[new], &old).await?);
+    let commits: Vec<_> = graph
+        .walk(&[new], &old)
+        .iter()
+        .filter_map(|id| graph.commit(id))
+        .map(|c| (c.author.email.clone(), c.author.timestamp))
+        .collect();
+    if commits.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut emails: Vec<String> = commits.iter().map(|(email, _)| email.clone()).collect();
+    emails.sort();
+    emails.dedup();
+    let users: HashMap<String, String> = context
+        .services
+        .user_storage
+        .find_users_by_email(emails)
+        .await?
+        .into_iter()
+        .filter_map(|user| user.email.map(|email| (email, user.name)))
+        .collect();
+    Ok(commits
+        .into_iter()
+        .filter_map(|(email, timestamp)| {
+            let day = DateTime::from_timestamp(timestamp as i64, 0)?.date_naive();
+            users.get(&email).map(|name| (name.clone(), day))
+        })
+        .collect())
+}
+
+/// The contributions of a ref update pushed by `pusher` on `day`, with the commits `authors`
+/// merged into the default branch.
+fn contributions(
+    pusher: Option<&str>,
+    day: NaiveDate,
+    is_default: bool,
+    authors: &[(String, NaiveDate)],
+) -> Vec<mega_user_contribution::Model> {
+    let mut counts = BTreeMap::new();
+    if let Some(pusher) = pusher {
+        let contribution = count(&mut counts, pusher, day);
+        contribution.pushes += 1;
+        if is_default {
+            contribution.merges += 1;
+        }
+    }
+    for (author, day) in authors {
+        count(&mut counts, author, *day).commits += 1;
+    }
+    counts.into_values().collect()
+}
+
+fn count<'a>(
+    counts: &'a mut BTreeMap<(String, NaiveDate), mega_user_contribution::Model>,
+    user: &str,
+    day: NaiveDate,
+) -> &'a mut mega_user_contribution::Model {
+    counts
+        .entry((user.to_owned(), day))
+        .or_insert_with(|| mega_user_contribution::Model {
+            user_name: user.to_owned(),
+            day,
+            commits: 0,
+            pushes: 0,
+            merges: 0,
+        })
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActivityItem {
+    pub id: i64,
+    pub user: Option<String>,
+    /// The repository, or the directory of the monorepo, the ref was pushed to.
+    pub path: String,
+    pub ref_name: String,
+    pub old_id: String,
+    pub new_id: String,
+    /// The commits merged into the default branch.
+    pub commit_count: i32,
+    pub created_at: i64,
+}
+
+impl From<mega_user_activity::Model> for ActivityItem {
+    fn from(value: mega_user_activity::Model) -> Self {
+        ActivityItem {
+            id: value.id,
+            user: value.user_name,
+            path: value.repo_path,
+            ref_name: value.ref_name,
+            old_id: value.old_id,
+            new_id: value.new_id,
+            commit_count: value.commit_count,
+            created_at: value.created_at.and_utc().timestamp(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Contribution {
+    pub user: String,
+    /// The day as `YYYY-MM-DD`.
+    pub day: String,
+    pub commits: i32,
+    pub pushes: i32,
+    pub merges: i32,
+}
+
+impl From<mega_user_contribution::Model> for Contribution {
+    fn from(value: mega_user_contribution::Model) -> Self {
+        Contribution {
+            user: value.user_name,
+            day: value.day.format("%Y-%m-%d").to_string(),
+            commits: value.commits,
+            pushes: value.pushes,
+            merges: value.merges,
+        }
+    }
+}
+
+/// The feed of `user` before the item `before`, newest first.
+pub async fn user_activities(
+    context: &Context,
+    user: &str,
+    before: Option<i64>,
+    limit: Option<u64>,
+) -> Result<Vec<ActivityItem>, MegaError> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    Ok(context
+        .services
+        .activity_storage
+        .get_activities(user, before, limit)
+        .await?
+        .into_iter()
+        .map(ActivityItem::from)
+        .collect())
+}
+
+/// The contributions of `users` from `since` to `until` included, the days without any are
+/// left out.
+pub async fn contribution_graph(
+    context: &Context,
+    users: Vec<String>,
+    since: NaiveDate,
+    until: NaiveDate,
+) -> Result<Vec<Contribution>, MegaError> {
+    Ok(context
+        .services
+        .activity_storage
+        .get_contributions(users, since, until)
+        .await?
+        .into_iter()
+        .map(Contribution::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::contributions;
+
+    #[test]
+    fn test_contributions() {
+        let day = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let before = NaiveDate::from_ymd_opt(2024, 2, 28).unwrap();
+        let authors = vec![
+            (String::from("alice"), day),
+            (String::from("alice"), day),
+            (String::from("bob"), before),
+        ];
+        let counts: Vec<_> = contributions(Some("alice"), day, true, &authors)
+            .into_iter()
+            .map(|c| (c.user_name, c.day, c.commits, c.pushes, c.merges))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (String::from("alice"), day, 2, 1, 1),
+                (String::from("bob"), before, 1, 0, 0),
+            ]
+        );
+
+        let counts = contributions(Some("bob"), day, false, &[]);
+        assert_eq!(counts.len(), 1);
+        assert_eq!((counts[0].pushes, counts[0].merges), (1, 0));
+        assert!(contributions(None, day, false, &[]).is_empty());
+    }
+}
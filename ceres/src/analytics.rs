@@ -0,0 +1,443 @@
+//!
+//! Code frequency and hotspots of the directories of the monorepo, the data behind the tech
+//! debt dashboards. Every commit reaching the default branch adds, to the week it was authored
+//! in, the lines it changed and its author to each directory it touched, down to the depth of
+//! the `code_analytics_depth` setting, and counts the directories it changed together. Merge
+//! commits are left out, like in `git log --numstat`, and the lines are counted by comparing
+//! the lines of both versions of a file, ignoring their order.
+//!
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Weekday};
+use serde::Serialize;
+
+use callisto::{mega_dir_churn, mega_dir_contributor, mega_dir_coupling};
+use common::errors::MegaError;
+use common::utils::ZERO_ID;
+use jupiter::context::Context;
+use jupiter::events::{RefUpdateEvent, RefUpdateObserver};
+use jupiter::storage::analytics_storage::CommitStats;
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::internal::revwalk::CommitGraph;
+use venus::repo::Repo;
+
+use crate::compare::{self, FileChange};
+
+const DEFAULT_BRANCH: &str = "refs/heads/main";
+
+/// Commits changing more directories are not counted in the coupling, they are mass changes
+/// rather than changes of related code.
+const MAX_COUPLED_DIRS: usize = 20;
+
+/// Directories returned when the request sets no limit, and the most it can ask for.
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 500;
+
+/// Collects the statistics of the commits reaching the default branch, unless the
+/// `code_analytics` setting is off.
+pub struct CodeAnalytics;
+
+#[async_trait]
+impl RefUpdateObserver for CodeAnalytics {
+    fn name(&self) -> &'static str {
+        "code_analytics"
+    }
+
+    async fn on_ref_update(
+        &self,
+        context: &Context,
+        event: &RefUpdateEvent,
+    ) -> Result<(), MegaError> {
+        let settings = context.settings.current();
+        if !settings.code_analytics || event.ref_name != DEFAULT_BRANCH {
+            return Ok(());
+        }
+        let Ok(new) = SHA1::from_str(&event.new_id) else {
+            return Ok(());
+        };
+        let old: Vec<SHA1> = Some(event.old_id.as_str())
+            .filter(|id| *id != ZERO_ID)
+            .and_then(|id| SHA1::from_str(id).ok())
+            .into_iter()
+            .collect();
+        let (repo, base) = match context
+            .services
+            .mega_storage
+            .find_git_repo(&event.repo_path)
+            .await?
+        {
+            Some(model) => {
+                let base = model.repo_path.clone();
+                (Repo::from(model), base)
+            }
+            None => (Repo::empty(), String::from("/")),
+        };
+
+        let graph = CommitGraph::new(compare::load_history(context, &repo, &[new], &old).await?);
+        for id in graph.walk(&[new], &old).into_iter().rev() {
+            let Some(commit) = graph.commit(&id) else {
+                continue;
+            };
+            if commit.parent_commit_ids.len() > 1 {
+                continue;
+            }
+            let parent_tree = commit
+                .parent_commit_ids
+                .first()
+                .and_then(|parent| graph.commit(parent))
+                .map(|parent| parent.tree_id);
+            let changes = compare::diff_trees(context, parent_tree, Some(commit.tree_id)).await?;
+            let files = changed_lines(context, &base, &changes).await?;
+            let stats = commit_stats(commit, &files, settings.code_analytics_depth);
+            context
+                .services
+                .analytics_storage
+                .record_commit(&id.to_plain_str(), stats)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// The absolute path of every changed file with the lines it added and removed. The lines of
+/// binary files and of files kept outside the database are not counted.
+async fn changed_lines(
+    context: &Context,
+    base: &str,
+    changes: &[FileChange],
+) -> Result<Vec<(String, i64, i64)>, MegaError> {
+    let ids: Vec<SHA1> = changes
+        .iter()
+        .flat_map(|change| [change.old_id, change.new_id])
+        .flatten()
+        .collect();
+    let blobs = context
+        .services
+        .mega_storage
+        .get_blob_contents(&ids)
+        .await?;
+    let content = |id: Option<SHA1>| match id {
+        Some(id) => blobs.get(&id).map(Vec::as_slice),
+        None => Some(&[][..]),
+    };
+    Ok(changes
+        .iter()
+        .map(|change| {
+            let (added, removed) = match (content(change.old_id), content(change.new_id)) {
+                (Some(old), Some(new)) => line_changes(old, new),
+                _ => (0, 0),
+            };
+            let path = format!("{}/{}", base.trim_end_matches('/'), change.path);
+            (path, added, removed)
+        })
+        .collect())
+}
+
+/// The lines added to and removed from `old` to get `new`, a binary file has none.
+fn line_changes(old: &[u8], new: &[u8]) -> (i64, i64) {
+    if old.contains(&0) || new.contains(&0) {
+        return (0, 0);
+    }
+    let mut remaining: HashMap<&[u8], i64> = HashMap::new();
+    for line in old.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+        *remaining.entry(line).or_default() += 1;
+    }
+    let mut added = 0;
+    for line in new.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+        match remaining.get_mut(line) {
+            Some(count) if *count > 0 => *count -= 1,
+            _ => added += 1,
+        }
+    }
+    (added, remaining.values().sum())
+}
+
+/// The directory holding `path` and its parents, from `/` down to `depth` segments.
+fn parent_dirs(path: &str, depth: usize) -> Vec<String> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let dirs = segments.len().saturating_sub(1).min(depth);
+    (0..=dirs)
+        .map(|n| format!("/{}", segments[..n].join("/")))
+        .collect()
+}
+
+/// The statistics `commit` adds with the lines of its `files`.
+fn commit_stats(commit: &Commit, files: &[(String, i64, i64)], depth: usize) -> CommitStats {
+    let week = DateTime::from_timestamp(commit.author.timestamp as i64, 0)
+        .map(|time| time.date_naive().week(Weekday::Mon).first_day())
+        .unwrap_or(NaiveDate::MIN);
+    let mut churn: BTreeMap<String, (i64, i64)> = BTreeMap::new();
+    let mut deepest: BTreeSet<String> = BTreeSet::new();
+    for (path, added, removed) in files {
+        let dirs = parent_dirs(path, depth);
+        for dir in &dirs {
+            let lines = churn.entry(dir.clone()).or_default();
+            lines.0 += added;
+            lines.1 += removed;
+        }
+        deepest.extend(dirs.last().cloned());
+    }
+    // a directory is not coupled with its parents
+    let deepest: Vec<String> = deepest
+        .iter()
+        .filter(|dir| {
+            !deepest
+                .iter()
+                .any(|other| other != *dir && other.starts_with(&format!("{}/", dir)))
+        })
+        .filter(|dir| *dir != "/")
+        .cloned()
+        .collect();
+    let mut coupling = Vec::new();
+    if deepest.len() <= MAX_COUPLED_DIRS {
+        for (i, path) in deepest.iter().enumerate() {
+            for coupled_path in &deepest[i + 1..] {
+                coupling.push(mega_dir_coupling::Model {
+                    path: path.clone(),
+                    coupled_path: coupled_path.clone(),
+                    week,
+                    commits: 1,
+                });
+            }
+        }
+    }
+
+    CommitStats {
+        contributors: churn
+            .keys()
+            .map(|path| mega_dir_contributor::Model {
+                path: path.clone(),
+                week,
+                author: commit.author.email.clone(),
+            })
+            .collect(),
+        churn: churn
+            .into_iter()
+            .map(|(path, (added, removed))| mega_dir_churn::Model {
+                path,
+                week,
+                lines_added: added,
+                lines_removed: removed,
+                commits: 1,
+            })
+            .collect(),
+        coupling,
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DirStats {
+    pub path: String,
+    pub lines_added: i64,
+    pub lines_removed: i64,
+    pub commits: i64,
+    pub contributors: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct WeekStats {
+    /// The Monday of the week as `YYYY-MM-DD`.
+    pub week: String,
+    pub lines_added: i64,
+    pub lines_removed: i64,
+    pub commits: i64,
+    pub contributors: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Coupling {
+    pub path: String,
+    pub coupled_path: String,
+    /// The commits changing both directories.
+    pub commits: i64,
+}
+
+/// The directories below `path` with the most lines changed from `since` to `until`.
+pub async fn hotspots(
+    context: &Context,
+    path: &str,
+    since: NaiveDate,
+    until: NaiveDate,
+    limit: Option<usize>,
+) -> Result<Vec<DirStats>, MegaError> {
+    let storage = context.services.analytics_storage.clone();
+    let mut dirs: BTreeMap<String, DirStats> = BTreeMap::new();
+    for churn in storage.get_churn(path, since, until).await? {
+        let stats = dirs.entry(churn.path.clone()).or_default();
+        stats.path = churn.path;
+        stats.lines_added += churn.lines_added;
+        stats.lines_removed += churn.lines_removed;
+        stats.commits += i64::from(churn.commits);
+    }
+    let mut authors: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for contributor in storage.get_contributors(path, since, until).await? {
+        authors
+            .entry(contributor.path)
+            .or_default()
+            .insert(contributor.author);
+    }
+    let mut hotspots: Vec<DirStats> = dirs
+        .into_values()
+        .map(|mut stats| {
+            stats.contributors = authors.get(&stats.path).map_or(0, BTreeSet::len);
+            stats
+        })
+        .collect();
+    hotspots.sort_by_key(|stats| std::cmp::Reverse(stats.lines_added + stats.lines_removed));
+    hotspots.truncate(limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE));
+    Ok(hotspots)
+}
+
+/// The changes of `path` per week from `since` to `until`, the weeks without any are left out.
+pub async fn code_frequency(
+    context: &Context,
+    path: &str,
+    since: NaiveDate,
+    until: NaiveDate,
+) -> Result<Vec<WeekStats>, MegaError> {
+    let storage = context.services.analytics_storage.clone();
+    let path = normalize(path);
+    let mut weeks: BTreeMap<NaiveDate, WeekStats> = BTreeMap::new();
+    for churn in storage.get_churn(&path, since, until).await? {
+        if churn.path != path {
+            continue;
+        }
+        let stats = weeks.entry(churn.week).or_default();
+        stats.lines_added += churn.lines_added;
+        stats.lines_removed += churn.lines_removed;
+        stats.commits += i64::from(churn.commits);
+    }
+    for contributor in storage.get_contributors(&path, since, until).await? {
+        if contributor.path == path {
+            weeks.entry(contributor.week).or_default().contributors += 1;
+        }
+    }
+    Ok(weeks
+        .into_iter()
+        .map(|(week, stats)| WeekStats {
+            week: week.format("%Y-%m-%d").to_string(),
+            ..stats
+        })
+        .collect())
+}
+
+/// The pairs of directories most often changed together, one of them below `path`.
+pub async fn coupling(
+    context: &Context,
+    path: &str,
+    since: NaiveDate,
+    until: NaiveDate,
+    limit: Option<usize>,
+) -> Result<Vec<Coupling>, MegaError> {
+    let mut pairs: BTreeMap<(String, String), i64> = BTreeMap::new();
+    for coupling in context
+        .services
+        .analytics_storage
+        .get_coupling(path, since, until)
+        .await?
+    {
+        *pairs
+            .entry((coupling.path, coupling.coupled_path))
+            .or_default() += i64::from(coupling.commits);
+    }
+    let mut coupling: Vec<Coupling> = pairs
+        .into_iter()
+        .map(|((path, coupled_path), commits)| Coupling {
+            path,
+            coupled_path,
+            commits,
+        })
+        .collect();
+    coupling.sort_by_key(|c| std::cmp::Reverse(c.commits));
+    coupling.truncate(limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE));
+    Ok(coupling)
+}
+
+/// `path` as stored: absolute and without a trailing slash.
+fn normalize(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use venus::hash::SHA1;
+    use venus::internal::object::commit::Commit;
+    use venus::internal::object::signature::{Signature, SignatureType};
+
+    use super::{commit_stats, line_changes, parent_dirs};
+
+    #[test]
+    fn test_line_changes() {
+        assert_eq!(line_changes(b"a\nb\nc\n", b"a\nc\nd\ne\n"), (2, 1));
+        assert_eq!(line_changes(b"", b"a\nb"), (2, 0));
+        assert_eq!(line_changes(b"a\na\n", b"a\n"), (0, 1));
+        assert_eq!(line_changes(b"\0\x01", b"text"), (0, 0));
+    }
+
+    #[test]
+    fn test_parent_dirs() {
+        assert_eq!(
+            parent_dirs("/projects/mega/ceres/src/lib.rs", 2),
+            vec!["/", "/projects", "/projects/mega"]
+        );
+        assert_eq!(parent_dirs("/README.md", 3), vec!["/"]);
+    }
+
+    #[test]
+    fn test_commit_stats() {
+        let signature = Signature {
+            signature_type: SignatureType::Author,
+            name: String::from("alice"),
+            email: String::from("alice@example.com"),
+            // Wednesday 2024-03-06
+            timestamp: 1709726400,
+            timezone: String::from("+0000"),
+        };
+        let id = SHA1::from_str(&format!("{:040x}", 1)).unwrap();
+        let commit = Commit {
+            id,
+            tree_id: id,
+            parent_commit_ids: vec![],
+            author: signature.clone(),
+            committer: signature,
+            message: String::new(),
+        };
+        let files = vec![
+            (String::from("/projects/mega/src/lib.rs"), 10, 2),
+            (String::from("/projects/mega/Cargo.toml"), 1, 1),
+            (String::from("/projects/libra/src/main.rs"), 3, 0),
+            (String::from("/projects/README.md"), 1, 0),
+        ];
+        let stats = commit_stats(&commit, &files, 2);
+        let churn: Vec<_> = stats
+            .churn
+            .iter()
+            .map(|c| (c.path.as_str(), c.lines_added, c.lines_removed))
+            .collect();
+        assert_eq!(
+            churn,
+            vec![
+                ("/", 15, 3),
+                ("/projects", 15, 3),
+                ("/projects/libra", 3, 0),
+                ("/projects/mega", 11, 3),
+            ]
+        );
+        assert!(stats
+            .churn
+            .iter()
+            .all(|c| c.week.to_string() == "2024-03-04"));
+        assert_eq!(stats.contributors.len(), 4);
+        let coupling: Vec<_> = stats
+            .coupling
+            .iter()
+            .map(|c| (c.path.as_str(), c.coupled_path.as_str()))
+            .collect();
+        assert_eq!(coupling, vec![("/projects/libra", "/projects/mega")]);
+    }
+}
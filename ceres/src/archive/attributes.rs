@@ -0,0 +1,224 @@
+//!
+//! The `export-ignore` attribute of the `.gitattributes` files, which leaves paths out of an
+//! archive. The patterns are those of gitattributes(5): one without a slash matches the name
+//! of a path at any depth below its file, another one the path relative to its file, with
+//! `*`, `?`, `[...]` and `**`. The rules of the deeper files, then of the later lines, take
+//! precedence.
+//!
+
+/// The file the attributes of a directory are read from.
+pub(crate) const ATTRIBUTES_FILE: &str = ".gitattributes";
+
+const EXPORT_IGNORE: &str = "export-ignore";
+
+#[derive(Debug, Clone)]
+struct Rule {
+    /// The directory of the file of the rule.
+    base: Vec<String>,
+    pattern: Vec<String>,
+    /// Whether the pattern only matches the name of a path.
+    name_only: bool,
+    ignore: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExportIgnore {
+    rules: Vec<Rule>,
+}
+
+impl ExportIgnore {
+    /// Adds the rules of the attributes file of the directory `dir`, empty for the root. The
+    /// files are to be added from the root down.
+    pub fn add(&mut self, dir: &str, text: &str) {
+        let base: Vec<String> = segments(dir).map(str::to_owned).collect();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let Some(pattern) = fields.next() else {
+                continue;
+            };
+            // negative patterns are not allowed in attributes files
+            if pattern.starts_with('!') {
+                continue;
+            }
+            let Some(ignore) = fields.filter_map(export_ignore).next_back() else {
+                continue;
+            };
+            self.rules.push(Rule::new(base.clone(), pattern, ignore));
+        }
+    }
+
+    /// Whether the file or directory at `path` is left out of the archive.
+    pub fn is_ignored(&self, path: &str) -> bool {
+        let path: Vec<&str> = segments(path).collect();
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(&path))
+            .is_some_and(|rule| rule.ignore)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl Rule {
+    fn new(base: Vec<String>, pattern: &str, ignore: bool) -> Self {
+        let trimmed = pattern.trim_end_matches('/');
+        Rule {
+            base,
+            pattern: segments(trimmed).map(str::to_owned).collect(),
+            name_only: !trimmed.contains('/'),
+            ignore,
+        }
+    }
+
+    fn matches(&self, path: &[&str]) -> bool {
+        if path.len() <= self.base.len() || path.iter().zip(&self.base).any(|(a, b)| a != b) {
+            return false;
+        }
+        let relative = &path[self.base.len()..];
+        if self.name_only {
+            return match (self.pattern.first(), relative.last()) {
+                (Some(pattern), Some(name)) => wildmatch(pattern.as_bytes(), name.as_bytes()),
+                _ => false,
+            };
+        }
+        let pattern: Vec<&str> = self.pattern.iter().map(String::as_str).collect();
+        path_matches(&pattern, relative)
+    }
+}
+
+/// Whether `path` matches the pattern of a `.gitattributes` file of the root.
+pub(crate) fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let path: Vec<&str> = segments(path).collect();
+    Rule::new(Vec::new(), pattern, true).matches(&path)
+}
+
+/// The value an attribute sets `export-ignore` to, None for another attribute.
+fn export_ignore(attribute: &str) -> Option<bool> {
+    match attribute {
+        EXPORT_IGNORE => Some(true),
+        _ if attribute.strip_prefix(['-', '!']) == Some(EXPORT_IGNORE) => Some(false),
+        _ => None,
+    }
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+fn path_matches(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| path_matches(rest, &path[skip..])),
+        Some((segment, rest)) => match path.split_first() {
+            Some((name, path_rest)) => {
+                wildmatch(segment.as_bytes(), name.as_bytes()) && path_matches(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Matches a name against `*`, `?` and the `[...]` classes of a pattern.
+fn wildmatch(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| wildmatch(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && wildmatch(rest, &name[1..]),
+        Some((b'[', rest)) => match (class_matches(rest, name.first()), name.split_first()) {
+            (Some((true, after)), Some((_, name_rest))) => wildmatch(after, name_rest),
+            (Some(_), _) => false,
+            // an unclosed bracket is literal
+            (None, Some((b'[', name_rest))) => wildmatch(rest, name_rest),
+            (None, _) => false,
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            name.first() == Some(&rest[0]) && wildmatch(&rest[1..], &name[1..])
+        }
+        Some((c, rest)) => name.first() == Some(c) && wildmatch(rest, &name[1..]),
+    }
+}
+
+/// Whether `c` is in the class at the start of `pattern`, after its `[`, and the rest of the
+/// pattern. None if the class is not closed.
+fn class_matches<'a>(pattern: &'a [u8], c: Option<&u8>) -> Option<(bool, &'a [u8])> {
+    let (negated, mut i) = match pattern.first() {
+        Some(b'!') | Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let start = i;
+    let mut found = false;
+    loop {
+        let first = *pattern.get(i)?;
+        if first == b']' && i > start {
+            break;
+        }
+        if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|c| *c != b']') {
+            let last = pattern[i + 2];
+            found |= c.is_some_and(|c| (first..=last).contains(c));
+            i += 3;
+        } else {
+            found |= c == Some(&first);
+            i += 1;
+        }
+    }
+    Some((found != negated, &pattern[i + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pattern_matches, wildmatch, ExportIgnore};
+
+    #[test]
+    fn test_wildmatch() {
+        assert!(wildmatch(b"*.pdf", b"manual.pdf"));
+        assert!(!wildmatch(b"*.pdf", b"manual.pdf.txt"));
+        assert!(wildmatch(b"file?.txt", b"file1.txt"));
+        assert!(wildmatch(b"[a-c]*", b"build"));
+        assert!(!wildmatch(b"[!a-c]*", b"build"));
+        assert!(wildmatch(b"[]]", b"]"));
+        assert!(wildmatch(b"a[b", b"a[b"));
+        assert!(wildmatch(b"\\*", b"*"));
+    }
+
+    #[test]
+    fn test_pattern_matches() {
+        assert!(pattern_matches("*.bin", "assets/model.bin"));
+        assert!(pattern_matches("/assets/*.bin", "assets/model.bin"));
+        assert!(!pattern_matches("/assets/*.bin", "vendor/assets/model.bin"));
+        assert!(pattern_matches("assets/**", "assets/3d/model.bin"));
+        assert!(!pattern_matches("*.bin", "model.binary"));
+    }
+
+    #[test]
+    fn test_export_ignore() {
+        let mut rules = ExportIgnore::default();
+        rules.add(
+            "",
+            "# not in the archives\n\
+             *.psd export-ignore\n\
+             /tests export-ignore\n\
+             docs/**/draft* export-ignore binary\n\
+             *.txt text\n",
+        );
+        rules.add("vendor", "*.psd -export-ignore\nlib/ export-ignore\n");
+
+        assert!(rules.is_ignored("assets/logo.psd"));
+        assert!(rules.is_ignored("tests"));
+        assert!(!rules.is_ignored("src/tests"));
+        assert!(rules.is_ignored("docs/draft.md"));
+        assert!(rules.is_ignored("docs/guide/draft-v2.md"));
+        assert!(!rules.is_ignored("docs/guide.md"));
+        assert!(!rules.is_ignored("notes.txt"));
+        // the rules of a deeper file take precedence
+        assert!(!rules.is_ignored("vendor/logo.psd"));
+        assert!(rules.is_ignored("vendor/lib"));
+        assert!(!rules.is_ignored("lib"));
+    }
+}
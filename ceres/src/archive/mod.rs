@@ -0,0 +1,424 @@
+//!
+//! Archives of a commit, what `git archive` makes: the files of its tree, or of a directory
+//! of it, as a tar, a tar.gz or a zip. They are read from the object store and sent as they
+//! are encoded, so a snapshot of the source is downloaded without a clone. The paths with the
+//! `export-ignore` attribute are left out, see [`attributes`].
+//!
+pub(crate) mod attributes;
+mod tar;
+mod zip;
+
+use std::collections::HashMap;
+use std::io;
+use std::str::FromStr;
+
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::Stream;
+use tokio::sync::mpsc;
+
+use common::errors::MegaError;
+use jupiter::context::Context;
+use venus::hash::SHA1;
+use venus::internal::object::tree::{TreeItem, TreeItemMode};
+
+use crate::compare;
+use crate::history;
+use attributes::{ExportIgnore, ATTRIBUTES_FILE};
+use tar::TarWriter;
+use zip::ZipWriter;
+
+/// Blobs read from the storage at once.
+const BLOB_BATCH: usize = 64;
+/// The encoded bytes are sent once there are this many of them.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tar" => Ok(ArchiveFormat::Tar),
+            "tar.gz" | "tgz" => Ok(ArchiveFormat::TarGz),
+            "zip" => Ok(ArchiveFormat::Zip),
+            _ => Err(format!("unknown archive format: {}", s)),
+        }
+    }
+}
+
+/// The kind of an entry of an archive, which gives its mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EntryKind {
+    Directory,
+    File,
+    Executable,
+    Symlink,
+}
+
+impl EntryKind {
+    /// The mode of the entry, with the permissions `git archive` gives with its default
+    /// `tar.umask` of 002.
+    fn mode(self) -> u32 {
+        match self {
+            EntryKind::Directory => 0o40775,
+            EntryKind::File => 0o100664,
+            EntryKind::Executable => 0o100775,
+            EntryKind::Symlink => 0o120777,
+        }
+    }
+}
+
+/// The tree an archive is made of.
+#[derive(Debug, Clone)]
+pub struct ArchiveSource {
+    pub tree: SHA1,
+    /// The commit archived, its id is the comment of the archive. None for a tree.
+    pub commit: Option<SHA1>,
+    /// The modification time of the entries, the time of the commit, or the current time for
+    /// a tree as with git.
+    pub mtime: u64,
+}
+
+/// The tree to archive for `rev`, a commit or a tree id, ref or branch name of the repository
+/// at `repo_path`. With a `path`, only this directory of the tree is archived.
+pub async fn open(
+    context: &Context,
+    repo_path: &str,
+    rev: &str,
+    path: Option<&str>,
+) -> Result<ArchiveSource, MegaError> {
+    let mut source = match compare::open_revisions(context, repo_path, rev, rev).await {
+        Ok((_, id, _, graph)) => ArchiveSource {
+            tree: graph
+                .tree(&id)
+                .ok_or_else(|| MegaError::with_message(&format!("commit not found: {}", rev)))?,
+            commit: Some(id),
+            mtime: graph.committer_date(&id).unwrap_or_default() as u64,
+        },
+        Err(err) => match SHA1::from_str(rev) {
+            Ok(id) if is_tree(context, &id).await? => ArchiveSource {
+                tree: id,
+                commit: None,
+                mtime: chrono::Utc::now().timestamp() as u64,
+            },
+            _ => return Err(err),
+        },
+    };
+    let components: Vec<&str> = path
+        .unwrap_or_default()
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .collect();
+    if !components.is_empty() {
+        let id =
+            history::entry_at(context, Some(source.tree), &components, &mut HashMap::new()).await?;
+        source.tree = match id {
+            Some(id) if is_tree(context, &id).await? => id,
+            _ => {
+                return Err(MegaError::with_message(&format!(
+                    "no directory at {}",
+                    components.join("/")
+                )))
+            }
+        };
+    }
+    Ok(source)
+}
+
+/// The archive of `source` in `format`, encoded as it is sent. The paths in it start with
+/// `prefix`, e.g. `mega-1.0/`. The paths of the tree `readable` refuses are left out, the
+/// directories below one are still walked. An error past the first bytes ends the stream
+/// with it.
+pub fn archive_stream(
+    context: Context,
+    source: ArchiveSource,
+    format: ArchiveFormat,
+    prefix: String,
+    readable: impl Fn(&str) -> bool + Send + Sync + 'static,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let (sender, receiver) = mpsc::channel(16);
+    let task = tokio::spawn(async move {
+        write_archive(&context, &source, format, &prefix, &readable, sender).await
+    });
+    futures::stream::unfold((receiver, Some(task)), |(mut receiver, task)| async move {
+        if let Some(chunk) = receiver.recv().await {
+            return Some((Ok(chunk), (receiver, task)));
+        }
+        let err = match task?.await {
+            Ok(Ok(())) => return None,
+            Ok(Err(err)) => err.to_string(),
+            Err(err) => err.to_string(),
+        };
+        tracing::error!("failed to write an archive: {}", err);
+        Some((Err(io::Error::other(err)), (receiver, None)))
+    })
+}
+
+/// Encodes the archive of `source` to `sender`, until the receiver is dropped.
+async fn write_archive(
+    context: &Context,
+    source: &ArchiveSource,
+    format: ArchiveFormat,
+    prefix: &str,
+    readable: &impl Fn(&str) -> bool,
+    sender: mpsc::Sender<Bytes>,
+) -> Result<(), MegaError> {
+    let comment = source
+        .commit
+        .map(|id| id.to_plain_str())
+        .unwrap_or_default();
+    let mut encoder = Encoder::new(format, &comment, source.mtime)?;
+    let prefix = prefix.trim_start_matches('/');
+    if let Some(dir) = prefix.strip_suffix('/').filter(|dir| !dir.is_empty()) {
+        encoder.append(dir, EntryKind::Directory, source.mtime, &[])?;
+    }
+
+    let mut export_ignore = ExportIgnore::default();
+    let mut dirs = vec![(String::new(), source.tree)];
+    while let Some((dir, id)) = dirs.pop() {
+        if !dir.is_empty() && readable(&dir) {
+            let path = format!("{}{}", prefix, dir);
+            encoder.append(&path, EntryKind::Directory, source.mtime, &[])?;
+        }
+        let items = compare::load_tree_items(context, Some(id)).await?;
+        if let Some(item) = items
+            .iter()
+            .find(|item| item.name == ATTRIBUTES_FILE && item.mode == TreeItemMode::Blob)
+        {
+            let blobs = context
+                .services
+                .mega_storage
+                .get_blob_contents(&[item.id])
+                .await?;
+            if let Some(data) = blobs.get(&item.id) {
+                export_ignore.add(&dir, &String::from_utf8_lossy(data));
+            }
+        }
+
+        let (files, subtrees) = entries(&dir, items, &export_ignore, readable);
+        for batch in files.chunks(BLOB_BATCH) {
+            let ids: Vec<SHA1> = batch
+                .iter()
+                .filter(|(_, kind, _)| *kind != EntryKind::Directory)
+                .map(|(_, _, id)| *id)
+                .collect();
+            let blobs = context
+                .services
+                .mega_storage
+                .get_blob_contents(&ids)
+                .await?;
+            for (path, kind, id) in batch {
+                let data = match kind {
+                    EntryKind::Directory => &[][..],
+                    _ => blobs.get(id).map(Vec::as_slice).ok_or_else(|| {
+                        MegaError::with_message(&format!("blob not found: {}", id.to_plain_str()))
+                    })?,
+                };
+                encoder.append(&format!("{}{}", prefix, path), *kind, source.mtime, data)?;
+                if encoder.buffer().len() >= CHUNK_SIZE && !send(&sender, &mut encoder).await {
+                    return Ok(());
+                }
+            }
+        }
+        dirs.extend(subtrees.into_iter().rev());
+    }
+    let rest = encoder.finish(&comment)?;
+    if !rest.is_empty() {
+        let _ = sender.send(Bytes::from(rest)).await;
+    }
+    Ok(())
+}
+
+/// The files of a directory with their kind and blob, and its subtrees, by path.
+type DirEntries = (Vec<(String, EntryKind, SHA1)>, Vec<(String, SHA1)>);
+
+/// The files and the subtrees of the directory `dir` made of `items`, less those ignored by
+/// `export_ignore`. The files `readable` refuses are left out, the subtrees are all kept.
+fn entries(
+    dir: &str,
+    items: Vec<TreeItem>,
+    export_ignore: &ExportIgnore,
+    readable: &impl Fn(&str) -> bool,
+) -> DirEntries {
+    let mut files = Vec::new();
+    let mut subtrees = Vec::new();
+    for item in items {
+        let path = if dir.is_empty() {
+            item.name.clone()
+        } else {
+            format!("{}/{}", dir, item.name)
+        };
+        if !export_ignore.is_empty() && export_ignore.is_ignored(&path) {
+            continue;
+        }
+        let kind = match item.mode {
+            TreeItemMode::Tree => {
+                subtrees.push((path, item.id));
+                continue;
+            }
+            // a submodule is an empty directory, its files are not in the repository
+            TreeItemMode::Commit => EntryKind::Directory,
+            TreeItemMode::Blob => EntryKind::File,
+            TreeItemMode::BlobExecutable => EntryKind::Executable,
+            TreeItemMode::Link => EntryKind::Symlink,
+        };
+        if readable(&path) {
+            files.push((path, kind, item.id));
+        }
+    }
+    (files, subtrees)
+}
+
+/// Sends the bytes encoded so far, false once the receiver is dropped.
+async fn send(sender: &mpsc::Sender<Bytes>, encoder: &mut Encoder) -> bool {
+    let chunk = std::mem::take(encoder.buffer());
+    sender.send(Bytes::from(chunk)).await.is_ok()
+}
+
+async fn is_tree(context: &Context, id: &SHA1) -> Result<bool, MegaError> {
+    Ok(context
+        .services
+        .mega_storage
+        .get_mega_tree_by_sha(&id.to_plain_str())
+        .await?
+        .is_some())
+}
+
+/// The encoder of an archive, writing to a buffer taken out between the entries.
+enum Encoder {
+    Tar(TarWriter<Vec<u8>>),
+    TarGz(TarWriter<GzEncoder<Vec<u8>>>),
+    Zip(ZipWriter<Vec<u8>>),
+}
+
+impl Encoder {
+    /// A tar starts with the `comment`, a zip ends with it.
+    fn new(format: ArchiveFormat, comment: &str, mtime: u64) -> io::Result<Self> {
+        let mut encoder = match format {
+            ArchiveFormat::Tar => Encoder::Tar(TarWriter::new(Vec::new())),
+            ArchiveFormat::TarGz => Encoder::TarGz(TarWriter::new(GzEncoder::new(
+                Vec::new(),
+                Compression::default(),
+            ))),
+            ArchiveFormat::Zip => return Ok(Encoder::Zip(ZipWriter::new(Vec::new()))),
+        };
+        if !comment.is_empty() {
+            match &mut encoder {
+                Encoder::Tar(tar) => tar.comment(comment, mtime)?,
+                Encoder::TarGz(tar) => tar.comment(comment, mtime)?,
+                Encoder::Zip(_) => {}
+            }
+        }
+        Ok(encoder)
+    }
+
+    fn append(&mut self, path: &str, kind: EntryKind, mtime: u64, data: &[u8]) -> io::Result<()> {
+        match self {
+            Encoder::Tar(tar) => tar.append(path, kind, mtime, data),
+            Encoder::TarGz(tar) => tar.append(path, kind, mtime, data),
+            Encoder::Zip(zip) => zip.append(path, kind, mtime, data),
+        }
+    }
+
+    fn buffer(&mut self) -> &mut Vec<u8> {
+        match self {
+            Encoder::Tar(tar) => tar.get_mut(),
+            Encoder::TarGz(tar) => tar.get_mut().get_mut(),
+            Encoder::Zip(zip) => zip.get_mut(),
+        }
+    }
+
+    /// The last bytes of the archive.
+    fn finish(self, comment: &str) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Tar(tar) => tar.finish(),
+            Encoder::TarGz(tar) => tar.finish()?.finish(),
+            Encoder::Zip(zip) => zip.finish(comment),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use venus::hash::SHA1;
+    use venus::internal::object::tree::{TreeItem, TreeItemMode};
+
+    use super::attributes::ExportIgnore;
+    use super::{entries, ArchiveFormat, Encoder, EntryKind};
+
+    #[test]
+    fn test_archive_format() {
+        assert_eq!("tgz".parse(), Ok(ArchiveFormat::TarGz));
+        assert_eq!("zip".parse(), Ok(ArchiveFormat::Zip));
+        assert!("rar".parse::<ArchiveFormat>().is_err());
+        assert_eq!(ArchiveFormat::TarGz.extension(), "tar.gz");
+    }
+
+    #[test]
+    fn test_encoder_chunks() {
+        // the bytes taken out between the entries make up the whole archive
+        let mut encoder = Encoder::new(ArchiveFormat::TarGz, &"a".repeat(40), 0).unwrap();
+        let mut archive = Vec::new();
+        for i in 0..10 {
+            let data = format!("file {}\n", i).repeat(1000);
+            encoder
+                .append(&format!("f{}.txt", i), EntryKind::File, 0, data.as_bytes())
+                .unwrap();
+            archive.append(encoder.buffer());
+        }
+        archive.extend(encoder.finish("").unwrap());
+
+        let mut tar = Vec::new();
+        GzDecoder::new(archive.as_slice())
+            .read_to_end(&mut tar)
+            .unwrap();
+        assert_eq!(&tar[..17], b"pax_global_header");
+        assert_eq!(tar.len() % 512, 0);
+        assert!(tar.windows(6).any(|w| w == b"f9.txt"));
+    }
+
+    #[test]
+    fn test_entries_readable() {
+        let item = |mode, name: &str| TreeItem::new(mode, SHA1::default(), name.to_owned());
+        let items = vec![
+            item(TreeItemMode::Blob, "lib.rs"),
+            item(TreeItemMode::Blob, "key.pem"),
+            item(TreeItemMode::Tree, "secret"),
+        ];
+        let readable = |path: &str| !path.ends_with(".pem") && !path.starts_with("src/secret");
+        let (files, subtrees) = entries("src", items, &ExportIgnore::default(), &readable);
+        let files: Vec<&str> = files.iter().map(|(path, _, _)| path.as_str()).collect();
+        assert_eq!(files, vec!["src/lib.rs"]);
+        // a denied directory is walked, what it holds is checked entry by entry
+        assert_eq!(subtrees.len(), 1);
+    }
+}
@@ -0,0 +1,234 @@
+//!
+//! Tar encoding of an archive, the POSIX.1-2001 (pax) format `git archive` writes: ustar
+//! headers, with a pax extended header before the entries whose path or link target does not
+//! fit in one, and a global one carrying the commit id as a comment.
+//!
+use std::io::{self, Write};
+
+use super::EntryKind;
+
+const BLOCK_SIZE: usize = 512;
+const NAME_SIZE: usize = 100;
+
+/// Writes the entries of a tar archive to `inner`, as they are appended.
+pub(crate) struct TarWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(inner: W) -> Self {
+        TarWriter { inner }
+    }
+
+    /// The archive written so far, a caller may take the bytes out of it between entries.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Adds a global header with the `comment` record, as git does with the id of the commit
+    /// archived, which `git get-tar-commit-id` reads.
+    pub fn comment(&mut self, comment: &str, mtime: u64) -> io::Result<()> {
+        let records = pax_record("comment", comment);
+        self.write_header(
+            "pax_global_header",
+            b'g',
+            0o100666,
+            mtime,
+            records.len(),
+            "",
+        )?;
+        self.write_data(&records)
+    }
+
+    pub fn append(
+        &mut self,
+        path: &str,
+        kind: EntryKind,
+        mtime: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let path = match kind {
+            EntryKind::Directory => format!("{}/", path),
+            _ => path.to_owned(),
+        };
+        let link = match kind {
+            EntryKind::Symlink => String::from_utf8_lossy(data).into_owned(),
+            _ => String::new(),
+        };
+        let mut records = Vec::new();
+        if path.len() > NAME_SIZE {
+            records.extend(pax_record("path", &path));
+        }
+        if link.len() > NAME_SIZE {
+            records.extend(pax_record("linkpath", &link));
+        }
+        if !records.is_empty() {
+            let name = format!("PaxHeaders/{}", truncate(&path, NAME_SIZE - 11));
+            self.write_header(&name, b'x', 0o100666, mtime, records.len(), "")?;
+            self.write_data(&records)?;
+        }
+        let (flag, size) = match kind {
+            EntryKind::Directory => (b'5', 0),
+            EntryKind::Symlink => (b'2', 0),
+            EntryKind::File | EntryKind::Executable => (b'0', data.len()),
+        };
+        self.write_header(
+            truncate(&path, NAME_SIZE),
+            flag,
+            kind.mode(),
+            mtime,
+            size,
+            truncate(&link, NAME_SIZE),
+        )?;
+        if size > 0 {
+            self.write_data(data)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the archive with its two empty blocks.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&[0; BLOCK_SIZE * 2])?;
+        Ok(self.inner)
+    }
+
+    fn write_header(
+        &mut self,
+        name: &str,
+        flag: u8,
+        mode: u32,
+        mtime: u64,
+        size: usize,
+        link: &str,
+    ) -> io::Result<()> {
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        write_octal(&mut header[100..108], (mode & 0o7777) as u64);
+        write_octal(&mut header[108..116], 0);
+        write_octal(&mut header[116..124], 0);
+        write_octal(&mut header[124..136], size as u64);
+        write_octal(&mut header[136..148], mtime);
+        header[156] = flag;
+        header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[265..269].copy_from_slice(b"root");
+        header[297..301].copy_from_slice(b"root");
+        // the checksum is computed with its own field filled with spaces
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+        write_octal(&mut header[148..155], checksum as u64);
+        self.inner.write_all(&header)
+    }
+
+    /// Writes `data` padded to a whole number of blocks.
+    fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data)?;
+        let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        self.inner.write_all(&[0; BLOCK_SIZE][..padding])
+    }
+}
+
+/// A `<length> <key>=<value>\n` record of a pax header, the length counting its own digits.
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    format!("{} {}={}\n", len, key, value).into_bytes()
+}
+
+/// `value` in octal, zero-padded and ended with a NUL to fill `field`.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    let digits = &digits.as_bytes()[digits.len() + 1 - field.len()..];
+    field[..digits.len()].copy_from_slice(digits);
+    field[digits.len()] = 0;
+}
+
+/// The longest prefix of `s` of at most `max` bytes ending on a character boundary.
+fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pax_record, TarWriter, BLOCK_SIZE};
+    use crate::archive::EntryKind;
+
+    fn field(header: &[u8], start: usize, end: usize) -> &str {
+        std::str::from_utf8(&header[start..end])
+            .unwrap()
+            .trim_end_matches('\0')
+    }
+
+    #[test]
+    fn test_pax_record() {
+        assert_eq!(pax_record("path", "a"), b"9 path=a\n");
+        let record = pax_record("comment", &"c".repeat(40));
+        assert_eq!(record.len(), 52);
+        assert!(record.starts_with(b"52 comment="));
+    }
+
+    #[test]
+    fn test_tar_entries() {
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append("src", EntryKind::Directory, 1700000000, b"")
+            .unwrap();
+        tar.append(
+            "src/main.rs",
+            EntryKind::File,
+            1700000000,
+            b"fn main() {}\n",
+        )
+        .unwrap();
+        let long = format!("{}/lib.rs", "d".repeat(120));
+        tar.append(&long, EntryKind::Executable, 1700000000, b"x")
+            .unwrap();
+        let data = tar.finish().unwrap();
+        assert_eq!(data.len() % BLOCK_SIZE, 0);
+
+        let header = &data[..BLOCK_SIZE];
+        assert_eq!(field(header, 0, 100), "src/");
+        assert_eq!(header[156], b'5');
+        assert_eq!(field(header, 100, 108), "0000775");
+        let checksum: u32 = header
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                if (148..156).contains(&i) {
+                    b' ' as u32
+                } else {
+                    *b as u32
+                }
+            })
+            .sum();
+        let stored = u32::from_str_radix(field(header, 148, 155), 8).unwrap();
+        assert_eq!(stored, checksum);
+
+        let header = &data[BLOCK_SIZE..BLOCK_SIZE * 2];
+        assert_eq!(field(header, 0, 100), "src/main.rs");
+        assert_eq!(field(header, 124, 136), "00000000015");
+        assert_eq!(
+            &data[BLOCK_SIZE * 2..BLOCK_SIZE * 2 + 13],
+            b"fn main() {}\n"
+        );
+
+        // the long path is in a pax header before the entry
+        let header = &data[BLOCK_SIZE * 3..BLOCK_SIZE * 4];
+        assert_eq!(header[156], b'x');
+        let records = &data[BLOCK_SIZE * 4..BLOCK_SIZE * 5];
+        assert!(records.starts_with(format!("137 path={}\n", long).as_bytes()));
+        let header = &data[BLOCK_SIZE * 5..BLOCK_SIZE * 6];
+        assert_eq!(header[156], b'0');
+        assert_eq!(field(header, 100, 108), "0000775");
+    }
+}
@@ -0,0 +1,260 @@
+//!
+//! Zip encoding of an archive, as `git archive --format=zip` writes it: the files are deflated
+//! unless that does not make them smaller, their unix modes are kept in the external
+//! attributes, and the commit id is the comment of the archive. Zip64 is not written, an
+//! archive past 4 GiB or 65535 entries is to be made as a tar.
+//!
+use std::io::{self, Write};
+
+use chrono::{DateTime, Datelike, Timelike};
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+
+use super::EntryKind;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_SIGNATURE: u32 = 0x06054b50;
+const VERSION_NEEDED: u16 = 20;
+/// Made on unix, so the external attributes hold the mode.
+const VERSION_MADE_BY: u16 = (3 << 8) | 20;
+/// The names and comment are UTF-8.
+const FLAG_UTF8: u16 = 1 << 11;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// An entry already written, described again in the central directory.
+struct CentralEntry {
+    name: Vec<u8>,
+    method: u16,
+    time: u16,
+    date: u16,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    external_attributes: u32,
+    offset: u32,
+}
+
+/// Writes the entries of a zip archive to `inner`, as they are appended. The offsets are
+/// counted here, so the bytes written may be taken out of `inner` between entries.
+pub(crate) struct ZipWriter<W: Write> {
+    inner: W,
+    offset: u64,
+    entries: Vec<CentralEntry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ZipWriter {
+            inner,
+            offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn append(
+        &mut self,
+        path: &str,
+        kind: EntryKind,
+        mtime: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let name = match kind {
+            EntryKind::Directory => format!("{}/", path),
+            _ => path.to_owned(),
+        }
+        .into_bytes();
+        let mut crc = Crc::new();
+        crc.update(data);
+        let deflated = match kind {
+            EntryKind::File | EntryKind::Executable if !data.is_empty() => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                Some(encoder.finish()?).filter(|deflated| deflated.len() < data.len())
+            }
+            _ => None,
+        };
+        let (method, content) = match &deflated {
+            Some(deflated) => (METHOD_DEFLATED, deflated.as_slice()),
+            None => (METHOD_STORED, data),
+        };
+        let (time, date) = dos_time(mtime);
+        let mut external_attributes = kind.mode() << 16;
+        if kind == EntryKind::Directory {
+            // the MS-DOS directory attribute
+            external_attributes |= 0x10;
+        }
+        let entry = CentralEntry {
+            name,
+            method,
+            time,
+            date,
+            crc: crc.sum(),
+            compressed_size: to_u32(content.len() as u64)?,
+            size: to_u32(data.len() as u64)?,
+            external_attributes,
+            offset: to_u32(self.offset)?,
+        };
+
+        let mut header = Vec::with_capacity(30 + entry.name.len());
+        header.extend(LOCAL_HEADER_SIGNATURE.to_le_bytes());
+        header.extend(VERSION_NEEDED.to_le_bytes());
+        header.extend(FLAG_UTF8.to_le_bytes());
+        header.extend(entry.method.to_le_bytes());
+        header.extend(entry.time.to_le_bytes());
+        header.extend(entry.date.to_le_bytes());
+        header.extend(entry.crc.to_le_bytes());
+        header.extend(entry.compressed_size.to_le_bytes());
+        header.extend(entry.size.to_le_bytes());
+        header.extend((entry.name.len() as u16).to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(&entry.name);
+        self.write(&header)?;
+        self.write(content)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Writes the central directory and its end, with `comment` as the comment of the archive.
+    pub fn finish(mut self, comment: &str) -> io::Result<W> {
+        if self.entries.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many entries for a zip archive",
+            ));
+        }
+        let start = to_u32(self.offset)?;
+        let entries = std::mem::take(&mut self.entries);
+        for entry in &entries {
+            let mut header = Vec::with_capacity(46 + entry.name.len());
+            header.extend(CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+            header.extend(VERSION_MADE_BY.to_le_bytes());
+            header.extend(VERSION_NEEDED.to_le_bytes());
+            header.extend(FLAG_UTF8.to_le_bytes());
+            header.extend(entry.method.to_le_bytes());
+            header.extend(entry.time.to_le_bytes());
+            header.extend(entry.date.to_le_bytes());
+            header.extend(entry.crc.to_le_bytes());
+            header.extend(entry.compressed_size.to_le_bytes());
+            header.extend(entry.size.to_le_bytes());
+            header.extend((entry.name.len() as u16).to_le_bytes());
+            // extra field, comment, disk number and internal attributes
+            header.extend([0u8; 8]);
+            header.extend(entry.external_attributes.to_le_bytes());
+            header.extend(entry.offset.to_le_bytes());
+            header.extend(&entry.name);
+            self.write(&header)?;
+        }
+        let size = to_u32(self.offset)? - start;
+        let comment = &comment.as_bytes()[..comment.len().min(u16::MAX as usize)];
+        let mut end = Vec::with_capacity(22 + comment.len());
+        end.extend(END_SIGNATURE.to_le_bytes());
+        end.extend([0u8; 4]);
+        end.extend((entries.len() as u16).to_le_bytes());
+        end.extend((entries.len() as u16).to_le_bytes());
+        end.extend(size.to_le_bytes());
+        end.extend(start.to_le_bytes());
+        end.extend((comment.len() as u16).to_le_bytes());
+        end.extend(comment);
+        self.write(&end)?;
+        Ok(self.inner)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+}
+
+fn to_u32(value: u64) -> io::Result<u32> {
+    u32::try_from(value).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the archive is too large for a zip, make it a tar",
+        )
+    })
+}
+
+/// The MS-DOS time and date of a unix time, in UTC, which starts in 1980.
+fn dos_time(mtime: u64) -> (u16, u16) {
+    let Some(time) = DateTime::from_timestamp(mtime as i64, 0).filter(|time| time.year() >= 1980)
+    else {
+        return (0, (1 << 5) | 1);
+    };
+    let dos_time = (time.hour() << 11) | (time.minute() << 5) | (time.second() / 2);
+    let dos_date = ((time.year() as u32 - 1980) << 9) | (time.month() << 5) | time.day();
+    (dos_time as u16, dos_date as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::DeflateDecoder;
+
+    use super::{dos_time, ZipWriter};
+    use crate::archive::EntryKind;
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_dos_time() {
+        // 2023-11-14 22:13:20 UTC
+        let (time, date) = dos_time(1700000000);
+        assert_eq!(time, (22 << 11) | (13 << 5) | 10);
+        assert_eq!(date, (43 << 9) | (11 << 5) | 14);
+        assert_eq!(dos_time(0), (0, (1 << 5) | 1));
+    }
+
+    #[test]
+    fn test_zip_entries() {
+        let content = "fn main() {}\n".repeat(20);
+        let mut zip = ZipWriter::new(Vec::new());
+        zip.append("src", EntryKind::Directory, 1700000000, b"")
+            .unwrap();
+        zip.append(
+            "src/main.rs",
+            EntryKind::File,
+            1700000000,
+            content.as_bytes(),
+        )
+        .unwrap();
+        let data = zip.finish("comment").unwrap();
+
+        // the end of the central directory
+        let end = data.len() - 22 - 7;
+        assert_eq!(u32_at(&data, end), 0x06054b50);
+        assert_eq!(u16_at(&data, end + 10), 2);
+        assert_eq!(&data[end + 22..], b"comment");
+        let central = u32_at(&data, end + 16) as usize;
+
+        // the second entry of the central directory, after the 46 bytes and name of the first
+        let second = central + 46 + 4;
+        assert_eq!(u32_at(&data, second), 0x02014b50);
+        assert_eq!(u16_at(&data, second + 10), 8);
+        assert_eq!(u32_at(&data, second + 38) >> 16, 0o100664);
+        let compressed_size = u32_at(&data, second + 20) as usize;
+        assert_eq!(u32_at(&data, second + 24) as usize, content.len());
+
+        let local = u32_at(&data, second + 42) as usize;
+        assert_eq!(u32_at(&data, local), 0x04034b50);
+        let start = local + 30 + "src/main.rs".len();
+        let mut inflated = String::new();
+        DeflateDecoder::new(&data[start..start + compressed_size])
+            .read_to_string(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, content);
+    }
+}
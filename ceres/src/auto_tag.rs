@@ -0,0 +1,377 @@
+//!
+//! Version tags created by the server, see the `auto_tag` option of [`crate::policy`]. When an
+//! update of the tagged branch changes the manifest of a directory with the option, the
+//! version it declares is tagged on the new commit with an annotated tag `<prefix>v<version>`.
+//! The tags of a directory only go up: a version not above the highest one tagged, or a tag
+//! name already taken, is skipped and logged. The releases are returned for the server to
+//! announce them.
+//!
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::Utc;
+use serde::Serialize;
+
+use callisto::refs;
+use common::errors::MegaError;
+use common::utils::{generate_id, ZERO_ID};
+use jupiter::context::Context;
+use jupiter::events::RefUpdateEvent;
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::internal::object::signature::{Signature, SignatureType};
+use venus::internal::object::tag::Tag;
+use venus::internal::object::types::ObjectType;
+use venus::internal::pack::entry::Entry;
+use venus::internal::pack::reference::RefCommand;
+use venus::mr::MergeRequest;
+use venus::repo::Repo;
+
+use crate::compare;
+use crate::policy::{self, AutoTag};
+use crate::replace::{self, Replacements};
+
+/// A version of a manifest, `<major>.<minor>.<patch>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(u64, u64, u64);
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<u64> = s
+            .strip_prefix('v')
+            .unwrap_or(s)
+            .split('.')
+            .map(|part| part.parse::<u64>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("not a version: {}", s))?;
+        match parts[..] {
+            [major, minor, patch] => Ok(Version(major, minor, patch)),
+            _ => Err(format!("not a version: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// A version tagged by the server.
+#[derive(Debug, Clone, Serialize)]
+pub struct Release {
+    pub repo_path: String,
+    /// The directory of the manifest, `""` for the root.
+    pub directory: String,
+    pub version: String,
+    pub tag_name: String,
+    pub tag_id: String,
+    pub commit_id: String,
+    /// The pusher of the update changing the version.
+    pub pusher: Option<String>,
+}
+
+/// Why a version is not tagged.
+#[derive(Debug, PartialEq)]
+enum TagConflict {
+    /// A tag of this name exists, e.g. the event is delivered again.
+    Exists,
+    /// The directory already has a tag of a version at least as high.
+    NotAbove(Version),
+}
+
+/// Tags the versions changed by `event` in the directories with an `auto_tag` policy.
+pub async fn tag_releases(
+    context: &Context,
+    event: &RefUpdateEvent,
+) -> Result<Vec<Release>, MegaError> {
+    if event.new_id == ZERO_ID || !event.ref_name.starts_with("refs/heads/") {
+        return Ok(Vec::new());
+    }
+    let repo = context
+        .services
+        .mega_storage
+        .find_git_repo(&event.repo_path)
+        .await?
+        .map(Repo::from)
+        .unwrap_or_else(Repo::empty);
+    let replacements = Replacements::load(context, &repo).await?;
+    let Some(commit) = read_commit(context, &repo, &replacements, &event.new_id).await? else {
+        return Ok(Vec::new());
+    };
+    let old_tree = read_commit(context, &repo, &replacements, &event.old_id)
+        .await?
+        .map(|commit| commit.tree_id);
+    let changes = compare::diff_trees(context, old_tree, Some(commit.tree_id)).await?;
+    if changes.is_empty() {
+        return Ok(Vec::new());
+    }
+    // the policies are read from the updated tree, changing them was reviewed
+    let directories: BTreeSet<String> = changes
+        .iter()
+        .flat_map(|change| policy::directories(&change.path))
+        .collect();
+    let policies = policy::load_policies(context, commit.tree_id, &directories).await?;
+
+    let mut refs = context.services.mega_storage.get_repo_refs(&repo).await?;
+    let mut releases = Vec::new();
+    let mut events = Vec::new();
+    for (directory, policy) in policies.policies() {
+        let Some(auto_tag) = policy.ok().and_then(|policy| policy.auto_tag.as_ref()) else {
+            continue;
+        };
+        if !tags_branch(auto_tag, &event.ref_name) {
+            continue;
+        }
+        let manifest = join(directory, &auto_tag.manifest);
+        // a deleted manifest declares no version
+        let Some(blob) = changes
+            .iter()
+            .find(|change| change.path == manifest)
+            .and_then(|change| change.new_id)
+        else {
+            continue;
+        };
+        let content = load_blob(context, &blob).await?;
+        let Some(version) = manifest_version(&auto_tag.manifest, &content) else {
+            tracing::warn!("no version in {} of {}", manifest, event.repo_path);
+            continue;
+        };
+        let prefix = auto_tag
+            .prefix
+            .clone()
+            .unwrap_or_else(|| default_prefix(directory));
+        let tag_name = format!("{}v{}", prefix, version);
+        match check_tag(&refs, &prefix, version) {
+            Ok(()) => {}
+            Err(TagConflict::Exists) => {
+                tracing::info!("{} of {} is already tagged", tag_name, event.repo_path);
+                continue;
+            }
+            Err(TagConflict::NotAbove(highest)) => {
+                tracing::warn!(
+                    "{} of {} is not tagged, {}v{} is tagged already",
+                    tag_name,
+                    event.repo_path,
+                    prefix,
+                    highest
+                );
+                continue;
+            }
+        }
+
+        let tagger = Signature {
+            signature_type: SignatureType::Tagger,
+            timestamp: Utc::now().timestamp() as usize,
+            timezone: String::from("+0000"),
+            ..commit.committer.clone()
+        };
+        let tag = Tag::new(
+            commit.id,
+            ObjectType::Commit,
+            tag_name.clone(),
+            tagger,
+            &format!("{}\n", tag_name),
+        )
+        .map_err(|e| MegaError::with_message(&e.to_string()))?;
+        let tag_id = tag.id.to_plain_str();
+        context
+            .services
+            .mega_storage
+            .save_entry(&MergeRequest::default(), &repo, vec![Entry::from(tag)])
+            .await?;
+        let command = RefCommand::new(
+            ZERO_ID.to_owned(),
+            tag_id.clone(),
+            format!("refs/tags/{}", tag_name),
+        );
+        context
+            .services
+            .mega_storage
+            .handler_refs(&repo, &command)
+            .await;
+        refs.push(refs::Model {
+            ref_git_id: tag_id.clone(),
+            ..command.clone().into()
+        });
+        events.push(RefUpdateEvent {
+            id: generate_id(),
+            repo_id: repo.repo_id,
+            repo_path: event.repo_path.clone(),
+            ref_name: command.ref_name,
+            old_id: command.old_id,
+            new_id: command.new_id,
+            pusher: None,
+            created_at: Utc::now().naive_utc(),
+        });
+        releases.push(Release {
+            repo_path: event.repo_path.clone(),
+            directory: directory.to_owned(),
+            version: version.to_string(),
+            tag_name,
+            tag_id,
+            commit_id: event.new_id.clone(),
+            pusher: event.pusher.clone(),
+        });
+    }
+    if !events.is_empty() {
+        context.events.publish(context, events).await?;
+    }
+    Ok(releases)
+}
+
+async fn read_commit(
+    context: &Context,
+    repo: &Repo,
+    replacements: &Replacements,
+    id: &str,
+) -> Result<Option<Commit>, MegaError> {
+    match SHA1::from_str(id).ok().filter(|_| id != ZERO_ID) {
+        Some(id) => replace::read_commit(context, repo, replacements, &id).await,
+        None => Ok(None),
+    }
+}
+
+async fn load_blob(context: &Context, id: &SHA1) -> Result<Vec<u8>, MegaError> {
+    context
+        .services
+        .mega_storage
+        .get_blob_content(id)
+        .await?
+        .ok_or_else(|| MegaError::with_message(&format!("blob not found: {}", id.to_plain_str())))
+}
+
+/// Whether the updates of `ref_name` are tagged.
+fn tags_branch(auto_tag: &AutoTag, ref_name: &str) -> bool {
+    if auto_tag.branch.starts_with("refs/") {
+        auto_tag.branch == ref_name
+    } else {
+        ref_name.strip_prefix("refs/heads/") == Some(auto_tag.branch.as_str())
+    }
+}
+
+fn join(directory: &str, name: &str) -> String {
+    if directory.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", directory, name)
+    }
+}
+
+fn default_prefix(directory: &str) -> String {
+    if directory.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", directory)
+    }
+}
+
+/// The version declared by `content`, the manifest named `manifest`.
+fn manifest_version(manifest: &str, content: &[u8]) -> Option<Version> {
+    let content = std::str::from_utf8(content).ok()?;
+    let version = if manifest.ends_with(".toml") {
+        let value: toml::Value = toml::from_str(content).ok()?;
+        let package = value
+            .get("package")
+            .and_then(|package| package.get("version"));
+        package
+            .or_else(|| value.get("version"))?
+            .as_str()?
+            .to_owned()
+    } else if manifest.ends_with(".json") {
+        let value: serde_json::Value = serde_json::from_str(content).ok()?;
+        value.get("version")?.as_str()?.to_owned()
+    } else {
+        content.trim().to_owned()
+    };
+    version.parse().ok()
+}
+
+/// Checks that tagging `version` with `prefix` keeps the tags of `refs` monotonic.
+fn check_tag(refs: &[refs::Model], prefix: &str, version: Version) -> Result<(), TagConflict> {
+    let tag_name = format!("refs/tags/{}v{}", prefix, version);
+    if refs.iter().any(|r| r.ref_name == tag_name) {
+        return Err(TagConflict::Exists);
+    }
+    let tag_prefix = format!("refs/tags/{}v", prefix);
+    let highest = refs
+        .iter()
+        .filter_map(|r| {
+            r.ref_name
+                .strip_prefix(&tag_prefix)?
+                .parse::<Version>()
+                .ok()
+        })
+        .max();
+    match highest {
+        Some(highest) if highest >= version => Err(TagConflict::NotAbove(highest)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use callisto::db_enums::RefType;
+    use callisto::refs;
+
+    use super::{check_tag, default_prefix, manifest_version, TagConflict, Version};
+
+    fn tag(name: &str) -> refs::Model {
+        refs::Model {
+            id: 0,
+            repo_id: 0,
+            ref_name: format!("refs/tags/{}", name),
+            ref_git_id: "a".repeat(40),
+            ref_type: RefType::Tag,
+            created_at: Default::default(),
+            updated_at: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_manifest_version() {
+        let cargo = b"[package]\nname = \"foo\"\nversion = \"1.2.3\"\n";
+        assert_eq!(
+            manifest_version("Cargo.toml", cargo),
+            Some(Version(1, 2, 3))
+        );
+        let json = br#"{"name": "foo", "version": "0.10.0"}"#;
+        assert_eq!(
+            manifest_version("package.json", json),
+            Some(Version(0, 10, 0))
+        );
+        assert_eq!(
+            manifest_version("VERSION", b"v2.0.1\n"),
+            Some(Version(2, 0, 1))
+        );
+        assert_eq!(manifest_version("VERSION", b"2.0"), None);
+        assert_eq!(manifest_version("VERSION", b"2.0.0-rc.1"), None);
+        assert_eq!(default_prefix("services/foo"), "services/foo/");
+        assert_eq!(default_prefix(""), "");
+    }
+
+    #[test]
+    fn test_check_tag() {
+        let refs = vec![
+            tag("services/foo/v1.2.3"),
+            tag("services/foo/v1.10.0"),
+            tag("services/bar/v9.0.0"),
+            tag("services/foo/bar/v9.0.0"),
+        ];
+        let prefix = "services/foo/";
+        assert_eq!(check_tag(&refs, prefix, Version(1, 10, 1)), Ok(()));
+        assert_eq!(check_tag(&refs, prefix, Version(2, 0, 0)), Ok(()));
+        assert_eq!(
+            check_tag(&refs, prefix, Version(1, 10, 0)),
+            Err(TagConflict::Exists)
+        );
+        assert_eq!(
+            check_tag(&refs, prefix, Version(1, 9, 0)),
+            Err(TagConflict::NotAbove(Version(1, 10, 0)))
+        );
+        assert_eq!(check_tag(&refs, "services/baz/", Version(0, 1, 0)), Ok(()));
+    }
+}
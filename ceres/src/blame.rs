@@ -0,0 +1,372 @@
+//!
+//! Blame of a file, the commit that last changed each of its lines, along the first parents
+//! as with `git blame --first-parent`: the lines a merge brings in are attributed to the
+//! merge. Blames are cached by path and commit, see `BlameStorage`, so that the blame at a
+//! later commit starts from the nearest cached one and only replays the diffs of the commits
+//! changing the file since. The size of the cache is `blame_cache_max_entries`.
+//!
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use diffs::Diff;
+use serde::{Deserialize, Serialize};
+
+use callisto::mega_blame_cache;
+use common::errors::MegaError;
+use common::utils::generate_id;
+use jupiter::context::Context;
+use venus::hash::SHA1;
+
+use crate::compare;
+use crate::history::{self, Person};
+use crate::replace::Replacements;
+
+/// Largest file blamed, in bytes.
+const MAX_BLAME_FILE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Consecutive lines last changed by the same commit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlameHunk {
+    /// The first line of the hunk, from 1.
+    pub start_line: usize,
+    pub lines: usize,
+    pub commit_id: String,
+    pub author: Person,
+    /// The first line of the message of the commit.
+    pub summary: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Blame {
+    pub repo_path: String,
+    pub path: String,
+    pub commit_id: String,
+    pub blob_id: String,
+    pub hunks: Vec<BlameHunk>,
+    /// The commit of the cached blame this one started from, None when it was computed from
+    /// the commit adding the file.
+    pub cached_from: Option<String>,
+    /// The commits changing the file whose diffs were replayed.
+    pub replayed: usize,
+}
+
+/// A run of lines of a cached blame.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedRun {
+    commit_id: String,
+    lines: usize,
+}
+
+/// The blame of the file `path` at the commit `rev` resolves to in the repository at
+/// `repo_path`.
+pub async fn blame(
+    context: &Context,
+    repo_path: &str,
+    rev: &str,
+    path: &str,
+) -> Result<Blame, MegaError> {
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    if components.is_empty() {
+        return Err(MegaError::with_message("no file to blame"));
+    }
+    let path = components.join("/");
+    let (repo, head, _, graph) = compare::open_revisions(context, repo_path, rev, rev).await?;
+    let storage = context.services.blame_storage.clone();
+    let max_entries = context.settings.current().blame_cache_max_entries;
+    let cached = if max_entries > 0 {
+        storage.get_cached_commits(repo.repo_id, &path).await?
+    } else {
+        HashSet::new()
+    };
+
+    let mut trees = HashMap::new();
+    let head_blob = history::entry_at(context, graph.tree(&head), &components, &mut trees)
+        .await?
+        .ok_or_else(|| MegaError::with_message(&format!("no file at {}", path)))?;
+
+    // the versions of the file down the first parents, up to a cached blame or the commit
+    // adding the file
+    let mut versions = Vec::new();
+    let mut base = None;
+    let (mut id, mut blob) = (head, head_blob);
+    loop {
+        if cached.contains(&id.to_plain_str()) {
+            if let Some(found) = load_cached(context, repo.repo_id, &path, &id, &blob).await? {
+                base = Some((id, found));
+                break;
+            }
+        }
+        let parent = graph.parents_of(&id).first().copied();
+        let parent_blob = match parent {
+            Some(parent) => {
+                history::entry_at(context, graph.tree(&parent), &components, &mut trees).await?
+            }
+            None => None,
+        };
+        if parent_blob != Some(blob) {
+            versions.push((id, blob));
+        }
+        match (parent, parent_blob) {
+            (Some(parent), Some(parent_blob)) => (id, blob) = (parent, parent_blob),
+            _ => break,
+        }
+    }
+
+    let (cached_from, mut content, mut owners) = match base {
+        Some((id, (content, owners))) => (Some(id), content, owners),
+        None => (None, Vec::new(), Vec::new()),
+    };
+    for (id, blob) in versions.iter().rev() {
+        let data = load_text(context, blob, &path).await?;
+        owners = reattribute(&split_lines(&content), &owners, &split_lines(&data), *id);
+        content = data;
+    }
+
+    if max_entries > 0 && cached_from != Some(head) {
+        if let Err(e) = save_cached(context, repo.repo_id, &path, &head, &head_blob, &owners).await
+        {
+            tracing::warn!(
+                "{}: failed to cache the blame of {}: {}",
+                repo_path,
+                path,
+                e
+            );
+        } else if let Err(e) = storage.evict(max_entries).await {
+            tracing::warn!("failed to evict the blame cache: {}", e);
+        }
+    }
+
+    let replacements = Replacements::load(context, &repo).await?;
+    let mut hunks = Vec::new();
+    let mut commits = HashMap::new();
+    let mut start_line = 1;
+    for run in runs(&owners) {
+        let commit_id = SHA1::from_str(&run.commit_id).map_err(|_| {
+            MegaError::with_message(&format!("invalid commit id {}", run.commit_id))
+        })?;
+        let commit = match commits.entry(commit_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(
+                history::read_commit(context, &repo, &replacements, &graph, &commit_id)
+                    .await?
+                    .ok_or_else(|| {
+                        MegaError::with_message(&format!("commit not found: {}", run.commit_id))
+                    })?,
+            ),
+        };
+        hunks.push(BlameHunk {
+            start_line,
+            lines: run.lines,
+            commit_id: run.commit_id,
+            author: Person::from(&commit.author),
+            summary: commit
+                .message
+                .trim()
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_owned(),
+        });
+        start_line += run.lines;
+    }
+    Ok(Blame {
+        repo_path: repo_path.to_owned(),
+        path,
+        commit_id: head.to_plain_str(),
+        blob_id: head_blob.to_plain_str(),
+        hunks,
+        cached_from: cached_from.map(|id| id.to_plain_str()),
+        replayed: versions.len(),
+    })
+}
+
+/// The content of the version `blob` of `path` and the commit of each of its lines in the
+/// cached blame at `commit`, None when the cached blame is not the one of this version of the
+/// file or cannot be read.
+async fn load_cached(
+    context: &Context,
+    repo_id: i64,
+    path: &str,
+    commit: &SHA1,
+    blob: &SHA1,
+) -> Result<Option<(Vec<u8>, Vec<SHA1>)>, MegaError> {
+    let Some(model) = context
+        .services
+        .blame_storage
+        .get_blame(repo_id, path, &commit.to_plain_str())
+        .await?
+    else {
+        return Ok(None);
+    };
+    if model.blob_id != blob.to_plain_str() {
+        return Ok(None);
+    }
+    let content = load_text(context, blob, path).await?;
+    let owners = serde_json::from_str::<Vec<CachedRun>>(&model.hunks)
+        .ok()
+        .and_then(|runs| expand(&runs))
+        .filter(|owners| owners.len() == split_lines(&content).len());
+    match owners {
+        Some(owners) => Ok(Some((content, owners))),
+        None => {
+            tracing::warn!("unreadable cached blame of {} at {}", path, model.commit_id);
+            Ok(None)
+        }
+    }
+}
+
+async fn save_cached(
+    context: &Context,
+    repo_id: i64,
+    path: &str,
+    commit: &SHA1,
+    blob: &SHA1,
+    owners: &[SHA1],
+) -> Result<(), MegaError> {
+    let hunks = serde_json::to_string(&runs(owners))
+        .map_err(|e| MegaError::with_message(&e.to_string()))?;
+    let now = chrono::Utc::now().naive_utc();
+    context
+        .services
+        .blame_storage
+        .save_blame(mega_blame_cache::Model {
+            id: generate_id(),
+            repo_id,
+            path: path.to_owned(),
+            commit_id: commit.to_plain_str(),
+            blob_id: blob.to_plain_str(),
+            hunks,
+            created_at: now,
+            last_used_at: now,
+        })
+        .await
+}
+
+/// The content of the blob `id`, a version of the file `path`, which has to be text.
+async fn load_text(context: &Context, id: &SHA1, path: &str) -> Result<Vec<u8>, MegaError> {
+    let data = context
+        .services
+        .mega_storage
+        .get_blob_content(id)
+        .await?
+        .ok_or_else(|| MegaError::with_message(&format!("no file at {}", path)))?;
+    if data.len() > MAX_BLAME_FILE_SIZE || data.contains(&0) {
+        return Err(MegaError::with_message(&format!(
+            "{} is binary or too large to blame",
+            path
+        )));
+    }
+    Ok(data)
+}
+
+/// The lines of a file, without their line feeds.
+fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    let data = data.strip_suffix(b"\n").unwrap_or(data);
+    if data.is_empty() {
+        return Vec::new();
+    }
+    data.split(|b| *b == b'\n').collect()
+}
+
+/// For each line of the new version, the line of the old version it is kept from.
+struct KeptLines(Vec<Option<usize>>);
+
+impl Diff for KeptLines {
+    type Error = ();
+
+    fn equal(&mut self, old: usize, new: usize, len: usize) -> Result<(), Self::Error> {
+        for i in 0..len {
+            self.0[new + i] = Some(old + i);
+        }
+        Ok(())
+    }
+}
+
+/// The commit of each line of the version `new` of a file, changed from `old` by `commit`:
+/// the lines kept keep their commit in `owners`, the others are attributed to `commit`.
+fn reattribute(old: &[&[u8]], owners: &[SHA1], new: &[&[u8]], commit: SHA1) -> Vec<SHA1> {
+    let mut kept = KeptLines(vec![None; new.len()]);
+    diffs::myers::diff(&mut kept, old, 0, old.len(), new, 0, new.len()).unwrap();
+    kept.0
+        .into_iter()
+        .map(|line| {
+            line.and_then(|line| owners.get(line).copied())
+                .unwrap_or(commit)
+        })
+        .collect()
+}
+
+/// The consecutive lines of the same commit.
+fn runs(owners: &[SHA1]) -> Vec<CachedRun> {
+    let mut runs: Vec<CachedRun> = Vec::new();
+    for (i, owner) in owners.iter().enumerate() {
+        if i > 0 && owners[i - 1] == *owner {
+            runs.last_mut().unwrap().lines += 1;
+        } else {
+            runs.push(CachedRun {
+                commit_id: owner.to_plain_str(),
+                lines: 1,
+            });
+        }
+    }
+    runs
+}
+
+fn expand(runs: &[CachedRun]) -> Option<Vec<SHA1>> {
+    let mut owners = Vec::new();
+    for run in runs {
+        let id = SHA1::from_str(&run.commit_id).ok()?;
+        owners.extend(std::iter::repeat_n(id, run.lines));
+    }
+    Some(owners)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use venus::hash::SHA1;
+
+    use super::{expand, reattribute, runs, split_lines};
+
+    #[test]
+    fn test_split_lines() {
+        assert!(split_lines(b"").is_empty());
+        assert_eq!(split_lines(b"a\n"), vec![b"a".as_slice()]);
+        assert_eq!(split_lines(b"a\n\nb"), vec![b"a".as_slice(), b"", b"b"]);
+    }
+
+    #[test]
+    fn test_reattribute() {
+        let first = SHA1::from_str(&"a".repeat(40)).unwrap();
+        let second = SHA1::from_str(&"b".repeat(40)).unwrap();
+        let third = SHA1::from_str(&"c".repeat(40)).unwrap();
+
+        let v1 = split_lines(b"fn main() {\n    run();\n}\n");
+        let owners = reattribute(&[], &[], &v1, first);
+        assert_eq!(owners, vec![first; 3]);
+
+        let v2 = split_lines(b"fn main() {\n    init();\n    run();\n}\n");
+        let owners = reattribute(&v1, &owners, &v2, second);
+        assert_eq!(owners, vec![first, second, first, first]);
+
+        let v3 = split_lines(b"fn main() {\n    init();\n    run_all();\n}\n");
+        let owners = reattribute(&v2, &owners, &v3, third);
+        assert_eq!(owners, vec![first, second, third, first]);
+    }
+
+    #[test]
+    fn test_runs() {
+        let first = SHA1::from_str(&"a".repeat(40)).unwrap();
+        let second = SHA1::from_str(&"b".repeat(40)).unwrap();
+        let owners = vec![first, first, second, first];
+        let runs = runs(&owners);
+        assert_eq!(
+            runs.iter().map(|run| run.lines).collect::<Vec<_>>(),
+            vec![2, 1, 1]
+        );
+        assert_eq!(expand(&runs), Some(owners));
+        assert!(super::runs(&[]).is_empty());
+    }
+}
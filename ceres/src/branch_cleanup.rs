@@ -0,0 +1,378 @@
+//!
+//! Removal of the branches nobody needs anymore: branches merged into the default branch and
+//! branches not updated for a number of days. A branch is never deleted on the run that finds
+//! it, the run schedules its deletion after a grace period and reports it so that its owner,
+//! the user who last pushed it, can be notified. Pushing to the branch during the grace period
+//! cancels the deletion.
+//!
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use chrono::{Duration, NaiveDateTime};
+use serde::Serialize;
+
+use callisto::db_enums::OperationType;
+use callisto::{mega_branch_cleanup, refs};
+use common::errors::MegaError;
+use common::utils::{generate_id, ZERO_ID};
+use jupiter::context::Context;
+use jupiter::events::RefUpdateEvent;
+use jupiter::storage::audit_storage::OperationFilter;
+use venus::hash::SHA1;
+use venus::internal::pack::reference::RefCommand;
+use venus::internal::revwalk::CommitGraph;
+use venus::repo::Repo;
+
+use crate::compare;
+
+const DEFAULT_BRANCH: &str = "refs/heads/main";
+
+/// What a directory does with its branches, nothing is removed by the default rule.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CleanupRule {
+    /// Removes the branches merged into the default branch.
+    pub merged: bool,
+    /// Removes the branches not updated for this many days, 0 keeps them.
+    pub stale_days: u32,
+    /// Days between the notification and the deletion.
+    pub grace_days: u32,
+}
+
+impl CleanupRule {
+    pub fn is_enabled(&self) -> bool {
+        self.merged || self.stale_days > 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CleanupReason {
+    Merged,
+    Stale,
+}
+
+impl CleanupReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CleanupReason::Merged => "merged",
+            CleanupReason::Stale => "stale",
+        }
+    }
+}
+
+impl FromStr for CleanupReason {
+    type Err = MegaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "merged" => Ok(CleanupReason::Merged),
+            "stale" => Ok(CleanupReason::Stale),
+            _ => Err(MegaError::with_message(&format!(
+                "unknown cleanup reason: {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CleanupAction {
+    /// The deletion was scheduled, the owner has to be notified.
+    Notify,
+    /// The deletion is scheduled, the grace period has not passed yet.
+    Wait,
+    Delete,
+    /// The branch was updated or no longer matches the rule, its deletion was cancelled.
+    Cancel,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BranchCleanup {
+    pub repo_path: String,
+    pub ref_name: String,
+    pub commit_id: String,
+    pub reason: CleanupReason,
+    pub owner: Option<String>,
+    pub delete_after: i64,
+    pub action: CleanupAction,
+}
+
+/// Applies `rule` to the branches of `repo` at `now`. A dry run only reports what a run would
+/// do, it neither schedules, cancels nor deletes anything.
+pub async fn cleanup_branches(
+    context: &Context,
+    repo: &Repo,
+    repo_path: &str,
+    rule: &CleanupRule,
+    dry_run: bool,
+    now: NaiveDateTime,
+) -> Result<Vec<BranchCleanup>, MegaError> {
+    let storage = context.services.mega_storage.clone();
+    let mut scheduled: HashMap<String, mega_branch_cleanup::Model> = storage
+        .get_branch_cleanups(repo)
+        .await?
+        .into_iter()
+        .map(|s| (s.ref_name.clone(), s))
+        .collect();
+    if !rule.is_enabled() && scheduled.is_empty() {
+        return Ok(Vec::new());
+    }
+    let refs = storage.get_repo_refs(repo).await?;
+    let branches: Vec<&refs::Model> = refs
+        .iter()
+        .filter(|r| r.ref_name.starts_with("refs/heads/") && r.ref_name != DEFAULT_BRANCH)
+        .collect();
+    let merged = if rule.merged && !branches.is_empty() {
+        merged_commits(context, repo, &refs).await?
+    } else {
+        CommitGraph::new(Vec::new())
+    };
+
+    let mut report = Vec::new();
+    for branch in branches {
+        let is_merged = SHA1::from_str(&branch.ref_git_id).is_ok_and(|id| merged.contains(&id));
+        let reason = candidate_reason(rule, branch, is_merged, now);
+        let schedule = scheduled.remove(&branch.ref_name);
+        let Some((action, reason, schedule)) = plan(reason, schedule, &branch.ref_git_id, now)
+        else {
+            continue;
+        };
+        let cleanup = match action {
+            CleanupAction::Notify => {
+                let owner = branch_owner(context, repo, branch).await?;
+                let model = mega_branch_cleanup::Model {
+                    id: generate_id(),
+                    repo_id: repo.repo_id,
+                    ref_name: branch.ref_name.clone(),
+                    ref_git_id: branch.ref_git_id.clone(),
+                    reason: reason.as_str().to_owned(),
+                    owner,
+                    delete_after: now + Duration::days(rule.grace_days.into()),
+                    created_at: now,
+                };
+                if !dry_run {
+                    if let Some(previous) = &schedule {
+                        storage.remove_branch_cleanup(previous.id).await?;
+                    }
+                    storage.save_branch_cleanup(model.clone()).await?;
+                }
+                report_entry(repo_path, &model, reason, action)
+            }
+            CleanupAction::Wait => {
+                let schedule = schedule.unwrap();
+                report_entry(repo_path, &schedule, reason, action)
+            }
+            CleanupAction::Delete => {
+                let schedule = schedule.unwrap();
+                if !dry_run {
+                    delete_branch(context, repo, repo_path, branch).await?;
+                    storage.remove_branch_cleanup(schedule.id).await?;
+                }
+                report_entry(repo_path, &schedule, reason, action)
+            }
+            CleanupAction::Cancel => {
+                let schedule = schedule.unwrap();
+                if !dry_run {
+                    storage.remove_branch_cleanup(schedule.id).await?;
+                }
+                report_entry(repo_path, &schedule, reason, action)
+            }
+        };
+        report.push(cleanup);
+    }
+    // the branches deleted by a push
+    if !dry_run {
+        for schedule in scheduled.into_values() {
+            storage.remove_branch_cleanup(schedule.id).await?;
+        }
+    }
+    Ok(report)
+}
+
+/// Why `branch` should be removed under `rule`, if it should.
+fn candidate_reason(
+    rule: &CleanupRule,
+    branch: &refs::Model,
+    is_merged: bool,
+    now: NaiveDateTime,
+) -> Option<CleanupReason> {
+    if rule.merged && is_merged {
+        return Some(CleanupReason::Merged);
+    }
+    let stale =
+        rule.stale_days > 0 && branch.updated_at < now - Duration::days(rule.stale_days.into());
+    stale.then_some(CleanupReason::Stale)
+}
+
+/// The action for a branch at `tip` removable for `reason` with the deletion `schedule`,
+/// and the schedule it acts on. Schedules of another tip are replaced.
+fn plan(
+    reason: Option<CleanupReason>,
+    schedule: Option<mega_branch_cleanup::Model>,
+    tip: &str,
+    now: NaiveDateTime,
+) -> Option<(
+    CleanupAction,
+    CleanupReason,
+    Option<mega_branch_cleanup::Model>,
+)> {
+    match (reason, schedule) {
+        (None, None) => None,
+        (None, Some(schedule)) => {
+            let reason = schedule.reason.parse().unwrap_or(CleanupReason::Stale);
+            Some((CleanupAction::Cancel, reason, Some(schedule)))
+        }
+        (Some(reason), Some(schedule)) if schedule.ref_git_id == tip => {
+            let action = if schedule.delete_after <= now {
+                CleanupAction::Delete
+            } else {
+                CleanupAction::Wait
+            };
+            Some((action, reason, Some(schedule)))
+        }
+        (Some(reason), schedule) => Some((CleanupAction::Notify, reason, schedule)),
+    }
+}
+
+/// The history of the default branch.
+async fn merged_commits(
+    context: &Context,
+    repo: &Repo,
+    refs: &[refs::Model],
+) -> Result<CommitGraph, MegaError> {
+    let tip = refs
+        .iter()
+        .find(|r| r.ref_name == DEFAULT_BRANCH)
+        .and_then(|r| SHA1::from_str(&r.ref_git_id).ok());
+    let Some(tip) = tip else {
+        return Ok(CommitGraph::new(Vec::new()));
+    };
+    compare::load_graph(context, repo, &[tip]).await
+}
+
+/// The user of the push that set the current tip of `branch`.
+async fn branch_owner(
+    context: &Context,
+    repo: &Repo,
+    branch: &refs::Model,
+) -> Result<Option<String>, MegaError> {
+    let filter = OperationFilter {
+        // the operations on the monorepo are recorded with the path of the pushed directory
+        repo_path: (repo.repo_id != 0).then(|| repo.repo_path.clone()),
+        operation: Some(OperationType::Push),
+        since: Some(branch.updated_at),
+        ..Default::default()
+    };
+    let operations = context
+        .services
+        .audit_storage
+        .get_operations(filter)
+        .await?;
+    Ok(operations
+        .into_iter()
+        .rev()
+        .find(|op| op.refs.split(',').any(|name| name == branch.ref_name))
+        .and_then(|op| op.user_name))
+}
+
+async fn delete_branch(
+    context: &Context,
+    repo: &Repo,
+    repo_path: &str,
+    branch: &refs::Model,
+) -> Result<(), MegaError> {
+    let command = RefCommand::new(
+        branch.ref_git_id.clone(),
+        ZERO_ID.to_owned(),
+        branch.ref_name.clone(),
+    );
+    context
+        .services
+        .mega_storage
+        .handler_refs(repo, &command)
+        .await;
+    let event = RefUpdateEvent {
+        id: generate_id(),
+        repo_id: repo.repo_id,
+        repo_path: repo_path.to_owned(),
+        ref_name: command.ref_name,
+        old_id: command.old_id,
+        new_id: command.new_id,
+        pusher: None,
+        created_at: chrono::Utc::now().naive_utc(),
+    };
+    context.events.publish(context, vec![event]).await
+}
+
+fn report_entry(
+    repo_path: &str,
+    schedule: &mega_branch_cleanup::Model,
+    reason: CleanupReason,
+    action: CleanupAction,
+) -> BranchCleanup {
+    BranchCleanup {
+        repo_path: repo_path.to_owned(),
+        ref_name: schedule.ref_name.clone(),
+        commit_id: schedule.ref_git_id.clone(),
+        reason,
+        owner: schedule.owner.clone(),
+        delete_after: schedule.delete_after.and_utc().timestamp(),
+        action,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, NaiveDateTime};
+
+    use callisto::mega_branch_cleanup;
+
+    use super::{plan, CleanupAction, CleanupReason};
+
+    fn schedule(tip: &str, delete_after: NaiveDateTime) -> mega_branch_cleanup::Model {
+        mega_branch_cleanup::Model {
+            id: 1,
+            repo_id: 0,
+            ref_name: String::from("refs/heads/feature"),
+            ref_git_id: tip.to_owned(),
+            reason: String::from("merged"),
+            owner: Some(String::from("alice")),
+            delete_after,
+            created_at: delete_after - Duration::days(7),
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let now = chrono::Utc::now().naive_utc();
+        let merged = Some(CleanupReason::Merged);
+        let action = |reason, schedule| plan(reason, schedule, "a1", now).map(|(a, r, _)| (a, r));
+
+        assert_eq!(action(None, None), None);
+        assert_eq!(
+            action(merged, None),
+            Some((CleanupAction::Notify, CleanupReason::Merged))
+        );
+        let waiting = schedule("a1", now + Duration::days(1));
+        assert_eq!(
+            action(merged, Some(waiting.clone())),
+            Some((CleanupAction::Wait, CleanupReason::Merged))
+        );
+        assert_eq!(
+            action(None, Some(waiting)),
+            Some((CleanupAction::Cancel, CleanupReason::Merged))
+        );
+        let due = schedule("a1", now - Duration::days(1));
+        assert_eq!(
+            action(merged, Some(due)),
+            Some((CleanupAction::Delete, CleanupReason::Merged))
+        );
+        // pushed to during the grace period
+        let updated = schedule("b2", now - Duration::days(1));
+        assert_eq!(
+            action(Some(CleanupReason::Stale), Some(updated)),
+            Some((CleanupAction::Notify, CleanupReason::Stale))
+        );
+    }
+}
@@ -0,0 +1,288 @@
+//!
+//! Moves the state of a repository in and out of mega as a git bundle, see
+//! [`mercury::internal::bundle`], for the machines no network protocol reaches. A bundle is
+//! created from the refs of an imported repository or a directory of the monorepo, with the
+//! commits of `basis` as prerequisites for an incremental one. Unbundling feeds the pack and
+//! the refs of the bundle to receive-pack, so they are checked as those of a push.
+//!
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bytes::{Bytes, BytesMut};
+use serde::Serialize;
+
+use common::errors::MegaError;
+use common::utils::ZERO_ID;
+use jupiter::context::Context;
+use mercury::internal::bundle::{
+    write_bundle, BundleHeader, BundleRef, BundleVersion, Prerequisite,
+};
+use venus::hash::SHA1;
+use venus::repo::Repo;
+
+use crate::protocol::pack::{add_pkt_line_string, read_pkt_line, PKT_LINE_END_MARKER};
+use crate::protocol::{PackProtocol, Protocol};
+use crate::replace::{self, Replacements};
+
+/// The update of a ref of an unbundled bundle.
+#[derive(Debug, Clone, Serialize)]
+pub struct UnbundledRef {
+    pub ref_name: String,
+    pub old_id: String,
+    pub new_id: String,
+    /// Why the update was refused, None once the ref points to `new_id`.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UnbundleReport {
+    pub refs: Vec<UnbundledRef>,
+}
+
+/// Writes to `writer` a bundle of the refs of `repo_path` named in `refs`, all of them when
+/// none is named. A ref is named by its full name or the name of a branch or a tag. With
+/// `basis`, only the objects the receiver lacks on top of these commits are bundled.
+pub async fn create_bundle<W: Write>(
+    context: &Context,
+    repo_path: &str,
+    refs: &[String],
+    basis: &[String],
+    version: BundleVersion,
+    writer: &mut W,
+) -> Result<BundleHeader, MegaError> {
+    let repo = find_repo(context, repo_path).await?;
+    let stored = context.services.mega_storage.get_repo_refs(&repo).await?;
+    if let Some(name) = refs
+        .iter()
+        .find(|name| !stored.iter().any(|r| matches_ref(name, &r.ref_name)))
+    {
+        return Err(MegaError::with_message(&format!("ref not found: {}", name)));
+    }
+    let mut header = BundleHeader::new(version);
+    for r in stored
+        .iter()
+        .filter(|r| refs.is_empty() || refs.iter().any(|name| matches_ref(name, &r.ref_name)))
+    {
+        header.references.push(BundleRef {
+            id: parse_id(&r.ref_git_id)?,
+            name: r.ref_name.clone(),
+        });
+    }
+    if header.references.is_empty() {
+        return Err(MegaError::with_message(&format!(
+            "no ref to bundle in {}",
+            repo_path
+        )));
+    }
+    header.references.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let replacements = Replacements::load(context, &repo).await?;
+    for id in basis {
+        let id = parse_id(id)?;
+        let commit = replace::read_commit(context, &repo, &replacements, &id)
+            .await?
+            .ok_or_else(|| {
+                MegaError::with_message(&format!("commit not found: {}", id.to_plain_str()))
+            })?;
+        header.prerequisites.push(Prerequisite {
+            id,
+            comment: subject(&commit.message),
+        });
+    }
+
+    let pack_protocol =
+        PackProtocol::new(PathBuf::from(repo_path), context.clone(), Protocol::Local);
+    let pack = if basis.is_empty() {
+        pack_protocol.get_full_pack_data(&pack_protocol.path).await
+    } else {
+        let want = header
+            .references
+            .iter()
+            .map(|r| r.id.to_plain_str())
+            .collect();
+        pack_protocol
+            .get_incremental_pack_data(want, basis.to_vec())
+            .await
+    }
+    .map_err(|e| MegaError::with_message(&e.to_string()))?;
+    write_bundle(writer, &header, &pack)?;
+    Ok(header)
+}
+
+/// Reads a bundle from `reader` into `repo_path`, its refs are updated as by a push. Refs
+/// outside of `refs/`, such as the `HEAD` of a bundle made by git, are skipped.
+pub async fn unbundle<R: BufRead>(
+    context: &Context,
+    repo_path: &str,
+    reader: &mut R,
+) -> Result<UnbundleReport, MegaError> {
+    let header = BundleHeader::read(reader).map_err(|e| MegaError::with_message(&e.to_string()))?;
+    if let Some(filter) = header.filter() {
+        return Err(MegaError::with_message(&format!(
+            "the bundle is partial (filter {}), its objects cannot be stored",
+            filter
+        )));
+    }
+    let repo = find_repo(context, repo_path).await?;
+    let replacements = Replacements::load(context, &repo).await?;
+    let mut missing = Vec::new();
+    for prerequisite in &header.prerequisites {
+        if replace::read_commit(context, &repo, &replacements, &prerequisite.id)
+            .await?
+            .is_none()
+        {
+            missing.push(prerequisite.id.to_plain_str());
+        }
+    }
+    if !missing.is_empty() {
+        return Err(MegaError::with_message(&format!(
+            "the repository lacks the prerequisite commits: {}",
+            missing.join(", ")
+        )));
+    }
+    let mut pack = Vec::new();
+    reader.read_to_end(&mut pack)?;
+
+    let current: HashMap<String, String> = context
+        .services
+        .mega_storage
+        .get_repo_refs(&repo)
+        .await?
+        .into_iter()
+        .map(|r| (r.ref_name, r.ref_git_id))
+        .collect();
+    let mut refs: Vec<UnbundledRef> = header
+        .references
+        .iter()
+        .filter(|r| r.name.starts_with("refs/"))
+        .map(|r| UnbundledRef {
+            ref_name: r.name.clone(),
+            old_id: current
+                .get(&r.name)
+                .cloned()
+                .unwrap_or_else(|| ZERO_ID.to_owned()),
+            new_id: r.id.to_plain_str(),
+            error: None,
+        })
+        .collect();
+    // the refs already up to date are not sent
+    let updates: Vec<&UnbundledRef> = refs.iter().filter(|r| r.old_id != r.new_id).collect();
+    if updates.is_empty() {
+        return Ok(UnbundleReport { refs });
+    }
+
+    let mut request = BytesMut::new();
+    for (n, update) in updates.iter().enumerate() {
+        let caps = if n == 0 { "\0report-status" } else { "" };
+        add_pkt_line_string(
+            &mut request,
+            format!(
+                "{} {} {}{}\n",
+                update.old_id, update.new_id, update.ref_name, caps
+            ),
+        );
+    }
+    request.extend_from_slice(PKT_LINE_END_MARKER);
+    request.extend_from_slice(&pack);
+    let mut pack_protocol =
+        PackProtocol::new(PathBuf::from(repo_path), context.clone(), Protocol::Local);
+    let mut report = pack_protocol
+        .git_receive_pack(request.freeze())
+        .await
+        .map_err(|e| MegaError::with_message(&e.to_string()))?;
+    let statuses = parse_report(&mut report);
+    for r in refs.iter_mut().filter(|r| r.old_id != r.new_id) {
+        r.error = match statuses.get(&r.ref_name) {
+            Some(status) => status.clone(),
+            None => Some(String::from("no status reported")),
+        };
+    }
+    Ok(UnbundleReport { refs })
+}
+
+async fn find_repo(context: &Context, repo_path: &str) -> Result<Repo, MegaError> {
+    Ok(context
+        .services
+        .mega_storage
+        .find_git_repo(repo_path)
+        .await?
+        .map(Repo::from)
+        .unwrap_or_else(Repo::empty))
+}
+
+/// Whether `name`, given by the user, names the ref `ref_name`.
+pub(crate) fn matches_ref(name: &str, ref_name: &str) -> bool {
+    ref_name == name
+        || ref_name.strip_prefix("refs/heads/") == Some(name)
+        || ref_name.strip_prefix("refs/tags/") == Some(name)
+}
+
+fn parse_id(id: &str) -> Result<SHA1, MegaError> {
+    SHA1::from_str(id).map_err(|_| MegaError::with_message(&format!("invalid object id: {}", id)))
+}
+
+/// The first line of a commit message, the comment of a prerequisite.
+fn subject(message: &str) -> String {
+    message.trim().lines().next().unwrap_or_default().to_owned()
+}
+
+/// The status of every ref in the report of receive-pack, None for an updated ref.
+fn parse_report(report: &mut Bytes) -> HashMap<String, Option<String>> {
+    let mut statuses = HashMap::new();
+    loop {
+        let (bytes_take, line) = read_pkt_line(report);
+        if bytes_take == 0 {
+            break;
+        }
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end();
+        if let Some(ref_name) = line.strip_prefix("ok ") {
+            statuses.insert(ref_name.to_owned(), None);
+        } else if let Some(rest) = line.strip_prefix("ng ") {
+            let (ref_name, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+            statuses.insert(ref_name.to_owned(), Some(reason.to_owned()));
+        }
+    }
+    statuses
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+
+    use crate::protocol::pack::{add_pkt_line_string, PKT_LINE_END_MARKER};
+
+    use super::{matches_ref, parse_report, subject};
+
+    #[test]
+    fn test_matches_ref() {
+        assert!(matches_ref("main", "refs/heads/main"));
+        assert!(matches_ref("v1.0", "refs/tags/v1.0"));
+        assert!(matches_ref("refs/heads/main", "refs/heads/main"));
+        assert!(!matches_ref("heads/main", "refs/heads/main"));
+        assert!(!matches_ref("main", "refs/heads/feature/main"));
+        assert_eq!(subject("\nFix the build\n\nDetails\n"), "Fix the build");
+        assert_eq!(subject(""), "");
+    }
+
+    #[test]
+    fn test_parse_report() {
+        let mut buf = BytesMut::new();
+        add_pkt_line_string(&mut buf, String::from("unpack ok\n"));
+        add_pkt_line_string(&mut buf, String::from("ok refs/heads/main\n"));
+        add_pkt_line_string(
+            &mut buf,
+            String::from("ng refs/heads/locked the ref is locked\n"),
+        );
+        buf.extend_from_slice(PKT_LINE_END_MARKER);
+        let statuses = parse_report(&mut Bytes::from(buf));
+        assert_eq!(statuses["refs/heads/main"], None);
+        assert_eq!(
+            statuses["refs/heads/locked"],
+            Some(String::from("the ref is locked"))
+        );
+        assert_eq!(statuses.len(), 2);
+    }
+}
@@ -0,0 +1,427 @@
+//!
+//! Commands and notifications of the chat rooms of a team. A Slack slash command, or a Matrix
+//! bot relaying the messages of a room, posts commands like:
+//!
+//! ```text
+//! /mega mr merge 123
+//! ```
+//!
+//! The chat account sending it is mapped to a mega user by `MEGA_CHATOPS_USERS`, e.g.
+//! `slack:U024BE7LH=alice,matrix:@bob:example.org=bob`, and the command runs with the
+//! permissions of this user. The ref updates are announced in the rooms, see
+//! [`ref_update_message`].
+//!
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use hmac::Mac;
+
+use callisto::db_enums::MergeStatus;
+use callisto::mega_mr;
+use common::errors::MegaError;
+use common::utils::ZERO_ID;
+use jupiter::context::Context;
+use jupiter::events::RefUpdateEvent;
+
+use crate::email_reply::mac;
+
+/// The merge requests `mr list` shows.
+const LIST_LIMIT: u64 = 10;
+/// A signed Slack request older than this is refused, so that it can not be replayed.
+const SLACK_MAX_AGE_SECS: i64 = 5 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChatPlatform {
+    Slack,
+    Matrix,
+}
+
+impl ChatPlatform {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatPlatform::Slack => "slack",
+            ChatPlatform::Matrix => "matrix",
+        }
+    }
+}
+
+impl FromStr for ChatPlatform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "slack" => Ok(ChatPlatform::Slack),
+            "matrix" => Ok(ChatPlatform::Matrix),
+            _ => Err(format!("unknown chat platform: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatCommand {
+    Help,
+    MrShow(i64),
+    /// The last updated merge requests, with a status or all of them.
+    MrList(Option<MergeStatus>),
+    MrMerge(i64),
+    MrClose(i64),
+}
+
+impl ChatCommand {
+    /// Whether the command changes something, it needs the write permission then.
+    pub fn is_write(&self) -> bool {
+        matches!(self, ChatCommand::MrMerge(_) | ChatCommand::MrClose(_))
+    }
+}
+
+impl FromStr for ChatCommand {
+    type Err = String;
+
+    /// Parses the text of a command, with or without its `/mega` or `!mega` prefix.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace().peekable();
+        if words
+            .peek()
+            .is_some_and(|word| *word == "/mega" || *word == "!mega")
+        {
+            words.next();
+        }
+        let words: Vec<&str> = words.collect();
+        let mr_id = |id: &str| {
+            id.trim_start_matches('#')
+                .parse::<i64>()
+                .map_err(|_| format!("invalid merge request id: {}", id))
+        };
+        match words.as_slice() {
+            [] | ["help"] => Ok(ChatCommand::Help),
+            ["mr", "show", id] => Ok(ChatCommand::MrShow(mr_id(id)?)),
+            ["mr", "merge", id] => Ok(ChatCommand::MrMerge(mr_id(id)?)),
+            ["mr", "close", id] => Ok(ChatCommand::MrClose(mr_id(id)?)),
+            ["mr", "list"] => Ok(ChatCommand::MrList(None)),
+            ["mr", "list", status] => match *status {
+                "open" => Ok(ChatCommand::MrList(Some(MergeStatus::Open))),
+                "merged" => Ok(ChatCommand::MrList(Some(MergeStatus::Merged))),
+                "closed" => Ok(ChatCommand::MrList(Some(MergeStatus::Closed))),
+                _ => Err(format!("unknown status: {}", status)),
+            },
+            _ => Err(format!("unknown command: {}, try help", words.join(" "))),
+        }
+    }
+}
+
+/// The mega users of the chat accounts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChatUsers(HashMap<(ChatPlatform, String), String>);
+
+impl ChatUsers {
+    /// The users of `MEGA_CHATOPS_USERS`, none when it is unset or invalid.
+    pub fn from_env() -> Self {
+        let value = std::env::var("MEGA_CHATOPS_USERS").unwrap_or_default();
+        value.parse().unwrap_or_else(|err| {
+            tracing::error!("invalid MEGA_CHATOPS_USERS: {}", err);
+            ChatUsers::default()
+        })
+    }
+
+    pub fn user(&self, platform: ChatPlatform, account: &str) -> Option<&str> {
+        self.0
+            .get(&(platform, account.to_owned()))
+            .map(String::as_str)
+    }
+}
+
+impl FromStr for ChatUsers {
+    type Err = String;
+
+    /// Parses `<platform>:<account>=<user>,...`, the account of Matrix has colons of its own.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut users = HashMap::new();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (account, user) = item
+                .rsplit_once('=')
+                .ok_or_else(|| format!("expected <platform>:<account>=<user>: {}", item))?;
+            let (platform, account) = account
+                .split_once(':')
+                .ok_or_else(|| format!("expected <platform>:<account>=<user>: {}", item))?;
+            users.insert(
+                (platform.trim().parse()?, account.trim().to_owned()),
+                user.trim().to_owned(),
+            );
+        }
+        Ok(ChatUsers(users))
+    }
+}
+
+/// Whether `signature`, the `X-Slack-Signature` header, signs `body` sent at `timestamp`, the
+/// `X-Slack-Request-Timestamp` header, with the signing secret of the Slack app. `now` is in
+/// seconds since the epoch.
+pub fn verify_slack_signature(
+    secret: &str,
+    timestamp: &str,
+    body: &[u8],
+    signature: &str,
+    now: i64,
+) -> bool {
+    let Ok(sent) = timestamp.parse::<i64>() else {
+        return false;
+    };
+    if (now - sent).abs() > SLACK_MAX_AGE_SECS {
+        return false;
+    }
+    let Some(Ok(signature)) = signature.strip_prefix("v0=").map(hex::decode) else {
+        return false;
+    };
+    let mut mac = mac(secret.as_bytes(), format!("v0:{}:", timestamp).as_bytes());
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Whether the `Authorization` header of a request of the Matrix bot bears `token`.
+pub fn verify_bearer_token(token: &str, authorization: &str) -> bool {
+    authorization
+        .strip_prefix("Bearer ")
+        .is_some_and(|sent| !token.is_empty() && constant_time_eq(token, sent.trim()))
+}
+
+/// Compares secrets in constant time, the time taken would tell how much of a forgery is right.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Runs `command` for `user`, whose permissions are checked by the caller, and returns the
+/// reply to post in the room.
+pub async fn execute(
+    context: &Context,
+    user: &str,
+    command: &ChatCommand,
+) -> Result<String, MegaError> {
+    let storage = &context.services.mega_storage;
+    match command {
+        ChatCommand::Help => Ok(String::from(
+            "mr list [open|merged|closed]: the last updated merge requests\n\
+             mr show <id>: a merge request\n\
+             mr merge <id>: merges an open merge request\n\
+             mr close <id>: closes an open merge request",
+        )),
+        ChatCommand::MrShow(id) => match storage.get_mr(*id).await? {
+            Some(mr) => Ok(MrLine(&mr).to_string()),
+            None => Ok(format!("Merge request {} not found", id)),
+        },
+        ChatCommand::MrList(status) => {
+            let mrs = storage.get_mrs(*status, Some(LIST_LIMIT)).await?;
+            if mrs.is_empty() {
+                return Ok(String::from("No merge request"));
+            }
+            Ok(mrs
+                .iter()
+                .map(|mr| MrLine(mr).to_string())
+                .collect::<Vec<_>>()
+                .join("\n"))
+        }
+        ChatCommand::MrMerge(id) => {
+            storage.set_mr_status(*id, MergeStatus::Merged).await?;
+            Ok(format!("Merge request {} merged by {}", id, user))
+        }
+        ChatCommand::MrClose(id) => {
+            storage.set_mr_status(*id, MergeStatus::Closed).await?;
+            Ok(format!("Merge request {} closed by {}", id, user))
+        }
+    }
+}
+
+/// A merge request on one line of a reply.
+struct MrLine<'a>(&'a mega_mr::Model);
+
+impl fmt::Display for MrLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mr = self.0;
+        let status = match mr.status {
+            MergeStatus::Open => "open",
+            MergeStatus::Merged => "merged",
+            MergeStatus::Closed => "closed",
+        };
+        write!(f, "#{} [{}]", mr.id, status)?;
+        if let Some(message) = mr.mr_msg.as_deref().and_then(|m| m.lines().next()) {
+            write!(f, " {}", message)?;
+        }
+        write!(f, " (updated {})", mr.updated_at.format("%Y-%m-%d %H:%M"))
+    }
+}
+
+/// A notification in the forms the chats render.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub text: String,
+    /// Slack `mrkdwn`.
+    pub markdown: String,
+    /// The HTML subset of Matrix.
+    pub html: String,
+}
+
+/// The announcement of a ref update.
+pub fn ref_update_message(event: &RefUpdateEvent) -> ChatMessage {
+    let pusher = event.pusher.as_deref().unwrap_or("someone");
+    let short = |id: &str| id[..id.len().min(7)].to_owned();
+    let (action, ids) = if event.old_id == ZERO_ID {
+        ("created", short(&event.new_id))
+    } else if event.new_id == ZERO_ID {
+        ("deleted", short(&event.old_id))
+    } else {
+        (
+            "updated",
+            format!("{}..{}", short(&event.old_id), short(&event.new_id)),
+        )
+    };
+    ChatMessage {
+        text: format!(
+            "{} {} {} in {} ({})",
+            pusher, action, event.ref_name, event.repo_path, ids
+        ),
+        markdown: format!(
+            "*{}* {} `{}` in `{}` (`{}`)",
+            pusher, action, event.ref_name, event.repo_path, ids
+        ),
+        html: format!(
+            "<b>{}</b> {} <code>{}</code> in <code>{}</code> (<code>{}</code>)",
+            escape_html(pusher),
+            action,
+            escape_html(&event.ref_name),
+            escape_html(&event.repo_path),
+            ids
+        ),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use callisto::db_enums::MergeStatus;
+    use common::utils::ZERO_ID;
+    use jupiter::events::RefUpdateEvent;
+
+    use super::{
+        ref_update_message, verify_bearer_token, verify_slack_signature, ChatCommand, ChatPlatform,
+        ChatUsers,
+    };
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!("/mega mr merge 123".parse(), Ok(ChatCommand::MrMerge(123)));
+        assert_eq!("!mega mr close #7".parse(), Ok(ChatCommand::MrClose(7)));
+        assert_eq!(" mr  show 42 ".parse(), Ok(ChatCommand::MrShow(42)));
+        assert_eq!(
+            "mr list open".parse(),
+            Ok(ChatCommand::MrList(Some(MergeStatus::Open)))
+        );
+        assert_eq!("mr list".parse(), Ok(ChatCommand::MrList(None)));
+        assert_eq!("/mega".parse(), Ok(ChatCommand::Help));
+        assert!("mr merge abc".parse::<ChatCommand>().is_err());
+        assert!("mr list stale".parse::<ChatCommand>().is_err());
+        assert!("deploy".parse::<ChatCommand>().is_err());
+        assert!(ChatCommand::MrMerge(1).is_write());
+        assert!(!ChatCommand::MrShow(1).is_write());
+    }
+
+    #[test]
+    fn test_chat_users() {
+        let users: ChatUsers = "slack:U024BE7LH=alice, matrix:@bob:example.org=bob"
+            .parse()
+            .unwrap();
+        assert_eq!(users.user(ChatPlatform::Slack, "U024BE7LH"), Some("alice"));
+        assert_eq!(
+            users.user(ChatPlatform::Matrix, "@bob:example.org"),
+            Some("bob")
+        );
+        assert_eq!(users.user(ChatPlatform::Matrix, "U024BE7LH"), None);
+        assert_eq!("".parse::<ChatUsers>(), Ok(ChatUsers::default()));
+        assert!("irc:alice=alice".parse::<ChatUsers>().is_err());
+        assert!("slack:U024BE7LH".parse::<ChatUsers>().is_err());
+    }
+
+    #[test]
+    fn test_verify_slack_signature() {
+        // the example of the Slack documentation
+        let secret = "8f742231b10e8888abcd99yyyzzz85a5";
+        let body = b"token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow&channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner&command=%2Fwebhook-collect&text=&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN&trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+        let signature = "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503";
+        let timestamp = "1531420618";
+        assert!(verify_slack_signature(
+            secret,
+            timestamp,
+            body,
+            signature,
+            1531420618 + 60
+        ));
+        // replayed later, or changed
+        assert!(!verify_slack_signature(
+            secret,
+            timestamp,
+            body,
+            signature,
+            1531420618 + 600
+        ));
+        assert!(!verify_slack_signature(
+            secret,
+            timestamp,
+            &body[1..],
+            signature,
+            1531420618
+        ));
+        assert!(!verify_slack_signature(
+            "other", timestamp, body, signature, 1531420618
+        ));
+    }
+
+    #[test]
+    fn test_verify_bearer_token() {
+        assert!(verify_bearer_token("s3cret", "Bearer s3cret"));
+        assert!(!verify_bearer_token("s3cret", "Bearer s3cre"));
+        assert!(!verify_bearer_token("s3cret", "Basic s3cret"));
+        assert!(!verify_bearer_token("", "Bearer "));
+    }
+
+    #[test]
+    fn test_ref_update_message() {
+        let event = RefUpdateEvent {
+            id: 1,
+            repo_id: 0,
+            repo_path: String::from("/project"),
+            ref_name: String::from("refs/heads/main"),
+            old_id: "a".repeat(40),
+            new_id: "b".repeat(40),
+            pusher: Some(String::from("alice")),
+            created_at: NaiveDateTime::default(),
+        };
+        let message = ref_update_message(&event);
+        assert_eq!(
+            message.text,
+            "alice updated refs/heads/main in /project (aaaaaaa..bbbbbbb)"
+        );
+        assert!(message
+            .markdown
+            .starts_with("*alice* updated `refs/heads/main`"));
+
+        let deleted = RefUpdateEvent {
+            new_id: ZERO_ID.to_owned(),
+            ref_name: String::from("refs/heads/<b>"),
+            pusher: None,
+            ..event
+        };
+        let message = ref_update_message(&deleted);
+        assert!(message.text.starts_with("someone deleted refs/heads/<b>"));
+        assert!(message.html.contains("refs/heads/&lt;b&gt;"));
+    }
+}
@@ -0,0 +1,495 @@
+//!
+//! Code navigation: go-to-definition and find-references for the files of a repository of the
+//! monorepo at a commit, answered from a SCIP or LSIF index uploaded by CI for that commit.
+//! The paths of an index are relative to the repository it was uploaded for and the positions
+//! are zero-based, as in LSP. Symbols local to a document only match in that document.
+//!
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use protobuf::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use callisto::mega_code_symbol;
+use common::errors::MegaError;
+use jupiter::context::Context;
+use venus::repo::Repo;
+
+use crate::compare;
+
+/// Largest index accepted in an upload.
+pub const MAX_INDEX_SIZE: usize = 512 * 1024 * 1024;
+
+/// Longest `next` chain followed from a range of an LSIF index to its result set.
+const MAX_LSIF_CHAIN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexFormat {
+    Scip,
+    Lsif,
+}
+
+impl FromStr for IndexFormat {
+    type Err = MegaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scip" => Ok(IndexFormat::Scip),
+            "lsif" => Ok(IndexFormat::Lsif),
+            _ => Err(MegaError::with_message(&format!(
+                "unknown index format {}, expected scip or lsif",
+                s
+            ))),
+        }
+    }
+}
+
+impl IndexFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            IndexFormat::Scip => "scip",
+            IndexFormat::Lsif => "lsif",
+        }
+    }
+}
+
+/// A range of a file where a symbol is defined or referenced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Occurrence {
+    pub path: String,
+    pub symbol: String,
+    /// Start line, start character, end line and end character, the end excluded.
+    pub range: [i32; 4],
+    pub definition: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Location {
+    pub path: String,
+    pub start_line: i32,
+    pub start_character: i32,
+    pub end_line: i32,
+    pub end_character: i32,
+}
+
+impl From<mega_code_symbol::Model> for Location {
+    fn from(value: mega_code_symbol::Model) -> Self {
+        Location {
+            path: value.path,
+            start_line: value.start_line,
+            start_character: value.start_character,
+            end_line: value.end_line,
+            end_character: value.end_character,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexSummary {
+    pub commit: String,
+    pub format: String,
+    pub occurrences: i64,
+    pub created_at: String,
+}
+
+/// Saves the index of the commit `refs` of the repository at `repo_path`, replacing the one
+/// uploaded before for the commit.
+pub async fn upload(
+    context: &Context,
+    repo_path: &str,
+    refs: &str,
+    format: IndexFormat,
+    data: &[u8],
+) -> Result<IndexSummary, MegaError> {
+    if !context.settings.current().code_navigation {
+        return Err(MegaError::with_message("code navigation is disabled"));
+    }
+    let (repo, commit_id) = resolve_commit(context, repo_path, refs).await?;
+    let occurrences = match format {
+        IndexFormat::Scip => parse_scip(data)?,
+        IndexFormat::Lsif => parse_lsif(data)?,
+    };
+    let symbols = occurrences
+        .into_iter()
+        .map(|occurrence| mega_code_symbol::Model {
+            index_id: 0,
+            seq: 0,
+            path: occurrence.path,
+            symbol: occurrence.symbol,
+            start_line: occurrence.range[0],
+            start_character: occurrence.range[1],
+            end_line: occurrence.range[2],
+            end_character: occurrence.range[3],
+            is_definition: occurrence.definition,
+        })
+        .collect();
+    let index = context
+        .services
+        .code_nav_storage
+        .save_index(repo.repo_id, &commit_id, format.as_str(), symbols)
+        .await?;
+    Ok(IndexSummary {
+        commit: index.commit_id,
+        format: index.format,
+        occurrences: index.occurrences,
+        created_at: index.created_at.and_utc().to_rfc3339(),
+    })
+}
+
+/// The definitions of the symbol at `line` and `character` of the file `path`.
+pub async fn definitions(
+    context: &Context,
+    repo_path: &str,
+    refs: &str,
+    path: &str,
+    line: i32,
+    character: i32,
+) -> Result<Vec<Location>, MegaError> {
+    occurrences(context, repo_path, refs, path, (line, character), true).await
+}
+
+/// The occurrences of the symbol at `line` and `character` of the file `path`, its
+/// definitions included.
+pub async fn references(
+    context: &Context,
+    repo_path: &str,
+    refs: &str,
+    path: &str,
+    line: i32,
+    character: i32,
+) -> Result<Vec<Location>, MegaError> {
+    occurrences(context, repo_path, refs, path, (line, character), false).await
+}
+
+async fn occurrences(
+    context: &Context,
+    repo_path: &str,
+    refs: &str,
+    path: &str,
+    position: (i32, i32),
+    definitions_only: bool,
+) -> Result<Vec<Location>, MegaError> {
+    let (repo, commit_id) = resolve_commit(context, repo_path, refs).await?;
+    let storage = &context.services.code_nav_storage;
+    let index = storage
+        .get_index(repo.repo_id, &commit_id)
+        .await?
+        .ok_or_else(|| {
+            MegaError::with_message(&format!("no code navigation index for {}", commit_id))
+        })?;
+    let path = path.trim_start_matches('/');
+    // the innermost range holding the position, e.g. a method call in its arguments
+    let symbol = storage
+        .get_symbols_at(index.id, path, position.0)
+        .await?
+        .into_iter()
+        .filter(|s| {
+            (s.start_line, s.start_character) <= position
+                && position < (s.end_line, s.end_character)
+        })
+        .max_by_key(|s| {
+            (
+                s.start_line,
+                s.start_character,
+                -s.end_line,
+                -s.end_character,
+            )
+        })
+        .map(|s| s.symbol);
+    let Some(symbol) = symbol else {
+        return Ok(Vec::new());
+    };
+    Ok(storage
+        .get_occurrences(index.id, &symbol, definitions_only)
+        .await?
+        .into_iter()
+        .map(Location::from)
+        .collect())
+}
+
+async fn resolve_commit(
+    context: &Context,
+    repo_path: &str,
+    refs: &str,
+) -> Result<(Repo, String), MegaError> {
+    let storage = context.services.mega_storage.clone();
+    let repo: Repo = storage
+        .find_git_repo(repo_path)
+        .await?
+        .ok_or_else(|| MegaError::with_message(&format!("repository not found: {}", repo_path)))?
+        .into();
+    let names: HashMap<String, String> = storage
+        .get_repo_refs(&repo)
+        .await?
+        .into_iter()
+        .map(|r| (r.ref_name, r.ref_git_id))
+        .collect();
+    let commit_id = compare::resolve(&names, refs)?.to_plain_str();
+    if storage
+        .get_commit_by_hash(&commit_id, &repo)
+        .await?
+        .is_none()
+    {
+        return Err(MegaError::with_message(&format!(
+            "commit not found: {}",
+            refs
+        )));
+    }
+    Ok((repo, commit_id))
+}
+
+/// The occurrences of a SCIP index, a protobuf `scip.Index`.
+pub fn parse_scip(data: &[u8]) -> Result<Vec<Occurrence>, MegaError> {
+    let index = scip::types::Index::parse_from_bytes(data)
+        .map_err(|err| MegaError::with_message(&format!("invalid SCIP index: {}", err)))?;
+    let mut occurrences = Vec::new();
+    for document in index.documents {
+        let path = document.relative_path.trim_start_matches('/').to_owned();
+        for occurrence in document.occurrences {
+            // [start line, start character, end character] on a single line
+            let range = match occurrence.range[..] {
+                [line, start, end] => [line, start, line, end],
+                [start_line, start, end_line, end] => [start_line, start, end_line, end],
+                _ => continue,
+            };
+            if occurrence.symbol.is_empty() {
+                continue;
+            }
+            let symbol = if occurrence.symbol.starts_with("local ") {
+                format!("{} {}", occurrence.symbol, path)
+            } else {
+                occurrence.symbol
+            };
+            occurrences.push(Occurrence {
+                path: path.clone(),
+                symbol,
+                range,
+                definition: occurrence.symbol_roles & scip::types::SymbolRole::Definition as i32
+                    != 0,
+            });
+        }
+    }
+    Ok(occurrences)
+}
+
+/// The occurrences of an LSIF index, in JSON lines or a JSON array. The ranges linked by
+/// `next` edges to the same result set are occurrences of one symbol, named by the moniker of
+/// the result set when it has one. A range is a definition when it is an item of the
+/// definition result of its result set.
+pub fn parse_lsif(data: &[u8]) -> Result<Vec<Occurrence>, MegaError> {
+    let invalid =
+        |err: serde_json::Error| MegaError::with_message(&format!("invalid LSIF index: {}", err));
+    let elements: Vec<Value> = if data.trim_ascii_start().starts_with(b"[") {
+        serde_json::from_slice(data).map_err(invalid)?
+    } else {
+        data.split(|b| *b == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<_, _>>()
+            .map_err(invalid)?
+    };
+
+    let mut root = String::new();
+    let mut documents = HashMap::new();
+    let mut ranges = HashMap::new();
+    let mut contains = Vec::new();
+    let mut next = HashMap::new();
+    let mut monikers = HashMap::new();
+    let mut moniker_of = HashMap::new();
+    let mut definition_result = HashMap::new();
+    let mut items: HashMap<String, Vec<String>> = HashMap::new();
+    let id = |value: &Value| match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    for element in &elements {
+        let label = element["label"].as_str().unwrap_or_default();
+        let out_v = id(&element["outV"]);
+        let in_vs = || {
+            let in_vs = element["inVs"]
+                .as_array()
+                .map(|v| v.iter().map(id).collect());
+            in_vs.unwrap_or_else(|| vec![id(&element["inV"])])
+        };
+        match (element["type"].as_str().unwrap_or_default(), label) {
+            ("vertex", "metaData") => {
+                root = element["projectRoot"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned();
+            }
+            ("vertex", "document") => {
+                let uri = element["uri"].as_str().unwrap_or_default().to_owned();
+                documents.insert(id(&element["id"]), uri);
+            }
+            ("vertex", "range") => {
+                let position = |p: &Value| {
+                    (
+                        p["line"].as_i64().unwrap_or(0) as i32,
+                        p["character"].as_i64().unwrap_or(0) as i32,
+                    )
+                };
+                let (start, end) = (position(&element["start"]), position(&element["end"]));
+                ranges.insert(id(&element["id"]), [start.0, start.1, end.0, end.1]);
+            }
+            ("vertex", "moniker") => {
+                let scheme = element["scheme"].as_str().unwrap_or_default();
+                let identifier = element["identifier"].as_str().unwrap_or_default();
+                monikers.insert(id(&element["id"]), format!("{} {}", scheme, identifier));
+            }
+            ("edge", "contains") => {
+                contains.extend(in_vs().into_iter().map(|range| (range, out_v.clone())));
+            }
+            ("edge", "next") => {
+                next.insert(out_v, id(&element["inV"]));
+            }
+            ("edge", "moniker") => {
+                moniker_of.insert(out_v, id(&element["inV"]));
+            }
+            ("edge", "textDocument/definition") => {
+                definition_result.insert(out_v, id(&element["inV"]));
+            }
+            ("edge", "item") => {
+                items.entry(out_v).or_default().extend(in_vs());
+            }
+            _ => {}
+        }
+    }
+
+    let mut occurrences = Vec::new();
+    for (range_id, document_id) in contains {
+        let (Some(range), Some(uri)) = (ranges.get(&range_id), documents.get(&document_id)) else {
+            continue;
+        };
+        let mut chain = vec![&range_id];
+        while let Some(set) = next.get(*chain.last().unwrap()) {
+            if chain.len() > MAX_LSIF_CHAIN {
+                return Err(MegaError::with_message(
+                    "invalid LSIF index: cyclic next edges",
+                ));
+            }
+            chain.push(set);
+        }
+        let symbol = chain
+            .iter()
+            .find_map(|v| moniker_of.get(*v).and_then(|m| monikers.get(m)))
+            .cloned()
+            .unwrap_or_else(|| format!("lsif {}", chain.last().unwrap()));
+        let definition = chain
+            .iter()
+            .find_map(|v| definition_result.get(*v).and_then(|r| items.get(r)))
+            .is_some_and(|definitions| definitions.contains(&range_id));
+        let path = uri
+            .strip_prefix(&root)
+            .unwrap_or(uri)
+            .trim_start_matches("file://");
+        occurrences.push(Occurrence {
+            path: path.trim_start_matches('/').to_owned(),
+            symbol,
+            range: *range,
+            definition,
+        });
+    }
+    Ok(occurrences)
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::Message;
+    use scip::types::{Document, Index, Occurrence as ScipOccurrence, SymbolRole};
+
+    use super::{parse_lsif, parse_scip, Occurrence};
+
+    fn occurrence(path: &str, symbol: &str, range: [i32; 4], definition: bool) -> Occurrence {
+        Occurrence {
+            path: path.to_owned(),
+            symbol: symbol.to_owned(),
+            range,
+            definition,
+        }
+    }
+
+    #[test]
+    fn test_parse_scip() {
+        let mut document = Document::new();
+        document.relative_path = String::from("src/lib.rs");
+        for (range, symbol, roles) in [
+            (
+                vec![1, 4, 9],
+                "rust-analyzer cargo mega 0.1.0 parse().",
+                SymbolRole::Definition as i32,
+            ),
+            (
+                vec![3, 8, 4, 1],
+                "rust-analyzer cargo mega 0.1.0 parse().",
+                0,
+            ),
+            (vec![2, 8, 9], "local 0", SymbolRole::Definition as i32),
+        ] {
+            let mut occurrence = ScipOccurrence::new();
+            occurrence.range = range;
+            occurrence.symbol = symbol.to_owned();
+            occurrence.symbol_roles = roles;
+            document.occurrences.push(occurrence);
+        }
+        let mut index = Index::new();
+        index.documents.push(document);
+
+        let occurrences = parse_scip(&index.write_to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            occurrences,
+            vec![
+                occurrence(
+                    "src/lib.rs",
+                    "rust-analyzer cargo mega 0.1.0 parse().",
+                    [1, 4, 1, 9],
+                    true
+                ),
+                occurrence(
+                    "src/lib.rs",
+                    "rust-analyzer cargo mega 0.1.0 parse().",
+                    [3, 8, 4, 1],
+                    false
+                ),
+                occurrence("src/lib.rs", "local 0 src/lib.rs", [2, 8, 2, 9], true),
+            ]
+        );
+        assert!(parse_scip(b"\xff\xff").is_err());
+    }
+
+    #[test]
+    fn test_parse_lsif() {
+        let index = r#"
+{"id":1,"type":"vertex","label":"metaData","version":"0.4.3","projectRoot":"file:///ci/mega"}
+{"id":2,"type":"vertex","label":"document","uri":"file:///ci/mega/src/a.ts","languageId":"typescript"}
+{"id":3,"type":"vertex","label":"range","start":{"line":0,"character":9},"end":{"line":0,"character":12}}
+{"id":4,"type":"vertex","label":"range","start":{"line":5,"character":2},"end":{"line":5,"character":5}}
+{"id":5,"type":"vertex","label":"resultSet"}
+{"id":6,"type":"edge","label":"next","outV":3,"inV":5}
+{"id":7,"type":"edge","label":"next","outV":4,"inV":5}
+{"id":8,"type":"vertex","label":"definitionResult"}
+{"id":9,"type":"edge","label":"textDocument/definition","outV":5,"inV":8}
+{"id":10,"type":"edge","label":"item","outV":8,"inVs":[3],"document":2}
+{"id":11,"type":"vertex","label":"moniker","scheme":"tsc","identifier":"a:foo","kind":"export"}
+{"id":12,"type":"edge","label":"moniker","outV":5,"inV":11}
+{"id":13,"type":"vertex","label":"range","start":{"line":7,"character":0},"end":{"line":7,"character":3}}
+{"id":14,"type":"edge","label":"contains","outV":2,"inVs":[3,4,13]}
+"#;
+        let occurrences = parse_lsif(index.as_bytes()).unwrap();
+        assert_eq!(
+            occurrences,
+            vec![
+                occurrence("src/a.ts", "tsc a:foo", [0, 9, 0, 12], true),
+                occurrence("src/a.ts", "tsc a:foo", [5, 2, 5, 5], false),
+                occurrence("src/a.ts", "lsif 13", [7, 0, 7, 3], false),
+            ]
+        );
+
+        // the same index as a JSON array
+        let array = format!("[{}]", index.trim().replace('\n', ","));
+        assert_eq!(parse_lsif(array.as_bytes()).unwrap(), occurrences);
+        assert!(parse_lsif(b"{\"id\":").is_err());
+    }
+}
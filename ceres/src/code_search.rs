@@ -0,0 +1,385 @@
+//!
+//! Index of the files of the branches behind code search. A push only indexes the files it
+//! changed: the tree of the new commit is compared with the tree of the commit the branch is
+//! indexed at, so the latency stays in seconds whatever the size of the monorepo. A branch
+//! without an index, or indexed by an older [`INDEX_VERSION`], is rebuilt from its whole
+//! tree, which the backfill does for every branch when the server starts. The files of the
+//! previous index stay searchable until the rebuild is done.
+//!
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use serde::Serialize;
+
+use callisto::mega_search_file;
+use common::errors::MegaError;
+use common::utils::ZERO_ID;
+use jupiter::context::Context;
+use jupiter::events::{RefUpdateEvent, RefUpdateObserver};
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::repo::Repo;
+
+use crate::compare::{self, ChangeStatus};
+
+/// Version of what the index holds for a file. Bumping it makes the backfill rebuild the
+/// index of every branch.
+pub const INDEX_VERSION: i32 = 1;
+
+/// Larger files are indexed by path only, and so are binary files.
+pub const MAX_INDEXED_FILE_SIZE: usize = 1024 * 1024;
+
+/// Hits returned when the request sets no limit, and the most it can ask for.
+pub const DEFAULT_PAGE_SIZE: u64 = 20;
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+/// Matching lines returned per file.
+const MAX_LINES_PER_FILE: usize = 5;
+
+/// Files saved at once, their contents are held in memory until then.
+const SAVE_BATCH: usize = 500;
+
+const BRANCH_PREFIX: &str = "refs/heads/";
+
+/// How the index of a branch was brought to its tip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexUpdate {
+    UpToDate,
+    /// Only the files changed since the indexed commit were indexed.
+    Updated,
+    Rebuilt,
+}
+
+/// Keeps the index up to date with the pushed branches, unless the `code_search_index`
+/// setting is off.
+pub struct CodeSearchIndexer;
+
+#[async_trait]
+impl RefUpdateObserver for CodeSearchIndexer {
+    fn name(&self) -> &'static str {
+        "code_search_index"
+    }
+
+    async fn on_ref_update(
+        &self,
+        context: &Context,
+        event: &RefUpdateEvent,
+    ) -> Result<(), MegaError> {
+        if !context.settings.current().code_search_index
+            || !event.ref_name.starts_with(BRANCH_PREFIX)
+        {
+            return Ok(());
+        }
+        let repo = context
+            .services
+            .mega_storage
+            .find_git_repo(&event.repo_path)
+            .await?
+            .map(Repo::from)
+            .unwrap_or_else(Repo::empty);
+        index_ref_update(context, &repo, &event.ref_name, &event.new_id).await;
+        Ok(())
+    }
+}
+
+/// Updates the index of `ref_name` now pointing to `new_id`. Failures are only logged, the
+/// next update or the backfill picks the changes up again.
+pub async fn index_ref_update(context: &Context, repo: &Repo, ref_name: &str, new_id: &str) {
+    let result = if new_id == ZERO_ID {
+        context
+            .services
+            .search_storage
+            .remove_ref_index(repo.repo_id, ref_name)
+            .await
+    } else {
+        match SHA1::from_str(new_id) {
+            Ok(new) => index_ref(context, repo, ref_name, new).await.map(|_| ()),
+            Err(_) => return,
+        }
+    };
+    if let Err(err) = result {
+        tracing::error!("failed to update the search index of {}: {}", ref_name, err);
+    }
+}
+
+/// Brings the index of `ref_name` to the commit `new`, from the files changed since the
+/// commit it is indexed at, or from the whole tree of `new`.
+pub async fn index_ref(
+    context: &Context,
+    repo: &Repo,
+    ref_name: &str,
+    new: SHA1,
+) -> Result<IndexUpdate, MegaError> {
+    let storage = context.services.search_storage.clone();
+    let indexed = storage
+        .get_indexed_ref(repo.repo_id, ref_name)
+        .await?
+        .filter(|indexed| indexed.index_version == INDEX_VERSION);
+    if indexed
+        .as_ref()
+        .is_some_and(|indexed| indexed.commit_id == new.to_plain_str())
+    {
+        return Ok(IndexUpdate::UpToDate);
+    }
+    let new_tree = commit_tree(context, repo, &new.to_plain_str())
+        .await?
+        .ok_or_else(|| {
+            MegaError::with_message(&format!("commit not found: {}", new.to_plain_str()))
+        })?;
+    // the indexed commit may be gone, after a forced push and a garbage collection
+    let old_tree = match &indexed {
+        Some(indexed) => commit_tree(context, repo, &indexed.commit_id).await?,
+        None => None,
+    };
+    let rebuild = old_tree.is_none();
+
+    let changes = compare::diff_trees(context, old_tree, Some(new_tree)).await?;
+    let mut removed = Vec::new();
+    let mut changed = Vec::new();
+    for change in changes {
+        match (change.status, change.new_id) {
+            (ChangeStatus::Deleted, _) | (_, None) => removed.push(change.path),
+            (_, Some(blob_id)) => changed.push((change.path, blob_id)),
+        }
+    }
+    for batch in changed.chunks(SAVE_BATCH) {
+        let contents = load_contents(context, batch).await?;
+        let files = batch
+            .iter()
+            .map(|(path, blob_id)| mega_search_file::Model {
+                repo_id: repo.repo_id,
+                ref_name: ref_name.to_owned(),
+                path: path.clone(),
+                blob_id: blob_id.to_plain_str(),
+                commit_id: new.to_plain_str(),
+                content: contents.get(blob_id).cloned(),
+            })
+            .collect();
+        storage.save_files(files).await?;
+    }
+    storage
+        .update_ref_index(
+            repo.repo_id,
+            ref_name,
+            &new.to_plain_str(),
+            INDEX_VERSION,
+            removed,
+            rebuild,
+        )
+        .await?;
+    Ok(if rebuild {
+        IndexUpdate::Rebuilt
+    } else {
+        IndexUpdate::Updated
+    })
+}
+
+async fn commit_tree(
+    context: &Context,
+    repo: &Repo,
+    commit_id: &str,
+) -> Result<Option<SHA1>, MegaError> {
+    Ok(context
+        .services
+        .mega_storage
+        .get_commit_by_hash(commit_id, repo)
+        .await?
+        .map(|model| Commit::from(model).tree_id))
+}
+
+/// The content of the blobs of `files` that are indexed, see [`indexed_content`].
+async fn load_contents(
+    context: &Context,
+    files: &[(String, SHA1)],
+) -> Result<HashMap<SHA1, String>, MegaError> {
+    let ids: Vec<SHA1> = files.iter().map(|(_, id)| *id).collect();
+    Ok(context
+        .services
+        .mega_storage
+        .get_blob_contents(&ids)
+        .await?
+        .into_iter()
+        .filter_map(|(id, data)| Some((id, indexed_content(data)?)))
+        .collect())
+}
+
+/// The content indexed for a blob: its text, unless it is too large or binary.
+fn indexed_content(data: Vec<u8>) -> Option<String> {
+    if data.len() > MAX_INDEXED_FILE_SIZE || data.contains(&0) {
+        return None;
+    }
+    String::from_utf8(data).ok()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BackfillReport {
+    pub up_to_date: usize,
+    pub updated: usize,
+    pub rebuilt: usize,
+    /// Indexes of branches that no longer exist.
+    pub removed: usize,
+    pub failed: usize,
+}
+
+/// Indexes every branch of the monorepo and the imported repositories that is not indexed at
+/// its tip by the current [`INDEX_VERSION`], and removes the indexes of deleted branches.
+pub async fn backfill(context: &Context) -> Result<BackfillReport, MegaError> {
+    let storage = context.services.mega_storage.clone();
+    let mut repos = vec![Repo::empty()];
+    repos.extend(storage.get_git_repos().await?.into_iter().map(Repo::from));
+    let mut branches = HashMap::new();
+    for repo in &repos {
+        for r in storage.get_repo_refs(repo).await? {
+            if r.ref_name.starts_with(BRANCH_PREFIX) {
+                branches.insert((repo.repo_id, r.ref_name), (repo, r.ref_git_id));
+            }
+        }
+    }
+
+    let mut report = BackfillReport::default();
+    let search_storage = context.services.search_storage.clone();
+    for indexed in search_storage.get_indexed_refs().await? {
+        if !branches.contains_key(&(indexed.repo_id, indexed.ref_name.clone())) {
+            search_storage
+                .remove_ref_index(indexed.repo_id, &indexed.ref_name)
+                .await?;
+            report.removed += 1;
+        }
+    }
+    for ((_, ref_name), (repo, tip)) in branches {
+        let Ok(tip) = SHA1::from_str(&tip) else {
+            continue;
+        };
+        match index_ref(context, repo, &ref_name, tip).await {
+            Ok(IndexUpdate::UpToDate) => report.up_to_date += 1,
+            Ok(IndexUpdate::Updated) => report.updated += 1,
+            Ok(IndexUpdate::Rebuilt) => report.rebuilt += 1,
+            Err(err) => {
+                tracing::error!("failed to index {} for search: {}", ref_name, err);
+                report.failed += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CodeHit {
+    pub path: String,
+    pub blob_id: String,
+    /// The first lines matching a term, numbered from 1, none when only the path matches.
+    pub lines: Vec<(usize, String)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CodeSearchResult {
+    pub ref_name: String,
+    /// The commit the branch is indexed at, behind its tip while a push is being indexed.
+    /// Unset when the branch is not indexed yet.
+    pub commit_id: Option<String>,
+    pub offset: u64,
+    pub hits: Vec<CodeHit>,
+}
+
+/// The files of the branch `branch` of the repository at `repo_path`, the monorepo when
+/// unset, whose path or content contains every word of `text`.
+pub async fn search_code(
+    context: &Context,
+    repo_path: Option<&str>,
+    branch: &str,
+    text: &str,
+    offset: u64,
+    limit: Option<u64>,
+) -> Result<CodeSearchResult, MegaError> {
+    let repo = match repo_path {
+        Some(path) => context
+            .services
+            .mega_storage
+            .find_git_repo(path)
+            .await?
+            .map(Repo::from)
+            .ok_or_else(|| MegaError::with_message(&format!("repository not found: {}", path)))?,
+        None => Repo::empty(),
+    };
+    let ref_name = if branch.starts_with(BRANCH_PREFIX) {
+        branch.to_owned()
+    } else {
+        format!("{}{}", BRANCH_PREFIX, branch)
+    };
+    let terms: Vec<String> = text.split_whitespace().map(str::to_owned).collect();
+    let storage = context.services.search_storage.clone();
+    let indexed = storage.get_indexed_ref(repo.repo_id, &ref_name).await?;
+    let files = if terms.is_empty() || indexed.is_none() {
+        vec![]
+    } else {
+        let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        storage
+            .search_files(repo.repo_id, &ref_name, &terms, offset, limit)
+            .await?
+    };
+    let hits = files
+        .into_iter()
+        .map(|file| CodeHit {
+            lines: file
+                .content
+                .as_deref()
+                .map(|content| matching_lines(content, &terms, MAX_LINES_PER_FILE))
+                .unwrap_or_default(),
+            path: file.path,
+            blob_id: file.blob_id,
+        })
+        .collect();
+    Ok(CodeSearchResult {
+        ref_name,
+        commit_id: indexed.map(|indexed| indexed.commit_id),
+        offset,
+        hits,
+    })
+}
+
+/// The first `max` lines of `content` containing one of `terms`, ignoring case.
+fn matching_lines(content: &str, terms: &[String], max: usize) -> Vec<(usize, String)> {
+    let terms: Vec<String> = terms.iter().map(|term| term.to_lowercase()).collect();
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.to_lowercase();
+            terms.iter().any(|term| line.contains(term.as_str()))
+        })
+        .take(max)
+        .map(|(no, line)| (no + 1, line.to_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{indexed_content, matching_lines, MAX_INDEXED_FILE_SIZE};
+
+    #[test]
+    fn test_indexed_content() {
+        assert_eq!(
+            indexed_content(b"fn main() {}\n".to_vec()).as_deref(),
+            Some("fn main() {}\n")
+        );
+        assert_eq!(indexed_content(b"\x89PNG\r\n\x1a\n\0\0".to_vec()), None);
+        assert_eq!(indexed_content(vec![0xff, 0xfe]), None);
+        assert_eq!(indexed_content(vec![b'a'; MAX_INDEXED_FILE_SIZE + 1]), None);
+    }
+
+    #[test]
+    fn test_matching_lines() {
+        let content = "use std::fs;\n\nfn Read_Config() {\n    fs::read(\"a\");\n}\n";
+        let terms = vec![String::from("READ")];
+        assert_eq!(
+            matching_lines(content, &terms, 5),
+            vec![
+                (3, String::from("fn Read_Config() {")),
+                (4, String::from("    fs::read(\"a\");")),
+            ]
+        );
+        assert_eq!(matching_lines(content, &terms, 1).len(), 1);
+        assert!(matching_lines(content, &[String::from("write")], 5).is_empty());
+    }
+}
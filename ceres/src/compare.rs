@@ -0,0 +1,448 @@
+//!
+//! Comparison of two revisions of a repository, the query behind "compare & create merge
+//! request": the commits of `head` missing from `base`, the merge base and the files changed
+//! between the merge base and `head`.
+//!
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use common::errors::MegaError;
+use jupiter::context::Context;
+use venus::hash::SHA1;
+use venus::internal::commit_graph::{self, CommitGraphFile};
+use venus::internal::object::commit::Commit;
+use venus::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+use venus::internal::revwalk::CommitGraph;
+use venus::repo::Repo;
+
+use crate::replace::{self, Replacements};
+
+/// Commits returned when the request sets no limit, and the most it can ask for.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 250;
+
+/// Changed files listed at most, larger divergences only report the count.
+const MAX_FILES: usize = 3000;
+
+/// Where the commit-graph file of each repository is kept in the raw storage, under the id
+/// of the repository.
+const COMMIT_GRAPH_DIR: &str = "commit-graphs";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeStatus {
+    Added,
+    Modified,
+    Deleted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileChange {
+    pub path: String,
+    pub status: ChangeStatus,
+    /// The blobs on both sides, unset on the side the file does not exist.
+    #[serde(skip)]
+    pub old_id: Option<SHA1>,
+    #[serde(skip)]
+    pub new_id: Option<SHA1>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CommitSummary {
+    pub id: String,
+    pub author: String,
+    pub email: String,
+    pub timestamp: usize,
+    pub message: String,
+}
+
+impl From<&Commit> for CommitSummary {
+    fn from(value: &Commit) -> Self {
+        CommitSummary {
+            id: value.id.to_plain_str(),
+            author: value.author.name.clone(),
+            email: value.author.email.clone(),
+            timestamp: value.author.timestamp,
+            message: value.message.trim().to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Comparison {
+    pub base: String,
+    pub head: String,
+    pub merge_base: Option<String>,
+    /// Commits of `head` missing from `base`, and the other way around.
+    pub ahead_by: usize,
+    pub behind_by: usize,
+    /// One page of the commits ahead, newest first.
+    pub commits: Vec<CommitSummary>,
+    pub offset: usize,
+    pub total_files: usize,
+    pub files_truncated: bool,
+    pub files: Vec<FileChange>,
+}
+
+/// Compares `head` against `base` in the repository at `repo_path`. Both can be a commit id,
+/// a full ref name or a branch name.
+pub async fn compare(
+    context: &Context,
+    repo_path: &str,
+    base: &str,
+    head: &str,
+    offset: usize,
+    limit: Option<usize>,
+) -> Result<Comparison, MegaError> {
+    let (repo, base_id, head_id, graph) = open_revisions(context, repo_path, base, head).await?;
+    let ahead = graph.walk(&[head_id], &[base_id]);
+    let behind = graph.walk(&[base_id], &[head_id]);
+    let merge_base = graph.merge_base(&base_id, &head_id);
+
+    // a graph read from its file has no commit, only the page is read
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let replacements = Replacements::load(context, &repo).await?;
+    let mut commits = Vec::new();
+    for id in ahead.iter().skip(offset).take(limit) {
+        let commit = match graph.commit(id) {
+            Some(commit) => Some(commit.clone()),
+            None => replace::read_commit(context, &repo, &replacements, id).await?,
+        };
+        commits.extend(commit.as_ref().map(CommitSummary::from));
+    }
+
+    let old_tree = merge_base.and_then(|id| graph.tree(&id));
+    let new_tree = graph.tree(&head_id);
+    let mut files = diff_trees(context, old_tree, new_tree).await?;
+    let total_files = files.len();
+    files.truncate(MAX_FILES);
+
+    Ok(Comparison {
+        base: base_id.to_plain_str(),
+        head: head_id.to_plain_str(),
+        merge_base: merge_base.map(|id| id.to_plain_str()),
+        ahead_by: ahead.len(),
+        behind_by: behind.len(),
+        commits,
+        offset,
+        total_files,
+        files_truncated: total_files > files.len(),
+        files,
+    })
+}
+
+/// The repository at `repo_path`, the commits `base` and `head` resolve to and their history.
+pub(crate) async fn open_revisions(
+    context: &Context,
+    repo_path: &str,
+    base: &str,
+    head: &str,
+) -> Result<(Repo, SHA1, SHA1, CommitGraph), MegaError> {
+    let storage = context.services.mega_storage.clone();
+    let repo: Repo = storage
+        .find_git_repo(repo_path)
+        .await?
+        .ok_or_else(|| MegaError::with_message(&format!("repository not found: {}", repo_path)))?
+        .into();
+    let refs: HashMap<String, String> = storage
+        .get_repo_refs(&repo)
+        .await?
+        .into_iter()
+        .map(|r| (r.ref_name, r.ref_git_id))
+        .collect();
+    let base_id = resolve(&refs, base)?;
+    let head_id = resolve(&refs, head)?;
+
+    let graph = load_graph(context, &repo, &[base_id, head_id]).await?;
+    for (name, id) in [(base, base_id), (head, head_id)] {
+        if !graph.contains(&id) {
+            return Err(MegaError::with_message(&format!(
+                "commit not found: {}",
+                name
+            )));
+        }
+    }
+    Ok((repo, base_id, head_id, graph))
+}
+
+/// A commit id, or the target of `name`, `refs/heads/<name>` or `refs/tags/<name>`.
+pub(crate) fn resolve(refs: &HashMap<String, String>, name: &str) -> Result<SHA1, MegaError> {
+    let target = [
+        name.to_owned(),
+        format!("refs/heads/{}", name),
+        format!("refs/tags/{}", name),
+    ]
+    .iter()
+    .find_map(|candidate| refs.get(candidate))
+    .map(String::as_str)
+    .unwrap_or(name);
+    SHA1::from_str(target)
+        .map_err(|_| MegaError::with_message(&format!("unknown revision: {}", name)))
+}
+
+/// The history of `tips`, read from the commit-graph file of the repository when it has all
+/// of them. Otherwise the commits are loaded, and the file is written again for the next
+/// call. A repository with replaced commits has no file, its history is not the one written
+/// in the commits.
+pub(crate) async fn load_graph(
+    context: &Context,
+    repo: &Repo,
+    tips: &[SHA1],
+) -> Result<CommitGraph, MegaError> {
+    let raw_storage = context.services.mega_storage.raw_storage.clone();
+    let object_id = repo.repo_id.to_string();
+    let replacements = Replacements::load(context, repo).await?;
+    if replacements.is_empty() && raw_storage.exist_object(COMMIT_GRAPH_DIR, &object_id) {
+        let data = raw_storage.get_object(COMMIT_GRAPH_DIR, &object_id).await?;
+        match CommitGraphFile::from_bytes(&data) {
+            Ok(file) if tips.iter().all(|tip| file.contains(tip)) => {
+                return Ok(CommitGraph::from_file(&file));
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("{}: {}", repo.repo_path, e),
+        }
+    }
+
+    let graph = CommitGraph::new(load_history_with(context, repo, &replacements, tips, &[]).await?);
+    if replacements.is_empty() {
+        let data = commit_graph::encode_commit_graph(&graph);
+        if let Err(e) = raw_storage
+            .put_object(COMMIT_GRAPH_DIR, &object_id, &data)
+            .await
+        {
+            tracing::warn!(
+                "{}: failed to write the commit-graph: {}",
+                repo.repo_path,
+                e
+            );
+        }
+    }
+    Ok(graph)
+}
+
+/// Loads `tips` and all their ancestors found in the repository, the parents of the commits
+/// in `stop` are not loaded. Replaced commits are read from their replacement.
+pub(crate) async fn load_history(
+    context: &Context,
+    repo: &Repo,
+    tips: &[SHA1],
+    stop: &[SHA1],
+) -> Result<Vec<Commit>, MegaError> {
+    let replacements = Replacements::load(context, repo).await?;
+    load_history_with(context, repo, &replacements, tips, stop).await
+}
+
+async fn load_history_with(
+    context: &Context,
+    repo: &Repo,
+    replacements: &Replacements,
+    tips: &[SHA1],
+    stop: &[SHA1],
+) -> Result<Vec<Commit>, MegaError> {
+    let mut commits = Vec::new();
+    let mut visited: HashSet<SHA1> = HashSet::new();
+    let mut stack = tips.to_vec();
+    while let Some(id) = stack.pop() {
+        if !visited.insert(id) {
+            continue;
+        }
+        if let Some(commit) = replace::read_commit(context, repo, replacements, &id).await? {
+            if !stop.contains(&id) {
+                stack.extend(commit.parent_commit_ids.iter().copied());
+            }
+            commits.push(commit);
+        }
+    }
+    Ok(commits)
+}
+
+/// Files changed from the tree `old` to the tree `new`, sorted by path. Subtrees with the same
+/// id on both sides are not loaded.
+pub(crate) async fn diff_trees(
+    context: &Context,
+    old: Option<SHA1>,
+    new: Option<SHA1>,
+) -> Result<Vec<FileChange>, MegaError> {
+    let mut changes = Vec::new();
+    let mut pending = vec![(String::new(), old, new)];
+    while let Some((path, old, new)) = pending.pop() {
+        if old == new {
+            continue;
+        }
+        let old_items = load_tree_items(context, old).await?;
+        let new_items = load_tree_items(context, new).await?;
+        let (files, subtrees) = diff_items(&path, &old_items, &new_items);
+        changes.extend(files);
+        pending.extend(subtrees);
+    }
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(changes)
+}
+
+pub(crate) async fn load_tree_items(
+    context: &Context,
+    id: Option<SHA1>,
+) -> Result<Vec<TreeItem>, MegaError> {
+    let Some(id) = id else {
+        return Ok(vec![]);
+    };
+    let model = context
+        .services
+        .mega_storage
+        .get_mega_tree_by_sha(&id.to_plain_str())
+        .await?
+        .ok_or_else(|| {
+            MegaError::with_message(&format!("tree not found: {}", id.to_plain_str()))
+        })?;
+    let tree: Tree = model.into();
+    Ok(tree.tree_items)
+}
+
+type SubtreeDiff = (String, Option<SHA1>, Option<SHA1>);
+
+/// Entries of a directory by name: the subtrees, then the files.
+fn split_items(items: &[TreeItem]) -> (HashMap<&str, &TreeItem>, HashMap<&str, &TreeItem>) {
+    let mut trees = HashMap::new();
+    let mut files = HashMap::new();
+    for item in items {
+        match item.mode {
+            TreeItemMode::Tree => trees.insert(item.name.as_str(), item),
+            _ => files.insert(item.name.as_str(), item),
+        };
+    }
+    (trees, files)
+}
+
+/// Compares the entries of one directory: the changed files, and the subtrees to descend into.
+/// A path that is a file on one side and a directory on the other counts as both.
+fn diff_items(
+    path: &str,
+    old: &[TreeItem],
+    new: &[TreeItem],
+) -> (Vec<FileChange>, Vec<SubtreeDiff>) {
+    let (old_trees, old_files) = split_items(old);
+    let (new_trees, new_files) = split_items(new);
+    let join = |name: &str| {
+        if path.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}/{}", path, name)
+        }
+    };
+
+    let mut changes = Vec::new();
+    for (name, item) in &new_files {
+        let status = match old_files.get(name) {
+            None => ChangeStatus::Added,
+            Some(old) if old.id != item.id || old.mode != item.mode => ChangeStatus::Modified,
+            Some(_) => continue,
+        };
+        changes.push(FileChange {
+            path: join(name),
+            status,
+            old_id: old_files.get(name).map(|old| old.id),
+            new_id: Some(item.id),
+        });
+    }
+    for (name, item) in old_files
+        .iter()
+        .filter(|(name, _)| !new_files.contains_key(*name))
+    {
+        changes.push(FileChange {
+            path: join(name),
+            status: ChangeStatus::Deleted,
+            old_id: Some(item.id),
+            new_id: None,
+        });
+    }
+
+    let names: HashSet<&str> = old_trees.keys().chain(new_trees.keys()).copied().collect();
+    let subtrees = names
+        .into_iter()
+        .map(|name| {
+            (
+                join(name),
+                old_trees.get(name).map(|item| item.id),
+                new_trees.get(name).map(|item| item.id),
+            )
+        })
+        .collect();
+    (changes, subtrees)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use venus::hash::SHA1;
+    use venus::internal::object::tree::{TreeItem, TreeItemMode};
+
+    use super::{diff_items, resolve, ChangeStatus, FileChange};
+
+    fn item(mode: TreeItemMode, name: &str, n: u8) -> TreeItem {
+        TreeItem::new(
+            mode,
+            SHA1::from_str(&format!("{:040x}", n)).unwrap(),
+            name.to_owned(),
+        )
+    }
+
+    #[test]
+    fn test_diff_items() {
+        let old = vec![
+            item(TreeItemMode::Blob, "README.md", 1),
+            item(TreeItemMode::Blob, "build.rs", 2),
+            item(TreeItemMode::Tree, "src", 3),
+            item(TreeItemMode::Tree, "docs", 4),
+        ];
+        let new = vec![
+            item(TreeItemMode::Blob, "README.md", 1),
+            item(TreeItemMode::BlobExecutable, "build.rs", 2),
+            item(TreeItemMode::Blob, "LICENSE", 5),
+            item(TreeItemMode::Tree, "src", 6),
+        ];
+        let id = |n: u8| SHA1::from_str(&format!("{:040x}", n)).ok();
+        let (mut files, mut subtrees) = diff_items("mega", &old, &new);
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        subtrees.sort();
+        assert_eq!(
+            files,
+            vec![
+                FileChange {
+                    path: String::from("mega/LICENSE"),
+                    status: ChangeStatus::Added,
+                    old_id: None,
+                    new_id: id(5),
+                },
+                FileChange {
+                    path: String::from("mega/build.rs"),
+                    status: ChangeStatus::Modified,
+                    old_id: id(2),
+                    new_id: id(2),
+                },
+            ]
+        );
+        assert_eq!(subtrees.len(), 2);
+        assert_eq!(subtrees[0].0, "mega/docs");
+        assert!(subtrees[0].2.is_none());
+        assert_eq!(subtrees[1].0, "mega/src");
+    }
+
+    #[test]
+    fn test_resolve() {
+        let id = format!("{:040x}", 7);
+        let refs = HashMap::from([
+            (String::from("refs/heads/main"), id.clone()),
+            (String::from("refs/tags/v0.1"), id.clone()),
+        ]);
+        let expected = SHA1::from_str(&id).unwrap();
+        assert_eq!(resolve(&refs, "main").unwrap(), expected);
+        assert_eq!(resolve(&refs, "refs/heads/main").unwrap(), expected);
+        assert_eq!(resolve(&refs, "v0.1").unwrap(), expected);
+        assert_eq!(resolve(&refs, &id).unwrap(), expected);
+        assert!(resolve(&refs, "feature").is_err());
+    }
+}
@@ -0,0 +1,386 @@
+//!
+//! Replies by email to the review of merge requests. The notifications of the published
+//! comments carry a `Message-ID` made by [`message_id`] for the mailer sending them, it holds
+//! a token naming the comment, signed with `MEGA_EMAIL_REPLY_SECRET`:
+//!
+//! ```text
+//! <mr-42.c-7113.3f2a9c01d4e5b6a7@mega.example.com>
+//! ```
+//!
+//! Their `References` is the id of the merge request itself. A reply quotes these ids in its
+//! `In-Reply-To` or `References` header. The mail provider passes it on to the webhook, which
+//! adds the text of the reply to the merge request as a comment of the user with the address
+//! of the sender, on the file and line of the comment replied to, so that it joins its thread.
+//! The user must be allowed to write to the repository of the merge request, as for the
+//! comments posted to the API.
+//! The webhook only takes the posts signed with `MEGA_EMAIL_REPLY_WEBHOOK_SECRET`, see
+//! [`verify_webhook_signature`]. The `From` header is not authenticated by the token: a reply
+//! is only as trusted as the provider checking the SPF and DKIM of the sender before posting
+//! it.
+//!
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use callisto::mega_mr_comment;
+use common::errors::MegaError;
+use jupiter::context::Context;
+
+/// Bytes of the signature of a token.
+const SIGNATURE_LEN: usize = 8;
+
+/// What a reply answers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplyTarget {
+    pub mr_id: i64,
+    /// The comment replied to, None for the merge request itself.
+    pub comment_id: Option<i64>,
+}
+
+/// A reply as the mail provider posts it.
+#[derive(Debug, Clone)]
+pub struct InboundEmail {
+    /// The `From` header, e.g. `Alice <alice@example.com>`.
+    pub from: String,
+    pub in_reply_to: Option<String>,
+    pub references: Option<String>,
+    /// The plain text part.
+    pub text: String,
+}
+
+/// The `Message-ID` of a notification about `target` sent from `domain`.
+pub fn message_id(secret: &str, domain: &str, target: ReplyTarget) -> String {
+    let token = match target.comment_id {
+        Some(comment_id) => format!("mr-{}.c-{}", target.mr_id, comment_id),
+        None => format!("mr-{}", target.mr_id),
+    };
+    format!("<{}.{}@{}>", token, sign(secret, &token), domain)
+}
+
+/// The target of the first of the message ids in `headers` made by [`message_id`] and signed
+/// with `secret`.
+pub fn reply_target<'a>(
+    secret: &str,
+    headers: impl IntoIterator<Item = &'a str>,
+) -> Option<ReplyTarget> {
+    headers
+        .into_iter()
+        .flat_map(|header| header.split(['<', '>', ' ', ',']))
+        .find_map(|id| parse_token(secret, id))
+}
+
+fn parse_token(secret: &str, id: &str) -> Option<ReplyTarget> {
+    let (local, _domain) = id.split_once('@')?;
+    let (token, signature) = local.rsplit_once('.')?;
+    let signature = hex::decode(signature).ok()?;
+    if signature.len() != SIGNATURE_LEN
+        || mac(secret.as_bytes(), token.as_bytes())
+            .verify_truncated_left(&signature)
+            .is_err()
+    {
+        return None;
+    }
+    let mut parts = token.strip_prefix("mr-")?.split(".c-");
+    let mr_id = parts.next()?.parse().ok()?;
+    let comment_id = match parts.next() {
+        Some(comment_id) => Some(comment_id.parse().ok()?),
+        None => None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(ReplyTarget { mr_id, comment_id })
+}
+
+/// HMAC-SHA256 of `token` with `secret`, shortened.
+fn sign(secret: &str, token: &str) -> String {
+    let signature = mac(secret.as_bytes(), token.as_bytes())
+        .finalize()
+        .into_bytes();
+    hex::encode(&signature[..SIGNATURE_LEN])
+}
+
+/// Whether `signature`, the `X-Mega-Signature` header of a post to the webhook, is
+/// `sha256=` and the hex HMAC-SHA256 of `body` with `secret`. It is compared in constant time.
+pub fn verify_webhook_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Some(Ok(signature)) = signature.strip_prefix("sha256=").map(hex::decode) else {
+        return false;
+    };
+    !secret.is_empty()
+        && mac(secret.as_bytes(), body)
+            .verify_slice(&signature)
+            .is_ok()
+}
+
+/// HMAC-SHA256 of `data` with `key`, see RFC 2104.
+pub fn mac(key: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac
+}
+
+/// The address of a `From` header, lower case.
+pub fn sender_address(from: &str) -> Option<String> {
+    let address = match (from.rfind('<'), from.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &from[start + 1..end],
+        _ => from,
+    };
+    let address = address.trim();
+    address.contains('@').then(|| address.to_lowercase())
+}
+
+/// The text written in a reply: the quote of the message replied to, its attribution line
+/// (`On <date>, <name> wrote:`) and the signature are dropped.
+pub fn reply_text(text: &str) -> String {
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let trimmed = line.trim_end();
+        if trimmed == "--" {
+            break;
+        }
+        if trimmed.starts_with("On ") && trimmed.ends_with("wrote:") {
+            break;
+        }
+        if trimmed.starts_with('>') {
+            continue;
+        }
+        lines.push(trimmed);
+    }
+    lines.join("\n").trim().to_owned()
+}
+
+/// Adds `email` to the merge request it replies to as a published comment of its sender, when
+/// `writable` allows that user to write to the path of the repository of the merge request.
+pub async fn ingest_reply(
+    context: &Context,
+    secret: &str,
+    email: &InboundEmail,
+    writable: impl Fn(&str, &str) -> bool,
+) -> Result<mega_mr_comment::Model, MegaError> {
+    let headers = [email.in_reply_to.as_deref(), email.references.as_deref()];
+    let target = reply_target(secret, headers.into_iter().flatten())
+        .ok_or_else(|| MegaError::with_message("not a reply to a merge request"))?;
+    let address = sender_address(&email.from)
+        .ok_or_else(|| MegaError::with_message(&format!("invalid sender: {}", email.from)))?;
+    let user = context
+        .services
+        .user_storage
+        .find_users_by_email(vec![address.clone()])
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| MegaError::with_message(&format!("no user with the address {}", address)))?;
+    let mega_storage = &context.services.mega_storage;
+    if mega_storage.get_mr(target.mr_id).await?.is_none() {
+        return Err(MegaError::with_message(&format!(
+            "merge request not found: {}",
+            target.mr_id
+        )));
+    }
+    let repo_path = mega_storage.get_mr_repo_path(target.mr_id).await?;
+    if !writable(&user.name, &repo_path) {
+        return Err(MegaError::with_message(&format!(
+            "{} may not comment on {}",
+            user.name, repo_path
+        )));
+    }
+    let body = reply_text(&email.text);
+    if body.is_empty() {
+        return Err(MegaError::with_message("empty reply"));
+    }
+
+    let storage = &context.services.review_storage;
+    // the thread of a comment is its file and line
+    let (path, line) = match target.comment_id {
+        Some(comment_id) => storage
+            .get_comment(comment_id)
+            .await?
+            .filter(|comment| comment.mr_id == target.mr_id && comment.review_id.is_some())
+            .map(|comment| (comment.path, comment.line))
+            .ok_or_else(|| {
+                MegaError::with_message(&format!("comment not found: {}", comment_id))
+            })?,
+        None => (None, None),
+    };
+    storage
+        .add_published_comment(target.mr_id, &user.name, path, line, &body)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    use sea_orm::{DatabaseBackend, MockDatabase};
+
+    use callisto::db_enums::MergeStatus;
+    use callisto::{git_repo, mega_commit, mega_mr, mega_user};
+    use jupiter::context::Context;
+    use jupiter::storage::mega_storage::MegaStorage;
+    use jupiter::storage::user_storage::UserStorage;
+
+    use super::{
+        ingest_reply, mac, message_id, reply_target, reply_text, sender_address, sign,
+        verify_webhook_signature, InboundEmail, ReplyTarget,
+    };
+    use hmac::Mac;
+
+    #[test]
+    fn test_reply_target() {
+        let comment = ReplyTarget {
+            mr_id: 42,
+            comment_id: Some(7113),
+        };
+        let id = message_id("secret", "mega.example.com", comment);
+        assert!(id.starts_with("<mr-42.c-7113."));
+        assert_eq!(reply_target("secret", [id.as_str()]), Some(comment));
+
+        // the first of the ids of ours in the references
+        let mr = ReplyTarget {
+            mr_id: 42,
+            comment_id: None,
+        };
+        let references = format!(
+            "<CAF1234@mail.example.com> {} {}",
+            message_id("secret", "mega.example.com", mr),
+            id
+        );
+        assert_eq!(reply_target("secret", ["", &references]), Some(mr));
+
+        // another secret, or a token changed
+        assert_eq!(reply_target("other", [id.as_str()]), None);
+        let forged = id.replace("mr-42", "mr-43");
+        assert_eq!(reply_target("secret", [forged.as_str()]), None);
+        assert_eq!(reply_target("secret", ["<mr-42@mega.example.com>"]), None);
+        // a prefix of the signature
+        let (local, domain) = id.split_once('@').unwrap();
+        let short = format!("{}@{}", &local[..local.len() - 2], domain);
+        assert_eq!(reply_target("secret", [short.as_str()]), None);
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        let mac = sign("Jefe", "what do ya want for nothing?");
+        assert_eq!(mac, "5bdcc146bf60754e");
+        let long = "k".repeat(100);
+        assert_ne!(sign(&long, "token"), sign(&long[..64], "token"));
+    }
+
+    #[test]
+    fn test_verify_webhook_signature() {
+        let body = br#"{"from":"alice@example.com"}"#;
+        let signature = format!(
+            "sha256={}",
+            hex::encode(mac(b"hook", body).finalize().into_bytes())
+        );
+        assert!(verify_webhook_signature("hook", body, &signature));
+        assert!(!verify_webhook_signature("other", body, &signature));
+        assert!(!verify_webhook_signature("hook", b"{}", &signature));
+        assert!(!verify_webhook_signature("hook", body, &signature[7..]));
+        assert!(!verify_webhook_signature("hook", body, &signature[..20]));
+        assert!(!verify_webhook_signature("", body, "sha256="));
+    }
+
+    #[test]
+    fn test_reply_text() {
+        let text = "Fixed in the next push.\r\n\r\nThanks!\r\n\r\n\
+            On Mon, 6 May 2024 at 10:00, Mega <mega@mega.example.com> wrote:\r\n\
+            > Please rename this function.\r\n";
+        assert_eq!(reply_text(text), "Fixed in the next push.\n\nThanks!");
+        assert_eq!(reply_text("> quoted\nLGTM\n-- \nAlice\n"), "LGTM");
+        assert_eq!(reply_text("> only a quote\n"), "");
+    }
+
+    #[test]
+    fn test_sender_address() {
+        assert_eq!(
+            sender_address("Alice <Alice@Example.com>"),
+            Some(String::from("alice@example.com"))
+        );
+        assert_eq!(
+            sender_address("bob@example.com"),
+            Some(String::from("bob@example.com"))
+        );
+        assert_eq!(sender_address("Bob"), None);
+    }
+
+    #[tokio::test]
+    async fn test_ingest_reply_checks_the_policy() {
+        let now = chrono::Utc::now().naive_utc();
+        let users = MockDatabase::new(DatabaseBackend::Postgres).append_query_results([vec![
+            mega_user::Model {
+                id: 1,
+                name: String::from("alice"),
+                email: Some(String::from("alice@example.com")),
+                token_hash: None,
+                created_at: now,
+            },
+        ]]);
+        let mega = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_query_results([vec![mega_mr::Model {
+                id: 42,
+                mr_link: String::new(),
+                mr_msg: None,
+                merge_date: None,
+                status: MergeStatus::Open,
+                created_at: now,
+                updated_at: now,
+            }]])
+            .append_query_results([vec![mega_commit::Model {
+                id: 1,
+                repo_id: 7,
+                commit_id: String::from("95d09f2b10159347eece71399a7e2e907ea3df4f"),
+                tree: String::from("4b825dc642cb6eb9a060e54bf8d69288fbee4904"),
+                parents_id: vec![],
+                author: None,
+                committer: None,
+                content: None,
+                mr_id: 42,
+                status: MergeStatus::Open,
+                created_at: now,
+                updated_at: now,
+            }]])
+            .append_query_results([vec![git_repo::Model {
+                id: 7,
+                repo_path: String::from("/projects/mega"),
+                repo_name: String::from("mega"),
+                is_public: false,
+                created_at: now,
+                updated_at: now,
+            }]]);
+        let mut context = Context::mock();
+        let mut services = (*context.services).clone();
+        services.user_storage = Arc::new(UserStorage {
+            connection: Arc::new(users.into_connection()),
+        });
+        services.mega_storage = Arc::new(MegaStorage {
+            connection: Arc::new(mega.into_connection()),
+            ..MegaStorage::mock()
+        });
+        context.services = Arc::new(services);
+
+        let target = ReplyTarget {
+            mr_id: 42,
+            comment_id: None,
+        };
+        let email = InboundEmail {
+            from: String::from("Alice <alice@example.com>"),
+            in_reply_to: Some(message_id("secret", "mega.example.com", target)),
+            references: None,
+            text: String::from("LGTM"),
+        };
+        let checked = Cell::new(false);
+        let err = ingest_reply(&context, "secret", &email, |user, repo_path| {
+            checked.set(true);
+            assert_eq!((user, repo_path), ("alice", "/projects/mega"));
+            false
+        })
+        .await
+        .unwrap_err();
+        assert!(checked.get());
+        assert!(err
+            .to_string()
+            .contains("alice may not comment on /projects/mega"));
+    }
+}
@@ -0,0 +1,496 @@
+//!
+//! Fragmentation of the directory of packs written by [`gc::repack`], and the policy
+//! repacking it when the packs get fragmented rather than on a fixed schedule. Every check
+//! measures the directory: the number of packs, the share of them that are small, and how many
+//! objects of the small packs are stored as a delta and how long their delta chains are. Once
+//! a threshold is crossed the small packs are merged into one with [`gc::merge_packs`], the
+//! large ones are left as they are.
+//!
+//! A pack is never changed once written, so its figures are read once and kept by the
+//! [`RepackScheduler`]. So is the mean latency of the fetches of the hour before its last
+//! repack, which is logged with the latency since on the next check.
+//!
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use chrono::{NaiveDateTime, Utc};
+
+use callisto::db_enums::OperationType;
+use common::errors::MegaError;
+use jupiter::context::Context;
+use jupiter::storage::audit_storage::OperationFilter;
+
+use crate::gc;
+
+/// Packs of the directory before it is repacked.
+pub const DEFAULT_MAX_PACKS: usize = 50;
+/// Objects of the packs counted as small.
+pub const DEFAULT_SMALL_PACK_OBJECTS: usize = 10_000;
+/// Share of small packs before the directory is repacked.
+pub const DEFAULT_MAX_SMALL_PACK_RATIO: f64 = 0.5;
+/// Share of the objects of the small packs stored as a delta below which they are repacked,
+/// spread over many packs the objects find no base to be stored against.
+pub const DEFAULT_MIN_DELTA_RATIO: f64 = 0.3;
+
+/// Minutes of fetches the latency before a repack is measured over.
+const LATENCY_WINDOW_MINS: i64 = 60;
+
+/// Figures of one pack, see [`pack_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PackStats {
+    pub objects: usize,
+    /// Objects stored as a delta, on an offset or on a hash.
+    pub deltas: usize,
+    pub max_delta_depth: usize,
+    pub bytes: u64,
+}
+
+/// Figures of a directory of packs, see [`Fragmentation::measure`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Fragmentation {
+    pub packs: usize,
+    pub objects: usize,
+    pub bytes: u64,
+    /// The packs with fewer objects than the `small_pack_objects` of the policy.
+    pub small_packs: Vec<PathBuf>,
+    pub small_pack_objects: usize,
+    pub small_pack_deltas: usize,
+    pub max_delta_depth: usize,
+}
+
+impl Fragmentation {
+    /// Measures the packs of `pack_dir` with an index, reading the figures of the packs not
+    /// in `known` and adding them to it. The packs no longer there are removed from `known`.
+    pub async fn measure(
+        context: &Context,
+        pack_dir: &Path,
+        small_pack_objects: usize,
+        known: &mut HashMap<PathBuf, PackStats>,
+    ) -> Result<Self, MegaError> {
+        let packs = indexed_packs(pack_dir)?;
+        known.retain(|path, _| packs.contains(path));
+        let mut fragmentation = Fragmentation {
+            packs: packs.len(),
+            ..Default::default()
+        };
+        for path in packs {
+            let stats = match known.get(&path) {
+                Some(stats) => *stats,
+                None => {
+                    let stats = pack_stats(context, pack_dir, &path).await?;
+                    known.insert(path.clone(), stats);
+                    stats
+                }
+            };
+            fragmentation.objects += stats.objects;
+            fragmentation.bytes += stats.bytes;
+            fragmentation.max_delta_depth =
+                fragmentation.max_delta_depth.max(stats.max_delta_depth);
+            if stats.objects < small_pack_objects {
+                fragmentation.small_packs.push(path);
+                fragmentation.small_pack_objects += stats.objects;
+                fragmentation.small_pack_deltas += stats.deltas;
+            }
+        }
+        Ok(fragmentation)
+    }
+
+    pub fn small_pack_ratio(&self) -> f64 {
+        ratio(self.small_packs.len(), self.packs)
+    }
+
+    /// The share of the objects of the small packs stored as a delta.
+    pub fn small_pack_delta_ratio(&self) -> f64 {
+        ratio(self.small_pack_deltas, self.small_pack_objects)
+    }
+}
+
+impl Display for Fragmentation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} pack(s) of {} object(s), {} small ({:.0}%) with {:.0}% of deltas, \
+             delta chains up to {}",
+            self.packs,
+            self.objects,
+            self.small_packs.len(),
+            self.small_pack_ratio() * 100.0,
+            self.small_pack_delta_ratio() * 100.0,
+            self.max_delta_depth
+        )
+    }
+}
+
+fn ratio(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+/// Why a directory of packs is repacked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RepackReason {
+    TooManyPacks(usize),
+    SmallPacks(f64),
+    FewDeltas(f64),
+}
+
+impl Display for RepackReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RepackReason::TooManyPacks(packs) => write!(f, "{} packs", packs),
+            RepackReason::SmallPacks(ratio) => write!(f, "{:.0}% of small packs", ratio * 100.0),
+            RepackReason::FewDeltas(ratio) => {
+                write!(f, "{:.0}% of deltas in the small packs", ratio * 100.0)
+            }
+        }
+    }
+}
+
+/// The thresholds past which a directory of packs is repacked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RepackPolicy {
+    pub max_packs: usize,
+    pub small_pack_objects: usize,
+    pub max_small_pack_ratio: f64,
+    pub min_delta_ratio: f64,
+}
+
+impl Default for RepackPolicy {
+    fn default() -> Self {
+        RepackPolicy {
+            max_packs: DEFAULT_MAX_PACKS,
+            small_pack_objects: DEFAULT_SMALL_PACK_OBJECTS,
+            max_small_pack_ratio: DEFAULT_MAX_SMALL_PACK_RATIO,
+            min_delta_ratio: DEFAULT_MIN_DELTA_RATIO,
+        }
+    }
+}
+
+impl RepackPolicy {
+    /// The first threshold `fragmentation` crosses, `None` when there are not at least two
+    /// small packs to merge.
+    pub fn check(&self, fragmentation: &Fragmentation) -> Option<RepackReason> {
+        if fragmentation.small_packs.len() < 2 {
+            return None;
+        }
+        if fragmentation.packs > self.max_packs {
+            return Some(RepackReason::TooManyPacks(fragmentation.packs));
+        }
+        let small = fragmentation.small_pack_ratio();
+        if small > self.max_small_pack_ratio {
+            return Some(RepackReason::SmallPacks(small));
+        }
+        let deltas = fragmentation.small_pack_delta_ratio();
+        if deltas < self.min_delta_ratio {
+            return Some(RepackReason::FewDeltas(deltas));
+        }
+        None
+    }
+}
+
+/// What a check of [`RepackScheduler`] did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepackCheck {
+    pub before: Fragmentation,
+    /// Why the small packs were merged, and the figures of the directory after.
+    pub repack: Option<(RepackReason, Fragmentation)>,
+}
+
+/// Checks a directory of packs against a [`RepackPolicy`], and repacks it when needed.
+pub struct RepackScheduler {
+    pub pack_dir: PathBuf,
+    pub policy: RepackPolicy,
+    known: HashMap<PathBuf, PackStats>,
+    /// When the last repack happened, with the mean latency of the fetches before it.
+    last_repack: Option<(NaiveDateTime, Option<f64>)>,
+}
+
+impl RepackScheduler {
+    pub fn new(pack_dir: PathBuf, policy: RepackPolicy) -> Self {
+        RepackScheduler {
+            pack_dir,
+            policy,
+            known: HashMap::new(),
+            last_repack: None,
+        }
+    }
+
+    /// Measures the directory and merges its small packs once a threshold of the policy is
+    /// crossed. The latency of the fetches since the last repack is logged against the one
+    /// before it, the next check tries again when there was no fetch.
+    pub async fn check(&mut self, context: &Context) -> Result<RepackCheck, MegaError> {
+        let now = Utc::now().naive_utc();
+        if let Some((at, Some(before))) = self.last_repack.take() {
+            match mean_fetch_latency(context, at, now).await {
+                Ok(Some(after)) => tracing::info!(
+                    "repack of {}: fetches took {:.0} ms before, {:.0} ms since",
+                    self.pack_dir.display(),
+                    before,
+                    after
+                ),
+                Ok(None) => self.last_repack = Some((at, Some(before))),
+                Err(err) => tracing::warn!("failed to read the fetch latency: {}", err),
+            }
+        }
+        let before = self.measure(context).await?;
+        let Some(reason) = self.policy.check(&before) else {
+            return Ok(RepackCheck {
+                before,
+                repack: None,
+            });
+        };
+        let since = now - chrono::Duration::minutes(LATENCY_WINDOW_MINS);
+        let latency = match mean_fetch_latency(context, since, now).await {
+            Ok(latency) => latency,
+            Err(err) => {
+                tracing::warn!("failed to read the fetch latency: {}", err);
+                None
+            }
+        };
+        gc::merge_packs(context, &self.pack_dir, &before.small_packs).await?;
+        self.last_repack = Some((Utc::now().naive_utc(), latency));
+        let after = self.measure(context).await?;
+        Ok(RepackCheck {
+            before,
+            repack: Some((reason, after)),
+        })
+    }
+
+    async fn measure(&mut self, context: &Context) -> Result<Fragmentation, MegaError> {
+        Fragmentation::measure(
+            context,
+            &self.pack_dir,
+            self.policy.small_pack_objects,
+            &mut self.known,
+        )
+        .await
+    }
+}
+
+/// The mean duration of the fetches and clones recorded between `since` and `until`, `None`
+/// when there was none.
+async fn mean_fetch_latency(
+    context: &Context,
+    since: NaiveDateTime,
+    until: NaiveDateTime,
+) -> Result<Option<f64>, MegaError> {
+    let mut durations = Vec::new();
+    for operation in [OperationType::Fetch, OperationType::Clone] {
+        let filter = OperationFilter {
+            operation: Some(operation),
+            since: Some(since),
+            until: Some(until),
+            ..Default::default()
+        };
+        let operations = context
+            .services
+            .audit_storage
+            .get_operations(filter)
+            .await?;
+        durations.extend(operations.into_iter().map(|op| op.duration_ms));
+    }
+    if durations.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(
+        durations.iter().sum::<i64>() as f64 / durations.len() as f64,
+    ))
+}
+
+/// The `.pack` files of `pack_dir` with their `.idx`.
+fn indexed_packs(pack_dir: &Path) -> Result<Vec<PathBuf>, MegaError> {
+    let io_error =
+        |e: std::io::Error| MegaError::with_message(&format!("{}: {}", pack_dir.display(), e));
+    let mut packs = Vec::new();
+    for dir_entry in fs::read_dir(pack_dir).map_err(io_error)? {
+        let path = dir_entry.map_err(io_error)?.path();
+        if path.extension().is_some_and(|ext| ext == "pack") && path.with_extension("idx").is_file()
+        {
+            packs.push(path);
+        }
+    }
+    packs.sort();
+    Ok(packs)
+}
+
+/// Reads the figures of the pack at `path` of `pack_dir` by decoding it.
+pub async fn pack_stats(
+    context: &Context,
+    pack_dir: &Path,
+    path: &Path,
+) -> Result<PackStats, MegaError> {
+    let error = |e: String| MegaError::with_message(&format!("{}: {}", path.display(), e));
+    let file = File::open(path).map_err(|e| error(e.to_string()))?;
+    let bytes = file.metadata().map_err(|e| error(e.to_string()))?.len();
+    let mut pack = gc::decode_pack(context, pack_dir);
+    let stats =
+        tokio::task::spawn_blocking(move || pack.decode_stream(&mut BufReader::new(file), |_| {}))
+            .await
+            .unwrap()
+            .map_err(|e| error(e.to_string()))?;
+    Ok(PackStats {
+        objects: stats.commits + stats.trees + stats.blobs + stats.tags,
+        deltas: stats.offset_deltas + stats.hash_deltas,
+        max_delta_depth: stats.max_delta_depth,
+        bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use chrono::Utc;
+    use sea_orm::{DatabaseBackend, MockDatabase};
+
+    use callisto::db_enums::OperationType;
+    use callisto::git_operation;
+    use common::utils::generate_id;
+    use jupiter::context::Context;
+    use jupiter::storage::audit_storage::AuditStorage;
+    use mercury::internal::pack::index::PackIndex;
+    use venus::internal::object::blob::Blob;
+    use venus::internal::pack::entry::Entry;
+
+    use super::{Fragmentation, RepackPolicy, RepackReason, RepackScheduler};
+    use crate::gc;
+
+    /// Writes a pack of a blob of every content to `pack_dir`.
+    async fn write_pack(context: &Context, pack_dir: &Path, contents: &[&str]) -> PathBuf {
+        let entries: Vec<Entry> = contents
+            .iter()
+            .map(|content| Blob::from_content(content).into())
+            .collect();
+        gc::encode_pack(context, pack_dir, entries.len(), |sender| async move {
+            for entry in entries {
+                sender.send(entry).await.unwrap();
+            }
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    fn fetch(duration_ms: i64) -> git_operation::Model {
+        git_operation::Model {
+            id: generate_id(),
+            repo_path: String::from("/"),
+            operation: OperationType::Fetch,
+            user_name: None,
+            client_ip: None,
+            user_agent: None,
+            token_id: None,
+            refs: String::new(),
+            bytes_transferred: 0,
+            duration_ms,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    fn fragmentation(
+        packs: usize,
+        small_packs: usize,
+        objects: usize,
+        deltas: usize,
+    ) -> Fragmentation {
+        Fragmentation {
+            packs,
+            small_packs: (0..small_packs)
+                .map(|i| PathBuf::from(i.to_string()))
+                .collect(),
+            small_pack_objects: objects,
+            small_pack_deltas: deltas,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_repack_policy() {
+        let policy = RepackPolicy {
+            max_packs: 10,
+            small_pack_objects: 100,
+            max_small_pack_ratio: 0.5,
+            min_delta_ratio: 0.3,
+        };
+        assert_eq!(
+            policy.check(&fragmentation(11, 2, 100, 50)),
+            Some(RepackReason::TooManyPacks(11))
+        );
+        assert_eq!(
+            policy.check(&fragmentation(4, 3, 100, 50)),
+            Some(RepackReason::SmallPacks(0.75))
+        );
+        assert_eq!(
+            policy.check(&fragmentation(4, 2, 100, 10)),
+            Some(RepackReason::FewDeltas(0.1))
+        );
+        assert_eq!(policy.check(&fragmentation(4, 2, 100, 50)), None);
+        // a single small pack has nothing to be merged with
+        assert_eq!(policy.check(&fragmentation(20, 1, 100, 0)), None);
+    }
+
+    #[tokio::test]
+    async fn test_repack_scheduler() {
+        let pack_dir = env::temp_dir().join(format!("mega_test_fragmentation_{}", generate_id()));
+        fs::create_dir_all(&pack_dir).unwrap();
+        // the fetches and the clones before the repack, then since
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_query_results([vec![fetch(120), fetch(80)], vec![]])
+            .append_query_results([vec![fetch(40)], vec![]]);
+        let mut context = Context::mock();
+        let mut services = (*context.services).clone();
+        services.audit_storage = Arc::new(AuditStorage {
+            connection: Arc::new(db.into_connection()),
+        });
+        context.services = Arc::new(services);
+        let large = write_pack(&context, &pack_dir, &["a", "b", "c", "d"]).await;
+        write_pack(&context, &pack_dir, &["e"]).await;
+        write_pack(&context, &pack_dir, &["f", "a"]).await;
+
+        let policy = RepackPolicy {
+            small_pack_objects: 3,
+            ..RepackPolicy::default()
+        };
+        let mut known = HashMap::new();
+        let measured = Fragmentation::measure(&context, &pack_dir, 3, &mut known)
+            .await
+            .unwrap();
+        assert_eq!(measured.packs, 3);
+        assert_eq!(measured.objects, 7);
+        assert_eq!(measured.small_packs.len(), 2);
+        assert_eq!(measured.small_pack_objects, 3);
+        assert_eq!(known.len(), 3);
+
+        // the two small packs are merged, the large one is left as it is
+        let mut scheduler = RepackScheduler::new(pack_dir.clone(), policy);
+        let check = scheduler.check(&context).await.unwrap();
+        assert_eq!(check.before, measured);
+        let (reason, after) = check.repack.unwrap();
+        assert!(matches!(reason, RepackReason::SmallPacks(_)));
+        assert_eq!(after.packs, 2);
+        assert_eq!(after.objects, 7);
+        assert!(large.is_file());
+        assert!(after.small_packs.is_empty());
+        let merged: Vec<PathBuf> = fs::read_dir(&pack_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "pack") && *path != large)
+            .collect();
+        assert_eq!(merged.len(), 1);
+        let index = PackIndex::read(&merged[0].with_extension("idx")).unwrap();
+        assert_eq!(index.len(), 3);
+        // the latency since is read on the next check, nothing is left to merge
+        assert!(scheduler.check(&context).await.unwrap().repack.is_none());
+        fs::remove_dir_all(&pack_dir).unwrap();
+    }
+}
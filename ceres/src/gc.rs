@@ -0,0 +1,737 @@
+//!
+//! Garbage collection of the objects stored in the database. The objects reachable from the
+//! refs and the pins of a repository, from the commits of an open merge request or from a
+//! read lease are kept, the others are removed once they are older than a grace period: a
+//! push in progress stores its objects before it updates the refs. The content of a blob kept
+//! in the raw storage is removed with it. The walk holds the ids of the objects it reached,
+//! up to a limit past which it fails rather than exhausting the memory.
+//!
+//! The objects kept can then be repacked into a few packs with their `.idx`, written to a
+//! directory of packs and replacing the packs whose objects they all hold. The small packs of
+//! the directory are merged on their own once it gets fragmented, see [`crate::fragmentation`].
+//!
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{Duration, Utc};
+use tokio::sync::mpsc;
+
+use common::errors::MegaError;
+use common::utils::generate_id;
+use jupiter::context::Context;
+use jupiter::storage::mega_storage::StoredObjects;
+use mercury::internal::pack::encode::PackEncoder;
+use mercury::internal::pack::index::PackIndex;
+use mercury::internal::pack::midx::{self, MULTI_PACK_INDEX};
+use mercury::internal::pack::Pack;
+use venus::errors::GitError;
+use venus::hash::SHA1;
+use venus::internal::object::types::ObjectType;
+use venus::internal::pack::entry::Entry;
+use venus::repo::Repo;
+
+/// Days an unreachable object is kept after it was stored.
+pub const DEFAULT_EXPIRE_DAYS: u32 = 14;
+/// Reachable objects the walk keeps in memory at most, about 40 bytes each.
+pub const DEFAULT_MAX_REACHABLE: usize = 20_000_000;
+/// Objects of a pack written by [`repack`], the last pack holds the rest.
+pub const MAX_PACK_OBJECTS: usize = 1 << 20;
+/// Objects loaded from the database at once.
+const BATCH_SIZE: usize = 1000;
+const PACK_WINDOW: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct GcReport {
+    pub repositories: usize,
+    /// Objects reachable from a ref, a pin, an open merge request or a read lease.
+    pub reachable: usize,
+    /// The objects removed, or to remove on a dry run.
+    pub unreachable: StoredObjects,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RepackReport {
+    /// The number of objects of every pack, of the packs to write on a dry run.
+    pub packs: Vec<usize>,
+    /// The packs written, none on a dry run.
+    pub written: Vec<PathBuf>,
+    /// The packs removed since the new packs hold all their objects.
+    pub replaced: Vec<PathBuf>,
+    pub dry_run: bool,
+}
+
+/// Every object reachable in the monorepo and the imported repositories, with the number of
+/// repositories walked. Fails when more than `max_reachable` objects are, see
+/// [`MegaStorage::walk_reachable`](jupiter::storage::mega_storage::MegaStorage::walk_reachable).
+async fn reachable_objects(
+    context: &Context,
+    max_reachable: usize,
+) -> Result<(usize, HashSet<SHA1>), MegaError> {
+    let storage = &context.services.mega_storage;
+    let mut repos: Vec<Repo> = vec![Repo::empty()];
+    repos.extend(storage.get_git_repos().await?.into_iter().map(Repo::from));
+    let mut protected = storage.gc_protected_commits().await?;
+
+    let mut reachable = HashSet::new();
+    for repo in &repos {
+        let (mut commits, trees) = storage.reachable_roots(repo).await?;
+        commits.extend(protected.remove(&repo.repo_id).unwrap_or_default());
+        storage
+            .walk_reachable(repo, commits, trees, &mut reachable, max_reachable)
+            .await?;
+    }
+    Ok((repos.len(), reachable))
+}
+
+fn is_reachable(reachable: &HashSet<SHA1>, id: &str) -> bool {
+    SHA1::from_str(id).is_ok_and(|id| reachable.contains(&id))
+}
+
+/// Removes the objects stored more than `expire_days` ago that nothing reaches, or only
+/// reports them on a `dry_run`. The content of their blobs kept in the raw storage is removed
+/// with them.
+pub async fn prune(
+    context: &Context,
+    expire_days: u32,
+    max_reachable: usize,
+    dry_run: bool,
+) -> Result<GcReport, MegaError> {
+    let storage = &context.services.mega_storage;
+    let before = Utc::now().naive_utc() - Duration::days(expire_days.into());
+    // the candidates are listed first, an object stored during the walk is too recent anyway,
+    // and one pushed again has its `created_at` refreshed, so that the removal skips it
+    let mut unreachable = storage.stored_objects(before).await?;
+    let (repositories, reachable) = reachable_objects(context, max_reachable).await?;
+    // an id that is not one is kept
+    unreachable.retain(|id| SHA1::from_str(id).is_ok() && !is_reachable(&reachable, id));
+    if !dry_run && !unreachable.is_empty() {
+        storage.remove_objects(&unreachable, before).await?;
+    }
+    Ok(GcReport {
+        repositories,
+        reachable: reachable.len(),
+        unreachable,
+        dry_run,
+    })
+}
+
+/// Writes the reachable commits, trees and blobs kept in the database to packs of at most
+/// [`MAX_PACK_OBJECTS`] objects in `pack_dir`, then removes the older packs of the directory
+/// whose objects are all in the new ones. On a `dry_run`, nothing is written or removed.
+pub async fn repack(
+    context: &Context,
+    pack_dir: &Path,
+    max_reachable: usize,
+    dry_run: bool,
+) -> Result<RepackReport, MegaError> {
+    let storage = &context.services.mega_storage;
+    let mut objects = storage.stored_objects(Utc::now().naive_utc()).await?;
+    let (_, reachable) = reachable_objects(context, max_reachable).await?;
+    objects.retain(|id| is_reachable(&reachable, id));
+    // tags are left in the database, see `MegaStorage::get_entries`
+    objects.tags.clear();
+    objects.blobs = storage.get_database_blobs(&objects.blobs).await?;
+
+    let packed: HashSet<&str> = objects
+        .commits
+        .iter()
+        .chain(&objects.trees)
+        .chain(&objects.blobs)
+        .map(String::as_str)
+        .collect();
+    let mut replaced = replaced_packs(pack_dir, &packed)?;
+    let mut packs = Vec::new();
+    let mut written = Vec::new();
+    for chunk in split(objects, MAX_PACK_OBJECTS) {
+        packs.push(chunk.len());
+        if !dry_run {
+            written.push(write_pack(context, pack_dir, chunk).await?);
+        }
+    }
+    // a pack written again with the same objects has the same name
+    replaced.retain(|pack| !written.contains(pack));
+    if !dry_run {
+        for pack in &replaced {
+            remove_pack(pack)?;
+        }
+    }
+    Ok(RepackReport {
+        packs,
+        written,
+        replaced,
+        dry_run,
+    })
+}
+
+/// Splits `objects` into parts of at most `size` objects, keeping the order of the types.
+fn split(mut objects: StoredObjects, size: usize) -> Vec<StoredObjects> {
+    let fields: [fn(&mut StoredObjects) -> &mut Vec<String>; 4] = [
+        |o| &mut o.commits,
+        |o| &mut o.trees,
+        |o| &mut o.blobs,
+        |o| &mut o.tags,
+    ];
+    let mut parts = vec![];
+    let mut part = StoredObjects::default();
+    for field in fields {
+        for id in field(&mut objects).drain(..) {
+            if part.len() == size {
+                parts.push(std::mem::take(&mut part));
+            }
+            field(&mut part).push(id);
+        }
+    }
+    if !part.is_empty() {
+        parts.push(part);
+    }
+    parts
+}
+
+/// The packs of `pack_dir` with an index whose objects are all in `packed`.
+fn replaced_packs(pack_dir: &Path, packed: &HashSet<&str>) -> Result<Vec<PathBuf>, MegaError> {
+    let mut replaced = Vec::new();
+    for dir_entry in fs::read_dir(pack_dir).map_err(|e| io_error(pack_dir, e))? {
+        let path = dir_entry.map_err(|e| io_error(pack_dir, e))?.path();
+        if path.extension().is_none_or(|ext| ext != "pack") {
+            continue;
+        }
+        let Ok(index) = PackIndex::read(&path.with_extension("idx")) else {
+            continue;
+        };
+        if index
+            .entries()
+            .iter()
+            .all(|entry| packed.contains(entry.hash.to_plain_str().as_str()))
+        {
+            replaced.push(path);
+        }
+    }
+    replaced.sort();
+    Ok(replaced)
+}
+
+/// Encodes `objects` to `pack-<hash>.pack` in `pack_dir` and indexes it.
+async fn write_pack(
+    context: &Context,
+    pack_dir: &Path,
+    objects: StoredObjects,
+) -> Result<PathBuf, MegaError> {
+    let storage = context.services.mega_storage.clone();
+    encode_pack(context, pack_dir, objects.len(), |sender| async move {
+        for batch in split(objects, BATCH_SIZE) {
+            for entry in storage.get_entries(&batch).await? {
+                // the encoder stopped on an error, it is returned by `encode_pack`
+                if sender.send(entry).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Encodes the `count` objects `load` sends to `pack-<hash>.pack` in `pack_dir` and indexes
+/// it.
+pub(crate) async fn encode_pack<F, Fut>(
+    context: &Context,
+    pack_dir: &Path,
+    count: usize,
+    load: F,
+) -> Result<PathBuf, MegaError>
+where
+    F: FnOnce(mpsc::Sender<Entry>) -> Fut,
+    Fut: Future<Output = Result<(), MegaError>>,
+{
+    let temp_path = pack_dir.join(format!("tmp_pack_{}", generate_id()));
+    let file = File::create(&temp_path).map_err(|e| io_error(&temp_path, e))?;
+    let (sender, mut receiver) = mpsc::channel::<Entry>(BATCH_SIZE);
+    let encoder = tokio::task::spawn_blocking(move || {
+        let mut encoder = PackEncoder::new(count, PACK_WINDOW, BufWriter::new(file));
+        encoder.encode_entries(std::iter::from_fn(|| receiver.blocking_recv()))?;
+        Ok::<_, GitError>(encoder.get_hash().unwrap())
+    });
+    let loaded = load(sender).await;
+    let encoded = encoder.await.unwrap();
+    let hash = match (loaded, encoded) {
+        (Ok(()), Ok(hash)) => hash,
+        (loaded, encoded) => {
+            let _ = fs::remove_file(&temp_path);
+            loaded?;
+            return Err(MegaError::with_message(&encoded.unwrap_err().to_string()));
+        }
+    };
+
+    let pack_path = pack_dir.join(format!("pack-{}.pack", hash));
+    fs::rename(&temp_path, &pack_path).map_err(|e| io_error(&pack_path, e))?;
+    let index_path = pack_path.with_extension("idx");
+    let path = pack_path.clone();
+    let mut pack = decode_pack(context, pack_dir);
+    tokio::task::spawn_blocking(move || {
+        let file = File::open(&path).map_err(|e| io_error(&path, e))?;
+        pack.decode_with_index(&mut BufReader::new(file), &index_path, |_| {})
+            .map_err(|e| MegaError::with_message(&format!("{}: {}", path.display(), e)))
+    })
+    .await
+    .unwrap()?;
+    Ok(pack_path)
+}
+
+/// A decoder of the packs of `pack_dir`, spilling to a cache in the directory.
+pub(crate) fn decode_pack(context: &Context, pack_dir: &Path) -> Pack {
+    let mem_limit = context.settings.current().pack_decode_mem_limit * 1024 * 1024;
+    Pack::new(None, Some(mem_limit), Some(pack_dir.join(".cache_temp")))
+}
+
+/// Merges `packs` of `pack_dir` into one pack and removes them, a multi-pack-index of the
+/// directory is written again. Their objects are read back from the packs and held in memory
+/// while they are encoded, so it is meant for small packs. An object pruned from the database
+/// since they were written stays until the next [`repack`].
+pub async fn merge_packs(
+    context: &Context,
+    pack_dir: &Path,
+    packs: &[PathBuf],
+) -> Result<PathBuf, MegaError> {
+    let mut objects: HashMap<SHA1, Entry> = HashMap::new();
+    for path in packs {
+        let decoded = Arc::new(Mutex::new(Vec::new()));
+        let sink = decoded.clone();
+        let mut pack = decode_pack(context, pack_dir);
+        let file = File::open(path).map_err(|e| io_error(path, e))?;
+        tokio::task::spawn_blocking(move || {
+            pack.decode_stream(&mut BufReader::new(file), move |entry| {
+                sink.lock().unwrap().push(entry)
+            })
+        })
+        .await
+        .unwrap()
+        .map_err(|e| MegaError::with_message(&format!("{}: {}", path.display(), e)))?;
+        let decoded = std::mem::take(&mut *decoded.lock().unwrap());
+        objects.extend(decoded.into_iter().map(|entry| (entry.hash, entry)));
+    }
+    // in the order `repack` writes them, the deltas are searched among objects of a type
+    let mut entries: Vec<Entry> = objects.into_values().collect();
+    entries.sort_by_key(|entry| (type_rank(entry.obj_type), entry.hash));
+    let merged = encode_pack(context, pack_dir, entries.len(), |sender| async move {
+        for entry in entries {
+            if sender.send(entry).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    })
+    .await?;
+    for pack in packs.iter().filter(|pack| **pack != merged) {
+        remove_pack(pack)?;
+    }
+    if pack_dir.join(MULTI_PACK_INDEX).is_file() {
+        midx::write_multi_pack_index(pack_dir)
+            .map_err(|e| MegaError::with_message(&e.to_string()))?;
+    }
+    Ok(merged)
+}
+
+fn type_rank(obj_type: ObjectType) -> u8 {
+    match obj_type {
+        ObjectType::Commit => 0,
+        ObjectType::Tree => 1,
+        ObjectType::Blob => 2,
+        _ => 3,
+    }
+}
+
+/// Removes the `.pack` at `pack` with its index and bitmaps.
+fn remove_pack(pack: &Path) -> Result<(), MegaError> {
+    for ext in ["pack", "idx", "bitmap"] {
+        let path = pack.with_extension(ext);
+        if path.is_file() {
+            fs::remove_file(&path).map_err(|e| io_error(&path, e))?;
+        }
+    }
+    Ok(())
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> MegaError {
+    MegaError::with_message(&format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use sea_orm::{DatabaseBackend, DatabaseConnection, MockDatabase, MockExecResult, Value};
+
+    use callisto::db_enums::RefType;
+    use callisto::{git_repo, mega_commit, mega_object_pin, mega_tree, raw_blob, refs};
+    use common::utils::generate_id;
+    use jupiter::context::Context;
+    use jupiter::raw_storage::local_storage::LocalStorage;
+    use jupiter::storage::mega_storage::{MegaStorage, StoredObjects, RAW_BLOB_DIR};
+    use mercury::internal::pack::index::PackIndex;
+    use venus::hash::SHA1;
+    use venus::internal::object::blob::Blob;
+    use venus::internal::object::commit::Commit;
+    use venus::internal::object::signature::{Signature, SignatureType};
+    use venus::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+    use venus::internal::object::types::ObjectType;
+    use venus::internal::pack::entry::Entry;
+    use venus::mr::MergeRequest;
+    use venus::repo::Repo;
+
+    use super::{prune, repack, split};
+
+    type Row = BTreeMap<String, Value>;
+
+    /// A row of a query selecting `values`, in that order.
+    fn row(values: Vec<Value>) -> Row {
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(index, value)| (index.to_string(), value))
+            .collect()
+    }
+
+    fn id_rows(ids: &[SHA1]) -> Vec<Row> {
+        ids.iter()
+            .map(|id| row(vec![id.to_plain_str().into()]))
+            .collect()
+    }
+
+    fn tree(blob: &Blob, name: &str) -> Tree {
+        Tree::from_tree_items(vec![TreeItem::new(
+            TreeItemMode::Blob,
+            blob.id,
+            name.into(),
+        )])
+        .unwrap()
+    }
+
+    fn commit(tree: &Tree, message: &str) -> Commit {
+        let signature = Signature {
+            signature_type: SignatureType::Committer,
+            name: String::from("alice"),
+            email: String::from("alice@example.com"),
+            timestamp: 1000,
+            timezone: String::from("+0000"),
+        };
+        let mut commit = Commit {
+            id: SHA1::default(),
+            tree_id: tree.id,
+            parent_commit_ids: vec![],
+            author: Signature {
+                signature_type: SignatureType::Author,
+                ..signature.clone()
+            },
+            committer: signature,
+            message: format!("\n{}", message),
+        };
+        commit.id = SHA1::from_type_and_data(ObjectType::Commit, &commit.to_data().unwrap());
+        commit
+    }
+
+    /// The row of `commit`, with its signatures as git writes them.
+    fn commit_model(commit: &Commit) -> mega_commit::Model {
+        let signature = |s: &Signature| String::from_utf8(s.to_data().unwrap()).unwrap();
+        mega_commit::Model {
+            author: Some(signature(&commit.author)),
+            committer: Some(signature(&commit.committer)),
+            ..commit.clone().into()
+        }
+    }
+
+    /// main is at `c1`, which reaches `t1` and `b1`. The open merge request 7 carries `c2`,
+    /// whose tree `t2` reaches `b3`, stored before the merge request, and `b2`, stored with it.
+    /// Nothing reaches `c3`, `t3` and `b4`, the content of `b4` is in the raw storage.
+    struct History {
+        c1: Commit,
+        c2: Commit,
+        c3: Commit,
+        t1: Tree,
+        t2: Tree,
+        t3: Tree,
+        b1: Blob,
+        b2: Blob,
+        b3: Blob,
+        b4: Blob,
+    }
+
+    impl History {
+        fn new() -> Self {
+            let (b1, b2, b3, b4) = (
+                Blob::from_content("b1"),
+                Blob::from_content("b2"),
+                Blob::from_content("b3"),
+                Blob::from_content("b4"),
+            );
+            let (t1, t3) = (tree(&b1, "a"), tree(&b4, "d"));
+            let t2 = Tree::from_tree_items(vec![
+                TreeItem::new(TreeItemMode::Blob, b2.id, "b".into()),
+                TreeItem::new(TreeItemMode::Blob, b3.id, "c".into()),
+            ])
+            .unwrap();
+            History {
+                c1: commit(&t1, "main"),
+                c2: commit(&t2, "open"),
+                c3: commit(&t3, "abandoned"),
+                t1,
+                t2,
+                t3,
+                b1,
+                b2,
+                b3,
+                b4,
+            }
+        }
+
+        /// The answers to the queries of `MegaStorage::stored_objects`.
+        fn stored(&self, db: MockDatabase, open_mr: bool) -> MockDatabase {
+            let open_mrs: Vec<Row> = match open_mr {
+                true => vec![row(vec![7i64.into()])],
+                false => vec![],
+            };
+            let open_blobs = match open_mr {
+                true => id_rows(&[self.b2.id]),
+                false => vec![],
+            };
+            db.append_query_results([
+                open_mrs,
+                id_rows(&[self.c1.id, self.c3.id]),
+                id_rows(&[self.t1.id, self.t3.id]),
+                open_blobs,
+                id_rows(&[self.b1.id, self.b2.id, self.b3.id, self.b4.id]),
+                vec![],
+            ])
+        }
+
+        /// The answers to the queries of the walk of the reachable objects.
+        fn walked(&self, db: MockDatabase, open_mr: bool) -> MockDatabase {
+            let main = refs::Model {
+                id: 1,
+                repo_id: 0,
+                ref_name: String::from("refs/heads/main"),
+                ref_git_id: self.c1.id.to_plain_str(),
+                ref_type: RefType::Branch,
+                created_at: chrono::Utc::now().naive_utc(),
+                updated_at: chrono::Utc::now().naive_utc(),
+            };
+            let open_mrs: Vec<Row> = match open_mr {
+                true => vec![row(vec![7i64.into()])],
+                false => vec![],
+            };
+            let mut db = db
+                .append_query_results([Vec::<git_repo::Model>::new()])
+                .append_query_results([open_mrs]);
+            if open_mr {
+                db = db.append_query_results([vec![row(vec![
+                    0i64.into(),
+                    self.c2.id.to_plain_str().into(),
+                ])]]);
+            }
+            let mut commits: Vec<mega_commit::Model> = vec![commit_model(&self.c1)];
+            let mut trees: Vec<mega_tree::Model> = vec![self.t1.clone().into()];
+            if open_mr {
+                // the commits of the merge request are walked first, the trees last
+                commits.insert(0, commit_model(&self.c2));
+                trees.push(self.t2.clone().into());
+            }
+            db.append_query_results([Vec::<Row>::new()])
+                .append_query_results([vec![main]])
+                .append_query_results([Vec::<mega_object_pin::Model>::new()])
+                .append_query_results(commits.into_iter().map(|c| vec![c]))
+                .append_query_results(trees.into_iter().map(|t| vec![t]))
+        }
+    }
+
+    /// A context whose database answers with `db`, its raw storage in `dir`.
+    fn mock_context(db: MockDatabase, dir: &Path) -> Context {
+        let mut context = Context::mock();
+        let mut services = (*context.services).clone();
+        services.mega_storage = Arc::new(MegaStorage {
+            connection: Arc::new(db.into_connection()),
+            raw_storage: Arc::new(LocalStorage::init(dir.to_path_buf())),
+            ..MegaStorage::mock()
+        });
+        context.services = Arc::new(services);
+        context
+    }
+
+    /// The SQL run on `connection`, the statements of a mock transaction are only exposed by
+    /// its debug output.
+    fn logged_sql(connection: DatabaseConnection) -> String {
+        format!("{:?}", connection.into_transaction_log()).replace("\\\"", "\"")
+    }
+
+    fn postgres() -> MockDatabase {
+        MockDatabase::new(DatabaseBackend::Postgres)
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let dir = env::temp_dir().join(format!("mega_test_prune_{}", generate_id()));
+        let history = History::new();
+        let b4 = history.b4.id.to_plain_str();
+        let walked = || history.walked(history.stored(postgres(), true), true);
+
+        // a dry run only reports the objects nothing reaches, those of main and of the open
+        // merge request are kept
+        let context = mock_context(walked(), &dir);
+        let raw_storage = context.services.mega_storage.raw_storage.clone();
+        raw_storage
+            .put_object(RAW_BLOB_DIR, &b4, b"b4")
+            .await
+            .unwrap();
+        let report = prune(&context, 14, usize::MAX, true).await.unwrap();
+        assert_eq!(report.repositories, 1);
+        assert_eq!(report.reachable, 7);
+        assert_eq!(
+            report.unreachable,
+            StoredObjects {
+                commits: vec![history.c3.id.to_plain_str()],
+                trees: vec![history.t3.id.to_plain_str()],
+                blobs: vec![b4.clone()],
+                tags: vec![],
+            }
+        );
+        assert!(raw_storage.exist_object(RAW_BLOB_DIR, &b4));
+
+        // the content of a blob kept out of the database is removed with its row
+        let db = walked()
+            .append_query_results([id_rows(&[history.b4.id])])
+            .append_exec_results((0..4).map(|_| MockExecResult {
+                last_insert_id: 0,
+                rows_affected: 1,
+            }));
+        let report = prune(&mock_context(db, &dir), 14, usize::MAX, false)
+            .await
+            .unwrap();
+        assert_eq!(report.unreachable.len(), 3);
+        assert!(!raw_storage.exist_object(RAW_BLOB_DIR, &b4));
+
+        // the walk stops past the objects it may hold
+        let err = prune(&mock_context(walked(), &dir), 14, 2, true)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().ends_with("more than 2 objects are reachable"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_prune_repushed() {
+        let dir = env::temp_dir().join(format!("mega_test_prune_repushed_{}", generate_id()));
+        let history = History::new();
+        let b4 = history.b4.id.to_plain_str();
+        let applied = |rows_affected| MockExecResult {
+            last_insert_id: 0,
+            rows_affected,
+        };
+
+        // `b4` is pushed again while a gc walks the refs, the push refreshes when it was stored
+        let storage = MegaStorage {
+            connection: Arc::new(
+                postgres()
+                    .append_exec_results([applied(1), applied(1)])
+                    .into_connection(),
+            ),
+            ..MegaStorage::mock()
+        };
+        let entries = vec![Entry::from(history.b4.clone())];
+        storage
+            .save_entry(&MergeRequest::default(), &Repo::empty(), entries)
+            .await
+            .unwrap();
+        let sql = logged_sql(Arc::try_unwrap(storage.connection).unwrap());
+        assert!(sql.contains(
+            r#"ON CONFLICT ("sha1") DO UPDATE SET "created_at" = "excluded"."created_at""#
+        ));
+
+        // the gc listed it before, but its removal no longer finds it old
+        let db = history
+            .walked(history.stored(postgres(), true), true)
+            .append_query_results([Vec::<Row>::new()])
+            .append_exec_results([applied(1), applied(1), applied(0), applied(0)]);
+        let context = mock_context(db, &dir);
+        let connection = context.services.mega_storage.connection.clone();
+        let raw_storage = context.services.mega_storage.raw_storage.clone();
+        raw_storage
+            .put_object(RAW_BLOB_DIR, &b4, b"b4")
+            .await
+            .unwrap();
+        prune(&context, 14, usize::MAX, false).await.unwrap();
+        drop(context);
+        assert!(raw_storage.exist_object(RAW_BLOB_DIR, &b4));
+        let sql = logged_sql(Arc::try_unwrap(connection).unwrap());
+        assert!(sql.contains(r#""raw_blob"."created_at" < $2"#));
+        assert!(sql.contains("FOR UPDATE"));
+        assert!(sql.contains(r#""mega_blob"."created_at" < $2"#));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_repack() {
+        let dir = env::temp_dir().join(format!("mega_test_repack_{}", generate_id()));
+        let pack_dir = dir.join("packs");
+        fs::create_dir_all(&pack_dir).unwrap();
+        let history = History::new();
+        let walked = || {
+            history
+                .walked(history.stored(postgres(), false), false)
+                .append_query_results([id_rows(&[history.b1.id])])
+        };
+
+        // only the objects reachable from main are packed
+        let report = repack(&mock_context(walked(), &dir), &pack_dir, usize::MAX, true)
+            .await
+            .unwrap();
+        assert_eq!(report.packs, [3]);
+        assert!(report.written.is_empty());
+        assert_eq!(fs::read_dir(&pack_dir).unwrap().count(), 0);
+
+        let commit = commit_model(&history.c1);
+        let tree: mega_tree::Model = history.t1.clone().into();
+        let blob: raw_blob::Model = history.b1.clone().into();
+        let db = walked()
+            .append_query_results([vec![commit]])
+            .append_query_results([vec![tree]])
+            .append_query_results([vec![blob]]);
+        let report = repack(&mock_context(db, &dir), &pack_dir, usize::MAX, false)
+            .await
+            .unwrap();
+        assert_eq!(report.written.len(), 1);
+        let index = PackIndex::read(&report.written[0].with_extension("idx")).unwrap();
+        let mut packed: Vec<SHA1> = index.entries().iter().map(|entry| entry.hash).collect();
+        packed.sort();
+        let mut expected = vec![history.c1.id, history.t1.id, history.b1.id];
+        expected.sort();
+        assert_eq!(packed, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_split() {
+        let ids = |prefix: &str, n: usize| (0..n).map(|i| format!("{}{}", prefix, i)).collect();
+        let objects = StoredObjects {
+            commits: ids("c", 2),
+            trees: ids("t", 3),
+            blobs: ids("b", 4),
+            tags: vec![],
+        };
+        let parts = split(objects, 4);
+        assert_eq!(parts.iter().map(|p| p.len()).collect::<Vec<_>>(), [4, 4, 1]);
+        assert_eq!(parts[0].commits, ["c0", "c1"]);
+        assert_eq!(parts[0].trees, ["t0", "t1"]);
+        assert_eq!(parts[1].trees, ["t2"]);
+        assert_eq!(parts[1].blobs, ["b0", "b1", "b2"]);
+        assert_eq!(parts[2].blobs, ["b3"]);
+        assert!(split(StoredObjects::default(), 4).is_empty());
+    }
+}
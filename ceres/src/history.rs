@@ -0,0 +1,208 @@
+//!
+//! History of a repository, the queries behind `mega log` and `mega show`: the commits
+//! reachable from a revision, newest first, optionally only those changing a path, and the
+//! files a commit changes against its first parent.
+//!
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use common::errors::MegaError;
+use jupiter::context::Context;
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::internal::object::signature::Signature;
+use venus::internal::object::tree::{TreeItem, TreeItemMode};
+use venus::internal::revwalk::CommitGraph;
+use venus::repo::Repo;
+
+use crate::compare::{self, FileChange};
+use crate::replace::{self, Replacements};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Person {
+    pub name: String,
+    pub email: String,
+    pub timestamp: usize,
+    /// Offset of the local time, e.g. `+0800`.
+    pub timezone: String,
+}
+
+impl From<&Signature> for Person {
+    fn from(value: &Signature) -> Self {
+        Person {
+            name: value.name.clone(),
+            email: value.email.clone(),
+            timestamp: value.timestamp,
+            timezone: value.timezone.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CommitDetails {
+    pub id: String,
+    pub tree: String,
+    pub parents: Vec<String>,
+    pub author: Person,
+    pub committer: Person,
+    pub message: String,
+}
+
+impl From<&Commit> for CommitDetails {
+    fn from(value: &Commit) -> Self {
+        CommitDetails {
+            id: value.id.to_plain_str(),
+            tree: value.tree_id.to_plain_str(),
+            parents: value
+                .parent_commit_ids
+                .iter()
+                .map(|id| id.to_plain_str())
+                .collect(),
+            author: Person::from(&value.author),
+            committer: Person::from(&value.committer),
+            message: value.message.trim().to_owned(),
+        }
+    }
+}
+
+/// A commit and the files it changes, against nothing for a root commit.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommitChanges {
+    #[serde(flatten)]
+    pub commit: CommitDetails,
+    pub files: Vec<FileChange>,
+}
+
+/// The commits reachable from `rev` in the repository at `repo_path`, newest first, at most
+/// `limit` of them. With a `path`, only the commits whose entry at that path differs from the
+/// one of their first parent are listed.
+pub async fn log(
+    context: &Context,
+    repo_path: &str,
+    rev: &str,
+    path: Option<&str>,
+    limit: usize,
+) -> Result<Vec<CommitDetails>, MegaError> {
+    let (repo, id, _, graph) = compare::open_revisions(context, repo_path, rev, rev).await?;
+    let replacements = Replacements::load(context, &repo).await?;
+    let components: Option<Vec<&str>> =
+        path.map(|path| path.split('/').filter(|c| !c.is_empty()).collect());
+    let mut trees = HashMap::new();
+    let mut commits = Vec::new();
+    for id in graph.walk(&[id], &[]) {
+        if commits.len() >= limit {
+            break;
+        }
+        if let Some(components) = &components {
+            let parent = graph.parents_of(&id).first().and_then(|p| graph.tree(p));
+            let old = entry_at(context, parent, components, &mut trees).await?;
+            let new = entry_at(context, graph.tree(&id), components, &mut trees).await?;
+            if old == new {
+                continue;
+            }
+        }
+        let commit = read_commit(context, &repo, &replacements, &graph, &id).await?;
+        commits.extend(commit.as_ref().map(CommitDetails::from));
+    }
+    Ok(commits)
+}
+
+/// The commit `rev` resolves to in the repository at `repo_path` and the files it changes.
+pub async fn show(
+    context: &Context,
+    repo_path: &str,
+    rev: &str,
+) -> Result<CommitChanges, MegaError> {
+    let (repo, id, _, graph) = compare::open_revisions(context, repo_path, rev, rev).await?;
+    let replacements = Replacements::load(context, &repo).await?;
+    let commit = read_commit(context, &repo, &replacements, &graph, &id)
+        .await?
+        .ok_or_else(|| MegaError::with_message(&format!("commit not found: {}", rev)))?;
+    let parent = commit.parent_commit_ids.first().and_then(|p| graph.tree(p));
+    let files = compare::diff_trees(context, parent, Some(commit.tree_id)).await?;
+    Ok(CommitChanges {
+        commit: CommitDetails::from(&commit),
+        files,
+    })
+}
+
+/// A graph read from its file has no commit, they are then read from the storage.
+pub(crate) async fn read_commit(
+    context: &Context,
+    repo: &Repo,
+    replacements: &Replacements,
+    graph: &CommitGraph,
+    id: &SHA1,
+) -> Result<Option<Commit>, MegaError> {
+    match graph.commit(id) {
+        Some(commit) => Ok(Some(commit.clone())),
+        None => replace::read_commit(context, repo, replacements, id).await,
+    }
+}
+
+/// The id of the entry at `path` under the tree `root`, None if there is none. The items of
+/// the trees read are kept in `trees`, the commits of a history share most of them.
+pub(crate) async fn entry_at(
+    context: &Context,
+    root: Option<SHA1>,
+    path: &[&str],
+    trees: &mut HashMap<SHA1, Vec<TreeItem>>,
+) -> Result<Option<SHA1>, MegaError> {
+    let Some(mut current) = root else {
+        return Ok(None);
+    };
+    for (i, name) in path.iter().enumerate() {
+        let items = match trees.entry(current) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(compare::load_tree_items(context, Some(current)).await?)
+            }
+        };
+        // only the last component can be a file
+        match find_item(items, name) {
+            Some(item) if i + 1 == path.len() || item.mode == TreeItemMode::Tree => {
+                current = item.id
+            }
+            _ => return Ok(None),
+        }
+    }
+    Ok(Some(current))
+}
+
+/// The entry `name` of a directory, the submodules are left out, their history is not in
+/// the repository.
+fn find_item<'a>(items: &'a [TreeItem], name: &str) -> Option<&'a TreeItem> {
+    items
+        .iter()
+        .find(|item| item.name == name && item.mode != TreeItemMode::Commit)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use venus::hash::SHA1;
+    use venus::internal::object::tree::{TreeItem, TreeItemMode};
+
+    use super::find_item;
+
+    #[test]
+    fn test_find_item() {
+        let blob = SHA1::from_str("8ab686eafeb1f44702738c8b0f24f2567c36da6d").unwrap();
+        let tree = SHA1::from_str("4b825dc642cb6eb9a060e54bf8d69288fbee4904").unwrap();
+        let items = vec![
+            TreeItem::new(TreeItemMode::Blob, blob, String::from("README.md")),
+            TreeItem::new(TreeItemMode::Tree, tree, String::from("src")),
+            TreeItem::new(TreeItemMode::Commit, blob, String::from("vendor")),
+        ];
+        assert_eq!(
+            find_item(&items, "README.md").map(|item| item.id),
+            Some(blob)
+        );
+        assert_eq!(find_item(&items, "src").map(|item| item.id), Some(tree));
+        assert!(find_item(&items, "vendor").is_none());
+        assert!(find_item(&items, "missing").is_none());
+    }
+}
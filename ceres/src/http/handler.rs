@@ -4,19 +4,30 @@
 //!
 //!
 use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
 
 use anyhow::Result;
 use axum::body::Body;
 use axum::http::response::Builder;
-use axum::http::{Request, Response, StatusCode};
+use axum::http::{header, HeaderMap, Request, Response, StatusCode};
 use bytes::{Bytes, BytesMut};
-use futures::TryStreamExt;
+use futures::{Stream, StreamExt, TryStreamExt};
 use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tokio_util::io::StreamReader;
+use tracing::Instrument;
 
 use common::model::GetParams;
 
+use crate::http::resume::{parse_range, PackSessions, PackSpool, SESSION_HEADER};
+use crate::protocol::throttle::TokenBucket;
+use crate::protocol::v2::ProtocolVersion;
 use crate::protocol::{pack, PackProtocol, ServiceType};
 
+/// Bytes of a response sent at once when it is throttled or read back from a file.
+const PACK_CHUNK_SIZE: usize = 65536;
+
 // # Discovering Reference
 // HTTP clients that support the "smart" protocol (or both the "smart" and "dumb" protocols) MUST
 // discover references by making a parameterized request for the info/refs file of the repository.
@@ -30,7 +41,10 @@ pub async fn git_info_refs(
     let service_name = params.service.unwrap();
     pack_protocol.service_type = service_name.parse::<ServiceType>().unwrap();
     let resp = build_res_header(format!("application/x-{}-advertisement", service_name));
-    let pkt_line_stream = pack_protocol.git_info_refs().await;
+    let pkt_line_stream = match (pack_protocol.service_type, pack_protocol.version) {
+        (ServiceType::UploadPack, ProtocolVersion::V2) => pack_protocol.git_info_refs_v2(),
+        _ => pack_protocol.git_info_refs().await,
+    };
     let body = Body::from(pkt_line_stream.freeze());
     Ok(resp.body(body).unwrap())
 }
@@ -44,21 +58,28 @@ pub async fn git_info_refs(
 /// of the request body using `body.next().await`. The chunks are concatenated into the `upload_request`
 /// buffer.
 ///
-/// The `pack_protocol` is then used to process the `upload_request` using the `git_upload_pack` method.
-/// It returns the `send_pack_data` and `buf` containing the response data.
+/// A request repeated with the session of a response kept and a `Range` header is answered with
+/// the rest of that response instead, see [`crate::http::resume`].
 ///
-/// A response header is constructed using the `build_res_header` function with a content type of
-/// "application/x-git-upload-pack-result". The response body channel is created using `Body::channel()`.
+/// A client of protocol v2 sends one command per request, which is answered by
+/// [`PackProtocol::git_upload_pack_v2`].
 ///
-/// The `buf` is sent as the initial data using the `sender` to establish the response body.
+/// The `pack_protocol` is then used to process the `upload_request` using the `git_upload_pack` method.
+/// It returns the pack and the acknowledgements `buf`, sent first, then the pack in side-band
+/// chunks and a flush-pkt.
 ///
-/// A new task is spawned to send the remaining `send_pack_data` using the `send_pack` function.
+/// A response header is constructed using the `build_res_header` function with a content type of
+/// "application/x-git-upload-pack-result".
 ///
-/// Finally, the constructed response with the response body is returned.
+/// The response is streamed to the client, and written to a [`PackSpool`] as its chunks go out
+/// when the resume is enabled, see [`tee_body`]. The session is sent before the size of the
+/// response is known, a client resuming one too small to be kept is answered anew.
+#[tracing::instrument(skip_all)]
 pub async fn git_upload_pack(
     req: Request<Body>,
     mut pack_protocol: PackProtocol,
 ) -> Result<Response<Body>, (StatusCode, String)> {
+    let headers = req.headers().clone();
     let upload_request: BytesMut = req
         .into_body()
         .into_data_stream()
@@ -66,39 +87,196 @@ pub async fn git_upload_pack(
             acc.extend_from_slice(&chunk);
             Ok(acc)
         })
+        .instrument(tracing::info_span!("transport"))
         .await
         .unwrap();
     tracing::debug!("bytes from client: {:?}", upload_request);
+    let sessions = PackSessions::new(
+        pack_protocol
+            .context
+            .settings
+            .current()
+            .pack_resume_window_mins,
+    );
+    let session_id = PackSessions::session_id(&pack_protocol, &upload_request);
+    if let Some(offset) = resume_offset(&headers, &session_id) {
+        if let Some(resp) = resume_pack(&sessions, &session_id, offset, &pack_protocol)? {
+            return Ok(resp);
+        }
+    }
+
+    let mut resp = build_res_header("application/x-git-upload-pack-result".to_owned());
+    let mut upload_request = upload_request.freeze();
+    let chunks = match pack_protocol.version {
+        ProtocolVersion::V2 => pack_protocol
+            .git_upload_pack_v2(&mut upload_request)
+            .await
+            .unwrap(),
+        ProtocolVersion::V0 => upload_pack_v0(&mut pack_protocol, &mut upload_request)
+            .await
+            .boxed(),
+    };
+    let chunks = match sessions.spool(&session_id) {
+        Some(spool) => {
+            resp = resp
+                .header(SESSION_HEADER, &session_id)
+                .header(header::ACCEPT_RANGES, "bytes");
+            tee_body(chunks, spool).boxed()
+        }
+        None => chunks,
+    };
+    let body = match pack_protocol.upload_throttle() {
+        Some(bucket) => throttled_body(chunks, bucket),
+        None => Body::from_stream(chunks),
+    };
+    let resp = resp.body(body).unwrap();
+    Ok(resp)
+}
+
+/// The acknowledgements of a protocol v0 negotiation followed by the pack, in side-band when
+/// the client asked for it.
+async fn upload_pack_v0(
+    pack_protocol: &mut PackProtocol,
+    upload_request: &mut Bytes,
+) -> impl Stream<Item = io::Result<Bytes>> {
     let (send_pack_data, buf) = pack_protocol
-        .git_upload_pack(&mut upload_request.freeze())
+        .git_upload_pack(upload_request)
         .await
         .unwrap();
     tracing::info!("send ack/nak message buf: {:?}", buf);
-    let mut res_bytes = BytesMut::new();
-    res_bytes.extend(buf);
-
-    let resp = build_res_header("application/x-git-upload-pack-result".to_owned());
-
-    tracing::info!("send response");
-
-    let mut reader = send_pack_data.as_slice();
-    loop {
-        let mut temp = BytesMut::new();
-        temp.reserve(65500);
-        let length = reader.read_buf(&mut temp).await.unwrap();
-        if length == 0 {
-            let bytes_out = Bytes::from_static(pack::PKT_LINE_END_MARKER);
-            tracing::info!("send back pkt-flush line '0000', actually: {:?}", bytes_out);
-            res_bytes.extend(bytes_out);
-            break;
+    let side_band = pack_protocol.clone();
+    let pack = chunked(Bytes::from(send_pack_data)).map_ok(move |chunk| {
+        let length = chunk.len();
+        side_band
+            .build_side_band_format(BytesMut::from(&chunk[..]), length)
+            .freeze()
+    });
+    futures::stream::once(async { Ok(buf.freeze()) })
+        .chain(pack)
+        .chain(futures::stream::once(async {
+            Ok(Bytes::from_static(pack::PKT_LINE_END_MARKER))
+        }))
+}
+
+/// `bytes` in chunks of at most [`PACK_CHUNK_SIZE`], which are not copied.
+fn chunked(bytes: Bytes) -> impl Stream<Item = io::Result<Bytes>> {
+    let len = bytes.len();
+    let chunks = (0..len)
+        .step_by(PACK_CHUNK_SIZE)
+        .map(move |at| Ok(bytes.slice(at..len.min(at + PACK_CHUNK_SIZE))));
+    futures::stream::iter(chunks)
+}
+
+/// Sends `chunks` on and writes them to `spool` as they go out. They are read to the end even
+/// when the client is gone, so that the response it resumes is kept whole. A response failing
+/// midway is not kept.
+fn tee_body(
+    chunks: impl Stream<Item = io::Result<Bytes>> + Send + 'static,
+    spool: PackSpool,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let (sender, receiver) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut chunks = std::pin::pin!(chunks);
+        let mut spool = Some(spool);
+        while let Some(chunk) = chunks.next().await {
+            match (&chunk, &mut spool) {
+                (Ok(bytes), Some(file)) => {
+                    if let Err(e) = file.write(bytes) {
+                        tracing::warn!("failed to keep the pack to resume: {}", e);
+                        spool = None;
+                    }
+                }
+                (Err(_), _) => spool = None,
+                (Ok(_), None) => {}
+            }
+            let failed = chunk.is_err();
+            let gone = sender.send(chunk).await.is_err();
+            // the client is gone and there is nothing left to keep
+            if failed || gone && spool.is_none() {
+                return;
+            }
+        }
+        if let Some(Err(e)) = spool.map(PackSpool::finish) {
+            tracing::warn!("failed to keep the pack to resume: {}", e);
         }
-        let bytes_out = pack_protocol.build_side_band_format(temp, length);
-        tracing::info!("send pack file: length: {:?}", bytes_out.len());
-        res_bytes.extend(bytes_out);
+    });
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    })
+}
+
+/// The offset to resume the response `session_id` from, when the client asks for it.
+fn resume_offset(headers: &HeaderMap, session_id: &str) -> Option<u64> {
+    let session = headers.get(SESSION_HEADER)?.to_str().ok()?;
+    if session != session_id {
+        return None;
     }
-    let body = Body::from(res_bytes.freeze());
-    let resp = resp.body(body).unwrap();
-    Ok(resp)
+    parse_range(headers.get(header::RANGE)?.to_str().ok()?)
+}
+
+/// Sends the rest of the response `session_id` from `offset`, None if it is no longer kept.
+fn resume_pack(
+    sessions: &PackSessions,
+    session_id: &str,
+    offset: u64,
+    pack_protocol: &PackProtocol,
+) -> Result<Option<Response<Body>>, (StatusCode, String)> {
+    let Some((file, len)) = sessions
+        .open(session_id, offset)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    else {
+        return Ok(None);
+    };
+    if offset >= len {
+        let resp = Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+            .body(Body::empty())
+            .unwrap();
+        return Ok(Some(resp));
+    }
+    tracing::info!(
+        "resume the pack {} from {} of {} bytes",
+        session_id,
+        offset,
+        len
+    );
+    let chunks =
+        futures::stream::try_unfold(tokio::fs::File::from_std(file), |mut file| async move {
+            let mut chunk = BytesMut::with_capacity(PACK_CHUNK_SIZE);
+            let read = file.read_buf(&mut chunk).await?;
+            Ok::<_, io::Error>((read > 0).then(|| (chunk.freeze(), file)))
+        });
+    let body = match pack_protocol.upload_throttle() {
+        Some(bucket) => throttled_body(chunks, bucket),
+        None => Body::from_stream(chunks),
+    };
+    let resp = build_res_header("application/x-git-upload-pack-result".to_owned())
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(SESSION_HEADER, session_id)
+        .header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", offset, len - 1, len),
+        )
+        .body(body)
+        .unwrap();
+    Ok(Some(resp))
+}
+
+/// A capped client is sent the pack as the bucket of its user allows.
+fn throttled_body(
+    chunks: impl Stream<Item = io::Result<Bytes>> + Send + 'static,
+    bucket: Arc<TokenBucket>,
+) -> Body {
+    Body::from_stream(chunks.then(move |chunk| {
+        let bucket = bucket.clone();
+        async move {
+            if let Ok(chunk) = &chunk {
+                bucket.take(chunk.len()).await;
+            }
+            chunk
+        }
+    }))
 }
 
 /// # Handles a Git receive pack request and prepares the response.
@@ -106,39 +284,36 @@ pub async fn git_upload_pack(
 /// The function takes a `req` parameter representing the HTTP request received and a `pack_protocol`
 /// parameter containing the configuration for the Git pack protocol.
 ///
-/// The function extracts the request body into a vector of bytes, `combined_body_bytes`, by iterating over the
-/// chunks of the request body using `body.next().await`. The chunks are appended to the `combined_body_bytes`.
-///
-/// The `pack_protocol` is then used to process the `combined_body_bytes` using the `git_receive_pack` method.
-/// It returns the `pack_data` containing the response data.
-///
-/// The `pack_data` is passed to the `git_receive_pack` method again to obtain the final response data as a `buf`.
+/// The request body is not buffered: it is read as a stream, the ref commands first, then the
+/// pack which is decoded as it arrives, see [`PackProtocol::git_receive_pack_stream`]. The
+/// `Content-Length` of the request, when there is one, picks the decode lane.
 ///
-/// The `buf` is converted into a `Body` using `Body::from()` and assigned to `body`.
-/// Tracing information is logged regarding the status of the response body.
+/// It returns the `report-status` of the push as the response body.
+/// Every ref update is reported on its own in the `report-status` of the response, the pushes
+/// are only failed as a whole when the request body cannot be read.
 ///
 /// A response header is constructed using the `build_res_header` function with a content type of
-/// "application/x-git-receive-pack-result". The response body is set to `body`.
+/// "application/x-git-receive-pack-result".
 ///
 /// Finally, the constructed response is returned.
+#[tracing::instrument(skip_all)]
 pub async fn git_receive_pack(
     req: Request<Body>,
     mut pack_protocol: PackProtocol,
 ) -> Result<Response<Body>, (StatusCode, String)> {
-    let combined_body_bytes: BytesMut = req
-        .into_body()
-        .into_data_stream()
-        .try_fold(BytesMut::new(), |mut acc, chunk| async move {
-            acc.extend_from_slice(&chunk);
-            Ok(acc)
-        })
-        .await
-        .unwrap();
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    let body = StreamReader::new(req.into_body().into_data_stream().map_err(io::Error::other));
 
+    // the refusal of a ref update is reported in the body, an error here means no ref was
+    // updated
     let parse_report = pack_protocol
-        .git_receive_pack(combined_body_bytes.freeze())
+        .git_receive_pack_stream(body, length)
         .await
-        .unwrap();
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
     tracing::info!("report status:{:?}", parse_report);
     let resp = build_res_header("application/x-git-receive-pack-result".to_owned());
     let resp = resp.body(Body::from(parse_report)).unwrap();
@@ -163,4 +338,54 @@ pub fn build_res_header(content_type: String) -> Builder {
     resp
 }
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::io::{self, Read};
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+
+    use common::utils::generate_id;
+
+    use super::{chunked, tee_body};
+    use crate::http::resume::PackSessions;
+
+    #[tokio::test]
+    async fn test_tee_body() {
+        let root = std::env::temp_dir().join(format!("tee_body_{}", generate_id()));
+        let sessions = PackSessions::new_in(&root, Duration::from_secs(60));
+        let body: Vec<u8> = (0..3 * 1024 * 1024).map(|i| i as u8).collect();
+        let body = Bytes::from(body);
+
+        // the client leaves after the first chunk, the response is kept whole anyway
+        let id = "a".repeat(40);
+        let mut sent = tee_body(chunked(body.clone()), sessions.spool(&id).unwrap()).boxed();
+        assert_eq!(sent.next().await.unwrap().unwrap(), body.slice(..65536));
+        drop(sent);
+        let mut kept = None;
+        for _ in 0..100 {
+            kept = sessions.open(&id, 0).unwrap();
+            if kept.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let (mut file, len) = kept.unwrap();
+        assert_eq!(len, body.len() as u64);
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content, body);
+
+        // a response failing midway is sent up to the failure, and not kept
+        let id = "b".repeat(40);
+        let failing = chunked(body.clone()).chain(futures::stream::once(async {
+            Err(io::Error::other("lost"))
+        }));
+        let sent: Vec<_> = tee_body(failing, sessions.spool(&id).unwrap())
+            .collect()
+            .await;
+        assert!(sent.last().unwrap().is_err());
+        assert!(sessions.open(&id, 0).unwrap().is_none());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+//!
+//! Resume of the packs sent over HTTP, so that a client whose connection drops in the middle
+//! of a large clone does not download it again from the first byte.
+//!
+//! The response of an upload-pack request is written to a [`PackSpool`] as it is sent, and kept
+//! for `pack_resume_window_mins`, named after the repository, the user and the request, and
+//! this name is sent in the [`SESSION_HEADER`]. The client resumes by sending the same request again with this header
+//! and a `Range: bytes=<offset>-` header, it is answered `206 Partial Content` with the rest
+//! of the pack kept, which the refs updated meanwhile do not change.
+//!
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use common::fs_utils;
+use venus::hash::SHA1;
+
+use crate::protocol::PackProtocol;
+
+pub const SESSION_HEADER: &str = "Mega-Pack-Session";
+/// Smaller responses, e.g. the acknowledgements of a negotiation, are not kept.
+const MIN_RESUMABLE_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone)]
+pub struct PackSessions {
+    root: PathBuf,
+    window: Duration,
+}
+
+impl PackSessions {
+    /// The packs kept under `MEGA_PACK_RESUME_PATH`, the pack-resume directory under
+    /// `MEGA_OBJ_LOCAL_PATH` when unset, for `window_mins`.
+    pub fn new(window_mins: u64) -> Self {
+        let root = env::var("MEGA_PACK_RESUME_PATH")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| match env::var("MEGA_OBJ_LOCAL_PATH") {
+                Ok(path) => PathBuf::from(path).join("pack-resume"),
+                Err(_) => env::temp_dir().join("mega-pack-resume"),
+            });
+        Self::new_in(&root, Duration::from_secs(window_mins * 60))
+    }
+
+    pub fn new_in(root: &Path, window: Duration) -> Self {
+        PackSessions {
+            root: root.to_path_buf(),
+            window,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    /// The name of the response to `request` of the client of `pack_protocol`.
+    pub fn session_id(pack_protocol: &PackProtocol, request: &[u8]) -> String {
+        let mut data = Vec::with_capacity(request.len() + 128);
+        for part in [
+            pack_protocol.path.to_string_lossy().as_bytes(),
+            pack_protocol
+                .client
+                .user
+                .as_deref()
+                .unwrap_or("")
+                .as_bytes(),
+            pack_protocol.object_format.to_string().as_bytes(),
+        ] {
+            data.extend(part);
+            data.push(0);
+        }
+        data.extend(request);
+        SHA1::new(&data).to_plain_str()
+    }
+
+    /// Starts keeping the response `id` for the client to resume, None when the resume is
+    /// disabled.
+    pub fn spool(&self, id: &str) -> Option<PackSpool> {
+        self.is_enabled().then(|| PackSpool {
+            sessions: self.clone(),
+            path: self.root.join(id),
+            head: Vec::new(),
+            temp: None,
+        })
+    }
+
+    /// The pack `id` positioned at `offset` and its length, None if it is not kept or expired.
+    pub fn open(&self, id: &str, offset: u64) -> io::Result<Option<(File, u64)>> {
+        if !self.is_enabled() || !is_session_id(id) {
+            return Ok(None);
+        }
+        let path = self.root.join(id);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let metadata = file.metadata()?;
+        if self.is_expired(metadata.modified()?) {
+            return Ok(None);
+        }
+        file.seek(SeekFrom::Start(offset.min(metadata.len())))?;
+        Ok(Some((file, metadata.len())))
+    }
+
+    fn is_expired(&self, modified: SystemTime) -> bool {
+        modified.elapsed().unwrap_or_default() > self.window
+    }
+
+    fn remove_expired(&self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if self.is_expired(entry.metadata()?.modified()?) {
+                // another request may be removing it too
+                if let Err(e) = fs::remove_file(entry.path()) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A response written as it is sent. It stays in memory until it is worth being kept, then
+/// goes to a temporary file moved in place by [`PackSpool::finish`], a spool dropped before
+/// leaves nothing behind.
+pub struct PackSpool {
+    sessions: PackSessions,
+    path: PathBuf,
+    head: Vec<u8>,
+    temp: Option<(PathBuf, File)>,
+}
+
+impl PackSpool {
+    pub fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        if let Some((_, file)) = &mut self.temp {
+            return file.write_all(chunk);
+        }
+        self.head.extend_from_slice(chunk);
+        if self.head.len() >= MIN_RESUMABLE_SIZE {
+            fs::create_dir_all(&self.sessions.root)?;
+            self.sessions.remove_expired()?;
+            let temp = fs_utils::temp_path(&self.path)?;
+            let mut file = File::create(&temp)?;
+            // the file is removed on drop from now on
+            self.temp = Some((temp, file.try_clone()?));
+            file.write_all(&std::mem::take(&mut self.head))?;
+        }
+        Ok(())
+    }
+
+    /// Keeps the response written, returns false if it is too small to be worth it.
+    pub fn finish(mut self) -> io::Result<bool> {
+        let Some((temp, file)) = self.temp.take() else {
+            return Ok(false);
+        };
+        drop(file);
+        fs_utils::replace(&temp, &self.path).inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })?;
+        Ok(true)
+    }
+}
+
+impl Drop for PackSpool {
+    fn drop(&mut self) {
+        if let Some((temp, _)) = self.temp.take() {
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
+/// A name sent by a client is only used as a file name if it is one of ours.
+fn is_session_id(id: &str) -> bool {
+    id.len() == 40 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The offset of a `Range: bytes=<offset>-` header, the only form a resume sends.
+pub fn parse_range(value: &str) -> Option<u64> {
+    value
+        .trim()
+        .strip_prefix("bytes=")?
+        .strip_suffix('-')?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::time::Duration;
+
+    use common::utils::generate_id;
+
+    use super::{parse_range, PackSessions, MIN_RESUMABLE_SIZE};
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=1024-"), Some(1024));
+        assert_eq!(parse_range("bytes=0-"), Some(0));
+        assert_eq!(parse_range("bytes=0-99"), None);
+        assert_eq!(parse_range("bytes=-100"), None);
+        assert_eq!(parse_range("items=1-"), None);
+    }
+
+    #[test]
+    fn test_spool_and_open() {
+        let root = std::env::temp_dir().join(format!("pack_resume_{}", generate_id()));
+        let sessions = PackSessions::new_in(&root, Duration::from_secs(60));
+        let id = "a".repeat(40);
+        let body: Vec<u8> = (0..MIN_RESUMABLE_SIZE + 10).map(|i| i as u8).collect();
+
+        let mut spool = sessions.spool(&id).unwrap();
+        spool.write(&body[..10]).unwrap();
+        assert!(!spool.finish().unwrap());
+        assert!(sessions.open(&id, 0).unwrap().is_none());
+        // a response cut short is not kept
+        let mut spool = sessions.spool(&id).unwrap();
+        spool.write(&body).unwrap();
+        drop(spool);
+        assert!(sessions.open(&id, 0).unwrap().is_none());
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+
+        let mut spool = sessions.spool(&id).unwrap();
+        for chunk in body.chunks(4096) {
+            spool.write(chunk).unwrap();
+        }
+        assert!(spool.finish().unwrap());
+        let (mut file, len) = sessions.open(&id, 1000).unwrap().unwrap();
+        assert_eq!(len, body.len() as u64);
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, &body[1000..]);
+        // not a name of ours
+        assert!(sessions.open("../secret", 0).unwrap().is_none());
+
+        // past the window, or disabled
+        let expired = PackSessions::new_in(&root, Duration::from_nanos(1));
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(expired.open(&id, 0).unwrap().is_none());
+        let disabled = PackSessions::new_in(&root, Duration::ZERO);
+        assert!(disabled.open(&id, 0).unwrap().is_none());
+        assert!(disabled.spool(&id).is_none());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+//!
+//! Impact preview of a merge request before it is submitted: the owners of the files it
+//! changes, the checks the server has to run on its merge and the policy rules it breaks. The
+//! policies are read from `base` and applied to the files changed since the merge base, as
+//! receive-pack does when the changes are pushed, see [`crate::policy`].
+//!
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::Serialize;
+
+use common::errors::MegaError;
+use jupiter::context::Context;
+use venus::hash::SHA1;
+
+use crate::compare::{self, FileChange};
+use crate::policy::{self, ChangedFile, PolicySet, Violation};
+
+/// Files listed per owner rule, the rest is only counted.
+const MAX_OWNED_FILES: usize = 100;
+
+/// The files of a merge request owned by the same users.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OwnerRule {
+    /// The directory of the policy setting the owners, `""` for the root.
+    pub directory: String,
+    pub owners: Vec<String>,
+    pub total_files: usize,
+    pub files: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RequiredCheck {
+    pub directory: String,
+    pub check: String,
+    /// Whether the server runs the check, a push is refused otherwise.
+    pub registered: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Impact {
+    pub base: String,
+    pub head: String,
+    pub merge_base: Option<String>,
+    pub total_files: usize,
+    /// The directories whose policy applies to the changed files.
+    pub policies: Vec<String>,
+    pub owners: Vec<OwnerRule>,
+    pub required_checks: Vec<RequiredCheck>,
+    /// The rules the changes break when `user` pushes them, the owner rules are all broken
+    /// for an anonymous user.
+    pub violations: Vec<Violation>,
+}
+
+/// Previews the impact of merging `head` into `base` in the repository at `repo_path`, both
+/// resolved as in [`compare::compare`].
+pub async fn preview(
+    context: &Context,
+    repo_path: &str,
+    base: &str,
+    head: &str,
+    user: Option<&str>,
+) -> Result<Impact, MegaError> {
+    let (_, base_id, head_id, graph) =
+        compare::open_revisions(context, repo_path, base, head).await?;
+    let merge_base = graph.merge_base(&base_id, &head_id);
+    let changes = compare::diff_trees(
+        context,
+        merge_base.and_then(|id| graph.tree(&id)),
+        graph.tree(&head_id),
+    )
+    .await?;
+
+    let directories: BTreeSet<String> = changes
+        .iter()
+        .flat_map(|change| policy::directories(&change.path))
+        .collect();
+    let base_tree = graph.tree(&base_id).unwrap();
+    let policies = policy::load_policies(context, base_tree, &directories).await?;
+    let files = changed_files(context, &policies, &changes).await?;
+
+    let hooks = context.hooks.hooks();
+    let checks: Vec<&str> = hooks.iter().map(|hook| hook.name()).collect();
+    let required_checks = policies
+        .policies()
+        .filter_map(|(directory, policy)| Some((directory, policy.ok()?)))
+        .flat_map(|(directory, policy)| {
+            policy.required_checks.iter().map(|check| RequiredCheck {
+                directory: directory.to_owned(),
+                check: check.clone(),
+                registered: checks.contains(&check.as_str()),
+            })
+        })
+        .collect();
+    // forced updates are refused by the push, not by the merge request
+    let violations = policies.check(base, false, &files, user, &checks);
+
+    Ok(Impact {
+        base: base_id.to_plain_str(),
+        head: head_id.to_plain_str(),
+        merge_base: merge_base.map(|id| id.to_plain_str()),
+        total_files: changes.len(),
+        policies: policies.policies().map(|(d, _)| d.to_owned()).collect(),
+        owners: owner_rules(&policies, &files),
+        required_checks,
+        violations,
+    })
+}
+
+/// The changed files, with the size of the new blob of those under a size limit.
+async fn changed_files(
+    context: &Context,
+    policies: &PolicySet,
+    changes: &[FileChange],
+) -> Result<Vec<ChangedFile>, MegaError> {
+    let limited: Vec<SHA1> = changes
+        .iter()
+        .filter(|change| policies.size_limit_of(&change.path).is_some())
+        .filter_map(|change| change.new_id)
+        .collect();
+    let sizes: HashMap<SHA1, usize> = context
+        .services
+        .mega_storage
+        .get_blob_contents(&limited)
+        .await?
+        .into_iter()
+        .map(|(id, data)| (id, data.len()))
+        .collect();
+    Ok(changes
+        .iter()
+        .map(|change| ChangedFile {
+            path: change.path.clone(),
+            size: change.new_id.and_then(|id| sizes.get(&id).copied()),
+        })
+        .collect())
+}
+
+fn owner_rules(policies: &PolicySet, files: &[ChangedFile]) -> Vec<OwnerRule> {
+    let mut rules: BTreeMap<&str, OwnerRule> = BTreeMap::new();
+    for file in files {
+        let Some((directory, policy)) = policies.owners_of(&file.path) else {
+            continue;
+        };
+        let rule = rules.entry(directory).or_insert_with(|| OwnerRule {
+            directory: directory.to_owned(),
+            owners: policy.owners.clone(),
+            total_files: 0,
+            files: Vec::new(),
+        });
+        rule.total_files += 1;
+        if rule.files.len() < MAX_OWNED_FILES {
+            rule.files.push(file.path.clone());
+        }
+    }
+    rules.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::policy::{ChangedFile, Policy, PolicySet};
+
+    use super::owner_rules;
+
+    #[test]
+    fn test_owner_rules() {
+        let mut policies = PolicySet::default();
+        let owners = |names: &[&str]| Policy {
+            owners: names.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        };
+        policies.insert(String::new(), Ok(Arc::new(owners(&["root"]))));
+        policies.insert(String::from("docs"), Ok(Arc::new(owners(&["alice"]))));
+        policies.insert(String::from("src"), Ok(Arc::new(Policy::default())));
+        let files: Vec<ChangedFile> = ["README", "docs/a.md", "docs/b/c.md", "src/main.rs"]
+            .iter()
+            .map(|path| ChangedFile {
+                path: path.to_string(),
+                size: None,
+            })
+            .collect();
+
+        let rules = owner_rules(&policies, &files);
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].directory, "");
+        assert_eq!(rules[0].files, vec!["README", "src/main.rs"]);
+        assert_eq!(rules[1].owners, vec!["alice"]);
+        assert_eq!(rules[1].total_files, 2);
+    }
+}
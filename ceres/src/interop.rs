@@ -0,0 +1,339 @@
+//!
+//! Serves the repositories, stored with SHA-1 ids, to the clients of SHA-256 repositories, after
+//! the hash function transition plan of Git. The SHA-256 name of every stored object is kept in
+//! the `mega_object_map` table: [`map_objects`] translates the objects reachable from the refs
+//! of a repository, see [`venus::internal::object::interop`], and records their names.
+//!
+//! A client of the SHA-256 format is told the refs by their SHA-256 names, and the ids of its
+//! wants and haves are looked up back to SHA-1, see [`to_client_ids`] and [`to_stored_ids`].
+//! A ref whose tip is not mapped yet is left out of the advertisement. The pack it is sent is
+//! re-encoded with the objects translated, see [`translate_pack`]. Only the reads are
+//! translated, a push in SHA-256 is refused by the client itself on the advertised format.
+//!
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+
+use callisto::mega_object_map;
+use common::errors::MegaError;
+use common::utils::generate_id;
+use jupiter::context::Context;
+use jupiter::storage::mega_storage::StoredObjects;
+use mercury::internal::pack::encode::PackEncoder;
+use mercury::internal::pack::Pack;
+use venus::errors::GitError;
+use venus::hash::HashKind;
+use venus::internal::object::interop::{referenced_ids, translate_object};
+use venus::internal::pack::entry::Entry;
+use venus::repo::Repo;
+
+/// The entries compared for a delta when a pack is re-encoded.
+const PACK_WINDOW: usize = 10;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MapReport {
+    /// The objects whose SHA-256 name was recorded.
+    pub mapped: usize,
+    /// The objects mapped already.
+    pub known: usize,
+    /// The objects left unmapped, they refer to an object not stored in the database, e.g. a
+    /// blob kept in the file system, or to one of them.
+    pub unmapped: usize,
+}
+
+/// Records the SHA-256 name of the objects reachable from the refs and the pins of `repo`.
+/// Annotated tags are left out, their tagger is not stored byte for byte.
+pub async fn map_objects(context: &Context, repo: &Repo) -> Result<MapReport, MegaError> {
+    let storage = &context.services.mega_storage;
+    let object_map = &context.services.object_map_storage;
+    let reachable: Vec<String> = storage.reachable_objects(repo).await?.into_iter().collect();
+    let known = object_map.get_sha256(&reachable).await?;
+    let unknown: Vec<String> = reachable
+        .into_iter()
+        .filter(|id| !known.contains_key(id))
+        .collect();
+    // the ids are of any type, each table returns those it holds
+    let entries = storage
+        .get_entries(&StoredObjects {
+            commits: unknown.clone(),
+            trees: unknown.clone(),
+            blobs: unknown.clone(),
+            tags: Vec::new(),
+        })
+        .await?;
+
+    let mut map = HashMap::with_capacity(known.len() + entries.len());
+    for (sha1, sha256) in &known {
+        if let (Ok(sha1), Ok(sha256)) = (hex::decode(sha1), hex::decode(sha256)) {
+            map.insert(sha1, sha256);
+        }
+    }
+    let names = translate_entries(&entries, &mut map);
+    let now = Utc::now().naive_utc();
+    let mappings: Vec<mega_object_map::Model> = entries
+        .iter()
+        .filter_map(|entry| {
+            names
+                .get(entry.hash.0.as_slice())
+                .map(|sha256| mega_object_map::Model {
+                    sha1: entry.hash.to_plain_str(),
+                    sha256: hex::encode(sha256),
+                    object_type: entry.obj_type.to_string(),
+                    created_at: now,
+                })
+        })
+        .collect();
+    let report = MapReport {
+        mapped: mappings.len(),
+        known: known.len(),
+        unmapped: unknown.len() - mappings.len(),
+    };
+    object_map.save_mappings(mappings).await?;
+    Ok(report)
+}
+
+/// Translates `entries` to SHA-256, the objects they refer to first, and names them. `map`
+/// holds the names known, the new ones are added to it and returned. An entry referring to an
+/// object neither in `map` nor in `entries`, or to an entry left unmapped, is left unmapped.
+fn translate_entries(
+    entries: &[Entry],
+    map: &mut HashMap<Vec<u8>, Vec<u8>>,
+) -> HashMap<Vec<u8>, Vec<u8>> {
+    let by_id: HashMap<&[u8], &Entry> = entries.iter().map(|e| (&e.hash.0[..], e)).collect();
+    let mut names = HashMap::new();
+    let mut unmapped: HashSet<&[u8]> = HashSet::new();
+    for entry in entries {
+        // depth first, an entry is translated once the entries it refers to are
+        let mut stack = vec![(&entry.hash.0[..], false)];
+        while let Some((id, referred)) = stack.pop() {
+            if map.contains_key(id) || unmapped.contains(id) {
+                continue;
+            }
+            let Some(entry) = by_id.get(id) else {
+                unmapped.insert(id);
+                continue;
+            };
+            if !referred {
+                stack.push((id, true));
+                let Ok(ids) = referenced_ids(entry.obj_type, &entry.data, HashKind::Sha1) else {
+                    unmapped.insert(id);
+                    continue;
+                };
+                for referenced in ids {
+                    if let Some((key, _)) = by_id.get_key_value(referenced.as_slice()) {
+                        stack.push((key, false));
+                    }
+                }
+                continue;
+            }
+            match translate_object(
+                entry.obj_type,
+                &entry.data,
+                HashKind::Sha1,
+                HashKind::Sha256,
+                map,
+            ) {
+                Ok(data) => {
+                    let sha256 = HashKind::Sha256.object_hash(entry.obj_type, &data);
+                    map.insert(id.to_vec(), sha256.clone());
+                    names.insert(id.to_vec(), sha256);
+                }
+                Err(_) => {
+                    unmapped.insert(id);
+                }
+            }
+        }
+    }
+    names
+}
+
+/// Re-encodes `pack`, whose objects are named in SHA-1, for a client of `format`: the ids held
+/// by every object are replaced with their names recorded by [`map_objects`], or computed for
+/// the objects of the pack not mapped yet, and the trailer is a hash of `format`. Fails when an
+/// object refers to one that has no name in `format`.
+pub async fn translate_pack(
+    context: &Context,
+    format: HashKind,
+    pack: Vec<u8>,
+) -> Result<Vec<u8>, MegaError> {
+    if format == HashKind::Sha1 || pack.is_empty() {
+        return Ok(pack);
+    }
+    let mem_limit = context.settings.current().pack_decode_mem_limit * 1024 * 1024;
+    let cache = env::temp_dir().join(format!("mega-interop-{}", generate_id()));
+    let decoded = Arc::new(Mutex::new(Vec::new()));
+    let sink = decoded.clone();
+    let cache_dir = cache.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        Pack::new(None, Some(mem_limit), Some(cache_dir))
+            .decode(&mut Cursor::new(pack), move |entry| sink.lock().unwrap().push(entry))
+    })
+    .await
+    .unwrap();
+    let _ = std::fs::remove_dir_all(&cache);
+    result.map_err(|e| MegaError::with_message(&e.to_string()))?;
+    let entries = std::mem::take(&mut *decoded.lock().unwrap());
+
+    let ids: Vec<String> = entries.iter().map(|e| e.hash.to_plain_str()).collect();
+    let mut map = HashMap::with_capacity(entries.len());
+    for (sha1, name) in context.services.object_map_storage.get_sha256(&ids).await? {
+        if let (Ok(sha1), Ok(name)) = (hex::decode(sha1), hex::decode(name)) {
+            map.insert(sha1, name);
+        }
+    }
+    tokio::task::spawn_blocking(move || encode_translated(entries, format, &mut map))
+        .await
+        .unwrap()
+        .map_err(|e| MegaError::with_message(&e.to_string()))
+}
+
+/// Encodes `entries` in `format`, the names of the objects they refer to are in `map` or
+/// are computed from `entries`, see [`translate_entries`].
+fn encode_translated(
+    entries: Vec<Entry>,
+    format: HashKind,
+    map: &mut HashMap<Vec<u8>, Vec<u8>>,
+) -> Result<Vec<u8>, GitError> {
+    translate_entries(&entries, map);
+    let mut pack = Vec::new();
+    let mut encoder =
+        PackEncoder::new(entries.len(), PACK_WINDOW, &mut pack).with_hash_kind(format);
+    let mut error = None;
+    let translated = entries.into_iter().map_while(|entry| {
+        match translate_object(entry.obj_type, &entry.data, HashKind::Sha1, format, map) {
+            Ok(data) => Some(Entry { data, ..entry }),
+            Err(e) => {
+                error = Some(e);
+                None
+            }
+        }
+    });
+    let result = encoder.encode_entries(translated);
+    drop(encoder);
+    if let Some(e) = error {
+        return Err(e);
+    }
+    result.map(|_| pack)
+}
+
+/// The names in `format` of the stored objects `ids`, by id. The objects not mapped are left
+/// out.
+pub async fn to_client_ids(
+    context: &Context,
+    format: HashKind,
+    ids: &[String],
+) -> Result<HashMap<String, String>, MegaError> {
+    match format {
+        HashKind::Sha1 => Ok(ids.iter().map(|id| (id.clone(), id.clone())).collect()),
+        HashKind::Sha256 => context.services.object_map_storage.get_sha256(ids).await,
+    }
+}
+
+/// The stored ids of the objects named `ids` in `format`, by name. The names not mapped are
+/// left out.
+pub async fn to_stored_ids(
+    context: &Context,
+    format: HashKind,
+    ids: &[String],
+) -> Result<HashMap<String, String>, MegaError> {
+    match format {
+        HashKind::Sha1 => Ok(ids.iter().map(|id| (id.clone(), id.clone())).collect()),
+        HashKind::Sha256 => context.services.object_map_storage.get_sha1(ids).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use venus::hash::SHA1;
+    use venus::internal::object::types::ObjectType;
+    use venus::internal::pack::entry::Entry;
+
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use mercury::internal::pack::Pack;
+    use venus::hash::HashKind;
+
+    use super::{encode_translated, translate_entries};
+
+    fn entry(obj_type: ObjectType, data: &[u8]) -> Entry {
+        Entry {
+            obj_type,
+            data: data.to_vec(),
+            hash: SHA1::from_type_and_data(obj_type, &data.to_vec()),
+            crc32: None,
+        }
+    }
+
+    #[test]
+    fn test_translate_entries() {
+        let blob = entry(ObjectType::Blob, b"hello\n");
+        let mut tree_data = b"100644 a.txt\0".to_vec();
+        tree_data.extend(blob.hash.0);
+        let tree = entry(ObjectType::Tree, &tree_data);
+        let commit = entry(
+            ObjectType::Commit,
+            format!(
+                "tree {}\nauthor Mega <mega@mega.dev> 1700000000 +0000\n\
+                 committer Mega <mega@mega.dev> 1700000000 +0000\n\ninit\n",
+                tree.hash.to_plain_str()
+            )
+            .as_bytes(),
+        );
+        let mut missing_tree = b"100644 b.txt\0".to_vec();
+        missing_tree.extend([7; 20]);
+        let orphan = entry(ObjectType::Tree, &missing_tree);
+
+        // the commit first, it is translated after the tree and the blob
+        let entries = vec![commit.clone(), orphan.clone(), tree, blob];
+        let mut map = HashMap::new();
+        let names = translate_entries(&entries, &mut map);
+        assert_eq!(names.len(), 3);
+        assert_eq!(
+            hex::encode(&names[&commit.hash.0.to_vec()]),
+            "6497f598fd8dc66bb8e66bcd4c3df6254978cfbc064444314ff90491c3297d8c"
+        );
+        assert!(!names.contains_key(orphan.hash.0.as_slice()));
+        assert_eq!(map.len(), 3);
+
+        // the names known are not translated again
+        assert!(translate_entries(&entries, &mut map).is_empty());
+    }
+
+    #[test]
+    fn test_encode_translated() {
+        let blob = entry(ObjectType::Blob, b"hello\n");
+        let mut tree_data = b"100644 a.txt\0".to_vec();
+        tree_data.extend(blob.hash.0);
+        let tree = entry(ObjectType::Tree, &tree_data);
+        let mut map = HashMap::new();
+        let pack = encode_translated(vec![tree.clone(), blob.clone()], HashKind::Sha256, &mut map)
+            .unwrap();
+        let (body, trailer) = pack.split_at(pack.len() - 32);
+        assert_eq!(HashKind::Sha256.digest(body), trailer);
+
+        // the tree of the pack holds the SHA-256 name of the blob
+        let decoded = Arc::new(Mutex::new(Vec::new()));
+        let sink = decoded.clone();
+        let mut body_pack = body.to_vec();
+        body_pack.extend(HashKind::Sha1.digest(body));
+        let tmp = std::env::temp_dir().join("mega_test_interop");
+        Pack::new(None, Some(1024 * 1024), Some(tmp))
+            .decode(&mut Cursor::new(body_pack), move |e| sink.lock().unwrap().push(e))
+            .unwrap();
+        let decoded = decoded.lock().unwrap();
+        let tree_entry = decoded.iter().find(|e| e.obj_type == ObjectType::Tree).unwrap();
+        assert!(tree_entry.data.ends_with(&map[blob.hash.0.as_slice()]));
+
+        // a reference to an object without a name is refused
+        let mut missing = b"100644 b.txt\0".to_vec();
+        missing.extend([7; 20]);
+        let orphan = entry(ObjectType::Tree, &missing);
+        assert!(encode_translated(vec![orphan], HashKind::Sha256, &mut HashMap::new()).is_err());
+    }
+}
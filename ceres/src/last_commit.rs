@@ -0,0 +1,303 @@
+//!
+//! Index of the last commit modifying every path of a ref, the data repository browsers show
+//! next to each entry of a directory listing. Walking the history for every entry is far too
+//! slow on a monorepo, so the index is updated with the commits of each ref update and a
+//! listing costs a single query.
+//!
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use serde::Serialize;
+
+use common::errors::MegaError;
+use common::utils::ZERO_ID;
+use jupiter::context::Context;
+use jupiter::events::{RefUpdateEvent, RefUpdateObserver};
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::internal::object::tree::{Tree, TreeItemMode};
+use venus::internal::pack::reference::{CommandType, RefCommand};
+use venus::internal::revwalk::CommitGraph;
+use venus::repo::Repo;
+
+use crate::compare::{self, ChangeStatus};
+use crate::replace::REPLACE_REF_PREFIX;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryCommit {
+    pub name: String,
+    pub path: String,
+    pub is_dir: bool,
+    /// Unset while the entry is not indexed yet.
+    pub commit_id: Option<String>,
+    pub message: Option<String>,
+    pub author: Option<String>,
+    pub date: Option<i64>,
+}
+
+/// Keeps the index up to date with the pushed refs, unless the `last_commit_index` setting is
+/// off.
+pub struct LastCommitIndexer;
+
+#[async_trait]
+impl RefUpdateObserver for LastCommitIndexer {
+    fn name(&self) -> &'static str {
+        "last_commit_index"
+    }
+
+    async fn on_ref_update(
+        &self,
+        context: &Context,
+        event: &RefUpdateEvent,
+    ) -> Result<(), MegaError> {
+        if !context.settings.current().last_commit_index {
+            return Ok(());
+        }
+        let repo = context
+            .services
+            .mega_storage
+            .find_git_repo(&event.repo_path)
+            .await?
+            .map(Repo::from)
+            .unwrap_or_else(Repo::empty);
+        if event.ref_name.starts_with(REPLACE_REF_PREFIX) {
+            // a replacement rewrites the history of every ref reaching the replaced commit
+            for branch in context.services.mega_storage.get_repo_refs(&repo).await? {
+                if !branch.ref_name.starts_with(REPLACE_REF_PREFIX) {
+                    let command =
+                        RefCommand::new(ZERO_ID.to_owned(), branch.ref_git_id, branch.ref_name);
+                    index_ref_update(context, &repo, &command).await;
+                }
+            }
+            return Ok(());
+        }
+        let command = RefCommand::new(
+            event.old_id.clone(),
+            event.new_id.clone(),
+            event.ref_name.clone(),
+        );
+        index_ref_update(context, &repo, &command).await;
+        Ok(())
+    }
+}
+
+/// Updates the index after `command` was applied. Failures are only logged, a push never fails
+/// because of the index.
+pub async fn index_ref_update(context: &Context, repo: &Repo, command: &RefCommand) {
+    let result = match command.command_type {
+        CommandType::Delete => {
+            context
+                .services
+                .mega_storage
+                .remove_path_commits(repo, &command.ref_name, None)
+                .await
+        }
+        _ => {
+            let old = Some(command.old_id.as_str())
+                .filter(|id| *id != ZERO_ID)
+                .and_then(|id| SHA1::from_str(id).ok());
+            match SHA1::from_str(&command.new_id) {
+                Ok(new) => index_ref(context, repo, &command.ref_name, old, new).await,
+                Err(_) => return,
+            }
+        }
+    };
+    if let Err(err) = result {
+        tracing::error!(
+            "failed to index the last commits of {}: {}",
+            command.ref_name,
+            err
+        );
+    }
+}
+
+/// Applies the commits between `old` and `new` to the index of `ref_name`, oldest first, so
+/// every path ends up with the newest commit touching it. The index is rebuilt from the whole
+/// history of `new` when there is no `old` or when `new` does not contain it.
+async fn index_ref(
+    context: &Context,
+    repo: &Repo,
+    ref_name: &str,
+    old: Option<SHA1>,
+    new: SHA1,
+) -> Result<(), MegaError> {
+    let storage = context.services.mega_storage.clone();
+    let stop: Vec<SHA1> = old.into_iter().collect();
+    let mut graph = CommitGraph::new(compare::load_history(context, repo, &[new], &stop).await?);
+    let incremental = old.is_some_and(|old| graph.is_ancestor(&old, &new));
+    if !incremental {
+        storage.remove_path_commits(repo, ref_name, None).await?;
+        graph = CommitGraph::new(compare::load_history(context, repo, &[new], &[]).await?);
+    }
+    let hidden: Vec<SHA1> = if incremental { stop } else { vec![] };
+
+    let mut latest: HashMap<String, Option<(String, i64)>> = HashMap::new();
+    for id in graph.walk(&[new], &hidden).into_iter().rev() {
+        let Some(commit) = graph.commit(&id) else {
+            continue;
+        };
+        let parent_tree = commit
+            .parent_commit_ids
+            .first()
+            .and_then(|parent| graph.commit(parent))
+            .map(|parent| parent.tree_id);
+        let touched = Some((id.to_plain_str(), commit.committer.timestamp as i64));
+        for change in compare::diff_trees(context, parent_tree, Some(commit.tree_id)).await? {
+            for dir in parent_dirs(&change.path) {
+                latest.insert(dir.to_owned(), touched.clone());
+            }
+            let entry = match change.status {
+                ChangeStatus::Deleted => None,
+                _ => touched.clone(),
+            };
+            latest.insert(change.path, entry);
+        }
+    }
+
+    let mut saved = Vec::new();
+    let mut removed = Vec::new();
+    for (path, entry) in latest {
+        match entry {
+            Some((commit_id, date)) => saved.push((path, commit_id, date)),
+            None => removed.push(path),
+        }
+    }
+    storage.save_path_commits(repo, ref_name, saved).await?;
+    if !removed.is_empty() {
+        storage
+            .remove_path_commits(repo, ref_name, Some(removed))
+            .await?;
+    }
+    Ok(())
+}
+
+/// The directories holding `path`, without the root: `a/b/c` gives `a` and `a/b`.
+fn parent_dirs(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/').map(move |(i, _)| &path[..i])
+}
+
+/// The entries of the directory `path` at `rev` with the last commit modifying each of them.
+pub async fn tree_entry_commits(
+    context: &Context,
+    repo_path: &str,
+    rev: &str,
+    path: &str,
+) -> Result<Vec<EntryCommit>, MegaError> {
+    let storage = context.services.mega_storage.clone();
+    let repo: Repo = storage
+        .find_git_repo(repo_path)
+        .await?
+        .ok_or_else(|| MegaError::with_message(&format!("repository not found: {}", repo_path)))?
+        .into();
+    let refs: HashMap<String, String> = storage
+        .get_repo_refs(&repo)
+        .await?
+        .into_iter()
+        .map(|r| (r.ref_name, r.ref_git_id))
+        .collect();
+    let ref_name = [
+        rev.to_owned(),
+        format!("refs/heads/{}", rev),
+        format!("refs/tags/{}", rev),
+    ]
+    .into_iter()
+    .find(|name| refs.contains_key(name))
+    .ok_or_else(|| MegaError::with_message(&format!("unknown ref: {}", rev)))?;
+    let head = compare::resolve(&refs, &ref_name)?;
+
+    let not_found = || MegaError::with_message(&format!("path not found: {}", path));
+    let commit: Commit = storage
+        .get_commit_by_hash(&head.to_plain_str(), &repo)
+        .await?
+        .ok_or_else(not_found)?
+        .into();
+    let dir = path.trim_matches('/');
+    let mut tree = load_tree(context, commit.tree_id)
+        .await?
+        .ok_or_else(not_found)?;
+    for name in dir.split('/').filter(|name| !name.is_empty()) {
+        let item = tree
+            .tree_items
+            .iter()
+            .find(|item| item.name == name && item.mode == TreeItemMode::Tree)
+            .ok_or_else(not_found)?;
+        tree = load_tree(context, item.id).await?.ok_or_else(not_found)?;
+    }
+
+    let join = |name: &str| match dir {
+        "" => name.to_owned(),
+        dir => format!("{}/{}", dir, name),
+    };
+    let paths: Vec<String> = tree
+        .tree_items
+        .iter()
+        .map(|item| join(&item.name))
+        .collect();
+    let mut indexed = storage
+        .get_path_commits(&repo, &ref_name, paths.clone())
+        .await?;
+    if indexed.is_empty() && !paths.is_empty() {
+        // the ref was last updated before the index existed
+        index_ref(context, &repo, &ref_name, None, head).await?;
+        indexed = storage
+            .get_path_commits(&repo, &ref_name, paths.clone())
+            .await?;
+    }
+    let indexed: HashMap<String, (String, i64)> = indexed
+        .into_iter()
+        .map(|m| (m.path, (m.commit_id, m.committed_at)))
+        .collect();
+
+    let mut commit_ids: Vec<String> = indexed.values().map(|(id, _)| id.clone()).collect();
+    commit_ids.sort();
+    commit_ids.dedup();
+    let commits: HashMap<String, Commit> = storage
+        .get_commits_by_hashes(&repo, commit_ids)
+        .await?
+        .into_iter()
+        .map(|model| (model.commit_id.clone(), model.into()))
+        .collect();
+
+    Ok(tree
+        .tree_items
+        .iter()
+        .zip(paths)
+        .map(|(item, path)| {
+            let last = indexed.get(&path);
+            let commit = last.and_then(|(id, _)| commits.get(id));
+            EntryCommit {
+                name: item.name.clone(),
+                is_dir: item.mode == TreeItemMode::Tree,
+                commit_id: last.map(|(id, _)| id.clone()),
+                message: commit.map(|c| c.message.trim().to_owned()),
+                author: commit.map(|c| c.author.name.clone()),
+                date: last.map(|(_, date)| *date),
+                path,
+            }
+        })
+        .collect())
+}
+
+async fn load_tree(context: &Context, id: SHA1) -> Result<Option<Tree>, MegaError> {
+    Ok(context
+        .services
+        .mega_storage
+        .get_mega_tree_by_sha(&id.to_plain_str())
+        .await?
+        .map(Tree::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parent_dirs;
+
+    #[test]
+    fn test_parent_dirs() {
+        assert_eq!(
+            parent_dirs("third_parts/mega/src/lib.rs").collect::<Vec<_>>(),
+            vec!["third_parts", "third_parts/mega", "third_parts/mega/src"]
+        );
+        assert_eq!(parent_dirs("README.md").count(), 0);
+    }
+}
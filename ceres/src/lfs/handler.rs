@@ -10,15 +10,18 @@ use bytes::Bytes;
 use chrono::{prelude::*, Duration};
 use jupiter::storage::lfs_storage::LfsStorage;
 use rand::prelude::*;
+use sha2::{Digest, Sha256};
 
-use callisto::{lfs_locks, lfs_objects};
+use callisto::{lfs_locks, lfs_objects, mega_lfs_object};
 use common::errors::{GitLFSError, MegaError};
 
 use crate::lfs::lfs_structs::{
     BatchRequest, LockList, LockRequest, ObjectError, UnlockRequest, VerifiableLockList,
     VerifiableLockRequest,
 };
-use crate::lfs::lfs_structs::{Link, Lock, LockListQuery, MetaObject, Representation, RequestVars};
+use crate::lfs::lfs_structs::{
+    Link, Lock, LockListQuery, MetaObject, Representation, RequestVars, User,
+};
 use crate::lfs::LfsConfig;
 
 pub async fn lfs_retrieve_lock(
@@ -52,6 +55,7 @@ pub async fn lfs_retrieve_lock(
 pub async fn lfs_verify_lock(
     config: &LfsConfig,
     req: VerifiableLockRequest,
+    user: Option<&str>,
 ) -> Result<VerifiableLockList, MegaError> {
     let mut limit = req.limit.unwrap_or(0);
     if limit == 0 {
@@ -76,10 +80,10 @@ pub async fn lfs_verify_lock(
             lock_list.next_cursor = next_cursor;
 
             for lock in locks.iter() {
-                if Option::is_none(&lock.owner) {
-                    lock_list.ours.push(lock.clone());
-                } else {
+                if held_by_other(lock, user) {
                     lock_list.theirs.push(lock.clone());
+                } else {
+                    lock_list.ours.push(lock.clone());
                 }
             }
         }
@@ -88,7 +92,55 @@ pub async fn lfs_verify_lock(
     Ok(lock_list)
 }
 
-pub async fn lfs_create_lock(config: &LfsConfig, req: LockRequest) -> Result<Lock, GitLFSError> {
+/// Returns the locks on `refspec` that cover one of `paths` and are held by someone other than `user`.
+///
+/// Locks without an owner are treated as belonging to the caller, the same way `lfs_verify_lock` reports them.
+pub async fn lfs_locks_held_by_others(
+    storage: Arc<LfsStorage>,
+    refspec: &str,
+    paths: &[String],
+    user: Option<&str>,
+) -> Result<Vec<Lock>, GitLFSError> {
+    if paths.is_empty() {
+        return Ok(vec![]);
+    }
+    let locks = match storage.get_lock_by_id(refspec).await {
+        Ok(Some(val)) if !val.data.is_empty() => serde_json::from_str::<Vec<Lock>>(&val.data)
+            .map_err(|e| GitLFSError::GeneralError(e.to_string()))?,
+        Ok(_) => vec![],
+        Err(_) => {
+            return Err(GitLFSError::GeneralError(
+                "Lookup operation failed!".to_string(),
+            ))
+        }
+    };
+    Ok(filter_conflicting_locks(locks, paths, user))
+}
+
+fn filter_conflicting_locks(locks: Vec<Lock>, paths: &[String], user: Option<&str>) -> Vec<Lock> {
+    locks
+        .into_iter()
+        .filter(|lock| held_by_other(lock, user))
+        .filter(|lock| {
+            let locked = lock.path.trim_start_matches('/');
+            paths.iter().any(|p| p.trim_start_matches('/') == locked)
+        })
+        .collect()
+}
+
+/// Whether `lock` has an owner other than `user`, an anonymous user owning no lock.
+fn held_by_other(lock: &Lock, user: Option<&str>) -> bool {
+    lock.owner
+        .as_ref()
+        .is_some_and(|owner| Some(owner.name.as_str()) != user)
+}
+
+/// Locks `req.path` for `user`, the authenticated user making the request.
+pub async fn lfs_create_lock(
+    config: &LfsConfig,
+    req: LockRequest,
+    user: Option<&str>,
+) -> Result<Lock, GitLFSError> {
     let res = lfs_get_filtered_locks(
         config.context.services.lfs_storage.clone(),
         &req.refs.name,
@@ -121,7 +173,9 @@ pub async fn lfs_create_lock(config: &LfsConfig, req: LockRequest) -> Result<Loc
             random_num
         },
         path: req.path.to_owned(),
-        owner: None,
+        owner: user.map(|name| User {
+            name: name.to_owned(),
+        }),
         locked_at: {
             let locked_at: DateTime<Utc> = Utc::now();
             locked_at.to_rfc3339()
@@ -142,10 +196,12 @@ pub async fn lfs_create_lock(config: &LfsConfig, req: LockRequest) -> Result<Loc
     }
 }
 
+/// Unlocks the lock `id`, which has to be held by `user` unless the request forces it.
 pub async fn lfs_delete_lock(
     config: &LfsConfig,
     id: &str,
     unlock_request: UnlockRequest,
+    user: Option<&str>,
 ) -> Result<Lock, GitLFSError> {
     if id.is_empty() {
         return Err(GitLFSError::GeneralError("Invalid lock id!".to_string()));
@@ -153,7 +209,7 @@ pub async fn lfs_delete_lock(
     let res = delete_lock(
         config.context.services.lfs_storage.clone(),
         &unlock_request.refs.name,
-        None,
+        user,
         id,
         unlock_request.force.unwrap_or(false),
     )
@@ -187,7 +243,7 @@ pub async fn lfs_process_batch(
         request.authorization = "".to_string();
     }
     let mut response_objects = Vec::<Representation>::new();
-    let server_url = format!("http://{}:{}", config.host, config.port);
+    let server_url = config.server_url.clone();
 
     let storage = config.context.services.lfs_storage.clone();
 
@@ -226,22 +282,38 @@ pub async fn lfs_upload_object(
     request_vars: &RequestVars,
     body_bytes: &[u8],
 ) -> Result<(), GitLFSError> {
-    let meta = lfs_get_meta(config.context.services.lfs_storage.clone(), request_vars)
+    let storage = config.context.services.lfs_storage.clone();
+    let meta = lfs_get_meta(storage.clone(), request_vars)
         .await
-        .unwrap();
+        .map_err(|_| GitLFSError::GeneralError(String::from("Object not requested by a batch!")))?;
+    // the oid of an object is the sha256 of its content
+    if body_bytes.len() as i64 != meta.size || hex::encode(Sha256::digest(body_bytes)) != meta.oid
+    {
+        lfs_delete_meta(storage, request_vars).await?;
+        return Err(GitLFSError::GeneralError(String::from(
+            "Content does not match the oid or the size!",
+        )));
+    }
     let res = config
         .lfs_storage
-        .put_object(&config.repo_name,&meta.oid,  body_bytes)
+        .put_object(&config.repo_name, &meta.oid, body_bytes)
         .await;
-    if res.is_err() {
-        lfs_delete_meta(config.context.services.lfs_storage.clone(), request_vars)
-            .await
-            .unwrap();
+    let Ok(location) = res else {
+        lfs_delete_meta(storage, request_vars).await?;
         return Err(GitLFSError::GeneralError(String::from(
             "Header not acceptable!",
         )));
-    }
-    Ok(())
+    };
+    storage
+        .save_lfs_location(mega_lfs_object::Model {
+            oid: meta.oid,
+            size: meta.size,
+            storage_type: config.lfs_storage.get_storage_type(),
+            location,
+            created_at: Utc::now().naive_utc(),
+        })
+        .await
+        .map_err(|err| GitLFSError::GeneralError(err.to_string()))
 }
 
 pub async fn lfs_download_object(
@@ -250,9 +322,12 @@ pub async fn lfs_download_object(
 ) -> Result<Bytes, GitLFSError> {
     let meta = lfs_get_meta(config.context.services.lfs_storage.clone(), request_vars)
         .await
-        .unwrap();
-    let bytes = config.lfs_storage.get_object(&config.repo_name,&meta.oid).await.unwrap();
-    Ok(bytes)
+        .map_err(|_| GitLFSError::GeneralError(String::from("Object not found!")))?;
+    config
+        .lfs_storage
+        .get_object(&config.repo_name, &meta.oid)
+        .await
+        .map_err(|err| GitLFSError::GeneralError(err.to_string()))
 }
 
 pub async fn represent(
@@ -510,7 +585,7 @@ async fn lfs_delete_meta(storage: Arc<LfsStorage>, v: &RequestVars) -> Result<()
 async fn delete_lock(
     storage: Arc<LfsStorage>,
     repo: &str,
-    _user: Option<String>,
+    user: Option<&str>,
     id: &str,
     force: bool,
 ) -> Result<Lock, GitLFSError> {
@@ -539,7 +614,7 @@ async fn delete_lock(
 
             for lock in locks_from_data.iter() {
                 if lock.id == *id {
-                    if Option::is_some(&lock.owner) && !force {
+                    if held_by_other(lock, user) && !force {
                         return Err(GitLFSError::GeneralError("".to_string()));
                     }
                     lock_to_delete.id = lock.id.to_owned();
@@ -578,3 +653,54 @@ async fn delete_lock(
         None => Err(GitLFSError::GeneralError("".to_string())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::lfs::lfs_structs::{Lock, User};
+
+    use super::filter_conflicting_locks;
+
+    fn lock(id: &str, path: &str, owner: Option<&str>) -> Lock {
+        Lock {
+            id: id.to_owned(),
+            path: path.to_owned(),
+            locked_at: String::new(),
+            owner: owner.map(|name| User {
+                name: name.to_owned(),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_filter_conflicting_locks() {
+        let locks = vec![
+            lock("1", "/assets/a.psd", Some("alice")),
+            lock("2", "assets/b.psd", Some("bob")),
+            lock("3", "assets/c.psd", None),
+            lock("4", "assets/d.psd", Some("alice")),
+        ];
+        let paths = vec![
+            "assets/a.psd".to_owned(),
+            "assets/b.psd".to_owned(),
+            "assets/c.psd".to_owned(),
+        ];
+        let ids = |locks: Vec<Lock>| locks.into_iter().map(|l| l.id).collect::<Vec<_>>();
+
+        assert_eq!(
+            ids(filter_conflicting_locks(locks.clone(), &paths, Some("bob"))),
+            vec!["1"]
+        );
+        assert_eq!(
+            ids(filter_conflicting_locks(
+                locks.clone(),
+                &paths,
+                Some("alice")
+            )),
+            vec!["2"]
+        );
+        assert_eq!(
+            ids(filter_conflicting_locks(locks, &paths, None)),
+            vec!["1", "2"]
+        );
+    }
+}
@@ -68,7 +68,7 @@ impl RequestVars {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Lock {
     pub id: String,
     pub path: String,
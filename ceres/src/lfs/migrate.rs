@@ -0,0 +1,927 @@
+//!
+//! Moves the large files of a repository to LFS, as `git lfs migrate import` does on the
+//! client side. The blobs at the paths matching one of the patterns, gitattributes(5)
+//! patterns, with at least `threshold` bytes are uploaded to the LFS store and replaced with
+//! their pointers, and the patterns are tracked in the `.gitattributes` file of the root with
+//! the attributes `git lfs track` sets.
+//!
+//! - [`MigrateMode::Rewrite`] rewrites every commit of the migrated branches, and the
+//!   lightweight tags pointing to them: the commits get new ids and the clones have to be made
+//!   again. Annotated tags are left on the former history.
+//! - [`MigrateMode::FutureOnly`] adds a commit converting the files on top of each branch, like
+//!   `git lfs migrate import --no-rewrite`: the history keeps its ids, and its blobs.
+//!
+//! The former and new id of every commit created are recorded in `mega_lfs_commit_map`. A ref
+//! updated while the migration ran is left as it is. Blobs kept outside the database are not
+//! moved.
+//!
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::Utc;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use callisto::{lfs_objects, mega_lfs_commit_map, mega_lfs_object};
+use common::errors::MegaError;
+use common::utils::generate_id;
+use jupiter::context::Context;
+use jupiter::events::RefUpdateEvent;
+use jupiter::raw_storage::RawStorage;
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::internal::object::signature::{Signature, SignatureType};
+use venus::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+use venus::internal::object::types::ObjectType;
+use venus::internal::pack::entry::Entry;
+use venus::internal::pack::reference::RefCommand;
+use venus::mr::MergeRequest;
+use venus::repo::Repo;
+
+use crate::archive::attributes::{pattern_matches, ATTRIBUTES_FILE};
+use crate::bundle::matches_ref;
+use crate::lfs::{content_storage, LFS_REPO_NAME};
+
+pub const POINTER_VERSION: &str = "https://git-lfs.github.com/spec/v1";
+
+/// The attributes `git lfs track` sets on a pattern.
+const LFS_ATTRIBUTES: &str = "filter=lfs diff=lfs merge=lfs -text";
+
+/// Pointers are smaller, see the specification of git-lfs.
+const MAX_POINTER_SIZE: usize = 1024;
+
+/// Objects saved, and commits read, per batch.
+const CHUNK: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MigrateMode {
+    Rewrite,
+    FutureOnly,
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrateOptions {
+    pub patterns: Vec<String>,
+    /// Bytes from which a blob is moved.
+    pub threshold: i64,
+    pub mode: MigrateMode,
+    /// The branches migrated, by full name or branch name, all of them when empty.
+    pub branches: Vec<String>,
+    /// Computes the migration without uploading or saving anything.
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MovedBlob {
+    pub blob_id: String,
+    pub oid: String,
+    pub size: i64,
+    /// The first path the blob was found at.
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CommitMapping {
+    pub old_id: String,
+    pub new_id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MigratedRef {
+    pub ref_name: String,
+    pub old_id: String,
+    pub new_id: String,
+    /// Why the ref was not updated, None once it points to `new_id`.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrateReport {
+    pub migration_id: i64,
+    pub mode: MigrateMode,
+    pub dry_run: bool,
+    pub blobs: Vec<MovedBlob>,
+    pub commits: Vec<CommitMapping>,
+    pub refs: Vec<MigratedRef>,
+    /// The tags left as they are: the annotated tags, and the tags of the commits outside of
+    /// the migrated history.
+    pub skipped_tags: Vec<String>,
+}
+
+/// Moves the blobs of `repo_path` selected by `options` to LFS.
+pub async fn migrate(
+    context: &Context,
+    repo_path: &str,
+    options: &MigrateOptions,
+) -> Result<MigrateReport, MegaError> {
+    if options.patterns.is_empty() {
+        return Err(MegaError::with_message("no path pattern given"));
+    }
+    if let Some(pattern) = options
+        .patterns
+        .iter()
+        .find(|p| p.is_empty() || p.starts_with('!') || p.contains(char::is_whitespace))
+    {
+        return Err(MegaError::with_message(&format!(
+            "invalid pattern: {:?}",
+            pattern
+        )));
+    }
+    let storage = context.services.mega_storage.clone();
+    let repo = match repo_path {
+        "/" => Repo::empty(),
+        path => storage
+            .find_git_repo(path)
+            .await?
+            .map(Repo::from)
+            .ok_or_else(|| MegaError::with_message(&format!("no repository at {}", path)))?,
+    };
+    let refs = storage.get_repo_refs(&repo).await?;
+    if let Some(name) = options.branches.iter().find(|name| {
+        !refs
+            .iter()
+            .any(|r| r.ref_name.starts_with("refs/heads/") && matches_ref(name, &r.ref_name))
+    }) {
+        return Err(MegaError::with_message(&format!(
+            "branch not found: {}",
+            name
+        )));
+    }
+    let branches: Vec<(String, SHA1)> = refs
+        .iter()
+        .filter(|r| r.ref_name.starts_with("refs/heads/"))
+        .filter(|r| {
+            options.branches.is_empty()
+                || options.branches.iter().any(|n| matches_ref(n, &r.ref_name))
+        })
+        .filter_map(|r| Some((r.ref_name.clone(), SHA1::from_str(&r.ref_git_id).ok()?)))
+        .collect();
+    if branches.is_empty() {
+        return Err(MegaError::with_message(&format!(
+            "no branch to migrate in {}",
+            repo_path
+        )));
+    }
+
+    let mut migration = Migration::new(context, repo.clone(), options);
+    let mut report = MigrateReport {
+        migration_id: generate_id(),
+        mode: options.mode,
+        dry_run: options.dry_run,
+        blobs: Vec::new(),
+        commits: Vec::new(),
+        refs: Vec::new(),
+        skipped_tags: Vec::new(),
+    };
+    // the refs to move, with their current and new target
+    let mut moves: Vec<(String, SHA1, SHA1)> = Vec::new();
+    let mut mapping: HashMap<SHA1, SHA1> = HashMap::new();
+    match options.mode {
+        MigrateMode::Rewrite => {
+            let tips = branches.iter().map(|(_, id)| *id).collect();
+            let commits = load_history(context, &repo, tips).await?;
+            for id in parents_first(&commits) {
+                let commit = &commits[&id];
+                let tree_id = migration.rewrite_tree(commit.tree_id).await?;
+                let parents: Vec<SHA1> = commit
+                    .parent_commit_ids
+                    .iter()
+                    .map(|p| *mapping.get(p).unwrap_or(p))
+                    .collect();
+                if tree_id == commit.tree_id && parents == commit.parent_commit_ids {
+                    continue;
+                }
+                let new_id = migration.add_commit(Commit {
+                    tree_id,
+                    parent_commit_ids: parents,
+                    ..commit.clone()
+                })?;
+                mapping.insert(id, new_id);
+                migration.save(false).await?;
+            }
+            for r in refs.iter().filter(|r| r.ref_name.starts_with("refs/tags/")) {
+                let Ok(target) = SHA1::from_str(&r.ref_git_id) else {
+                    continue;
+                };
+                if let Some(new_id) = mapping.get(&target) {
+                    moves.push((r.ref_name.clone(), target, *new_id));
+                } else if !commits.contains_key(&target) {
+                    report.skipped_tags.push(r.ref_name.clone());
+                }
+            }
+        }
+        MigrateMode::FutureOnly => {
+            let signature = |signature_type| Signature {
+                signature_type,
+                name: String::from("Mega"),
+                email: String::from("mega@mega.dev"),
+                timestamp: Utc::now().timestamp() as usize,
+                timezone: String::from("+0000"),
+            };
+            for (_, tip) in &branches {
+                if mapping.contains_key(tip) {
+                    continue;
+                }
+                let commit: Commit = storage
+                    .get_commit_by_hash(&tip.to_plain_str(), &repo)
+                    .await?
+                    .ok_or_else(|| MegaError::with_message(&format!("commit not found: {}", tip)))?
+                    .into();
+                let tree_id = migration.rewrite_tree(commit.tree_id).await?;
+                if tree_id == commit.tree_id {
+                    continue;
+                }
+                let new_id = migration.add_commit(Commit {
+                    id: SHA1::default(),
+                    tree_id,
+                    parent_commit_ids: vec![*tip],
+                    author: signature(SignatureType::Author),
+                    committer: signature(SignatureType::Committer),
+                    message: format!(
+                        "\nMove the files matching {} to LFS\n",
+                        options.patterns.join(", ")
+                    ),
+                })?;
+                mapping.insert(*tip, new_id);
+            }
+        }
+    }
+    for (ref_name, tip) in &branches {
+        if let Some(new_id) = mapping.get(tip) {
+            moves.push((ref_name.clone(), *tip, *new_id));
+        }
+    }
+    migration.save(true).await?;
+    report.blobs = std::mem::take(&mut migration.moved);
+
+    let mut mappings: Vec<(SHA1, SHA1)> = mapping.into_iter().collect();
+    mappings.sort();
+    report.commits = mappings
+        .iter()
+        .map(|(old_id, new_id)| CommitMapping {
+            old_id: old_id.to_plain_str(),
+            new_id: new_id.to_plain_str(),
+        })
+        .collect();
+    moves.sort();
+    report.refs = moves
+        .into_iter()
+        .map(|(ref_name, old_id, new_id)| MigratedRef {
+            ref_name,
+            old_id: old_id.to_plain_str(),
+            new_id: new_id.to_plain_str(),
+            error: None,
+        })
+        .collect();
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    let now = Utc::now().naive_utc();
+    context
+        .services
+        .lfs_storage
+        .save_commit_map(
+            report
+                .commits
+                .iter()
+                .map(|m| mega_lfs_commit_map::Model {
+                    id: generate_id(),
+                    migration_id: report.migration_id,
+                    repo_id: repo.repo_id,
+                    old_id: m.old_id.clone(),
+                    new_id: m.new_id.clone(),
+                    created_at: now,
+                })
+                .collect(),
+        )
+        .await?;
+    let current: HashMap<String, String> = storage
+        .get_repo_refs(&repo)
+        .await?
+        .into_iter()
+        .map(|r| (r.ref_name, r.ref_git_id))
+        .collect();
+    let mut events = Vec::new();
+    for r in report.refs.iter_mut() {
+        if current.get(&r.ref_name) != Some(&r.old_id) {
+            r.error = Some(String::from("the ref moved during the migration"));
+            continue;
+        }
+        let command = RefCommand::new(r.old_id.clone(), r.new_id.clone(), r.ref_name.clone());
+        storage.handler_refs(&repo, &command).await;
+        events.push(RefUpdateEvent {
+            id: generate_id(),
+            repo_id: repo.repo_id,
+            repo_path: repo_path.to_owned(),
+            ref_name: command.ref_name,
+            old_id: command.old_id,
+            new_id: command.new_id,
+            pusher: None,
+            created_at: Utc::now().naive_utc(),
+        });
+    }
+    if !events.is_empty() {
+        context.events.publish(context, events).await?;
+    }
+    Ok(report)
+}
+
+/// The pointer file standing for a content of `size` bytes whose SHA-256 is `oid`.
+pub fn pointer(oid: &str, size: i64) -> String {
+    format!(
+        "version {}\noid sha256:{}\nsize {}\n",
+        POINTER_VERSION, oid, size
+    )
+}
+
+fn is_pointer(data: &[u8]) -> bool {
+    data.len() < MAX_POINTER_SIZE
+        && data.starts_with(format!("version {}\n", POINTER_VERSION).as_bytes())
+}
+
+/// `text`, the content of a `.gitattributes` file, with the LFS attributes of the `patterns`
+/// not tracked yet.
+fn with_lfs_attributes(text: &str, patterns: &[String]) -> String {
+    let mut result = text.to_owned();
+    for pattern in patterns {
+        let tracked = result.lines().any(|line| {
+            let mut fields = line.split_whitespace();
+            fields.next() == Some(pattern.as_str()) && fields.any(|f| f == "filter=lfs")
+        });
+        if tracked {
+            continue;
+        }
+        if !result.is_empty() && !result.ends_with('\n') {
+            result.push('\n');
+        }
+        result.push_str(&format!("{} {}\n", pattern, LFS_ATTRIBUTES));
+    }
+    result
+}
+
+/// The commits of `repo` reachable from `tips`, the parents not stored are left out.
+async fn load_history(
+    context: &Context,
+    repo: &Repo,
+    tips: Vec<SHA1>,
+) -> Result<HashMap<SHA1, Commit>, MegaError> {
+    let storage = &context.services.mega_storage;
+    let mut commits: HashMap<SHA1, Commit> = HashMap::new();
+    let mut seen: HashSet<SHA1> = tips.iter().copied().collect();
+    let mut frontier = tips;
+    while !frontier.is_empty() {
+        let mut next = Vec::new();
+        for chunk in frontier.chunks(CHUNK) {
+            let ids = chunk.iter().map(|id| id.to_plain_str()).collect();
+            for model in storage.get_commits_by_hashes(repo, ids).await? {
+                let commit: Commit = model.into();
+                for parent in &commit.parent_commit_ids {
+                    if seen.insert(*parent) {
+                        next.push(*parent);
+                    }
+                }
+                commits.insert(commit.id, commit);
+            }
+        }
+        frontier = next;
+    }
+    Ok(commits)
+}
+
+/// The ids of `commits`, every commit after its parents.
+fn parents_first(commits: &HashMap<SHA1, Commit>) -> Vec<SHA1> {
+    let mut ids: Vec<SHA1> = commits.keys().copied().collect();
+    ids.sort();
+    let mut order = Vec::with_capacity(ids.len());
+    let mut done: HashSet<SHA1> = HashSet::with_capacity(ids.len());
+    for id in ids {
+        // depth first, a commit is added once its parents are
+        let mut stack = vec![(id, false)];
+        while let Some((id, expanded)) = stack.pop() {
+            if done.contains(&id) {
+                continue;
+            }
+            if expanded {
+                done.insert(id);
+                order.push(id);
+                continue;
+            }
+            stack.push((id, true));
+            for parent in &commits[&id].parent_commit_ids {
+                if commits.contains_key(parent) && !done.contains(parent) {
+                    stack.push((*parent, false));
+                }
+            }
+        }
+    }
+    order
+}
+
+/// A migration under way: the objects read, moved and created.
+struct Migration<'a> {
+    context: &'a Context,
+    repo: Repo,
+    threshold: i64,
+    dry_run: bool,
+    lfs_store: Arc<dyn RawStorage>,
+    rewriter: Rewriter,
+    /// The `.gitattributes` of the root tracking the patterns, by the former one, None for an
+    /// absent file. None when the file tracks them already.
+    attributes: HashMap<Option<SHA1>, Option<SHA1>>,
+    /// The objects created and not saved yet.
+    entries: Vec<Entry>,
+    written: HashSet<SHA1>,
+    moved: Vec<MovedBlob>,
+}
+
+impl<'a> Migration<'a> {
+    fn new(context: &'a Context, repo: Repo, options: &MigrateOptions) -> Self {
+        Migration {
+            context,
+            repo,
+            threshold: options.threshold,
+            dry_run: options.dry_run,
+            lfs_store: content_storage(),
+            rewriter: Rewriter {
+                patterns: options.patterns.clone(),
+                ..Default::default()
+            },
+            attributes: HashMap::new(),
+            entries: Vec::new(),
+            written: HashSet::new(),
+            moved: Vec::new(),
+        }
+    }
+
+    /// The tree standing for the tree `root` of a commit once its blobs are moved.
+    async fn rewrite_tree(&mut self, root: SHA1) -> Result<SHA1, MegaError> {
+        self.load_trees(root).await?;
+        let candidates = self.rewriter.candidates(root);
+        self.move_blobs(candidates).await?;
+        let attributes = self.root_attributes(root).await?;
+        Ok(self.rewriter.rewrite(root, attributes))
+    }
+
+    /// Reads the trees below `root` not read yet.
+    async fn load_trees(&mut self, root: SHA1) -> Result<(), MegaError> {
+        let storage = &self.context.services.mega_storage;
+        let mut pending = vec![root];
+        while let Some(id) = pending.pop() {
+            if self.rewriter.trees.contains_key(&id) {
+                continue;
+            }
+            let tree: Tree = storage
+                .get_mega_tree_by_sha(&id.to_plain_str())
+                .await?
+                .ok_or_else(|| MegaError::with_message(&format!("tree not found: {}", id)))?
+                .into();
+            pending.extend(
+                tree.tree_items
+                    .iter()
+                    .filter(|item| item.mode == TreeItemMode::Tree)
+                    .map(|item| item.id),
+            );
+            self.rewriter.trees.insert(id, tree);
+        }
+        Ok(())
+    }
+
+    /// Uploads the `candidates` of at least the threshold to the LFS store and creates their
+    /// pointers.
+    async fn move_blobs(&mut self, candidates: Vec<(SHA1, String)>) -> Result<(), MegaError> {
+        if candidates.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<String> = candidates.iter().map(|(id, _)| id.to_plain_str()).collect();
+        let sizes = self.context.services.stats_storage.blob_sizes(&ids).await?;
+        for (id, path) in candidates {
+            let large = sizes
+                .get(&id.to_plain_str())
+                .is_some_and(|size| *size >= self.threshold);
+            let pointer = match large {
+                true => self.move_blob(id, path).await?,
+                false => None,
+            };
+            self.rewriter.pointers.insert(id, pointer);
+        }
+        Ok(())
+    }
+
+    /// The pointer of the blob `id`, None for a pointer itself.
+    async fn move_blob(&mut self, id: SHA1, path: String) -> Result<Option<SHA1>, MegaError> {
+        let Some(data) = self.load_blob(&id).await? else {
+            return Ok(None);
+        };
+        if is_pointer(&data) {
+            return Ok(None);
+        }
+        let oid = hex::encode(Sha256::digest(&data));
+        let size = data.len() as i64;
+        if !self.dry_run {
+            self.upload(&oid, &data).await?;
+        }
+        let pointer = self.add_blob(pointer(&oid, size).into_bytes());
+        self.moved.push(MovedBlob {
+            blob_id: id.to_plain_str(),
+            oid,
+            size,
+            path,
+        });
+        Ok(Some(pointer))
+    }
+
+    /// Stores `data` in the LFS store, the way an upload of the batch api does.
+    async fn upload(&self, oid: &str, data: &[u8]) -> Result<(), MegaError> {
+        let lfs_storage = &self.context.services.lfs_storage;
+        if lfs_storage.get_lfs_location(oid).await?.is_some() {
+            return Ok(());
+        }
+        if lfs_storage.get_lfs_object(oid.to_owned()).await?.is_none() {
+            lfs_storage
+                .new_lfs_object(lfs_objects::Model {
+                    oid: oid.to_owned(),
+                    size: data.len() as i64,
+                    exist: true,
+                })
+                .await?;
+        }
+        let location = self.lfs_store.put_object(LFS_REPO_NAME, oid, data).await?;
+        lfs_storage
+            .save_lfs_location(mega_lfs_object::Model {
+                oid: oid.to_owned(),
+                size: data.len() as i64,
+                storage_type: self.lfs_store.get_storage_type(),
+                location,
+                created_at: Utc::now().naive_utc(),
+            })
+            .await
+    }
+
+    /// The `.gitattributes` blob of the root tree `root` once it tracks the patterns, None
+    /// when it tracks them already.
+    async fn root_attributes(&mut self, root: SHA1) -> Result<Option<SHA1>, MegaError> {
+        let current = self.rewriter.trees[&root]
+            .tree_items
+            .iter()
+            .find(|item| item.name == ATTRIBUTES_FILE && item.mode == TreeItemMode::Blob)
+            .map(|item| item.id);
+        if let Some(attributes) = self.attributes.get(&current) {
+            return Ok(*attributes);
+        }
+        let text = match &current {
+            Some(id) => self
+                .load_blob(id)
+                .await?
+                .map(|data| String::from_utf8_lossy(&data).into_owned())
+                .unwrap_or_default(),
+            None => String::new(),
+        };
+        let tracked = with_lfs_attributes(&text, &self.rewriter.patterns);
+        let attributes = (tracked != text).then(|| self.add_blob(tracked.into_bytes()));
+        self.attributes.insert(current, attributes);
+        Ok(attributes)
+    }
+
+    async fn load_blob(&self, id: &SHA1) -> Result<Option<Vec<u8>>, MegaError> {
+        self.context
+            .services
+            .mega_storage
+            .get_blob_content(id)
+            .await
+    }
+
+    fn add_blob(&mut self, data: Vec<u8>) -> SHA1 {
+        let id = SHA1::from_type_and_data(ObjectType::Blob, &data);
+        self.add_entry(ObjectType::Blob, data, id);
+        id
+    }
+
+    fn add_commit(&mut self, commit: Commit) -> Result<SHA1, MegaError> {
+        let data = commit
+            .to_data()
+            .map_err(|e| MegaError::with_message(&e.to_string()))?;
+        let id = SHA1::from_type_and_data(ObjectType::Commit, &data);
+        self.add_entry(ObjectType::Commit, data, id);
+        Ok(id)
+    }
+
+    fn add_entry(&mut self, obj_type: ObjectType, data: Vec<u8>, hash: SHA1) {
+        if self.written.insert(hash) {
+            self.entries.push(Entry {
+                obj_type,
+                data,
+                hash,
+                crc32: None,
+            });
+        }
+    }
+
+    /// Saves the objects created, once there are enough of them unless `all` is set.
+    async fn save(&mut self, all: bool) -> Result<(), MegaError> {
+        for tree in std::mem::take(&mut self.rewriter.created) {
+            let data = tree
+                .to_data()
+                .map_err(|e| MegaError::with_message(&e.to_string()))?;
+            self.add_entry(ObjectType::Tree, data, tree.id);
+        }
+        if self.entries.len() < CHUNK && !all {
+            return Ok(());
+        }
+        let entries = std::mem::take(&mut self.entries);
+        if self.dry_run || entries.is_empty() {
+            return Ok(());
+        }
+        self.context
+            .services
+            .mega_storage
+            .save_entry(&MergeRequest::default(), &self.repo, entries)
+            .await
+    }
+}
+
+/// Rewrites the trees read, with the pointers of the blobs moved.
+#[derive(Default)]
+struct Rewriter {
+    patterns: Vec<String>,
+    trees: HashMap<SHA1, Tree>,
+    /// The pointer of the blobs moved, None for a blob checked and kept.
+    pointers: HashMap<SHA1, Option<SHA1>>,
+    /// The tree standing for a tree at a path.
+    rewritten: HashMap<(SHA1, String), SHA1>,
+    /// The trees created and not saved yet.
+    created: Vec<Tree>,
+}
+
+impl Rewriter {
+    /// The blobs below `root` at a path matching a pattern and not checked yet, with the
+    /// first path they are found at.
+    fn candidates(&self, root: SHA1) -> Vec<(SHA1, String)> {
+        let mut candidates = Vec::new();
+        let mut found = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![(root, String::new())];
+        while let Some((id, dir)) = pending.pop() {
+            if !visited.insert((id, dir.clone())) {
+                continue;
+            }
+            let Some(tree) = self.trees.get(&id) else {
+                continue;
+            };
+            for item in &tree.tree_items {
+                let path = join(&dir, &item.name);
+                match item.mode {
+                    TreeItemMode::Tree => pending.push((item.id, path)),
+                    TreeItemMode::Blob | TreeItemMode::BlobExecutable
+                        if !self.pointers.contains_key(&item.id)
+                            && self.matches(&path)
+                            && found.insert(item.id) =>
+                    {
+                        candidates.push((item.id, path));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        candidates
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        self.patterns.iter().any(|p| pattern_matches(p, path))
+    }
+
+    /// The root tree standing for `root`, with the `.gitattributes` blob `attributes`.
+    fn rewrite(&mut self, root: SHA1, attributes: Option<SHA1>) -> SHA1 {
+        let Some(attributes) = attributes else {
+            return self.rewrite_dir(root, "");
+        };
+        let Some(tree) = self.trees.get(&root) else {
+            return root;
+        };
+        let original = tree.tree_items.clone();
+        let mut items = self.rewrite_items(&original, "");
+        set_blob(&mut items, ATTRIBUTES_FILE, attributes);
+        self.create(root, original, items)
+    }
+
+    fn rewrite_dir(&mut self, id: SHA1, dir: &str) -> SHA1 {
+        if let Some(rewritten) = self.rewritten.get(&(id, dir.to_owned())) {
+            return *rewritten;
+        }
+        let Some(tree) = self.trees.get(&id) else {
+            return id;
+        };
+        let original = tree.tree_items.clone();
+        let items = self.rewrite_items(&original, dir);
+        let rewritten = self.create(id, original, items);
+        self.rewritten.insert((id, dir.to_owned()), rewritten);
+        rewritten
+    }
+
+    fn rewrite_items(&mut self, items: &[TreeItem], dir: &str) -> Vec<TreeItem> {
+        let mut rewritten = Vec::with_capacity(items.len());
+        for item in items {
+            let path = join(dir, &item.name);
+            let id = match item.mode {
+                TreeItemMode::Tree => self.rewrite_dir(item.id, &path),
+                TreeItemMode::Blob | TreeItemMode::BlobExecutable if self.matches(&path) => {
+                    match self.pointers.get(&item.id) {
+                        Some(Some(pointer)) => *pointer,
+                        _ => item.id,
+                    }
+                }
+                _ => item.id,
+            };
+            rewritten.push(TreeItem { id, ..item.clone() });
+        }
+        rewritten
+    }
+
+    /// The tree of `items`, `id` when they are its `original` ones.
+    fn create(&mut self, id: SHA1, original: Vec<TreeItem>, items: Vec<TreeItem>) -> SHA1 {
+        if items == original {
+            return id;
+        }
+        match Tree::from_tree_items(items) {
+            Ok(tree) => {
+                let id = tree.id;
+                self.created.push(tree);
+                id
+            }
+            Err(_) => id,
+        }
+    }
+}
+
+/// Points the blob `name` of `items` to `id`, adding it in the order of git when absent.
+fn set_blob(items: &mut Vec<TreeItem>, name: &str, id: SHA1) {
+    if let Some(item) = items.iter_mut().find(|item| item.name == name) {
+        item.mode = TreeItemMode::Blob;
+        item.id = id;
+        return;
+    }
+    let position = items
+        .iter()
+        .position(|item| sort_key(item) > name.as_bytes().to_vec())
+        .unwrap_or(items.len());
+    items.insert(
+        position,
+        TreeItem::new(TreeItemMode::Blob, id, name.to_owned()),
+    );
+}
+
+/// Git orders the entries of a tree by name, a directory as if its name ended with a slash.
+fn sort_key(item: &TreeItem) -> Vec<u8> {
+    let mut key = item.name.as_bytes().to_vec();
+    if item.mode == TreeItemMode::Tree {
+        key.push(b'/');
+    }
+    key
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use venus::hash::SHA1;
+    use venus::internal::object::commit::Commit;
+    use venus::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+    use venus::test_support::{commit, id};
+
+    use super::{is_pointer, parents_first, pointer, with_lfs_attributes, Rewriter};
+
+    fn tree(items: Vec<(TreeItemMode, SHA1, &str)>) -> Tree {
+        Tree::from_tree_items(
+            items
+                .into_iter()
+                .map(|(mode, id, name)| TreeItem::new(mode, id, name.to_owned()))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pointer() {
+        let oid = "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393";
+        let text = pointer(oid, 12345);
+        assert_eq!(
+            text,
+            format!(
+                "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 12345\n",
+                oid
+            )
+        );
+        assert!(is_pointer(text.as_bytes()));
+        assert!(!is_pointer(b"version 2\n"));
+    }
+
+    #[test]
+    fn test_with_lfs_attributes() {
+        let patterns = vec![String::from("*.bin"), String::from("assets/**")];
+        assert_eq!(
+            with_lfs_attributes("", &patterns),
+            "*.bin filter=lfs diff=lfs merge=lfs -text\n\
+             assets/** filter=lfs diff=lfs merge=lfs -text\n"
+        );
+        let text = "*.txt text\n*.bin filter=lfs diff=lfs merge=lfs -text";
+        assert_eq!(
+            with_lfs_attributes(text, &patterns),
+            format!("{}\nassets/** filter=lfs diff=lfs merge=lfs -text\n", text)
+        );
+        let tracked = with_lfs_attributes(text, &patterns);
+        assert_eq!(with_lfs_attributes(&tracked, &patterns), tracked);
+    }
+
+    #[test]
+    fn test_parents_first() {
+        let commits: HashMap<SHA1, Commit> = [
+            commit(4, &[2, 3]),
+            commit(3, &[1]),
+            commit(2, &[1]),
+            commit(1, &[]),
+            commit(5, &[4, 9]),
+        ]
+        .into_iter()
+        .map(|c| (c.id, c))
+        .collect();
+        let order = parents_first(&commits);
+        assert_eq!(order.len(), 5);
+        let position = |n: u8| order.iter().position(|c| *c == id(n)).unwrap();
+        assert!(position(1) < position(2));
+        assert!(position(1) < position(3));
+        assert!(position(2) < position(4));
+        assert!(position(3) < position(4));
+        assert!(position(4) < position(5));
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let assets = tree(vec![
+            (TreeItemMode::Blob, id(1), "logo.png"),
+            (TreeItemMode::Blob, id(2), "model.bin"),
+        ]);
+        let root = tree(vec![
+            (TreeItemMode::Blob, id(2), "README.md"),
+            (TreeItemMode::Tree, assets.id, "assets"),
+            (TreeItemMode::Blob, id(3), "data.bin"),
+        ]);
+        let mut rewriter = Rewriter {
+            patterns: vec![String::from("*.bin")],
+            ..Default::default()
+        };
+        rewriter.trees.insert(assets.id, assets.clone());
+        rewriter.trees.insert(root.id, root.clone());
+
+        let candidates = rewriter.candidates(root.id);
+        let mut paths: Vec<&str> = candidates.iter().map(|(_, p)| p.as_str()).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["assets/model.bin", "data.bin"]);
+
+        // data.bin is below the threshold
+        rewriter.pointers.insert(id(2), Some(id(20)));
+        rewriter.pointers.insert(id(3), None);
+        assert!(rewriter.candidates(root.id).is_empty());
+        let rewritten = rewriter.rewrite(root.id, Some(id(30)));
+        let trees: HashMap<SHA1, Tree> = rewriter
+            .created
+            .iter()
+            .map(|tree| (tree.id, tree.clone()))
+            .collect();
+        let new_root = &trees[&rewritten];
+        let names: Vec<&str> = new_root
+            .tree_items
+            .iter()
+            .map(|i| i.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec![".gitattributes", "README.md", "assets", "data.bin"]
+        );
+        // README.md has the content of model.bin but does not match the pattern
+        assert_eq!(new_root.tree_items[0].id, id(30));
+        assert_eq!(new_root.tree_items[1].id, id(2));
+        assert_eq!(new_root.tree_items[3].id, id(3));
+        let new_assets = &trees[&new_root.tree_items[2].id];
+        assert_eq!(new_assets.tree_items[0].id, id(1));
+        assert_eq!(new_assets.tree_items[1].id, id(20));
+
+        // nothing to change
+        let mut rewriter = Rewriter {
+            patterns: vec![String::from("*.psd")],
+            ..Default::default()
+        };
+        rewriter.trees.insert(assets.id, assets.clone());
+        assert_eq!(rewriter.rewrite(assets.id, None), assets.id);
+        assert!(rewriter.created.is_empty());
+    }
+}
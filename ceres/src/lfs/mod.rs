@@ -1,9 +1,44 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 
+use common::errors::MegaError;
+use jupiter::raw_storage::{self, local_storage::LocalStorage};
 use jupiter::{context::Context, raw_storage::RawStorage};
 
 pub mod handler;
 pub mod lfs_structs;
+pub mod migrate;
+
+/// The directory the content of the LFS objects is stored in, under [`LFS_REPO_NAME`].
+pub const LFS_FILES_DIR: &str = "lfs-files";
+pub const LFS_REPO_NAME: &str = "lfs";
+
+/// The store of the content of the LFS objects, encrypted at rest when it is enabled.
+pub fn content_storage() -> Arc<dyn RawStorage> {
+    raw_storage::with_encryption(Arc::new(LocalStorage::init(PathBuf::from(LFS_FILES_DIR))))
+}
+
+/// Rewraps the content of the stored LFS objects with the current key encryption key, after a
+/// rotation. Returns the objects rewritten.
+pub async fn rewrap_contents(context: &Context) -> Result<usize, MegaError> {
+    let storage = content_storage();
+    let mut rewrapped = 0;
+    let mut after = String::new();
+    loop {
+        let oids = context.services.lfs_storage.get_lfs_oids(&after, 1000).await?;
+        let Some(last) = oids.last() else {
+            return Ok(rewrapped);
+        };
+        after = last.clone();
+        for oid in oids {
+            if storage.exist_object(LFS_REPO_NAME, &oid)
+                && storage.rewrap_object(LFS_REPO_NAME, &oid).await?
+            {
+                rewrapped += 1;
+            }
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct LfsConfig {
@@ -16,4 +51,7 @@ pub struct LfsConfig {
     pub lfs_storage: Arc<dyn RawStorage>,
 
     pub repo_name: String,
+
+    /// Base of the links of batch responses, the address the client reached mega through.
+    pub server_url: String,
 }
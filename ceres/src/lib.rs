@@ -1,3 +1,29 @@
+pub mod activity;
+pub mod analytics;
+pub mod archive;
+pub mod auto_tag;
+pub mod blame;
+pub mod branch_cleanup;
+pub mod bundle;
+pub mod chatops;
+pub mod code_nav;
+pub mod code_search;
+pub mod compare;
+pub mod email_reply;
+pub mod fragmentation;
+pub mod gc;
+pub mod history;
 pub mod http;
+pub mod impact;
+pub mod interop;
+pub mod last_commit;
 pub mod lfs;
+pub mod merge_message;
+pub mod object_store;
+pub mod policy;
 pub mod protocol;
+pub mod replace;
+pub mod restore;
+pub mod search;
+pub mod storage_report;
+pub mod watch;
@@ -0,0 +1,171 @@
+//!
+//! The message of a merge, rendered from the `merge_message_template` setting: a handlebars
+//! template given the `id` and the `title` of the merge request. The authors of the merged
+//! commits are credited with `Co-authored-by` trailers and the approvers with `Reviewed-by`
+//! trailers, appended to the last paragraph like `git interpret-trailers` does. A trailer the
+//! rendered message already has is not repeated.
+//!
+use handlebars::Handlebars;
+use serde::Serialize;
+
+use common::errors::MegaError;
+use venus::internal::object::commit::Commit;
+
+const CO_AUTHORED_BY: &str = "Co-authored-by";
+const REVIEWED_BY: &str = "Reviewed-by";
+
+/// A name and an email, as in `Name <email>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Person {
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MergeMessage {
+    pub id: i64,
+    pub title: Option<String>,
+    #[serde(skip)]
+    pub co_authors: Vec<Person>,
+    #[serde(skip)]
+    pub reviewers: Vec<Person>,
+}
+
+impl MergeMessage {
+    /// Renders `template` and appends the trailers, the message ends with a LF.
+    pub fn render(&self, template: &str) -> Result<String, MegaError> {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_escape_fn(handlebars::no_escape);
+        let message = handlebars
+            .render_template(template, self)
+            .map_err(|err| MegaError::with_message(&format!("invalid merge template: {}", err)))?;
+        let trailers: Vec<_> = self
+            .co_authors
+            .iter()
+            .map(|person| trailer(CO_AUTHORED_BY, person))
+            .chain(
+                self.reviewers
+                    .iter()
+                    .map(|person| trailer(REVIEWED_BY, person)),
+            )
+            .collect();
+        Ok(add_trailers(&message, &trailers))
+    }
+}
+
+/// The distinct authors of `commits`, the earliest authored first.
+pub fn co_authors<'a>(commits: impl IntoIterator<Item = &'a Commit>) -> Vec<Person> {
+    let mut commits: Vec<_> = commits.into_iter().collect();
+    commits.sort_by_key(|commit| commit.author.timestamp);
+    let mut authors: Vec<Person> = Vec::new();
+    for commit in commits {
+        let email = commit.author.email.trim();
+        if authors.iter().all(|a| !a.email.eq_ignore_ascii_case(email)) {
+            authors.push(Person {
+                name: commit.author.name.trim().to_owned(),
+                email: email.to_owned(),
+            });
+        }
+    }
+    authors
+}
+
+fn trailer(token: &str, person: &Person) -> String {
+    format!("{}: {} <{}>", token, person.name, person.email)
+}
+
+/// A `Token: value` line, the token made of letters, digits and dashes.
+fn is_trailer(line: &str) -> bool {
+    match line.split_once(": ") {
+        Some((token, _)) => {
+            !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        }
+        None => false,
+    }
+}
+
+/// Appends the `trailers` missing from `message`, to its last paragraph when it is made of
+/// trailers, otherwise as a paragraph of their own. The subject is never taken as trailers.
+fn add_trailers(message: &str, trailers: &[String]) -> String {
+    let mut message = message.trim_end().to_owned();
+    let mut missing: Vec<&String> = Vec::new();
+    for trailer in trailers {
+        if !message.lines().any(|line| line.trim() == trailer) && !missing.contains(&trailer) {
+            missing.push(trailer);
+        }
+    }
+    if !missing.is_empty() {
+        let has_trailers = match message.rsplit_once("\n\n") {
+            Some((_, last)) => last.lines().all(is_trailer),
+            None => false,
+        };
+        message.push_str(if has_trailers { "\n" } else { "\n\n" });
+        for trailer in missing {
+            message.push_str(trailer);
+            message.push('\n');
+        }
+        message.pop();
+    }
+    message.push('\n');
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_trailers, MergeMessage, Person};
+
+    const TEMPLATE: &str = "Merge request !{{id}}{{#if title}}: {{title}}{{/if}}";
+
+    fn person(name: &str) -> Person {
+        Person {
+            name: name.to_owned(),
+            email: format!("{}@example.com", name.to_lowercase()),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let message = MergeMessage {
+            id: 42,
+            title: Some(String::from("Use <Arc> in the cache")),
+            co_authors: vec![person("Alice"), person("Bob")],
+            reviewers: vec![person("Carol")],
+        };
+        assert_eq!(
+            message.render(TEMPLATE).unwrap(),
+            "Merge request !42: Use <Arc> in the cache\n\n\
+             Co-authored-by: Alice <alice@example.com>\n\
+             Co-authored-by: Bob <bob@example.com>\n\
+             Reviewed-by: Carol <carol@example.com>\n"
+        );
+
+        let untitled = MergeMessage {
+            id: 7,
+            ..Default::default()
+        };
+        assert_eq!(untitled.render(TEMPLATE).unwrap(), "Merge request !7\n");
+        assert!(untitled.render("{{#if id}}").is_err());
+    }
+
+    #[test]
+    fn test_add_trailers() {
+        let trailers = vec![
+            String::from("Co-authored-by: Bob <bob@example.com>"),
+            String::from("Reviewed-by: Carol <carol@example.com>"),
+        ];
+        // a subject alone is not a trailer paragraph
+        assert_eq!(
+            add_trailers("Fix: the cache\n", &trailers[..1]),
+            "Fix: the cache\n\nCo-authored-by: Bob <bob@example.com>\n"
+        );
+        // appended to the trailers of the message, the ones present are not repeated
+        assert_eq!(
+            add_trailers(
+                "Merge !1\n\nBody\n\nCo-authored-by: Bob <bob@example.com>\n",
+                &trailers
+            ),
+            "Merge !1\n\nBody\n\nCo-authored-by: Bob <bob@example.com>\n\
+             Reviewed-by: Carol <carol@example.com>\n"
+        );
+    }
+}
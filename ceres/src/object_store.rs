@@ -0,0 +1,514 @@
+//!
+//! One interface to the places the objects are kept: the packs with their `.idx`, the loose
+//! objects of a Git object directory and the database. An [`ObjectStore`] reads, writes and
+//! lists objects by their SHA-1, a [`LayeredStore`] stacks stores so that an object is found
+//! wherever it is, e.g. a loose object written lately in front of the packs it is not in yet.
+//!
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use common::errors::MegaError;
+use common::fs_utils;
+use jupiter::storage::mega_storage::{MegaStorage, StoredObjects};
+use mercury::internal::pack::reader::PackReader;
+use venus::errors::GitError;
+use venus::hash::SHA1;
+use venus::internal::object::types::ObjectType;
+use venus::internal::pack::entry::Entry;
+use venus::mr::MergeRequest;
+use venus::repo::Repo;
+
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /// The object `hash`, None if the store does not have it.
+    async fn get(&self, hash: &SHA1) -> Result<Option<Entry>, MegaError>;
+
+    /// Keeps `entry`, nothing is done if the store has it already.
+    async fn put(&self, entry: &Entry) -> Result<(), MegaError>;
+
+    async fn exists(&self, hash: &SHA1) -> Result<bool, MegaError> {
+        Ok(self.get(hash).await?.is_some())
+    }
+
+    /// The objects of `hashes` the store has, in no particular order.
+    async fn get_many(&self, hashes: &[SHA1]) -> Result<Vec<Entry>, MegaError> {
+        let mut entries = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            entries.extend(self.get(hash).await?);
+        }
+        Ok(entries)
+    }
+
+    /// The ids of all the objects of the store, sorted.
+    async fn list(&self) -> Result<Vec<SHA1>, MegaError>;
+
+    /// Whether [`put`](Self::put) keeps objects, a [`LayeredStore`] writes to the first store
+    /// that does.
+    fn is_writable(&self) -> bool {
+        true
+    }
+}
+
+fn git_error(e: GitError) -> MegaError {
+    MegaError::with_message(&e.to_string())
+}
+
+/// The objects of the packs of a directory, read through their `.idx`. Packs are written
+/// whole by a push or a repack, not object by object, so this store is read only.
+pub struct PackStore {
+    packs: Vec<PackReader>,
+}
+
+impl PackStore {
+    /// Opens every pack of `pack_dir` with its index, an index without its pack is left out.
+    pub fn open(pack_dir: &Path) -> Result<Self, MegaError> {
+        let mut index_paths: Vec<PathBuf> = fs::read_dir(pack_dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|path| path.extension().is_some_and(|ext| ext == "idx"))
+            .filter(|path| path.with_extension("pack").is_file())
+            .collect();
+        index_paths.sort();
+        let packs = index_paths
+            .iter()
+            .map(|index_path| PackReader::open(&index_path.with_extension("pack"), index_path))
+            .collect::<Result<_, _>>()
+            .map_err(git_error)?;
+        Ok(PackStore { packs })
+    }
+}
+
+#[async_trait]
+impl ObjectStore for PackStore {
+    async fn get(&self, hash: &SHA1) -> Result<Option<Entry>, MegaError> {
+        for pack in &self.packs {
+            if let Some(entry) = pack.get(hash).map_err(git_error)? {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn put(&self, entry: &Entry) -> Result<(), MegaError> {
+        Err(MegaError::with_message(&format!(
+            "can not write {} to the packs, they are read only",
+            entry.hash.to_plain_str()
+        )))
+    }
+
+    async fn exists(&self, hash: &SHA1) -> Result<bool, MegaError> {
+        Ok(self.packs.iter().any(|pack| pack.contains(hash)))
+    }
+
+    async fn list(&self) -> Result<Vec<SHA1>, MegaError> {
+        let hashes: BTreeSet<SHA1> = self
+            .packs
+            .iter()
+            .flat_map(|pack| pack.hashes().copied())
+            .collect();
+        Ok(hashes.into_iter().collect())
+    }
+
+    fn is_writable(&self) -> bool {
+        false
+    }
+}
+
+/// The loose objects of a Git object directory: `<root>/ab/cdef...`, every one the zlib of
+/// `<type> <size>\0<content>`, readable by Git itself.
+pub struct LooseStore {
+    root: PathBuf,
+}
+
+impl LooseStore {
+    pub fn new(root: &Path) -> Self {
+        LooseStore {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn object_path(&self, hash: &SHA1) -> PathBuf {
+        let hex = hash.to_plain_str();
+        self.root.join(&hex[..2]).join(&hex[2..])
+    }
+
+    fn read(&self, hash: &SHA1) -> Result<Option<Entry>, MegaError> {
+        let file = match fs::File::open(self.object_path(hash)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut data = Vec::new();
+        ZlibDecoder::new(file).read_to_end(&mut data)?;
+        parse_loose_object(hash, &data).map(Some)
+    }
+
+    fn write(&self, entry: &Entry) -> Result<(), MegaError> {
+        let path = self.object_path(&entry.hash);
+        if path.exists() {
+            return Ok(());
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(entry.obj_type.to_bytes())?;
+        encoder.write_all(format!(" {}\0", entry.data.len()).as_bytes())?;
+        encoder.write_all(&entry.data)?;
+        let data = encoder.finish()?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs_utils::write_atomic(&path, &data)?;
+        Ok(())
+    }
+
+    fn scan(&self) -> Result<Vec<SHA1>, MegaError> {
+        let dirs = match fs::read_dir(&self.root) {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut hashes = Vec::new();
+        for dir in dirs {
+            let dir = dir?;
+            let prefix = dir.file_name().to_string_lossy().into_owned();
+            // `pack` and `info` live there too
+            if prefix.len() != 2 || !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let name = file?.file_name().to_string_lossy().into_owned();
+                // the temporary files of a write are longer
+                if let Ok(hash) = SHA1::from_str(&format!("{}{}", prefix, name)) {
+                    hashes.push(hash);
+                }
+            }
+        }
+        hashes.sort();
+        Ok(hashes)
+    }
+}
+
+/// Reads an object stored as `<type> <size>\0<content>`, which must have the id `hash`.
+fn parse_loose_object(hash: &SHA1, data: &[u8]) -> Result<Entry, MegaError> {
+    let invalid = || MegaError::with_message(&format!("invalid object {}", hash.to_plain_str()));
+    let header_end = data.iter().position(|b| *b == b'\0').ok_or_else(invalid)?;
+    let header = std::str::from_utf8(&data[..header_end]).map_err(|_| invalid())?;
+    let (obj_type, size) = header.split_once(' ').ok_or_else(invalid)?;
+    let obj_type = ObjectType::from_string(obj_type).map_err(git_error)?;
+    let content = data[header_end + 1..].to_vec();
+    if size.parse::<usize>().ok() != Some(content.len()) {
+        return Err(invalid());
+    }
+    if SHA1::from_type_and_data(obj_type, &content) != *hash {
+        return Err(invalid());
+    }
+    Ok(Entry {
+        obj_type,
+        data: content,
+        hash: *hash,
+        crc32: None,
+    })
+}
+
+#[async_trait]
+impl ObjectStore for LooseStore {
+    async fn get(&self, hash: &SHA1) -> Result<Option<Entry>, MegaError> {
+        self.read(hash)
+    }
+
+    async fn put(&self, entry: &Entry) -> Result<(), MegaError> {
+        self.write(entry)
+    }
+
+    async fn exists(&self, hash: &SHA1) -> Result<bool, MegaError> {
+        Ok(self.object_path(hash).is_file())
+    }
+
+    async fn list(&self) -> Result<Vec<SHA1>, MegaError> {
+        self.scan()
+    }
+}
+
+/// The objects of the database, see [`MegaStorage::get_entries`]. The objects written are
+/// saved as those of `mr` in `repo`.
+pub struct DbStore {
+    storage: MegaStorage,
+    repo: Repo,
+    mr: MergeRequest,
+}
+
+impl DbStore {
+    pub fn new(storage: MegaStorage, repo: Repo, mr: MergeRequest) -> Self {
+        DbStore { storage, repo, mr }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for DbStore {
+    async fn get(&self, hash: &SHA1) -> Result<Option<Entry>, MegaError> {
+        // the id is of any type, the table holding it returns it
+        let id = vec![hash.to_plain_str()];
+        let entries = self
+            .storage
+            .get_entries(&StoredObjects {
+                commits: id.clone(),
+                trees: id.clone(),
+                blobs: id,
+                tags: Vec::new(),
+            })
+            .await?;
+        Ok(entries.into_iter().next())
+    }
+
+    /// Read a thousand at a time, an id is looked up in the tables of every type.
+    async fn get_many(&self, hashes: &[SHA1]) -> Result<Vec<Entry>, MegaError> {
+        let mut entries = Vec::with_capacity(hashes.len());
+        for chunk in hashes.chunks(1000) {
+            let ids: Vec<String> = chunk.iter().map(|hash| hash.to_plain_str()).collect();
+            let objects = StoredObjects {
+                commits: ids.clone(),
+                trees: ids.clone(),
+                blobs: ids,
+                tags: Vec::new(),
+            };
+            entries.extend(self.storage.get_entries(&objects).await?);
+        }
+        Ok(entries)
+    }
+
+    async fn put(&self, entry: &Entry) -> Result<(), MegaError> {
+        self.storage
+            .save_entry(&self.mr, &self.repo, vec![entry.clone()])
+            .await
+    }
+
+    async fn list(&self) -> Result<Vec<SHA1>, MegaError> {
+        let ids = self.storage.object_ids().await?;
+        let hashes: BTreeSet<SHA1> = [ids.commits, ids.trees, ids.blobs]
+            .into_iter()
+            .flatten()
+            .filter_map(|id| SHA1::from_str(&id).ok())
+            .collect();
+        Ok(hashes.into_iter().collect())
+    }
+}
+
+/// Stores looked up in order, an object is read from the first one that has it and written
+/// to the first writable one.
+pub struct LayeredStore {
+    layers: Vec<Box<dyn ObjectStore>>,
+}
+
+impl LayeredStore {
+    pub fn new(layers: Vec<Box<dyn ObjectStore>>) -> Self {
+        LayeredStore { layers }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for LayeredStore {
+    async fn get(&self, hash: &SHA1) -> Result<Option<Entry>, MegaError> {
+        for layer in &self.layers {
+            if let Some(entry) = layer.get(hash).await? {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn get_many(&self, hashes: &[SHA1]) -> Result<Vec<Entry>, MegaError> {
+        let mut entries = Vec::with_capacity(hashes.len());
+        let mut missing = hashes.to_vec();
+        for layer in &self.layers {
+            if missing.is_empty() {
+                break;
+            }
+            let found = layer.get_many(&missing).await?;
+            let found_hashes: HashSet<SHA1> = found.iter().map(|entry| entry.hash).collect();
+            missing.retain(|hash| !found_hashes.contains(hash));
+            entries.extend(found);
+        }
+        Ok(entries)
+    }
+
+    /// Nothing is written if a layer has the object already.
+    async fn put(&self, entry: &Entry) -> Result<(), MegaError> {
+        if self.exists(&entry.hash).await? {
+            return Ok(());
+        }
+        let layer = self
+            .layers
+            .iter()
+            .find(|layer| layer.is_writable())
+            .ok_or_else(|| MegaError::with_message("no writable object store"))?;
+        layer.put(entry).await
+    }
+
+    async fn exists(&self, hash: &SHA1) -> Result<bool, MegaError> {
+        for layer in &self.layers {
+            if layer.exists(hash).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn list(&self) -> Result<Vec<SHA1>, MegaError> {
+        let mut hashes = BTreeSet::new();
+        for layer in &self.layers {
+            hashes.extend(layer.list().await?);
+        }
+        Ok(hashes.into_iter().collect())
+    }
+
+    fn is_writable(&self) -> bool {
+        self.layers.iter().any(|layer| layer.is_writable())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use mercury::internal::pack::Pack;
+    use mercury::test_support::{FixtureConfig, SyntheticRepo};
+    use venus::hash::SHA1;
+    use venus::internal::object::types::ObjectType;
+    use venus::internal::pack::entry::Entry;
+
+    use super::{LayeredStore, LooseStore, ObjectStore, PackStore};
+
+    fn temp_dir() -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("object_store_{}", common::utils::generate_id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn blob(content: &str) -> Entry {
+        let data = content.as_bytes().to_vec();
+        Entry {
+            obj_type: ObjectType::Blob,
+            hash: SHA1::from_type_and_data(ObjectType::Blob, &data),
+            data,
+            crc32: None,
+        }
+    }
+
+    /// A pack of a synthetic repository with its index in `dir`.
+    fn write_pack(dir: &std::path::Path) -> SyntheticRepo {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 3,
+            ..Default::default()
+        });
+        let pack = repo.to_pack();
+        fs::write(dir.join("pack-0.pack"), &pack).unwrap();
+        let mut p = Pack::new(None, Some(1024 * 1024 * 20), Some(dir.to_path_buf()));
+        p.decode_with_index(&mut Cursor::new(pack), &dir.join("pack-0.idx"), |_| {})
+            .unwrap();
+        repo
+    }
+
+    #[tokio::test]
+    async fn test_loose_store() {
+        let dir = temp_dir();
+        let store = LooseStore::new(&dir);
+        let hello = blob("hello\n");
+        assert_eq!(
+            hello.hash,
+            SHA1::from_str("ce013625030ba8dba906f756967f9e9ca394464a").unwrap()
+        );
+        assert!(store.get(&hello.hash).await.unwrap().is_none());
+        assert!(store.list().await.unwrap().is_empty());
+
+        store.put(&hello).await.unwrap();
+        store.put(&hello).await.unwrap();
+        assert!(dir
+            .join("ce/013625030ba8dba906f756967f9e9ca394464a")
+            .is_file());
+        assert!(store.exists(&hello.hash).await.unwrap());
+        let read = store.get(&hello.hash).await.unwrap().unwrap();
+        assert_eq!(read.data, hello.data);
+        assert_eq!(read.obj_type, ObjectType::Blob);
+        assert_eq!(store.list().await.unwrap(), vec![hello.hash]);
+
+        // an object whose content does not match its name
+        let other = blob("other\n");
+        fs::create_dir_all(dir.join("aa")).unwrap();
+        fs::copy(
+            dir.join("ce/013625030ba8dba906f756967f9e9ca394464a"),
+            dir.join("aa").join(&other.hash.to_plain_str()[2..]),
+        )
+        .unwrap();
+        let forged = SHA1::from_str(&format!("aa{}", &other.hash.to_plain_str()[2..])).unwrap();
+        assert!(store.get(&forged).await.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pack_store() {
+        let dir = temp_dir();
+        let repo = write_pack(&dir);
+        // an index without its pack is left out
+        fs::copy(dir.join("pack-0.idx"), dir.join("pack-9.idx")).unwrap();
+        let store = PackStore::open(&dir).unwrap();
+        for object in &repo.objects {
+            assert!(store.exists(&object.hash).await.unwrap());
+            let entry = store.get(&object.hash).await.unwrap().unwrap();
+            assert_eq!(entry.obj_type, object.obj_type);
+        }
+        let mut hashes: Vec<SHA1> = repo.objects.iter().map(|object| object.hash).collect();
+        hashes.sort();
+        hashes.dedup();
+        assert_eq!(store.list().await.unwrap(), hashes);
+
+        let hello = blob("hello\n");
+        assert!(store.get(&hello.hash).await.unwrap().is_none());
+        assert!(store.put(&hello).await.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_layered_store() {
+        let dir = temp_dir();
+        let repo = write_pack(&dir);
+        let loose = dir.join("objects");
+        let store = LayeredStore::new(vec![
+            Box::new(PackStore::open(&dir).unwrap()),
+            Box::new(LooseStore::new(&loose)),
+        ]);
+        assert!(store.is_writable());
+
+        // written to the loose objects, behind the read only packs
+        let hello = blob("hello\n");
+        store.put(&hello).await.unwrap();
+        assert!(LooseStore::new(&loose).exists(&hello.hash).await.unwrap());
+        assert_eq!(
+            store.get(&hello.hash).await.unwrap().unwrap().data,
+            hello.data
+        );
+
+        // an object of the packs is not written again
+        let packed = &repo.objects[0];
+        let entry = store.get(&packed.hash).await.unwrap().unwrap();
+        store.put(&entry).await.unwrap();
+        assert!(!LooseStore::new(&loose).exists(&packed.hash).await.unwrap());
+
+        let list = store.list().await.unwrap();
+        assert!(list.contains(&hello.hash) && list.contains(&packed.hash));
+        assert!(list.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let read_only = LayeredStore::new(vec![Box::new(PackStore::open(&dir).unwrap())]);
+        assert!(!read_only.is_writable());
+        assert!(read_only.put(&blob("new\n")).await.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,456 @@
+//!
+//! Policy as code: a `.mega/policy.toml` file in any directory of the tree sets the rules of
+//! the pushes changing files under that directory. The files are read from the tree the push
+//! updates, not from the pushed one, so a push cannot loosen the rules it is checked against
+//! and changing them is reviewed in a merge request like any other change.
+//!
+//! ```toml
+//! # refs/heads/main and the release branches can be neither deleted nor force-pushed
+//! protected_branches = ["main", "release/*"]
+//! # post-merge hooks the server has to run on the merge
+//! required_checks = ["build-index"]
+//! # users allowed to change the files under the directory
+//! owners = ["alice", "bob"]
+//! # largest file in bytes
+//! max_file_size = 1048576
+//!
+//! # an annotated tag `services/foo/v1.2.3` when the version in Cargo.toml changes on main
+//! [auto_tag]
+//! manifest = "Cargo.toml"
+//! prefix = "services/foo/"
+//! ```
+//!
+//! The policies of all the directories above a file apply to it, the nearest `owners` decide
+//! and the smallest `max_file_size` wins. `auto_tag` only concerns its own directory, see
+//! [`crate::auto_tag`].
+//!
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+use common::errors::MegaError;
+use jupiter::context::Context;
+use venus::hash::SHA1;
+use venus::internal::object::tree::{Tree, TreeItemMode};
+
+/// The directory holding the policy file, and its name.
+pub const POLICY_DIR: &str = ".mega";
+pub const POLICY_FILE: &str = "policy.toml";
+
+/// Policy files kept parsed. A file never changes under its id, the cache is only cleared
+/// once it is full.
+const MAX_CACHED_POLICIES: usize = 1024;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// Branches that can be neither deleted nor force-pushed, as branch names or full ref
+    /// names, a trailing `*` matches any suffix.
+    pub protected_branches: Vec<String>,
+    /// Names of the post-merge hooks that have to be registered for a push to be accepted.
+    pub required_checks: Vec<String>,
+    /// Users allowed to change the files under the directory, anyone when empty.
+    pub owners: Vec<String>,
+    /// Largest file accepted under the directory in bytes, 0 means no limit.
+    pub max_file_size: usize,
+    /// Tags the versions of the directory.
+    pub auto_tag: Option<AutoTag>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AutoTag {
+    /// The file of the directory holding the version: the `version` of a TOML or JSON file
+    /// (`[package]` for a Cargo.toml), or the whole content of any other file.
+    pub manifest: String,
+    /// Prepended to `v<version>` to name the tags, the directory followed by `/` when unset.
+    pub prefix: Option<String>,
+    /// The branch whose updates are tagged, a branch name or a full ref name.
+    #[serde(default = "default_auto_tag_branch")]
+    pub branch: String,
+}
+
+fn default_auto_tag_branch() -> String {
+    String::from("main")
+}
+
+impl Policy {
+    pub fn parse(content: &[u8]) -> Result<Self, MegaError> {
+        let content = std::str::from_utf8(content)
+            .map_err(|_| MegaError::with_message("the policy is not UTF-8"))?;
+        toml::from_str(content).map_err(|e| MegaError::with_message(e.message()))
+    }
+
+    /// Whether the branch `ref_name` is protected.
+    pub fn protects(&self, ref_name: &str) -> bool {
+        self.protected_branches.iter().any(|pattern| {
+            let pattern = if pattern.starts_with("refs/") {
+                pattern.to_owned()
+            } else {
+                format!("refs/heads/{}", pattern)
+            };
+            match pattern.strip_suffix('*') {
+                Some(prefix) => ref_name.starts_with(prefix),
+                None => ref_name == pattern,
+            }
+        })
+    }
+}
+
+type CachedPolicy = Result<Arc<Policy>, String>;
+
+fn cache() -> &'static Mutex<HashMap<SHA1, CachedPolicy>> {
+    static CACHE: OnceLock<Mutex<HashMap<SHA1, CachedPolicy>>> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// The policy file `id` parsed earlier, an invalid one gives the error it failed with.
+pub fn cached_policy(id: &SHA1) -> Option<CachedPolicy> {
+    cache().lock().unwrap().get(id).cloned()
+}
+
+/// Parses the policy file `id` and keeps it for the next pushes.
+pub fn parse_and_cache(id: SHA1, content: &[u8]) -> CachedPolicy {
+    let policy = Policy::parse(content)
+        .map(Arc::new)
+        .map_err(|e| e.to_string());
+    let mut cache = cache().lock().unwrap();
+    if cache.len() >= MAX_CACHED_POLICIES {
+        cache.clear();
+    }
+    cache.insert(id, policy.clone());
+    policy
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Violation {
+    /// The push deletes or force-pushes a branch protected by the policy of `directory`.
+    ProtectedBranch { directory: String },
+    /// The pusher is not one of the owners of `path`.
+    NotOwner {
+        path: String,
+        directory: String,
+        owners: Vec<String>,
+    },
+    /// A pushed file is larger than the limit of `directory`, sizes in bytes.
+    FileTooLarge {
+        path: String,
+        directory: String,
+        size: usize,
+        limit: usize,
+    },
+    /// The policy of `directory` requires a check the server does not run.
+    MissingCheck { directory: String, check: String },
+    /// The policy file of `directory` cannot be read, nothing is accepted under it until
+    /// it is fixed.
+    InvalidPolicy { directory: String, error: String },
+}
+
+/// A file changed by a push, the size is set for the files of the pack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangedFile {
+    pub path: String,
+    pub size: Option<usize>,
+}
+
+/// The policies of the directories of a tree by path, the root being `""`.
+#[derive(Debug, Clone, Default)]
+pub struct PolicySet {
+    policies: BTreeMap<String, CachedPolicy>,
+}
+
+impl PolicySet {
+    pub fn insert(&mut self, directory: String, policy: CachedPolicy) {
+        self.policies.insert(directory, policy);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    /// Whether a valid policy of the set protects the branch `ref_name`.
+    pub fn protects(&self, ref_name: &str) -> bool {
+        self.policies.values().any(|policy| {
+            policy
+                .as_ref()
+                .is_ok_and(|policy| policy.protects(ref_name))
+        })
+    }
+
+    /// The valid policies applying to `path`, from the root down.
+    fn governing<'a>(&'a self, path: &str) -> impl Iterator<Item = (&'a str, &'a Policy)> {
+        directories(path).into_iter().filter_map(|directory| {
+            let (directory, policy) = self.policies.get_key_value(&directory)?;
+            Some((directory.as_str(), policy.as_deref().ok()?))
+        })
+    }
+
+    /// The nearest policy setting the owners of `path`, with its directory.
+    pub fn owners_of(&self, path: &str) -> Option<(&str, &Policy)> {
+        self.governing(path)
+            .filter(|(_, policy)| !policy.owners.is_empty())
+            .last()
+    }
+
+    /// The policy setting the smallest file size limit of `path`, with its directory.
+    pub fn size_limit_of(&self, path: &str) -> Option<(&str, &Policy)> {
+        self.governing(path)
+            .filter(|(_, policy)| policy.max_file_size > 0)
+            .min_by_key(|(_, policy)| policy.max_file_size)
+    }
+
+    /// The directories of the set and their policies, `Err` for an invalid one.
+    pub fn policies(&self) -> impl Iterator<Item = (&str, Result<&Policy, &str>)> {
+        self.policies.iter().map(|(directory, policy)| {
+            (
+                directory.as_str(),
+                policy.as_deref().map_err(String::as_str),
+            )
+        })
+    }
+
+    /// The rules broken by an update of `ref_name` changing `files`. `forced` is set for a
+    /// deletion or a non fast-forward update, `checks` are the names of the post-merge hooks.
+    pub fn check(
+        &self,
+        ref_name: &str,
+        forced: bool,
+        files: &[ChangedFile],
+        pusher: Option<&str>,
+        checks: &[&str],
+    ) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        for (directory, policy) in &self.policies {
+            let policy = match policy {
+                Ok(policy) => policy,
+                Err(error) => {
+                    violations.push(Violation::InvalidPolicy {
+                        directory: directory.clone(),
+                        error: error.clone(),
+                    });
+                    continue;
+                }
+            };
+            if forced && policy.protects(ref_name) {
+                violations.push(Violation::ProtectedBranch {
+                    directory: directory.clone(),
+                });
+            }
+            for check in &policy.required_checks {
+                if !checks.contains(&check.as_str()) {
+                    violations.push(Violation::MissingCheck {
+                        directory: directory.clone(),
+                        check: check.clone(),
+                    });
+                }
+            }
+        }
+
+        for file in files {
+            if let Some((directory, policy)) = self.owners_of(&file.path) {
+                if !pusher.is_some_and(|pusher| policy.owners.iter().any(|o| o == pusher)) {
+                    violations.push(Violation::NotOwner {
+                        path: file.path.clone(),
+                        directory: directory.to_owned(),
+                        owners: policy.owners.clone(),
+                    });
+                }
+            }
+            if let (Some(size), Some((directory, policy))) =
+                (file.size, self.size_limit_of(&file.path))
+            {
+                if size > policy.max_file_size {
+                    violations.push(Violation::FileTooLarge {
+                        path: file.path.clone(),
+                        directory: directory.to_owned(),
+                        size,
+                        limit: policy.max_file_size,
+                    });
+                }
+            }
+        }
+        violations
+    }
+}
+
+/// The policy files of `directories` in the saved tree `root`, the parents of a directory
+/// have to be listed.
+pub async fn load_policies(
+    context: &Context,
+    root: SHA1,
+    directories: &BTreeSet<String>,
+) -> Result<PolicySet, MegaError> {
+    let mut policies = PolicySet::default();
+    // a parent sorts before its subdirectories, its tree is loaded first
+    let mut trees: HashMap<String, Option<Tree>> = HashMap::new();
+    trees.insert(String::new(), load_saved_tree(context, &root).await?);
+    for directory in directories {
+        if !trees.contains_key(directory) {
+            let (parent, name) = directory.rsplit_once('/').unwrap_or(("", directory));
+            let id = subtree(trees.get(parent).and_then(Option::as_ref), name);
+            let tree = match id {
+                Some(id) => load_saved_tree(context, &id).await?,
+                None => None,
+            };
+            trees.insert(directory.clone(), tree);
+        }
+        let Some(policy_dir) = subtree(trees[directory].as_ref(), POLICY_DIR) else {
+            continue;
+        };
+        let policy_file = load_saved_tree(context, &policy_dir)
+            .await?
+            .and_then(|tree| {
+                tree.tree_items
+                    .into_iter()
+                    .find(|item| item.name == POLICY_FILE && item.mode != TreeItemMode::Tree)
+                    .map(|item| item.id)
+            });
+        if let Some(id) = policy_file {
+            let policy = match cached_policy(&id) {
+                Some(policy) => policy,
+                None => parse_and_cache(id, &load_policy_file(context, &id).await?),
+            };
+            policies.insert(directory.clone(), policy);
+        }
+    }
+    Ok(policies)
+}
+
+async fn load_saved_tree(context: &Context, id: &SHA1) -> Result<Option<Tree>, MegaError> {
+    let storage = context.services.mega_storage.clone();
+    let model = storage.get_mega_tree_by_sha(&id.to_plain_str()).await?;
+    Ok(model.map(Tree::from))
+}
+
+async fn load_policy_file(context: &Context, id: &SHA1) -> Result<Vec<u8>, MegaError> {
+    let storage = context.services.mega_storage.clone();
+    storage.get_blob_content(id).await?.ok_or_else(|| {
+        MegaError::with_message(&format!("policy file not found: {}", id.to_plain_str()))
+    })
+}
+
+/// The id of the subtree `name` of `tree`.
+fn subtree(tree: Option<&Tree>, name: &str) -> Option<SHA1> {
+    tree?
+        .tree_items
+        .iter()
+        .find(|item| item.name == name && item.mode == TreeItemMode::Tree)
+        .map(|item| item.id)
+}
+
+/// The directories above the file `path`, from the root down.
+pub fn directories(path: &str) -> Vec<String> {
+    let mut directories = vec![String::new()];
+    let mut components: Vec<&str> = path.split('/').collect();
+    components.pop();
+    for n in 1..=components.len() {
+        directories.push(components[..n].join("/"));
+    }
+    directories
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{directories, ChangedFile, Policy, PolicySet, Violation};
+
+    fn file(path: &str, size: Option<usize>) -> ChangedFile {
+        ChangedFile {
+            path: path.to_owned(),
+            size,
+        }
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        let policy = Policy::parse(
+            br#"
+protected_branches = ["main", "release/*"]
+owners = ["alice"]
+max_file_size = 1024
+"#,
+        )
+        .unwrap();
+        assert!(policy.protects("refs/heads/main"));
+        assert!(policy.protects("refs/heads/release/1.0"));
+        assert!(!policy.protects("refs/heads/feature"));
+        assert!(policy.required_checks.is_empty());
+        assert!(policy.auto_tag.is_none());
+        assert!(Policy::parse(b"owner = [\"alice\"]").is_err());
+
+        let policy = Policy::parse(b"[auto_tag]\nmanifest = \"Cargo.toml\"").unwrap();
+        let auto_tag = policy.auto_tag.unwrap();
+        assert_eq!(auto_tag.branch, "main");
+        assert_eq!(auto_tag.prefix, None);
+        assert!(Policy::parse(b"[auto_tag]\nprefix = \"v\"").is_err());
+        assert_eq!(directories("a/b/c.txt"), vec!["", "a", "a/b"]);
+    }
+
+    #[test]
+    fn test_check_policies() {
+        let mut policies = PolicySet::default();
+        let root = Policy {
+            protected_branches: vec![String::from("main")],
+            required_checks: vec![String::from("build-index")],
+            max_file_size: 100,
+            ..Default::default()
+        };
+        let third_party = Policy {
+            owners: vec![String::from("alice")],
+            max_file_size: 1000,
+            ..Default::default()
+        };
+        policies.insert(String::new(), Ok(Arc::new(root)));
+        policies.insert(String::from("third-party"), Ok(Arc::new(third_party)));
+        policies.insert(String::from("broken"), Err(String::from("expected `=`")));
+
+        let files = [
+            file("README", Some(10)),
+            file("third-party/lib/big.bin", Some(500)),
+            file("third-party/kept.txt", None),
+        ];
+        let violations = policies.check(
+            "refs/heads/main",
+            true,
+            &files,
+            Some("bob"),
+            &["build-index"],
+        );
+        assert_eq!(
+            violations,
+            vec![
+                Violation::ProtectedBranch {
+                    directory: String::new()
+                },
+                Violation::InvalidPolicy {
+                    directory: String::from("broken"),
+                    error: String::from("expected `=`")
+                },
+                Violation::NotOwner {
+                    path: String::from("third-party/lib/big.bin"),
+                    directory: String::from("third-party"),
+                    owners: vec![String::from("alice")]
+                },
+                Violation::FileTooLarge {
+                    path: String::from("third-party/lib/big.bin"),
+                    directory: String::new(),
+                    size: 500,
+                    limit: 100
+                },
+                Violation::NotOwner {
+                    path: String::from("third-party/kept.txt"),
+                    directory: String::from("third-party"),
+                    owners: vec![String::from("alice")]
+                },
+            ]
+        );
+
+        // a fast-forward of the owner, without the hook
+        let violations = policies.check("refs/heads/main", false, &files[2..], Some("alice"), &[]);
+        assert_eq!(violations.len(), 2);
+        assert!(matches!(violations[0], Violation::MissingCheck { .. }));
+    }
+}
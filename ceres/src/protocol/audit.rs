@@ -0,0 +1,242 @@
+//!
+//! Every clone, fetch and push served by the pack protocol is recorded in the
+//! `git_operation` table together with the client information, so that access to
+//! the monorepo can be audited afterwards.
+//!
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use chrono::{Duration, Utc};
+use serde::Serialize;
+
+use callisto::db_enums::OperationType;
+use callisto::git_operation;
+use common::utils::generate_id;
+use jupiter::context::Context;
+
+use crate::protocol::PackProtocol;
+
+impl PackProtocol {
+    /// Saves one git operation, failures are logged but never abort the transfer.
+    pub async fn record_operation(
+        &self,
+        operation: OperationType,
+        refs: Vec<String>,
+        bytes_transferred: usize,
+        started_at: Instant,
+    ) {
+        let model = git_operation::Model {
+            id: generate_id(),
+            repo_path: self.path.to_string_lossy().into_owned(),
+            operation,
+            user_name: self.client.user.clone(),
+            client_ip: self.client.ip.clone(),
+            user_agent: self.client.user_agent.clone(),
+            token_id: self.client.token_id.clone(),
+            refs: refs.join(","),
+            bytes_transferred: bytes_transferred as i64,
+            duration_ms: started_at.elapsed().as_millis() as i64,
+            created_at: Utc::now().naive_utc(),
+        };
+        let storage = self.context.services.audit_storage.clone();
+        if let Err(err) = storage.save_operation(model).await {
+            tracing::error!("failed to record {:?} on {:?}: {}", operation, self.path, err);
+        }
+    }
+}
+
+/// Deletes the operations older than `retention_days`, a value of 0 keeps everything.
+pub async fn purge_expired_operations(context: &Context, retention_days: i64) {
+    if retention_days <= 0 {
+        return;
+    }
+    let before = Utc::now().naive_utc() - Duration::try_days(retention_days).unwrap();
+    match context
+        .services
+        .audit_storage
+        .delete_operations_before(before)
+        .await
+    {
+        Ok(count) => tracing::info!("removed {} expired git operation records", count),
+        Err(err) => tracing::error!("failed to remove expired git operation records: {}", err),
+    }
+}
+
+/// Thresholds of the mass-clone detection, a limit of 0 is not checked.
+#[derive(Debug, Clone, Default)]
+pub struct CloneRule {
+    pub max_clones: usize,
+    pub max_bytes: i64,
+}
+
+/// A user token (or an anonymous client address) that exceeded a `CloneRule`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CloneAnomaly {
+    pub identity: String,
+    pub is_user: bool,
+    /// The token or SSH key the clones were made with, None when the user was not verified.
+    pub token_id: Option<String>,
+    pub clones: usize,
+    pub bytes: i64,
+    pub repos: Vec<String>,
+}
+
+/// Groups the clone operations by user and token, or by client ip for anonymous
+/// clients, and reports every group above one of the thresholds of `rule`. A user name
+/// without a token was not verified and can be changed at will, its clones are counted for
+/// the client ip as well.
+pub fn detect_clone_anomalies(
+    operations: &[git_operation::Model],
+    rule: &CloneRule,
+) -> Vec<CloneAnomaly> {
+    let mut groups: BTreeMap<(String, bool, Option<String>), CloneAnomaly> = BTreeMap::new();
+    for op in operations
+        .iter()
+        .filter(|op| op.operation == OperationType::Clone)
+    {
+        let verified = op.user_name.is_some() && op.token_id.is_some();
+        let user = op
+            .user_name
+            .as_ref()
+            .map(|user| (user.clone(), true, op.token_id.clone()));
+        let ip = op
+            .client_ip
+            .as_ref()
+            .filter(|_| !verified)
+            .map(|ip| (ip.clone(), false, None));
+        for key in user.into_iter().chain(ip) {
+            let group = groups.entry(key.clone()).or_insert_with(|| CloneAnomaly {
+                identity: key.0,
+                is_user: key.1,
+                token_id: key.2,
+                clones: 0,
+                bytes: 0,
+                repos: vec![],
+            });
+            group.clones += 1;
+            group.bytes += op.bytes_transferred;
+            if !group.repos.contains(&op.repo_path) {
+                group.repos.push(op.repo_path.clone());
+            }
+        }
+    }
+    groups
+        .into_values()
+        .filter(|g| {
+            (rule.max_clones > 0 && g.clones > rule.max_clones)
+                || (rule.max_bytes > 0 && g.bytes > rule.max_bytes)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use callisto::db_enums::OperationType;
+    use callisto::git_operation;
+
+    use super::{detect_clone_anomalies, CloneRule};
+
+    /// An operation of `user` verified with a token, or of an anonymous client.
+    fn operation(user: Option<&str>, ip: &str, op: OperationType, bytes: i64) -> git_operation::Model {
+        let token = user.map(|user| format!("token:{}", user));
+        token_operation(user, token.as_deref(), ip, op, bytes)
+    }
+
+    fn token_operation(
+        user: Option<&str>,
+        token: Option<&str>,
+        ip: &str,
+        op: OperationType,
+        bytes: i64,
+    ) -> git_operation::Model {
+        git_operation::Model {
+            id: 0,
+            repo_path: String::from("/projects/mega"),
+            operation: op,
+            user_name: user.map(String::from),
+            client_ip: Some(ip.to_owned()),
+            user_agent: None,
+            token_id: token.map(String::from),
+            refs: String::new(),
+            bytes_transferred: bytes,
+            duration_ms: 0,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn test_detect_clone_anomalies() {
+        let ops = vec![
+            operation(Some("alice"), "10.0.0.1", OperationType::Clone, 10),
+            operation(Some("alice"), "10.0.0.2", OperationType::Clone, 10),
+            operation(Some("alice"), "10.0.0.2", OperationType::Clone, 10),
+            operation(Some("bob"), "10.0.0.3", OperationType::Clone, 10),
+            operation(Some("bob"), "10.0.0.3", OperationType::Fetch, 10),
+            operation(Some("bob"), "10.0.0.3", OperationType::Fetch, 10),
+            operation(None, "192.168.1.1", OperationType::Clone, 500),
+        ];
+        let rule = CloneRule {
+            max_clones: 2,
+            max_bytes: 0,
+        };
+        let anomalies = detect_clone_anomalies(&ops, &rule);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].identity, "alice");
+        assert_eq!(anomalies[0].clones, 3);
+
+        let rule = CloneRule {
+            max_clones: 0,
+            max_bytes: 100,
+        };
+        let anomalies = detect_clone_anomalies(&ops, &rule);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].identity, "192.168.1.1");
+        assert!(!anomalies[0].is_user);
+    }
+
+    #[test]
+    fn test_detect_clone_anomalies_per_token() {
+        let ops = vec![
+            token_operation(Some("alice"), Some("token:a"), "10.0.0.1", OperationType::Clone, 10),
+            token_operation(Some("alice"), Some("token:a"), "10.0.0.1", OperationType::Clone, 10),
+            token_operation(Some("alice"), Some("token:a"), "10.0.0.1", OperationType::Clone, 10),
+            token_operation(Some("alice"), Some("key:b"), "10.0.0.2", OperationType::Clone, 10),
+        ];
+        let rule = CloneRule {
+            max_clones: 2,
+            max_bytes: 0,
+        };
+        let anomalies = detect_clone_anomalies(&ops, &rule);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].identity, "alice");
+        assert_eq!(anomalies[0].token_id.as_deref(), Some("token:a"));
+        assert_eq!(anomalies[0].clones, 3);
+    }
+
+    #[test]
+    fn test_detect_clone_anomalies_unverified() {
+        // a client renaming itself for every clone is caught by its address
+        let ops: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|user| token_operation(Some(user), None, "10.0.0.9", OperationType::Clone, 10))
+            .collect();
+        let rule = CloneRule {
+            max_clones: 2,
+            max_bytes: 0,
+        };
+        let anomalies = detect_clone_anomalies(&ops, &rule);
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].identity, "10.0.0.9");
+        assert!(!anomalies[0].is_user);
+        assert_eq!(anomalies[0].clones, 3);
+
+        // while the clones of verified users are not added up by address
+        let ops: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|user| operation(Some(user), "10.0.0.9", OperationType::Clone, 10))
+            .collect();
+        assert!(detect_clone_anomalies(&ops, &rule).is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+//!
+//! Check the connectivity of a push before its refs are updated: every commit, tree and blob
+//! the new tip of a ref reaches has to be in the pack or already in the repository, so that
+//! an update never leaves a ref pointing to an incomplete history.
+//!
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+
+use common::errors::MegaError;
+use common::utils::ZERO_ID;
+use venus::hash::SHA1;
+use venus::internal::object::tag::Tag;
+use venus::internal::object::tree::{Tree, TreeItemMode};
+use venus::internal::object::types::ObjectType;
+use venus::internal::object::ObjectTrait;
+use venus::internal::pack::reference::{CommandType, RefCommand};
+use venus::repo::Repo;
+
+use crate::protocol::quarantine::PushedObjects;
+use crate::protocol::report::PushRejection;
+use crate::protocol::PackProtocol;
+
+/// Nested tags followed to the object they point to before the update is refused.
+const MAX_TAG_DEPTH: usize = 16;
+
+impl PackProtocol {
+    /// Checks that the history of the new tip of `command` is complete. `pushed` holds the
+    /// commits and trees of the pack and `blob_sizes` its blobs, anything else is looked up
+    /// in the storage. A lookup that fails refuses the update rather than leaving it unchecked.
+    pub async fn check_connectivity(
+        &self,
+        repo: &Repo,
+        command: &RefCommand,
+        pushed: &PushedObjects,
+        blob_sizes: &HashMap<SHA1, usize>,
+    ) -> Option<PushRejection> {
+        if command.command_type == CommandType::Delete {
+            return None;
+        }
+        match self
+            .missing_objects(repo, &command.new_id, pushed, blob_sizes)
+            .await
+        {
+            Ok(missing) if !missing.is_empty() => Some(PushRejection::MissingObjects(missing)),
+            Ok(_) => None,
+            Err(err) => {
+                tracing::error!("connectivity check of {} failed: {}", command.ref_name, err);
+                Some(PushRejection::ConnectivityCheckFailed)
+            }
+        }
+    }
+
+    /// `command` with its ids peeled: an annotated tag, pushed or stored, is replaced by the
+    /// object it points to, so that the commit of a tag is checked as the one of a branch.
+    pub async fn peel_command(
+        &self,
+        command: &RefCommand,
+        pushed: &PushedObjects,
+    ) -> Result<RefCommand, MegaError> {
+        let mut peeled = command.clone();
+        peeled.old_id = self.peel(&command.old_id, pushed).await?;
+        peeled.new_id = self.peel(&command.new_id, pushed).await?;
+        Ok(peeled)
+    }
+
+    /// The object the tag `id` points to, through nested tags, or `id` itself when it is not
+    /// a tag.
+    async fn peel(&self, id: &str, pushed: &PushedObjects) -> Result<String, MegaError> {
+        let mut id = id.to_owned();
+        for _ in 0..MAX_TAG_DEPTH {
+            if id == ZERO_ID {
+                return Ok(id);
+            }
+            let Ok(hash) = SHA1::from_str(&id) else {
+                return Ok(id);
+            };
+            let target = match pushed.get(&hash) {
+                Some(entry) if entry.obj_type == ObjectType::Tag => {
+                    Tag::from_bytes(entry.data, hash)
+                        .map_err(|e| MegaError::with_message(&e.to_string()))?
+                        .object_hash
+                        .to_plain_str()
+                }
+                Some(_) => return Ok(id),
+                None => match self.context.services.mega_storage.get_tag_target(&id).await? {
+                    Some(target) => target,
+                    None => return Ok(id),
+                },
+            };
+            id = target;
+        }
+        Err(MegaError::with_message(&format!(
+            "more than {} nested tags",
+            MAX_TAG_DEPTH
+        )))
+    }
+
+    /// The ids of the objects reached from `tip` that are neither pushed nor stored, sorted.
+    /// The walk stops at the commits outside the pack, the repository holds their history.
+    async fn missing_objects(
+        &self,
+        repo: &Repo,
+        tip: &str,
+        pushed: &PushedObjects,
+        blob_sizes: &HashMap<SHA1, usize>,
+    ) -> Result<Vec<String>, MegaError> {
+        let mut missing = BTreeSet::new();
+        let Ok(tip) = SHA1::from_str(tip) else {
+            missing.insert(tip.to_owned());
+            return Ok(missing.into_iter().collect());
+        };
+
+        let mut roots = Vec::new();
+        let mut visited = HashSet::new();
+        let mut stack = vec![tip];
+        while let Some(id) = stack.pop() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let in_pack = pushed.contains_key(&id);
+            match self.load_commit(repo, &id.to_plain_str(), pushed).await {
+                Some(commit) if in_pack => {
+                    stack.extend(commit.parent_commit_ids);
+                    roots.push(commit.tree_id);
+                }
+                Some(_) => {}
+                None => {
+                    missing.insert(id.to_plain_str());
+                }
+            }
+        }
+
+        let (stored_trees, stored_blobs) =
+            referenced_objects(roots, pushed, blob_sizes, &mut missing);
+        let storage = self.context.services.mega_storage.clone();
+        let found: HashSet<String> = storage
+            .get_existing_trees(&stored_trees)
+            .await?
+            .into_iter()
+            .chain(storage.get_existing_blobs(&stored_blobs).await?)
+            .collect();
+        missing.extend(
+            stored_trees
+                .into_iter()
+                .chain(stored_blobs)
+                .filter(|id| !found.contains(id)),
+        );
+        Ok(missing.into_iter().collect())
+    }
+}
+
+/// Walks the pushed trees from `roots`, the trees and blobs they refer to outside the pack
+/// are returned to be looked up in the storage. A pushed tree that cannot be decoded is
+/// added to `missing`. The commits of submodules are not followed.
+fn referenced_objects(
+    roots: Vec<SHA1>,
+    pushed: &PushedObjects,
+    blob_sizes: &HashMap<SHA1, usize>,
+    missing: &mut BTreeSet<String>,
+) -> (Vec<String>, Vec<String>) {
+    let mut trees = BTreeSet::new();
+    let mut blobs = BTreeSet::new();
+    let mut visited = HashSet::new();
+    let mut stack = roots;
+    while let Some(id) = stack.pop() {
+        if !visited.insert(id) {
+            continue;
+        }
+        let Some(entry) = pushed.get(&id) else {
+            trees.insert(id.to_plain_str());
+            continue;
+        };
+        let Ok(tree) = Tree::from_bytes(entry.data, id) else {
+            missing.insert(id.to_plain_str());
+            continue;
+        };
+        for item in tree.tree_items {
+            match item.mode {
+                TreeItemMode::Tree => stack.push(item.id),
+                TreeItemMode::Commit => {}
+                _ if !blob_sizes.contains_key(&item.id) => {
+                    blobs.insert(item.id.to_plain_str());
+                }
+                _ => {}
+            }
+        }
+    }
+    (trees.into_iter().collect(), blobs.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+
+    use venus::hash::SHA1;
+    use venus::internal::object::blob::Blob;
+    use venus::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+    use venus::internal::object::types::ObjectType;
+    use venus::internal::pack::entry::Entry;
+
+    use super::referenced_objects;
+    use crate::protocol::quarantine::pushed_objects;
+
+    #[test]
+    fn test_referenced_objects() {
+        let pushed_blob = Blob::from_content("pushed");
+        let stored_blob = Blob::from_content("stored");
+        let stored_tree = Tree::from_tree_items(vec![TreeItem::new(
+            TreeItemMode::Blob,
+            stored_blob.id,
+            "c".into(),
+        )])
+        .unwrap();
+        let submodule = SHA1::new(&b"submodule".to_vec());
+        let root = Tree::from_tree_items(vec![
+            TreeItem::new(TreeItemMode::Blob, pushed_blob.id, "a.txt".into()),
+            TreeItem::new(TreeItemMode::Blob, stored_blob.id, "b.txt".into()),
+            TreeItem::new(TreeItemMode::Tree, stored_tree.id, "lib".into()),
+            TreeItem::new(TreeItemMode::Commit, submodule, "vendor".into()),
+        ])
+        .unwrap();
+        let entry = Entry {
+            obj_type: ObjectType::Tree,
+            data: root.to_data().unwrap(),
+            hash: root.id,
+            crc32: None,
+        };
+        let (_quarantine, pushed) = pushed_objects([entry]);
+        let blob_sizes = HashMap::from([(pushed_blob.id, 6)]);
+        let mut missing = BTreeSet::new();
+
+        let (trees, blobs) = referenced_objects(vec![root.id], &pushed, &blob_sizes, &mut missing);
+        assert_eq!(trees, vec![stored_tree.id.to_plain_str()]);
+        assert_eq!(blobs, vec![stored_blob.id.to_plain_str()]);
+        assert!(missing.is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+//!
+//! Enforce Git LFS locks at push time: every path touched between the old and the new
+//! commit of a ref update is checked against the locks of that ref, and the update is
+//! rejected if one of them is held by someone other than the pusher.
+//!
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+use venus::internal::object::ObjectTrait;
+use venus::internal::pack::reference::{CommandType, RefCommand};
+use venus::repo::Repo;
+
+use crate::lfs::handler;
+use crate::protocol::quarantine::PushedObjects;
+use crate::protocol::report::PushRejection;
+use crate::protocol::PackProtocol;
+
+impl PackProtocol {
+    /// Checks whether the push modifies a path of `command` locked by another user.
+    ///
+    /// `pushed` holds the commits and trees received in the pack, anything else is
+    /// looked up in the storage. The pusher is the user the client authenticated as. A new
+    /// commit found in neither is refused, as its paths cannot be checked.
+    pub async fn check_ref_locks(
+        &self,
+        repo: &Repo,
+        command: &RefCommand,
+        pushed: &PushedObjects,
+    ) -> Option<PushRejection> {
+        if command.command_type == CommandType::Delete {
+            return None;
+        }
+        let Some(new_commit) = self.load_commit(repo, &command.new_id, pushed).await else {
+            tracing::error!(
+                "commit {} of {} not found for the lock check",
+                command.new_id,
+                command.ref_name
+            );
+            return Some(PushRejection::LockCheckFailed);
+        };
+        let old_tree = match command.command_type {
+            CommandType::Update => self
+                .load_commit(repo, &command.old_id, pushed)
+                .await
+                .map(|c| c.tree_id),
+            _ => None,
+        };
+        let paths = self
+            .changed_paths(old_tree, Some(new_commit.tree_id), pushed)
+            .await;
+
+        let locks = handler::lfs_locks_held_by_others(
+            self.context.services.lfs_storage.clone(),
+            &command.ref_name,
+            &paths,
+            self.client.user.as_deref(),
+        )
+        .await;
+        match locks {
+            Ok(locks) if !locks.is_empty() => Some(PushRejection::LockedPaths(locks)),
+            Ok(_) => None,
+            Err(err) => {
+                tracing::error!("lock lookup for {} failed: {}", command.ref_name, err);
+                Some(PushRejection::LockCheckFailed)
+            }
+        }
+    }
+
+    pub(crate) async fn load_commit(
+        &self,
+        repo: &Repo,
+        id: &str,
+        pushed: &PushedObjects,
+    ) -> Option<Commit> {
+        let hash = SHA1::from_str(id).ok()?;
+        if let Some(entry) = pushed.get(&hash) {
+            return Commit::from_bytes(entry.data, hash).ok();
+        }
+        let storage = self.context.services.mega_storage.clone();
+        match storage.get_commit_by_hash(id, repo).await {
+            Ok(Some(model)) => Some(model.into()),
+            _ => None,
+        }
+    }
+
+    async fn load_tree(&self, hash: SHA1, pushed: &PushedObjects) -> Option<Tree> {
+        if let Some(entry) = pushed.get(&hash) {
+            return Tree::from_bytes(entry.data, hash).ok();
+        }
+        let storage = self.context.services.mega_storage.clone();
+        match storage.get_mega_tree_by_sha(&hash.to_plain_str()).await {
+            Ok(Some(model)) => Some(model.into()),
+            _ => None,
+        }
+    }
+
+    /// Collects the paths of all blobs added, removed or modified between two trees.
+    /// A tree that can not be found is treated as empty, so its counterpart is reported in full.
+    pub async fn changed_paths(
+        &self,
+        old: Option<SHA1>,
+        new: Option<SHA1>,
+        pushed: &PushedObjects,
+    ) -> Vec<String> {
+        self.changed_files(old, new, pushed)
+            .await
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    /// Like [`Self::changed_paths`], along with the id of the new blob of each path, unset
+    /// when the path is no longer a file.
+    pub async fn changed_files(
+        &self,
+        old: Option<SHA1>,
+        new: Option<SHA1>,
+        pushed: &PushedObjects,
+    ) -> Vec<(String, Option<SHA1>)> {
+        let mut paths = Vec::new();
+        let mut stack = vec![(String::new(), old, new)];
+        while let Some((prefix, old, new)) = stack.pop() {
+            if old == new {
+                continue;
+            }
+            let old_items = match old {
+                Some(hash) => self.load_tree(hash, pushed).await,
+                None => None,
+            };
+            let new_items = match new {
+                Some(hash) => self.load_tree(hash, pushed).await,
+                None => None,
+            };
+            let mut items: BTreeMap<String, (Option<_>, Option<_>)> = BTreeMap::new();
+            for item in old_items.into_iter().flat_map(|t| t.tree_items) {
+                let name = item.name.clone();
+                items.entry(name).or_default().0 = Some(item);
+            }
+            for item in new_items.into_iter().flat_map(|t| t.tree_items) {
+                let name = item.name.clone();
+                items.entry(name).or_default().1 = Some(item);
+            }
+
+            for (name, (old_item, new_item)) in items {
+                let path = if prefix.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", prefix, name)
+                };
+                if let (Some(o), Some(n)) = (&old_item, &new_item) {
+                    if o.id == n.id && o.mode == n.mode {
+                        continue;
+                    }
+                }
+                let subtree = |item: &Option<TreeItem>| {
+                    item.as_ref()
+                        .filter(|i| i.mode == TreeItemMode::Tree)
+                        .map(|i| i.id)
+                };
+                let (old_tree, new_tree) = (subtree(&old_item), subtree(&new_item));
+                if old_tree.is_some() || new_tree.is_some() {
+                    stack.push((path.clone(), old_tree, new_tree));
+                }
+                let is_file = |item: &Option<TreeItem>| {
+                    item.as_ref().is_some_and(|i| i.mode != TreeItemMode::Tree)
+                };
+                if is_file(&old_item) || is_file(&new_item) {
+                    let blob = new_item
+                        .filter(|i| i.mode != TreeItemMode::Tree)
+                        .map(|i| i.id);
+                    paths.push((path, blob));
+                }
+            }
+        }
+        paths.sort();
+        paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sea_orm::{DatabaseBackend, MockDatabase};
+
+    use callisto::{lfs_locks, mega_commit};
+    use jupiter::storage::lfs_storage::LfsStorage;
+    use jupiter::storage::mega_storage::MegaStorage;
+    use venus::hash::SHA1;
+    use venus::internal::object::blob::Blob;
+    use venus::internal::object::commit::Commit;
+    use venus::internal::object::signature::{Signature, SignatureType};
+    use venus::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+    use venus::internal::object::types::ObjectType;
+    use venus::internal::pack::entry::Entry;
+    use venus::internal::pack::reference::RefCommand;
+    use venus::repo::Repo;
+
+    use crate::lfs::lfs_structs::{Lock, User};
+    use crate::protocol::quarantine::pushed_objects;
+    use crate::protocol::report::PushRejection;
+    use crate::protocol::PackProtocol;
+
+    fn tree_entry(tree: &Tree) -> Entry {
+        Entry {
+            obj_type: ObjectType::Tree,
+            data: tree.to_data().unwrap(),
+            hash: tree.id,
+            crc32: None,
+        }
+    }
+
+    fn commit_entry(tree_id: SHA1, parents: Vec<SHA1>) -> (SHA1, Entry) {
+        // the committer is not who the push is checked for
+        let signature = Signature {
+            signature_type: SignatureType::Committer,
+            name: String::from("alice"),
+            email: String::from("alice@example.com"),
+            timestamp: 1000,
+            timezone: String::from("+0000"),
+        };
+        let commit = Commit {
+            id: SHA1::default(),
+            tree_id,
+            parent_commit_ids: parents,
+            author: Signature {
+                signature_type: SignatureType::Author,
+                ..signature.clone()
+            },
+            committer: signature,
+            message: String::from("\nupdate"),
+        };
+        let data = commit.to_data().unwrap();
+        let hash = SHA1::from_type_and_data(ObjectType::Commit, &data);
+        let entry = Entry {
+            obj_type: ObjectType::Commit,
+            data,
+            hash,
+            crc32: None,
+        };
+        (hash, entry)
+    }
+
+    /// A protocol whose locks of `refs/heads/main` are `locks`, pushed to by `user`.
+    fn locked(locks: &[Lock], user: Option<&str>) -> PackProtocol {
+        let model = lfs_locks::Model {
+            id: String::from("refs/heads/main"),
+            data: serde_json::to_string(locks).unwrap(),
+        };
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_query_results([vec![model]])
+            .into_connection();
+        let mut protocol = PackProtocol::mock();
+        let mut services = (*protocol.context.services).clone();
+        services.lfs_storage = Arc::new(LfsStorage {
+            connection: Arc::new(db),
+        });
+        protocol.context.services = Arc::new(services);
+        protocol.client.user = user.map(str::to_owned);
+        protocol
+    }
+
+    #[tokio::test]
+    async fn test_check_ref_locks() {
+        let a = Blob::from_content("a");
+        let b = Blob::from_content("b");
+        let old_root = Tree::from_tree_items(vec![TreeItem::new(
+            TreeItemMode::Blob,
+            a.id,
+            "x.psd".into(),
+        )])
+        .unwrap();
+        let new_root = Tree::from_tree_items(vec![TreeItem::new(
+            TreeItemMode::Blob,
+            b.id,
+            "x.psd".into(),
+        )])
+        .unwrap();
+        let (old_id, old_commit) = commit_entry(old_root.id, vec![]);
+        let (new_id, new_commit) = commit_entry(new_root.id, vec![old_id]);
+        let (_quarantine, pushed) = pushed_objects(
+            [&old_root, &new_root]
+                .into_iter()
+                .map(tree_entry)
+                .chain([old_commit, new_commit]),
+        );
+        let command = RefCommand::new(
+            old_id.to_plain_str(),
+            new_id.to_plain_str(),
+            String::from("refs/heads/main"),
+        );
+        let lock = Lock {
+            id: String::from("1"),
+            path: String::from("x.psd"),
+            locked_at: String::new(),
+            owner: Some(User {
+                name: String::from("bob"),
+            }),
+        };
+
+        // the committer name is alice's, the lock of bob still refuses her push
+        let protocol = locked(std::slice::from_ref(&lock), Some("alice"));
+        let rejection = protocol
+            .check_ref_locks(&Repo::empty(), &command, &pushed)
+            .await;
+        assert_eq!(
+            rejection,
+            Some(PushRejection::LockedPaths(vec![lock.clone()]))
+        );
+
+        let protocol = locked(std::slice::from_ref(&lock), None);
+        let rejection = protocol
+            .check_ref_locks(&Repo::empty(), &command, &pushed)
+            .await;
+        assert_eq!(
+            rejection,
+            Some(PushRejection::LockedPaths(vec![lock.clone()]))
+        );
+
+        let protocol = locked(std::slice::from_ref(&lock), Some("bob"));
+        let rejection = protocol
+            .check_ref_locks(&Repo::empty(), &command, &pushed)
+            .await;
+        assert_eq!(rejection, None);
+
+        // a new commit that cannot be loaded is not let through unchecked
+        let (_quarantine, pushed) = pushed_objects(Vec::new());
+        let mut protocol = locked(&[lock], Some("alice"));
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_query_results([Vec::<mega_commit::Model>::new()]);
+        let mut services = (*protocol.context.services).clone();
+        services.mega_storage = Arc::new(MegaStorage {
+            connection: Arc::new(db.into_connection()),
+            ..MegaStorage::mock()
+        });
+        protocol.context.services = Arc::new(services);
+        let rejection = protocol
+            .check_ref_locks(&Repo::empty(), &command, &pushed)
+            .await;
+        assert_eq!(rejection, Some(PushRejection::LockCheckFailed));
+    }
+
+    #[tokio::test]
+    async fn test_changed_paths() {
+        let a = Blob::from_content("a");
+        let b = Blob::from_content("b");
+        let c = Blob::from_content("c");
+
+        let old_sub = Tree::from_tree_items(vec![TreeItem::new(
+            TreeItemMode::Blob,
+            a.id,
+            "x.psd".into(),
+        )])
+        .unwrap();
+        let new_sub = Tree::from_tree_items(vec![TreeItem::new(
+            TreeItemMode::Blob,
+            b.id,
+            "x.psd".into(),
+        )])
+        .unwrap();
+        let old_root = Tree::from_tree_items(vec![
+            TreeItem::new(TreeItemMode::Blob, a.id, "README".into()),
+            TreeItem::new(TreeItemMode::Tree, old_sub.id, "assets".into()),
+        ])
+        .unwrap();
+        let new_root = Tree::from_tree_items(vec![
+            TreeItem::new(TreeItemMode::Blob, a.id, "README".into()),
+            TreeItem::new(TreeItemMode::Tree, new_sub.id, "assets".into()),
+            TreeItem::new(TreeItemMode::Blob, c.id, "new.bin".into()),
+        ])
+        .unwrap();
+
+        let (_quarantine, pushed) = pushed_objects(
+            [&old_sub, &new_sub, &old_root, &new_root]
+                .into_iter()
+                .map(tree_entry),
+        );
+
+        let mock = PackProtocol::mock();
+        let paths = mock
+            .changed_paths(Some(old_root.id), Some(new_root.id), &pushed)
+            .await;
+        assert_eq!(
+            paths,
+            vec!["assets/x.psd".to_string(), "new.bin".to_string()]
+        );
+
+        let paths = mock.changed_paths(None, Some(new_root.id), &pushed).await;
+        assert_eq!(
+            paths,
+            vec![
+                "README".to_string(),
+                "assets/x.psd".to_string(),
+                "new.bin".to_string()
+            ]
+        );
+    }
+}
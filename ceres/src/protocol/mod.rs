@@ -3,14 +3,31 @@
 //!
 //!
 //!
-use std::{path::PathBuf, str::FromStr};
+use std::{collections::HashSet, path::PathBuf, str::FromStr};
 
-use common::{errors::MegaError, utils::ZERO_ID};
+use common::{errors::MegaError, i18n::Locale};
 use jupiter::context::Context;
 
+use mercury::internal::pack::filter::FilterSpec;
+use venus::hash::{HashKind, SHA1};
 use venus::internal::pack::reference::RefCommand;
 
+use snapshot::RefSnapshot;
+use v2::ProtocolVersion;
+
+pub mod audit;
+mod connectivity;
+mod lock;
 pub mod pack;
+pub mod pack_objects;
+mod policy;
+pub mod quarantine;
+pub mod report;
+pub mod shallow;
+pub mod slow_op;
+pub mod snapshot;
+pub mod throttle;
+pub mod v2;
 
 #[derive(Clone)]
 pub struct PackProtocol {
@@ -21,6 +38,37 @@ pub struct PackProtocol {
     // only needed in ssh protocal
     pub service_type: ServiceType,
     pub context: Context,
+    pub client: ClientInfo,
+    /// Options sent by `git push -o`, once the client accepted the `push-options` capability.
+    pub push_options: Vec<String>,
+    /// The refs as read when the negotiation started, the advertisement and the pack both
+    /// follow it even when a push lands in between.
+    pub snapshot: Option<RefSnapshot>,
+    /// The shallow commits of the client once it has the pack being negotiated, the pack
+    /// does not go past them. Over HTTP every request lists the client's shallow commits again.
+    pub shallow: HashSet<SHA1>,
+    /// The shallow commits the client has, its history stops at them.
+    pub client_shallow: HashSet<SHA1>,
+    /// The objects left out of the pack of a partial clone or fetch, sent in the `filter` line.
+    pub filter: Option<FilterSpec>,
+    /// The object format of the client, the ids it is told and sends are named in it, see
+    /// [`crate::interop`].
+    pub object_format: HashKind,
+    /// The protocol version asked for by the client, see [`v2`].
+    pub version: ProtocolVersion,
+}
+
+/// Who is on the other side of the connection, recorded with every git operation.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    /// The verified user, None for an anonymous client.
+    pub user: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Fingerprint of the access token or SSH key `user` was verified with.
+    pub token_id: Option<String>,
+    /// Language of the messages sent to the client.
+    pub locale: Locale,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, Default)]
@@ -76,6 +124,10 @@ pub enum Capability {
     OfsDelta,
     DeepenSince,
     DeepenNot,
+    DeepenRelative,
+    PushOptions,
+    Filter,
+    Atomic,
 }
 
 impl FromStr for Capability {
@@ -93,6 +145,10 @@ impl FromStr for Capability {
             "no-done" => Ok(Capability::NoDone),
             "deepen-since" => Ok(Capability::DeepenSince),
             "deepen-not" => Ok(Capability::DeepenNot),
+            "deepen-relative" => Ok(Capability::DeepenRelative),
+            "push-options" => Ok(Capability::PushOptions),
+            "filter" => Ok(Capability::Filter),
+            "atomic" => Ok(Capability::Atomic),
             _ => Err(()),
         }
     }
@@ -129,6 +185,14 @@ impl PackProtocol {
             command_list: Vec::new(),
             service_type: ServiceType::ReceivePack,
             context,
+            client: ClientInfo::default(),
+            push_options: Vec::new(),
+            snapshot: None,
+            shallow: HashSet::new(),
+            client_shallow: HashSet::new(),
+            filter: None,
+            object_format: HashKind::default(),
+            version: ProtocolVersion::default(),
         }
     }
 
@@ -141,6 +205,14 @@ impl PackProtocol {
             command_list: Vec::new(),
             service_type: ServiceType::ReceivePack,
             context,
+            client: ClientInfo::default(),
+            push_options: Vec::new(),
+            snapshot: None,
+            shallow: HashSet::new(),
+            client_shallow: HashSet::new(),
+            filter: None,
+            object_format: HashKind::default(),
+            version: ProtocolVersion::default(),
         }
     }
 }
@@ -3,20 +3,50 @@
 //!
 //!
 
-use std::io::Cursor;
-use std::path::{Path, PathBuf};
-use std::sync::mpsc;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Cursor};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chrono::Utc;
+use futures::{Stream, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
+use tracing::Instrument;
 
-use callisto::db_enums::RefType;
-use callisto::refs;
+use callisto::db_enums::{OperationType, RefType};
+use callisto::mega_commit;
+use common::errors::MegaError;
+use common::utils::generate_id;
+use jupiter::events::RefUpdateEvent;
+use jupiter::hooks::{Merge, Push};
+use jupiter::settings::Settings;
+use mercury::internal::pack::filter::FilterSpec;
+use mercury::internal::pack::lanes::{Lane, Lanes};
 use mercury::internal::pack::Pack;
-use venus::errors::GitError;
+use venus::hash::{HashKind, SHA1};
+use venus::internal::object::commit::Commit;
+use venus::internal::object::tree::{Tree, TreeItemMode};
+use venus::internal::object::types::ObjectType;
+use venus::internal::object::ObjectTrait;
+use venus::internal::pack::entry::Entry;
+use venus::internal::pack::reference::CommandType;
 use venus::repo::Repo;
 
-use crate::protocol::ZERO_ID;
+use crate::interop;
+use crate::merge_message::{self, MergeMessage};
+use crate::object_store::{DbStore, ObjectStore};
+use crate::protocol::pack_objects::{PackObjects, PackRequest};
+use crate::protocol::quarantine::{PushedObjects, Quarantine};
+use crate::protocol::report::PushRejection;
+use crate::protocol::shallow::ShallowRequest;
+use crate::protocol::slow_op::SlowOp;
+use crate::protocol::snapshot::{ReadLease, RefSnapshot};
 use crate::protocol::{Capability, PackProtocol, Protocol, RefCommand, ServiceType, SideBind};
 
 use venus::mr::MergeRequest;
@@ -29,17 +59,31 @@ const NUL: char = '\0';
 
 pub const PKT_LINE_END_MARKER: &[u8; 4] = b"0000";
 
+/// Commits read at once when the wants are looked for in the history of the refs.
+const WANT_WALK_BATCH: usize = 1000;
+
+/// The signature starting a pack, where the commands of a push end.
+const PACK_SIGNATURE: [u8; 4] = *b"PACK";
+
 // The atomic, report-status, report-status-v2, delete-refs, quiet,
 // and push-cert capabilities are sent and recognized by the receive-pack (push to server) process.
-const RECEIVE_CAP_LIST: &str = "report-status report-status-v2 delete-refs quiet atomic ";
+const RECEIVE_CAP_LIST: &str =
+    "report-status report-status-v2 delete-refs quiet atomic push-options ";
+
+/// Push option of `git push -o dry-run`: the push is validated and reported as usual, but
+/// neither the objects nor the refs are saved.
+const DRY_RUN_OPTION: &str = "dry-run";
 
 // The ofs-delta and side-band-64k capabilities are sent and recognized by both upload-pack and receive-pack protocols.
 // The agent and session-id capabilities may optionally be sent in both protocols.
+// The object-format is sha1, the only one stored, see `venus::hash::HashKind`, or sha256 for
+// a fetch of a client asking for it, see `crate::interop`. The clients of repositories of
+// another format refuse to push or fetch.
 const CAP_LIST: &str = "side-band-64k ofs-delta agent=mega/0.0.1";
 
 // All other capabilities are only recognized by the upload-pack (fetch from server) process.
 const UPLOAD_CAP_LIST: &str =
-    "shallow deepen-since deepen-not deepen-relative multi_ack_detailed no-done include-tag ";
+    "shallow deepen-since deepen-not deepen-relative multi_ack_detailed no-done include-tag filter ";
 
 impl PackProtocol {
     /// # Retrieves the information about Git references (refs) for the specified service type.
@@ -66,19 +110,53 @@ impl PackProtocol {
     /// Tracing information is logged regarding the response packet line stream.
     ///
     /// Finally, the constructed packet line stream is returned.
-    pub async fn git_info_refs(&self) -> BytesMut {
+    pub async fn git_info_refs(&mut self) -> BytesMut {
         let service_type = self.service_type;
         let repo = self.convert_path_to_repo().await;
         // The stream MUST include capability declarations behind a NUL on the first ref.
-        let (head_hash, git_refs) = self.repo_head_object_id(repo).await;
-        let name = if head_hash == ZERO_ID {
+        let snapshot = self.ref_snapshot(&repo).await.unwrap();
+        let mut head_hash = snapshot.head();
+        let mut git_refs = snapshot.refs().to_vec();
+        if service_type == ServiceType::UploadPack && self.object_format != HashKind::Sha1 {
+            // the refs whose tip has no name in the format of the client are left out
+            let mut ids: Vec<String> = git_refs.iter().map(|r| r.ref_git_id.clone()).collect();
+            ids.push(head_hash.clone());
+            let names = interop::to_client_ids(&self.context, self.object_format, &ids)
+                .await
+                .unwrap_or_default();
+            git_refs.retain_mut(|r| match names.get(&r.ref_git_id) {
+                Some(name) => {
+                    r.ref_git_id = name.clone();
+                    true
+                }
+                None => false,
+            });
+            head_hash = names
+                .get(&head_hash)
+                .cloned()
+                .unwrap_or_else(|| "0".repeat(self.object_format.hex_len()));
+        }
+        let name = if head_hash.bytes().all(|b| b == b'0') {
             "capabilities^{}"
         } else {
             "HEAD"
         };
         let cap_list = match service_type {
-            ServiceType::UploadPack => format!("{}{}", UPLOAD_CAP_LIST, CAP_LIST),
-            ServiceType::ReceivePack => format!("{}{}", RECEIVE_CAP_LIST, CAP_LIST),
+            ServiceType::UploadPack => format!(
+                "{}{}{} object-format={}",
+                UPLOAD_CAP_LIST,
+                want_capabilities(&self.context.settings.current()),
+                CAP_LIST,
+                self.object_format
+            ),
+            ServiceType::ReceivePack => {
+                format!(
+                    "{}{} object-format={}",
+                    RECEIVE_CAP_LIST,
+                    CAP_LIST,
+                    HashKind::Sha1
+                )
+            }
         };
         let pkt_line = format!("{}{}{}{}{}{}", head_hash, SP, name, NUL, cap_list, LF);
         let mut ref_list = vec![pkt_line];
@@ -92,15 +170,19 @@ impl PackProtocol {
         pkt_line_stream
     }
 
+    #[tracing::instrument(skip_all, fields(path = %self.path.display()))]
     pub async fn git_upload_pack(
         &mut self,
         upload_request: &mut Bytes,
     ) -> Result<(Vec<u8>, BytesMut)> {
+        let started_at = Instant::now();
+        let _slow_op = self.watch_slow_op("upload-pack");
         let repo = self.convert_path_to_repo().await;
 
         let mut want: Vec<String> = Vec::new();
         let mut have: Vec<String> = Vec::new();
         let mut last_common_commit = String::new();
+        let mut shallow_request = ShallowRequest::default();
 
         let mut read_first_line = false;
         loop {
@@ -118,13 +200,37 @@ impl PackProtocol {
 
             match commands {
                 b"want" => {
-                    want.push(String::from_utf8(dst[5..45].to_vec()).unwrap());
+                    want.push(object_id(&dst));
                 }
                 b"have" => {
-                    have.push(String::from_utf8(dst[5..45].to_vec()).unwrap());
+                    have.push(object_id(&dst));
                 }
                 b"done" => break,
                 other => {
+                    let line = String::from_utf8_lossy(&dst);
+                    if let Some(spec) = line.trim_end().strip_prefix("filter ") {
+                        match spec.parse::<FilterSpec>() {
+                            Ok(filter) => self.filter = Some(filter),
+                            Err(msg) => {
+                                let mut buf = BytesMut::new();
+                                add_pkt_line_string(
+                                    &mut buf,
+                                    format!("ERR upload-pack: {}\n", msg),
+                                );
+                                return Ok((vec![], buf));
+                            }
+                        }
+                        continue;
+                    }
+                    match shallow_request.parse_line(&line) {
+                        Ok(true) => continue,
+                        Ok(false) => {}
+                        Err(msg) => {
+                            let mut buf = BytesMut::new();
+                            add_pkt_line_string(&mut buf, format!("ERR upload-pack: {}\n", msg));
+                            return Ok((vec![], buf));
+                        }
+                    }
                     tracing::error!(
                         "unsupported command: {:?}",
                         String::from_utf8(other.to_vec())
@@ -133,11 +239,40 @@ impl PackProtocol {
                 }
             };
             if !read_first_line {
-                self.parse_capabilities(&String::from_utf8(dst[46..].to_vec()).unwrap());
+                let caps = dst.get(6 + object_id(&dst).len()..).unwrap_or_default();
+                self.parse_capabilities(std::str::from_utf8(caps).unwrap());
                 read_first_line = true;
             }
         }
 
+        // A client of the SHA-256 format names the objects in it, they are looked up back.
+        let mut client_ids: HashMap<String, String> = HashMap::new();
+        if want
+            .first()
+            .is_some_and(|id| id.len() == HashKind::Sha256.hex_len())
+        {
+            self.object_format = HashKind::Sha256;
+            let names: Vec<String> = want.iter().chain(&have).cloned().collect();
+            let stored =
+                match interop::to_stored_ids(&self.context, self.object_format, &names).await {
+                    Ok(stored) => stored,
+                    Err(err) => anyhow::bail!("failed to look up the object ids: {}", err),
+                };
+            if let Some(id) = want.iter().find(|id| !stored.contains_key(*id)) {
+                let mut buf = BytesMut::new();
+                add_pkt_line_string(&mut buf, format!("ERR upload-pack: not our ref {}\n", id));
+                return Ok((vec![], buf));
+            }
+            want = want.iter().map(|id| stored[id].clone()).collect();
+            // an object the server does not know is not a common one
+            have = have
+                .iter()
+                .filter_map(|id| stored.get(id).cloned())
+                .collect();
+            client_ids = stored.into_iter().map(|(name, id)| (id, name)).collect();
+        }
+        let client_id = |id: &str| client_ids.get(id).cloned().unwrap_or_else(|| id.to_owned());
+
         tracing::info!(
             "want commands: {:?}\n have commans: {:?}\n caps:{:?}",
             want,
@@ -145,11 +280,72 @@ impl PackProtocol {
             self.capabilities
         );
 
-        let mut pack_data = vec![];
+        // The wants are checked against the pinned refs and leased so that archiving a merge
+        // request cannot remove them while the pack is generated.
+        let snapshot = match self.ref_snapshot(&repo).await {
+            Ok(snapshot) => snapshot.clone(),
+            Err(err) => anyhow::bail!("failed to read the refs: {}", err),
+        };
+        match self.refused_want(&repo, &snapshot, &want).await {
+            Ok(None) => {}
+            Ok(Some(id)) => {
+                let mut buf = BytesMut::new();
+                add_pkt_line_string(&mut buf, format!("ERR upload-pack: not our ref {}\n", id));
+                return Ok((vec![], buf));
+            }
+            Err(err) => anyhow::bail!("failed to check the wants: {}", err),
+        }
+        // The shallow update comes before the acknowledgements.
         let mut buf = BytesMut::new();
+        if self.capabilities.contains(&Capability::DeepenRelative) {
+            shallow_request.relative = true;
+        }
+        if shallow_request.is_deepen() {
+            let update = match self
+                .shallow_update(&repo, &snapshot, &want, &shallow_request)
+                .await
+            {
+                Ok(update) => update,
+                Err(msg) => {
+                    add_pkt_line_string(&mut buf, format!("ERR upload-pack: {}\n", msg));
+                    return Ok((vec![], buf));
+                }
+            };
+            for id in &update.shallow {
+                add_pkt_line_string(&mut buf, format!("shallow {}\n", id.to_plain_str()));
+            }
+            for id in &update.unshallow {
+                add_pkt_line_string(&mut buf, format!("unshallow {}\n", id.to_plain_str()));
+            }
+            buf.put(&PKT_LINE_END_MARKER[..]);
+            self.shallow = update.apply(&shallow_request.shallows);
+        } else {
+            self.shallow = shallow_request.shallows.iter().copied().collect();
+        }
+        self.client_shallow = shallow_request.shallows.iter().copied().collect();
+
+        let lease = match ReadLease::acquire(&self.context, &repo, &want).await {
+            Ok(lease) => Some(lease),
+            Err(err) => {
+                tracing::warn!("failed to lease the wanted commits: {}", err);
+                None
+            }
+        };
+
+        let mut pack_data = vec![];
+        let wanted_refs = want.clone();
+        let operation = if have.is_empty() {
+            OperationType::Clone
+        } else {
+            OperationType::Fetch
+        };
 
         if have.is_empty() {
-            pack_data = self.get_full_pack_data(&self.path).await.unwrap();
+            pack_data = self
+                .get_incremental_pack_data(want, Vec::new())
+                .instrument(tracing::info_span!("pack"))
+                .await
+                .map_err(|err| anyhow::anyhow!("failed to pack the objects: {}", err))?;
             add_pkt_line_string(&mut buf, String::from("NAK\n"));
         } else {
             if self.capabilities.contains(&Capability::MultiAckDetailed) {
@@ -166,95 +362,705 @@ impl PackProtocol {
                         .unwrap()
                         .is_some()
                     {
-                        add_pkt_line_string(&mut buf, format!("ACK {} common\n", hash));
+                        add_pkt_line_string(&mut buf, format!("ACK {} common\n", client_id(hash)));
                         if last_common_commit.is_empty() {
                             last_common_commit = hash.to_string();
                         }
                     } else {
                         //send NAK if missing common commit
                         add_pkt_line_string(&mut buf, String::from("NAK\n"));
+                        self.record_operation(operation, wanted_refs, buf.len(), started_at)
+                            .await;
+                        if let Some(lease) = lease {
+                            lease.release(&self.context).await;
+                        }
                         return Ok((pack_data, buf));
                     }
                 }
 
-                for hash in &want {
-                    if self.capabilities.contains(&Capability::NoDone) {
-                        // If multi_ack_detailed and no-done are both present, then the sender is free to immediately send a pack
-                        // following its first "ACK obj-id ready" message.
-                        add_pkt_line_string(&mut buf, format!("ACK {} ready\n", hash));
-                    }
+                // If multi_ack_detailed and no-done are both present, then the sender is free to immediately send a pack
+                // following its first "ACK obj-id ready" message. Only say so once every want reaches a common commit,
+                // otherwise the client keeps negotiating.
+                if self.capabilities.contains(&Capability::NoDone)
+                    && self.wants_reach_common(&repo, &want, &have).await
+                {
+                    add_pkt_line_string(
+                        &mut buf,
+                        format!("ACK {} ready\n", client_id(&last_common_commit)),
+                    );
                 }
 
-                pack_data = self.get_incremental_pack_data(want, have).await.unwrap();
+                pack_data = self
+                    .get_incremental_pack_data(want, have)
+                    .instrument(tracing::info_span!("pack"))
+                    .await
+                    .map_err(|err| anyhow::anyhow!("failed to pack the objects: {}", err))?;
             } else {
                 tracing::error!("capability unsupported");
             }
-            add_pkt_line_string(&mut buf, format!("ACK {} \n", last_common_commit));
+            add_pkt_line_string(
+                &mut buf,
+                format!("ACK {} \n", client_id(&last_common_commit)),
+            );
         }
+        if let Some(lease) = lease {
+            lease.release(&self.context).await;
+        }
+        // the objects are named in the format of the client, as the refs it was told
+        if self.object_format != HashKind::Sha1 {
+            pack_data = interop::translate_pack(&self.context, self.object_format, pack_data)
+                .await
+                .map_err(|err| anyhow::anyhow!("failed to translate the pack: {}", err))?;
+        }
+        let sent = pack_data.len() + buf.len();
+        self.record_operation(operation, wanted_refs, sent, started_at)
+            .await;
         Ok((pack_data, buf))
     }
 
-    pub async fn git_receive_pack(&mut self, mut body_bytes: Bytes) -> Result<Bytes> {
-        if body_bytes.len() < 1000 {
-            tracing::debug!("bytes from client: {:?}", body_bytes);
-        } else {
-            tracing::debug!("{} bytes from client", body_bytes.len())
+    /// Logs `operation` if it takes longer than the `slow_op_threshold` setting, see
+    /// [`SlowOp`].
+    pub(crate) fn watch_slow_op(&self, operation: &'static str) -> Option<SlowOp> {
+        let settings = self.context.settings.current();
+        SlowOp::start(
+            operation,
+            &self.path,
+            Duration::from_secs(settings.slow_op_threshold),
+            Duration::from_secs(settings.slow_op_profile_secs),
+            self.context.services.mega_storage.raw_storage.clone(),
+        )
+    }
+
+    /// The first of `want` the client may not fetch. Besides the refs of the snapshot, the
+    /// `upload_allow_*_sha1_in_want` settings accept the current tips of the refs, the commits
+    /// reachable from them or any commit. Over HTTP the advertisement and the negotiation are
+    /// separate requests and the refs may move in between, so the commits reachable from the
+    /// refs are always accepted there, as git does for stateless RPC. A want that is not an
+    /// object of the repository is refused before any history is walked. The trees and blobs
+    /// a partial clone fetches later are looked for in the trees of that history.
+    pub(crate) async fn refused_want(
+        &self,
+        repo: &Repo,
+        snapshot: &RefSnapshot,
+        want: &[String],
+    ) -> Result<Option<String>, MegaError> {
+        let settings = self.context.settings.current();
+        let mut pending: Vec<String> = want
+            .iter()
+            .filter(|id| !snapshot.is_tip(id))
+            .cloned()
+            .collect();
+        if pending.is_empty() {
+            return Ok(None);
         }
-        while !body_bytes.starts_with(&[b'P', b'A', b'C', b'K']) && !body_bytes.is_empty() {
-            let (bytes_take, mut pkt_line) = read_pkt_line(&mut body_bytes);
-            if bytes_take != 0 {
-                let command = self.parse_ref_command(&mut pkt_line);
-                self.parse_capabilities(&String::from_utf8(pkt_line.to_vec()).unwrap());
-                tracing::debug!("init command: {:?}, caps:{:?}", command, self.capabilities);
-                self.command_list.push(command);
+        if settings.upload_allow_any_sha1_in_want {
+            let (_, objects) = self.split_wants(repo, &pending).await?;
+            return self.unknown_object(&objects).await;
+        }
+        let reachable = settings.upload_allow_reachable_sha1_in_want
+            || self.transfer_protocol == Protocol::Http;
+        if !settings.upload_allow_tip_sha1_in_want && !reachable {
+            return Ok(pending.into_iter().next());
+        }
+        let current = RefSnapshot::take(&self.context, repo).await?;
+        pending.retain(|id| !current.is_tip(id));
+        if !reachable || pending.is_empty() {
+            return Ok(pending.into_iter().next());
+        }
+        let (commits, objects) = self.split_wants(repo, &pending).await?;
+        if let Some(id) = self.unknown_object(&objects).await? {
+            return Ok(Some(id));
+        }
+        let tips: Vec<String> = current
+            .refs()
+            .iter()
+            .map(|r| r.ref_git_id.clone())
+            .collect();
+        let limit = settings.upload_want_walk_limit;
+        if let Some(id) = self
+            .unreachable_commits(repo, tips.clone(), commits, limit)
+            .await?
+            .into_iter()
+            .next()
+        {
+            return Ok(Some(id));
+        }
+        if objects.is_empty() {
+            return Ok(None);
+        }
+        Ok(self
+            .unreachable_objects(repo, tips, objects, limit)
+            .await?
+            .into_iter()
+            .next())
+    }
+
+    /// `ids` split into the commits of `repo` and the other ids.
+    async fn split_wants(
+        &self,
+        repo: &Repo,
+        ids: &[String],
+    ) -> Result<(Vec<String>, Vec<String>), MegaError> {
+        let stored: HashSet<String> = self
+            .load_commits(repo, ids)
+            .await?
+            .into_iter()
+            .map(|commit| commit.commit_id)
+            .collect();
+        Ok(ids.iter().cloned().partition(|id| stored.contains(id)))
+    }
+
+    /// The first of `ids` that is neither a stored tree nor a stored blob.
+    async fn unknown_object(&self, ids: &[String]) -> Result<Option<String>, MegaError> {
+        if ids.is_empty() {
+            return Ok(None);
+        }
+        let storage = self.context.services.mega_storage.clone();
+        let mut stored: HashSet<String> =
+            storage.get_existing_trees(ids).await?.into_iter().collect();
+        let rest: Vec<String> = ids
+            .iter()
+            .filter(|id| !stored.contains(*id))
+            .cloned()
+            .collect();
+        stored.extend(storage.get_existing_blobs(&rest).await?);
+        Ok(ids.iter().find(|id| !stored.contains(*id)).cloned())
+    }
+
+    /// The rows of the commits of `ids` stored in `repo`, read [`WANT_WALK_BATCH`] at a time.
+    async fn load_commits(
+        &self,
+        repo: &Repo,
+        ids: &[String],
+    ) -> Result<Vec<mega_commit::Model>, MegaError> {
+        let storage = self.context.services.mega_storage.clone();
+        let mut commits = Vec::new();
+        for batch in ids.chunks(WANT_WALK_BATCH) {
+            commits.extend(storage.get_commits_by_hashes(repo, batch.to_vec()).await?);
+        }
+        Ok(commits)
+    }
+
+    /// The commits of `targets` missing from the history of `tips`. The history is walked a
+    /// generation of parents at a time, each read in batches, and stops as soon as every
+    /// target was found. The targets not found within `limit` commits are returned as
+    /// missing, 0 means no limit.
+    async fn unreachable_commits(
+        &self,
+        repo: &Repo,
+        tips: Vec<String>,
+        targets: Vec<String>,
+        limit: usize,
+    ) -> Result<Vec<String>, MegaError> {
+        let mut targets: HashSet<String> = targets.into_iter().collect();
+        let mut visited: HashSet<String> = HashSet::new();
+        let mut frontier = tips;
+        loop {
+            frontier.retain(|id| visited.insert(id.clone()));
+            for id in &frontier {
+                targets.remove(id);
+            }
+            if targets.is_empty() || frontier.is_empty() {
+                break;
+            }
+            if limit > 0 && visited.len() >= limit {
+                tracing::warn!(
+                    "{} want(s) not found within {} commits of the refs of {}",
+                    targets.len(),
+                    limit,
+                    self.path.display()
+                );
+                break;
+            }
+            frontier = self
+                .load_commits(repo, &frontier)
+                .await?
+                .into_iter()
+                .flat_map(|commit| commit.parents_id)
+                .collect();
+        }
+        Ok(targets.into_iter().collect())
+    }
+
+    /// The trees and blobs of `targets` missing from the trees of the history of `tips`. The
+    /// history is walked as [`Self::unreachable_commits`] walks it, the trees of a generation
+    /// a level at a time, and stops as soon as every target was found. The targets not found
+    /// within `limit` commits are returned as missing, 0 means no limit.
+    async fn unreachable_objects(
+        &self,
+        repo: &Repo,
+        tips: Vec<String>,
+        targets: Vec<String>,
+        limit: usize,
+    ) -> Result<Vec<String>, MegaError> {
+        let storage = self.context.services.mega_storage.clone();
+        let mut targets: HashSet<String> = targets.into_iter().collect();
+        let mut visited: HashSet<String> = HashSet::new();
+        let mut visited_trees: HashSet<String> = HashSet::new();
+        let mut frontier = tips;
+        while !targets.is_empty() {
+            frontier.retain(|id| visited.insert(id.clone()));
+            if frontier.is_empty() {
+                break;
+            }
+            let commits = self.load_commits(repo, &frontier).await?;
+            let mut trees: Vec<String> = commits.iter().map(|commit| commit.tree.clone()).collect();
+            while !trees.is_empty() && !targets.is_empty() {
+                trees.retain(|id| visited_trees.insert(id.clone()));
+                for id in &trees {
+                    targets.remove(id);
+                }
+                let mut subtrees = Vec::new();
+                for model in storage.get_trees_by_hashes(&trees).await? {
+                    let id = SHA1::from_str(&model.tree_id)
+                        .map_err(|e| MegaError::with_message(&e.to_string()))?;
+                    let tree = Tree::from_bytes(model.sub_trees, id)
+                        .map_err(|e| MegaError::with_message(&e.to_string()))?;
+                    for item in tree.tree_items {
+                        match item.mode {
+                            TreeItemMode::Tree => subtrees.push(item.id.to_plain_str()),
+                            TreeItemMode::Commit => {}
+                            _ => {
+                                targets.remove(&item.id.to_plain_str());
+                            }
+                        }
+                    }
+                }
+                trees = subtrees;
+            }
+            if limit > 0 && visited.len() >= limit {
+                tracing::warn!(
+                    "{} want(s) not found within the trees of {} commits of the refs of {}",
+                    targets.len(),
+                    limit,
+                    self.path.display()
+                );
+                break;
+            }
+            frontier = commits
+                .into_iter()
+                .flat_map(|commit| commit.parents_id)
+                .collect();
+        }
+        Ok(targets.into_iter().collect())
+    }
+
+    /// Whether the history of every want contains one of the common commits, the client is
+    /// then told the pack is ready. The history is walked by its parents a generation at a
+    /// time, each read in batches as [`Self::unreachable_commits`] reads it, and no date is
+    /// compared, so a wrong committer clock changes nothing. The walk stops at the common
+    /// commits. Past `upload_want_walk_limit` commits the wants are taken as not ready and the
+    /// client keeps negotiating.
+    pub(crate) async fn wants_reach_common(
+        &self,
+        repo: &Repo,
+        want: &[String],
+        common: &[String],
+    ) -> bool {
+        let limit = self.context.settings.current().upload_want_walk_limit;
+        let common: HashSet<&str> = common.iter().map(String::as_str).collect();
+        // the wants reaching each commit walked, by their index
+        let mut reached: HashMap<String, HashSet<usize>> = HashMap::new();
+        let mut ready: HashSet<usize> = HashSet::new();
+        let mut frontier: Vec<(String, HashSet<usize>)> = want
+            .iter()
+            .enumerate()
+            .map(|(n, id)| (id.clone(), HashSet::from([n])))
+            .collect();
+        loop {
+            // a commit is walked again only for the wants not reaching it yet
+            let mut next: HashMap<String, HashSet<usize>> = HashMap::new();
+            for (id, wants) in frontier {
+                let known = reached.entry(id.clone()).or_default();
+                let new: Vec<usize> = wants.difference(known).copied().collect();
+                if new.is_empty() {
+                    continue;
+                }
+                known.extend(&new);
+                if common.contains(id.as_str()) {
+                    ready.extend(new);
+                } else {
+                    next.entry(id).or_default().extend(new);
+                }
             }
+            if next.is_empty() || ready.len() == want.len() {
+                break;
+            }
+            if limit > 0 && reached.len() >= limit {
+                tracing::warn!(
+                    "{} want(s) not found to reach a common commit within {} commits of {}",
+                    want.len() - ready.len(),
+                    limit,
+                    self.path.display()
+                );
+                return false;
+            }
+            let ids: Vec<String> = next.keys().cloned().collect();
+            let commits = match self.load_commits(repo, &ids).await {
+                Ok(commits) => commits,
+                Err(err) => {
+                    tracing::error!(
+                        "failed to walk the wants of {}: {}",
+                        self.path.display(),
+                        err
+                    );
+                    return false;
+                }
+            };
+            frontier = commits
+                .into_iter()
+                .flat_map(|commit| {
+                    let wants = next.get(&commit.commit_id).cloned().unwrap_or_default();
+                    commit
+                        .parents_id
+                        .into_iter()
+                        .map(move |parent| (parent, wants.clone()))
+                })
+                .collect();
         }
+        ready.len() == want.len()
+    }
+
+    pub async fn git_receive_pack(&mut self, body_bytes: Bytes) -> Result<Bytes> {
+        let length = body_bytes.len();
+        self.git_receive_pack_stream(Cursor::new(body_bytes), Some(length))
+            .await
+    }
+
+    /// Handles a push read from `body` as it arrives: the commands are read first, then the
+    /// pack, if the push is not only deleting refs, is decoded into the quarantine without
+    /// being buffered. `length` is the size of the
+    /// body when it is known, it picks the decode lane.
+    #[tracing::instrument(skip_all, fields(path = %self.path.display(), bytes = length))]
+    pub async fn git_receive_pack_stream(
+        &mut self,
+        mut body: impl AsyncRead + Unpin + Send,
+        length: Option<usize>,
+    ) -> Result<Bytes> {
+        let started_at = Instant::now();
+        let _slow_op = self.watch_slow_op("receive-pack");
+        let (mut commands, pack_started) = read_receive_commands(&mut body).await?;
+        tracing::debug!("commands from client: {:?}", commands);
+        let mut received = commands.len();
+        self.parse_receive_commands(&mut commands);
         // handles situation when client send b"0000"
-        if body_bytes.is_empty() {
-            return Ok(body_bytes);
+        if self.command_list.is_empty() {
+            return Ok(Bytes::new());
         }
         // After receiving the pack data from the sender, the receiver sends a report
         let mut report_status = BytesMut::new();
-        let storage = self.context.services.mega_storage.clone();
         let repo = self.convert_path_to_repo().await;
-        let mut mr = MergeRequest::default();
-        mr.merge(None);
-        storage.save_mr(mr.clone()).await.unwrap();
-        //1. unpack progress
-        let parse_obj_result = self.unpack_and_persist(&mr, &repo, body_bytes).await;
-        // write "unpack ok\n to report"
-        add_pkt_line_string(&mut report_status, "unpack ok\n".to_owned());
+        let dry_run = self.is_dry_run();
+        //1. unpack progress, the objects stay in quarantine until an update is accepted
+        let mut quarantine = None;
+        let (parse_obj_result, pushed, blob_sizes) = if !pack_started {
+            // a push only deleting refs sends no pack
+            (true, PushedObjects::default(), HashMap::new())
+        } else {
+            let pack = AsyncReadExt::chain(Cursor::new(PACK_SIGNATURE), body);
+            match Quarantine::create() {
+                Ok(created) => {
+                    let quarantine = quarantine.insert(created);
+                    self.unpack_to_quarantine(quarantine, pack, length, &mut received)
+                        .instrument(tracing::info_span!("decode"))
+                        .await
+                }
+                Err(err) => {
+                    tracing::error!("failed to create the push quarantine: {}", err);
+                    (false, PushedObjects::default(), HashMap::new())
+                }
+            }
+        };
+        // the refs are still reported one by one when the pack is refused
+        let unpack_status = if parse_obj_result {
+            "unpack ok\n"
+        } else {
+            "unpack failed to decode the pack\n"
+        };
+        add_pkt_line_string(&mut report_status, unpack_status.to_owned());
         //2. parse progress
         // let parse_obj_result = conversion::save_node_from_mr(self.storage.clone(), 0, &self.path)
         //     .await
         //     .is_ok();
 
-        //3. update each refs and build report
-        for mut command in self.command_list.clone() {
-            if command.ref_type == RefType::Tag {
-                // just update if refs type is tag
-                storage.handler_refs(&repo, &command).await;
+        //3. check each ref
+        let mut commands = self.command_list.clone();
+        let mut rejections = self
+            .check_commands(&repo, &commands, parse_obj_result, &pushed, &blob_sizes)
+            .await;
+        let atomic = self.capabilities.contains(&Capability::Atomic);
+        if atomic {
+            fail_atomic(&mut rejections);
+        }
+
+        //4. run the pre-receive and update hooks on the updates accepted so far
+        let mut hook_output = Vec::new();
+        let push = if self.context.push_hooks.is_empty() {
+            None
+        } else {
+            Some(self.push(&repo, accepted_commands(&commands, &rejections), &pushed))
+        };
+        if let Some(push) = push.as_ref().filter(|push| !push.commands.is_empty()) {
+            self.check_push_hooks(push, &commands, &mut rejections, &mut hook_output)
+                .instrument(tracing::info_span!("hooks"))
+                .await;
+            if atomic {
+                fail_atomic(&mut rejections);
+            }
+        }
+
+        //5. move the objects out of quarantine and update the accepted refs in one transaction
+        let mut updated = false;
+        if !dry_run && rejections.iter().any(Option::is_none) {
+            let accepted = accepted_commands(&commands, &rejections);
+            match self
+                .merge(quarantine.as_mut(), &repo, accepted.clone(), &pushed)
+                .instrument(tracing::info_span!("merge"))
+                .await
+            {
+                Ok(()) => {
+                    updated = true;
+                    if let Some(push) = &push {
+                        self.context
+                            .push_hooks
+                            .post_receive(push, &accepted, &mut hook_output)
+                            .await;
+                    }
+                }
+                Err(err) => {
+                    tracing::error!("failed to merge the push: {}", err);
+                    for rejection in rejections.iter_mut().filter(|r| r.is_none()) {
+                        *rejection = Some(PushRejection::MergeFailed);
+                    }
+                }
+            }
+        }
+
+        //6. build report, the output of the hooks first as git prints it
+        let mut messages = BytesMut::new();
+        for line in hook_output {
+            let line = if line.ends_with('\n') {
+                line
             } else {
-                // Updates can be unsuccessful for a number of reasons.
-                // a.The reference can have changed since the reference discovery phase was originally sent, meaning someone pushed in the meantime.
-                // b.The reference being pushed could be a non-fast-forward reference and the update hooks or configuration could be set to not allow that, etc.
-                // c.Also, some references can be updated while others can be rejected.
-                if parse_obj_result {
-                    storage.handler_refs(&repo, &command).await;
-                // self.handle_directory().await.unwrap()
-                } else {
-                    command.failed(String::from("parse commit tree from obj failed"));
+                line + "\n"
+            };
+            self.add_side_band_message(&mut messages, line);
+        }
+        for (command, rejection) in commands.iter_mut().zip(rejections) {
+            match rejection {
+                Some(rejection) => {
+                    let locale = self.client.locale;
+                    command.failed(rejection.reason(locale));
+                    for line in rejection.message(&command.ref_name, locale) {
+                        self.add_side_band_message(&mut messages, line);
+                    }
+                }
+                // reported as refused so that the client keeps its remote-tracking refs
+                None if dry_run => {
+                    command.failed(self.client.locale.tr("push-dry-run-reason", &[]));
                 }
+                None => {}
             }
-            add_pkt_line_string(&mut report_status, command.get_status());
+            add_pkt_line_string(&mut report_status, command.get_status() + "\n");
+        }
+        if dry_run {
+            let line = self.client.locale.tr("push-dry-run", &[]);
+            self.add_side_band_message(&mut messages, line + "\n");
+        } else if updated {
+            self.context.events.wake();
         }
         report_status.put(&PKT_LINE_END_MARKER[..]);
         let length = report_status.len();
-        let mut buf = self.build_side_band_format(report_status, length);
+        // progress messages come first so the client prints them before the ref summary
+        let mut buf = messages;
+        if self.reports_status() {
+            buf.put(self.build_side_band_format(report_status, length));
+        }
         buf.put(&PKT_LINE_END_MARKER[..]);
+        let refs = self
+            .command_list
+            .iter()
+            .map(|c| c.ref_name.clone())
+            .collect();
+        self.record_operation(OperationType::Push, refs, received, started_at)
+            .await;
         Ok(buf.into())
     }
 
+    /// The rejection of each of `commands`, `None` for those accepted.
+    #[tracing::instrument(name = "policy", skip_all, fields(commands = commands.len()))]
+    async fn check_commands(
+        &self,
+        repo: &Repo,
+        commands: &[RefCommand],
+        parse_obj_result: bool,
+        pushed: &PushedObjects,
+        blob_sizes: &HashMap<SHA1, usize>,
+    ) -> Vec<Option<PushRejection>> {
+        // Largest blob accepted in bytes, 0 means no limit.
+        let max_blob_size = self.context.settings.current().max_push_blob_size * 1024;
+        let mut large_blobs: Vec<(String, usize)> = blob_sizes
+            .iter()
+            .filter(|(_, size)| max_blob_size > 0 && **size > max_blob_size)
+            .map(|(id, size)| (id.to_plain_str(), *size))
+            .collect();
+        large_blobs.sort();
+        let too_large = (!large_blobs.is_empty()).then_some(PushRejection::BlobTooLarge {
+            blobs: large_blobs,
+            limit: max_blob_size,
+        });
+        let refs = self.current_refs(repo).await;
+        let mut rejections = Vec::new();
+        for command in commands {
+            // Updates can be unsuccessful for a number of reasons.
+            // a.The reference can have changed since the reference discovery phase was originally sent, meaning someone pushed in the meantime.
+            // b.The reference being pushed could be a non-fast-forward reference and the update hooks or configuration could be set to not allow that, etc.
+            // c.Also, some references can be updated while others can be rejected.
+            let rejection = if !parse_obj_result {
+                Some(PushRejection::Unpack)
+            } else if refs.as_ref().is_some_and(|refs| is_stale(command, refs)) {
+                Some(PushRejection::StaleRef)
+            } else if command.ref_type == RefType::Tag {
+                // a tag is checked on the commit it points to, as a branch would be
+                match self.peel_command(command, pushed).await {
+                    Ok(peeled) => {
+                        self.check_content(repo, &peeled, pushed, blob_sizes, too_large.as_ref())
+                            .await
+                    }
+                    Err(err) => {
+                        tracing::error!("failed to peel the tag {}: {}", command.ref_name, err);
+                        Some(PushRejection::ConnectivityCheckFailed)
+                    }
+                }
+            } else {
+                self.check_content(repo, command, pushed, blob_sizes, too_large.as_ref())
+                    .await
+            };
+            rejections.push(rejection);
+        }
+        rejections
+    }
+
+    /// Checks what `command` points to: its history is complete, the pack has no blob above
+    /// `max_push_blob_size`, else `too_large` refuses it, and the paths it changes are neither
+    /// locked by another user nor refused by a policy. The policies only protect branches.
+    async fn check_content(
+        &self,
+        repo: &Repo,
+        command: &RefCommand,
+        pushed: &PushedObjects,
+        blob_sizes: &HashMap<SHA1, usize>,
+        too_large: Option<&PushRejection>,
+    ) -> Option<PushRejection> {
+        if let Some(rejection) = self
+            .check_connectivity(repo, command, pushed, blob_sizes)
+            .await
+        {
+            return Some(rejection);
+        }
+        if let Some(rejection) = too_large {
+            return Some(rejection.clone());
+        }
+        if let Some(rejection) = self.check_ref_locks(repo, command, pushed).await {
+            return Some(rejection);
+        }
+        self.check_ref_policy(repo, command, pushed, blob_sizes)
+            .await
+    }
+
+    /// Runs the pre-receive step of the push hooks, then the update step for every update
+    /// still accepted, and refuses the updates the hooks refuse.
+    async fn check_push_hooks(
+        &self,
+        push: &Push,
+        commands: &[RefCommand],
+        rejections: &mut [Option<PushRejection>],
+        output: &mut Vec<String>,
+    ) {
+        let hooks = self.context.push_hooks.clone();
+        if let Err(refusal) = hooks.pre_receive(push, output).await {
+            let rejection = PushRejection::from(refusal);
+            for pending in rejections.iter_mut().filter(|r| r.is_none()) {
+                *pending = Some(rejection.clone());
+            }
+            return;
+        }
+        for (command, rejection) in commands.iter().zip(rejections.iter_mut()) {
+            if rejection.is_some() {
+                continue;
+            }
+            if let Err(refusal) = hooks.update(push, command, output).await {
+                *rejection = Some(PushRejection::from(refusal));
+            }
+        }
+    }
+
+    /// The push as the hooks see it, with the updates `commands`.
+    fn push(&self, repo: &Repo, commands: Vec<RefCommand>, pushed: &PushedObjects) -> Push {
+        Push {
+            repo: repo.clone(),
+            repo_path: self.path.to_string_lossy().into_owned(),
+            pusher: self.client.user.clone(),
+            commands,
+            push_options: self.push_options.clone(),
+            commits: pushed_commits(pushed),
+        }
+    }
+
+    /// Reads the ref commands, and the push options following them, up to the pack.
+    fn parse_receive_commands(&mut self, body_bytes: &mut Bytes) {
+        let mut reading_options = false;
+        while !body_bytes.starts_with(b"PACK") && !body_bytes.is_empty() {
+            let (bytes_take, mut pkt_line) = read_pkt_line(body_bytes);
+            if bytes_take == 0 {
+                // the push options follow the flush-pkt ending the commands
+                reading_options = self.capabilities.contains(&Capability::PushOptions);
+            } else if reading_options {
+                let option = String::from_utf8_lossy(&pkt_line).trim_end().to_owned();
+                self.push_options.push(option);
+            } else {
+                let command = self.parse_ref_command(&mut pkt_line);
+                self.parse_capabilities(std::str::from_utf8(&pkt_line).unwrap());
+                tracing::debug!("init command: {:?}, caps:{:?}", command, self.capabilities);
+                self.command_list.push(command);
+            }
+        }
+    }
+
+    /// The current id of every ref of `repo`, `None` if they could not be read, the updates
+    /// are then only checked against concurrent pushes when they are applied.
+    async fn current_refs(&self, repo: &Repo) -> Option<HashMap<String, String>> {
+        match self.context.services.mega_storage.get_repo_refs(repo).await {
+            Ok(refs) => Some(
+                refs.into_iter()
+                    .map(|r| (r.ref_name, r.ref_git_id))
+                    .collect(),
+            ),
+            Err(err) => {
+                tracing::error!(
+                    "failed to read the refs of {}: {}",
+                    self.path.display(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Whether the client asked for the status of the ref updates, with `report-status` or
+    /// `report-status-v2`. Both get the same report, the refs are never rewritten on the
+    /// server so there is no `option` line to send.
+    pub fn reports_status(&self) -> bool {
+        self.capabilities.contains(&Capability::ReportStatus)
+            || self.capabilities.contains(&Capability::ReportStatusv2)
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.push_options
+            .iter()
+            .any(|option| option == DRY_RUN_OPTION)
+    }
+
     /// # Builds the packet data in the sideband format if the SideBand/64k capability is enabled.
     ///
     /// If the `SideBand` or `SideBand64k` capability is present in the `capabilities` vector,
@@ -287,6 +1093,19 @@ impl PackProtocol {
         from_bytes
     }
 
+    /// Appends `line` as a progress sideband packet, which clients print prefixed with `remote:`.
+    /// Nothing is sent if the client did not ask for a sideband.
+    pub fn add_side_band_message(&self, buf: &mut BytesMut, line: String) {
+        if self.capabilities.contains(&Capability::SideBand)
+            || self.capabilities.contains(&Capability::SideBand64k)
+        {
+            let length = line.len() + 5;
+            buf.put(Bytes::from(format!("{length:04x}")));
+            buf.put_u8(SideBind::ProgressInfo.value());
+            buf.put(line.as_bytes());
+        }
+    }
+
     pub fn build_smart_reply(&self, ref_list: &Vec<String>, service: String) -> BytesMut {
         let mut pkt_line_stream = BytesMut::new();
         if self.transfer_protocol == Protocol::Http {
@@ -338,68 +1157,292 @@ impl PackProtocol {
         )
     }
 
-    pub async fn repo_head_object_id(&self, repo: Repo) -> (String, Vec<refs::Model>) {
-        let storage = self.context.services.mega_storage.clone();
-        let refs = storage.get_repo_refs(&repo).await.unwrap();
+    /// The refs of this session, read on first use. The HTTP transport runs the advertisement
+    /// and the negotiation in separate requests, so a fetch there pins the refs it negotiates
+    /// against rather than the advertised ones.
+    pub async fn ref_snapshot(&mut self, repo: &Repo) -> Result<&RefSnapshot, MegaError> {
+        if self.snapshot.is_none() {
+            self.snapshot = Some(RefSnapshot::take(&self.context, repo).await?);
+        }
+        Ok(self.snapshot.as_ref().unwrap())
+    }
 
-        let mut head_hash = ZERO_ID.to_string();
-        for git_ref in refs.iter() {
-            if git_ref.ref_name == *"refs/heads/main" {
-                head_hash = git_ref.ref_git_id.clone();
-            }
+    fn ref_update_event(&self, repo: &Repo, command: &RefCommand) -> RefUpdateEvent {
+        RefUpdateEvent {
+            id: generate_id(),
+            repo_id: repo.repo_id,
+            repo_path: self.path.to_string_lossy().into_owned(),
+            ref_name: command.ref_name.clone(),
+            old_id: command.old_id.clone(),
+            new_id: command.new_id.clone(),
+            pusher: self.client.user.clone(),
+            created_at: Utc::now().naive_utc(),
         }
-        (head_hash, refs)
     }
 
-    async fn unpack_and_persist(&self, mr: &MergeRequest, repo: &Repo, pack_file: Bytes) -> bool {
-        let (sender, receiver) = mpsc::channel();
+    /// Decodes the pack read from `pack` into `quarantine`, its commits, trees and tags are
+    /// also returned, read back from the quarantine, so that the ref updates can be checked
+    /// against file locks and policies, along with the size of every blob. The bytes of the
+    /// pack are added to `received`.
+    ///
+    /// A push of unknown `length`, which git sends chunked once it outgrows its
+    /// `http.postBuffer`, is decoded in the batch lane.
+    async fn unpack_to_quarantine(
+        &self,
+        quarantine: &mut Quarantine,
+        pack: impl AsyncRead + Unpin + Send,
+        length: Option<usize>,
+        received: &mut usize,
+    ) -> (bool, PushedObjects, HashMap<SHA1, usize>) {
+        let (sender, mut receiver) = mpsc::channel::<Entry>(1024);
         // thread::spawn(|| {
         //     let tmp = PathBuf::from("/tmp/.cache_temp");
         //     let mut p = Pack::new(None, Some(1024 * 1024 * 1024 * 4), Some(tmp.clone()));
         //     p.decode(&mut Cursor::new(pack_file), Some(sender)).unwrap();
         // });
-        let tmp = PathBuf::from("/tmp/.cache_temp");
-        let p = Pack::new(None, Some(1024 * 1024 * 1024 * 4), Some(tmp.clone()));
-        p.decode_async(Cursor::new(pack_file), sender); //Pack moved here
-
-        let storage = self.context.services.mega_storage.clone();
-        let mut entry_list = Vec::new();
+        let settings = self.context.settings.current();
+        let tmp = quarantine.dir().join("cache");
+        let mem_limit = settings.pack_decode_mem_limit * 1024 * 1024;
+        let mut p = Pack::new(None, Some(mem_limit), Some(tmp))
+            .with_max_delta_depth(settings.pack_max_delta_depth);
+        if settings.pack_max_object_size > 0 {
+            p = p.with_max_object_size(settings.pack_max_object_size * 1024 * 1024);
+        }
+        let batch_size = settings.pack_batch_size * 1024 * 1024;
+        let lane = if batch_size > 0 && length.is_none_or(|length| length > batch_size) {
+            Lane::Batch
+        } else {
+            Lane::Interactive
+        };
+        p = p.with_lanes(decode_lanes(settings.pack_pin_threads), lane);
+        let mut quarantined = true;
+        let mut blob_sizes = HashMap::new();
 
-        for entry in receiver {
-            entry_list.push(entry);
-            if entry_list.len() >= 1000 {
-                storage.save_entry(mr, repo, entry_list).await.unwrap();
-                entry_list = Vec::new();
+        let receive = async {
+            while let Some(entry) = receiver.recv().await {
+                if entry.obj_type == ObjectType::Blob {
+                    blob_sizes.insert(entry.hash, entry.data.len());
+                }
+                if quarantined {
+                    if let Err(err) = quarantine.add(&entry) {
+                        tracing::error!("failed to quarantine {}: {}", entry.hash, err);
+                        quarantined = false;
+                    }
+                }
+            }
+        };
+        let (decoded, ()) = tokio::join!(p.decode_tokio(pack, sender), receive);
+        match decoded {
+            Ok(stats) => *received += stats.bytes_read,
+            Err(err) => {
+                tracing::error!("failed to decode the pack: {}", err);
+                quarantined = false;
             }
         }
-        storage.save_entry(mr, repo, entry_list).await.unwrap();
-        true
+        // the decode cache lives in the quarantine, it has to be released before its removal
+        drop(p);
+        let pushed = match quarantine.pushed_objects() {
+            Ok(pushed) => pushed,
+            Err(err) => {
+                tracing::error!("failed to read back the quarantine: {}", err);
+                quarantined = false;
+                PushedObjects::default()
+            }
+        };
+        (quarantined, pushed, blob_sizes)
     }
 
-    /// Asynchronously retrieves the full pack data for the specified repository path.
-    /// This function collects commits and nodes from the storage and packs them into
-    /// a single binary vector. There is no need to build the entire tree; the function
-    /// only sends all the data related to this repository.
-    ///
-    /// # Arguments
-    /// * `repo_path` - The path to the repository.
-    ///
-    /// # Returns
-    /// * `Result<Vec<u8>, GitError>` - The packed binary data as a vector of bytes.
+    /// Saves the quarantined objects in a new merge request, the accepted ref updates
+    /// `commands` and their events in one transaction, see [`MegaStorage::merge`]. The
+    /// post-merge hooks run before the commit. The message of the merge request credits the
+    /// authors of the `pushed` commits.
     ///
-    pub async fn get_full_pack_data(&self, _repo_path: &Path) -> Result<Vec<u8>, GitError> {
-        todo!()
+    /// [`MegaStorage::merge`]: jupiter::storage::mega_storage::MegaStorage::merge
+    async fn merge(
+        &self,
+        quarantine: Option<&mut Quarantine>,
+        repo: &Repo,
+        commands: Vec<RefCommand>,
+        pushed: &PushedObjects,
+    ) -> Result<(), MegaError> {
+        let settings = self.context.settings.current();
+        let commits = pushed_commits(pushed);
+        let mut mr = MergeRequest::default();
+        let message = MergeMessage {
+            id: mr.id,
+            co_authors: merge_message::co_authors(&commits),
+            ..Default::default()
+        };
+        match message.render(&settings.merge_message_template) {
+            Ok(message) => mr.merge(Some(message)),
+            Err(err) => {
+                tracing::warn!("failed to render the merge message: {}", err);
+                mr.merge(None);
+            }
+        }
+        let events = commands
+            .iter()
+            .map(|command| self.ref_update_event(repo, command))
+            .collect();
+        let merge = Merge {
+            mr,
+            repo: repo.clone(),
+            commands,
+            events,
+        };
+        let entries = match quarantine {
+            Some(quarantine) => Some(quarantine.entries()?),
+            None => None,
+        };
+        self.context
+            .services
+            .mega_storage
+            .merge(
+                &merge,
+                entries.into_iter().flatten(),
+                &self.context.hooks,
+                settings.merge_hook_rollback,
+            )
+            .await
+    }
+
+    /// The pack of all the refs of the repository at `repo_path`, as [`Self::pack_stream`]
+    /// sends it for a client without any object.
+    pub async fn get_full_pack_data(&self, repo_path: &Path) -> Result<Vec<u8>, MegaError> {
+        let storage = &self.context.services.mega_storage;
+        let repo: Repo = match storage.find_git_repo(&repo_path.to_string_lossy()).await? {
+            Some(model) => model.into(),
+            None => return Err(MegaError::with_message("repository not found")),
+        };
+        let snapshot = RefSnapshot::take(&self.context, &repo).await?;
+        let mut want: Vec<String> = snapshot
+            .refs()
+            .iter()
+            .map(|r| r.ref_git_id.clone())
+            .collect();
+        want.sort();
+        want.dedup();
+        collect_pack(self.pack_stream(&repo, want, Vec::new()).await?).await
     }
 
+    /// The pack of the commits of `want` the client does not have, see [`Self::pack_stream`].
     pub async fn get_incremental_pack_data(
         &self,
-        _want: Vec<String>,
-        _have: Vec<String>,
-    ) -> Result<Vec<u8>, GitError> {
-        todo!()
+        want: Vec<String>,
+        have: Vec<String>,
+    ) -> Result<Vec<u8>, MegaError> {
+        let repo = self.convert_path_to_repo().await;
+        collect_pack(self.pack_stream(&repo, want, have).await?).await
+    }
+
+    /// The pack of the objects reachable from `want` and not from `have`, encoded as it is
+    /// sent, see [`PackObjects`]. For a shallow client the commits stop at `self.shallow`,
+    /// the objects omitted by the `filter` of a partial clone are left out. The objects are
+    /// listed before it starts, an object of them missing from the store fails the call.
+    pub async fn pack_stream(
+        &self,
+        repo: &Repo,
+        want: Vec<String>,
+        have: Vec<String>,
+    ) -> Result<impl Stream<Item = io::Result<Bytes>> + Send + 'static, MegaError> {
+        let parse = |ids: Vec<String>| -> Result<Vec<SHA1>, MegaError> {
+            ids.iter()
+                .map(|id| {
+                    SHA1::from_str(id)
+                        .map_err(|_| MegaError::with_message(&format!("invalid object id {}", id)))
+                })
+                .collect()
+        };
+        let request = PackRequest {
+            want: parse(want)?,
+            have: parse(have)?,
+            client_shallow: self.client_shallow.clone(),
+            shallow: self.shallow.clone(),
+            filter: self.filter,
+        };
+        let store: Arc<dyn ObjectStore> = Arc::new(DbStore::new(
+            self.context.services.mega_storage.as_ref().clone(),
+            repo.clone(),
+            MergeRequest::default(),
+        ));
+        let objects = PackObjects::list(store.as_ref(), &request).await?;
+        tracing::info!("packing {} objects", objects.len());
+        Ok(objects.encode(store))
+    }
+}
+
+/// The whole pack of `stream`.
+async fn collect_pack(stream: impl Stream<Item = io::Result<Bytes>>) -> Result<Vec<u8>, MegaError> {
+    stream
+        .try_fold(Vec::new(), |mut pack, chunk| async move {
+            pack.extend_from_slice(&chunk);
+            Ok(pack)
+        })
+        .await
+        .map_err(|e| MegaError::with_message(&format!("failed to encode the pack: {}", e)))
+}
+
+/// The commits of the pack.
+fn pushed_commits(pushed: &PushedObjects) -> Vec<Commit> {
+    pushed
+        .commits()
+        .filter_map(|entry| Commit::from_bytes(entry.data, entry.hash).ok())
+        .collect()
+}
+
+/// Refuses every update of an `atomic` push once one of them is rejected, the others are
+/// reported as `atomic push failed`.
+fn fail_atomic(rejections: &mut [Option<PushRejection>]) {
+    if rejections.iter().any(Option::is_some) {
+        for rejection in rejections.iter_mut().filter(|r| r.is_none()) {
+            *rejection = Some(PushRejection::AtomicPushFailed);
+        }
+    }
+}
+
+/// The `commands` not rejected.
+fn accepted_commands(
+    commands: &[RefCommand],
+    rejections: &[Option<PushRejection>],
+) -> Vec<RefCommand> {
+    commands
+        .iter()
+        .zip(rejections)
+        .filter(|(_, rejection)| rejection.is_none())
+        .map(|(command, _)| command.clone())
+        .collect()
+}
+
+/// Whether `refs`, the current ids of the refs, no longer match the old id of `command`: the
+/// ref moved since the client read it, or a ref to create already exists.
+fn is_stale(command: &RefCommand, refs: &HashMap<String, String>) -> bool {
+    let current = refs.get(&command.ref_name);
+    match command.command_type {
+        CommandType::Create => current.is_some(),
+        CommandType::Update | CommandType::Delete => current != Some(&command.old_id),
+    }
+}
+
+/// Capabilities telling the client which wants are accepted besides the advertised refs.
+fn want_capabilities(settings: &Settings) -> &'static str {
+    let tip = settings.upload_allow_tip_sha1_in_want || settings.upload_allow_any_sha1_in_want;
+    let reachable =
+        settings.upload_allow_reachable_sha1_in_want || settings.upload_allow_any_sha1_in_want;
+    match (tip, reachable) {
+        (true, true) => "allow-tip-sha1-in-want allow-reachable-sha1-in-want ",
+        (true, false) => "allow-tip-sha1-in-want ",
+        (false, true) => "allow-reachable-sha1-in-want ",
+        (false, false) => "",
     }
 }
 
+/// The id of a `want` or `have` line, 40 hexadecimal digits in SHA-1 or 64 in SHA-256.
+fn object_id(line: &[u8]) -> String {
+    let id = line.get(5..).unwrap_or_default();
+    let len = id.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+    String::from_utf8_lossy(&id[..len]).into_owned()
+}
+
 fn read_until_white_space(bytes: &mut Bytes) -> String {
     let mut buf = Vec::new();
     while bytes.has_remaining() {
@@ -412,7 +1455,7 @@ fn read_until_white_space(bytes: &mut Bytes) -> String {
     String::from_utf8(buf).unwrap()
 }
 
-fn add_pkt_line_string(pkt_line_stream: &mut BytesMut, buf_str: String) {
+pub fn add_pkt_line_string(pkt_line_stream: &mut BytesMut, buf_str: String) {
     let buf_str_length = buf_str.len() + 4;
     pkt_line_stream.put(Bytes::from(format!("{buf_str_length:04x}")));
     pkt_line_stream.put(buf_str.as_bytes());
@@ -466,14 +1509,99 @@ pub fn read_pkt_line(bytes: &mut Bytes) -> (usize, Bytes) {
     (pkt_length, pkt_line)
 }
 
+/// Reads the pkt-lines of a push from `body` up to its pack, and whether a pack follows them.
+/// The signature of the pack is consumed, see [`PACK_SIGNATURE`].
+async fn read_receive_commands(
+    body: &mut (impl AsyncRead + Unpin),
+) -> std::io::Result<(Bytes, bool)> {
+    let mut commands = BytesMut::new();
+    loop {
+        let mut head = [0u8; 4];
+        match body.read_exact(&mut head).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok((commands.freeze(), false))
+            }
+            Err(e) => return Err(e),
+        }
+        if head == PACK_SIGNATURE {
+            return Ok((commands.freeze(), true));
+        }
+        let pkt_length = std::str::from_utf8(&head)
+            .ok()
+            .and_then(|head| usize::from_str_radix(head, 16).ok())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{:?} is not a pkt-line length", head),
+                )
+            })?;
+        commands.extend_from_slice(&head);
+        if pkt_length > 4 {
+            let start = commands.len();
+            commands.resize(start + pkt_length - 4, 0);
+            body.read_exact(&mut commands[start..]).await?;
+        }
+    }
+}
+
+/// The lanes shared by the decodes of all the pushes, a quarter of the threads is kept for the
+/// packs of the interactive lane. They are started by the first decode, `pin` is ignored after
+/// it and a change of `pack_pin_threads` waits for a restart.
+fn decode_lanes(pin: bool) -> Arc<Lanes> {
+    static LANES: OnceLock<Arc<Lanes>> = OnceLock::new();
+    LANES
+        .get_or_init(|| {
+            let threads = thread::available_parallelism().map_or(1, |n| n.get());
+            let batch_threads = threads - (threads / 4).max(1);
+            #[cfg(all(target_os = "linux", feature = "pinned-threads"))]
+            if pin {
+                return Arc::new(Lanes::pinned(threads, batch_threads));
+            }
+            if pin {
+                tracing::warn!("pack_pin_threads needs the pinned-threads feature on Linux");
+            }
+            Arc::new(Lanes::new(threads, batch_threads))
+        })
+        .clone()
+}
+
 #[cfg(test)]
 pub mod test {
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::Arc;
+
     use bytes::{Bytes, BytesMut};
-    use callisto::db_enums::RefType;
+    use callisto::db_enums::{MergeStatus, RefType};
+    use callisto::{git_repo, mega_commit, mega_tag, mega_tree, refs};
+    use futures::stream;
+    use sea_orm::{DatabaseBackend, MockDatabase, Value};
+    use tokio::io::AsyncReadExt;
+    use tokio_util::io::StreamReader;
     use venus::internal::pack::reference::{CommandType, RefCommand};
 
-    use crate::protocol::pack::{add_pkt_line_string, read_pkt_line, read_until_white_space};
-    use crate::protocol::{Capability, PackProtocol};
+    use common::utils::ZERO_ID;
+    use jupiter::settings::{RuntimeSettings, Settings};
+    use jupiter::storage::audit_storage::AuditStorage;
+    use jupiter::storage::mega_storage::MegaStorage;
+    use mercury::test_support::{FixtureConfig, SyntheticRepo};
+    use venus::hash::SHA1;
+    use venus::internal::object::blob::Blob;
+    use venus::internal::object::signature::Signature;
+    use venus::internal::object::tag::Tag;
+    use venus::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+    use venus::internal::object::types::ObjectType;
+    use venus::internal::pack::entry::Entry;
+    use venus::repo::Repo;
+
+    use crate::protocol::pack::{
+        add_pkt_line_string, object_id, read_pkt_line, read_receive_commands,
+        read_until_white_space, want_capabilities,
+    };
+    use crate::protocol::quarantine::pushed_objects;
+    use crate::protocol::report::PushRejection;
+    use crate::protocol::snapshot::RefSnapshot;
+    use crate::protocol::{Capability, PackProtocol, Protocol};
 
     #[test]
     pub fn test_read_pkt_line() {
@@ -508,6 +1636,593 @@ pub mod test {
         assert_eq!(&buf.freeze()[..], b"0038ACK 7bdc783132575d5b3e78400ace9971970ff43a18 common\n0037ACK 7bdc783132575d5b3e78400ace9971970ff43a18 ready\n");
     }
 
+    #[test]
+    pub fn test_want_capabilities() {
+        let mut settings = Settings::default();
+        assert_eq!(want_capabilities(&settings), "");
+        settings.upload_allow_reachable_sha1_in_want = true;
+        assert_eq!(
+            want_capabilities(&settings),
+            "allow-reachable-sha1-in-want "
+        );
+        settings.upload_allow_reachable_sha1_in_want = false;
+        settings.upload_allow_any_sha1_in_want = true;
+        assert_eq!(
+            want_capabilities(&settings),
+            "allow-tip-sha1-in-want allow-reachable-sha1-in-want "
+        );
+    }
+
+    fn commit_row(id: &str, parents: &[&str]) -> mega_commit::Model {
+        let now = chrono::Utc::now().naive_utc();
+        mega_commit::Model {
+            id: 0,
+            repo_id: 1,
+            commit_id: id.to_owned(),
+            tree: "0".repeat(40),
+            parents_id: parents.iter().map(|p| p.to_string()).collect(),
+            author: None,
+            committer: None,
+            content: None,
+            mr_id: 0,
+            status: MergeStatus::Merged,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    fn ref_row(name: &str, id: &str) -> refs::Model {
+        let now = chrono::Utc::now().naive_utc();
+        refs::Model {
+            id: 0,
+            repo_id: 1,
+            ref_name: name.to_owned(),
+            ref_git_id: id.to_owned(),
+            ref_type: RefType::Branch,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// A protocol reading `db`, serving the repository 1.
+    fn db_protocol(db: MockDatabase, settings: Settings) -> (PackProtocol, Repo) {
+        let mut mock = PackProtocol::mock();
+        let mut services = (*mock.context.services).clone();
+        services.mega_storage = Arc::new(MegaStorage {
+            connection: Arc::new(db.into_connection()),
+            ..MegaStorage::mock()
+        });
+        mock.context.services = Arc::new(services);
+        mock.context.settings = Arc::new(RuntimeSettings::new(settings));
+        let repo = Repo {
+            repo_id: 1,
+            repo_path: String::from("/project"),
+            repo_name: String::from("project"),
+        };
+        (mock, repo)
+    }
+
+    /// A protocol reading `db`, whose first result is the snapshot of the refs.
+    async fn want_protocol(
+        db: MockDatabase,
+        settings: Settings,
+    ) -> (PackProtocol, Repo, RefSnapshot) {
+        let (mock, repo) = db_protocol(db, settings);
+        let snapshot = RefSnapshot::take(&mock.context, &repo).await.unwrap();
+        (mock, repo, snapshot)
+    }
+
+    #[tokio::test]
+    async fn test_wants_reach_common() {
+        // c1 <- c2 <- c3, c4 is unrelated
+        let [c1, c2, c3, c4] = [1, 2, 3, 4].map(|i| format!("{:040x}", i));
+        let history = [
+            commit_row(&c3, &[&c2]),
+            commit_row(&c2, &[&c1]),
+            commit_row(&c1, &[]),
+        ];
+        let postgres = || MockDatabase::new(DatabaseBackend::Postgres);
+
+        // read a generation at a time, up to the common commit
+        let db = postgres().append_query_results(history.clone().map(|commit| vec![commit]));
+        let (mock, repo) = db_protocol(db, Settings::default());
+        assert!(
+            mock.wants_reach_common(&repo, std::slice::from_ref(&c3), std::slice::from_ref(&c1))
+                .await
+        );
+        // a have out of the history is walked past to the root
+        let db = postgres().append_query_results(history.clone().map(|commit| vec![commit]));
+        let (mock, repo) = db_protocol(db, Settings::default());
+        assert!(
+            !mock
+                .wants_reach_common(&repo, std::slice::from_ref(&c3), std::slice::from_ref(&c4))
+                .await
+        );
+        // and the walk gives up at its limit
+        let settings = Settings {
+            upload_want_walk_limit: 2,
+            ..Settings::default()
+        };
+        let db = postgres().append_query_results([vec![history[0].clone()]]);
+        let (mock, repo) = db_protocol(db, settings);
+        assert!(
+            !mock
+                .wants_reach_common(&repo, std::slice::from_ref(&c3), std::slice::from_ref(&c1))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_refused_want() {
+        // c1 <- c2 <- c3, main moved from c2 to c3 since the advertisement, c4 is the commit
+        // of an abandoned push
+        let [c1, c2, c3, c4] = [1, 2, 3, 4].map(|i| format!("{:040x}", i));
+        let advertised = || vec![ref_row("refs/heads/main", &c2)];
+        let current = || vec![ref_row("refs/heads/main", &c3)];
+        let history = [
+            commit_row(&c3, &[&c2]),
+            commit_row(&c2, &[&c1]),
+            commit_row(&c1, &[]),
+        ];
+        let postgres = || MockDatabase::new(DatabaseBackend::Postgres);
+
+        // the advertised refs are always accepted, without reading anything else
+        let (mock, repo, snapshot) = want_protocol(
+            postgres().append_query_results([advertised()]),
+            Settings::default(),
+        )
+        .await;
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, std::slice::from_ref(&c2))
+                .await
+                .unwrap(),
+            None
+        );
+
+        // tip: the current tips only
+        let settings = Settings {
+            upload_allow_tip_sha1_in_want: true,
+            ..Settings::default()
+        };
+        let db = postgres()
+            .append_query_results([advertised(), current()])
+            .append_query_results([advertised(), current()]);
+        let (mut mock, repo, snapshot) = want_protocol(db, settings).await;
+        mock.transfer_protocol = Protocol::Ssh;
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, std::slice::from_ref(&c3))
+                .await
+                .unwrap(),
+            None
+        );
+        let snapshot = RefSnapshot::take(&mock.context, &repo).await.unwrap();
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, &[c3.clone(), c1.clone()])
+                .await
+                .unwrap(),
+            Some(c1.clone())
+        );
+
+        // reachable: the history of the refs is walked a generation at a time until every
+        // want is found
+        let db = postgres()
+            .append_query_results([advertised(), current()])
+            .append_query_results([vec![history[2].clone()]])
+            .append_query_results([vec![history[0].clone()], vec![history[1].clone()]]);
+        let (mock, repo, snapshot) = want_protocol(db, Settings::default()).await;
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, std::slice::from_ref(&c1))
+                .await
+                .unwrap(),
+            None
+        );
+        // an unknown id is refused without walking
+        let db = postgres()
+            .append_query_results([advertised(), current()])
+            .append_query_results([Vec::<mega_commit::Model>::new()])
+            .append_query_results([Vec::<mega_tree::Model>::new(), Vec::new()]);
+        let (mock, repo, snapshot) = want_protocol(db, Settings::default()).await;
+        let bogus = "f".repeat(40);
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, std::slice::from_ref(&bogus))
+                .await
+                .unwrap(),
+            Some(bogus.clone())
+        );
+        // as is a commit the refs do not reach
+        let db = postgres()
+            .append_query_results([advertised(), current()])
+            .append_query_results([vec![commit_row(&c4, &[&c1])]])
+            .append_query_results(history.clone().map(|commit| vec![commit]));
+        let (mock, repo, snapshot) = want_protocol(db, Settings::default()).await;
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, std::slice::from_ref(&c4))
+                .await
+                .unwrap(),
+            Some(c4.clone())
+        );
+        // and one past the limit of the walk
+        let settings = Settings {
+            upload_want_walk_limit: 2,
+            ..Settings::default()
+        };
+        let db = postgres()
+            .append_query_results([advertised(), current()])
+            .append_query_results([vec![history[2].clone()], vec![history[0].clone()]]);
+        let (mock, repo, snapshot) = want_protocol(db, settings).await;
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, std::slice::from_ref(&c1))
+                .await
+                .unwrap(),
+            Some(c1.clone())
+        );
+
+        // any: every commit of the repository, looked up at once
+        let settings = Settings {
+            upload_allow_any_sha1_in_want: true,
+            ..Settings::default()
+        };
+        let db = postgres()
+            .append_query_results([advertised()])
+            .append_query_results([vec![commit_row(&c4, &[&c1]), history[2].clone()]])
+            .append_query_results([vec![history[2].clone()]])
+            .append_query_results([Vec::<mega_tree::Model>::new(), Vec::new()]);
+        let (mock, repo, snapshot) = want_protocol(db, settings).await;
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, &[c4.clone(), c1.clone()])
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, &[c1.clone(), bogus.clone()])
+                .await
+                .unwrap(),
+            Some(bogus)
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_refused_lazy_fetch() {
+        // c1 <- c2 <- c3 share the tree holding src/lib.rs, a partial clone of main left the
+        // blob behind and fetches it later, `stray` is a blob no commit refers to
+        let lib = Blob::from_content("pub fn lib() {}");
+        let stray = Blob::from_content("stray").id.to_plain_str();
+        let src = Tree::from_tree_items(vec![TreeItem::new(
+            TreeItemMode::Blob,
+            lib.id,
+            String::from("lib.rs"),
+        )])
+        .unwrap();
+        let root = Tree::from_tree_items(vec![TreeItem::new(
+            TreeItemMode::Tree,
+            src.id,
+            String::from("src"),
+        )])
+        .unwrap();
+        let [c1, c2, c3] = [1, 2, 3].map(|i| format!("{:040x}", i));
+        let commit = |id: &str, parents: &[&str]| mega_commit::Model {
+            tree: root.id.to_plain_str(),
+            ..commit_row(id, parents)
+        };
+        let history = [commit(&c3, &[&c2]), commit(&c2, &[&c1]), commit(&c1, &[])];
+        let main = || vec![ref_row("refs/heads/main", &c3)];
+        let blob_row = |id: &str| [BTreeMap::from([("sha1", Value::from(id))])];
+        let trees = [
+            mega_tree::Model::from(root.clone()),
+            mega_tree::Model::from(src.clone()),
+        ];
+        let postgres = || MockDatabase::new(DatabaseBackend::Postgres);
+        let lib = lib.id.to_plain_str();
+
+        // the clone wants the tip only
+        let (mock, repo, snapshot) = want_protocol(
+            postgres().append_query_results([main()]),
+            Settings::default(),
+        )
+        .await;
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, std::slice::from_ref(&c3))
+                .await
+                .unwrap(),
+            None
+        );
+
+        // the blob it fetches later is found in the trees of the tip
+        let db = postgres()
+            .append_query_results([main(), main()])
+            .append_query_results([Vec::<mega_commit::Model>::new()])
+            .append_query_results([Vec::<mega_tree::Model>::new()])
+            .append_query_results([blob_row(&lib)])
+            .append_query_results([vec![history[0].clone()]])
+            .append_query_results(trees.clone().map(|tree| vec![tree]));
+        let (mock, repo, snapshot) = want_protocol(db, Settings::default()).await;
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, std::slice::from_ref(&lib))
+                .await
+                .unwrap(),
+            None
+        );
+
+        // a stored blob outside the history is refused once the history is walked
+        let db = postgres()
+            .append_query_results([main(), main()])
+            .append_query_results([Vec::<mega_commit::Model>::new()])
+            .append_query_results([Vec::<mega_tree::Model>::new()])
+            .append_query_results([blob_row(&stray)])
+            .append_query_results([vec![history[0].clone()]])
+            .append_query_results(trees.clone().map(|tree| vec![tree]))
+            .append_query_results([vec![history[1].clone()], vec![history[2].clone()]]);
+        let (mock, repo, snapshot) = want_protocol(db, Settings::default()).await;
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, std::slice::from_ref(&stray))
+                .await
+                .unwrap(),
+            Some(stray.clone())
+        );
+
+        // unless any object may be wanted
+        let settings = Settings {
+            upload_allow_any_sha1_in_want: true,
+            ..Settings::default()
+        };
+        let db = postgres()
+            .append_query_results([main()])
+            .append_query_results([Vec::<mega_commit::Model>::new()])
+            .append_query_results([Vec::<mega_tree::Model>::new()])
+            .append_query_results([blob_row(&stray)]);
+        let (mock, repo, snapshot) = want_protocol(db, settings).await;
+        assert_eq!(
+            mock.refused_want(&repo, &snapshot, std::slice::from_ref(&stray))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_check_tag_commands() {
+        let fixture = SyntheticRepo::generate(&FixtureConfig {
+            commits: 1,
+            files: 2,
+            blob_size: 4096,
+            ..Default::default()
+        });
+        let tagger = Signature::from_data(
+            b"tagger Mega Fixture <fixture@mega.dev> 1700000000 +0000".to_vec(),
+        )
+        .unwrap();
+        let tag = Tag::new(
+            fixture.head,
+            ObjectType::Commit,
+            String::from("v1"),
+            tagger,
+            "release\n",
+        )
+        .unwrap();
+        let command = RefCommand::new(
+            ZERO_ID.to_owned(),
+            tag.id.to_plain_str(),
+            String::from("refs/tags/v1"),
+        );
+        // the commits, trees and tag of the push, without the commit `except`
+        let pushed = |except: Option<SHA1>| {
+            pushed_objects(
+                fixture
+                    .objects
+                    .iter()
+                    .filter(|entry| entry.obj_type != ObjectType::Blob)
+                    .filter(|entry| Some(entry.hash) != except)
+                    .cloned()
+                    .chain([Entry::from(tag.clone())]),
+            )
+        };
+        let (_quarantine, all) = pushed(None);
+        let blob_sizes: HashMap<SHA1, usize> = fixture
+            .objects
+            .iter()
+            .filter(|entry| entry.obj_type == ObjectType::Blob)
+            .map(|entry| (entry.hash, entry.data.len()))
+            .collect();
+        let postgres = || MockDatabase::new(DatabaseBackend::Postgres);
+
+        // the blobs of a tag are held to the size limit
+        let settings = Settings {
+            max_push_blob_size: 1,
+            ..Settings::default()
+        };
+        let db = postgres().append_query_results([Vec::<refs::Model>::new()]);
+        let (mock, repo) = db_protocol(db, settings);
+        let rejections = mock
+            .check_commands(
+                &repo,
+                std::slice::from_ref(&command),
+                true,
+                &all,
+                &blob_sizes,
+            )
+            .await;
+        assert!(matches!(
+            rejections[..],
+            [Some(PushRejection::BlobTooLarge { .. })]
+        ));
+
+        // and the history of the commit it points to is complete
+        let (_quarantine, without_head) = pushed(Some(fixture.head));
+        let db = postgres()
+            .append_query_results([Vec::<refs::Model>::new()])
+            .append_query_results([Vec::<mega_tag::Model>::new()])
+            .append_query_results([Vec::<mega_commit::Model>::new()]);
+        let (mock, repo) = db_protocol(db, Settings::default());
+        let rejections = mock
+            .check_commands(&repo, &[command], true, &without_head, &blob_sizes)
+            .await;
+        assert_eq!(
+            rejections,
+            vec![Some(PushRejection::MissingObjects(vec![fixture
+                .head
+                .to_plain_str()]))]
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_receive_delete_only() {
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_query_results([Vec::<git_repo::Model>::new()])
+            .append_query_results([Vec::<refs::Model>::new()]);
+        let (mut mock, _) = db_protocol(db, Settings::default());
+        // the operation is not recorded
+        let mut services = (*mock.context.services).clone();
+        let audit = MockDatabase::new(DatabaseBackend::Postgres).into_connection();
+        services.audit_storage = Arc::new(AuditStorage {
+            connection: Arc::new(audit),
+        });
+        mock.context.services = Arc::new(services);
+        let mut body = BytesMut::new();
+        add_pkt_line_string(
+            &mut body,
+            format!(
+                "{} {} refs/heads/topic\0report-status push-options delete-refs\n",
+                "7bdc783132575d5b3e78400ace9971970ff43a18", ZERO_ID
+            ),
+        );
+        body.extend_from_slice(b"0000");
+        add_pkt_line_string(&mut body, String::from("dry-run\n"));
+        body.extend_from_slice(b"0000");
+        // the deletion is reported although no pack follows the commands
+        let report = mock.git_receive_pack(body.freeze()).await.unwrap();
+        let report = String::from_utf8_lossy(&report);
+        assert!(report.contains("unpack ok"));
+        assert!(report.contains("ng refs/heads/topic"));
+
+        // a flush-pkt alone updates nothing
+        let mut mock = PackProtocol::mock();
+        let report = mock
+            .git_receive_pack(Bytes::from_static(b"0000"))
+            .await
+            .unwrap();
+        assert!(report.is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn test_receive_atomic() {
+        let deleted = "7bdc783132575d5b3e78400ace9971970ff43a18";
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_query_results([Vec::<git_repo::Model>::new()])
+            .append_query_results([vec![ref_row("refs/heads/topic", deleted)]])
+            // the policy of the deleted commit, unknown here
+            .append_query_results([Vec::<mega_commit::Model>::new()]);
+        let (mut mock, _) = db_protocol(db, Settings::default());
+        let mut services = (*mock.context.services).clone();
+        let audit = MockDatabase::new(DatabaseBackend::Postgres).into_connection();
+        services.audit_storage = Arc::new(AuditStorage {
+            connection: Arc::new(audit),
+        });
+        mock.context.services = Arc::new(services);
+        let mut body = BytesMut::new();
+        // topic is where the push expects it, stale is not there any more
+        add_pkt_line_string(
+            &mut body,
+            format!(
+                "{} {} refs/heads/topic\0report-status delete-refs atomic\n",
+                deleted, ZERO_ID
+            ),
+        );
+        add_pkt_line_string(
+            &mut body,
+            format!("{} {} refs/heads/stale\n", deleted, ZERO_ID),
+        );
+        body.extend_from_slice(b"0000");
+        // nothing is merged, the mock database has no result left for it
+        let report = mock.git_receive_pack(body.freeze()).await.unwrap();
+        let report = String::from_utf8_lossy(&report);
+        assert!(report.contains("ng refs/heads/topic atomic push failed"));
+        assert!(report.contains("ng refs/heads/stale fetch first"));
+    }
+
+    #[test]
+    pub fn test_object_id() {
+        let sha1 = "7bdc783132575d5b3e78400ace9971970ff43a18";
+        let line = format!("want {} side-band-64k ofs-delta\n", sha1);
+        assert_eq!(object_id(line.as_bytes()), sha1);
+        let sha256 = "6497f598fd8dc66bb8e66bcd4c3df6254978cfbc064444314ff90491c3297d8c";
+        assert_eq!(object_id(format!("have {}\n", sha256).as_bytes()), sha256);
+        assert_eq!(object_id(b"done"), "");
+    }
+
+    #[test]
+    pub fn test_parse_push_options() {
+        let mut mock = PackProtocol::mock();
+        let mut body = BytesMut::new();
+        add_pkt_line_string(
+            &mut body,
+            format!(
+                "{} {} refs/heads/main\0report-status push-options\n",
+                "0".repeat(40),
+                "7bdc783132575d5b3e78400ace9971970ff43a18"
+            ),
+        );
+        body.extend_from_slice(b"0000");
+        add_pkt_line_string(&mut body, String::from("dry-run\n"));
+        body.extend_from_slice(b"0000PACK");
+        let mut body = body.freeze();
+        mock.parse_receive_commands(&mut body);
+        assert_eq!(mock.command_list.len(), 1);
+        assert_eq!(mock.push_options, vec!["dry-run"]);
+        assert!(mock.is_dry_run());
+        assert_eq!(&body[..], b"PACK");
+    }
+
+    #[tokio::test]
+    pub async fn test_read_receive_commands() {
+        let mut body = BytesMut::new();
+        add_pkt_line_string(
+            &mut body,
+            format!(
+                "{} {} refs/heads/main\0report-status\n",
+                "0".repeat(40),
+                "7bdc783132575d5b3e78400ace9971970ff43a18"
+            ),
+        );
+        body.extend_from_slice(b"0000");
+        let commands = body.clone().freeze();
+        body.extend_from_slice(b"PACK\0\0\0\x02");
+        // the body arrives in chunks cutting the pkt-lines
+        let chunks: Vec<std::io::Result<Bytes>> = body
+            .chunks(7)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let mut reader = StreamReader::new(stream::iter(chunks));
+        let (read, pack_started) = read_receive_commands(&mut reader).await.unwrap();
+        assert_eq!(read, commands);
+        assert!(pack_started);
+        // the pack is left to the decoder, without its signature
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"\0\0\0\x02");
+
+        let mut empty = &b"0000"[..];
+        let (read, pack_started) = read_receive_commands(&mut empty).await.unwrap();
+        assert_eq!(&read[..], b"0000");
+        assert!(!pack_started);
+        let mut invalid = &b"zz"[..];
+        assert!(!read_receive_commands(&mut invalid).await.unwrap().1);
+        let mut invalid = &b"zzzz"[..];
+        assert!(read_receive_commands(&mut invalid).await.is_err());
+    }
+
+    #[test]
+    pub fn test_add_side_band_message() {
+        let mut mock = PackProtocol::mock();
+        let mut buf = BytesMut::new();
+        mock.add_side_band_message(&mut buf, String::from("hint: see docs\n"));
+        assert!(buf.is_empty());
+
+        mock.capabilities.push(Capability::SideBand64k);
+        mock.add_side_band_message(&mut buf, String::from("hint: see docs\n"));
+        assert_eq!(&buf.freeze()[..], b"0014\x02hint: see docs\n");
+    }
+
     #[test]
     pub fn test_read_until_white_space() {
         let mut bytes = Bytes::from("Mega - A Monorepo Platform Engine".as_bytes());
@@ -544,10 +2259,14 @@ pub mod test {
     #[test]
     pub fn test_parse_capabilities() {
         let mut mock = PackProtocol::mock();
-        mock.parse_capabilities("report-status-v2 side-band-64k object-format=sha10000");
+        mock.parse_capabilities("report-status-v2 side-band-64k filter object-format=sha10000");
         assert_eq!(
             mock.capabilities,
-            vec![Capability::ReportStatusv2, Capability::SideBand64k]
+            vec![
+                Capability::ReportStatusv2,
+                Capability::SideBand64k,
+                Capability::Filter
+            ]
         );
     }
 }
@@ -0,0 +1,524 @@
+//!
+//! The packs sent by upload-pack and written to bundles, made as `git pack-objects` does. The
+//! objects are listed first, the header of a pack holds their number: the commits reachable
+//! from the wants and not from the haves, down to the shallow boundary, then the trees and
+//! blobs of these commits their parents do not have, less those the filter of a partial clone
+//! omits. They are read again from an [`ObjectStore`] as the [`PackEncoder`] writes the pack,
+//! which is sent in chunks as it is encoded.
+//!
+//! The history behind the haves is not walked, so a commit the client has through another
+//! path than a have may be sent again. An object sent twice is harmless, a missing one is not.
+//!
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use futures::Stream;
+use tokio::sync::mpsc;
+
+use common::errors::MegaError;
+use mercury::internal::pack::encode::PackEncoder;
+use mercury::internal::pack::filter::FilterSpec;
+use venus::errors::GitError;
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::internal::object::tag::Tag;
+use venus::internal::object::tree::{Tree, TreeItemMode};
+use venus::internal::object::types::ObjectType;
+use venus::internal::object::ObjectTrait;
+use venus::internal::pack::entry::Entry;
+use venus::internal::revwalk::CommitGraph;
+
+use crate::object_store::ObjectStore;
+
+/// Objects read from the store at once.
+const BATCH_SIZE: usize = 1000;
+/// The objects a delta is searched among, the `pack.window` of git.
+const PACK_WINDOW: usize = 10;
+/// Bytes of the pack sent at once.
+const CHUNK_SIZE: usize = 65536;
+
+/// What a client asks for, the ids in the object format of the store.
+#[derive(Debug, Clone, Default)]
+pub struct PackRequest {
+    pub want: Vec<SHA1>,
+    pub have: Vec<SHA1>,
+    /// The shallow commits of the client, the history it has stops at them.
+    pub client_shallow: HashSet<SHA1>,
+    /// The shallow commits of the client once it has the pack, their parents are not sent.
+    pub shallow: HashSet<SHA1>,
+    pub filter: Option<FilterSpec>,
+}
+
+/// The objects of a pack, in the order they are encoded.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PackObjects {
+    pub commits: Vec<SHA1>,
+    pub trees: Vec<SHA1>,
+    pub blobs: Vec<SHA1>,
+    pub tags: Vec<SHA1>,
+}
+
+/// A tree to send unless one of `bases`, the trees at its path in the parents of its commit,
+/// is the same.
+struct TreeVisit {
+    id: SHA1,
+    depth: usize,
+    bases: Vec<SHA1>,
+}
+
+impl PackObjects {
+    pub fn len(&self) -> usize {
+        self.commits.len() + self.trees.len() + self.blobs.len() + self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn ids(&self) -> impl Iterator<Item = &SHA1> {
+        self.commits
+            .iter()
+            .chain(&self.trees)
+            .chain(&self.blobs)
+            .chain(&self.tags)
+    }
+
+    /// Lists the objects of the pack answering `request`, read from `store`. Fails when a want
+    /// or an object one reaches is not in the store.
+    pub async fn list(store: &dyn ObjectStore, request: &PackRequest) -> Result<Self, MegaError> {
+        let mut objects = PackObjects::default();
+        let mut listed: HashSet<SHA1> = HashSet::new();
+        let mut tips = Vec::new();
+        let mut queue = Vec::new();
+        // an annotated tag is sent with the object it tags
+        let mut wanted = request.want.clone();
+        while !wanted.is_empty() {
+            for entry in read(store, &std::mem::take(&mut wanted)).await? {
+                if !listed.insert(entry.hash) {
+                    continue;
+                }
+                match entry.obj_type {
+                    ObjectType::Commit => {
+                        listed.remove(&entry.hash);
+                        tips.push(entry.hash);
+                    }
+                    ObjectType::Tree => {
+                        listed.remove(&entry.hash);
+                        queue.push(TreeVisit {
+                            id: entry.hash,
+                            depth: 0,
+                            bases: Vec::new(),
+                        });
+                    }
+                    ObjectType::Blob => objects.blobs.push(entry.hash),
+                    ObjectType::Tag => {
+                        let tag = Tag::from_bytes(entry.data, entry.hash).map_err(invalid)?;
+                        objects.tags.push(entry.hash);
+                        wanted.push(tag.object_hash);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let graph = load_history(store, &tips, request).await?;
+        let hidden: Vec<SHA1> = request
+            .have
+            .iter()
+            .filter(|id| graph.contains(id))
+            .copied()
+            .collect();
+        objects.commits =
+            graph.walk_shallow(&tips, &hidden, &request.client_shallow, &request.shallow);
+        for id in &objects.commits {
+            // a shallow commit is sent without its parents, so with all of its tree
+            let bases = match request.shallow.contains(id) {
+                true => Vec::new(),
+                false => graph
+                    .parents_of(id)
+                    .iter()
+                    .filter_map(|parent| graph.tree(parent))
+                    .collect(),
+            };
+            queue.push(TreeVisit {
+                id: graph.tree(id).unwrap(),
+                depth: 0,
+                bases,
+            });
+        }
+
+        let blobs = list_trees(store, queue, request.filter, &mut listed, &mut objects).await?;
+        match request.filter {
+            Some(filter @ FilterSpec::BlobLimit(_)) => {
+                for batch in blobs.chunks(BATCH_SIZE) {
+                    let ids: Vec<SHA1> = batch.iter().map(|(id, _)| *id).collect();
+                    let sizes: HashMap<SHA1, usize> = read(store, &ids)
+                        .await?
+                        .into_iter()
+                        .map(|entry| (entry.hash, entry.data.len()))
+                        .collect();
+                    objects.blobs.extend(
+                        batch
+                            .iter()
+                            .filter(|(id, depth)| filter.keeps_blob(sizes[id], *depth))
+                            .map(|(id, _)| *id),
+                    );
+                }
+            }
+            // the size only matters to `blob:limit`
+            Some(filter) => objects.blobs.extend(
+                blobs
+                    .into_iter()
+                    .filter(|(_, depth)| filter.keeps_blob(0, *depth))
+                    .map(|(id, _)| id),
+            ),
+            None => objects.blobs.extend(blobs.into_iter().map(|(id, _)| id)),
+        }
+        Ok(objects)
+    }
+
+    /// Encodes the objects, read from `store`, to a pack sent in chunks as it is written. An
+    /// object the store no longer has ends the stream with an error.
+    pub fn encode(
+        self,
+        store: Arc<dyn ObjectStore>,
+    ) -> impl Stream<Item = io::Result<Bytes>> + Send + 'static {
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let count = self.len();
+            let (entry_sender, mut entry_receiver) = mpsc::channel::<Entry>(BATCH_SIZE);
+            let writer = ChunkWriter {
+                sender: sender.clone(),
+                chunk: BytesMut::new(),
+            };
+            let encoder = tokio::task::spawn_blocking(move || {
+                PackEncoder::new(count, PACK_WINDOW, writer)
+                    .encode_entries(std::iter::from_fn(|| entry_receiver.blocking_recv()))
+            });
+            let loaded = self.load(store.as_ref(), entry_sender).await;
+            let error = match (loaded, encoder.await) {
+                (Err(e), _) => Some(e.to_string()),
+                (Ok(()), Err(e)) => Some(e.to_string()),
+                (Ok(()), Ok(Err(e))) => Some(e.to_string()),
+                (Ok(()), Ok(Ok(()))) => None,
+            };
+            if let Some(error) = error {
+                // nothing is told when the client is gone
+                let _ = sender.send(Err(io::Error::other(error))).await;
+            }
+        });
+        futures::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (chunk, receiver))
+        })
+    }
+
+    /// Sends the objects to the encoder in their order.
+    async fn load(
+        &self,
+        store: &dyn ObjectStore,
+        sender: mpsc::Sender<Entry>,
+    ) -> Result<(), MegaError> {
+        let ids: Vec<SHA1> = self.ids().copied().collect();
+        for batch in ids.chunks(BATCH_SIZE) {
+            let mut entries: HashMap<SHA1, Entry> = read(store, batch)
+                .await?
+                .into_iter()
+                .map(|entry| (entry.hash, entry))
+                .collect();
+            for id in batch {
+                // the encoder stopped on an error, it is returned with it
+                if sender.send(entries.remove(id).unwrap()).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The commits reachable from `tips`, read a generation at a time. The history behind a have is
+/// not read, but for the shallow commits of the client being deepened, nor the one behind
+/// the new shallow commits. A parent missing from the store is left out.
+async fn load_history(
+    store: &dyn ObjectStore,
+    tips: &[SHA1],
+    request: &PackRequest,
+) -> Result<CommitGraph, MegaError> {
+    let have: HashSet<SHA1> = request.have.iter().copied().collect();
+    let mut commits: HashMap<SHA1, Commit> = HashMap::new();
+    let mut frontier = tips.to_vec();
+    while !frontier.is_empty() {
+        let mut parents = Vec::new();
+        for chunk in frontier.chunks(BATCH_SIZE) {
+            for entry in store.get_many(chunk).await? {
+                let commit = Commit::from_bytes(entry.data, entry.hash).map_err(invalid)?;
+                let id = commit.id;
+                if (!have.contains(&id) || request.client_shallow.contains(&id))
+                    && !request.shallow.contains(&id)
+                {
+                    parents.extend(commit.parent_commit_ids.iter().copied());
+                }
+                commits.insert(id, commit);
+            }
+        }
+        parents.retain(|id| !commits.contains_key(id));
+        parents.sort();
+        parents.dedup();
+        frontier = parents;
+    }
+    Ok(CommitGraph::new(commits.into_values()))
+}
+
+/// Lists the trees of `queue` and below them into `objects`, level by level, and returns the
+/// blobs they hold with their depth, the filter is applied to them by the caller. An object
+/// found in the same place in a base is not listed, the client has it or gets it with the
+/// commit of the base.
+async fn list_trees(
+    store: &dyn ObjectStore,
+    mut queue: Vec<TreeVisit>,
+    filter: Option<FilterSpec>,
+    listed: &mut HashSet<SHA1>,
+    objects: &mut PackObjects,
+) -> Result<Vec<(SHA1, usize)>, MegaError> {
+    let mut blobs: Vec<(SHA1, usize)> = Vec::new();
+    let mut blob_depths: HashMap<SHA1, usize> = HashMap::new();
+    while !queue.is_empty() {
+        let level = std::mem::take(&mut queue);
+        for batch in level.chunks(BATCH_SIZE) {
+            let batch: Vec<&TreeVisit> = batch
+                .iter()
+                .filter(|visit| !visit.bases.contains(&visit.id) && !listed.contains(&visit.id))
+                .filter(|visit| filter.is_none_or(|filter| filter.keeps_tree(visit.depth)))
+                .collect();
+            let ids: Vec<SHA1> = batch.iter().map(|visit| visit.id).collect();
+            let mut trees = parse_trees(read(store, &ids).await?)?;
+            let mut bases: Vec<SHA1> = batch
+                .iter()
+                .flat_map(|visit| visit.bases.iter().copied())
+                .filter(|id| !trees.contains_key(id))
+                .collect();
+            bases.sort();
+            bases.dedup();
+            // a base missing from the store, behind a shallow commit, has nothing to compare
+            trees.extend(parse_trees(store.get_many(&bases).await?)?);
+
+            for visit in batch {
+                if !listed.insert(visit.id) {
+                    continue;
+                }
+                objects.trees.push(visit.id);
+                let mut base_items: HashMap<&str, Vec<(SHA1, TreeItemMode)>> = HashMap::new();
+                for base in visit.bases.iter().filter_map(|id| trees.get(id)) {
+                    for item in &base.tree_items {
+                        base_items
+                            .entry(item.name.as_str())
+                            .or_default()
+                            .push((item.id, item.mode));
+                    }
+                }
+                for item in &trees[&visit.id].tree_items {
+                    // the commits of submodules are not part of the repository
+                    if item.mode == TreeItemMode::Commit {
+                        continue;
+                    }
+                    let same_place = base_items.get(item.name.as_str());
+                    if same_place.is_some_and(|items| items.iter().any(|(id, _)| *id == item.id))
+                    {
+                        continue;
+                    }
+                    let depth = visit.depth + 1;
+                    if item.mode == TreeItemMode::Tree {
+                        let bases = same_place
+                            .into_iter()
+                            .flatten()
+                            .filter(|(_, mode)| *mode == TreeItemMode::Tree)
+                            .map(|(id, _)| *id)
+                            .collect();
+                        queue.push(TreeVisit {
+                            id: item.id,
+                            depth,
+                            bases,
+                        });
+                    } else if !listed.contains(&item.id) {
+                        // the shallowest place of a blob decides for `tree:<depth>`
+                        match blob_depths.get_mut(&item.id) {
+                            Some(found) => *found = depth.min(*found),
+                            None => {
+                                blob_depths.insert(item.id, depth);
+                                blobs.push((item.id, depth));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    for (id, depth) in blobs.iter_mut() {
+        *depth = blob_depths[id];
+    }
+    Ok(blobs)
+}
+
+/// The objects of `ids`, in no particular order. Fails when the store lacks one of them.
+async fn read(store: &dyn ObjectStore, ids: &[SHA1]) -> Result<Vec<Entry>, MegaError> {
+    let entries = store.get_many(ids).await?;
+    if entries.len() < ids.len() {
+        let found: HashSet<SHA1> = entries.iter().map(|entry| entry.hash).collect();
+        if let Some(id) = ids.iter().find(|id| !found.contains(id)) {
+            return Err(MegaError::with_message(&format!(
+                "object {} not found",
+                id.to_plain_str()
+            )));
+        }
+    }
+    Ok(entries)
+}
+
+fn parse_trees(entries: Vec<Entry>) -> Result<HashMap<SHA1, Tree>, MegaError> {
+    entries
+        .into_iter()
+        .filter(|entry| entry.obj_type == ObjectType::Tree)
+        .map(|entry| {
+            let tree = Tree::from_bytes(entry.data, entry.hash).map_err(invalid)?;
+            Ok((tree.id, tree))
+        })
+        .collect()
+}
+
+fn invalid(e: GitError) -> MegaError {
+    MegaError::with_message(&e.to_string())
+}
+
+/// Sends what the encoder writes in chunks of [`CHUNK_SIZE`].
+struct ChunkWriter {
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    chunk: BytesMut,
+}
+
+impl ChunkWriter {
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = self.chunk.split().freeze();
+        self.sender.blocking_send(Ok(chunk)).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "the pack is no longer read")
+        })
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.chunk.extend_from_slice(buf);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.chunk.is_empty() {
+            self.send()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use futures::TryStreamExt;
+    use mercury::internal::pack::filter::FilterSpec;
+    use mercury::internal::pack::Pack;
+    use mercury::test_support::{FixtureConfig, SyntheticRepo};
+    use venus::hash::SHA1;
+    use venus::internal::object::types::ObjectType;
+
+    use super::{PackObjects, PackRequest};
+    use crate::object_store::{LooseStore, ObjectStore};
+
+    fn temp_dir() -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("pack_objects_{}", common::utils::generate_id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_pack_objects() {
+        let dir = temp_dir();
+        let repo = SyntheticRepo::generate(&FixtureConfig::default());
+        let store = Arc::new(LooseStore::new(&dir));
+        for object in &repo.objects {
+            store.put(object).await.unwrap();
+        }
+        let commits: Vec<SHA1> = repo
+            .objects
+            .iter()
+            .filter(|object| object.obj_type == ObjectType::Commit)
+            .map(|object| object.hash)
+            .collect();
+
+        // a clone gets every object once
+        let full = PackRequest {
+            want: vec![repo.head],
+            ..Default::default()
+        };
+        let objects = PackObjects::list(store.as_ref(), &full).await.unwrap();
+        let all: HashSet<SHA1> = repo.objects.iter().map(|object| object.hash).collect();
+        assert_eq!(objects.ids().copied().collect::<HashSet<_>>(), all);
+        assert_eq!(objects.len(), all.len());
+
+        // the pack decodes to these objects
+        let pack: Vec<u8> = objects
+            .encode(store.clone())
+            .map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await
+            .unwrap();
+        let decoded = Arc::new(Mutex::new(Vec::new()));
+        let sink = decoded.clone();
+        Pack::new(None, Some(1024 * 1024 * 20), Some(dir.join("cache")))
+            .decode(&mut Cursor::new(pack), move |entry| {
+                sink.lock().unwrap().push(entry.hash)
+            })
+            .unwrap();
+        assert_eq!(
+            decoded.lock().unwrap().iter().copied().collect::<HashSet<_>>(),
+            all
+        );
+
+        // a fetch gets the commits after the have, and only the blob each one changed
+        let fetch = PackRequest {
+            want: vec![repo.head],
+            have: vec![commits[4]],
+            ..Default::default()
+        };
+        let objects = PackObjects::list(store.as_ref(), &fetch).await.unwrap();
+        assert_eq!(objects.commits.len(), commits.len() - 5);
+        assert!(!objects.commits.contains(&commits[4]));
+        assert!(objects.blobs.len() <= commits.len() - 5);
+
+        // a partial clone without blobs
+        let blobless = PackRequest {
+            want: vec![repo.head],
+            filter: Some(FilterSpec::BlobNone),
+            ..Default::default()
+        };
+        let objects = PackObjects::list(store.as_ref(), &blobless).await.unwrap();
+        assert!(objects.blobs.is_empty());
+        assert_eq!(objects.commits.len(), commits.len());
+
+        // a want the store does not have
+        let missing = PackRequest {
+            want: vec![SHA1::default()],
+            ..Default::default()
+        };
+        assert!(PackObjects::list(store.as_ref(), &missing).await.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+//!
+//! Enforce the `.mega/policy.toml` files at push time, see [`crate::policy`]. The policies
+//! are read from the tree the ref points to before the push, or from the default branch for
+//! a new ref, in the directories of the changed files and at the root.
+//!
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use common::errors::MegaError;
+use venus::hash::SHA1;
+use venus::internal::pack::reference::{CommandType, RefCommand};
+use venus::repo::Repo;
+
+use crate::policy::{self, ChangedFile};
+use crate::protocol::quarantine::PushedObjects;
+use crate::protocol::report::PushRejection;
+use crate::protocol::PackProtocol;
+
+/// The branch whose policies apply to the creation of a ref.
+const DEFAULT_BRANCH: &str = "refs/heads/main";
+
+impl PackProtocol {
+    /// Checks `command` against the policies of the directories it changes. `blob_sizes`
+    /// holds the size of every blob of the pack. A policy that cannot be loaded refuses the
+    /// update rather than leaving it unchecked.
+    pub async fn check_ref_policy(
+        &self,
+        repo: &Repo,
+        command: &RefCommand,
+        pushed: &PushedObjects,
+        blob_sizes: &HashMap<SHA1, usize>,
+    ) -> Option<PushRejection> {
+        match self
+            .ref_policy_violations(repo, command, pushed, blob_sizes)
+            .await
+        {
+            Ok(violations) if !violations.is_empty() => {
+                Some(PushRejection::PolicyViolations(violations))
+            }
+            Ok(_) => None,
+            Err(err) => {
+                tracing::error!("policy lookup for {} failed: {}", command.ref_name, err);
+                Some(PushRejection::PolicyCheckFailed)
+            }
+        }
+    }
+
+    async fn ref_policy_violations(
+        &self,
+        repo: &Repo,
+        command: &RefCommand,
+        pushed: &PushedObjects,
+        blob_sizes: &HashMap<SHA1, usize>,
+    ) -> Result<Vec<policy::Violation>, MegaError> {
+        let target = match command.command_type {
+            CommandType::Create => self.default_branch_tip(repo).await?,
+            _ => Some(command.old_id.clone()),
+        };
+        let Some(target) = target else {
+            return Ok(vec![]);
+        };
+        let Some(target_tree) = self
+            .load_commit(repo, &target, pushed)
+            .await
+            .map(|c| c.tree_id)
+        else {
+            return Ok(vec![]);
+        };
+
+        let new_commit = match command.command_type {
+            CommandType::Delete => None,
+            _ => self.load_commit(repo, &command.new_id, pushed).await,
+        };
+        // a deletion is checked against the policy of the root only
+        let files: Vec<ChangedFile> = match &new_commit {
+            Some(commit) => self
+                .changed_files(Some(target_tree), Some(commit.tree_id), pushed)
+                .await
+                .into_iter()
+                .map(|(path, blob)| ChangedFile {
+                    path,
+                    size: blob.and_then(|id| blob_sizes.get(&id).copied()),
+                })
+                .collect(),
+            None => vec![],
+        };
+        let directories: BTreeSet<String> = files
+            .iter()
+            .flat_map(|file| policy::directories(&file.path))
+            .chain([String::new()])
+            .collect();
+        let policies = policy::load_policies(&self.context, target_tree, &directories).await?;
+        if policies.is_empty() {
+            return Ok(vec![]);
+        }
+
+        // the history is only walked for a protected branch
+        let forced = match command.command_type {
+            CommandType::Delete => true,
+            CommandType::Update if policies.protects(&command.ref_name) => {
+                !self
+                    .is_fast_forward(repo, &command.old_id, &command.new_id, pushed)
+                    .await
+            }
+            _ => false,
+        };
+        let pusher = self
+            .client
+            .user
+            .clone()
+            .or_else(|| new_commit.map(|c| c.committer.name));
+        let hooks = self.context.hooks.hooks();
+        let checks: Vec<&str> = hooks.iter().map(|hook| hook.name()).collect();
+        Ok(policies.check(
+            &command.ref_name,
+            forced,
+            &files,
+            pusher.as_deref(),
+            &checks,
+        ))
+    }
+
+    async fn default_branch_tip(&self, repo: &Repo) -> Result<Option<String>, MegaError> {
+        let refs = self
+            .context
+            .services
+            .mega_storage
+            .get_repo_refs(repo)
+            .await?;
+        Ok(refs
+            .into_iter()
+            .find(|r| r.ref_name == DEFAULT_BRANCH)
+            .map(|r| r.ref_git_id))
+    }
+
+    /// Whether `old` is in the history of `new`.
+    async fn is_fast_forward(
+        &self,
+        repo: &Repo,
+        old: &str,
+        new: &str,
+        pushed: &PushedObjects,
+    ) -> bool {
+        let mut visited: HashSet<String> = HashSet::new();
+        let mut stack = vec![new.to_owned()];
+        while let Some(id) = stack.pop() {
+            if id == old {
+                return true;
+            }
+            if !visited.insert(id.clone()) {
+                continue;
+            }
+            if let Some(commit) = self.load_commit(repo, &id, pushed).await {
+                stack.extend(commit.parent_commit_ids.iter().map(|p| p.to_plain_str()));
+            }
+        }
+        false
+    }
+}
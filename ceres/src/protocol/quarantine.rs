@@ -0,0 +1,277 @@
+//!
+//! Objects of a push waiting for the checks of its ref updates. Like the temporary object
+//! directory of git receive-pack, the decoded objects are written to a directory of their own
+//! and only migrated to the object store once an update was accepted. The directory is removed
+//! with the push, so a rejected or failed push leaves nothing behind.
+//!
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use common::utils::generate_id;
+use venus::hash::SHA1;
+use venus::internal::object::types::ObjectType;
+use venus::internal::pack::entry::Entry;
+
+const OBJECTS_FILE: &str = "objects";
+
+pub struct Quarantine {
+    dir: PathBuf,
+    writer: BufWriter<File>,
+    count: usize,
+    /// The length of the objects file.
+    offset: u64,
+    /// Where the commits, trees and tags are in the objects file, see [`PushedObjects`].
+    index: HashMap<SHA1, (ObjectType, u64)>,
+}
+
+impl Quarantine {
+    /// Creates the directory of a push under `MEGA_QUARANTINE_PATH`, the quarantine directory
+    /// under `MEGA_OBJ_LOCAL_PATH` when unset.
+    pub fn create() -> io::Result<Self> {
+        let root = env::var("MEGA_QUARANTINE_PATH")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| match env::var("MEGA_OBJ_LOCAL_PATH") {
+                Ok(path) => PathBuf::from(path).join("quarantine"),
+                Err(_) => env::temp_dir().join("mega-quarantine"),
+            });
+        Self::create_in(&root)
+    }
+
+    pub fn create_in(root: &Path) -> io::Result<Self> {
+        let dir = root.join(generate_id().to_string());
+        fs::create_dir_all(&dir)?;
+        let writer = BufWriter::new(File::create(dir.join(OBJECTS_FILE))?);
+        Ok(Quarantine {
+            dir,
+            writer,
+            count: 0,
+            offset: 0,
+            index: HashMap::new(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Appends `entry` as its type, id, length and content.
+    pub fn add(&mut self, entry: &Entry) -> io::Result<()> {
+        self.writer.write_all(&[entry.obj_type.to_u8()])?;
+        self.writer.write_all(&entry.hash.0)?;
+        self.writer
+            .write_all(&(entry.data.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&entry.data)?;
+        if matches!(
+            entry.obj_type,
+            ObjectType::Commit | ObjectType::Tree | ObjectType::Tag
+        ) {
+            self.index.insert(entry.hash, (entry.obj_type, self.offset));
+        }
+        self.offset += 1 + 20 + 8 + entry.data.len() as u64;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// The commits, trees and tags added so far, read back from the objects file.
+    pub fn pushed_objects(&mut self) -> io::Result<PushedObjects> {
+        self.writer.flush()?;
+        Ok(PushedObjects {
+            file: Some(Mutex::new(File::open(self.dir.join(OBJECTS_FILE))?)),
+            index: std::mem::take(&mut self.index),
+        })
+    }
+
+    /// The quarantined objects in the order they were added.
+    pub fn entries(&mut self) -> io::Result<QuarantinedEntries> {
+        self.writer.flush()?;
+        Ok(QuarantinedEntries {
+            reader: BufReader::new(File::open(self.dir.join(OBJECTS_FILE))?),
+        })
+    }
+}
+
+impl Drop for Quarantine {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.dir) {
+            tracing::warn!(
+                "failed to remove quarantine {}: {}",
+                self.dir.display(),
+                err
+            );
+        }
+    }
+}
+
+pub struct QuarantinedEntries {
+    reader: BufReader<File>,
+}
+
+/// Reads the entry following its type `obj_type`.
+fn read_entry(reader: &mut impl Read, obj_type: u8) -> io::Result<Entry> {
+    let obj_type = ObjectType::from_u8(obj_type)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    let mut hash = [0u8; 20];
+    reader.read_exact(&mut hash)?;
+    let mut len = [0u8; 8];
+    reader.read_exact(&mut len)?;
+    let mut data = vec![0u8; u64::from_le_bytes(len) as usize];
+    reader.read_exact(&mut data)?;
+    Ok(Entry {
+        obj_type,
+        data,
+        hash: SHA1(hash),
+        crc32: None,
+    })
+}
+
+impl Iterator for QuarantinedEntries {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut obj_type = [0u8; 1];
+        match self.reader.read(&mut obj_type) {
+            Ok(0) => None,
+            Ok(_) => Some(read_entry(&mut self.reader, obj_type[0])),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// The commits, trees and tags of a push, looked up by id for the checks of its ref updates.
+/// Only their place in the quarantine is held, each one is read back when it is asked for.
+#[derive(Default)]
+pub struct PushedObjects {
+    file: Option<Mutex<File>>,
+    index: HashMap<SHA1, (ObjectType, u64)>,
+}
+
+impl PushedObjects {
+    pub fn contains_key(&self, hash: &SHA1) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    /// The object `hash` of the push, None when it is not in the pack or cannot be read.
+    pub fn get(&self, hash: &SHA1) -> Option<Entry> {
+        let (_, offset) = self.index.get(hash)?;
+        match self.read_at(*offset) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                tracing::error!("failed to read {} from the quarantine: {}", hash, err);
+                None
+            }
+        }
+    }
+
+    /// The commits of the push, in no particular order.
+    pub fn commits(&self) -> impl Iterator<Item = Entry> + '_ {
+        self.index
+            .iter()
+            .filter(|(_, (obj_type, _))| *obj_type == ObjectType::Commit)
+            .filter_map(|(hash, _)| self.get(hash))
+    }
+
+    fn read_at(&self, offset: u64) -> io::Result<Entry> {
+        let Some(file) = &self.file else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no quarantine"));
+        };
+        let mut file = file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(&mut *file);
+        let mut obj_type = [0u8; 1];
+        reader.read_exact(&mut obj_type)?;
+        read_entry(&mut reader, obj_type[0])
+    }
+}
+
+/// The `entries` as the objects of a push, in a quarantine under the temporary directory.
+#[cfg(test)]
+pub(crate) fn pushed_objects(
+    entries: impl IntoIterator<Item = Entry>,
+) -> (Quarantine, PushedObjects) {
+    let mut quarantine = Quarantine::create_in(&env::temp_dir().join("mega_test_pushed")).unwrap();
+    for entry in entries {
+        quarantine.add(&entry).unwrap();
+    }
+    let pushed = quarantine.pushed_objects().unwrap();
+    (quarantine, pushed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use venus::hash::SHA1;
+    use venus::internal::object::types::ObjectType;
+    use venus::internal::pack::entry::Entry;
+
+    use super::{pushed_objects, Quarantine};
+
+    #[test]
+    fn test_quarantine() {
+        let root = env::temp_dir().join("mega_test_quarantine");
+        let entries = [
+            (ObjectType::Blob, b"hello".to_vec()),
+            (ObjectType::Tree, vec![]),
+        ]
+        .map(|(obj_type, data)| Entry {
+            obj_type,
+            hash: SHA1::new(&data),
+            data,
+            crc32: None,
+        });
+        let mut quarantine = Quarantine::create_in(&root).unwrap();
+        for entry in &entries {
+            quarantine.add(entry).unwrap();
+        }
+        assert_eq!(quarantine.len(), 2);
+        let read: Vec<Entry> = quarantine.entries().unwrap().map(Result::unwrap).collect();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].obj_type, ObjectType::Blob);
+        assert_eq!(read[0].data, b"hello");
+        assert_eq!(read[0].hash, entries[0].hash);
+        assert!(read[1].data.is_empty());
+
+        let dir = quarantine.dir().to_path_buf();
+        drop(quarantine);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_pushed_objects() {
+        let entries = [
+            (ObjectType::Blob, b"hello".to_vec()),
+            (ObjectType::Commit, b"commit".to_vec()),
+            (ObjectType::Tree, b"tree".to_vec()),
+        ]
+        .map(|(obj_type, data)| Entry {
+            obj_type,
+            hash: SHA1::new(&data),
+            data,
+            crc32: None,
+        });
+        let (_quarantine, pushed) = pushed_objects(entries.clone());
+        // the blobs are only read when the push is merged
+        assert!(!pushed.contains_key(&entries[0].hash));
+        assert!(pushed.get(&entries[0].hash).is_none());
+        let tree = pushed.get(&entries[2].hash).unwrap();
+        assert_eq!(tree.obj_type, ObjectType::Tree);
+        assert_eq!(tree.data, b"tree");
+        let commits: Vec<Entry> = pushed.commits().collect();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].data, b"commit");
+    }
+}
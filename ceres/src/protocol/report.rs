@@ -0,0 +1,273 @@
+//!
+//! Presentation of rejected pushes: every reason receive-pack can refuse a ref update is a
+//! `PushRejection`, rendered once into the short reason of the `ng` status line and into the
+//! multi-line message sent to the client on the progress sideband, both in the language of the client.
+//!
+use common::i18n::Locale;
+use jupiter::hooks::HookRefusal;
+
+use crate::lfs::lfs_structs::Lock;
+use crate::policy::Violation;
+
+const DOC_PAGE: &str = "docs/troubleshooting.md";
+
+/// Lines of details shown per rejection, the rest is summarized.
+const MAX_DETAIL_LINES: usize = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PushRejection {
+    /// The pack could not be decoded or saved.
+    Unpack,
+    /// The pushed commits modify paths locked by other users.
+    LockedPaths(Vec<Lock>),
+    /// The locks of the ref could not be loaded, the update is refused rather than unchecked.
+    LockCheckFailed,
+    /// Blobs above the size limit, as `(id, size)`, the limit is in bytes.
+    BlobTooLarge {
+        blobs: Vec<(String, usize)>,
+        limit: usize,
+    },
+    /// Objects the update refers to are neither in the pack nor in the repository.
+    MissingObjects(Vec<String>),
+    /// The objects of the update could not be looked up, the update is refused rather than
+    /// unchecked.
+    ConnectivityCheckFailed,
+    /// The ref is no longer at the old id of the update, someone pushed in the meantime.
+    StaleRef,
+    /// The update breaks rules of the `.mega/policy.toml` files of the directories it changes.
+    PolicyViolations(Vec<Violation>),
+    /// The policies could not be loaded, the update is refused rather than unchecked.
+    PolicyCheckFailed,
+    /// Saving the objects and the ref updates failed, or a post-merge hook failed, the merge
+    /// was rolled back.
+    MergeFailed,
+    /// A push hook refused the update, with the reason it gave.
+    HookRefused { hook: String, reason: String },
+    /// A push hook could not be run, the update is refused rather than unchecked.
+    HookFailed { hook: String },
+    /// Another update of an `atomic` push was rejected, none is applied.
+    AtomicPushFailed,
+}
+
+impl PushRejection {
+    /// Stable key of the documentation section, also the anchor in the troubleshooting page.
+    pub fn doc_key(&self) -> &'static str {
+        match self {
+            PushRejection::Unpack => "push-unpack-failed",
+            PushRejection::LockedPaths(_) => "push-locked-paths",
+            PushRejection::LockCheckFailed => "push-lock-check-failed",
+            PushRejection::BlobTooLarge { .. } => "push-blob-too-large",
+            PushRejection::MissingObjects(_) => "push-missing-objects",
+            PushRejection::ConnectivityCheckFailed => "push-connectivity-check-failed",
+            PushRejection::StaleRef => "push-stale-ref",
+            PushRejection::PolicyViolations(_) => "push-policy-violations",
+            PushRejection::PolicyCheckFailed => "push-policy-check-failed",
+            PushRejection::MergeFailed => "push-merge-failed",
+            PushRejection::HookRefused { .. } => "push-hook-refused",
+            PushRejection::HookFailed { .. } => "push-hook-failed",
+            PushRejection::AtomicPushFailed => "push-atomic-failed",
+        }
+    }
+
+    /// Single line reason, used in the `ng <ref> <reason>` report status.
+    pub fn reason(&self, locale: Locale) -> String {
+        let key = format!("{}-reason", self.doc_key());
+        match self {
+            PushRejection::Unpack
+            | PushRejection::LockCheckFailed
+            | PushRejection::ConnectivityCheckFailed
+            | PushRejection::StaleRef
+            | PushRejection::PolicyCheckFailed
+            | PushRejection::MergeFailed
+            | PushRejection::AtomicPushFailed => locale.tr(&key, &[]),
+            PushRejection::LockedPaths(locks) => {
+                locale.tr(&key, &[("count", &locks.len().to_string())])
+            }
+            PushRejection::BlobTooLarge { blobs, limit } => locale.tr(
+                &key,
+                &[
+                    ("count", &blobs.len().to_string()),
+                    ("limit", &limit.to_string()),
+                ],
+            ),
+            PushRejection::MissingObjects(ids) => {
+                locale.tr(&key, &[("count", &ids.len().to_string())])
+            }
+            PushRejection::PolicyViolations(violations) => {
+                locale.tr(&key, &[("count", &violations.len().to_string())])
+            }
+            PushRejection::HookRefused { hook, .. } | PushRejection::HookFailed { hook } => {
+                locale.tr(&key, &[("hook", hook)])
+            }
+        }
+    }
+
+    fn details(&self, locale: Locale) -> Vec<String> {
+        match self {
+            PushRejection::LockedPaths(locks) => locks
+                .iter()
+                .map(|lock| {
+                    let owner = lock.owner.as_ref().map(|u| u.name.as_str()).unwrap_or("");
+                    locale.tr(
+                        "push-locked-path",
+                        &[
+                            ("path", &lock.path),
+                            ("owner", owner),
+                            ("time", &lock.locked_at),
+                        ],
+                    )
+                })
+                .collect(),
+            PushRejection::BlobTooLarge { blobs, .. } => blobs
+                .iter()
+                .map(|(id, size)| {
+                    locale.tr(
+                        "push-large-blob",
+                        &[("id", id), ("size", &size.to_string())],
+                    )
+                })
+                .collect(),
+            PushRejection::MissingObjects(ids) => ids.clone(),
+            PushRejection::PolicyViolations(violations) => violations
+                .iter()
+                .map(|violation| violation_detail(violation, locale))
+                .collect(),
+            PushRejection::HookRefused { reason, .. } => {
+                reason.lines().map(str::to_owned).collect()
+            }
+            PushRejection::Unpack
+            | PushRejection::LockCheckFailed
+            | PushRejection::ConnectivityCheckFailed
+            | PushRejection::StaleRef
+            | PushRejection::PolicyCheckFailed
+            | PushRejection::MergeFailed
+            | PushRejection::HookFailed { .. }
+            | PushRejection::AtomicPushFailed => vec![],
+        }
+    }
+
+    /// Multi-line message explaining the rejection of `ref_name`, every line ends with LF.
+    pub fn message(&self, ref_name: &str, locale: Locale) -> Vec<String> {
+        let reason = self.reason(locale);
+        let mut lines = vec![locale.tr("push-rejected", &[("ref", ref_name), ("reason", &reason)])];
+        let details = self.details(locale);
+        for detail in details.iter().take(MAX_DETAIL_LINES) {
+            lines.push(format!("  {}", detail));
+        }
+        if details.len() > MAX_DETAIL_LINES {
+            let more = (details.len() - MAX_DETAIL_LINES).to_string();
+            lines.push(format!("  {}", locale.tr("push-more", &[("count", &more)])));
+        }
+        let hint = locale.tr(&format!("{}-hint", self.doc_key()), &[]);
+        lines.push(locale.tr("push-hint", &[("hint", &hint)]));
+        let doc = format!("{}#{}", DOC_PAGE, self.doc_key());
+        lines.push(locale.tr("push-see-doc", &[("doc", &doc)]));
+        lines.into_iter().map(|line| line + "\n").collect()
+    }
+}
+
+impl From<HookRefusal> for PushRejection {
+    fn from(refusal: HookRefusal) -> Self {
+        match refusal.reason {
+            Some(reason) => PushRejection::HookRefused {
+                hook: refusal.hook,
+                reason,
+            },
+            None => PushRejection::HookFailed { hook: refusal.hook },
+        }
+    }
+}
+
+fn violation_detail(violation: &Violation, locale: Locale) -> String {
+    // the directories are shown absolute, the root as `/`
+    let dir = |directory: &str| format!("/{}", directory);
+    match violation {
+        Violation::ProtectedBranch { directory } => {
+            locale.tr("push-policy-protected", &[("dir", &dir(directory))])
+        }
+        Violation::NotOwner {
+            path,
+            directory,
+            owners,
+        } => locale.tr(
+            "push-policy-owner",
+            &[
+                ("path", path),
+                ("owners", &owners.join(", ")),
+                ("dir", &dir(directory)),
+            ],
+        ),
+        Violation::FileTooLarge {
+            path,
+            directory,
+            size,
+            limit,
+        } => locale.tr(
+            "push-policy-file-size",
+            &[
+                ("path", path),
+                ("size", &size.to_string()),
+                ("limit", &limit.to_string()),
+                ("dir", &dir(directory)),
+            ],
+        ),
+        Violation::MissingCheck { directory, check } => locale.tr(
+            "push-policy-check",
+            &[("check", check), ("dir", &dir(directory))],
+        ),
+        Violation::InvalidPolicy { directory, error } => locale.tr(
+            "push-policy-invalid",
+            &[("dir", &dir(directory)), ("error", error)],
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::i18n::Locale;
+
+    use crate::lfs::lfs_structs::{Lock, User};
+
+    use super::PushRejection;
+
+    #[test]
+    fn test_locked_paths_message() {
+        let rejection = PushRejection::LockedPaths(vec![Lock {
+            id: String::from("1"),
+            path: String::from("assets/x.psd"),
+            locked_at: String::from("2024-03-01T00:00:00Z"),
+            owner: Some(User {
+                name: String::from("alice"),
+            }),
+        }]);
+        assert_eq!(rejection.reason(Locale::En), "1 locked path(s)");
+        let message = rejection.message("refs/heads/main", Locale::En);
+        assert_eq!(
+            message[0],
+            "error: update of refs/heads/main rejected: 1 locked path(s)\n"
+        );
+        assert_eq!(
+            message[1],
+            "  assets/x.psd (locked by alice at 2024-03-01T00:00:00Z)\n"
+        );
+        assert_eq!(
+            message.last().unwrap(),
+            "hint: see docs/troubleshooting.md#push-locked-paths\n"
+        );
+
+        let message = rejection.message("refs/heads/main", Locale::Zh);
+        assert_eq!(
+            message[0],
+            "错误：refs/heads/main 的更新被拒绝：1 个路径已被锁定\n"
+        );
+    }
+
+    #[test]
+    fn test_details_are_truncated() {
+        let ids = (0..25).map(|i| format!("{:040x}", i)).collect();
+        let message = PushRejection::MissingObjects(ids).message("refs/heads/main", Locale::En);
+        // header, 20 ids, summary, 2 hints
+        assert_eq!(message.len(), 24);
+        assert_eq!(message[21], "  ... and 5 more\n");
+    }
+}
@@ -0,0 +1,300 @@
+//!
+//! Shallow clones and fetches. The client lists the commits it has without their parents in
+//! `shallow` lines and asks for a new boundary with `deepen <depth>`, `deepen-since <time>` or
+//! `deepen-not <ref>`. The server answers with the commits that become shallow and the ones
+//! whose parents are now sent (`unshallow`), and the pack stops at that boundary.
+//!
+use std::collections::{HashSet, VecDeque};
+use std::str::FromStr;
+
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::internal::revwalk::{Boundary, CommitGraph};
+use venus::repo::Repo;
+
+use crate::protocol::quarantine::PushedObjects;
+use crate::protocol::snapshot::RefSnapshot;
+use crate::protocol::PackProtocol;
+
+/// The shallow lines of an upload-pack request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShallowRequest {
+    /// The shallow commits of the client.
+    pub shallows: Vec<SHA1>,
+    pub depth: Option<usize>,
+    /// The depth is counted from the shallow commits of the client rather than from the wants.
+    pub relative: bool,
+    /// Only the commits made at this time or later are sent.
+    pub since: Option<usize>,
+    /// Only the commits not reachable from these refs are sent.
+    pub not: Vec<String>,
+}
+
+impl ShallowRequest {
+    /// Reads `line` into the request, returns `Ok(false)` when it is not a shallow line.
+    pub fn parse_line(&mut self, line: &str) -> Result<bool, String> {
+        let line = line.trim_end();
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "shallow" => {
+                let id =
+                    SHA1::from_str(arg).map_err(|_| format!("invalid shallow line: {}", line))?;
+                self.shallows.push(id);
+            }
+            "deepen" => match arg.parse() {
+                Ok(depth) if depth > 0 => self.depth = Some(depth),
+                _ => return Err(format!("invalid depth: {}", arg)),
+            },
+            "deepen-since" => {
+                let since = arg
+                    .parse()
+                    .map_err(|_| format!("invalid deepen-since: {}", arg))?;
+                self.since = Some(since);
+            }
+            "deepen-not" if !arg.is_empty() => self.not.push(arg.to_owned()),
+            "deepen-relative" => self.relative = true,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Whether the client asked for a new boundary, it then expects the shallow update.
+    pub fn is_deepen(&self) -> bool {
+        self.depth.is_some() || self.is_rev_list()
+    }
+
+    /// The boundary is set by dates and refs rather than by a depth.
+    fn is_rev_list(&self) -> bool {
+        self.since.is_some() || !self.not.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.depth.is_some() && self.is_rev_list() {
+            return Err(String::from(
+                "deepen and deepen-since (or deepen-not) cannot be used together",
+            ));
+        }
+        if self.relative && self.is_rev_list() {
+            return Err(String::from(
+                "deepen-relative cannot be used with deepen-since or deepen-not",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The `shallow` and `unshallow` lines of the answer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShallowUpdate {
+    pub shallow: Vec<SHA1>,
+    pub unshallow: Vec<SHA1>,
+}
+
+impl ShallowUpdate {
+    /// The update of `boundary` sent to a client whose shallow commits are `shallows`.
+    pub fn new(boundary: &Boundary, shallows: &[SHA1]) -> Self {
+        let mut shallow: Vec<SHA1> = boundary
+            .shallow
+            .iter()
+            .filter(|id| !shallows.contains(id))
+            .copied()
+            .collect();
+        let mut unshallow: Vec<SHA1> = shallows
+            .iter()
+            .filter(|id| boundary.deepened.contains(id))
+            .copied()
+            .collect();
+        shallow.sort_by_key(|id| id.0);
+        unshallow.sort_by_key(|id| id.0);
+        unshallow.dedup();
+        ShallowUpdate { shallow, unshallow }
+    }
+
+    /// The shallow commits of the client once it received the pack.
+    pub fn apply(&self, shallows: &[SHA1]) -> HashSet<SHA1> {
+        shallows
+            .iter()
+            .filter(|id| !self.unshallow.contains(id))
+            .chain(&self.shallow)
+            .copied()
+            .collect()
+    }
+}
+
+impl PackProtocol {
+    /// Computes the shallow update of `request` for a fetch of `want`. The error is the message
+    /// sent back to the client.
+    pub(crate) async fn shallow_update(
+        &self,
+        repo: &Repo,
+        snapshot: &RefSnapshot,
+        want: &[String],
+        request: &ShallowRequest,
+    ) -> Result<ShallowUpdate, String> {
+        request.validate()?;
+        let wants: Vec<SHA1> = want
+            .iter()
+            .filter_map(|id| SHA1::from_str(id).ok())
+            .collect();
+
+        if let Some(depth) = request.depth {
+            // relative to the current boundary, its commits are at depth 0
+            let (heads, depth) = if request.relative && !request.shallows.is_empty() {
+                (request.shallows.clone(), depth + 1)
+            } else {
+                (wants, depth)
+            };
+            let commits = self
+                .load_history(repo, &heads, |_, level| level >= depth)
+                .await;
+            let graph = CommitGraph::new(commits);
+            let boundary = graph.boundary_by_depth(&heads, depth);
+            return Ok(ShallowUpdate::new(&boundary, &request.shallows));
+        }
+
+        let mut excluded_tips = Vec::new();
+        for name in &request.not {
+            excluded_tips.push(resolve_ref(snapshot, name)?);
+        }
+        let mut commits = self.load_history(repo, &excluded_tips, |_, _| false).await;
+        let excluded: HashSet<SHA1> = commits.iter().map(|c| c.id).collect();
+        let since = request.since.unwrap_or(0);
+        let include =
+            |commit: &Commit| commit.committer.timestamp >= since && !excluded.contains(&commit.id);
+        // stops at the first excluded commits, older ones behind them are not looked for
+        commits.extend(
+            self.load_history(repo, &wants, |commit, _| !include(commit))
+                .await,
+        );
+        let graph = CommitGraph::new(commits);
+        graph
+            .boundary_by_rev_list(&wants, include)
+            .map(|boundary| ShallowUpdate::new(&boundary, &request.shallows))
+            .ok_or_else(|| String::from("no commits selected for shallow requests"))
+    }
+
+    /// The commits reachable from `heads`, breadth first. The parents of a commit for which
+    /// `stop` holds are not loaded, it gets the commit and its depth, a head being at depth 1.
+    async fn load_history(
+        &self,
+        repo: &Repo,
+        heads: &[SHA1],
+        stop: impl Fn(&Commit, usize) -> bool,
+    ) -> Vec<Commit> {
+        let mut commits = Vec::new();
+        let mut visited = HashSet::new();
+        let mut queue: VecDeque<(SHA1, usize)> = heads.iter().map(|id| (*id, 1)).collect();
+        while let Some((id, level)) = queue.pop_front() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let Some(commit) = self
+                .load_commit(repo, &id.to_plain_str(), &PushedObjects::default())
+                .await
+            else {
+                continue;
+            };
+            if !stop(&commit, level) {
+                queue.extend(commit.parent_commit_ids.iter().map(|p| (*p, level + 1)));
+            }
+            commits.push(commit);
+        }
+        commits
+    }
+}
+
+/// The target of the ref `name` of a `deepen-not`, a full name or a branch or tag name.
+fn resolve_ref(snapshot: &RefSnapshot, name: &str) -> Result<SHA1, String> {
+    let candidates = [
+        name.to_owned(),
+        format!("refs/heads/{}", name),
+        format!("refs/tags/{}", name),
+    ];
+    let found: Vec<_> = snapshot
+        .refs()
+        .iter()
+        .filter(|r| candidates.contains(&r.ref_name))
+        .collect();
+    match found.as_slice() {
+        [r] => SHA1::from_str(&r.ref_git_id).map_err(|_| format!("invalid ref {}", name)),
+        [] => Err(format!("deepen-not is not a ref: {}", name)),
+        _ => Err(format!("ambiguous deepen-not: {}", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use venus::hash::SHA1;
+    use venus::internal::revwalk::CommitGraph;
+    use venus::test_support::{commit, id};
+
+    use super::{ShallowRequest, ShallowUpdate};
+
+    /// 1 - 2 - 3 - 5 - 6
+    ///      \     /
+    ///        4 -
+    fn graph() -> CommitGraph {
+        CommitGraph::new(vec![
+            commit(1, &[]),
+            commit(2, &[1]),
+            commit(3, &[2]),
+            commit(4, &[2]),
+            commit(5, &[3, 4]),
+            commit(6, &[5]),
+        ])
+    }
+
+    fn ids(list: &[u8]) -> Vec<SHA1> {
+        let mut ids: Vec<SHA1> = list.iter().map(|n| id(*n)).collect();
+        ids.sort_by_key(|id| id.0);
+        ids
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let mut request = ShallowRequest::default();
+        for line in [
+            format!("shallow {}\n", id(3)),
+            String::from("deepen 2\n"),
+            String::from("deepen-relative\n"),
+        ] {
+            assert_eq!(request.parse_line(&line), Ok(true));
+        }
+        assert_eq!(request.parse_line(&format!("have {}\n", id(1))), Ok(false));
+        assert!(request.parse_line("deepen 0\n").is_err());
+        assert!(request.parse_line("deepen-since yesterday\n").is_err());
+        assert_eq!(request.shallows, vec![id(3)]);
+        assert_eq!(request.depth, Some(2));
+        assert!(request.relative && request.is_deepen());
+        assert!(request.validate().is_ok());
+
+        request.parse_line("deepen-not refs/heads/main").unwrap();
+        assert!(request.validate().is_err());
+    }
+
+    #[test]
+    fn test_shallow_update() {
+        let graph = graph();
+        let boundary = graph.boundary_by_depth(&[id(6)], 3);
+        let update = ShallowUpdate::new(&boundary, &[]);
+        assert_eq!(update.shallow, ids(&[3, 4]));
+        assert!(update.unshallow.is_empty());
+
+        // deepening a clone of depth 1 by 2 more commits
+        let update = ShallowUpdate::new(&boundary, &[id(6)]);
+        assert_eq!(update.unshallow, ids(&[6]));
+        assert_eq!(update.apply(&[id(6)]), ids(&[3, 4]).into_iter().collect());
+
+        // the whole history is shorter than the depth
+        let boundary = graph.boundary_by_depth(&[id(6)], 10);
+        assert_eq!(ShallowUpdate::new(&boundary, &[id(2)]).unshallow, ids(&[2]));
+
+        // committed at 1003 or later
+        let boundary = graph
+            .boundary_by_rev_list(&[id(6)], |c| c.committer.timestamp >= 1003)
+            .unwrap();
+        let update = ShallowUpdate::new(&boundary, &[id(5)]);
+        assert_eq!(update.shallow, ids(&[3, 4]));
+        assert_eq!(update.unshallow, ids(&[5]));
+    }
+}
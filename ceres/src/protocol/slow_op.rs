@@ -0,0 +1,240 @@
+//!
+//! Detection of the slow clones, fetches and pushes. An operation still running past the
+//! threshold is logged, and with the `profiling` feature the whole process is sampled while it
+//! goes on: the flamegraph is saved in the object storage for the analysis offline. Nothing
+//! is sampled while every operation is fast.
+//!
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::task::JoinHandle;
+
+use jupiter::raw_storage::RawStorage;
+
+/// The directory of the flamegraphs in the object storage.
+pub const SLOW_OP_DIR: &str = "slow_ops";
+
+/// Held for the duration of an operation, a slow one is logged once dropped.
+pub struct SlowOp {
+    operation: &'static str,
+    path: String,
+    threshold: Duration,
+    started_at: Instant,
+    watchdog: JoinHandle<()>,
+    /// Dropped with the operation, which ends its profile.
+    _running: mpsc::Sender<()>,
+}
+
+impl SlowOp {
+    /// Watches `operation` on `path`, `None` when `threshold` is zero. The profile lasts
+    /// `profile` at most, none is captured when it is zero.
+    pub fn start(
+        operation: &'static str,
+        path: &Path,
+        threshold: Duration,
+        profile: Duration,
+        storage: Arc<dyn RawStorage>,
+    ) -> Option<Self> {
+        if threshold.is_zero() {
+            return None;
+        }
+        // the samples are only taken with the feature, the operation is still logged without
+        let profile = if cfg!(all(unix, feature = "profiling")) {
+            profile
+        } else {
+            Duration::ZERO
+        };
+        let path = path.display().to_string();
+        let (running, done) = mpsc::channel();
+        let watchdog = {
+            let path = path.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(threshold).await;
+                tracing::warn!(
+                    target: "slow_op",
+                    "{} on {} is still running after {:?}",
+                    operation,
+                    path,
+                    threshold
+                );
+                if profile.is_zero() {
+                    return;
+                }
+                let flamegraph = match tokio::task::spawn_blocking(move || capture(profile, done))
+                    .await
+                {
+                    Ok(Ok(flamegraph)) => flamegraph,
+                    Ok(Err(e)) => {
+                        tracing::warn!(target: "slow_op", "no profile of {}: {}", operation, e);
+                        return;
+                    }
+                    Err(e) => {
+                        tracing::error!(target: "slow_op", "profile of {} failed: {}", operation, e);
+                        return;
+                    }
+                };
+                let id = format!("{:016x}", common::utils::generate_id());
+                match storage.put_object(SLOW_OP_DIR, &id, &flamegraph).await {
+                    Ok(location) => tracing::warn!(
+                        target: "slow_op",
+                        "flamegraph of {} on {} saved to {}",
+                        operation,
+                        path,
+                        location
+                    ),
+                    Err(e) => tracing::error!(
+                        target: "slow_op",
+                        "failed to save the flamegraph of {}: {}",
+                        operation,
+                        e
+                    ),
+                }
+            })
+        };
+        Some(SlowOp {
+            operation,
+            path,
+            threshold,
+            started_at: Instant::now(),
+            watchdog,
+            _running: running,
+        })
+    }
+}
+
+impl Drop for SlowOp {
+    /// A fast operation stops its watchdog, a slow one leaves it saving the profile.
+    fn drop(&mut self) {
+        let elapsed = self.started_at.elapsed();
+        if elapsed < self.threshold {
+            self.watchdog.abort();
+        } else {
+            tracing::warn!(
+                target: "slow_op",
+                "{} on {} took {} ms, over the threshold of {} ms",
+                self.operation,
+                self.path,
+                elapsed.as_millis(),
+                self.threshold.as_millis()
+            );
+        }
+    }
+}
+
+/// Samples the process until `done` is disconnected, or for `profile` at most, and renders
+/// the flamegraph as SVG.
+#[cfg(all(unix, feature = "profiling"))]
+fn capture(profile: Duration, done: mpsc::Receiver<()>) -> Result<Vec<u8>, String> {
+    // one profile at a time in a process, another slow operation may be sampled already
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(99)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| e.to_string())?;
+    let _ = done.recv_timeout(profile);
+    let report = guard.report().build().map_err(|e| e.to_string())?;
+    if report.data.is_empty() {
+        return Err(String::from("no samples"));
+    }
+    let mut flamegraph = Vec::new();
+    report
+        .flamegraph(&mut flamegraph)
+        .map_err(|e| e.to_string())?;
+    Ok(flamegraph)
+}
+
+#[cfg(not(all(unix, feature = "profiling")))]
+fn capture(_profile: Duration, _done: mpsc::Receiver<()>) -> Result<Vec<u8>, String> {
+    Err(String::from("mega is built without the profiling feature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use jupiter::raw_storage::local_storage::LocalStorage;
+
+    use super::{SlowOp, SLOW_OP_DIR};
+
+    fn storage() -> (PathBuf, Arc<LocalStorage>) {
+        let dir = std::env::temp_dir().join(format!("slow_op_{}", common::utils::generate_id()));
+        (dir.clone(), Arc::new(LocalStorage::init(dir)))
+    }
+
+    #[tokio::test]
+    async fn test_fast_op_is_not_profiled() {
+        let (dir, storage) = storage();
+        assert!(SlowOp::start(
+            "push",
+            Path::new("/"),
+            Duration::ZERO,
+            Duration::ZERO,
+            storage.clone()
+        )
+        .is_none());
+        let slow_op = SlowOp::start(
+            "push",
+            Path::new("/"),
+            Duration::from_millis(100),
+            Duration::from_secs(1),
+            storage,
+        );
+        drop(slow_op);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!dir.join(SLOW_OP_DIR).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "profiling"))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_slow_op_is_profiled() {
+        let (dir, storage) = storage();
+        let slow_op = SlowOp::start(
+            "push",
+            Path::new("/"),
+            Duration::from_millis(50),
+            Duration::from_secs(10),
+            storage,
+        );
+        // busy, so that the samples have something to show
+        let started_at = std::time::Instant::now();
+        let mut n: u64 = 0;
+        while started_at.elapsed() < Duration::from_secs(3) {
+            n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+        }
+        drop(slow_op);
+        let objects = dir.join(SLOW_OP_DIR);
+        let mut svg = Vec::new();
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            svg = walkdir(&objects);
+            if !svg.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(svg.len(), 1);
+        assert!(std::fs::read_to_string(&svg[0]).unwrap().contains("<svg"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "profiling"))]
+    fn walkdir(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return files;
+        };
+        for entry in entries {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(walkdir(&path));
+            } else {
+                files.push(path);
+            }
+        }
+        files
+    }
+}
@@ -0,0 +1,111 @@
+//!
+//! Read consistency of clones and fetches while pushes land. The refs are read once when the
+//! negotiation starts and both the advertisement and the pack follow that snapshot, and the
+//! wanted commits are leased so that archiving cannot remove them before the pack is sent.
+//!
+use callisto::refs;
+use common::errors::MegaError;
+use common::utils::ZERO_ID;
+use jupiter::context::Context;
+use venus::repo::Repo;
+
+/// A lease not released by its reader, e.g. after a crash, expires after this long.
+const READ_LEASE_TTL_SECS: i64 = 60 * 60;
+
+#[derive(Debug, Clone)]
+pub struct RefSnapshot {
+    refs: Vec<refs::Model>,
+}
+
+impl RefSnapshot {
+    pub async fn take(context: &Context, repo: &Repo) -> Result<Self, MegaError> {
+        let refs = context.services.mega_storage.get_repo_refs(repo).await?;
+        Ok(RefSnapshot { refs })
+    }
+
+    pub fn refs(&self) -> &[refs::Model] {
+        &self.refs
+    }
+
+    /// The target of `refs/heads/main`, the zero id when the branch does not exist.
+    pub fn head(&self) -> String {
+        self.refs
+            .iter()
+            .find(|r| r.ref_name == "refs/heads/main")
+            .map(|r| r.ref_git_id.clone())
+            .unwrap_or(ZERO_ID.to_string())
+    }
+
+    pub fn is_tip(&self, id: &str) -> bool {
+        self.refs.iter().any(|r| r.ref_git_id == id)
+    }
+}
+
+pub struct ReadLease {
+    id: i64,
+}
+
+impl ReadLease {
+    pub async fn acquire(
+        context: &Context,
+        repo: &Repo,
+        commit_ids: &[String],
+    ) -> Result<Self, MegaError> {
+        let ttl = chrono::Duration::try_seconds(READ_LEASE_TTL_SECS).unwrap_or_default();
+        let id = context
+            .services
+            .mega_storage
+            .acquire_read_lease(repo, commit_ids, ttl)
+            .await?;
+        Ok(ReadLease { id })
+    }
+
+    /// Failures are only logged, the lease then expires on its own.
+    pub async fn release(self, context: &Context) {
+        if let Err(err) = context
+            .services
+            .mega_storage
+            .release_read_lease(self.id)
+            .await
+        {
+            tracing::warn!("failed to release read lease {}: {}", self.id, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use callisto::db_enums::RefType;
+    use callisto::refs;
+    use common::utils::ZERO_ID;
+
+    use super::RefSnapshot;
+
+    fn git_ref(name: &str, id: &str) -> refs::Model {
+        let now = chrono::Utc::now().naive_utc();
+        refs::Model {
+            id: 0,
+            repo_id: 0,
+            ref_name: name.to_owned(),
+            ref_git_id: id.to_owned(),
+            ref_type: RefType::Branch,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    #[test]
+    fn test_snapshot_head() {
+        let main = format!("{:040x}", 1);
+        let snapshot = RefSnapshot {
+            refs: vec![
+                git_ref("refs/heads/feature", &format!("{:040x}", 2)),
+                git_ref("refs/heads/main", &main),
+            ],
+        };
+        assert_eq!(snapshot.head(), main);
+        assert!(snapshot.is_tip(&main));
+        assert!(!snapshot.is_tip(ZERO_ID));
+        assert_eq!(RefSnapshot { refs: vec![] }.head(), ZERO_ID);
+    }
+}
@@ -0,0 +1,162 @@
+//!
+//! Bandwidth caps of the packs sent to the clones and fetches, set by user in
+//! [`Settings::upload_bandwidth_limits`](jupiter::settings::Settings), so that the bulk
+//! mirroring jobs can be given less than the developers.
+//!
+//! Every user capped has a token bucket shared by all its transfers, every anonymous client
+//! one by address: the writer of a pack takes as many tokens as it sends bytes and waits when
+//! the bucket runs out. The bucket holds one second of the cap, a transfer starting after an
+//! idle time sends that much at once.
+//!
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use super::PackProtocol;
+
+/// Buckets kept before those idle are dropped, the anonymous clients add one per address.
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+pub struct TokenBucket {
+    /// Bytes per second, also the capacity of the bucket.
+    rate: u64,
+    /// The tokens, negative when more was taken than the bucket held, and when they were
+    /// counted.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate.max(1),
+            state: Mutex::new((rate.max(1) as f64, Instant::now())),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Takes `bytes` tokens, returns how long to wait before sending them. The tokens are taken
+    /// at once even if missing, the transfers sharing the bucket wait in the order they took.
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.1).as_secs_f64() * self.rate as f64;
+        let tokens = (state.0 + refill).min(self.rate as f64) - bytes as f64;
+        *state = (tokens, now);
+        if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate as f64)
+        }
+    }
+
+    /// Whether the bucket refilled since it was last taken from, dropping it then changes
+    /// nothing to the transfers.
+    fn is_full(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        let refill = state.1.elapsed().as_secs_f64() * self.rate as f64;
+        state.0 + refill >= self.rate as f64
+    }
+
+    /// Waits until `bytes` may be sent.
+    pub async fn take(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Whose transfers share a bucket: a user, or an anonymous client by its address. The
+/// anonymous clients without an address share the one of None.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketKey {
+    User(String),
+    Anonymous(Option<String>),
+}
+
+impl BucketKey {
+    fn new(user: Option<&str>, ip: Option<&str>) -> Self {
+        match user {
+            Some(user) => BucketKey::User(user.to_owned()),
+            None => BucketKey::Anonymous(ip.map(str::to_owned)),
+        }
+    }
+}
+
+fn buckets() -> &'static Mutex<HashMap<BucketKey, Arc<TokenBucket>>> {
+    static BUCKETS: OnceLock<Mutex<HashMap<BucketKey, Arc<TokenBucket>>>> = OnceLock::new();
+    BUCKETS.get_or_init(Default::default)
+}
+
+/// The bucket of `key` at `kbps`, a new one if the cap changed.
+fn bucket(key: BucketKey, kbps: u64) -> Arc<TokenBucket> {
+    let rate = kbps.saturating_mul(1024);
+    let mut buckets = buckets().lock().unwrap();
+    if buckets.len() >= MAX_IDLE_BUCKETS && !buckets.contains_key(&key) {
+        buckets.retain(|_, bucket| Arc::strong_count(bucket) > 1 || !bucket.is_full());
+    }
+    let bucket = buckets
+        .entry(key)
+        .or_insert_with(|| Arc::new(TokenBucket::new(rate)));
+    if bucket.rate() != rate {
+        *bucket = Arc::new(TokenBucket::new(rate));
+    }
+    bucket.clone()
+}
+
+impl PackProtocol {
+    /// The bucket the pack sent to this client is written through, None if it is not capped.
+    pub fn upload_throttle(&self) -> Option<Arc<TokenBucket>> {
+        let user = self.client.user.as_deref();
+        let kbps = self
+            .context
+            .settings
+            .current()
+            .upload_bandwidth_limits
+            .get(user);
+        (kbps > 0).then(|| bucket(BucketKey::new(user, self.client.ip.as_deref()), kbps))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{bucket, BucketKey, TokenBucket};
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        // a second of the rate is sent at once
+        assert_eq!(bucket.reserve(600), Duration::ZERO);
+        assert_eq!(bucket.reserve(400), Duration::ZERO);
+        // then it is paced, a transfer waits for those before it
+        let wait = bucket.reserve(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+        let wait = bucket.reserve(500);
+        assert!(wait > Duration::from_millis(950) && wait <= Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_bucket_by_user() {
+        let key = BucketKey::new;
+        let a = bucket(key(Some("mirror-bot"), Some("10.0.0.1")), 512);
+        assert_eq!(a.rate(), 512 * 1024);
+        // a user keeps its bucket from every address
+        assert!(Arc::ptr_eq(
+            &a,
+            &bucket(key(Some("mirror-bot"), Some("10.0.0.2")), 512)
+        ));
+        assert!(!Arc::ptr_eq(&a, &bucket(key(None, Some("10.0.0.1")), 512)));
+        assert_eq!(bucket(key(Some("mirror-bot"), None), 64).rate(), 64 * 1024);
+
+        // the anonymous clients have a bucket by address
+        let b = bucket(key(None, Some("192.0.2.1")), 512);
+        assert!(Arc::ptr_eq(&b, &bucket(key(None, Some("192.0.2.1")), 512)));
+        assert!(!Arc::ptr_eq(&b, &bucket(key(None, Some("192.0.2.2")), 512)));
+    }
+}
@@ -0,0 +1,507 @@
+//!
+//! Protocol v2 of upload-pack, asked for by a client with the `version=2` parameter of the
+//! `Git-Protocol` header. The advertisement lists the capabilities of the server instead of
+//! the refs, the client then sends one command per request: `ls-refs` for the refs it wants to
+//! see and `fetch` for the negotiation and the pack.
+//!
+//! A request is a list of pkt-lines: `command=<name>`, the capabilities of the client, a
+//! delim-pkt, the arguments of the command and a flush-pkt. The pack of `fetch` is always sent
+//! multiplexed in side-band-64k.
+//!
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use tracing::Instrument;
+
+use callisto::db_enums::OperationType;
+use common::utils::ZERO_ID;
+use mercury::internal::pack::filter::FilterSpec;
+use venus::repo::Repo;
+
+use crate::protocol::pack::{add_pkt_line_string, PKT_LINE_END_MARKER};
+use crate::protocol::shallow::ShallowRequest;
+use crate::protocol::snapshot::ReadLease;
+use crate::protocol::{Capability, PackProtocol, SideBind};
+
+pub const PKT_LINE_DELIM_MARKER: &[u8; 4] = b"0001";
+
+/// Data of a side-band-64k packet, the largest pkt-line is 65520 bytes with its length and band.
+const SIDE_BAND_DATA_SIZE: usize = 65515;
+
+/// The response to a request, sent as its chunks come.
+pub type Response = BoxStream<'static, io::Result<Bytes>>;
+
+/// The target advertised for `HEAD`, see [`RefSnapshot::head`].
+///
+/// [`RefSnapshot::head`]: crate::protocol::snapshot::RefSnapshot::head
+const HEAD_TARGET: &str = "refs/heads/main";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolVersion {
+    /// The refs are advertised first, version 1 only adds a `version 1` line to it.
+    #[default]
+    V0,
+    V2,
+}
+
+impl ProtocolVersion {
+    /// The version asked for in a `Git-Protocol` value, e.g. `version=2:object-format=sha1`.
+    pub fn from_git_protocol(value: &str) -> Self {
+        match value.split(':').find_map(|param| param.strip_prefix("version=")) {
+            Some("2") => ProtocolVersion::V2,
+            _ => ProtocolVersion::V0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Packet {
+    Flush,
+    Delim,
+    ResponseEnd,
+    Data(Bytes),
+}
+
+/// Reads one pkt-line of `bytes`, None once it is empty.
+fn read_packet(bytes: &mut Bytes) -> Result<Option<Packet>, String> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    let length = bytes
+        .get(..4)
+        .and_then(|length| std::str::from_utf8(length).ok())
+        .and_then(|length| usize::from_str_radix(length, 16).ok())
+        .ok_or_else(|| String::from("invalid pkt-line length"))?;
+    let packet = match length {
+        0 => Packet::Flush,
+        1 => Packet::Delim,
+        2 => Packet::ResponseEnd,
+        3 => return Err(String::from("invalid pkt-line length")),
+        _ if length > bytes.len() => return Err(String::from("truncated pkt-line")),
+        _ => {
+            bytes.advance(4);
+            return Ok(Some(Packet::Data(bytes.split_to(length - 4))));
+        }
+    };
+    bytes.advance(4);
+    Ok(Some(packet))
+}
+
+/// Takes the first whole request of `buf`, up to its flush-pkt, for the transports sending the
+/// requests one after the other on a single stream. None while it is incomplete.
+pub fn take_request(buf: &mut BytesMut) -> Option<Bytes> {
+    let mut bytes = Bytes::copy_from_slice(buf);
+    while let Ok(Some(packet)) = read_packet(&mut bytes) {
+        if packet == Packet::Flush {
+            let length = buf.len() - bytes.len();
+            return Some(buf.split_to(length).freeze());
+        }
+    }
+    None
+}
+
+/// A command sent by the client, None in `command` for a request of a lone flush-pkt.
+#[derive(Debug, Default, PartialEq)]
+struct CommandRequest {
+    command: Option<String>,
+    capabilities: Vec<String>,
+    args: Vec<String>,
+}
+
+impl CommandRequest {
+    fn parse(bytes: &mut Bytes) -> Result<Self, String> {
+        let mut request = CommandRequest::default();
+        let mut in_args = false;
+        while let Some(packet) = read_packet(bytes)? {
+            let line = match packet {
+                Packet::Flush => break,
+                Packet::Delim => {
+                    in_args = true;
+                    continue;
+                }
+                Packet::ResponseEnd => return Err(String::from("unexpected response-end-pkt")),
+                Packet::Data(line) => String::from_utf8_lossy(&line).trim_end().to_owned(),
+            };
+            if in_args {
+                request.args.push(line);
+            } else if let Some(command) = line.strip_prefix("command=") {
+                request.command = Some(command.to_owned());
+            } else {
+                request.capabilities.push(line);
+            }
+        }
+        Ok(request)
+    }
+}
+
+/// The arguments of `fetch`.
+#[derive(Debug, Default, PartialEq)]
+struct FetchRequest {
+    want: Vec<String>,
+    have: Vec<String>,
+    done: bool,
+    shallow: ShallowRequest,
+    filter: Option<FilterSpec>,
+    ofs_delta: bool,
+}
+
+impl FetchRequest {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut request = FetchRequest::default();
+        for arg in args {
+            let (name, value) = arg.split_once(' ').unwrap_or((arg, ""));
+            match name {
+                "want" => request.want.push(value.to_owned()),
+                "have" => request.have.push(value.to_owned()),
+                "done" => request.done = true,
+                "ofs-delta" => request.ofs_delta = true,
+                "filter" => request.filter = Some(value.parse()?),
+                // accepted, the pack does not change with them
+                "thin-pack" | "no-progress" | "include-tag" => {}
+                _ if request.shallow.parse_line(arg)? => {}
+                _ => return Err(format!("unexpected line: '{}'", arg)),
+            }
+        }
+        Ok(request)
+    }
+}
+
+impl PackProtocol {
+    /// The capability advertisement of `GET /info/refs?service=git-upload-pack`.
+    pub fn git_info_refs_v2(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        for line in [
+            "version 2",
+            "agent=mega/0.0.1",
+            "ls-refs=unborn",
+            "fetch=shallow filter",
+            "server-option",
+            "object-format=sha1",
+        ] {
+            add_pkt_line_string(&mut buf, format!("{}\n", line));
+        }
+        buf.put(&PKT_LINE_END_MARKER[..]);
+        buf
+    }
+
+    /// Runs the command of a request to `git-upload-pack` and returns its response, the pack
+    /// of `fetch` is streamed as it is encoded.
+    #[tracing::instrument(skip_all, fields(path = %self.path.display()))]
+    pub async fn git_upload_pack_v2(&mut self, request: &mut Bytes) -> Result<Response> {
+        let request = match CommandRequest::parse(request) {
+            Ok(request) => request,
+            Err(msg) => return Ok(error_response(&msg)),
+        };
+        tracing::debug!("protocol v2 request: {:?}", request);
+        let repo = self.convert_path_to_repo().await;
+        match request.command.as_deref() {
+            None => Ok(whole(Bytes::new())),
+            Some("ls-refs") => self.ls_refs(&repo, &request.args).await.map(whole),
+            Some("fetch") => match FetchRequest::parse(&request.args) {
+                Ok(fetch) => self.fetch(&repo, fetch).await,
+                Err(msg) => Ok(error_response(&msg)),
+            },
+            Some(other) => Ok(error_response(&format!("unknown command '{}'", other))),
+        }
+    }
+
+    /// The refs starting with one of the `ref-prefix` arguments, all of them without one.
+    /// Tags are listed without their peeled commit.
+    async fn ls_refs(&mut self, repo: &Repo, args: &[String]) -> Result<Bytes> {
+        let symrefs = args.iter().any(|arg| arg == "symrefs");
+        let unborn = args.iter().any(|arg| arg == "unborn");
+        let prefixes: Vec<&str> = args
+            .iter()
+            .filter_map(|arg| arg.strip_prefix("ref-prefix "))
+            .collect();
+        let listed =
+            |name: &str| prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p));
+        let snapshot = match self.ref_snapshot(repo).await {
+            Ok(snapshot) => snapshot,
+            Err(err) => anyhow::bail!("failed to read the refs: {}", err),
+        };
+
+        let mut buf = BytesMut::new();
+        if listed("HEAD") {
+            let target = match symrefs {
+                true => format!(" symref-target:{}", HEAD_TARGET),
+                false => String::new(),
+            };
+            let head = snapshot.head();
+            if head != ZERO_ID {
+                add_pkt_line_string(&mut buf, format!("{} HEAD{}\n", head, target));
+            } else if unborn {
+                add_pkt_line_string(&mut buf, format!("unborn HEAD{}\n", target));
+            }
+        }
+        for git_ref in snapshot.refs().iter().filter(|r| listed(&r.ref_name)) {
+            add_pkt_line_string(
+                &mut buf,
+                format!("{} {}\n", git_ref.ref_git_id, git_ref.ref_name),
+            );
+        }
+        buf.put(&PKT_LINE_END_MARKER[..]);
+        Ok(buf.freeze())
+    }
+
+    /// Acknowledges the common commits of the client, and sends the pack once it said `done`
+    /// or every want reaches one of them.
+    async fn fetch(&mut self, repo: &Repo, request: FetchRequest) -> Result<Response> {
+        let started_at = Instant::now();
+        let _slow_op = self.watch_slow_op("upload-pack");
+        self.capabilities.push(Capability::SideBand64k);
+        if request.ofs_delta {
+            self.capabilities.push(Capability::OfsDelta);
+        }
+        self.filter = request.filter;
+        if request.want.is_empty() {
+            return Ok(error_response("fetch without any want"));
+        }
+        let snapshot = match self.ref_snapshot(repo).await {
+            Ok(snapshot) => snapshot.clone(),
+            Err(err) => anyhow::bail!("failed to read the refs: {}", err),
+        };
+        match self.refused_want(repo, &snapshot, &request.want).await {
+            Ok(None) => {}
+            Ok(Some(id)) => return Ok(error_response(&format!("not our ref {}", id))),
+            Err(err) => anyhow::bail!("failed to check the wants: {}", err),
+        }
+
+        let mut common = Vec::new();
+        for id in &request.have {
+            if self
+                .context
+                .services
+                .mega_storage
+                .get_commit_by_hash(id, repo)
+                .await
+                .map_err(|err| anyhow::anyhow!("failed to look up {}: {}", id, err))?
+                .is_some()
+            {
+                common.push(id.clone());
+            }
+        }
+        let mut buf = BytesMut::new();
+        if !request.done {
+            add_pkt_line_string(&mut buf, String::from("acknowledgments\n"));
+            for id in &common {
+                add_pkt_line_string(&mut buf, format!("ACK {}\n", id));
+            }
+            if common.is_empty() {
+                add_pkt_line_string(&mut buf, String::from("NAK\n"));
+            }
+            let ready =
+                !common.is_empty() && self.wants_reach_common(repo, &request.want, &common).await;
+            if !ready {
+                buf.put(&PKT_LINE_END_MARKER[..]);
+                return Ok(whole(buf.freeze()));
+            }
+            add_pkt_line_string(&mut buf, String::from("ready\n"));
+            buf.put(&PKT_LINE_DELIM_MARKER[..]);
+        }
+
+        let shallow_request = request.shallow;
+        if shallow_request.is_deepen() {
+            let update = match self
+                .shallow_update(repo, &snapshot, &request.want, &shallow_request)
+                .await
+            {
+                Ok(update) => update,
+                Err(msg) => return Ok(error_response(&msg)),
+            };
+            add_pkt_line_string(&mut buf, String::from("shallow-info\n"));
+            for id in &update.shallow {
+                add_pkt_line_string(&mut buf, format!("shallow {}\n", id.to_plain_str()));
+            }
+            for id in &update.unshallow {
+                add_pkt_line_string(&mut buf, format!("unshallow {}\n", id.to_plain_str()));
+            }
+            buf.put(&PKT_LINE_DELIM_MARKER[..]);
+            self.shallow = update.apply(&shallow_request.shallows);
+        } else {
+            self.shallow = shallow_request.shallows.iter().copied().collect();
+        }
+        self.client_shallow = shallow_request.shallows.iter().copied().collect();
+
+        let lease = match ReadLease::acquire(&self.context, repo, &request.want).await {
+            Ok(lease) => Some(lease),
+            Err(err) => {
+                tracing::warn!("failed to lease the wanted commits: {}", err);
+                None
+            }
+        };
+        let operation = if request.have.is_empty() {
+            OperationType::Clone
+        } else {
+            OperationType::Fetch
+        };
+        let pack = self
+            .pack_stream(repo, request.want.clone(), common)
+            .instrument(tracing::info_span!("pack"))
+            .await
+            .map_err(|err| anyhow::anyhow!("failed to pack the objects: {}", err))?;
+
+        add_pkt_line_string(&mut buf, String::from("packfile\n"));
+        // the lease is held and the operation recorded once the whole pack is sent
+        let sent = Arc::new(AtomicUsize::new(buf.len()));
+        let counted = sent.clone();
+        let packets = pack.map_ok(move |chunk| {
+            let packets = side_band_packets(&chunk);
+            counted.fetch_add(packets.len(), Ordering::Relaxed);
+            packets
+        });
+        let protocol = self.clone();
+        let end = stream::once(async move {
+            if let Some(lease) = lease {
+                lease.release(&protocol.context).await;
+            }
+            let sent = sent.load(Ordering::Relaxed) + PKT_LINE_END_MARKER.len();
+            protocol
+                .record_operation(operation, request.want, sent, started_at)
+                .await;
+            Ok(Bytes::from_static(PKT_LINE_END_MARKER))
+        });
+        Ok(stream::once(async move { Ok(buf.freeze()) })
+            .chain(packets)
+            .chain(end)
+            .boxed())
+    }
+}
+
+/// `chunk` of the pack in side-band-64k packets of the pack data band.
+fn side_band_packets(chunk: &[u8]) -> Bytes {
+    let mut packets = BytesMut::with_capacity(chunk.len() + 5);
+    for data in chunk.chunks(SIDE_BAND_DATA_SIZE) {
+        packets.put(format!("{:04x}", data.len() + 5).as_bytes());
+        packets.put_u8(SideBind::PackfileData.value());
+        packets.put(data);
+    }
+    packets.freeze()
+}
+
+/// A response sent whole.
+fn whole(bytes: Bytes) -> Response {
+    stream::once(async move { Ok(bytes) }).boxed()
+}
+
+/// An error ends the response, the client prints its message.
+fn error_response(msg: &str) -> Response {
+    let mut buf = BytesMut::new();
+    add_pkt_line_string(&mut buf, format!("ERR upload-pack: {}\n", msg));
+    whole(buf.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+
+    use crate::protocol::pack::add_pkt_line_string;
+    use crate::protocol::PackProtocol;
+
+    use super::{
+        read_packet, take_request, CommandRequest, FetchRequest, Packet, ProtocolVersion,
+    };
+
+    #[test]
+    fn test_protocol_version() {
+        assert_eq!(
+            ProtocolVersion::from_git_protocol("version=2"),
+            ProtocolVersion::V2
+        );
+        assert_eq!(
+            ProtocolVersion::from_git_protocol("object-format=sha1:version=2"),
+            ProtocolVersion::V2
+        );
+        assert_eq!(
+            ProtocolVersion::from_git_protocol("version=1"),
+            ProtocolVersion::V0
+        );
+        assert_eq!(ProtocolVersion::from_git_protocol(""), ProtocolVersion::V0);
+    }
+
+    #[test]
+    fn test_read_packet() {
+        let mut bytes = Bytes::from_static(b"0009done\n000100020000");
+        assert_eq!(
+            read_packet(&mut bytes),
+            Ok(Some(Packet::Data(Bytes::from_static(b"done\n"))))
+        );
+        assert_eq!(read_packet(&mut bytes), Ok(Some(Packet::Delim)));
+        assert_eq!(read_packet(&mut bytes), Ok(Some(Packet::ResponseEnd)));
+        assert_eq!(read_packet(&mut bytes), Ok(Some(Packet::Flush)));
+        assert_eq!(read_packet(&mut bytes), Ok(None));
+        assert!(read_packet(&mut Bytes::from_static(b"0010done")).is_err());
+        assert!(read_packet(&mut Bytes::from_static(b"zzzz")).is_err());
+    }
+
+    #[test]
+    fn test_take_request() {
+        let mut buf = BytesMut::from(&b"0014command=ls-refs\n00010000000e"[..]);
+        assert_eq!(
+            take_request(&mut buf),
+            Some(Bytes::from_static(b"0014command=ls-refs\n00010000"))
+        );
+        // the rest of the next request has not arrived yet
+        assert_eq!(take_request(&mut buf), None);
+        assert_eq!(&buf[..], b"000e");
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let mut body = BytesMut::new();
+        add_pkt_line_string(&mut body, String::from("command=ls-refs\n"));
+        add_pkt_line_string(&mut body, String::from("agent=git/2.43.0\n"));
+        body.extend_from_slice(b"0001");
+        add_pkt_line_string(&mut body, String::from("symrefs\n"));
+        add_pkt_line_string(&mut body, String::from("ref-prefix refs/heads/\n"));
+        body.extend_from_slice(b"0000");
+        let request = CommandRequest::parse(&mut body.freeze()).unwrap();
+        assert_eq!(request.command.as_deref(), Some("ls-refs"));
+        assert_eq!(request.capabilities, vec!["agent=git/2.43.0"]);
+        assert_eq!(request.args, vec!["symrefs", "ref-prefix refs/heads/"]);
+
+        let request = CommandRequest::parse(&mut Bytes::from_static(b"0000")).unwrap();
+        assert_eq!(request.command, None);
+    }
+
+    #[test]
+    fn test_parse_fetch() {
+        let want = "7bdc783132575d5b3e78400ace9971970ff43a18";
+        let have = "27dd8d4cf39f3868c6eee38b601bc9e9939304f5";
+        let args: Vec<String> = [
+            format!("want {}", want),
+            format!("have {}", have),
+            String::from("thin-pack"),
+            String::from("ofs-delta"),
+            String::from("deepen 1"),
+            String::from("filter blob:none"),
+            String::from("done"),
+        ]
+        .into_iter()
+        .collect();
+        let fetch = FetchRequest::parse(&args).unwrap();
+        assert_eq!(fetch.want, vec![want]);
+        assert_eq!(fetch.have, vec![have]);
+        assert!(fetch.done && fetch.ofs_delta);
+        assert_eq!(fetch.shallow.depth, Some(1));
+        assert!(fetch.filter.is_some());
+
+        assert!(FetchRequest::parse(&[String::from("deepen 0")]).is_err());
+        assert!(FetchRequest::parse(&[String::from("want-ref refs/heads/main")]).is_err());
+    }
+
+    #[test]
+    fn test_git_info_refs_v2() {
+        let mock = PackProtocol::mock();
+        assert_eq!(
+            &mock.git_info_refs_v2()[..],
+            b"000eversion 2\n0015agent=mega/0.0.1\n0013ls-refs=unborn\n0019fetch=shallow filter\n0012server-option\n0017object-format=sha1\n0000"
+        );
+    }
+}
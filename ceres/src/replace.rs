@@ -0,0 +1,242 @@
+//!
+//! Replace refs: `refs/replace/<commit>` points to the commit read in its place, the way
+//! `git replace` grafts an imported history onto the one it continues. The history walks read
+//! the replacement under the id of the replaced commit, so every ref reaching it sees the new
+//! parents. Replace refs are plain refs, they are advertised and fetched like the others, and
+//! git applies them on the client side the same way.
+//!
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use callisto::refs;
+use common::errors::MegaError;
+use common::utils::{generate_id, ZERO_ID};
+use jupiter::context::Context;
+use jupiter::events::RefUpdateEvent;
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::internal::pack::reference::RefCommand;
+use venus::repo::Repo;
+
+pub const REPLACE_REF_PREFIX: &str = "refs/replace/";
+
+/// Longest chain of replacements followed, like `MAXREPLACEDEPTH` of git.
+const MAX_REPLACE_DEPTH: usize = 5;
+
+/// The replacements of the objects of a repository.
+#[derive(Debug, Clone, Default)]
+pub struct Replacements {
+    targets: HashMap<SHA1, SHA1>,
+}
+
+impl Replacements {
+    pub fn from_refs(refs: &[refs::Model]) -> Self {
+        let targets = refs
+            .iter()
+            .filter_map(|r| {
+                let object = r.ref_name.strip_prefix(REPLACE_REF_PREFIX)?;
+                Some((
+                    SHA1::from_str(object).ok()?,
+                    SHA1::from_str(&r.ref_git_id).ok()?,
+                ))
+            })
+            .collect();
+        Replacements { targets }
+    }
+
+    pub async fn load(context: &Context, repo: &Repo) -> Result<Self, MegaError> {
+        let refs = context.services.mega_storage.get_repo_refs(repo).await?;
+        Ok(Replacements::from_refs(&refs))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// The object read in place of `id`, `id` itself when it is not replaced. A chain longer
+    /// than `MAX_REPLACE_DEPTH` ends where it is cut.
+    pub fn resolve(&self, id: &SHA1) -> SHA1 {
+        let mut current = *id;
+        for _ in 0..MAX_REPLACE_DEPTH {
+            match self.targets.get(&current) {
+                Some(target) => current = *target,
+                None => break,
+            }
+        }
+        current
+    }
+}
+
+/// Reads the commit `id` of `repo`, or its replacement under its id.
+pub async fn read_commit(
+    context: &Context,
+    repo: &Repo,
+    replacements: &Replacements,
+    id: &SHA1,
+) -> Result<Option<Commit>, MegaError> {
+    let source = replacements.resolve(id);
+    let model = context
+        .services
+        .mega_storage
+        .get_commit_by_hash(&source.to_plain_str(), repo)
+        .await?;
+    Ok(model.map(|model| {
+        let mut commit: Commit = model.into();
+        commit.id = *id;
+        commit
+    }))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Replacement {
+    pub object_id: String,
+    pub replacement: String,
+    pub updated_at: i64,
+}
+
+pub async fn list_replacements(
+    context: &Context,
+    repo: &Repo,
+) -> Result<Vec<Replacement>, MegaError> {
+    let refs = context.services.mega_storage.get_repo_refs(repo).await?;
+    Ok(refs
+        .into_iter()
+        .filter_map(|r| {
+            Some(Replacement {
+                object_id: r.ref_name.strip_prefix(REPLACE_REF_PREFIX)?.to_owned(),
+                replacement: r.ref_git_id,
+                updated_at: r.updated_at.and_utc().timestamp(),
+            })
+        })
+        .collect())
+}
+
+/// Reads the commit `replacement` in place of the commit `object_id`. Both have to be commits
+/// of `repo`, and `replacement` cannot lead back to `object_id`.
+pub async fn replace_commit(
+    context: &Context,
+    repo: &Repo,
+    repo_path: &str,
+    object_id: &str,
+    replacement: &str,
+) -> Result<(), MegaError> {
+    let storage = context.services.mega_storage.clone();
+    let (Ok(object), Ok(target)) = (SHA1::from_str(object_id), SHA1::from_str(replacement)) else {
+        return Err(MegaError::with_message("invalid commit id"));
+    };
+    if object == target {
+        return Err(MegaError::with_message("a commit cannot replace itself"));
+    }
+    for id in [object_id, replacement] {
+        if storage.get_commit_by_hash(id, repo).await?.is_none() {
+            return Err(MegaError::with_message(&format!("no commit {}", id)));
+        }
+    }
+    let refs = storage.get_repo_refs(repo).await?;
+    let replacements = Replacements::from_refs(&refs);
+    if replacements.resolve(&target) == object {
+        return Err(MegaError::with_message(&format!(
+            "{} is replaced by {}",
+            replacement, object_id
+        )));
+    }
+
+    let ref_name = format!("{}{}", REPLACE_REF_PREFIX, object_id);
+    let old_id = refs
+        .iter()
+        .find(|r| r.ref_name == ref_name)
+        .map_or(ZERO_ID.to_owned(), |r| r.ref_git_id.clone());
+    if old_id == replacement {
+        return Ok(());
+    }
+    let command = RefCommand::new(old_id, replacement.to_owned(), ref_name);
+    update_ref(context, repo, repo_path, command).await
+}
+
+/// Reads the commit `object_id` itself again, returns whether it was replaced.
+pub async fn remove_replacement(
+    context: &Context,
+    repo: &Repo,
+    repo_path: &str,
+    object_id: &str,
+) -> Result<bool, MegaError> {
+    let ref_name = format!("{}{}", REPLACE_REF_PREFIX, object_id);
+    let refs = context.services.mega_storage.get_repo_refs(repo).await?;
+    let Some(current) = refs.into_iter().find(|r| r.ref_name == ref_name) else {
+        return Ok(false);
+    };
+    let command = RefCommand::new(current.ref_git_id, ZERO_ID.to_owned(), ref_name);
+    update_ref(context, repo, repo_path, command).await?;
+    Ok(true)
+}
+
+async fn update_ref(
+    context: &Context,
+    repo: &Repo,
+    repo_path: &str,
+    command: RefCommand,
+) -> Result<(), MegaError> {
+    context
+        .services
+        .mega_storage
+        .handler_refs(repo, &command)
+        .await;
+    // the cached blames were computed along the history before the replacement
+    context
+        .services
+        .blame_storage
+        .remove_blames(Some(repo.repo_id), None)
+        .await?;
+    let event = RefUpdateEvent {
+        id: generate_id(),
+        repo_id: repo.repo_id,
+        repo_path: repo_path.to_owned(),
+        ref_name: command.ref_name,
+        old_id: command.old_id,
+        new_id: command.new_id,
+        pusher: None,
+        created_at: chrono::Utc::now().naive_utc(),
+    };
+    context.events.publish(context, vec![event]).await
+}
+
+#[cfg(test)]
+mod tests {
+    use callisto::db_enums::RefType;
+    use callisto::refs;
+    use venus::test_support::id;
+
+    use super::Replacements;
+
+    fn replace_ref(object: u8, replacement: u8) -> refs::Model {
+        refs::Model {
+            id: object as i64,
+            repo_id: 1,
+            ref_name: format!("refs/replace/{}", id(object).to_plain_str()),
+            ref_git_id: id(replacement).to_plain_str(),
+            ref_type: RefType::Branch,
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut refs: Vec<refs::Model> = (1..=7).map(|n| replace_ref(n, n + 1)).collect();
+        refs.push(replace_ref(20, 21));
+        refs.push(refs::Model {
+            ref_name: String::from("refs/heads/main"),
+            ..replace_ref(30, 31)
+        });
+        let replacements = Replacements::from_refs(&refs);
+
+        assert_eq!(replacements.resolve(&id(20)), id(21));
+        assert_eq!(replacements.resolve(&id(21)), id(21));
+        assert_eq!(replacements.resolve(&id(30)), id(30));
+        // the chain is cut after 5 replacements
+        assert_eq!(replacements.resolve(&id(1)), id(6));
+        assert_eq!(replacements.resolve(&id(4)), id(8));
+    }
+}
@@ -0,0 +1,241 @@
+//!
+//! Point-in-time restore of the refs. Every ref update is recorded in the `mega_ref_event`
+//! table with the tip before and after it, see [`jupiter::events`]: the tip of a ref at an
+//! instant is the tip before the first update recorded after it, and a ref not updated since
+//! is unchanged. A tip whose commit or tag is no longer stored, e.g. removed by garbage
+//! collection, can't be restored, the ref is reported and left as it is.
+//!
+//! The events are removed once delivered and older than [`RETENTION_DAYS`], so only an
+//! instant within that window is sure to have every later update recorded. The tips are read
+//! once, a push landing during the restore is overwritten.
+//!
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+
+use common::errors::MegaError;
+use common::utils::{generate_id, ZERO_ID};
+use jupiter::context::Context;
+use jupiter::events::{RefUpdateEvent, RETENTION_DAYS};
+use venus::internal::pack::reference::RefCommand;
+use venus::repo::Repo;
+
+/// A ref whose tip differs from the one it had at the instant restored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefRestore {
+    pub repo_path: String,
+    pub ref_name: String,
+    /// The tip now, None if the ref does not exist.
+    pub current: Option<String>,
+    /// The tip at the instant, None if the ref did not exist.
+    pub target: Option<String>,
+    /// False if the target is no longer stored, the ref is left as it is.
+    pub restorable: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RestoreReport {
+    pub at: NaiveDateTime,
+    /// The refs restored, or to restore on a dry run, by repository and name.
+    pub refs: Vec<RefRestore>,
+    pub dry_run: bool,
+}
+
+/// Parses an instant given as RFC 3339, e.g. `2024-05-01T12:00:00Z`, or as seconds since the
+/// Unix epoch.
+pub fn parse_instant(instant: &str) -> Result<NaiveDateTime, MegaError> {
+    let parsed = match instant.parse::<i64>() {
+        Ok(seconds) => DateTime::from_timestamp(seconds, 0),
+        Err(_) => DateTime::parse_from_rfc3339(instant)
+            .ok()
+            .map(|t| t.to_utc()),
+    };
+    parsed
+        .map(|t| t.naive_utc())
+        .ok_or_else(|| MegaError::with_message(&format!("invalid instant: {}", instant)))
+}
+
+/// Restores the refs of the monorepo and the imported repositories, or only those of
+/// `repo_path` (`/` for the monorepo), to their tips at `at`. A dry run only reports them.
+pub async fn restore_refs(
+    context: &Context,
+    at: NaiveDateTime,
+    repo_path: Option<&str>,
+    dry_run: bool,
+) -> Result<RestoreReport, MegaError> {
+    let now = Utc::now().naive_utc();
+    if at > now {
+        return Err(MegaError::with_message(&format!("{} is in the future", at)));
+    }
+    if at < now - Duration::days(RETENTION_DAYS) {
+        return Err(MegaError::with_message(&format!(
+            "the ref updates are only kept {} days, {} is older",
+            RETENTION_DAYS, at
+        )));
+    }
+    let storage = &context.services.mega_storage;
+    let mut repos = vec![(Repo::empty(), String::from("/"))];
+    repos.extend(storage.get_git_repos().await?.into_iter().map(|m| {
+        let path = m.repo_path.clone();
+        (Repo::from(m), path)
+    }));
+    if let Some(repo_path) = repo_path {
+        repos.retain(|(_, path)| path == repo_path);
+        if repos.is_empty() {
+            return Err(MegaError::with_message(&format!(
+                "no repository at {}",
+                repo_path
+            )));
+        }
+    }
+
+    let mut events: HashMap<i64, Vec<RefUpdateEvent>> = HashMap::new();
+    for event in context
+        .services
+        .event_storage
+        .get_ref_events_since(at)
+        .await?
+    {
+        events.entry(event.repo_id).or_default().push(event);
+    }
+    let mut report = Vec::new();
+    for (repo, path) in repos {
+        let Some(events) = events.remove(&repo.repo_id) else {
+            continue;
+        };
+        let current: HashMap<String, String> = storage
+            .get_repo_refs(&repo)
+            .await?
+            .into_iter()
+            .map(|r| (r.ref_name, r.ref_git_id))
+            .collect();
+        let mut updates = Vec::new();
+        for (ref_name, target) in tips_before(&events) {
+            let current = current.get(&ref_name).cloned();
+            if current == target {
+                continue;
+            }
+            let restorable = match &target {
+                Some(id) => storage.has_ref_target(&repo, id).await?,
+                None => true,
+            };
+            if restorable && !dry_run {
+                updates.push(RefCommand::new(
+                    current.clone().unwrap_or(ZERO_ID.to_owned()),
+                    target.clone().unwrap_or(ZERO_ID.to_owned()),
+                    ref_name.clone(),
+                ));
+            }
+            report.push(RefRestore {
+                repo_path: path.clone(),
+                ref_name,
+                current,
+                target,
+                restorable,
+            });
+        }
+        apply(context, &repo, &path, updates).await?;
+    }
+    Ok(RestoreReport {
+        at,
+        refs: report,
+        dry_run,
+    })
+}
+
+/// The tip of every ref `events` update before the first of them, None for a ref they
+/// created. `events` are those of one repository in the order they were recorded.
+fn tips_before(events: &[RefUpdateEvent]) -> BTreeMap<String, Option<String>> {
+    let mut tips = BTreeMap::new();
+    for event in events {
+        tips.entry(event.ref_name.clone())
+            .or_insert_with(|| Some(event.old_id.clone()).filter(|id| id != ZERO_ID));
+    }
+    tips
+}
+
+/// Updates the refs of `repo` and records the updates like a push does.
+async fn apply(
+    context: &Context,
+    repo: &Repo,
+    repo_path: &str,
+    commands: Vec<RefCommand>,
+) -> Result<(), MegaError> {
+    if commands.is_empty() {
+        return Ok(());
+    }
+    let mut events = Vec::with_capacity(commands.len());
+    for command in commands {
+        context
+            .services
+            .mega_storage
+            .handler_refs(repo, &command)
+            .await;
+        events.push(RefUpdateEvent {
+            id: generate_id(),
+            repo_id: repo.repo_id,
+            repo_path: repo_path.to_owned(),
+            ref_name: command.ref_name,
+            old_id: command.old_id,
+            new_id: command.new_id,
+            pusher: None,
+            created_at: Utc::now().naive_utc(),
+        });
+    }
+    context.events.publish(context, events).await
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use common::utils::ZERO_ID;
+    use jupiter::events::RefUpdateEvent;
+
+    use super::{parse_instant, tips_before};
+
+    fn event(id: i64, ref_name: &str, old_id: &str, new_id: &str) -> RefUpdateEvent {
+        RefUpdateEvent {
+            id,
+            repo_id: 0,
+            repo_path: String::from("/project"),
+            ref_name: ref_name.to_owned(),
+            old_id: old_id.to_owned(),
+            new_id: new_id.to_owned(),
+            pusher: None,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn test_tips_before() {
+        let (a, b, c) = ("a".repeat(40), "b".repeat(40), "c".repeat(40));
+        let events = vec![
+            event(1, "refs/heads/main", &a, &b),
+            event(2, "refs/heads/feature", ZERO_ID, &c),
+            event(3, "refs/heads/main", &b, &c),
+            event(4, "refs/heads/old", &a, ZERO_ID),
+            event(5, "refs/heads/feature", &c, ZERO_ID),
+        ];
+        let tips = tips_before(&events);
+        assert_eq!(tips.len(), 3);
+        assert_eq!(tips["refs/heads/main"], Some(a.clone()));
+        assert_eq!(tips["refs/heads/feature"], None);
+        assert_eq!(tips["refs/heads/old"], Some(a));
+        assert!(tips_before(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_instant() {
+        let expected = DateTime::from_timestamp(1_714_564_800, 0)
+            .unwrap()
+            .naive_utc();
+        assert_eq!(parse_instant("1714564800").unwrap(), expected);
+        assert_eq!(parse_instant("2024-05-01T12:00:00Z").unwrap(), expected);
+        assert_eq!(
+            parse_instant("2024-05-01T14:00:00+02:00").unwrap(),
+            expected
+        );
+        assert!(parse_instant("yesterday").is_err());
+    }
+}
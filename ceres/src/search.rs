@@ -0,0 +1,214 @@
+//!
+//! Search across the merge requests and issues behind the `/search` endpoint. Every word of
+//! the query has to appear in the message of a merge request or the title of an issue, and the
+//! matches are narrowed down with the type, state and author filters. The facets count the
+//! matches of every value of these fields before the filters apply, so a client can offer the
+//! other values as drill-downs.
+//!
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use callisto::{mega_issue, mega_mr};
+use common::errors::MegaError;
+use jupiter::context::Context;
+
+/// Hits returned when the request sets no limit, and the most it can ask for.
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchKind {
+    Mr,
+    Issue,
+}
+
+impl SearchKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchKind::Mr => "mr",
+            SearchKind::Issue => "issue",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchQuery {
+    pub text: String,
+    /// Both kinds when unset.
+    pub kind: Option<SearchKind>,
+    pub state: Option<String>,
+    pub author: Option<String>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SearchHit {
+    #[serde(rename = "type")]
+    pub kind: SearchKind,
+    pub id: i64,
+    /// The number of an issue, merge requests are identified by their id.
+    pub number: Option<i64>,
+    pub title: String,
+    pub state: String,
+    /// Unset for merge requests, which do not record their author.
+    pub author: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl From<mega_mr::Model> for SearchHit {
+    fn from(value: mega_mr::Model) -> Self {
+        let state = serde_json::to_value(value.status)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .unwrap_or_default();
+        SearchHit {
+            kind: SearchKind::Mr,
+            id: value.id,
+            number: None,
+            title: value.mr_msg.unwrap_or_default(),
+            state,
+            author: None,
+            created_at: value.created_at.and_utc().timestamp(),
+            updated_at: value.updated_at.and_utc().timestamp(),
+        }
+    }
+}
+
+impl From<mega_issue::Model> for SearchHit {
+    fn from(value: mega_issue::Model) -> Self {
+        SearchHit {
+            kind: SearchKind::Issue,
+            id: value.id,
+            number: Some(value.number),
+            title: value.title,
+            state: value.state,
+            author: Some(value.sender_name),
+            created_at: value.created_at.and_utc().timestamp(),
+            updated_at: value.updated_at.and_utc().timestamp(),
+        }
+    }
+}
+
+/// The number of matches of every value of a field.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Facets {
+    #[serde(rename = "type")]
+    pub kind: BTreeMap<String, usize>,
+    pub state: BTreeMap<String, usize>,
+    pub author: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    pub total: usize,
+    pub offset: usize,
+    pub hits: Vec<SearchHit>,
+    pub facets: Facets,
+}
+
+pub async fn search(context: &Context, query: &SearchQuery) -> Result<SearchResult, MegaError> {
+    let storage = context.services.mega_storage.clone();
+    let terms: Vec<String> = query.text.split_whitespace().map(str::to_owned).collect();
+    let mut matches: Vec<SearchHit> = storage
+        .search_mrs(&terms)
+        .await?
+        .into_iter()
+        .map(SearchHit::from)
+        .collect();
+    matches.extend(
+        storage
+            .search_issues(&terms)
+            .await?
+            .into_iter()
+            .map(SearchHit::from),
+    );
+    Ok(filter(matches, query))
+}
+
+/// Applies the filters of `query` to `matches`, newest first, and counts the facets.
+fn filter(mut matches: Vec<SearchHit>, query: &SearchQuery) -> SearchResult {
+    let mut facets = Facets::default();
+    for hit in &matches {
+        *facets.kind.entry(hit.kind.as_str().to_owned()).or_default() += 1;
+        *facets.state.entry(hit.state.clone()).or_default() += 1;
+        if let Some(author) = &hit.author {
+            *facets.author.entry(author.clone()).or_default() += 1;
+        }
+    }
+    matches.retain(|hit| {
+        query.kind.is_none_or(|kind| hit.kind == kind)
+            && query.state.as_ref().is_none_or(|state| &hit.state == state)
+            && query
+                .author
+                .as_ref()
+                .is_none_or(|author| hit.author.as_ref() == Some(author))
+    });
+    matches.sort_by_key(|hit| std::cmp::Reverse(hit.updated_at));
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    SearchResult {
+        total: matches.len(),
+        offset: query.offset,
+        hits: matches.into_iter().skip(query.offset).take(limit).collect(),
+        facets,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{filter, SearchHit, SearchKind, SearchQuery};
+
+    fn hit(kind: SearchKind, id: i64, state: &str, author: Option<&str>) -> SearchHit {
+        SearchHit {
+            kind,
+            id,
+            number: None,
+            title: format!("fix the build {}", id),
+            state: state.to_owned(),
+            author: author.map(str::to_owned),
+            created_at: id,
+            updated_at: id,
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let matches = vec![
+            hit(SearchKind::Mr, 1, "merged", None),
+            hit(SearchKind::Issue, 2, "open", Some("alice")),
+            hit(SearchKind::Issue, 3, "closed", Some("bob")),
+            hit(SearchKind::Issue, 4, "open", Some("bob")),
+        ];
+        let query = SearchQuery {
+            kind: Some(SearchKind::Issue),
+            state: Some(String::from("open")),
+            ..Default::default()
+        };
+        let result = filter(matches.clone(), &query);
+        assert_eq!(result.total, 2);
+        let ids: Vec<i64> = result.hits.iter().map(|h| h.id).collect();
+        assert_eq!(ids, vec![4, 2]);
+        assert_eq!(result.facets.kind["mr"], 1);
+        assert_eq!(result.facets.kind["issue"], 3);
+        assert_eq!(result.facets.state["open"], 2);
+        assert_eq!(result.facets.author["bob"], 2);
+
+        let query = SearchQuery {
+            author: Some(String::from("bob")),
+            offset: 1,
+            limit: Some(1),
+            ..Default::default()
+        };
+        let result = filter(matches, &query);
+        assert_eq!(result.total, 2);
+        assert_eq!(result.hits.len(), 1);
+        assert_eq!(result.hits[0].id, 3);
+    }
+}
@@ -0,0 +1,422 @@
+//!
+//! Storage reports of the monorepo and the imported repositories, to find the files worth
+//! moving to LFS: the objects stored and the bytes they take by type, the largest blobs with
+//! the paths they are at on the default branches, and the directories that grew the most on
+//! those branches over the last `window_days`. The growth of a directory is the size of the
+//! files added or changed below it since the last commit before the window, less that of the
+//! files removed or replaced, along the first parents.
+//!
+//! The reports are computed daily by the servers and kept in the database, see
+//! [`generate_and_save`], or on demand with `mega service storage-report`.
+//!
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use callisto::refs;
+use common::errors::MegaError;
+use jupiter::context::Context;
+use jupiter::storage::stats_storage::ObjectTypeStats;
+use venus::hash::SHA1;
+use venus::internal::object::tree::TreeItemMode;
+use venus::internal::revwalk::CommitGraph;
+use venus::repo::Repo;
+
+use crate::compare::{self, ChangeStatus, FileChange};
+
+/// Blobs and directories listed when the options set no limit.
+pub const DEFAULT_LIMIT: usize = 20;
+pub const DEFAULT_WINDOW_DAYS: u32 = 30;
+/// Path segments the growth of the directories is summed up to.
+pub const DEFAULT_DEPTH: usize = 3;
+/// Reports kept in the database, the older ones are removed.
+pub const KEEP_REPORTS: u64 = 30;
+/// Paths listed per blob, a blob copied in many places is listed at the first ones.
+const MAX_BLOB_PATHS: usize = 5;
+/// The branch a repository is measured on, the first one it has.
+const DEFAULT_BRANCHES: [&str; 2] = ["refs/heads/main", "refs/heads/master"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportOptions {
+    /// Blobs and directories listed.
+    pub limit: usize,
+    pub window_days: u32,
+    pub depth: usize,
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        ReportOptions {
+            limit: DEFAULT_LIMIT,
+            window_days: DEFAULT_WINDOW_DAYS,
+            depth: DEFAULT_DEPTH,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypeStats {
+    pub object_type: String,
+    pub count: i64,
+    /// Bytes taken in the database, the size of the LFS objects in their store.
+    pub size: i64,
+}
+
+impl From<ObjectTypeStats> for TypeStats {
+    fn from(stats: ObjectTypeStats) -> Self {
+        TypeStats {
+            object_type: stats.object_type,
+            count: stats.count,
+            size: stats.size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobPath {
+    pub repo_path: String,
+    /// The path of the file in the monorepo, under `repo_path`.
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LargeBlob {
+    pub id: String,
+    pub size: i64,
+    /// The paths of the blob on the default branches, none when only older commits have it.
+    pub paths: Vec<BlobPath>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathGrowth {
+    pub repo_path: String,
+    /// The directory in the monorepo, under `repo_path`.
+    pub path: String,
+    /// Bytes of the files added or changed below the directory, less those they replaced.
+    pub growth: i64,
+    pub files_added: usize,
+    pub files_changed: usize,
+    pub files_removed: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageReport {
+    pub generated_at: i64,
+    pub window_days: u32,
+    pub depth: usize,
+    pub types: Vec<TypeStats>,
+    pub largest_blobs: Vec<LargeBlob>,
+    /// The directories that grew the most, the largest growth first.
+    pub growing_paths: Vec<PathGrowth>,
+}
+
+/// Computes the storage report of the monorepo and the imported repositories. A repository
+/// whose history cannot be read is left out of the paths, with a warning.
+pub async fn generate(
+    context: &Context,
+    options: ReportOptions,
+) -> Result<StorageReport, MegaError> {
+    let stats = context.services.stats_storage.clone();
+    let storage = context.services.mega_storage.clone();
+    let types = stats
+        .object_type_stats()
+        .await?
+        .into_iter()
+        .map(TypeStats::from)
+        .collect();
+    let largest = stats.largest_blobs(options.limit as u64).await?;
+    let wanted: HashSet<SHA1> = largest
+        .iter()
+        .filter_map(|(id, _)| SHA1::from_str(id).ok())
+        .collect();
+
+    let mut repos = vec![(Repo::empty(), String::from("/"))];
+    repos.extend(storage.get_git_repos().await?.into_iter().map(|m| {
+        let path = m.repo_path.clone();
+        (Repo::from(m), path)
+    }));
+    let now = chrono::Utc::now().timestamp();
+    let since = now - i64::from(options.window_days) * 24 * 60 * 60;
+    let mut paths: HashMap<SHA1, Vec<BlobPath>> = HashMap::new();
+    let mut growing_paths = Vec::new();
+    for (repo, repo_path) in &repos {
+        match measure_repo(context, repo, repo_path, &wanted, since, options.depth).await {
+            Ok((found, growth)) => {
+                for (id, path) in found {
+                    let paths = paths.entry(id).or_default();
+                    if paths.len() < MAX_BLOB_PATHS {
+                        paths.push(path);
+                    }
+                }
+                growing_paths.extend(growth);
+            }
+            Err(err) => tracing::warn!("{}: failed to measure the repository: {}", repo_path, err),
+        }
+    }
+    growing_paths.retain(|growth| growth.growth > 0);
+    growing_paths.sort_by(|a, b| b.growth.cmp(&a.growth).then_with(|| a.path.cmp(&b.path)));
+    growing_paths.truncate(options.limit);
+
+    let largest_blobs = largest
+        .into_iter()
+        .map(|(id, size)| LargeBlob {
+            paths: SHA1::from_str(&id)
+                .ok()
+                .and_then(|sha| paths.remove(&sha))
+                .unwrap_or_default(),
+            id,
+            size,
+        })
+        .collect();
+    Ok(StorageReport {
+        generated_at: now,
+        window_days: options.window_days,
+        depth: options.depth,
+        types,
+        largest_blobs,
+        growing_paths,
+    })
+}
+
+/// Computes the storage report and keeps it as the latest one.
+pub async fn generate_and_save(
+    context: &Context,
+    options: ReportOptions,
+) -> Result<StorageReport, MegaError> {
+    let report = generate(context, options).await?;
+    let json =
+        serde_json::to_string(&report).map_err(|err| MegaError::with_message(&err.to_string()))?;
+    context
+        .services
+        .stats_storage
+        .save_report(json, KEEP_REPORTS)
+        .await?;
+    Ok(report)
+}
+
+/// The last report saved, None before the first one.
+pub async fn latest(context: &Context) -> Result<Option<StorageReport>, MegaError> {
+    let Some(model) = context.services.stats_storage.latest_report().await? else {
+        return Ok(None);
+    };
+    serde_json::from_str(&model.report)
+        .map(Some)
+        .map_err(|err| MegaError::with_message(&format!("invalid storage report: {}", err)))
+}
+
+/// The paths of the blobs of `wanted` on the default branch of `repo`, and the growth of its
+/// directories since the time `since`.
+async fn measure_repo(
+    context: &Context,
+    repo: &Repo,
+    repo_path: &str,
+    wanted: &HashSet<SHA1>,
+    since: i64,
+    depth: usize,
+) -> Result<(Vec<(SHA1, BlobPath)>, Vec<PathGrowth>), MegaError> {
+    let refs = context.services.mega_storage.get_repo_refs(repo).await?;
+    let Some(tip) = default_branch(&refs) else {
+        return Ok((vec![], vec![]));
+    };
+    let graph = compare::load_graph(context, repo, &[tip]).await?;
+    let Some(tree) = graph.tree(&tip) else {
+        return Ok((vec![], vec![]));
+    };
+
+    let mut found = Vec::new();
+    if !wanted.is_empty() {
+        let mut pending = vec![(String::new(), tree)];
+        while let Some((dir, id)) = pending.pop() {
+            for item in compare::load_tree_items(context, Some(id)).await? {
+                let path = if dir.is_empty() {
+                    item.name.clone()
+                } else {
+                    format!("{}/{}", dir, item.name)
+                };
+                match item.mode {
+                    TreeItemMode::Tree => pending.push((path, item.id)),
+                    TreeItemMode::Commit => {}
+                    _ if wanted.contains(&item.id) => found.push((
+                        item.id,
+                        BlobPath {
+                            repo_path: repo_path.to_owned(),
+                            path: full_path(repo_path, &path),
+                        },
+                    )),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    let base = first_parent_before(&graph, tip, since);
+    let changes =
+        compare::diff_trees(context, base.and_then(|id| graph.tree(&id)), Some(tree)).await?;
+    let ids: Vec<String> = changes
+        .iter()
+        .flat_map(|change| [change.old_id, change.new_id])
+        .flatten()
+        .map(|id| id.to_plain_str())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let sizes = context.services.stats_storage.blob_sizes(&ids).await?;
+    Ok((found, growth_by_dir(repo_path, &changes, &sizes, depth)))
+}
+
+/// The tip of the first branch of `DEFAULT_BRANCHES` in `refs`.
+fn default_branch(refs: &[refs::Model]) -> Option<SHA1> {
+    DEFAULT_BRANCHES.iter().find_map(|name| {
+        refs.iter()
+            .find(|r| r.ref_name == *name)
+            .and_then(|r| SHA1::from_str(&r.ref_git_id).ok())
+    })
+}
+
+/// The first commit down the first parents of `tip` committed at or before `since`, None when
+/// the whole history is newer.
+fn first_parent_before(graph: &CommitGraph, tip: SHA1, since: i64) -> Option<SHA1> {
+    let mut id = tip;
+    loop {
+        if graph.committer_date(&id)? as i64 <= since {
+            return Some(id);
+        }
+        id = *graph.parents_of(&id).first()?;
+    }
+}
+
+/// Sums up the `changes` of the files by their directory, cut to `depth` segments.
+fn growth_by_dir(
+    repo_path: &str,
+    changes: &[FileChange],
+    sizes: &HashMap<String, i64>,
+    depth: usize,
+) -> Vec<PathGrowth> {
+    let size = |id: &Option<SHA1>| {
+        id.and_then(|id| sizes.get(&id.to_plain_str()).copied())
+            .unwrap_or(0)
+    };
+    let mut dirs: BTreeMap<String, PathGrowth> = BTreeMap::new();
+    for change in changes {
+        let segments: Vec<&str> = change.path.split('/').collect();
+        let dir = segments[..segments.len() - 1]
+            .iter()
+            .take(depth)
+            .copied()
+            .collect::<Vec<_>>()
+            .join("/");
+        let growth = dirs.entry(dir.clone()).or_insert_with(|| PathGrowth {
+            repo_path: repo_path.to_owned(),
+            path: full_path(repo_path, &dir),
+            growth: 0,
+            files_added: 0,
+            files_changed: 0,
+            files_removed: 0,
+        });
+        growth.growth += size(&change.new_id) - size(&change.old_id);
+        match change.status {
+            ChangeStatus::Added => growth.files_added += 1,
+            ChangeStatus::Modified => growth.files_changed += 1,
+            ChangeStatus::Deleted => growth.files_removed += 1,
+        }
+    }
+    dirs.into_values().collect()
+}
+
+/// The path in the monorepo of `path` of the repository at `repo_path`.
+fn full_path(repo_path: &str, path: &str) -> String {
+    let mut full = repo_path.trim_end_matches('/').to_owned();
+    if !path.is_empty() {
+        full.push('/');
+        full.push_str(path);
+    }
+    if full.is_empty() {
+        full.push('/');
+    }
+    full
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use callisto::db_enums::RefType;
+    use callisto::refs;
+    use venus::test_support::id;
+
+    use super::{default_branch, full_path, growth_by_dir};
+    use crate::compare::{ChangeStatus, FileChange};
+
+    fn change(path: &str, status: ChangeStatus, old: Option<u8>, new: Option<u8>) -> FileChange {
+        FileChange {
+            path: path.to_owned(),
+            status,
+            old_id: old.map(id),
+            new_id: new.map(id),
+        }
+    }
+
+    #[test]
+    fn test_full_path() {
+        assert_eq!(full_path("/", ""), "/");
+        assert_eq!(full_path("/", "assets/logo.psd"), "/assets/logo.psd");
+        assert_eq!(full_path("/third_parts/zlib", ""), "/third_parts/zlib");
+        assert_eq!(
+            full_path("/third_parts/zlib", "doc"),
+            "/third_parts/zlib/doc"
+        );
+    }
+
+    #[test]
+    fn test_default_branch() {
+        let r = |name: &str, target: u8| refs::Model {
+            id: target as i64,
+            repo_id: 1,
+            ref_name: name.to_owned(),
+            ref_git_id: id(target).to_plain_str(),
+            ref_type: RefType::Branch,
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        };
+        let refs = vec![r("refs/heads/dev", 1), r("refs/heads/master", 2)];
+        assert_eq!(default_branch(&refs), Some(id(2)));
+        let refs = vec![r("refs/heads/master", 2), r("refs/heads/main", 3)];
+        assert_eq!(default_branch(&refs), Some(id(3)));
+        assert_eq!(default_branch(&[r("refs/heads/dev", 1)]), None);
+    }
+
+    #[test]
+    fn test_growth_by_dir() {
+        let sizes: HashMap<String, i64> = [(1, 100), (2, 250), (3, 4000), (4, 10)]
+            .into_iter()
+            .map(|(n, size)| (id(n).to_plain_str(), size))
+            .collect();
+        let changes = vec![
+            change("README.md", ChangeStatus::Modified, Some(1), Some(2)),
+            change(
+                "assets/raw/a/b/scan.tif",
+                ChangeStatus::Added,
+                None,
+                Some(3),
+            ),
+            change("assets/raw/c.tif", ChangeStatus::Added, None, Some(3)),
+            change("assets/raw/old.tif", ChangeStatus::Deleted, Some(4), None),
+        ];
+        let growth = growth_by_dir("/", &changes, &sizes, 2);
+        assert_eq!(growth.len(), 2);
+        assert_eq!(growth[0].path, "/");
+        assert_eq!(growth[0].growth, 150);
+        assert_eq!(growth[0].files_changed, 1);
+        assert_eq!(growth[1].path, "/assets/raw");
+        assert_eq!(growth[1].growth, 7990);
+        assert_eq!(
+            (
+                growth[1].files_added,
+                growth[1].files_changed,
+                growth[1].files_removed
+            ),
+            (2, 0, 1)
+        );
+    }
+}
@@ -0,0 +1,375 @@
+//!
+//! Watches of the developers on a branch or a path. A watch names a repository, or a directory
+//! of the monorepo, and optionally a branch and a path in it. Every ref update matching the
+//! branch is diffed, and the watchers of a path are only notified when a file under it changed.
+//! The notifications are kept for their users to read, and handed over to the delivery of the
+//! server. The pushers are not notified of their own pushes.
+//!
+use std::str::FromStr;
+
+use chrono::Utc;
+use serde::Serialize;
+
+use callisto::{mega_watch, mega_watch_notification};
+use common::errors::MegaError;
+use common::utils::{generate_id, ZERO_ID};
+use jupiter::context::Context;
+use jupiter::events::RefUpdateEvent;
+use venus::hash::SHA1;
+use venus::repo::Repo;
+
+use crate::compare;
+use crate::replace::{self, Replacements};
+
+/// Notifications returned when the request sets no limit, and the most it can ask for.
+pub const DEFAULT_PAGE_SIZE: u64 = 50;
+pub const MAX_PAGE_SIZE: u64 = 200;
+
+/// Changed paths kept in a notification, the others are only counted.
+const MAX_PATHS: usize = 100;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Watch {
+    pub id: i64,
+    pub repo_path: String,
+    /// The ref watched, every ref of the repository when unset.
+    pub ref_name: Option<String>,
+    /// The directory or file watched, the whole tree when unset.
+    pub path: Option<String>,
+    pub created_at: i64,
+}
+
+impl From<mega_watch::Model> for Watch {
+    fn from(value: mega_watch::Model) -> Self {
+        Watch {
+            id: value.id,
+            repo_path: value.repo_path,
+            ref_name: value.ref_name,
+            path: value.path,
+            created_at: value.created_at.and_utc().timestamp(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchNotification {
+    pub id: i64,
+    pub user: String,
+    pub watch_id: i64,
+    pub repo_path: String,
+    pub ref_name: String,
+    pub old_id: String,
+    pub new_id: String,
+    pub pusher: Option<String>,
+    /// The changed files under the watched path, empty for a watch of the whole tree.
+    pub paths: Vec<String>,
+    /// Changed files under the watched path left out of `paths`.
+    pub more_paths: usize,
+    pub created_at: i64,
+    pub read: bool,
+}
+
+impl From<mega_watch_notification::Model> for WatchNotification {
+    fn from(value: mega_watch_notification::Model) -> Self {
+        let mut paths: Vec<String> = value.paths.lines().map(str::to_owned).collect();
+        // the count of the paths left out follows the kept ones
+        let more_paths = match paths.last().and_then(|last| last.strip_prefix('+')) {
+            Some(count) => count.parse().unwrap_or(0),
+            None => 0,
+        };
+        if more_paths > 0 {
+            paths.pop();
+        }
+        WatchNotification {
+            id: value.id,
+            user: value.user_name,
+            watch_id: value.watch_id,
+            repo_path: value.repo_path,
+            ref_name: value.ref_name,
+            old_id: value.old_id,
+            new_id: value.new_id,
+            pusher: value.pusher,
+            paths,
+            more_paths,
+            created_at: value.created_at.and_utc().timestamp(),
+            read: value.read_at.is_some(),
+        }
+    }
+}
+
+/// Adds a watch of `user` on `branch` and `path` of the repository at `repo_path`. A branch
+/// is either a full ref name or the name of a branch.
+pub async fn add_watch(
+    context: &Context,
+    user: &str,
+    repo_path: &str,
+    branch: Option<&str>,
+    path: Option<&str>,
+) -> Result<Watch, MegaError> {
+    let ref_name = branch
+        .map(str::trim)
+        .filter(|b| !b.is_empty())
+        .map(normalize_ref);
+    let path = path.and_then(normalize_path);
+    Ok(context
+        .services
+        .watch_storage
+        .add_watch(user, &normalize_repo_path(repo_path), ref_name, path)
+        .await?
+        .into())
+}
+
+pub async fn remove_watch(context: &Context, user: &str, id: i64) -> Result<bool, MegaError> {
+    context.services.watch_storage.remove_watch(id, user).await
+}
+
+pub async fn user_watches(context: &Context, user: &str) -> Result<Vec<Watch>, MegaError> {
+    Ok(context
+        .services
+        .watch_storage
+        .get_watches(user)
+        .await?
+        .into_iter()
+        .map(Watch::from)
+        .collect())
+}
+
+/// The notifications of `user` before the notification `before`, newest first.
+pub async fn user_notifications(
+    context: &Context,
+    user: &str,
+    before: Option<i64>,
+    unread: bool,
+    limit: Option<u64>,
+) -> Result<Vec<WatchNotification>, MegaError> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    Ok(context
+        .services
+        .watch_storage
+        .get_notifications(user, before, unread, limit)
+        .await?
+        .into_iter()
+        .map(WatchNotification::from)
+        .collect())
+}
+
+/// Marks the notifications `ids` of `user` as read, returns how many were unread.
+pub async fn mark_read(context: &Context, user: &str, ids: Vec<i64>) -> Result<u64, MegaError> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+    context.services.watch_storage.mark_read(user, ids).await
+}
+
+/// Records the notifications of the watches `event` matches, returns those that were not
+/// recorded by an earlier delivery of the event.
+pub async fn notify_watchers(
+    context: &Context,
+    event: &RefUpdateEvent,
+) -> Result<Vec<WatchNotification>, MegaError> {
+    let watches: Vec<mega_watch::Model> = context
+        .services
+        .watch_storage
+        .get_repo_watches(&normalize_repo_path(&event.repo_path))
+        .await?
+        .into_iter()
+        .filter(|w| matches_ref(w.ref_name.as_deref(), &event.ref_name))
+        .filter(|w| event.pusher.as_deref() != Some(w.user_name.as_str()))
+        .collect();
+    if watches.is_empty() {
+        return Ok(Vec::new());
+    }
+    let changes = if watches.iter().any(|w| w.path.is_some()) {
+        changed_paths(context, event).await?
+    } else {
+        Vec::new()
+    };
+
+    let now = Utc::now().naive_utc();
+    let mut notifications = Vec::new();
+    for watch in watches {
+        let Some(paths) = watched_paths(watch.path.as_deref(), &changes) else {
+            continue;
+        };
+        notifications.push(mega_watch_notification::Model {
+            id: generate_id(),
+            user_name: watch.user_name,
+            watch_id: watch.id,
+            event_id: event.id,
+            repo_path: event.repo_path.clone(),
+            ref_name: event.ref_name.clone(),
+            old_id: event.old_id.clone(),
+            new_id: event.new_id.clone(),
+            pusher: event.pusher.clone(),
+            paths: encode_paths(&paths),
+            created_at: now,
+            read_at: None,
+        });
+    }
+    Ok(context
+        .services
+        .watch_storage
+        .save_notifications(notifications)
+        .await?
+        .into_iter()
+        .map(WatchNotification::from)
+        .collect())
+}
+
+/// The files the ref update changed, between the trees of the old and the new commit.
+async fn changed_paths(
+    context: &Context,
+    event: &RefUpdateEvent,
+) -> Result<Vec<String>, MegaError> {
+    let repo = context
+        .services
+        .mega_storage
+        .find_git_repo(&event.repo_path)
+        .await?
+        .map(Repo::from)
+        .unwrap_or_else(Repo::empty);
+    let replacements = Replacements::load(context, &repo).await?;
+    let mut trees = Vec::new();
+    for id in [&event.old_id, &event.new_id] {
+        let tree = match SHA1::from_str(id).ok().filter(|_| id != ZERO_ID) {
+            Some(id) => replace::read_commit(context, &repo, &replacements, &id)
+                .await?
+                .map(|commit| commit.tree_id),
+            None => None,
+        };
+        trees.push(tree);
+    }
+    Ok(compare::diff_trees(context, trees[0], trees[1])
+        .await?
+        .into_iter()
+        .map(|change| change.path)
+        .collect())
+}
+
+/// Whether a watch of `watched`, a full ref name, covers an update of `ref_name`.
+fn matches_ref(watched: Option<&str>, ref_name: &str) -> bool {
+    watched.is_none_or(|watched| watched == ref_name)
+}
+
+/// The paths of `changes` under the watched `path`, None when the watch is not concerned. A
+/// watch of the whole tree is concerned by any update, even one changing no file.
+fn watched_paths(path: Option<&str>, changes: &[String]) -> Option<Vec<String>> {
+    let Some(path) = path else {
+        return Some(Vec::new());
+    };
+    let paths: Vec<String> = changes
+        .iter()
+        .filter(|change| {
+            change.as_str() == path
+                || change
+                    .strip_prefix(path)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+        .cloned()
+        .collect();
+    (!paths.is_empty()).then_some(paths)
+}
+
+/// One path per line, with the count of those left out as `+<count>` on the last line.
+fn encode_paths(paths: &[String]) -> String {
+    let mut lines: Vec<String> = paths.iter().take(MAX_PATHS).cloned().collect();
+    if paths.len() > MAX_PATHS {
+        lines.push(format!("+{}", paths.len() - MAX_PATHS));
+    }
+    lines.join("\n")
+}
+
+fn normalize_ref(branch: &str) -> String {
+    if branch.starts_with("refs/") {
+        branch.to_owned()
+    } else {
+        format!("refs/heads/{}", branch)
+    }
+}
+
+/// The path without its leading and trailing slashes, None for the root.
+fn normalize_path(path: &str) -> Option<String> {
+    let path = path.trim().trim_matches('/');
+    (!path.is_empty()).then(|| path.to_owned())
+}
+
+fn normalize_repo_path(repo_path: &str) -> String {
+    format!("/{}", repo_path.trim().trim_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use callisto::mega_watch_notification;
+
+    use super::{
+        encode_paths, matches_ref, normalize_path, normalize_ref, normalize_repo_path,
+        watched_paths, WatchNotification, MAX_PATHS,
+    };
+
+    #[test]
+    fn test_matches_ref() {
+        assert!(matches_ref(None, "refs/heads/feature"));
+        assert!(matches_ref(Some("refs/heads/main"), "refs/heads/main"));
+        assert!(!matches_ref(Some("refs/heads/main"), "refs/heads/main2"));
+        assert_eq!(normalize_ref("main"), "refs/heads/main");
+        assert_eq!(normalize_ref("refs/tags/v1"), "refs/tags/v1");
+    }
+
+    #[test]
+    fn test_watched_paths() {
+        let changes = vec![
+            String::from("README.md"),
+            String::from("src/lib.rs"),
+            String::from("src/main.rs"),
+            String::from("src2/main.rs"),
+        ];
+        assert_eq!(watched_paths(None, &changes), Some(vec![]));
+        assert_eq!(
+            watched_paths(Some("src"), &changes),
+            Some(vec![
+                String::from("src/lib.rs"),
+                String::from("src/main.rs")
+            ])
+        );
+        assert_eq!(
+            watched_paths(Some("README.md"), &changes),
+            Some(vec![String::from("README.md")])
+        );
+        assert_eq!(watched_paths(Some("docs"), &changes), None);
+        assert_eq!(normalize_path("/src/"), Some(String::from("src")));
+        assert_eq!(normalize_path("/"), None);
+        assert_eq!(normalize_repo_path("project/"), "/project");
+        assert_eq!(normalize_repo_path("/"), "/");
+    }
+
+    #[test]
+    fn test_encode_paths() {
+        let paths: Vec<String> = (0..MAX_PATHS + 2).map(|i| format!("f{}", i)).collect();
+        let model = mega_watch_notification::Model {
+            id: 1,
+            user_name: String::from("alice"),
+            watch_id: 2,
+            event_id: 3,
+            repo_path: String::from("/project"),
+            ref_name: String::from("refs/heads/main"),
+            old_id: "a".repeat(40),
+            new_id: "b".repeat(40),
+            pusher: Some(String::from("bob")),
+            paths: encode_paths(&paths),
+            created_at: NaiveDateTime::default(),
+            read_at: None,
+        };
+        let notification = WatchNotification::from(model.clone());
+        assert_eq!(notification.paths, paths[..MAX_PATHS]);
+        assert_eq!(notification.more_paths, 2);
+
+        let whole_tree = WatchNotification::from(mega_watch_notification::Model {
+            paths: encode_paths(&[]),
+            ..model
+        });
+        assert!(whole_tree.paths.is_empty());
+        assert_eq!(whole_tree.more_paths, 0);
+    }
+}
@@ -0,0 +1,76 @@
+# English messages, every key must also be present in zh.ftl.
+
+## CLI
+cli-help-config = Sets a config file work directory
+cli-about-init = Initialize the directory structure
+cli-about-service = Start different kinds of server: for example https, ssh, p2p
+cli-about-https = Start Git HTTPS server
+cli-about-ssh = Start Git SSH server
+cli-about-daemon = Start the read-only git:// daemon serving the public repositories
+cli-about-p2p = Start p2p node
+cli-about-start = Start multiple server by given params
+cli-about-fsck = Check pack files: checksums, delta chains, object formats and links
+cli-about-gc = Housekeeping of the storage: prune unreachable objects, repack the rest and write or remove the multi-pack-index and bitmaps of pack directories
+cli-about-shards = Add shards to a storage spread by object id prefix and move the objects of the buckets they take over
+cli-about-restore-refs = Compute the refs at a past instant from the log of the ref updates and report the differences, restore them with --apply
+cli-about-map-objects = Record the SHA-256 name of the objects reachable from the refs, to serve them to SHA-256 clients
+cli-about-bundle = Move repositories in and out of mega as git bundles, without a network protocol
+cli-about-bundle-create = Write the refs of a repository and their objects to a bundle file
+cli-about-bundle-unbundle = Store the objects of a bundle file and update the refs it carries
+cli-about-storage-report = Report the storage taken by each type of object, the largest blobs and the fastest-growing paths, the candidates to move to LFS
+cli-about-lfs-migrate = Move the large files of a repository to LFS, rewriting the history of its branches or from now on
+cli-about-rotate-keys = Rewrap the objects encrypted at rest with the current key encryption key after a rotation
+cli-about-compare = Compare two revisions of a repository: commits between, merge base and changed files
+cli-about-object = Read the objects from the storage, without a running service
+cli-about-object-show = Print the content of an object
+cli-about-mr = Read the merge requests from the storage, without a running service
+cli-about-mr-list = List the merge requests, the last updated first
+cli-about-log = Print the history of a repository straight from the storage, newest first
+cli-about-show = Print a commit and the files it changes
+cli-unknown-subcommand = Unknown subcommand: { $cmd }
+
+## HTTP
+http-user-suspended = User { $user } is suspended
+
+## Rejected pushes, the keys are prefixed by the documentation key of the rejection
+push-rejected = error: update of { $ref } rejected: { $reason }
+push-more = ... and { $count } more
+push-hint = hint: { $hint }
+push-see-doc = hint: see { $doc }
+push-locked-path = { $path } (locked by { $owner } at { $time })
+push-large-blob = { $id } ({ $size } bytes)
+push-unpack-failed-reason = parse commit tree from obj failed
+push-unpack-failed-hint = the pack sent by the client is invalid, run `git fsck` and push again
+push-locked-paths-reason = { $count } locked path(s)
+push-locked-paths-hint = ask the lock owners to release them with `git lfs unlock`, or drop your changes to these paths
+push-lock-check-failed-reason = unable to verify file locks
+push-lock-check-failed-hint = this is a server side problem, retry later or contact the administrator
+push-blob-too-large-reason = { $count } blob(s) larger than { $limit } bytes
+push-blob-too-large-hint = track large files with Git LFS (`git lfs track`) and rewrite the commits that added them
+push-missing-objects-reason = { $count } missing object(s)
+push-missing-objects-hint = the pushed history is incomplete, fetch the full history and push again
+push-connectivity-check-failed-reason = unable to verify the pushed objects
+push-connectivity-check-failed-hint = this is a server side problem, retry later or contact the administrator
+push-stale-ref-reason = fetch first
+push-stale-ref-hint = the ref was updated since you fetched it, fetch and integrate the new commits, then push again
+push-merge-failed-reason = the merge was rolled back
+push-merge-failed-hint = the push left no change on the server, retry later or contact the administrator
+push-policy-protected = the policy of { $dir } protects the branch from deletion and force pushes
+push-policy-owner = { $path }: only { $owners } can change it (policy of { $dir })
+push-policy-file-size = { $path }: { $size } bytes, above the limit of { $limit } bytes of { $dir }
+push-policy-check = the policy of { $dir } requires the check { $check }, which the server does not run
+push-policy-invalid = the policy of { $dir } is invalid: { $error }
+push-policy-violations-reason = { $count } policy violation(s)
+push-policy-violations-hint = the rules come from the `.mega/policy.toml` files of the listed directories, change them through a merge request
+push-policy-check-failed-reason = unable to load the directory policies
+push-policy-check-failed-hint = this is a server side problem, retry later or contact the administrator
+push-hook-refused-reason = refused by the { $hook } hook
+push-hook-refused-hint = the hooks run on the server, change the update as their message says or ask the administrator about their rules
+push-hook-failed-reason = unable to run the { $hook } hook
+push-hook-failed-hint = this is a server side problem, retry later or contact the administrator
+push-atomic-failed-reason = atomic push failed
+push-atomic-failed-hint = another ref of the atomic push was refused, fix it and push all of them again
+
+## Dry-run pushes (`git push -o dry-run`)
+push-dry-run = dry run: the push was checked, nothing was saved
+push-dry-run-reason = dry run, the update would be accepted
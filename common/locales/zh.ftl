@@ -0,0 +1,76 @@
+# 中文消息，键与 en.ftl 保持一致。
+
+## CLI
+cli-help-config = 设置配置文件的工作目录
+cli-about-init = 初始化目录结构
+cli-about-service = 启动不同类型的服务，例如 https、ssh、p2p
+cli-about-https = 启动 Git HTTPS 服务
+cli-about-ssh = 启动 Git SSH 服务
+cli-about-daemon = 启动只读的 git:// 守护进程，提供公开仓库的匿名克隆
+cli-about-p2p = 启动 p2p 节点
+cli-about-start = 按给定参数启动多个服务
+cli-about-fsck = 检查 pack 文件：校验和、delta 链、对象格式与对象间的引用
+cli-about-gc = 整理存储：清除不可达的对象，重新打包其余对象，并写入或移除 pack 目录的 multi-pack-index 和 bitmap
+cli-about-shards = 为按对象 ID 前缀分片的存储添加分片，并迁移新分片接管的桶中的对象
+cli-about-restore-refs = 根据引用更新日志计算过去某一时刻的引用并报告差异，使用 --apply 恢复
+cli-about-map-objects = 记录引用可达对象的 SHA-256 名称，以便向 SHA-256 客户端提供服务
+cli-about-bundle = 以 git bundle 文件导入导出仓库，无需网络协议
+cli-about-bundle-create = 将仓库的引用及其对象写入 bundle 文件
+cli-about-bundle-unbundle = 存储 bundle 文件中的对象并更新其携带的引用
+cli-about-storage-report = 报告各类对象占用的存储、最大的 blob 和增长最快的路径，即可迁移到 LFS 的候选文件
+cli-about-lfs-migrate = 将仓库中的大文件迁移到 LFS，可重写分支历史或仅从此刻起生效
+cli-about-rotate-keys = 密钥轮换后，用当前的密钥加密密钥重新封装静态加密的对象
+cli-about-compare = 比较仓库的两个版本：之间的提交、合并基础和变更的文件
+cli-about-object = 直接从存储读取对象，无需运行服务
+cli-about-object-show = 打印对象的内容
+cli-about-mr = 直接从存储读取合并请求，无需运行服务
+cli-about-mr-list = 列出合并请求，最近更新的在前
+cli-about-log = 直接从存储打印仓库的历史，最新的在前
+cli-about-show = 打印提交及其变更的文件
+cli-unknown-subcommand = 未知的子命令：{ $cmd }
+
+## HTTP
+http-user-suspended = 用户 { $user } 已被停用
+
+## 被拒绝的推送，键以拒绝原因的文档键为前缀
+push-rejected = 错误：{ $ref } 的更新被拒绝：{ $reason }
+push-more = ……以及另外 { $count } 项
+push-hint = 提示：{ $hint }
+push-see-doc = 提示：参见 { $doc }
+push-locked-path = { $path }（由 { $owner } 于 { $time } 锁定）
+push-large-blob = { $id }（{ $size } 字节）
+push-unpack-failed-reason = 无法解析推送的对象
+push-unpack-failed-hint = 客户端发送的 pack 无效，请运行 `git fsck` 后重新推送
+push-locked-paths-reason = { $count } 个路径已被锁定
+push-locked-paths-hint = 请锁的持有者使用 `git lfs unlock` 释放锁，或撤销对这些路径的修改
+push-lock-check-failed-reason = 无法校验文件锁
+push-lock-check-failed-hint = 这是服务端的问题，请稍后重试或联系管理员
+push-blob-too-large-reason = { $count } 个 blob 超过 { $limit } 字节
+push-blob-too-large-hint = 请使用 Git LFS（`git lfs track`）管理大文件，并重写添加了这些文件的提交
+push-missing-objects-reason = 缺少 { $count } 个对象
+push-missing-objects-hint = 推送的历史不完整，请获取完整历史后重新推送
+push-connectivity-check-failed-reason = 无法校验推送的对象
+push-connectivity-check-failed-hint = 这是服务端的问题，请稍后重试或联系管理员
+push-stale-ref-reason = 请先获取
+push-stale-ref-hint = 获取之后该引用已被更新，请获取并整合新的提交后重新推送
+push-merge-failed-reason = 合并已回滚
+push-merge-failed-hint = 推送未在服务端留下任何修改，请稍后重试或联系管理员
+push-policy-protected = { $dir } 的策略禁止删除或强制推送该分支
+push-policy-owner = { $path }：只有 { $owners } 可以修改（{ $dir } 的策略）
+push-policy-file-size = { $path }：{ $size } 字节，超过 { $dir } 限制的 { $limit } 字节
+push-policy-check = { $dir } 的策略要求检查 { $check }，但服务端未运行该检查
+push-policy-invalid = { $dir } 的策略无效：{ $error }
+push-policy-violations-reason = 违反 { $count } 条策略
+push-policy-violations-hint = 规则来自所列目录下的 `.mega/policy.toml` 文件，请通过合并请求修改
+push-policy-check-failed-reason = 无法加载目录策略
+push-policy-check-failed-hint = 这是服务端的问题，请稍后重试或联系管理员
+push-hook-refused-reason = 被 { $hook } 钩子拒绝
+push-hook-refused-hint = 钩子在服务端运行，请按其提示修改更新，或向管理员咨询其规则
+push-hook-failed-reason = 无法运行 { $hook } 钩子
+push-hook-failed-hint = 这是服务端的问题，请稍后重试或联系管理员
+push-atomic-failed-reason = 原子推送失败
+push-atomic-failed-hint = 原子推送中的另一个引用被拒绝，请修正后重新推送全部引用
+
+## 试运行的推送（`git push -o dry-run`）
+push-dry-run = 试运行：推送已检查，未保存任何内容
+push-dry-run-reason = 试运行，该更新可以被接受
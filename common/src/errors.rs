@@ -7,6 +7,8 @@ use thiserror::Error;
 
 use anyhow::Result;
 
+use crate::i18n::Locale;
+
 pub type MegaResult = Result<(), MegaError>;
 
 ///
@@ -30,8 +32,9 @@ impl MegaError {
     }
 
     pub fn unknown_subcommand(cmd: &str) -> MegaError {
+        let msg = Locale::from_env().tr("cli-unknown-subcommand", &[("cmd", cmd)]);
         MegaError {
-            error: anyhow::anyhow!("Unknown subcommand: {}", cmd).into(),
+            error: anyhow::anyhow!(msg).into(),
             code: 1,
         }
     }
@@ -0,0 +1,175 @@
+//!
+//! Fault injection for the tests of the storage layers: wrapped around a storage, a
+//! [`FaultInjector`] decides for each call whether it fails or is delayed, with a probability
+//! set per operation, so that the tests can check a push leaves nothing behind when a write
+//! fails halfway. The decisions follow the seed, a failing run is replayed with the same one.
+//!
+//! Enabled for the unit tests of this crate, other crates use the `fault-injection` feature.
+//!
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::errors::MegaError;
+
+/// The probabilities of the faults of an operation, from 0.0 (never) to 1.0 (every call).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Fault {
+    pub error: f64,
+    pub delay: f64,
+    /// How long a delayed call waits.
+    pub delay_for: Duration,
+}
+
+impl Fault {
+    pub fn error(probability: f64) -> Self {
+        Fault {
+            error: probability,
+            ..Default::default()
+        }
+    }
+
+    pub fn delay(probability: f64, delay_for: Duration) -> Self {
+        Fault {
+            delay: probability,
+            delay_for,
+            ..Default::default()
+        }
+    }
+}
+
+/// What happens to one call: it waits `delay`, then fails if `fail`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub delay: Duration,
+    pub fail: bool,
+}
+
+#[derive(Default)]
+struct State {
+    /// SplitMix64, good enough to pick faults and without a dependency.
+    rng: u64,
+    calls: HashMap<String, usize>,
+    failed: Vec<String>,
+}
+
+pub struct FaultInjector {
+    default: Fault,
+    faults: HashMap<String, Fault>,
+    state: Mutex<State>,
+}
+
+impl FaultInjector {
+    /// An injector without any fault, add them with [`FaultInjector::with_default`] and
+    /// [`FaultInjector::with_fault`].
+    pub fn new(seed: u64) -> Self {
+        FaultInjector {
+            default: Fault::default(),
+            faults: HashMap::new(),
+            state: Mutex::new(State {
+                rng: seed,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// The faults of the operations without their own.
+    pub fn with_default(mut self, fault: Fault) -> Self {
+        self.default = fault;
+        self
+    }
+
+    /// The faults of `operation`, named after the method of the storage, e.g. `put_object`.
+    pub fn with_fault(mut self, operation: &str, fault: Fault) -> Self {
+        self.faults.insert(operation.to_owned(), fault);
+        self
+    }
+
+    pub fn decide(&self, operation: &str) -> Decision {
+        let fault = self.faults.get(operation).unwrap_or(&self.default);
+        let mut state = self.state.lock().unwrap();
+        *state.calls.entry(operation.to_owned()).or_default() += 1;
+        let delay = if state.roll(fault.delay) {
+            fault.delay_for
+        } else {
+            Duration::ZERO
+        };
+        let fail = state.roll(fault.error);
+        if fail {
+            state.failed.push(operation.to_owned());
+        }
+        Decision { delay, fail }
+    }
+
+    /// The error returned by a failing call of `operation`.
+    pub fn error(operation: &str) -> MegaError {
+        MegaError::with_message(&format!("injected fault in {}", operation))
+    }
+
+    /// The calls of `operation` so far, failed or not.
+    pub fn calls(&self, operation: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.calls.get(operation).copied().unwrap_or(0)
+    }
+
+    /// The operations of the failed calls, in order.
+    pub fn failed(&self) -> Vec<String> {
+        self.state.lock().unwrap().failed.clone()
+    }
+}
+
+impl State {
+    fn roll(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        // the 53 high bits as a float in [0, 1)
+        ((z >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Decision, Fault, FaultInjector};
+
+    #[test]
+    fn test_fault_probabilities() {
+        let faults = FaultInjector::new(7)
+            .with_default(Fault::error(0.25))
+            .with_fault("save_ref", Fault::error(1.0))
+            .with_fault("get_ref", Fault::delay(1.0, Duration::from_millis(5)));
+        assert!(faults.decide("save_ref").fail);
+        assert_eq!(
+            faults.decide("get_ref"),
+            Decision {
+                delay: Duration::from_millis(5),
+                fail: false
+            }
+        );
+        let failed = (0..10_000)
+            .filter(|_| faults.decide("put_object").fail)
+            .count();
+        assert!((2_000..3_000).contains(&failed), "{}", failed);
+        assert_eq!(faults.calls("put_object"), 10_000);
+        assert_eq!(faults.failed()[0], "save_ref");
+    }
+
+    #[test]
+    fn test_faults_follow_the_seed() {
+        let run = |seed| {
+            let faults = FaultInjector::new(seed).with_default(Fault::error(0.5));
+            (0..64)
+                .map(|_| faults.decide("merge").fail)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+    }
+}
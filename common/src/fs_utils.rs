@@ -0,0 +1,264 @@
+//!
+//! File system helpers for the object stores and the decode cache that behave the same on
+//! Windows and Unix: paths longer than `MAX_PATH`, files opened by another thread while they
+//! are replaced or removed, and atomic replacement of an existing file.
+//!
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Attempts of an operation failing with a transient error, with a linear backoff of 10ms.
+const RETRIES: u32 = 10;
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a path usable past the 260 characters limit of Win32: absolute and with the
+/// `\\?\` prefix on Windows, unchanged elsewhere. `std::fs` already does this itself,
+/// it is needed for the paths passed to Win32 directly.
+pub fn long_path(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    {
+        use std::ffi::OsString;
+
+        let absolute = match std::path::absolute(path) {
+            Ok(path) => path,
+            Err(_) => return path.to_path_buf(),
+        };
+        let raw = absolute.as_os_str().to_string_lossy();
+        if raw.starts_with(r"\\?\") {
+            return absolute;
+        }
+        let mut prefixed = OsString::new();
+        match raw.strip_prefix(r"\\") {
+            Some(unc) => {
+                prefixed.push(r"\\?\UNC\");
+                prefixed.push(unc);
+            }
+            None => {
+                prefixed.push(r"\\?\");
+                prefixed.push(absolute.as_os_str());
+            }
+        }
+        PathBuf::from(prefixed)
+    }
+    #[cfg(not(windows))]
+    {
+        path.to_path_buf()
+    }
+}
+
+/// Errors caused by another handle on the same file, which go away once it is closed.
+/// Windows reports them while a file is read, replaced or pending deletion, Unix never does.
+pub fn is_transient(err: &io::Error) -> bool {
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Foundation::{
+            ERROR_ACCESS_DENIED, ERROR_LOCK_VIOLATION, ERROR_SHARING_VIOLATION,
+            ERROR_UNABLE_TO_REMOVE_REPLACED,
+        };
+        matches!(
+            err.raw_os_error().map(|code| code as u32),
+            Some(ERROR_ACCESS_DENIED)
+                | Some(ERROR_SHARING_VIOLATION)
+                | Some(ERROR_LOCK_VIOLATION)
+                | Some(ERROR_UNABLE_TO_REMOVE_REPLACED)
+        )
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = err;
+        false
+    }
+}
+
+/// Runs `op` again while it fails with a transient error.
+pub fn retry<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if is_transient(&e) && attempt < RETRIES => {
+                attempt += 1;
+                sleep(Duration::from_millis(10 * attempt as u64));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Opens a file for reading that other threads can still read, replace or delete meanwhile.
+pub fn open_shared(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        use windows_sys::Win32::Storage::FileSystem::{
+            FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE,
+        };
+        options.share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE);
+    }
+    retry(|| options.open(path))
+}
+
+/// Reads the whole file, see [`open_shared`].
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    open_shared(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Moves `from` to `to`, replacing `to` atomically if it exists.
+pub fn replace(from: &Path, to: &Path) -> io::Result<()> {
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        use windows_sys::Win32::Storage::FileSystem::{
+            MoveFileExW, ReplaceFileW, MOVEFILE_REPLACE_EXISTING, MOVEFILE_WRITE_THROUGH,
+            REPLACEFILE_IGNORE_MERGE_ERRORS,
+        };
+
+        let wide = |p: &Path| -> Vec<u16> {
+            p.as_os_str()
+                .encode_wide()
+                .chain(std::iter::once(0))
+                .collect()
+        };
+        let (from, to) = (wide(&long_path(from)), wide(&long_path(to)));
+        retry(|| {
+            // ReplaceFileW keeps the identity of the target and never leaves it missing,
+            // MoveFileExW is needed when there is nothing to replace yet
+            let replaced = unsafe {
+                ReplaceFileW(
+                    to.as_ptr(),
+                    from.as_ptr(),
+                    std::ptr::null(),
+                    REPLACEFILE_IGNORE_MERGE_ERRORS,
+                    std::ptr::null(),
+                    std::ptr::null(),
+                )
+            };
+            if replaced != 0 {
+                return Ok(());
+            }
+            let moved = unsafe {
+                MoveFileExW(
+                    from.as_ptr(),
+                    to.as_ptr(),
+                    MOVEFILE_REPLACE_EXISTING | MOVEFILE_WRITE_THROUGH,
+                )
+            };
+            if moved != 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        })
+    }
+    #[cfg(not(windows))]
+    {
+        fs::rename(from, to)
+    }
+}
+
+/// A path next to `path` for a file to be moved in place once written, that no other writer
+/// uses.
+pub fn temp_path(path: &Path) -> io::Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut temp_name = name.to_os_string();
+    temp_name.push(format!(
+        ".{}.{}.temp",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    Ok(path.with_file_name(temp_name))
+}
+
+/// Writes `data` to a temporary file next to `path` then moves it in place, so readers see
+/// either the old or the new content. Concurrent writers of the same path don't conflict.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temp = temp_path(path)?;
+    {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        file.write_all(data)?;
+    }
+    replace(&temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+/// Removes a directory tree, waiting for other handles on its files to be closed.
+pub fn remove_dir_all(path: &Path) -> io::Result<()> {
+    retry(|| match fs::remove_dir_all(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::thread;
+
+    use super::{long_path, read, remove_dir_all, write_atomic};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mega_fs_utils_{}", name));
+        remove_dir_all(&dir).unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_write_atomic_replaces() {
+        let dir = test_dir("replace");
+        let path = dir.join("refs_heads_main");
+        write_atomic(&path, b"old").unwrap();
+        // keep a reader open while replacing, Windows refuses this without share modes
+        let reader = super::open_shared(&path).unwrap();
+        write_atomic(&path, b"new").unwrap();
+        drop(reader);
+        assert_eq!(read(&path).unwrap(), b"new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let dir = test_dir("concurrent");
+        let path = dir.join("object");
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || write_atomic(&path, b"same content"))
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+        assert_eq!(read(&path).unwrap(), b"same content");
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_long_path() {
+        let dir = test_dir("long");
+        let mut path = long_path(&dir);
+        for i in 0..12 {
+            path.push(format!("{:02}-{}", i, "d".repeat(30)));
+        }
+        fs::create_dir_all(&path).unwrap();
+        let file = path.join("f".repeat(40));
+        write_atomic(&file, b"deep").unwrap();
+        assert_eq!(read(&file).unwrap(), b"deep");
+        remove_dir_all(&long_path(&dir)).unwrap();
+    }
+}
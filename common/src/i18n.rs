@@ -0,0 +1,145 @@
+//!
+//! Translations of the messages shown to users by the CLI and sent to git clients.
+//!
+//! The catalogs under `common/locales` use the simple subset of the Fluent syntax: one
+//! `key = text` per line, `# comments` and `{ $name }` placeables. A key missing from a
+//! catalog falls back to English, then to the key itself.
+//!
+use std::collections::HashMap;
+use std::env;
+use std::sync::OnceLock;
+
+const EN: &str = include_str!("../locales/en.ftl");
+const ZH: &str = include_str!("../locales/zh.ftl");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    En,
+    Zh,
+}
+
+impl Locale {
+    /// Parses a language tag (`zh-CN`) or a POSIX locale (`zh_CN.UTF-8`).
+    pub fn from_tag(tag: &str) -> Option<Locale> {
+        let lang = tag
+            .trim()
+            .split(['-', '_', '.'])
+            .next()?
+            .to_ascii_lowercase();
+        match lang.as_str() {
+            "en" | "c" | "posix" => Some(Locale::En),
+            "zh" => Some(Locale::Zh),
+            _ => None,
+        }
+    }
+
+    /// Picks the supported language with the highest quality from an `Accept-Language` header,
+    /// git sends one built from the locale of the user.
+    pub fn from_accept_language(header: &str) -> Option<Locale> {
+        let mut best: Option<(Locale, f32)> = None;
+        for range in header.split(',') {
+            let mut parts = range.split(';');
+            let locale = match parts.next().and_then(Locale::from_tag) {
+                Some(locale) => locale,
+                None => continue,
+            };
+            let quality = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+                best = Some((locale, quality));
+            }
+        }
+        best.map(|(locale, _)| locale)
+    }
+
+    /// The configured language: `MEGA_LANG`, then `LANG`, then English.
+    pub fn from_env() -> Locale {
+        ["MEGA_LANG", "LANG"]
+            .iter()
+            .filter_map(|key| env::var(key).ok())
+            .find_map(|value| Locale::from_tag(&value))
+            .unwrap_or_default()
+    }
+
+    fn catalog(self) -> &'static HashMap<&'static str, &'static str> {
+        static EN_CATALOG: OnceLock<HashMap<&str, &str>> = OnceLock::new();
+        static ZH_CATALOG: OnceLock<HashMap<&str, &str>> = OnceLock::new();
+        match self {
+            Locale::En => EN_CATALOG.get_or_init(|| parse_catalog(EN)),
+            Locale::Zh => ZH_CATALOG.get_or_init(|| parse_catalog(ZH)),
+        }
+    }
+
+    /// Translates `key`, replacing every `{ $name }` by the value of `name` in `args`.
+    pub fn tr(self, key: &str, args: &[(&str, &str)]) -> String {
+        let text = self
+            .catalog()
+            .get(key)
+            .or_else(|| Locale::En.catalog().get(key))
+            .copied()
+            .unwrap_or(key);
+        let mut message = text.to_owned();
+        for (name, value) in args {
+            message = message.replace(&format!("{{ ${} }}", name), value);
+        }
+        message
+    }
+}
+
+fn parse_catalog(source: &'static str) -> HashMap<&'static str, &'static str> {
+    source
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once(" = "))
+        .map(|(key, text)| (key.trim(), text.trim()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_catalog, Locale, EN, ZH};
+
+    #[test]
+    fn test_from_accept_language() {
+        assert_eq!(
+            Locale::from_accept_language("zh-CN, en;q=0.9, *;q=0.1"),
+            Some(Locale::Zh)
+        );
+        assert_eq!(
+            Locale::from_accept_language("fr-FR, en-US;q=0.5, zh;q=0.3"),
+            Some(Locale::En)
+        );
+        assert_eq!(
+            Locale::from_accept_language("zh;q=0, en;q=0.2"),
+            Some(Locale::En)
+        );
+        assert_eq!(Locale::from_accept_language("fr, de"), None);
+        assert_eq!(Locale::from_tag("zh_CN.UTF-8"), Some(Locale::Zh));
+    }
+
+    #[test]
+    fn test_tr() {
+        let args = [("cmd", "foo")];
+        assert_eq!(
+            Locale::En.tr("cli-unknown-subcommand", &args),
+            "Unknown subcommand: foo"
+        );
+        assert_eq!(
+            Locale::Zh.tr("cli-unknown-subcommand", &args),
+            "未知的子命令：foo"
+        );
+        assert_eq!(Locale::Zh.tr("no-such-key", &[]), "no-such-key");
+    }
+
+    #[test]
+    fn test_catalogs_have_the_same_keys() {
+        let mut en: Vec<_> = parse_catalog(EN).into_keys().collect();
+        let mut zh: Vec<_> = parse_catalog(ZH).into_keys().collect();
+        en.sort();
+        zh.sort();
+        assert_eq!(en, zh);
+    }
+}
@@ -1,4 +1,8 @@
 pub mod enums;
 pub mod errors;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod fault;
+pub mod fs_utils;
+pub mod i18n;
 pub mod model;
 pub mod utils;
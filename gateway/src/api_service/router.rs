@@ -1,29 +1,79 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    response::IntoResponse,
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
-    Json, Router,
+    Extension, Json, Router,
 };
 
+use callisto::db_enums::OperationType;
+use ceres::activity::{self, ActivityItem, Contribution};
+use ceres::analytics::{self, Coupling, DirStats, WeekStats};
+use ceres::archive::{self, ArchiveFormat};
+use ceres::blame::{self, Blame};
+use ceres::branch_cleanup::BranchCleanup;
+use ceres::code_nav::{self, IndexSummary, Location};
+use ceres::code_search::{self, CodeSearchResult};
+use ceres::compare::{self as comparison, Comparison};
+use ceres::email_reply;
+use ceres::impact::{self, Impact};
+use ceres::last_commit::{self, EntryCommit};
+use ceres::replace::{self, Replacement};
+use ceres::search::{self, SearchKind, SearchResult};
+use ceres::storage_report::{self, ReportOptions, StorageReport};
+use ceres::watch::{self, Watch, WatchNotification};
+use chrono::{DateTime, Days, NaiveDate, NaiveDateTime, Utc};
+use common::errors::MegaError;
 use ganymede::model::create_file::CreateFileInfo;
 use git::internal::pack::counter::GitTypeCounter;
 use jupiter::context::Context;
+use jupiter::settings::Settings;
+use jupiter::storage::audit_storage::OperationFilter;
+use venus::repo::Repo;
 
 use crate::{
     api_service::obj_service::ObjectService,
+    auth::policy::{Action, Policy},
+    auth::AuthUser,
+    lifecycle::{self, BranchCleanupConfig},
     model::{
+        admin::{
+            AdminUser, BlameCacheStats, ClearBlameCache, ClearedBlameCache, DisableUser,
+            ExpiredSessions, RepoVisibility, RevokeKeys, RevokedKeys, SshKey,
+            StorageReportRequest, UserRequest,
+        },
+        audit::{GitOperation, LiftSuspension, Suspension},
+        mr::{MrArchive, RestoreMr},
         objects::{BlobObjects, Directories},
-        query::DirectoryQuery,
+        pin::{ObjectPin, PinRequest, UnpinRequest},
+        query::{
+            ActivityQuery, AnalyticsQuery, ArchiveQuery, BlameQuery, CodeIndexQuery, CodeNavQuery,
+            CodeSearchQuery, CompareQuery, ContributionQuery, DirectoryQuery, ImpactQuery,
+            NotificationQuery, OperationQuery, PinQuery, ReviewQuery, SearchQuery, TreeCommitQuery,
+            UserQuery,
+        },
+        replace::{RemoveReplacement, ReplaceRequest},
+        review::{
+            DraftComment, EmailReply, MrComment, MrReviews, PublishReview, PublishedReview,
+            RemoveComment,
+        },
+        settings::ResetSettings,
+        watch::{ReadCount, ReadNotifications, RemoveWatch, WatchRequest},
     },
+    review_notice::ReviewNotifier,
 };
 
 #[derive(Clone)]
 pub struct ApiServiceState {
     pub object_service: ObjectService,
     pub context: Context,
+    /// The authorization policy, for the handlers checking the paths below the one requested.
+    pub policy: Arc<Policy>,
+    pub review_notifier: Arc<ReviewNotifier>,
 }
 
 pub fn routers() -> Router<ApiServiceState> {
@@ -35,12 +85,88 @@ pub fn routers() -> Router<ApiServiceState> {
         .route("/count-objs", get(get_count_nums))
         .route("/init", get(init))
         .route("/create_file", post(create_file))
+        .route("/audit/operations", get(get_git_operations))
+        .route("/audit/suspensions", get(get_suspensions))
+        .route("/audit/suspensions/lift", post(lift_suspension))
+        .route("/admin/users", get(get_users))
+        .route("/admin/users/disable", post(disable_user))
+        .route("/admin/users/enable", post(enable_user))
+        .route("/admin/users/operations", get(get_user_operations))
+        .route("/admin/users/keys", get(get_user_keys))
+        .route("/admin/users/keys/revoke", post(revoke_user_keys))
+        .route("/admin/users/token/revoke", post(revoke_user_token))
+        .route("/admin/users/sessions/expire", post(expire_user_sessions))
+        .route("/admin/repos/public", post(set_repo_public))
+        .route("/admin/blame-cache", get(get_blame_cache))
+        .route("/admin/blame-cache/clear", post(clear_blame_cache))
+        .route("/admin/storage-report", get(get_storage_report).post(generate_storage_report))
+        .route("/pins", get(get_pins).post(pin_object))
+        .route("/pins/remove", post(unpin_object))
+        .route("/replace", get(get_replacements).post(replace_object))
+        .route("/replace/remove", post(remove_replacement))
+        .route("/mr/archives", get(get_mr_archives))
+        .route("/compare", get(compare))
+        .route("/mr/impact", get(get_mr_impact))
+        .route("/tree/commits", get(get_tree_commits))
+        .route("/blame", get(get_blame))
+        .route("/archive", get(get_archive))
+        .route("/search", get(search_items))
+        .route("/search/code", get(search_code))
+        .route("/users/activity", get(get_user_activity))
+        .route("/users/contributions", get(get_contributions))
+        .route("/watches", get(get_watches).post(add_watch))
+        .route("/watches/remove", post(remove_watch))
+        .route("/notifications", get(get_notifications))
+        .route("/notifications/read", post(read_notifications))
+        .route("/analytics/hotspots", get(get_hotspots))
+        .route("/analytics/frequency", get(get_code_frequency))
+        .route("/analytics/coupling", get(get_coupling))
+        .route("/mr/archives/restore", post(restore_mr))
+        .route("/mr/reviews", get(get_reviews).post(publish_review))
+        .route("/mr/comments", post(add_comment))
+        .route("/mr/comments/remove", post(remove_comment))
+        .route(
+            "/code-nav/upload",
+            post(upload_code_index).layer(DefaultBodyLimit::max(code_nav::MAX_INDEX_SIZE)),
+        )
+        .route("/code-nav/definition", get(get_definitions))
+        .route("/code-nav/references", get(get_references))
+        .route("/branch-cleanup", get(get_branch_cleanup))
+        .route("/settings", get(get_settings).post(update_settings))
+        .route("/settings/reset", post(reset_settings))
+}
+
+/// The routes authenticating their requests themselves, they are served outside the
+/// authorization of the other routes.
+pub fn webhook_routers() -> Router<ApiServiceState> {
+    Router::new().route("/mr/email-replies", post(add_email_reply))
+}
+
+/// Refuses the reads of an object by its id to the users who may not read every path, the
+/// paths of the object are unknown and the policy cannot be checked against them.
+fn check_read_by_id(
+    state: &ApiServiceState,
+    user: Option<Extension<AuthUser>>,
+) -> Result<(), (StatusCode, String)> {
+    let user = user.map(|Extension(user)| user.name);
+    if state
+        .policy
+        .is_allowed_everywhere(user.as_deref(), Action::Read)
+    {
+        return Ok(());
+    }
+    Err((
+        StatusCode::FORBIDDEN,
+        String::from("reading an object by its id needs the read permission on every path"),
+    ))
 }
 
 async fn get_blob_object(
     Query(query): Query<HashMap<String, String>>,
+    user: Option<Extension<AuthUser>>,
     state: State<ApiServiceState>,
 ) -> Result<Json<BlobObjects>, (StatusCode, String)> {
+    check_read_by_id(&state, user)?;
     let object_id = query.get("object_id").unwrap();
     state.object_service.get_blob_objects(object_id).await
 }
@@ -54,8 +180,10 @@ async fn get_directories(
 
 async fn get_origin_object(
     Query(query): Query<HashMap<String, String>>,
+    user: Option<Extension<AuthUser>>,
     state: State<ApiServiceState>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_read_by_id(&state, user)?;
     let object_id = query.get("object_id").unwrap();
     let repo_path = query.get("repo_path").expect("repo_path is required");
     state
@@ -98,3 +226,1077 @@ async fn create_file(
         .unwrap();
     Ok(Json(json))
 }
+
+async fn get_git_operations(
+    Query(query): Query<OperationQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<GitOperation>>, (StatusCode, String)> {
+    let operation = match query.operation.as_deref() {
+        None => None,
+        Some("clone") => Some(OperationType::Clone),
+        Some("fetch") => Some(OperationType::Fetch),
+        Some("push") => Some(OperationType::Push),
+        Some(other) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("unknown operation: {}", other),
+            ))
+        }
+    };
+    let filter = OperationFilter {
+        user_name: query.user,
+        repo_path: query.repo_path,
+        client_ip: query.client_ip,
+        operation,
+        since: parse_time(query.since)?,
+        until: parse_time(query.until)?,
+        limit: Some(query.limit.unwrap_or(100)),
+        offset: query.offset,
+    };
+    let operations = state
+        .context
+        .services
+        .audit_storage
+        .get_operations(filter)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(operations.into_iter().map(GitOperation::from).collect()))
+}
+
+async fn get_suspensions(
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<Suspension>>, (StatusCode, String)> {
+    let suspensions = state
+        .context
+        .services
+        .audit_storage
+        .get_suspensions()
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(suspensions.into_iter().map(Suspension::from).collect()))
+}
+
+async fn lift_suspension(
+    state: State<ApiServiceState>,
+    Json(json): Json<LiftSuspension>,
+) -> Result<Json<LiftSuspension>, (StatusCode, String)> {
+    state
+        .context
+        .services
+        .audit_storage
+        .lift_suspension(&json.user_name)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(json))
+}
+
+async fn get_users(
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<AdminUser>>, (StatusCode, String)> {
+    let internal_error = |err: MegaError| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    let services = &state.context.services;
+    let users = services
+        .user_storage
+        .list_users()
+        .await
+        .map_err(internal_error)?;
+    let disabled: HashSet<String> = services
+        .audit_storage
+        .get_suspensions()
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|suspension| suspension.user_name)
+        .collect();
+    Ok(Json(
+        users
+            .into_iter()
+            .map(|user| {
+                let disabled = disabled.contains(&user.name);
+                AdminUser::new(user, disabled)
+            })
+            .collect(),
+    ))
+}
+
+/// Disables a user with a suspension, every request of the user is refused until it is lifted.
+async fn disable_user(
+    state: State<ApiServiceState>,
+    Json(json): Json<DisableUser>,
+) -> Result<Json<DisableUser>, (StatusCode, String)> {
+    let reason = match json.reason.as_str() {
+        "" => "disabled by an admin",
+        reason => reason,
+    };
+    state
+        .context
+        .services
+        .audit_storage
+        .suspend_user(&json.user_name, reason)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(json))
+}
+
+async fn enable_user(
+    state: State<ApiServiceState>,
+    Json(json): Json<UserRequest>,
+) -> Result<Json<UserRequest>, (StatusCode, String)> {
+    state
+        .context
+        .services
+        .audit_storage
+        .lift_suspension(&json.user_name)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(json))
+}
+
+/// The most recent git operations of a user, newest first.
+async fn get_user_operations(
+    Query(query): Query<UserQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<GitOperation>>, (StatusCode, String)> {
+    let filter = OperationFilter {
+        user_name: Some(query.user),
+        limit: Some(query.limit.unwrap_or(50)),
+        ..Default::default()
+    };
+    let operations = state
+        .context
+        .services
+        .audit_storage
+        .get_operations(filter)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(operations.into_iter().map(GitOperation::from).collect()))
+}
+
+async fn get_user_keys(
+    Query(query): Query<UserQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<SshKey>>, (StatusCode, String)> {
+    let keys = state
+        .context
+        .services
+        .user_storage
+        .find_ssh_keys(&query.user)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(keys.into_iter().map(SshKey::from).collect()))
+}
+
+async fn revoke_user_keys(
+    state: State<ApiServiceState>,
+    Json(json): Json<RevokeKeys>,
+) -> Result<Json<RevokedKeys>, (StatusCode, String)> {
+    let revoked = state
+        .context
+        .services
+        .user_storage
+        .remove_ssh_keys(&json.user_name, json.key_id)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if let (0, Some(key_id)) = (revoked, json.key_id) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("{} has no key {}", json.user_name, key_id),
+        ));
+    }
+    Ok(Json(RevokedKeys {
+        user_name: json.user_name,
+        revoked,
+    }))
+}
+
+/// Revokes the access token of a user of the database provider, a new one has to be issued.
+async fn revoke_user_token(
+    state: State<ApiServiceState>,
+    Json(json): Json<UserRequest>,
+) -> Result<Json<UserRequest>, (StatusCode, String)> {
+    let found = state
+        .context
+        .services
+        .user_storage
+        .revoke_token(&json.user_name)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if !found {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("user not found: {}", json.user_name),
+        ));
+    }
+    Ok(Json(json))
+}
+
+/// Expires the sessions of a user, the tokens signed by the OIDC issuer until now are refused
+/// and the user has to sign in again.
+async fn expire_user_sessions(
+    state: State<ApiServiceState>,
+    Json(json): Json<UserRequest>,
+) -> Result<Json<ExpiredSessions>, (StatusCode, String)> {
+    let expired_at = state
+        .context
+        .services
+        .user_storage
+        .expire_sessions(&json.user_name)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(ExpiredSessions {
+        user_name: json.user_name,
+        expired_at: expired_at.and_utc().to_rfc3339(),
+    }))
+}
+
+/// Exports a repository to the git daemon, or stops it.
+async fn set_repo_public(
+    state: State<ApiServiceState>,
+    Json(json): Json<RepoVisibility>,
+) -> Result<Json<RepoVisibility>, (StatusCode, String)> {
+    let found = state
+        .context
+        .services
+        .mega_storage
+        .set_repo_public(&json.repo_path, json.public)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if !found {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("repository not found: {}", json.repo_path),
+        ));
+    }
+    Ok(Json(json))
+}
+
+async fn get_blame_cache(
+    state: State<ApiServiceState>,
+) -> Result<Json<BlameCacheStats>, (StatusCode, String)> {
+    let entries = state
+        .context
+        .services
+        .blame_storage
+        .count_blames()
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(BlameCacheStats {
+        entries,
+        max_entries: state.context.settings.current().blame_cache_max_entries,
+    }))
+}
+
+async fn clear_blame_cache(
+    state: State<ApiServiceState>,
+    Json(json): Json<ClearBlameCache>,
+) -> Result<Json<ClearedBlameCache>, (StatusCode, String)> {
+    let repo_id = match &json.repo_path {
+        Some(repo_path) => Some(find_repo(&state, repo_path).await?.repo_id),
+        None if json.path.is_some() => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                String::from("a path needs the repo_path of its repository"),
+            ))
+        }
+        None => None,
+    };
+    let path = json.path.as_deref().map(|path| path.trim_matches('/'));
+    let removed = state
+        .context
+        .services
+        .blame_storage
+        .remove_blames(repo_id, path)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(ClearedBlameCache { removed }))
+}
+
+/// The last storage report computed, by the daily task or on demand.
+async fn get_storage_report(
+    state: State<ApiServiceState>,
+) -> Result<Json<StorageReport>, (StatusCode, String)> {
+    match storage_report::latest(&state.context).await {
+        Ok(Some(report)) => Ok(Json(report)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            String::from("no storage report computed yet"),
+        )),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+async fn generate_storage_report(
+    state: State<ApiServiceState>,
+    Json(json): Json<StorageReportRequest>,
+) -> Result<Json<StorageReport>, (StatusCode, String)> {
+    let defaults = ReportOptions::default();
+    let options = ReportOptions {
+        limit: json.limit.unwrap_or(defaults.limit).max(1),
+        window_days: json.window_days.unwrap_or(defaults.window_days),
+        depth: json.depth.unwrap_or(defaults.depth),
+    };
+    storage_report::generate_and_save(&state.context, options)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+async fn find_repo(state: &ApiServiceState, repo_path: &str) -> Result<Repo, (StatusCode, String)> {
+    state
+        .context
+        .services
+        .mega_storage
+        .find_git_repo(repo_path)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .map(Repo::from)
+        .ok_or((
+            StatusCode::NOT_FOUND,
+            format!("repository not found: {}", repo_path),
+        ))
+}
+
+async fn get_pins(
+    Query(query): Query<PinQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<ObjectPin>>, (StatusCode, String)> {
+    let repo = find_repo(&state, &query.repo_path).await?;
+    let pins = state
+        .context
+        .services
+        .mega_storage
+        .get_pins(&repo)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(pins.into_iter().map(ObjectPin::from).collect()))
+}
+
+async fn pin_object(
+    state: State<ApiServiceState>,
+    Json(json): Json<PinRequest>,
+) -> Result<Json<ObjectPin>, (StatusCode, String)> {
+    let repo = find_repo(&state, &json.repo_path).await?;
+    let storage = state.context.services.mega_storage.clone();
+    let internal_error = |err: MegaError| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    // only commits and trees can be pinned, blobs are kept by the trees holding them
+    let object_type = if storage
+        .get_commit_by_hash(&json.object_id, &repo)
+        .await
+        .map_err(internal_error)?
+        .is_some()
+    {
+        "commit"
+    } else if storage
+        .get_mega_tree_by_sha(&json.object_id)
+        .await
+        .map_err(internal_error)?
+        .is_some()
+    {
+        "tree"
+    } else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("no commit or tree {}", json.object_id),
+        ));
+    };
+    let pin = storage
+        .pin_object(&repo, &json.object_id, object_type, &json.reason)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(pin.into()))
+}
+
+async fn unpin_object(
+    state: State<ApiServiceState>,
+    Json(json): Json<UnpinRequest>,
+) -> Result<Json<UnpinRequest>, (StatusCode, String)> {
+    let repo = find_repo(&state, &json.repo_path).await?;
+    let removed = state
+        .context
+        .services
+        .mega_storage
+        .unpin_object(&repo, &json.object_id)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if !removed {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("{} is not pinned", json.object_id),
+        ));
+    }
+    Ok(Json(json))
+}
+
+async fn get_replacements(
+    Query(query): Query<PinQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<Replacement>>, (StatusCode, String)> {
+    let repo = find_repo(&state, &query.repo_path).await?;
+    replace::list_replacements(&state.context, &repo)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+async fn replace_object(
+    state: State<ApiServiceState>,
+    Json(json): Json<ReplaceRequest>,
+) -> Result<Json<ReplaceRequest>, (StatusCode, String)> {
+    let repo = find_repo(&state, &json.repo_path).await?;
+    replace::replace_commit(
+        &state.context,
+        &repo,
+        &json.repo_path,
+        &json.object_id,
+        &json.replacement,
+    )
+    .await
+    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    Ok(Json(json))
+}
+
+async fn remove_replacement(
+    state: State<ApiServiceState>,
+    Json(json): Json<RemoveReplacement>,
+) -> Result<Json<RemoveReplacement>, (StatusCode, String)> {
+    let repo = find_repo(&state, &json.repo_path).await?;
+    let removed =
+        replace::remove_replacement(&state.context, &repo, &json.repo_path, &json.object_id)
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if !removed {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("{} is not replaced", json.object_id),
+        ));
+    }
+    Ok(Json(json))
+}
+
+async fn get_mr_archives(
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<MrArchive>>, (StatusCode, String)> {
+    let archives = state
+        .context
+        .services
+        .mega_storage
+        .get_mr_archives()
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(archives.into_iter().map(MrArchive::from).collect()))
+}
+
+/// What the next branch cleanup run would do, nothing is changed.
+async fn get_branch_cleanup(
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<BranchCleanup>>, (StatusCode, String)> {
+    let config = BranchCleanupConfig::load()
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(lifecycle::cleanup_branches(&state.context, &config, true).await))
+}
+
+async fn restore_mr(
+    state: State<ApiServiceState>,
+    Json(json): Json<RestoreMr>,
+) -> Result<Json<MrArchive>, (StatusCode, String)> {
+    let archive = state
+        .context
+        .services
+        .mega_storage
+        .restore_mr(json.mr_id)
+        .await
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    Ok(Json(archive.into()))
+}
+
+/// The author of a review or a comment, anonymous clients can only read them.
+fn reviewer(user: Option<Extension<AuthUser>>) -> Result<String, (StatusCode, String)> {
+    user.map(|Extension(user)| user.name).ok_or((
+        StatusCode::UNAUTHORIZED,
+        String::from("reviewing a merge request needs an authenticated user"),
+    ))
+}
+
+async fn check_mr(state: &ApiServiceState, mr_id: i64) -> Result<(), (StatusCode, String)> {
+    state
+        .context
+        .services
+        .mega_storage
+        .get_mr(mr_id)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .map(|_| ())
+        .ok_or((
+            StatusCode::NOT_FOUND,
+            format!("merge request not found: {}", mr_id),
+        ))
+}
+
+async fn get_reviews(
+    Query(query): Query<ReviewQuery>,
+    user: Option<Extension<AuthUser>>,
+    state: State<ApiServiceState>,
+) -> Result<Json<MrReviews>, (StatusCode, String)> {
+    check_mr(&state, query.mr_id).await?;
+    let storage = &state.context.services.review_storage;
+    let internal_error = |err: MegaError| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    let reviews = storage
+        .get_reviews(query.mr_id)
+        .await
+        .map_err(internal_error)?;
+    let user_name = user.map(|Extension(user)| user.name);
+    let comments = storage
+        .get_comments(query.mr_id, user_name.as_deref())
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(MrReviews {
+        reviews: reviews.into_iter().map(Into::into).collect(),
+        comments: comments.into_iter().map(Into::into).collect(),
+    }))
+}
+
+async fn add_comment(
+    user: Option<Extension<AuthUser>>,
+    state: State<ApiServiceState>,
+    Json(json): Json<DraftComment>,
+) -> Result<Json<MrComment>, (StatusCode, String)> {
+    let user_name = reviewer(user)?;
+    if json.body.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, String::from("empty comment")));
+    }
+    check_mr(&state, json.mr_id).await?;
+    let comment = state
+        .context
+        .services
+        .review_storage
+        .add_draft_comment(json.mr_id, &user_name, json.path, json.line, &json.body)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(comment.into()))
+}
+
+/// Adds a reply by email as a comment of its sender, see [`email_reply`]. The mail provider
+/// posts it anonymously with the `X-Mega-Signature` of its body signed with
+/// `MEGA_EMAIL_REPLY_WEBHOOK_SECRET`, the policy is checked for the sender instead.
+async fn add_email_reply(
+    state: State<ApiServiceState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<MrComment>, (StatusCode, String)> {
+    let var = |name| std::env::var(name).unwrap_or_default();
+    let (secret, webhook_secret) = (
+        var("MEGA_EMAIL_REPLY_SECRET"),
+        var("MEGA_EMAIL_REPLY_WEBHOOK_SECRET"),
+    );
+    if secret.is_empty() || webhook_secret.is_empty() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            String::from("replies by email are disabled"),
+        ));
+    }
+    let signature = headers
+        .get("X-Mega-Signature")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if !email_reply::verify_webhook_signature(&webhook_secret, &body, signature) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            String::from("invalid signature of the reply"),
+        ));
+    }
+    let json: EmailReply = serde_json::from_slice(&body)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let writable = |user: &str, repo_path: &str| {
+        state
+            .policy
+            .is_allowed(Some(user), Action::Write, repo_path)
+    };
+    let comment = email_reply::ingest_reply(&state.context, &secret, &json.into(), writable)
+        .await
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+    state.review_notifier.notify(vec![comment.clone()]);
+    Ok(Json(comment.into()))
+}
+
+async fn remove_comment(
+    user: Option<Extension<AuthUser>>,
+    state: State<ApiServiceState>,
+    Json(json): Json<RemoveComment>,
+) -> Result<Json<RemoveComment>, (StatusCode, String)> {
+    let user_name = reviewer(user)?;
+    let removed = state
+        .context
+        .services
+        .review_storage
+        .remove_draft_comment(json.id, &user_name)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if !removed {
+        // published comments are part of a review and stay
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("no draft comment {}", json.id),
+        ));
+    }
+    Ok(Json(json))
+}
+
+async fn publish_review(
+    user: Option<Extension<AuthUser>>,
+    state: State<ApiServiceState>,
+    Json(json): Json<PublishReview>,
+) -> Result<Json<PublishedReview>, (StatusCode, String)> {
+    let user_name = reviewer(user)?;
+    check_mr(&state, json.mr_id).await?;
+    let (review, comments) = state
+        .context
+        .services
+        .review_storage
+        .publish_review(json.mr_id, &user_name, json.verdict, &json.summary)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or((
+            StatusCode::BAD_REQUEST,
+            String::from("nothing to publish: no draft comment and no summary"),
+        ))?;
+    // the review is published, the notifications failing to load are only logged
+    match state
+        .context
+        .services
+        .review_storage
+        .get_comments(json.mr_id, None)
+        .await
+    {
+        Ok(published) => state.review_notifier.notify(
+            published
+                .into_iter()
+                .filter(|comment| comment.review_id == Some(review.id))
+                .collect(),
+        ),
+        Err(err) => tracing::error!("failed to notify the review {}: {}", review.id, err),
+    }
+    Ok(Json(PublishedReview {
+        review: review.into(),
+        comments,
+    }))
+}
+
+async fn upload_code_index(
+    Query(query): Query<CodeIndexQuery>,
+    state: State<ApiServiceState>,
+    body: Bytes,
+) -> Result<Json<IndexSummary>, (StatusCode, String)> {
+    let format = query
+        .format
+        .parse()
+        .map_err(|err: MegaError| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    code_nav::upload(&state.context, &query.repo_path, &query.refs, format, &body)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))
+}
+
+async fn get_definitions(
+    Query(query): Query<CodeNavQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<Location>>, (StatusCode, String)> {
+    code_nav::definitions(
+        &state.context,
+        &query.repo_path,
+        &query.refs,
+        &query.path,
+        query.line,
+        query.character,
+    )
+    .await
+    .map(Json)
+    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))
+}
+
+async fn get_references(
+    Query(query): Query<CodeNavQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<Location>>, (StatusCode, String)> {
+    code_nav::references(
+        &state.context,
+        &query.repo_path,
+        &query.refs,
+        &query.path,
+        query.line,
+        query.character,
+    )
+    .await
+    .map(Json)
+    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))
+}
+
+async fn get_settings(state: State<ApiServiceState>) -> Json<Settings> {
+    Json(state.context.settings.current())
+}
+
+async fn update_settings(
+    state: State<ApiServiceState>,
+    Json(json): Json<serde_json::Map<String, serde_json::Value>>,
+) -> Result<Json<Settings>, (StatusCode, String)> {
+    let settings = state
+        .context
+        .settings
+        .update(&state.context.services.setting_storage, json)
+        .await
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    Ok(Json(settings))
+}
+
+async fn reset_settings(
+    state: State<ApiServiceState>,
+    Json(json): Json<ResetSettings>,
+) -> Result<Json<Settings>, (StatusCode, String)> {
+    let settings = state
+        .context
+        .settings
+        .reset(&state.context.services.setting_storage, json.names)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(settings))
+}
+
+async fn compare(
+    Query(query): Query<CompareQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Comparison>, (StatusCode, String)> {
+    comparison::compare(
+        &state.context,
+        &query.repo_path,
+        &query.base,
+        &query.head,
+        query.offset.unwrap_or(0),
+        query.limit,
+    )
+    .await
+    .map(Json)
+    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))
+}
+
+/// What a merge request of `head` into `base` would go through, checked as the pushing user.
+async fn get_mr_impact(
+    Query(query): Query<ImpactQuery>,
+    user: Option<Extension<AuthUser>>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Impact>, (StatusCode, String)> {
+    let user_name = user.map(|Extension(user)| user.name);
+    impact::preview(
+        &state.context,
+        &query.repo_path,
+        &query.base,
+        &query.head,
+        user_name.as_deref(),
+    )
+    .await
+    .map(Json)
+    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))
+}
+
+async fn get_tree_commits(
+    Query(query): Query<TreeCommitQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<EntryCommit>>, (StatusCode, String)> {
+    last_commit::tree_entry_commits(&state.context, &query.repo_path, &query.refs, &query.path)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))
+}
+
+async fn get_blame(
+    Query(query): Query<BlameQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Blame>, (StatusCode, String)> {
+    blame::blame(&state.context, &query.repo_path, &query.refs, &query.path)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))
+}
+
+/// A snapshot of a tree as `git archive` makes it, streamed as it is encoded. The files below
+/// the requested path the user cannot read are left out, a binary response is not redacted.
+async fn get_archive(
+    Query(query): Query<ArchiveQuery>,
+    user: Option<Extension<AuthUser>>,
+    state: State<ApiServiceState>,
+) -> Result<Response, (StatusCode, String)> {
+    let format: ArchiveFormat = query
+        .format
+        .parse()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    let source = archive::open(
+        &state.context,
+        &query.repo_path,
+        &query.refs,
+        query.path.as_deref(),
+    )
+    .await
+    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let name = query
+        .repo_path
+        .rsplit('/')
+        .find(|name| !name.is_empty())
+        .unwrap_or("archive");
+    let id = source.commit.unwrap_or(source.tree).to_plain_str();
+    let file_name = format!("{}-{}.{}", name, &id[..7], format.extension());
+    let readable = archive_readable(
+        state.policy.clone(),
+        user.map(|Extension(user)| user.name),
+        &query.repo_path,
+        query.path.as_deref(),
+    );
+    let chunks = archive::archive_stream(
+        state.context.clone(),
+        source,
+        format,
+        query.prefix.unwrap_or_default(),
+        readable,
+    );
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        )
+        .body(Body::from_stream(chunks))
+        .unwrap())
+}
+
+/// Whether `user` may read a path of the archived directory `path` of the repository at
+/// `repo_path`.
+fn archive_readable(
+    policy: Arc<Policy>,
+    user: Option<String>,
+    repo_path: &str,
+    path: Option<&str>,
+) -> impl Fn(&str) -> bool + Send + Sync + 'static {
+    let mut base = repo_path.trim_end_matches('/').to_owned();
+    if let Some(path) = path.map(|path| path.trim_matches('/')).filter(|p| !p.is_empty()) {
+        base = format!("{}/{}", base, path);
+    }
+    move |entry: &str| {
+        policy.is_allowed(user.as_deref(), Action::Read, &format!("{}/{}", base, entry))
+    }
+}
+
+async fn search_items(
+    Query(query): Query<SearchQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<SearchResult>, (StatusCode, String)> {
+    let kind = match query.kind.as_deref() {
+        None => None,
+        Some("mr") => Some(SearchKind::Mr),
+        Some("issue") => Some(SearchKind::Issue),
+        Some(other) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("unknown search type: {}", other),
+            ))
+        }
+    };
+    let query = search::SearchQuery {
+        text: query.q,
+        kind,
+        state: query.state,
+        author: query.author,
+        offset: query.offset.unwrap_or(0),
+        limit: query.limit,
+    };
+    search::search(&state.context, &query)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// The files of a branch matching the query, from the code search index.
+async fn search_code(
+    Query(query): Query<CodeSearchQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<CodeSearchResult>, (StatusCode, String)> {
+    code_search::search_code(
+        &state.context,
+        query.repo_path.as_deref(),
+        &query.branch,
+        &query.q,
+        query.offset.unwrap_or(0),
+        query.limit,
+    )
+    .await
+    .map(Json)
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+async fn get_user_activity(
+    Query(query): Query<ActivityQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<ActivityItem>>, (StatusCode, String)> {
+    activity::user_activities(&state.context, &query.user, query.before, query.limit)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// The contributions per day of a team, at most a year of them.
+async fn get_contributions(
+    Query(query): Query<ContributionQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<Contribution>>, (StatusCode, String)> {
+    let users: Vec<String> = query
+        .users
+        .split(',')
+        .map(str::trim)
+        .filter(|user| !user.is_empty())
+        .map(str::to_owned)
+        .collect();
+    let until = parse_day(query.until)?.unwrap_or_else(|| Utc::now().date_naive());
+    let year_before = until - Days::new(365);
+    let since = parse_day(query.since)?.unwrap_or(year_before).max(year_before);
+    activity::contribution_graph(&state.context, users, since, until)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// The directories below a path with the most lines changed.
+/// The user of a watch, anonymous clients have none.
+fn watcher(user: Option<Extension<AuthUser>>) -> Result<String, (StatusCode, String)> {
+    user.map(|Extension(user)| user.name).ok_or((
+        StatusCode::UNAUTHORIZED,
+        String::from("watching needs an authenticated user"),
+    ))
+}
+
+async fn get_watches(
+    user: Option<Extension<AuthUser>>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<Watch>>, (StatusCode, String)> {
+    let user_name = watcher(user)?;
+    watch::user_watches(&state.context, &user_name)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+async fn add_watch(
+    user: Option<Extension<AuthUser>>,
+    state: State<ApiServiceState>,
+    Json(json): Json<WatchRequest>,
+) -> Result<Json<Watch>, (StatusCode, String)> {
+    let user_name = watcher(user)?;
+    watch::add_watch(
+        &state.context,
+        &user_name,
+        &json.repo_path,
+        json.branch.as_deref(),
+        json.path.as_deref(),
+    )
+    .await
+    .map(Json)
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+async fn remove_watch(
+    user: Option<Extension<AuthUser>>,
+    state: State<ApiServiceState>,
+    Json(json): Json<RemoveWatch>,
+) -> Result<Json<RemoveWatch>, (StatusCode, String)> {
+    let user_name = watcher(user)?;
+    let removed = watch::remove_watch(&state.context, &user_name, json.id)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if !removed {
+        return Err((StatusCode::NOT_FOUND, format!("no watch {}", json.id)));
+    }
+    Ok(Json(json))
+}
+
+async fn get_notifications(
+    Query(query): Query<NotificationQuery>,
+    user: Option<Extension<AuthUser>>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<WatchNotification>>, (StatusCode, String)> {
+    let user_name = watcher(user)?;
+    watch::user_notifications(
+        &state.context,
+        &user_name,
+        query.before,
+        query.unread,
+        query.limit,
+    )
+    .await
+    .map(Json)
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+async fn read_notifications(
+    user: Option<Extension<AuthUser>>,
+    state: State<ApiServiceState>,
+    Json(json): Json<ReadNotifications>,
+) -> Result<Json<ReadCount>, (StatusCode, String)> {
+    let user_name = watcher(user)?;
+    watch::mark_read(&state.context, &user_name, json.ids)
+        .await
+        .map(|read| Json(ReadCount { read }))
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+async fn get_hotspots(
+    Query(query): Query<AnalyticsQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<DirStats>>, (StatusCode, String)> {
+    let (since, until) = analytics_range(&query)?;
+    analytics::hotspots(&state.context, &query.path, since, until, query.limit)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// The lines changed per week in a directory.
+async fn get_code_frequency(
+    Query(query): Query<AnalyticsQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<WeekStats>>, (StatusCode, String)> {
+    let (since, until) = analytics_range(&query)?;
+    analytics::code_frequency(&state.context, &query.path, since, until)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// The directories most often changed together with those below a path.
+async fn get_coupling(
+    Query(query): Query<AnalyticsQuery>,
+    state: State<ApiServiceState>,
+) -> Result<Json<Vec<Coupling>>, (StatusCode, String)> {
+    let (since, until) = analytics_range(&query)?;
+    analytics::coupling(&state.context, &query.path, since, until, query.limit)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+fn analytics_range(query: &AnalyticsQuery) -> Result<(NaiveDate, NaiveDate), (StatusCode, String)> {
+    let until = parse_day(query.until.clone())?.unwrap_or_else(|| Utc::now().date_naive());
+    let since = parse_day(query.since.clone())?.unwrap_or(until - Days::new(365));
+    Ok((since, until))
+}
+
+fn parse_day(day: Option<String>) -> Result<Option<NaiveDate>, (StatusCode, String)> {
+    day.map(|d| {
+        NaiveDate::parse_from_str(&d, "%Y-%m-%d")
+            .map_err(|err| (StatusCode::BAD_REQUEST, format!("invalid day {}: {}", d, err)))
+    })
+    .transpose()
+}
+
+fn parse_time(time: Option<String>) -> Result<Option<NaiveDateTime>, (StatusCode, String)> {
+    time.map(|t| {
+        DateTime::parse_from_rfc3339(&t)
+            .map(|t| t.naive_utc())
+            .map_err(|err| (StatusCode::BAD_REQUEST, format!("invalid time {}: {}", t, err)))
+    })
+    .transpose()
+}
@@ -0,0 +1,163 @@
+//!
+//! Housekeeping of the git operation audit records, and the detection of clients
+//! cloning unusual volumes of the monorepo.
+//!
+use std::collections::HashMap;
+use std::env;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use serde::Serialize;
+
+use callisto::db_enums::OperationType;
+use ceres::protocol::audit::{self, CloneAnomaly, CloneRule};
+use jupiter::context::Context;
+use jupiter::settings::Settings;
+use jupiter::storage::audit_storage::OperationFilter;
+
+/// Periodically removes the audit records older than `MEGA_AUDIT_RETENTION_DAYS`,
+/// nothing is removed when the variable is unset or 0.
+pub fn spawn_retention_task(context: Context) {
+    let retention_days = env_or("MEGA_AUDIT_RETENTION_DAYS", 0);
+    if retention_days <= 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60 * 24));
+        loop {
+            interval.tick().await;
+            audit::purge_expired_operations(&context, retention_days).await;
+        }
+    });
+}
+
+#[derive(Debug, Clone)]
+struct DetectionConfig {
+    rule: CloneRule,
+    window: Duration,
+    webhook: Option<String>,
+    suspend: bool,
+}
+
+impl DetectionConfig {
+    fn new(settings: &Settings, webhook: Option<String>) -> Self {
+        DetectionConfig {
+            rule: CloneRule {
+                max_clones: settings.audit_clone_limit,
+                max_bytes: settings.audit_clone_mb_limit * 1024 * 1024,
+            },
+            window: Duration::from_secs(settings.audit_clone_window_mins * 60),
+            webhook,
+            suspend: settings.audit_suspend_on_anomaly,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AnomalyAlert<'a> {
+    event: &'static str,
+    window_minutes: u64,
+    suspended: bool,
+    #[serde(flatten)]
+    anomaly: &'a CloneAnomaly,
+}
+
+/// Checks the clone volume of every client over the last `audit_clone_window_mins` once a
+/// minute, alerts `MEGA_AUDIT_ALERT_WEBHOOK` and optionally suspends the token the clones were
+/// made with. The limits are runtime settings, the job is idle while both `audit_clone_limit`
+/// and `audit_clone_mb_limit` are 0.
+pub fn spawn_anomaly_detection(context: Context) {
+    let webhook = env::var("MEGA_AUDIT_ALERT_WEBHOOK")
+        .ok()
+        .filter(|url| !url.is_empty());
+    let mut settings = context.settings.subscribe();
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        // an identity and token are reported once per window
+        let mut reported: HashMap<(String, Option<String>), Instant> = HashMap::new();
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let config = DetectionConfig::new(&settings.borrow_and_update(), webhook.clone());
+            if config.rule.max_clones == 0 && config.rule.max_bytes == 0 {
+                continue;
+            }
+            reported.retain(|_, at| at.elapsed() < config.window);
+
+            let filter = OperationFilter {
+                operation: Some(OperationType::Clone),
+                since: Some(
+                    Utc::now().naive_utc()
+                        - chrono::Duration::from_std(config.window).unwrap_or_default(),
+                ),
+                ..Default::default()
+            };
+            let operations = match context.services.audit_storage.get_operations(filter).await {
+                Ok(operations) => operations,
+                Err(err) => {
+                    tracing::error!("failed to load git operations: {}", err);
+                    continue;
+                }
+            };
+            for anomaly in audit::detect_clone_anomalies(&operations, &config.rule) {
+                let key = (anomaly.identity.clone(), anomaly.token_id.clone());
+                if reported.contains_key(&key) {
+                    continue;
+                }
+                reported.insert(key, Instant::now());
+                handle_anomaly(&context, &client, &config, &anomaly).await;
+            }
+        }
+    });
+}
+
+async fn handle_anomaly(
+    context: &Context,
+    client: &reqwest::Client,
+    config: &DetectionConfig,
+    anomaly: &CloneAnomaly,
+) {
+    tracing::warn!(
+        "unusual clone volume from {}: {} clones, {} bytes",
+        anomaly.identity,
+        anomaly.clones,
+        anomaly.bytes
+    );
+    let mut suspended = false;
+    // only verified credentials are suspended, an unverified user name could be anyone's
+    if let (true, Some(token_id)) = (config.suspend, &anomaly.token_id) {
+        let reason = format!(
+            "{} clones ({} bytes) within {} minutes",
+            anomaly.clones,
+            anomaly.bytes,
+            config.window.as_secs() / 60
+        );
+        match context
+            .services
+            .audit_storage
+            .suspend_token(&anomaly.identity, token_id, &reason)
+            .await
+        {
+            Ok(_) => suspended = true,
+            Err(err) => tracing::error!("failed to suspend {}: {}", anomaly.identity, err),
+        }
+    }
+    if let Some(webhook) = &config.webhook {
+        let alert = AnomalyAlert {
+            event: "mass_clone",
+            window_minutes: config.window.as_secs() / 60,
+            suspended,
+            anomaly,
+        };
+        if let Err(err) = client.post(webhook).json(&alert).send().await {
+            tracing::error!("failed to deliver clone alert to {}: {}", webhook, err);
+        }
+    }
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse::<T>().ok())
+        .unwrap_or(default)
+}
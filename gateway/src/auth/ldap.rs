@@ -0,0 +1,140 @@
+use std::env;
+
+use async_trait::async_trait;
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapError, Scope, SearchEntry};
+
+use common::errors::MegaError;
+
+use crate::auth::{AuthProvider, AuthUser};
+
+/// Users of an LDAP directory. A token is verified by binding as the user with it, users and
+/// their SSH keys are searched with the service account of `MEGA_LDAP_BIND_DN`.
+pub struct LdapProvider {
+    url: String,
+    /// DN of a user, `{user}` is replaced by the escaped user name.
+    user_dn: String,
+    base_dn: String,
+    /// Search filter of a user, `{user}` is replaced by the escaped user name.
+    user_filter: String,
+    bind_dn: Option<String>,
+    bind_password: String,
+    mail_attr: String,
+    key_attr: String,
+}
+
+impl LdapProvider {
+    pub fn from_env() -> Self {
+        let var = |key: &str, default: &str| {
+            env::var(key)
+                .ok()
+                .filter(|value| !value.is_empty())
+                .unwrap_or(default.to_owned())
+        };
+        let base_dn = env::var("MEGA_LDAP_BASE_DN").expect("MEGA_LDAP_BASE_DN not configured");
+        LdapProvider {
+            url: var("MEGA_LDAP_URL", "ldap://localhost:389"),
+            user_dn: var("MEGA_LDAP_USER_DN", &format!("uid={{user}},{}", base_dn)),
+            user_filter: var("MEGA_LDAP_USER_FILTER", "(uid={user})"),
+            bind_dn: env::var("MEGA_LDAP_BIND_DN")
+                .ok()
+                .filter(|dn| !dn.is_empty()),
+            bind_password: var("MEGA_LDAP_BIND_PASSWORD", ""),
+            mail_attr: var("MEGA_LDAP_MAIL_ATTR", "mail"),
+            key_attr: var("MEGA_LDAP_KEY_ATTR", "sshPublicKey"),
+            base_dn,
+        }
+    }
+
+    async fn connect(&self) -> Result<Ldap, MegaError> {
+        let (conn, ldap) = LdapConnAsync::new(&self.url).await.map_err(ldap_error)?;
+        ldap3::drive!(conn);
+        Ok(ldap)
+    }
+
+    /// A connection bound with the service account, anonymous when there is none.
+    async fn service_connection(&self) -> Result<Ldap, MegaError> {
+        let mut ldap = self.connect().await?;
+        if let Some(bind_dn) = &self.bind_dn {
+            ldap.simple_bind(bind_dn, &self.bind_password)
+                .await
+                .and_then(|res| res.success())
+                .map_err(ldap_error)?;
+        }
+        Ok(ldap)
+    }
+
+    async fn search_user(
+        &self,
+        ldap: &mut Ldap,
+        name: &str,
+    ) -> Result<Option<SearchEntry>, MegaError> {
+        let filter = self.user_filter.replace("{user}", &ldap_escape(name));
+        let attrs = vec![self.mail_attr.as_str(), self.key_attr.as_str()];
+        let (entries, _) = ldap
+            .search(&self.base_dn, Scope::Subtree, &filter, attrs)
+            .await
+            .and_then(|res| res.success())
+            .map_err(ldap_error)?;
+        Ok(entries.into_iter().next().map(SearchEntry::construct))
+    }
+
+    fn to_user(&self, name: &str, entry: &SearchEntry) -> AuthUser {
+        AuthUser {
+            name: name.to_owned(),
+            email: entry
+                .attrs
+                .get(&self.mail_attr)
+                .and_then(|values| values.first())
+                .cloned(),
+        }
+    }
+}
+
+fn ldap_error(err: LdapError) -> MegaError {
+    MegaError::new(anyhow::Error::from(err), 1)
+}
+
+#[async_trait]
+impl AuthProvider for LdapProvider {
+    async fn verify_token(
+        &self,
+        user: Option<&str>,
+        token: &str,
+    ) -> Result<Option<AuthUser>, MegaError> {
+        // a simple bind with an empty password is an anonymous bind and always succeeds
+        let Some(user) = user.filter(|_| !token.is_empty()) else {
+            return Ok(None);
+        };
+        let mut ldap = self.connect().await?;
+        let dn = self.user_dn.replace("{user}", &dn_escape(user));
+        let bound = ldap.simple_bind(&dn, token).await.map_err(ldap_error)?;
+        if bound.success().is_err() {
+            return Ok(None);
+        }
+        let entry = self.search_user(&mut ldap, user).await?;
+        let _ = ldap.unbind().await;
+        Ok(Some(match entry {
+            Some(entry) => self.to_user(user, &entry),
+            None => AuthUser {
+                name: user.to_owned(),
+                email: None,
+            },
+        }))
+    }
+
+    async fn lookup_user(&self, name: &str) -> Result<Option<AuthUser>, MegaError> {
+        let mut ldap = self.service_connection().await?;
+        let entry = self.search_user(&mut ldap, name).await?;
+        let _ = ldap.unbind().await;
+        Ok(entry.map(|entry| self.to_user(name, &entry)))
+    }
+
+    async fn list_keys(&self, name: &str) -> Result<Vec<String>, MegaError> {
+        let mut ldap = self.service_connection().await?;
+        let entry = self.search_user(&mut ldap, name).await?;
+        let _ = ldap.unbind().await;
+        Ok(entry
+            .and_then(|mut entry| entry.attrs.remove(&self.key_attr))
+            .unwrap_or_default())
+    }
+}
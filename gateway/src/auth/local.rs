@@ -0,0 +1,65 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use russh_keys::key::PublicKey;
+
+use callisto::mega_user;
+use common::errors::MegaError;
+use jupiter::storage::user_storage::UserStorage;
+
+use crate::auth::{self, AuthProvider, AuthUser};
+
+/// Users of the `mega_user` table, authenticated by the SHA-256 of their access token.
+pub struct LocalProvider {
+    storage: Arc<UserStorage>,
+}
+
+impl LocalProvider {
+    pub fn new(storage: Arc<UserStorage>) -> Self {
+        LocalProvider { storage }
+    }
+}
+
+impl From<mega_user::Model> for AuthUser {
+    fn from(value: mega_user::Model) -> Self {
+        AuthUser {
+            name: value.name,
+            email: value.email,
+        }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for LocalProvider {
+    async fn verify_token(
+        &self,
+        user: Option<&str>,
+        token: &str,
+    ) -> Result<Option<AuthUser>, MegaError> {
+        if token.is_empty() {
+            return Ok(None);
+        }
+        let found = self
+            .storage
+            .find_user_by_token(&sha256::digest(token))
+            .await?;
+        Ok(found
+            .filter(|model| user.is_none_or(|user| user == model.name))
+            .map(AuthUser::from))
+    }
+
+    async fn lookup_user(&self, name: &str) -> Result<Option<AuthUser>, MegaError> {
+        Ok(self.storage.find_user(name).await?.map(AuthUser::from))
+    }
+
+    async fn list_keys(&self, name: &str) -> Result<Vec<String>, MegaError> {
+        self.storage.get_ssh_keys(name).await
+    }
+
+    async fn find_key_owner(&self, public_key: &PublicKey) -> Result<Option<String>, MegaError> {
+        Ok(auth::key_owner(
+            self.storage.list_ssh_keys().await?,
+            public_key,
+        ))
+    }
+}
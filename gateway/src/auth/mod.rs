@@ -0,0 +1,193 @@
+//!
+//! Authentication of git clients against a pluggable identity system. The provider is
+//! selected with `MEGA_AUTH_PROVIDER`: `local` for the users and keys of the database, `ldap`
+//! or `oidc` for an enterprise directory. Without one every client is accepted.
+//!
+use std::env;
+use std::slice;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::http::{header, HeaderMap};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use russh_keys::key::PublicKey;
+
+use callisto::mega_ssh_key;
+use common::errors::MegaError;
+use jupiter::context::Context;
+
+pub mod ldap;
+pub mod local;
+pub mod oidc;
+pub mod policy;
+pub mod redact;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthUser {
+    pub name: String,
+    pub email: Option<String>,
+}
+
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    /// Checks a secret presented by a client: a password or access token sent with the user
+    /// name of basic auth, or a bearer token without one. Returns None when it is refused.
+    async fn verify_token(
+        &self,
+        user: Option<&str>,
+        token: &str,
+    ) -> Result<Option<AuthUser>, MegaError>;
+
+    async fn lookup_user(&self, name: &str) -> Result<Option<AuthUser>, MegaError>;
+
+    /// The SSH public keys of a user, in the OpenSSH `authorized_keys` format.
+    async fn list_keys(&self, name: &str) -> Result<Vec<String>, MegaError>;
+
+    /// The user holding `public_key`, for the clients connecting with the shared SSH account.
+    /// None when no user holds it, or when the provider cannot search the keys of all users.
+    async fn find_key_owner(&self, _public_key: &PublicKey) -> Result<Option<String>, MegaError> {
+        Ok(None)
+    }
+}
+
+/// The provider configured by `MEGA_AUTH_PROVIDER`, None when authentication is disabled.
+pub fn from_env(context: &Context) -> Option<Arc<dyn AuthProvider>> {
+    let provider = env::var("MEGA_AUTH_PROVIDER").unwrap_or_default();
+    match provider.as_str() {
+        "" | "none" => None,
+        "local" => Some(Arc::new(local::LocalProvider::new(
+            context.services.user_storage.clone(),
+        ))),
+        "ldap" => Some(Arc::new(ldap::LdapProvider::from_env())),
+        "oidc" => Some(Arc::new(oidc::OidcProvider::from_env(
+            context.services.user_storage.clone(),
+        ))),
+        other => panic!(
+            "Not supported config, MEGA_AUTH_PROVIDER should be 'local', 'ldap' or 'oidc', got '{}'",
+            other
+        ),
+    }
+}
+
+/// The user name, if any, and the secret of an `Authorization` header.
+pub fn credentials(headers: &HeaderMap) -> Option<(Option<String>, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        return Some((None, token.trim().to_owned()));
+    }
+    let decoded = STANDARD.decode(value.strip_prefix("Basic ")?.trim()).ok()?;
+    let (user, secret) = String::from_utf8(decoded)
+        .ok()?
+        .split_once(':')
+        .map(|(u, s)| (u.to_owned(), s.to_owned()))?;
+    Some((Some(user), secret))
+}
+
+/// Whether `public_key` is one of `keys`, lines that don't hold a valid key are skipped.
+pub fn key_matches(keys: &[String], public_key: &PublicKey) -> bool {
+    let fingerprint = public_key.fingerprint();
+    keys.iter()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .filter_map(|base64| russh_keys::parse_public_key_base64(base64).ok())
+        .any(|key| key.fingerprint() == fingerprint)
+}
+
+/// The user of the first of `keys` matching `public_key`.
+pub fn key_owner(keys: Vec<mega_ssh_key::Model>, public_key: &PublicKey) -> Option<String> {
+    keys.into_iter()
+        .find(|key| key_matches(slice::from_ref(&key.public_key), public_key))
+        .map(|key| key.user_name)
+}
+
+/// The id audit records and suspensions name a password or access token by, a digest that
+/// doesn't reveal it.
+pub fn token_id(secret: &str) -> String {
+    format!("token:{}", &sha256::digest(secret)[..16])
+}
+
+/// The id audit records and suspensions name an SSH key by, its fingerprint.
+pub fn key_id(public_key: &PublicKey) -> String {
+    format!("key:{}", public_key.fingerprint())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, HeaderMap, HeaderValue};
+    use chrono::Utc;
+    use russh_keys::key::KeyPair;
+    use russh_keys::PublicKeyBase64;
+
+    use callisto::mega_ssh_key;
+
+    use super::{credentials, key_matches, key_owner, token_id};
+
+    #[test]
+    fn test_credentials() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(credentials(&headers), None);
+        // alice:s3cr:et
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic YWxpY2U6czNjcjpldA=="),
+        );
+        assert_eq!(
+            credentials(&headers),
+            Some((Some(String::from("alice")), String::from("s3cr:et")))
+        );
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer eyJhbGciOi"),
+        );
+        assert_eq!(
+            credentials(&headers),
+            Some((None, String::from("eyJhbGciOi")))
+        );
+    }
+
+    #[test]
+    fn test_key_matches() {
+        let key = KeyPair::generate_ed25519()
+            .unwrap()
+            .clone_public_key()
+            .unwrap();
+        let other = KeyPair::generate_ed25519()
+            .unwrap()
+            .clone_public_key()
+            .unwrap();
+        let keys = vec![
+            String::from("not a key"),
+            format!("ssh-ed25519 {} alice@laptop", key.public_key_base64()),
+        ];
+        assert!(key_matches(&keys, &key));
+        assert!(!key_matches(&keys, &other));
+    }
+
+    #[test]
+    fn test_key_owner() {
+        let key = KeyPair::generate_ed25519()
+            .unwrap()
+            .clone_public_key()
+            .unwrap();
+        let other = KeyPair::generate_ed25519()
+            .unwrap()
+            .clone_public_key()
+            .unwrap();
+        let model = |id, user: &str, key: &russh_keys::key::PublicKey| mega_ssh_key::Model {
+            id,
+            user_name: user.to_owned(),
+            public_key: format!("ssh-ed25519 {}", key.public_key_base64()),
+            created_at: Utc::now().naive_utc(),
+        };
+        let keys = vec![model(1, "alice", &other), model(2, "bob", &key)];
+        assert_eq!(key_owner(keys.clone(), &key), Some(String::from("bob")));
+        assert_eq!(key_owner(keys[..1].to_vec(), &key), None);
+    }
+
+    #[test]
+    fn test_token_id() {
+        assert_eq!(token_id("s3cret"), token_id("s3cret"));
+        assert_ne!(token_id("s3cret"), token_id("s3cret2"));
+        assert!(!token_id("s3cret").contains("s3cret"));
+    }
+}
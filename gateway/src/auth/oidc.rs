@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, DecodingKey, Validation};
+use russh_keys::key::PublicKey;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::RwLock;
+
+use common::errors::MegaError;
+use jupiter::storage::user_storage::UserStorage;
+
+use crate::auth::{self, AuthProvider, AuthUser};
+
+/// Signing keys of the issuer are fetched again after this long, or on an unknown key id.
+const JWKS_TTL: Duration = Duration::from_secs(3600);
+
+/// Users of an OpenID Connect issuer, authenticated by the ID or access tokens it signs. The
+/// issuer has no SSH keys, users register theirs in the `mega_ssh_key` table. The tokens are the
+/// sessions of the users, an admin expires them in `mega_session_revocation`.
+pub struct OidcProvider {
+    issuer: String,
+    audience: Option<String>,
+    /// Claim holding the user name.
+    user_claim: String,
+    client: reqwest::Client,
+    jwks: RwLock<Option<(Instant, Arc<JwkSet>)>>,
+    /// Users seen in a verified token, the issuer offers no lookup without one.
+    users: RwLock<HashMap<String, AuthUser>>,
+    storage: Arc<UserStorage>,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+impl OidcProvider {
+    pub fn from_env(storage: Arc<UserStorage>) -> Self {
+        let issuer = env::var("MEGA_OIDC_ISSUER").expect("MEGA_OIDC_ISSUER not configured");
+        OidcProvider {
+            issuer: issuer.trim_end_matches('/').to_owned(),
+            audience: env::var("MEGA_OIDC_AUDIENCE")
+                .ok()
+                .filter(|a| !a.is_empty()),
+            user_claim: env::var("MEGA_OIDC_USER_CLAIM")
+                .unwrap_or(String::from("preferred_username")),
+            client: reqwest::Client::new(),
+            jwks: RwLock::new(None),
+            users: RwLock::new(HashMap::new()),
+            storage,
+        }
+    }
+
+    async fn jwks(&self, refresh: bool) -> Result<Arc<JwkSet>, MegaError> {
+        if !refresh {
+            if let Some((fetched, jwks)) = self.jwks.read().await.as_ref() {
+                if fetched.elapsed() < JWKS_TTL {
+                    return Ok(jwks.clone());
+                }
+            }
+        }
+        let discovery_url = format!("{}/.well-known/openid-configuration", self.issuer);
+        let discovery: Discovery = self.fetch(&discovery_url).await?;
+        let jwks = Arc::new(self.fetch::<JwkSet>(&discovery.jwks_uri).await?);
+        *self.jwks.write().await = Some((Instant::now(), jwks.clone()));
+        Ok(jwks)
+    }
+
+    async fn fetch<T: for<'de> Deserialize<'de>>(&self, url: &str) -> Result<T, MegaError> {
+        let res = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| MegaError::with_message(&format!("failed to fetch {}: {}", url, e)))?;
+        res.json()
+            .await
+            .map_err(|e| MegaError::with_message(&format!("invalid response of {}: {}", url, e)))
+    }
+}
+
+#[async_trait]
+impl AuthProvider for OidcProvider {
+    /// The user name of basic auth is ignored, git clients often send a placeholder with the
+    /// token as password.
+    async fn verify_token(
+        &self,
+        _user: Option<&str>,
+        token: &str,
+    ) -> Result<Option<AuthUser>, MegaError> {
+        let Ok(header) = decode_header(token) else {
+            return Ok(None);
+        };
+        let Some(kid) = header.kid else {
+            return Ok(None);
+        };
+        let mut jwks = self.jwks(false).await?;
+        if jwks.find(&kid).is_none() {
+            jwks = self.jwks(true).await?;
+        }
+        let Some(key) = jwks
+            .find(&kid)
+            .and_then(|jwk| DecodingKey::from_jwk(jwk).ok())
+        else {
+            return Ok(None);
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let Ok(data) = decode::<HashMap<String, Value>>(token, &key, &validation) else {
+            return Ok(None);
+        };
+        let claim = |name: &str| data.claims.get(name).and_then(Value::as_str);
+        let Some(name) = claim(&self.user_claim) else {
+            return Ok(None);
+        };
+        // a token issued before the sessions of its user were expired is refused
+        if let Some(revoked_at) = self.storage.sessions_revoked_at(name).await? {
+            let issued_at = data.claims.get("iat").and_then(Value::as_i64);
+            if issued_at.is_none_or(|iat| iat <= revoked_at.and_utc().timestamp()) {
+                return Ok(None);
+            }
+        }
+        let user = AuthUser {
+            name: name.to_owned(),
+            email: claim("email").map(str::to_owned),
+        };
+        self.users
+            .write()
+            .await
+            .insert(user.name.clone(), user.clone());
+        Ok(Some(user))
+    }
+
+    async fn lookup_user(&self, name: &str) -> Result<Option<AuthUser>, MegaError> {
+        Ok(self.users.read().await.get(name).cloned())
+    }
+
+    async fn list_keys(&self, name: &str) -> Result<Vec<String>, MegaError> {
+        self.storage.get_ssh_keys(name).await
+    }
+
+    async fn find_key_owner(&self, public_key: &PublicKey) -> Result<Option<String>, MegaError> {
+        Ok(auth::key_owner(
+            self.storage.list_ssh_keys().await?,
+            public_key,
+        ))
+    }
+}
@@ -0,0 +1,336 @@
+//!
+//! Authorization policy of the API and git endpoints, a list of rules with one rule per line:
+//!
+//! ```text
+//! # effect  subject         action  resource
+//! allow     *               read    /projects/**
+//! allow     @authenticated  write   /projects/**
+//! allow     alice           admin   /**
+//! deny      bob             *       /projects/secret/**
+//! ```
+//!
+//! The subject is a user name, `@authenticated` for any verified user or `*` for every client.
+//! The action is `read`, `write`, `admin` or `*`. In the resource `*` matches one path segment
+//! and `**` any number of them. A request is allowed when an `allow` rule and no `deny` rule
+//! matches it.
+//!
+use std::env;
+use std::fmt::{self, Display};
+use std::fs;
+use std::str::FromStr;
+
+use common::errors::MegaError;
+use jupiter::context::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Action::Read => "read",
+            Action::Write => "write",
+            Action::Admin => "admin",
+        };
+        write!(f, "{}", action)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Subject {
+    Any,
+    Authenticated,
+    User(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    allow: bool,
+    subject: Subject,
+    /// None matches every action.
+    action: Option<Action>,
+    resource: String,
+}
+
+impl Rule {
+    fn matches(&self, user: Option<&str>, action: Action, resource: &str) -> bool {
+        self.applies(user, action) && path_matches(&segments(&self.resource), &segments(resource))
+    }
+
+    /// Whether the rule is about `user` doing `action`, whatever the resource.
+    fn applies(&self, user: Option<&str>, action: Action) -> bool {
+        let subject = match &self.subject {
+            Subject::Any => true,
+            Subject::Authenticated => user.is_some(),
+            Subject::User(name) => user == Some(name.as_str()),
+        };
+        subject && self.action.is_none_or(|a| a == action)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [effect, subject, action, resource] = fields[..] else {
+            return Err(String::from(
+                "expected `<effect> <subject> <action> <resource>`",
+            ));
+        };
+        let allow = match effect {
+            "allow" => true,
+            "deny" => false,
+            _ => return Err(format!("unknown effect `{}`", effect)),
+        };
+        let subject = match subject {
+            "*" => Subject::Any,
+            "@authenticated" => Subject::Authenticated,
+            name => Subject::User(name.to_owned()),
+        };
+        let action = match action {
+            "*" => None,
+            "read" => Some(Action::Read),
+            "write" => Some(Action::Write),
+            "admin" => Some(Action::Admin),
+            _ => return Err(format!("unknown action `{}`", action)),
+        };
+        if !resource.starts_with('/') {
+            return Err(format!("resource `{}` is not an absolute path", resource));
+        }
+        Ok(Rule {
+            allow,
+            subject,
+            action,
+            resource: resource.to_owned(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Parses the rules of `text`, blank lines and lines starting with `#` are skipped.
+    pub fn parse(text: &str) -> Result<Self, MegaError> {
+        let mut rules = Vec::new();
+        for (no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = line.parse().map_err(|err| {
+                MegaError::with_message(&format!("invalid policy rule at line {}: {}", no + 1, err))
+            })?;
+            rules.push(rule);
+        }
+        Ok(Policy { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn is_allowed(&self, user: Option<&str>, action: Action, resource: &str) -> bool {
+        let mut allowed = false;
+        for rule in self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(user, action, resource))
+        {
+            if !rule.allow {
+                return false;
+            }
+            allowed = true;
+        }
+        allowed
+    }
+
+    /// Whether `user` may do `action` on every path: a rule allows it on `/**` and no rule
+    /// denies it anywhere. The requests that carry no path, such as reading an object by its
+    /// id, are only allowed to such users.
+    pub fn is_allowed_everywhere(&self, user: Option<&str>, action: Action) -> bool {
+        let mut allowed = false;
+        for rule in self.rules.iter().filter(|rule| rule.applies(user, action)) {
+            if !rule.allow {
+                return false;
+            }
+            let resource = segments(&rule.resource);
+            allowed |= !resource.is_empty() && resource.iter().all(|segment| *segment == "**");
+        }
+        allowed
+    }
+}
+
+/// The rules of the `MEGA_AUTHZ_POLICY_FILE` file followed by those of the database, see
+/// [`default_policy`] without any rule.
+pub async fn load(context: &Context, authentication: bool) -> Result<Policy, MegaError> {
+    let mut text = match env::var("MEGA_AUTHZ_POLICY_FILE") {
+        Ok(path) if !path.is_empty() => fs::read_to_string(&path).map_err(|err| {
+            MegaError::with_message(&format!("failed to read policy file {}: {}", path, err))
+        })?,
+        _ => String::new(),
+    };
+    for rule in context.services.user_storage.get_policy_rules().await? {
+        text.push('\n');
+        text.push_str(&rule);
+    }
+    let policy = Policy::parse(&text)?;
+    if !policy.is_empty() {
+        return Ok(policy);
+    }
+    Ok(default_policy(authentication))
+}
+
+/// The policy without any rule: every client may read and write, or every verified user once
+/// authentication is enabled. Administration needs a rule granting it.
+fn default_policy(authentication: bool) -> Policy {
+    let subject = if authentication {
+        "@authenticated"
+    } else {
+        "*"
+    };
+    Policy::parse(&format!(
+        "allow {subject} read /**\nallow {subject} write /**"
+    ))
+    .expect("the default policy is valid")
+}
+
+/// The segments of `path` once `.` and `..` are resolved, a `..` at the root stays there, so
+/// that `/projects/../secret` is matched as `/secret`.
+pub(crate) fn segments(path: &str) -> Vec<&str> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    segments
+}
+
+/// Whether `path` matches the segments of `pattern`. The segments after a `**` are matched
+/// from each position of the path, the last `**` only is retried so that a pattern with
+/// many of them takes at most the product of the two lengths instead of backtracking
+/// through every `**`.
+pub(crate) fn path_matches(pattern: &[&str], path: &[&str]) -> bool {
+    let mut pattern = pattern.to_vec();
+    pattern.dedup_by(|a, b| *a == "**" && *b == "**");
+    let (mut p, mut s) = (0, 0);
+    // the position after the last `**` and the one of the path it was tried at
+    let mut retry = None;
+    while s < path.len() {
+        match pattern.get(p) {
+            Some(&"**") => {
+                p += 1;
+                retry = Some((p, s));
+            }
+            Some(segment) if *segment == "*" || *segment == path[s] => {
+                p += 1;
+                s += 1;
+            }
+            _ => match retry {
+                Some((star, from)) => {
+                    p = star;
+                    s = from + 1;
+                    retry = Some((star, s));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|segment| *segment == "**")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_policy, path_matches, segments, Action, Policy};
+
+    #[test]
+    fn test_policy() {
+        let policy = Policy::parse(
+            "# public projects
+            allow * read /projects/**
+            allow @authenticated write /projects/*/src/**
+            allow alice * /**
+            deny bob * /projects/secret/**",
+        )
+        .unwrap();
+        assert!(policy.is_allowed(None, Action::Read, "/projects/mega"));
+        assert!(!policy.is_allowed(None, Action::Read, "/third_parts/rust"));
+        assert!(!policy.is_allowed(None, Action::Write, "/projects/mega/src/lib.rs"));
+        assert!(policy.is_allowed(Some("bob"), Action::Write, "/projects/mega/src/lib.rs"));
+        assert!(!policy.is_allowed(Some("bob"), Action::Write, "/projects/mega/docs"));
+        assert!(!policy.is_allowed(Some("bob"), Action::Read, "/projects/secret"));
+        assert!(policy.is_allowed(Some("alice"), Action::Admin, "/"));
+        assert!(!policy.is_allowed(Some("bob"), Action::Admin, "/"));
+
+        // the dot segments are resolved before matching
+        assert!(!policy.is_allowed(Some("bob"), Action::Read, "/projects/mega/../secret"));
+        assert!(!policy.is_allowed(Some("bob"), Action::Read, "/projects/./secret/x"));
+        assert!(!policy.is_allowed(None, Action::Read, "/projects/../../third_parts"));
+        assert!(policy.is_allowed(None, Action::Read, "/third_parts/../projects/mega"));
+
+        assert!(policy.is_allowed_everywhere(Some("alice"), Action::Read));
+        assert!(!policy.is_allowed_everywhere(Some("bob"), Action::Read));
+        assert!(!policy.is_allowed_everywhere(None, Action::Read));
+        let policy = Policy::parse("allow * read /\nallow * read /public/**").unwrap();
+        assert!(!policy.is_allowed_everywhere(None, Action::Read));
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = default_policy(true);
+        assert!(policy.is_allowed(Some("bob"), Action::Read, "/projects/mega"));
+        assert!(policy.is_allowed(Some("bob"), Action::Write, "/projects/mega"));
+        assert!(!policy.is_allowed(None, Action::Read, "/projects/mega"));
+        assert!(!policy.is_allowed(Some("bob"), Action::Admin, "/admin/users"));
+        assert!(policy.is_allowed_everywhere(Some("bob"), Action::Read));
+
+        let policy = default_policy(false);
+        assert!(policy.is_allowed(None, Action::Write, "/projects/mega"));
+        assert!(!policy.is_allowed(None, Action::Admin, "/admin/users"));
+    }
+
+    #[test]
+    fn test_path_matches() {
+        fn matches(pattern: &str, path: &str) -> bool {
+            path_matches(&segments(pattern), &segments(path))
+        }
+        assert!(matches("/**", "/"));
+        assert!(matches("/**", "/projects/mega"));
+        assert!(matches("/projects/**", "/projects"));
+        assert!(matches("/projects/*/src/**", "/projects/mega/src/lib.rs"));
+        assert!(!matches("/projects/*/src/**", "/projects/src"));
+        assert!(matches("/**/src/**/*", "/a/b/src/c/d/lib.rs"));
+        assert!(matches("/**/src/*", "/src/src/lib.rs"));
+        assert!(!matches("/**/src/*", "/a/src/b/c"));
+        assert!(!matches("/projects/*", "/projects"));
+        assert!(!matches("/projects", "/projects/mega"));
+
+        // many `**` do not backtrack through each other on a long path
+        let pattern = format!("{}/x", "/**/a".repeat(20));
+        let path = "/a".repeat(200);
+        assert!(!matches(&pattern, &path));
+        assert!(matches(&pattern, &format!("{}/x", path)));
+        assert!(matches("/**/**/**/mega", "/projects/mega"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Policy::parse("allow * read").is_err());
+        assert!(Policy::parse("permit * read /**").is_err());
+        assert!(Policy::parse("allow * push /**").is_err());
+        assert!(Policy::parse("allow * read projects").is_err());
+        assert!(Policy::parse("\n# nothing\n").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+//!
+//! Redaction of the API responses for paths the user cannot read. The policy is checked for
+//! the requested path before the handler runs, this filter covers what a response lists below
+//! it: every object of a JSON response with a `path` the policy denies to the user keeps its
+//! shape, but its strings are replaced and its numbers and ids dropped. Relative paths are
+//! resolved against the `repo_path` of the request. Once a response redacted a path, the
+//! commit messages it contains are redacted as well, as they may describe the hidden changes.
+//!
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::body::{to_bytes, Body};
+use axum::extract::{Query, Request, State};
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde_json::{Map, Value};
+
+use crate::auth::policy::{Action, Policy};
+use crate::auth::AuthUser;
+
+pub const REDACTED: &str = "[redacted]";
+
+/// Fields holding commit messages.
+const MESSAGE_FIELDS: [&str; 2] = ["message", "commit_msg"];
+
+pub async fn filter_response(
+    State(policy): State<Arc<Policy>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let user = req.extensions().get::<AuthUser>().map(|u| u.name.clone());
+    let base = Query::<HashMap<String, String>>::try_from_uri(req.uri())
+        .ok()
+        .and_then(|Query(mut query)| query.remove("repo_path"))
+        .unwrap_or(String::from("/"));
+
+    let res = next.run(req).await;
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if !is_json || !res.status().is_success() {
+        return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    let bytes = match to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            tracing::error!("failed to read the response to redact: {}", err);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let mut value: Value = match serde_json::from_slice(&bytes) {
+        Ok(value) => value,
+        Err(_) => return Response::from_parts(parts, Body::from(bytes)),
+    };
+    let readable = |path: &str| policy.is_allowed(user.as_deref(), Action::Read, path);
+    if !redact(&mut value, &base, &readable) {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(value.to_string()))
+}
+
+/// Redacts the objects of `value` with a path `readable` refuses, and then every commit
+/// message. Returns whether anything was redacted.
+pub fn redact(value: &mut Value, base: &str, readable: &impl Fn(&str) -> bool) -> bool {
+    if !redact_paths(value, base, readable) {
+        return false;
+    }
+    redact_messages(value);
+    true
+}
+
+fn redact_paths(value: &mut Value, base: &str, readable: &impl Fn(&str) -> bool) -> bool {
+    match value {
+        Value::Array(items) => items.iter_mut().fold(false, |redacted, item| {
+            redact_paths(item, base, readable) | redacted
+        }),
+        Value::Object(object) => {
+            let denied = match object.get("path") {
+                Some(Value::String(path)) => !readable(&full_path(base, path)),
+                _ => false,
+            };
+            if denied {
+                redact_object(object);
+                return true;
+            }
+            object.values_mut().fold(false, |redacted, item| {
+                redact_paths(item, base, readable) | redacted
+            })
+        }
+        _ => false,
+    }
+}
+
+fn redact_object(object: &mut Map<String, Value>) {
+    for value in object.values_mut() {
+        match value {
+            Value::String(s) => *s = String::from(REDACTED),
+            Value::Bool(_) | Value::Null => {}
+            _ => *value = Value::Null,
+        }
+    }
+}
+
+fn redact_messages(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(redact_messages),
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match value {
+                    Value::String(s) if MESSAGE_FIELDS.contains(&key.as_str()) => {
+                        *s = String::from(REDACTED)
+                    }
+                    _ => redact_messages(value),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn full_path(base: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        return path.to_owned();
+    }
+    format!("{}/{}", base.trim_end_matches('/'), path)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{full_path, redact};
+
+    #[test]
+    fn test_redact() {
+        let readable = |path: &str| !path.starts_with("/projects/mega/secret");
+        let mut comparison = json!({
+            "ahead_by": 1,
+            "commits": [{"id": "a1", "message": "rotate the signing key"}],
+            "files": [
+                {"path": "src/lib.rs", "status": "modified"},
+                {"path": "secret/key.pem", "status": "added"},
+            ],
+        });
+        assert!(redact(&mut comparison, "/projects/mega", &readable));
+        assert_eq!(
+            comparison,
+            json!({
+                "ahead_by": 1,
+                "commits": [{"id": "a1", "message": "[redacted]"}],
+                "files": [
+                    {"path": "src/lib.rs", "status": "modified"},
+                    {"path": "[redacted]", "status": "[redacted]"},
+                ],
+            })
+        );
+
+        let mut entries = json!([{"path": "/projects/mega/src", "is_dir": true, "date": 1}]);
+        let unchanged = entries.clone();
+        assert!(!redact(&mut entries, "/", &readable));
+        assert_eq!(entries, unchanged);
+    }
+
+    #[test]
+    fn test_full_path() {
+        assert_eq!(
+            full_path("/projects/mega/", "src/lib.rs"),
+            "/projects/mega/src/lib.rs"
+        );
+        assert_eq!(full_path("/", "docs"), "/docs");
+        assert_eq!(full_path("/projects", "/docs"), "/docs");
+    }
+}
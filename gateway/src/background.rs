@@ -0,0 +1,21 @@
+//!
+//! The periodic jobs of a mega process: the audit record retention, the mass-clone detection
+//! and the lifecycle policies, the repacking of the packs included. They are started once per
+//! process, however many of the servers it runs, so that no alert or suspension is issued
+//! twice.
+//!
+use common::model::CommonOptions;
+use jupiter::context::Context;
+
+use crate::{audit, lifecycle, settings};
+
+pub async fn start(common: &CommonOptions) {
+    let context = Context::new(&common.data_source).await;
+    settings::spawn_reload_task(context.clone());
+    audit::spawn_retention_task(context.clone());
+    audit::spawn_anomaly_detection(context.clone());
+    lifecycle::spawn_mr_archive_task(context.clone());
+    lifecycle::spawn_branch_cleanup_task(context.clone());
+    lifecycle::spawn_storage_report_task(context.clone());
+    lifecycle::spawn_repack_task(context);
+}
@@ -0,0 +1,232 @@
+//!
+//! The webhooks of the chats, see [`ceres::chatops`]. Slack posts its slash commands to
+//! `/chatops/slack`, signed with the signing secret of the app in
+//! `MEGA_CHATOPS_SLACK_SIGNING_SECRET`. A Matrix bot relays the commands of its rooms to
+//! `/chatops/matrix` with the token of `MEGA_CHATOPS_MATRIX_TOKEN`. These routes are left out
+//! of the authentication of the clients, the user of a command is the one its chat account is
+//! mapped to and the policy applies to it.
+//!
+//! The ref updates are announced through the incoming webhooks `MEGA_CHATOPS_SLACK_WEBHOOK`
+//! and `MEGA_CHATOPS_MATRIX_WEBHOOK`.
+//!
+use std::env;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use bytes::Bytes;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+
+use ceres::chatops::{self, ChatCommand, ChatPlatform, ChatUsers};
+use common::errors::MegaError;
+use jupiter::context::Context;
+use jupiter::events::{RefUpdateEvent, RefUpdateObserver};
+
+use crate::auth::policy::{Action, Policy};
+use crate::https_server::AppState;
+
+#[derive(Clone)]
+pub struct ChatOpsState {
+    context: Context,
+    policy: Arc<Policy>,
+    users: Arc<ChatUsers>,
+    slack_secret: Option<String>,
+    matrix_token: Option<String>,
+}
+
+/// The routes of the chats configured, None if there is none.
+pub fn routers(state: &AppState) -> Option<Router<AppState>> {
+    let var = |name| env::var(name).ok().filter(|value| !value.is_empty());
+    let slack_secret = var("MEGA_CHATOPS_SLACK_SIGNING_SECRET");
+    let matrix_token = var("MEGA_CHATOPS_MATRIX_TOKEN");
+    if slack_secret.is_none() && matrix_token.is_none() {
+        return None;
+    }
+    let chat_state = ChatOpsState {
+        context: state.context.clone(),
+        policy: state.policy.clone(),
+        users: Arc::new(ChatUsers::from_env()),
+        slack_secret,
+        matrix_token,
+    };
+    Some(
+        Router::new()
+            .route("/slack", post(slack_command))
+            .route("/matrix", post(matrix_command))
+            .with_state(chat_state),
+    )
+}
+
+/// The fields of a Slash command used, it is posted as a form.
+#[derive(Deserialize)]
+struct SlackCommand {
+    user_id: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct MatrixCommand {
+    /// The Matrix id of the sender, e.g. `@bob:example.org`.
+    sender: String,
+    body: String,
+}
+
+async fn slack_command(
+    State(state): State<ChatOpsState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some(secret) = &state.slack_secret else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    };
+    if !chatops::verify_slack_signature(
+        secret,
+        header("X-Slack-Request-Timestamp"),
+        &body,
+        header("X-Slack-Signature"),
+        Utc::now().timestamp(),
+    ) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let Ok(command) = serde_urlencoded::from_bytes::<SlackCommand>(&body) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    // an error is only shown to its sender, Slack reports the other statuses as a failure
+    let (response_type, text) =
+        match run(&state, ChatPlatform::Slack, &command.user_id, &command.text).await {
+            Ok(reply) => ("in_channel", reply),
+            Err(err) => ("ephemeral", err),
+        };
+    Json(json!({ "response_type": response_type, "text": text })).into_response()
+}
+
+async fn matrix_command(
+    State(state): State<ChatOpsState>,
+    headers: HeaderMap,
+    Json(command): Json<MatrixCommand>,
+) -> Response {
+    let Some(token) = &state.matrix_token else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let authorization = headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if !chatops::verify_bearer_token(token, authorization) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let reply = run(&state, ChatPlatform::Matrix, &command.sender, &command.body)
+        .await
+        .unwrap_or_else(|err| err);
+    Json(json!({ "body": reply })).into_response()
+}
+
+/// Runs the command `text` of `account` as its user, returns the reply or the error to show.
+async fn run(
+    state: &ChatOpsState,
+    platform: ChatPlatform,
+    account: &str,
+    text: &str,
+) -> Result<String, String> {
+    let command: ChatCommand = text.parse()?;
+    let user = state.users.user(platform, account).ok_or_else(|| {
+        format!(
+            "The {} account {} is not linked to a mega user",
+            platform.as_str(),
+            account
+        )
+    })?;
+    if state
+        .context
+        .services
+        .audit_storage
+        .is_user_suspended(user)
+        .await
+        .unwrap_or(false)
+    {
+        return Err(format!("The user {} is suspended", user));
+    }
+    let action = if command.is_write() {
+        Action::Write
+    } else {
+        Action::Read
+    };
+    if command != ChatCommand::Help && !state.policy.is_allowed(Some(user), action, "/") {
+        return Err(format!("Permission denied: {} /", action));
+    }
+    chatops::execute(&state.context, user, &command)
+        .await
+        .map_err(|err| {
+            tracing::error!("chat command {:?} of {} failed: {}", command, user, err);
+            err.to_string()
+        })
+}
+
+/// Announces the ref updates in the chats.
+pub struct ChatNotifier {
+    client: reqwest::Client,
+    slack_webhook: Option<String>,
+    matrix_webhook: Option<String>,
+}
+
+impl ChatNotifier {
+    /// None when no webhook is configured.
+    pub fn from_env() -> Option<Self> {
+        let var = |name| env::var(name).ok().filter(|url: &String| !url.is_empty());
+        let slack_webhook = var("MEGA_CHATOPS_SLACK_WEBHOOK");
+        let matrix_webhook = var("MEGA_CHATOPS_MATRIX_WEBHOOK");
+        (slack_webhook.is_some() || matrix_webhook.is_some()).then(|| ChatNotifier {
+            client: reqwest::Client::new(),
+            slack_webhook,
+            matrix_webhook,
+        })
+    }
+}
+
+#[async_trait]
+impl RefUpdateObserver for ChatNotifier {
+    fn name(&self) -> &'static str {
+        "chat_notifier"
+    }
+
+    /// The announcements are best effort, a chat failing to take one does not hold up the
+    /// next ones.
+    async fn on_ref_update(
+        &self,
+        _context: &Context,
+        event: &RefUpdateEvent,
+    ) -> Result<(), MegaError> {
+        let message = chatops::ref_update_message(event);
+        let slack = json!({
+            "text": message.text,
+            "blocks": [{
+                "type": "section",
+                "text": { "type": "mrkdwn", "text": message.markdown },
+            }],
+        });
+        let matrix = json!({ "text": message.text, "html": message.html });
+        for (webhook, payload) in [(&self.slack_webhook, slack), (&self.matrix_webhook, matrix)] {
+            let Some(webhook) = webhook else {
+                continue;
+            };
+            let res = self.client.post(webhook).json(&payload).send().await;
+            if let Err(err) = res.and_then(|res| res.error_for_status()) {
+                tracing::error!("failed to deliver a ref update to {}: {}", webhook, err);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,52 @@
+//!
+//! The `git://` server, see [`crate::git_protocol::daemon`]. It only reads, so it runs none of
+//! the background tasks of the other servers but the reload of the runtime settings.
+//!
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use clap::Args;
+use tokio::net::TcpListener;
+
+use common::model::CommonOptions;
+use jupiter::context::Context;
+
+use crate::git_protocol::daemon;
+use crate::settings;
+
+#[derive(Args, Clone, Debug)]
+pub struct DaemonOptions {
+    #[clap(flatten)]
+    pub common: CommonOptions,
+
+    #[clap(flatten)]
+    pub custom: DaemonCustom,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct DaemonCustom {
+    #[arg(long, default_value_t = 9418)]
+    daemon_port: u16,
+}
+
+/// start a git daemon
+pub async fn start_server(command: &DaemonOptions) {
+    let DaemonOptions {
+        common: CommonOptions { host, data_source },
+        custom: DaemonCustom { daemon_port },
+    } = command;
+    let context = Context::new(data_source).await;
+    settings::spawn_reload_task(context.clone());
+    let server_url = format!("{}:{}", host, daemon_port);
+    let addr = SocketAddr::from_str(&server_url).unwrap();
+    let listener = TcpListener::bind(addr).await.unwrap();
+    tracing::info!("git daemon listening on {}", addr);
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                tokio::spawn(daemon::serve(context.clone(), stream, addr));
+            }
+            Err(err) => tracing::error!("git daemon failed to accept a connection: {}", err),
+        }
+    }
+}
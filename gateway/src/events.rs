@@ -0,0 +1,57 @@
+//!
+//! Delivery of the pushed ref updates to their observers. The servers register the same
+//! observers, each server delivers the events as soon as it recorded them and picks up those of
+//! the other servers and the failed deliveries on a timer, an observer being delivered by one
+//! server at a time. The replicas configured are streamed the ref updates, see
+//! [`crate::replica`]. The code search index of the
+//! branches pushed while it was off, or indexed by an older version, is backfilled on start.
+//!
+use std::sync::Arc;
+use std::time::Duration;
+
+use ceres::activity::ActivityRecorder;
+use ceres::analytics::CodeAnalytics;
+use ceres::code_search::{self, CodeSearchIndexer};
+use ceres::last_commit::LastCommitIndexer;
+use jupiter::context::Context;
+
+use crate::chatops::ChatNotifier;
+use crate::release::ReleaseTagger;
+use crate::replica::ReplicaStreamer;
+use crate::watch::WatchNotifier;
+
+const RETRY_INTERVAL_SECS: u64 = 30;
+
+pub fn spawn_delivery_task(context: Context) {
+    context.events.register(Arc::new(LastCommitIndexer));
+    context.events.register(Arc::new(ActivityRecorder));
+    context.events.register(Arc::new(CodeAnalytics));
+    context.events.register(Arc::new(CodeSearchIndexer));
+    context.events.register(Arc::new(WatchNotifier::from_env()));
+    context.events.register(Arc::new(ReleaseTagger::from_env()));
+    if let Some(notifier) = ChatNotifier::from_env() {
+        context.events.register(Arc::new(notifier));
+    }
+    if let Some(streamer) = ReplicaStreamer::from_env() {
+        context.events.register(Arc::new(streamer));
+    }
+    if context.settings.current().code_search_index {
+        let context = context.clone();
+        tokio::spawn(async move {
+            match code_search::backfill(&context).await {
+                Ok(report) => tracing::info!("code search index backfilled: {:?}", report),
+                Err(err) => tracing::error!("failed to backfill the code search index: {}", err),
+            }
+        });
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(RETRY_INTERVAL_SECS));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = context.events.published() => {}
+            }
+            context.events.deliver(&context).await;
+        }
+    });
+}
@@ -0,0 +1,307 @@
+//!
+//! The `git://` transport of `git daemon`: anonymous, read-only fetches and clones of the
+//! repositories flagged public in the database. The request of the client names the service
+//! and the repository, e.g. `git-upload-pack /project/repo.git\0host=mega\0\0version=2\0`,
+//! upload-pack is then served by the same [`PackProtocol`] as the other transports. Any other
+//! service, and any repository that is not public, is refused with an `ERR` line.
+//!
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use ceres::protocol::pack;
+use ceres::protocol::v2::{self, ProtocolVersion};
+use ceres::protocol::{ClientInfo, PackProtocol, Protocol, ServiceType};
+use common::errors::MegaError;
+use common::i18n::Locale;
+use jupiter::context::Context;
+
+use super::ssh::{negotiation_rounds, repo_path};
+
+/// A client sending nothing for this long is disconnected.
+const IDLE_TIMEOUT_SECS: u64 = 60;
+
+/// The request line is short, a longer one is not from a git client.
+const MAX_REQUEST_LEN: usize = 4096;
+
+/// The first pkt-line sent by the client.
+#[derive(Debug, PartialEq)]
+struct DaemonRequest {
+    service: String,
+    path: String,
+    host: Option<String>,
+    version: ProtocolVersion,
+}
+
+impl DaemonRequest {
+    fn parse(line: &[u8]) -> Result<Self, String> {
+        let line = std::str::from_utf8(line).map_err(|_| String::from("invalid request"))?;
+        let mut fields = line.split('\0');
+        let (service, path) = fields
+            .next()
+            .and_then(|command| command.split_once(' '))
+            .ok_or_else(|| format!("invalid request: {}", line.trim_end()))?;
+        let mut request = DaemonRequest {
+            service: service.to_owned(),
+            path: repo_path(path),
+            host: None,
+            version: ProtocolVersion::V0,
+        };
+        // the extra parameters follow the host after a second NUL
+        for param in fields {
+            if let Some(host) = param.strip_prefix("host=") {
+                request.host = Some(host.to_owned());
+            } else if param.starts_with("version=") {
+                request.version = ProtocolVersion::from_git_protocol(param);
+            }
+        }
+        Ok(request)
+    }
+}
+
+/// Serves the connection of a client up to its end. The errors are logged, the client is sent
+/// those it can do something about.
+pub async fn serve(context: Context, mut stream: TcpStream, addr: SocketAddr) {
+    let mut buf = BytesMut::new();
+    let line = match read_request_line(&mut stream, &mut buf).await {
+        Ok(Some(line)) => line,
+        Ok(None) => return,
+        Err(err) => {
+            tracing::warn!("git daemon request of {}: {}", addr, err);
+            return;
+        }
+    };
+    let request = match DaemonRequest::parse(&line) {
+        Ok(request) => request,
+        Err(err) => {
+            send_error(&mut stream, &err).await;
+            return;
+        }
+    };
+    tracing::info!(
+        "git daemon: {} {} from {}",
+        request.service,
+        request.path,
+        addr
+    );
+    if request.service != "git-upload-pack" {
+        send_error(
+            &mut stream,
+            &format!("service not enabled: {}", request.service),
+        )
+        .await;
+        return;
+    }
+    match is_exported(&context, &request.path).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let message = format!("access denied or repository not exported: {}", request.path);
+            send_error(&mut stream, &message).await;
+            return;
+        }
+        Err(err) => {
+            tracing::error!("failed to read the repository {}: {}", request.path, err);
+            send_error(&mut stream, "internal error").await;
+            return;
+        }
+    }
+
+    let mut pack_protocol = PackProtocol::new(PathBuf::from(&request.path), context, Protocol::Git);
+    pack_protocol.service_type = ServiceType::UploadPack;
+    pack_protocol.version = request.version;
+    pack_protocol.client = ClientInfo {
+        user: None,
+        ip: Some(addr.ip().to_string()),
+        user_agent: None,
+        token_id: None,
+        locale: Locale::from_env(),
+    };
+    let res = match request.version {
+        ProtocolVersion::V2 => upload_pack_v2(&mut pack_protocol, &mut stream, buf).await,
+        ProtocolVersion::V0 => upload_pack(&mut pack_protocol, &mut stream, buf).await,
+    };
+    if let Err(err) = res {
+        tracing::warn!("git daemon upload-pack of {}: {}", addr, err);
+    }
+}
+
+/// Whether the repository at `path` may be fetched anonymously. Only the imported
+/// repositories carry the flag, the directories of the monorepo are never exported.
+async fn is_exported(context: &Context, path: &str) -> Result<bool, MegaError> {
+    Ok(context
+        .services
+        .mega_storage
+        .find_git_repo(path)
+        .await?
+        .is_some_and(|repo| repo.is_public))
+}
+
+/// Serves the v0 negotiation: the refs are advertised, every round of haves is answered with
+/// a `NAK` until the client is done, and the pack is sent in side-band.
+async fn upload_pack(
+    pack_protocol: &mut PackProtocol,
+    stream: &mut TcpStream,
+    mut buf: BytesMut,
+) -> Result<(), String> {
+    let refs = pack_protocol.git_info_refs().await;
+    write(stream, &refs).await?;
+    let mut answered = 0;
+    loop {
+        // a lone flush-pkt ends a client only listing the refs
+        if buf.starts_with(pack::PKT_LINE_END_MARKER) {
+            return Ok(());
+        }
+        let rounds = negotiation_rounds(&buf);
+        if rounds.done {
+            break;
+        }
+        let flushed = rounds.flushes.saturating_sub(1);
+        for _ in answered..flushed {
+            let mut nak = BytesMut::new();
+            pack::add_pkt_line_string(&mut nak, String::from("NAK\n"));
+            write(stream, &nak).await?;
+        }
+        answered = answered.max(flushed);
+        if read_more(stream, &mut buf).await? == 0 {
+            return Ok(());
+        }
+    }
+
+    let (pack_data, acks) = pack_protocol
+        .git_upload_pack(&mut buf.freeze())
+        .await
+        .map_err(|err| err.to_string())?;
+    write(stream, &acks).await?;
+    let bucket = pack_protocol.upload_throttle();
+    for chunk in super::side_band_chunks(pack_protocol, &pack_data) {
+        if let Some(bucket) = &bucket {
+            bucket.take(chunk.len()).await;
+        }
+        write(stream, &chunk).await?;
+    }
+    Ok(())
+}
+
+/// Serves the v2 commands: the capabilities are advertised and every request is answered in
+/// turn, until the client closes the connection.
+async fn upload_pack_v2(
+    pack_protocol: &mut PackProtocol,
+    stream: &mut TcpStream,
+    mut buf: BytesMut,
+) -> Result<(), String> {
+    write(stream, &pack_protocol.git_info_refs_v2()).await?;
+    loop {
+        while let Some(mut request) = v2::take_request(&mut buf) {
+            let mut response = pack_protocol
+                .git_upload_pack_v2(&mut request)
+                .await
+                .map_err(|err| err.to_string())?;
+            while let Some(chunk) = response.next().await {
+                write(stream, &chunk.map_err(|err| err.to_string())?).await?;
+            }
+        }
+        if read_more(stream, &mut buf).await? == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// The first pkt-line of the connection, None when the client closed it before sending one.
+async fn read_request_line(
+    stream: &mut TcpStream,
+    buf: &mut BytesMut,
+) -> Result<Option<Bytes>, String> {
+    loop {
+        if let Some(length) = pkt_line_length(buf)? {
+            if length > MAX_REQUEST_LEN {
+                return Err(format!("request too long: {} bytes", length));
+            }
+            if buf.len() >= length {
+                let line = buf.split_to(length).freeze();
+                return Ok(Some(line.slice(4..)));
+            }
+        }
+        if read_more(stream, buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// The length of the pkt-line `buf` starts with, None while fewer than 4 bytes are read.
+fn pkt_line_length(buf: &[u8]) -> Result<Option<usize>, String> {
+    let Some(length) = buf.get(..4) else {
+        return Ok(None);
+    };
+    match std::str::from_utf8(length)
+        .ok()
+        .and_then(|length| usize::from_str_radix(length, 16).ok())
+    {
+        Some(length) if length >= 4 => Ok(Some(length)),
+        _ => Err(String::from("invalid pkt-line")),
+    }
+}
+
+async fn read_more(stream: &mut TcpStream, buf: &mut BytesMut) -> Result<usize, String> {
+    match tokio::time::timeout(Duration::from_secs(IDLE_TIMEOUT_SECS), stream.read_buf(buf)).await {
+        Ok(res) => res.map_err(|err| err.to_string()),
+        Err(_) => Err(String::from("idle timeout")),
+    }
+}
+
+async fn write(stream: &mut TcpStream, data: &[u8]) -> Result<(), String> {
+    stream.write_all(data).await.map_err(|err| err.to_string())
+}
+
+/// Sends `message` as the `ERR` line git prints to its user.
+async fn send_error(stream: &mut TcpStream, message: &str) {
+    let mut buf = BytesMut::new();
+    pack::add_pkt_line_string(&mut buf, format!("ERR {}\n", message));
+    if let Err(err) = write(stream, &buf).await {
+        tracing::warn!("failed to send an error to a git daemon client: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ceres::protocol::v2::ProtocolVersion;
+
+    use super::{pkt_line_length, DaemonRequest};
+
+    #[test]
+    fn test_parse_request() {
+        let request =
+            DaemonRequest::parse(b"git-upload-pack /project/repo.git\0host=mega:9418\0").unwrap();
+        assert_eq!(
+            request,
+            DaemonRequest {
+                service: String::from("git-upload-pack"),
+                path: String::from("/project/repo"),
+                host: Some(String::from("mega:9418")),
+                version: ProtocolVersion::V0,
+            }
+        );
+        let request =
+            DaemonRequest::parse(b"git-upload-pack /repo\0host=mega\0\0version=2\0").unwrap();
+        assert_eq!(request.version, ProtocolVersion::V2);
+        assert_eq!(
+            DaemonRequest::parse(b"git-receive-pack ~alice/repo\0")
+                .unwrap()
+                .service,
+            "git-receive-pack"
+        );
+        assert!(DaemonRequest::parse(b"git-upload-pack\0host=mega\0").is_err());
+    }
+
+    #[test]
+    fn test_pkt_line_length() {
+        assert_eq!(pkt_line_length(b"003"), Ok(None));
+        assert_eq!(pkt_line_length(b"0032git-upload-pack"), Ok(Some(0x32)));
+        assert!(pkt_line_length(b"0000").is_err());
+        assert!(pkt_line_length(b"GET / HTTP/1.1").is_err());
+    }
+}
@@ -1,2 +1,26 @@
 // pub mod http;
-pub mod ssh;
\ No newline at end of file
+pub mod daemon;
+pub mod ssh;
+
+use bytes::BytesMut;
+
+use ceres::protocol::pack;
+use ceres::protocol::PackProtocol;
+
+/// Bytes of the pack in each side-band pkt-line, under the limit of a pkt-line.
+const SIDE_BAND_CHUNK_SIZE: usize = 65500;
+
+/// The pack of an upload-pack response in side-band pkt-lines, ended by a flush-pkt, for the
+/// transports writing the response themselves.
+pub(crate) fn side_band_chunks(pack_protocol: &PackProtocol, pack: &[u8]) -> Vec<Vec<u8>> {
+    let mut chunks: Vec<Vec<u8>> = pack
+        .chunks(SIDE_BAND_CHUNK_SIZE)
+        .map(|chunk| {
+            pack_protocol
+                .build_side_band_format(BytesMut::from(chunk), chunk.len())
+                .to_vec()
+        })
+        .collect();
+    chunks.push(pack::PKT_LINE_END_MARKER.to_vec());
+    chunks
+}
@@ -1,9 +1,16 @@
 //!
+//! Git over SSH: `git-upload-pack` and `git-receive-pack` are served by the same
+//! [`PackProtocol`] as the smart HTTP transport, the path of the command naming the
+//! repository, so that `git@mega:project/repo.git` and `ssh://git@mega/project/repo.git` are
+//! both valid remotes.
 //!
-//!
-//!
+//! Clients connecting with the shared account, `git` unless set with `MEGA_SSH_USER`, are
+//! identified by the owner of their key. A client asking for protocol v2 in `GIT_PROTOCOL` is
+//! served the v2 commands of upload-pack.
 //!
 use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
@@ -11,19 +18,33 @@ use std::sync::{Arc, Mutex};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use chrono::{DateTime, Duration, Utc};
-use russh::server::{self, Auth, Msg, Response, Session};
+use futures::{stream, StreamExt};
+use russh::server::{self, Auth, Handle, Msg, Response, Session};
 use russh::{Channel, ChannelId};
 use russh_keys::key;
-use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tokio_util::io::StreamReader;
 
 use ceres::lfs::lfs_structs::Link;
 use ceres::protocol::pack::{self};
+use ceres::protocol::v2::{self, ProtocolVersion};
 use ceres::protocol::ServiceType;
-use ceres::protocol::{PackProtocol, Protocol};
+use ceres::protocol::{ClientInfo, PackProtocol, Protocol};
+use common::i18n::Locale;
 use jupiter::context::Context;
 
+use crate::auth::policy::{Action, Policy};
+use crate::auth::{self, AuthProvider};
+
 type ClientMap = HashMap<(usize, ChannelId), Channel<Msg>>;
 
+/// The account of `git@mega:path` remotes when `MEGA_SSH_USER` is not set.
+pub const DEFAULT_SHARED_USER: &str = "git";
+
+/// The chunks of a pushed pack waiting for the decoder, the channel is not read further
+/// while they are all taken.
+const RECEIVE_PIPE_CHUNKS: usize = 16;
+
 #[derive(Clone)]
 pub struct SshServer {
     pub client_pubkey: Arc<russh_keys::key::PublicKey>,
@@ -33,12 +54,28 @@ pub struct SshServer {
     // TODO: consider is it a good choice to bind data here, find a better solution to bind data with ssh client
     pub pack_protocol: Option<PackProtocol>,
     pub data_combined: Vec<u8>,
+    /// The pack of a push on its way to the decoder, see [`spawn_receive_pack`].
+    pub receive_pipe: Option<mpsc::Sender<Bytes>>,
+    pub client_addr: Option<SocketAddr>,
+    pub user: Option<String>,
+    /// The id of the key or password `user` was verified with, see [`auth::key_id`].
+    pub token_id: Option<String>,
+    /// None when authentication is disabled and every client is accepted.
+    pub auth: Option<Arc<dyn AuthProvider>>,
+    pub policy: Arc<Policy>,
+    /// The account every client may connect with, the user is then the owner of the key.
+    pub shared_user: String,
+    /// The protocol asked for in the `GIT_PROTOCOL` environment variable.
+    pub version: ProtocolVersion,
+    /// The negotiation rounds of a v0 upload-pack already answered.
+    pub rounds_answered: usize,
 }
 
 impl server::Server for SshServer {
     type Handler = Self;
-    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Self {
-        let s = self.clone();
+    fn new_client(&mut self, addr: Option<SocketAddr>) -> Self {
+        let mut s = self.clone();
+        s.client_addr = addr;
         self.id += 1;
         s
     }
@@ -85,14 +122,52 @@ impl server::Handler for SshServer {
         // Pull: git-upload-pack '/path/to/repo.git'
         // LFS HTTP Authenticate: git-lfs-authenticate '/path/to/repo.git' download/upload
         let command: Vec<_> = data.split(' ').collect();
-        let path = command[1];
-        let path = path.replace(".git", "").replace('\'', "");
+        let Some(path) = command.get(1).map(|arg| repo_path(arg)) else {
+            let message = format!("Missing repository path: {}\n", data);
+            session.extended_data(channel, 1, message.into_bytes().into());
+            session.exit_status_request(channel, 1);
+            session.close(channel);
+            return Ok((self, session));
+        };
+        let action = match command[0] {
+            "git-receive-pack" => Action::Write,
+            _ => Action::Read,
+        };
+        // without an auth provider the user name is not verified
+        let subject = self.auth.as_ref().and(self.user.as_deref());
+        if !self.policy.is_allowed(subject, action, &path) {
+            let message = format!("Permission denied: {} {}\n", action, path);
+            session.extended_data(channel, 1, message.into_bytes().into());
+            session.exit_status_request(channel, 1);
+            session.close(channel);
+            return Ok((self, session));
+        }
         let mut pack_protocol =
             PackProtocol::new(PathBuf::from(&path), self.context.clone(), Protocol::Ssh);
+        pack_protocol.client = ClientInfo {
+            user: self.user.clone(),
+            ip: self.client_addr.map(|addr| addr.ip().to_string()),
+            user_agent: None,
+            token_id: self.token_id.clone(),
+            locale: Locale::from_env(),
+        };
         match command[0] {
             "git-upload-pack" | "git-receive-pack" => {
                 pack_protocol.service_type = ServiceType::from_str(command[0]).unwrap();
-                let res = pack_protocol.git_info_refs().await;
+                let res = match (pack_protocol.service_type, self.version) {
+                    (ServiceType::UploadPack, ProtocolVersion::V2) => {
+                        pack_protocol.version = ProtocolVersion::V2;
+                        pack_protocol.git_info_refs_v2()
+                    }
+                    _ => pack_protocol.git_info_refs().await,
+                };
+                if pack_protocol.service_type == ServiceType::ReceivePack {
+                    self.receive_pipe = Some(spawn_receive_pack(
+                        pack_protocol.clone(),
+                        channel,
+                        session.handle(),
+                    ));
+                }
                 self.pack_protocol = Some(pack_protocol);
                 session.data(channel, res.to_vec().into());
                 session.channel_success(channel);
@@ -119,17 +194,71 @@ impl server::Handler for SshServer {
                 };
                 session.data(channel, serde_json::to_vec(&link).unwrap().into());
             }
-            command => println!("Not Supported command! {}", command),
+            command => {
+                let message = format!("Not supported command: {}\n", command);
+                session.extended_data(channel, 1, message.into_bytes().into());
+                session.exit_status_request(channel, 1);
+                session.close(channel);
+            }
         }
         Ok((self, session))
     }
 
+    /// Keeps the protocol version of `GIT_PROTOCOL`, the other variables are ignored.
+    async fn env_request(
+        mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if variable_name == "GIT_PROTOCOL" {
+            self.version = ProtocolVersion::from_git_protocol(variable_value);
+        }
+        session.channel_success(channel);
+        Ok((self, session))
+    }
+
     async fn auth_publickey(
-        self,
+        mut self,
         user: &str,
         public_key: &key::PublicKey,
     ) -> Result<(Self, Auth), Self::Error> {
         tracing::info!("auth_publickey: {} / {:?}", user, public_key);
+        // without an auth provider the user name is not verified, the client stays anonymous
+        let Some(provider) = &self.auth else {
+            return Ok((self, Auth::Accept));
+        };
+        let accepted = match provider.list_keys(user).await {
+            Ok(keys) => auth::key_matches(&keys, public_key),
+            Err(err) => {
+                tracing::error!("failed to list the keys of {}: {}", user, err);
+                false
+            }
+        };
+        let owner = if !accepted && user == self.shared_user {
+            match provider.find_key_owner(public_key).await {
+                Ok(owner) => owner,
+                Err(err) => {
+                    tracing::error!("failed to find the owner of a key: {}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // the key of a user connecting with the shared account identifies them
+        let user = match owner {
+            Some(owner) => owner,
+            None if accepted => user.to_owned(),
+            None => return Ok((self, Auth::Reject { proceed_with_methods: None })),
+        };
+        let token_id = auth::key_id(public_key);
+        if self.is_suspended(&user, &token_id).await {
+            return Ok((self, Auth::Reject { proceed_with_methods: None }));
+        }
+        self.user = Some(user);
+        self.token_id = Some(token_id);
         Ok((self, Auth::Accept))
     }
 
@@ -140,12 +269,37 @@ impl server::Handler for SshServer {
         _: Option<Response<'async_trait>>,
     ) -> Result<(Self, Auth), Self::Error> {
         tracing::info!("auth_keyboard_interactive");
+        if self.auth.is_some() {
+            return Ok((self, Auth::Reject { proceed_with_methods: None }));
+        }
         Ok((self, Auth::Accept))
     }
 
-    async fn auth_password(self, user: &str, password: &str) -> Result<(Self, Auth), Self::Error> {
-        tracing::info!("auth_password: {} / {}", user, password);
-        // in this example implementation, any username/password combination is accepted
+    async fn auth_password(
+        mut self,
+        user: &str,
+        password: &str,
+    ) -> Result<(Self, Auth), Self::Error> {
+        tracing::info!("auth_password: {}", user);
+        // without an auth provider any username/password combination is accepted, and the
+        // client stays anonymous
+        let Some(provider) = &self.auth else {
+            return Ok((self, Auth::Accept));
+        };
+        let user = match provider.verify_token(Some(user), password).await {
+            Ok(Some(verified)) => verified.name,
+            Ok(None) => return Ok((self, Auth::Reject { proceed_with_methods: None })),
+            Err(err) => {
+                tracing::error!("failed to verify the password of {}: {}", user, err);
+                return Ok((self, Auth::Reject { proceed_with_methods: None }));
+            }
+        };
+        let token_id = auth::token_id(password);
+        if self.is_suspended(&user, &token_id).await {
+            return Ok((self, Auth::Reject { proceed_with_methods: None }));
+        }
+        self.user = Some(user);
+        self.token_id = Some(token_id);
         Ok((self, Auth::Accept))
     }
 
@@ -155,19 +309,39 @@ impl server::Handler for SshServer {
         data: &[u8],
         mut session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        let pack_protocol = self.pack_protocol.as_mut().unwrap();
         tracing::info!(
             "receiving data length:{}",
             // String::from_utf8_lossy(data),
             data.len()
         );
+        // the data of a channel without a git command is dropped
+        let Some(pack_protocol) = self.pack_protocol.as_ref() else {
+            return Ok((self, session));
+        };
 
-        match pack_protocol.service_type {
-            ServiceType::UploadPack => {
-                self.handle_upload_pack(channel, data, &mut session).await;
+        match (pack_protocol.service_type, pack_protocol.version) {
+            (ServiceType::UploadPack, ProtocolVersion::V2) => {
+                self.data_combined.extend(data);
+                self.handle_upload_pack_v2(channel, &mut session).await;
             }
-            ServiceType::ReceivePack => {
+            (ServiceType::UploadPack, ProtocolVersion::V0) => {
                 self.data_combined.extend(data);
+                let rounds = negotiation_rounds(&self.data_combined);
+                if rounds.done {
+                    let request = std::mem::take(&mut self.data_combined);
+                    self.handle_upload_pack(channel, &request, &mut session)
+                        .await;
+                } else {
+                    self.answer_rounds(channel, &rounds, &mut session);
+                }
+            }
+            // the pack is decoded as it arrives, the data of a push that failed are dropped
+            (ServiceType::ReceivePack, _) => {
+                if let Some(pipe) = &self.receive_pipe {
+                    if pipe.send(Bytes::copy_from_slice(data)).await.is_err() {
+                        self.receive_pipe = None;
+                    }
+                }
             }
         };
         session.channel_success(channel);
@@ -179,56 +353,239 @@ impl server::Handler for SshServer {
         channel: ChannelId,
         mut session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        if let Some(pack_protocol) = self.pack_protocol.as_mut() {
-            if pack_protocol.service_type == ServiceType::ReceivePack {
-                self.handle_receive_pack(channel, &mut session).await;
-            };
-        }
+        let receiving = self
+            .pack_protocol
+            .as_ref()
+            .is_some_and(|pack_protocol| pack_protocol.service_type == ServiceType::ReceivePack);
+        // the end of the pack, the task of the push reports it and closes the channel
+        self.receive_pipe = None;
 
         {
             let mut clients = self.clients.lock().unwrap();
             clients.remove(&(self.id, channel));
         }
-        session.exit_status_request(channel, 0000);
-        session.close(channel);
+        if !receiving {
+            session.exit_status_request(channel, 0000);
+            session.close(channel);
+        }
         Ok((self, session))
     }
 }
 
 impl SshServer {
+    /// Whether the user, or the key or password they authenticated with, is suspended.
+    async fn is_suspended(&self, user: &str, token_id: &str) -> bool {
+        self.context
+            .services
+            .audit_storage
+            .is_suspended(user, Some(token_id))
+            .await
+            .unwrap_or(false)
+    }
+
     async fn handle_upload_pack(&mut self, channel: ChannelId, data: &[u8], session: &mut Session) {
         let pack_protocol = self.pack_protocol.as_mut().unwrap();
 
-        let (send_pack_data, buf) = pack_protocol
+        let (send_pack_data, buf) = match pack_protocol
             .git_upload_pack(&mut Bytes::copy_from_slice(data))
             .await
-            .unwrap();
+        {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::error!("upload-pack failed: {}", err);
+                let message = format!("{}\n", err);
+                session.extended_data(channel, 1, message.into_bytes().into());
+                session.exit_status_request(channel, 1);
+                session.close(channel);
+                return;
+            }
+        };
 
         tracing::info!("buf is {:?}", buf);
-        session.data(channel, String::from_utf8(buf.to_vec()).unwrap().into());
-
-        let mut reader = send_pack_data.as_slice();
-        loop {
-            let mut temp = BytesMut::new();
-            temp.reserve(65500);
-            let length = reader.read_buf(&mut temp).await.unwrap();
-            if temp.is_empty() {
-                session.data(channel, pack::PKT_LINE_END_MARKER.to_vec().into());
-                return;
+        session.data(channel, buf.to_vec().into());
+
+        let chunks = super::side_band_chunks(pack_protocol, &send_pack_data);
+
+        let Some(bucket) = pack_protocol.upload_throttle() else {
+            for chunk in chunks {
+                session.data(channel, chunk.into());
             }
-            let bytes_out = pack_protocol.build_side_band_format(temp, length);
-            session.data(channel, bytes_out.to_vec().into());
-        }
+            return;
+        };
+        // the session only sends the data once this handler returns, a capped client is sent
+        // the pack by a task of its own
+        let handle = session.handle();
+        tokio::spawn(async move {
+            for chunk in chunks {
+                bucket.take(chunk.len()).await;
+                if handle.data(channel, chunk.into()).await.is_err() {
+                    tracing::warn!("ssh channel closed while sending the pack");
+                    return;
+                }
+            }
+        });
     }
 
-    async fn handle_receive_pack(&mut self, channel: ChannelId, session: &mut Session) {
+    /// Answers every complete request received, the client sends the next one on the same
+    /// channel once it has read the response.
+    async fn handle_upload_pack_v2(&mut self, channel: ChannelId, session: &mut Session) {
         let pack_protocol = self.pack_protocol.as_mut().unwrap();
+        let mut buf = BytesMut::from(&self.data_combined[..]);
+        while let Some(mut request) = v2::take_request(&mut buf) {
+            let sent = match pack_protocol.git_upload_pack_v2(&mut request).await {
+                Ok(mut response) => {
+                    let mut sent = Ok(());
+                    while let Some(chunk) = response.next().await {
+                        match chunk {
+                            Ok(chunk) => session.data(channel, chunk.to_vec().into()),
+                            Err(err) => {
+                                sent = Err(err.to_string());
+                                break;
+                            }
+                        }
+                    }
+                    sent
+                }
+                Err(err) => Err(err.to_string()),
+            };
+            match sent {
+                Ok(()) => {}
+                Err(err) => {
+                    tracing::error!("protocol v2 request failed: {}", err);
+                    let message = format!("{}\n", err);
+                    session.extended_data(channel, 1, message.into_bytes().into());
+                    session.exit_status_request(channel, 1);
+                    session.close(channel);
+                    break;
+                }
+            }
+        }
+        self.data_combined = buf.to_vec();
+    }
 
-        let buf = pack_protocol
-            .git_receive_pack(Bytes::from(self.data_combined.to_vec()))
-            .await
-            .unwrap();
-        tracing::info!("report status: {:?}", buf);
-        session.data(channel, buf.to_vec().into());
+    /// Answers the rounds of `have` lines the client flushed since the last call with a `NAK`:
+    /// over SSH the client waits for them before sending more, while the common commits are
+    /// only computed on `done`, from the whole negotiation. The first round holds the wants.
+    fn answer_rounds(
+        &mut self,
+        channel: ChannelId,
+        rounds: &NegotiationRounds,
+        session: &mut Session,
+    ) {
+        let flushed = rounds.flushes.saturating_sub(1);
+        for _ in self.rounds_answered..flushed {
+            let mut buf = BytesMut::new();
+            pack::add_pkt_line_string(&mut buf, String::from("NAK\n"));
+            session.data(channel, buf.to_vec().into());
+        }
+        self.rounds_answered = self.rounds_answered.max(flushed);
+    }
+}
+
+/// Runs the push of `channel` in a task of its own: the pack is decoded as the data of the
+/// channel are sent to the pipe returned, then the report is sent and the channel closed.
+fn spawn_receive_pack(
+    mut pack_protocol: PackProtocol,
+    channel: ChannelId,
+    handle: Handle,
+) -> mpsc::Sender<Bytes> {
+    let (sender, receiver) = mpsc::channel(RECEIVE_PIPE_CHUNKS);
+    let chunks = stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((Ok::<_, io::Error>(chunk), receiver))
+    });
+    tokio::spawn(async move {
+        let body = StreamReader::new(Box::pin(chunks));
+        let status = match pack_protocol.git_receive_pack_stream(body, None).await {
+            Ok(report) => {
+                tracing::info!("report status: {:?}", report);
+                let _ = handle.data(channel, report.to_vec().into()).await;
+                0
+            }
+            Err(err) => {
+                tracing::error!("receive-pack failed: {}", err);
+                let message = format!("{}\n", err);
+                let _ = handle
+                    .extended_data(channel, 1, message.into_bytes().into())
+                    .await;
+                1
+            }
+        };
+        let _ = handle.exit_status_request(channel, status).await;
+        let _ = handle.close(channel).await;
+    });
+    sender
+}
+
+/// The path of the repository of a command argument: `'/project/repo.git'` as sent for
+/// `ssh://` remotes and `'project/repo.git'` as sent for `git@mega:` ones are both `/project/repo`.
+pub(crate) fn repo_path(arg: &str) -> String {
+    let path = arg
+        .trim_matches(|c| c == '\'' || c == '"')
+        .trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    format!("/{}", path.trim_start_matches('/'))
+}
+
+/// The state of a v0 upload-pack negotiation received so far.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct NegotiationRounds {
+    /// The flush-pkts, ending the wants and every round of haves.
+    pub flushes: usize,
+    /// Whether the last line is `done`, the negotiation is then over.
+    pub done: bool,
+}
+
+pub(crate) fn negotiation_rounds(mut data: &[u8]) -> NegotiationRounds {
+    let mut rounds = NegotiationRounds::default();
+    while let Some(length) = data
+        .get(..4)
+        .and_then(|length| std::str::from_utf8(length).ok())
+        .and_then(|length| usize::from_str_radix(length, 16).ok())
+    {
+        if length == 0 {
+            rounds.flushes += 1;
+            rounds.done = false;
+            data = &data[4..];
+            continue;
+        }
+        let Some(line) = data.get(4..length) else {
+            break;
+        };
+        rounds.done = line.trim_ascii_end() == b"done";
+        data = &data[length..];
+    }
+    rounds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiation_rounds, repo_path, NegotiationRounds};
+
+    #[test]
+    fn test_repo_path() {
+        assert_eq!(repo_path("'/project/repo.git'"), "/project/repo");
+        assert_eq!(repo_path("'project/repo.git'"), "/project/repo");
+        assert_eq!(repo_path("'project/repo/'"), "/project/repo");
+        assert_eq!(repo_path("'/project.github/repo'"), "/project.github/repo");
+    }
+
+    #[test]
+    fn test_negotiation_rounds() {
+        let wants = b"0032want 7bdc783132575d5b3e78400ace9971970ff43a18\n0000".to_vec();
+        assert_eq!(
+            negotiation_rounds(&wants),
+            NegotiationRounds {
+                flushes: 1,
+                done: false
+            }
+        );
+        let mut haves = wants.clone();
+        haves.extend(b"0032have 0c1a6e8e5b5d3ab4b0fd1a4e9be7d1e0a8f04d3f\n0000");
+        assert_eq!(negotiation_rounds(&haves).flushes, 2);
+        // a line cut in the middle is not counted
+        assert_eq!(negotiation_rounds(&haves[..haves.len() - 10]).flushes, 1);
+        haves.extend(b"0009done\n");
+        assert!(negotiation_rounds(&haves).done);
     }
 }
@@ -3,34 +3,49 @@
 //!
 //!
 //!
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
 
 use anyhow::Result;
-use axum::body::Body;
-use axum::extract::{Query, State};
-use axum::http::{Request, StatusCode, Uri};
+use axum::body::{to_bytes, Body};
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::{header, HeaderMap, Method, Request, StatusCode, Uri};
+use axum::middleware::{self, Next};
 use axum::response::Response;
 use axum::routing::get;
-use axum::Router;
+use axum::{Extension, Router};
 use clap::Args;
 use regex::Regex;
 use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::trace::TraceLayer;
 
-use ceres::lfs::LfsConfig;
-use ceres::protocol::{PackProtocol, Protocol};
+use ceres::lfs::{content_storage, LfsConfig, LFS_REPO_NAME};
+use ceres::protocol::v2::ProtocolVersion;
+use ceres::protocol::{ClientInfo, PackProtocol, Protocol};
+use common::i18n::Locale;
 use common::model::{CommonOptions, GetParams};
 use jupiter::context::Context;
-use jupiter::raw_storage::local_storage::LocalStorage;
+use venus::hash::HashKind;
 
 use crate::api_service::obj_service::ObjectService;
 use crate::api_service::router::ApiServiceState;
-use crate::{api_service, lfs};
+use crate::auth::policy::{self, Action, Policy};
+use crate::auth::redact;
+use crate::auth::{self, AuthProvider, AuthUser};
+use crate::proxy::TrustedProxies;
+use crate::review_notice::ReviewNotifier;
+use crate::tls::{self, ClientAuth, ReloadingAcceptor, TlsConfig};
+#[cfg(unix)]
+use crate::unix_socket;
+use crate::{api_service, chatops, events, lfs, push_hooks, settings, web_ui};
+
+/// Bodies read to find the resource of a request, the default limit of the JSON extractor.
+const MAX_SCOPED_BODY_SIZE: usize = 2 * 1024 * 1024;
 
 #[derive(Args, Clone, Debug)]
 pub struct HttpOptions {
@@ -54,28 +69,374 @@ pub struct HttpCustom {
 
     #[arg(long, value_name = "FILE")]
     https_cert_path: Option<PathBuf>,
+
+    /// CA certificates of the clients of the HTTPS listener, PEM
+    #[arg(long, value_name = "FILE")]
+    https_client_ca_path: Option<PathBuf>,
+
+    /// Whether the clients of the HTTPS listener present a certificate signed by the client CA
+    #[arg(long, value_enum, default_value_t = ClientAuth::None)]
+    https_client_auth: ClientAuth,
+
+    /// Also serves HTTP on a unix domain socket at this path
+    #[cfg(unix)]
+    #[arg(long, value_name = "FILE")]
+    http_unix_socket: Option<PathBuf>,
+
+    /// Permissions of the unix domain socket, in octal
+    #[cfg(unix)]
+    #[arg(long, value_name = "MODE", default_value = "660", value_parser = unix_socket::parse_mode)]
+    http_unix_socket_mode: u32,
+
+    /// Serves the web UI embedded in the binary under /ui, see the web-ui feature
+    #[arg(long)]
+    web_ui: bool,
+}
+
+impl HttpCustom {
+    /// The TLS settings of the HTTPS listener, None when it has no certificate.
+    fn https_tls(&self) -> Option<TlsConfig> {
+        Some(TlsConfig {
+            cert_path: self.https_cert_path.clone()?,
+            key_path: self.https_key_path.clone()?,
+            client_ca_path: self.https_client_ca_path.clone(),
+            client_auth: self.https_client_auth,
+        })
+    }
 }
 
 #[derive(Clone)]
 pub struct AppState {
     pub context: Context,
     pub options: HttpOptions,
+    /// None when authentication is disabled and every client is accepted.
+    pub auth: Option<Arc<dyn AuthProvider>>,
+    pub policy: Arc<Policy>,
+    pub proxies: Arc<TrustedProxies>,
 }
 
 impl From<AppState> for LfsConfig {
     fn from(value: AppState) -> Self {
+        let server_url = format!(
+            "http://{}:{}",
+            value.options.common.host, value.options.custom.http_port
+        );
         Self {
             host: value.options.common.host,
             port: value.options.custom.http_port,
             context: value.context.clone(),
-            lfs_storage: Arc::new(LocalStorage::init(PathBuf::from("lfs-files"))),
-            repo_name: String::from("lfs"),
+            lfs_storage: content_storage(),
+            repo_name: String::from(LFS_REPO_NAME),
+            server_url,
+        }
+    }
+}
+
+/// The routes served under a repository, `*` stands for any segment. LFS is also served under
+/// `info/lfs`.
+const REPO_ROUTES: [&[&str]; 8] = [
+    &["info", "refs"],
+    &["git-upload-pack"],
+    &["git-receive-pack"],
+    &["objects", "batch"],
+    &["objects", "*"],
+    &["locks"],
+    &["locks", "verify"],
+    &["locks", "*", "unlock"],
+];
+
+/// The repository a git or LFS request is served from: its path less the route matched as
+/// whole trailing segments and a trailing `.git`, `/` for the root. The policy is checked
+/// against this same path, see [`request_scope`].
+pub fn repo_path(path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let matches = |route: &[&str], end: usize| {
+        end >= route.len()
+            && route
+                .iter()
+                .zip(&segments[end - route.len()..end])
+                .all(|(expected, segment)| *expected == "*" || expected == segment)
+    };
+    let mut end = REPO_ROUTES
+        .iter()
+        .filter(|route| matches(route, segments.len()))
+        .map(|route| segments.len() - route.len())
+        .min()
+        .unwrap_or(segments.len());
+    if end < segments.len() && matches(&["info", "lfs"], end) {
+        end -= 2;
+    }
+    let repo = segments[..end].join("/");
+    format!("/{}", repo.strip_suffix(".git").unwrap_or(&repo))
+}
+
+/// Collects the client address and user agent of a request for auditing, and the language of
+/// the client from `Accept-Language`. The user is left to the authentication, a user name of
+/// basic auth is anyone's to pick until a provider verified it.
+fn client_info(ip: IpAddr, headers: &HeaderMap) -> ClientInfo {
+    ClientInfo {
+        user: None,
+        ip: Some(ip.to_string()),
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from),
+        token_id: None,
+        locale: headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Locale::from_accept_language)
+            .unwrap_or_else(Locale::from_env),
+    }
+}
+
+/// The object format a client asks the refs in, the `object-format` parameter of the
+/// `Git-Protocol` header, e.g. `version=1:object-format=sha256`, SHA-1 by default.
+fn object_format(headers: &HeaderMap) -> HashKind {
+    headers
+        .get("Git-Protocol")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value
+                .split(':')
+                .find_map(|param| param.strip_prefix("object-format="))
+        })
+        .and_then(|format| format.parse().ok())
+        .unwrap_or_default()
+}
+
+/// The protocol version of the `Git-Protocol` header, v2 is only spoken in SHA-1: a client of
+/// another object format is served v0 with its ids translated.
+fn protocol_version(headers: &HeaderMap) -> ProtocolVersion {
+    let version = headers
+        .get("Git-Protocol")
+        .and_then(|value| value.to_str().ok())
+        .map(ProtocolVersion::from_git_protocol)
+        .unwrap_or_default();
+    match object_format(headers) {
+        HashKind::Sha1 => version,
+        _ => ProtocolVersion::V0,
+    }
+}
+
+/// Verifies the credentials of a request with the auth provider, a request without any is
+/// anonymous and one with invalid credentials is answered with a challenge.
+async fn authenticate(
+    state: &AppState,
+    headers: &HeaderMap,
+    client: &ClientInfo,
+) -> Result<Option<AuthUser>, Response> {
+    let Some(provider) = &state.auth else {
+        return Ok(None);
+    };
+    let Some((user, secret)) = auth::credentials(headers) else {
+        return Ok(None);
+    };
+    match provider.verify_token(user.as_deref(), &secret).await {
+        Ok(Some(user)) => Ok(Some(user)),
+        Ok(None) => {
+            tracing::warn!(
+                "refused credentials of {} from {}",
+                user.as_deref().unwrap_or("a bearer token"),
+                client.ip.as_deref().unwrap_or("unknown")
+            );
+            Err(challenge())
+        }
+        Err(err) => {
+            tracing::error!("failed to verify credentials: {}", err);
+            Err(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from("Authentication service unavailable\n"))
+                .unwrap())
         }
     }
 }
 
-pub fn remove_git_suffix(uri: Uri, git_suffix: &str) -> PathBuf {
-    PathBuf::from(uri.path().replace(".git", "").replace(git_suffix, ""))
+fn challenge() -> Response {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(header::WWW_AUTHENTICATE, "Basic realm=\"mega\"")
+        .body(Body::from("Authentication required\n"))
+        .unwrap()
+}
+
+/// The action and resources of a request checked against the policy. Git and LFS requests act
+/// on their repository, API requests on the `repo_path` and `path` of their query, see
+/// [`body_resource`] for the writes also naming one in their body. An API request naming
+/// none acts on `/`.
+fn request_scope(method: &Method, uri: &Uri) -> (Action, Vec<String>) {
+    let path = uri.path();
+    if let Some(route) = path.strip_prefix("/api/v1") {
+        let resources = query_resources(uri);
+        let admin = [
+            "/admin",
+            "/audit",
+            "/pins",
+            "/mr/archives",
+            "/branch-cleanup",
+            "/replace",
+            "/init",
+            "/settings",
+        ]
+        .iter()
+        .any(|prefix| route.starts_with(prefix));
+        let action = match *method {
+            _ if admin => Action::Admin,
+            Method::GET => Action::Read,
+            _ => Action::Write,
+        };
+        return (action, resources);
+    }
+
+    let push = path.ends_with("/git-receive-pack")
+        || uri.query().is_some_and(|q| q.contains("service=git-receive-pack"));
+    // the batch request only negotiates, objects are uploaded by PUT
+    let action = match *method {
+        _ if push => Action::Write,
+        Method::GET => Action::Read,
+        Method::POST if path.ends_with("/objects/batch") => Action::Read,
+        _ => Action::Write,
+    };
+    (action, vec![repo_path(path)])
+}
+
+/// The `repo_path` and `path` of the query. Next to a `repo_path` the `path` is relative to
+/// the repository and is checked below it, alone it is absolute.
+fn query_resources(uri: &Uri) -> Vec<String> {
+    let mut query: HashMap<String, String> = Query::try_from_uri(uri)
+        .map(|Query(query)| query)
+        .unwrap_or_default();
+    let path = query.remove("path");
+    match query.remove("repo_path") {
+        Some(repo_path) => {
+            let path = path.map(|path| format!("{}/{}", repo_path, path));
+            [Some(repo_path), path].into_iter().flatten().collect()
+        }
+        None => path.into_iter().collect(),
+    }
+}
+
+/// Whether a resource of a request may be named in its body: an API write with a body the
+/// JSON extractor reads.
+fn scoped_by_body(method: &Method, uri: &Uri, headers: &HeaderMap) -> bool {
+    uri.path().starts_with("/api/v1")
+        && *method != Method::GET
+        && headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(is_json_content_type)
+}
+
+/// Whether `content_type` is JSON as the `Json` extractor of axum takes it: `application/json`
+/// or `application/<name>+json`, with any parameter.
+fn is_json_content_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match essence.split_once('/') {
+        Some(("application", subtype)) => subtype == "json" || subtype.ends_with("+json"),
+        _ => false,
+    }
+}
+
+/// The resource named by the JSON body of an API write: its `repo_path`, or its `path` along
+/// with the `name` of the file created in it.
+fn body_resource(body: &[u8]) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+    let field = |name: &str| value.get(name).and_then(|v| v.as_str());
+    if let Some(repo_path) = field("repo_path") {
+        return Some(repo_path.to_owned());
+    }
+    let path = format!("/{}", field("path")?.trim_matches('/'));
+    Some(match field("name").filter(|name| !name.is_empty()) {
+        Some(name) => format!("{}/{}", path.trim_end_matches('/'), name),
+        None => path,
+    })
+}
+
+/// The first of `resources` the policy denies `action` on. Every path a request carries is
+/// checked, its handler may act on any of them.
+fn denied_resource<'a>(
+    policy: &Policy,
+    subject: Option<&str>,
+    action: Action,
+    resources: &'a [String],
+) -> Option<&'a str> {
+    resources
+        .iter()
+        .find(|resource| !policy.is_allowed(subject, action, resource))
+        .map(String::as_str)
+}
+
+/// Authenticates the client and checks the policy for every request, API and git alike. The
+/// verified user is passed on to the handlers as an extension.
+async fn authorize(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    let ip = state.proxies.client_ip(addr.ip(), req.headers());
+    let client = client_info(ip, req.headers());
+    let user = match authenticate(&state, req.headers(), &client).await {
+        Ok(user) => user,
+        Err(res) => return res,
+    };
+    // without an auth provider the user name of basic auth is not verified, so it is not
+    // suspended either
+    if let Some(name) = user.as_ref().map(|u| u.name.as_str()) {
+        let token_id = auth::credentials(req.headers()).map(|(_, secret)| auth::token_id(&secret));
+        if state
+            .context
+            .services
+            .audit_storage
+            .is_suspended(name, token_id.as_deref())
+            .await
+            .unwrap_or(false)
+        {
+            let message = client.locale.tr("http-user-suspended", &[("user", name)]) + "\n";
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from(message))
+                .unwrap();
+        }
+    }
+
+    let (action, mut resources) = request_scope(req.method(), req.uri());
+    if scoped_by_body(req.method(), req.uri(), req.headers()) {
+        let (parts, body) = req.into_parts();
+        let bytes = match to_bytes(body, MAX_SCOPED_BODY_SIZE).await {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                return Response::builder()
+                    .status(StatusCode::PAYLOAD_TOO_LARGE)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        };
+        resources.extend(body_resource(&bytes));
+        req = Request::from_parts(parts, Body::from(bytes));
+    }
+    if resources.is_empty() {
+        resources.push(String::from("/"));
+    }
+    let subject = user.as_ref().map(|u| u.name.as_str());
+    if let Some(resource) = denied_resource(&state.policy, subject, action, &resources) {
+        if subject.is_none() && state.auth.is_some() {
+            return challenge();
+        }
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from(format!("Permission denied: {} {}\n", action, resource)))
+            .unwrap();
+    }
+    if let Some(user) = user {
+        req.extensions_mut().insert(user);
+    }
+    next.run(req).await
 }
 
 pub async fn start_server(options: &HttpOptions) {
@@ -83,30 +444,47 @@ pub async fn start_server(options: &HttpOptions) {
         common: CommonOptions { host, data_source },
         custom:
             HttpCustom {
-                https_key_path: _,
-                https_cert_path: _,
                 http_port,
-                https_port: _,
+                https_port,
+                ..
             },
     } = options;
     let server_url = format!("{}:{}", host, http_port);
 
+    let context = Context::new(data_source).await;
+    let auth = auth::from_env(&context);
+    let policy = policy::load(&context, auth.is_some())
+        .await
+        .expect("failed to load the authorization policy");
     let state = AppState {
         options: options.to_owned(),
-        context: Context::new(data_source).await,
+        auth,
+        policy: Arc::new(policy),
+        proxies: Arc::new(TrustedProxies::from_env()),
+        context,
     };
+    push_hooks::register_push_hooks(&state.context);
+    settings::spawn_reload_task(state.context.clone());
+    events::spawn_delivery_task(state.context.clone());
 
     let api_state = ApiServiceState {
         object_service: ObjectService {
             storage: state.context.storage.clone(),
         },
         context: state.context.clone(),
+        policy: state.policy.clone(),
+        review_notifier: Arc::new(ReviewNotifier::from_env()),
     };
 
     let app = Router::new()
         .nest(
             "/api/v1",
-            api_service::router::routers().with_state(api_state),
+            api_service::router::routers()
+                .with_state(api_state.clone())
+                .layer(middleware::from_fn_with_state(
+                    state.policy.clone(),
+                    redact::filter_response,
+                )),
         )
         .route(
             "/*path",
@@ -114,13 +492,50 @@ pub async fn start_server(options: &HttpOptions) {
                 .post(post_method_router)
                 .put(put_method_router),
         )
+        .layer(middleware::from_fn_with_state(state.clone(), authorize));
+    // the webhooks and the chats authenticate their requests themselves
+    let app = app.nest(
+        "/api/v1",
+        api_service::router::webhook_routers().with_state(api_state),
+    );
+    let app = match chatops::routers(&state) {
+        Some(chatops) => app.nest("/chatops", chatops),
+        None => app,
+    };
+    // the assets of the UI are public, its calls to the API are authorized
+    let app = match options.custom.web_ui.then(web_ui::routers).flatten() {
+        Some(web_ui) => app.merge(web_ui),
+        None => app,
+    };
+    let app = app
         .layer(ServiceBuilder::new().layer(CorsLayer::new().allow_origin(Any)))
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
+    if let Some(tls) = options.custom.https_tls() {
+        let acceptor = match ReloadingAcceptor::new(tls) {
+            Ok(acceptor) => Arc::new(acceptor),
+            Err(err) => panic!("invalid TLS configuration of the HTTPS listener: {}", err),
+        };
+        tls::spawn_reload_task(acceptor.clone());
+        let addr = SocketAddr::from_str(&format!("{}:{}", host, https_port)).unwrap();
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(tls::serve(listener, acceptor, app.clone()));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &options.custom.http_unix_socket {
+        let listener = unix_socket::bind(path, options.custom.http_unix_socket_mode)
+            .unwrap_or_else(|err| panic!("failed to bind {}: {}", path.display(), err));
+        tokio::spawn(unix_socket::serve(listener, app.clone()));
+    }
+
     let addr = SocketAddr::from_str(&server_url).unwrap();
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app.into_make_service())
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
         .await
         .unwrap();
 }
@@ -128,6 +543,7 @@ pub async fn start_server(options: &HttpOptions) {
 async fn get_method_router(
     state: State<AppState>,
     Query(params): Query<GetParams>,
+    headers: HeaderMap,
     uri: Uri,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     let lfs_config: LfsConfig = state.deref().to_owned().into();
@@ -140,11 +556,13 @@ async fn get_method_router(
     } else if Regex::new(r"/locks$").unwrap().is_match(uri.path()) {
         return lfs::lfs_retrieve_lock(&lfs_config, params).await;
     } else if Regex::new(r"/info/refs$").unwrap().is_match(uri.path()) {
-        let pack_protocol = PackProtocol::new(
-            remove_git_suffix(uri, "/info/refs"),
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from(repo_path(uri.path())),
             state.context.clone(),
             Protocol::Http,
         );
+        pack_protocol.object_format = object_format(&headers);
+        pack_protocol.version = protocol_version(&headers);
         return ceres::http::handler::git_info_refs(params, pack_protocol).await;
     } else {
         return Err((
@@ -156,38 +574,53 @@ async fn get_method_router(
 
 async fn post_method_router(
     state: State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    user: Option<Extension<AuthUser>>,
     uri: Uri,
     req: Request<Body>,
 ) -> Result<Response, (StatusCode, String)> {
-    let lfs_config: LfsConfig = state.deref().to_owned().into();
+    let mut lfs_config: LfsConfig = state.deref().to_owned().into();
+    if let Some(url) = state.proxies.forwarded_url(addr.ip(), &headers) {
+        lfs_config.server_url = url;
+    }
+    let mut client = client_info(state.proxies.client_ip(addr.ip(), &headers), &headers);
+    if let Some(Extension(user)) = user {
+        client.user = Some(user.name);
+        client.token_id = auth::credentials(&headers).map(|(_, secret)| auth::token_id(&secret));
+    }
     // Routing LFS services.
     if Regex::new(r"/locks/verify$").unwrap().is_match(uri.path()) {
-        lfs::lfs_verify_lock(state, &lfs_config, req).await
+        lfs::lfs_verify_lock(state, &lfs_config, client.user.as_deref(), req).await
     } else if Regex::new(r"/locks$").unwrap().is_match(uri.path()) {
-        return lfs::lfs_create_lock(state, &lfs_config, req).await;
+        return lfs::lfs_create_lock(state, &lfs_config, client.user.as_deref(), req).await;
     } else if Regex::new(r"/unlock$").unwrap().is_match(uri.path()) {
-        return lfs::lfs_delete_lock(state, &lfs_config, uri.path(), req).await;
+        let user = client.user.as_deref();
+        return lfs::lfs_delete_lock(state, &lfs_config, uri.path(), user, req).await;
     } else if Regex::new(r"/objects/batch$").unwrap().is_match(uri.path()) {
         return lfs::lfs_process_batch(state, &lfs_config, req).await;
     } else if Regex::new(r"/git-upload-pack$")
         .unwrap()
         .is_match(uri.path())
     {
-        let pack_protocol = PackProtocol::new(
-            remove_git_suffix(uri, "/git-upload-pack"),
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from(repo_path(uri.path())),
             state.context.clone(),
             Protocol::Http,
         );
+        pack_protocol.client = client;
+        pack_protocol.version = protocol_version(&headers);
         ceres::http::handler::git_upload_pack(req, pack_protocol).await
     } else if Regex::new(r"/git-receive-pack$")
         .unwrap()
         .is_match(uri.path())
     {
-        let pack_protocol = PackProtocol::new(
-            remove_git_suffix(uri, "/git-receive-pack"),
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from(repo_path(uri.path())),
             state.context.clone(),
             Protocol::Http,
         );
+        pack_protocol.client = client;
         ceres::http::handler::git_receive_pack(req, pack_protocol).await
     } else {
         Err((
@@ -217,4 +650,203 @@ async fn put_method_router(
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::http::{header, HeaderMap, Method, Uri};
+
+    use common::i18n::Locale;
+
+    use venus::hash::HashKind;
+
+    use ceres::protocol::v2::ProtocolVersion;
+
+    use super::{
+        body_resource, client_info, denied_resource, is_json_content_type, object_format,
+        protocol_version, repo_path, request_scope, scoped_by_body,
+    };
+    use crate::auth::policy::{Action, Policy};
+
+    #[test]
+    fn test_client_info() {
+        let addr: SocketAddr = "10.0.0.8:51234".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, "git/2.43.0".parse().unwrap());
+        // alice:secret
+        headers.insert(header::AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0".parse().unwrap());
+        headers.insert(header::ACCEPT_LANGUAGE, "zh-CN, en;q=0.9".parse().unwrap());
+        let client = client_info(addr.ip(), &headers);
+        assert_eq!(client.ip.as_deref(), Some("10.0.0.8"));
+        // not verified
+        assert_eq!(client.user, None);
+        assert_eq!(client.token_id, None);
+        assert_eq!(client.user_agent.as_deref(), Some("git/2.43.0"));
+        assert_eq!(client.locale, Locale::Zh);
+
+        let client = client_info(addr.ip(), &HeaderMap::new());
+        assert_eq!(client.user, None);
+        assert_eq!(client.user_agent, None);
+    }
+
+    #[test]
+    fn test_object_format() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(object_format(&headers), HashKind::Sha1);
+        headers.insert("Git-Protocol", "version=1:object-format=sha256".parse().unwrap());
+        assert_eq!(object_format(&headers), HashKind::Sha256);
+        headers.insert("Git-Protocol", "object-format=md5".parse().unwrap());
+        assert_eq!(object_format(&headers), HashKind::Sha1);
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(protocol_version(&headers), ProtocolVersion::V0);
+        headers.insert("Git-Protocol", "version=2".parse().unwrap());
+        assert_eq!(protocol_version(&headers), ProtocolVersion::V2);
+        headers.insert("Git-Protocol", "version=2:object-format=sha256".parse().unwrap());
+        assert_eq!(protocol_version(&headers), ProtocolVersion::V0);
+    }
+
+    #[test]
+    fn test_request_scope() {
+        let scope =
+            |method: Method, uri: &str| request_scope(&method, &uri.parse::<Uri>().unwrap());
+        let repo = vec![String::from("/projects/mega")];
+        let root = vec![String::from("/")];
+        assert_eq!(
+            scope(Method::GET, "/projects/mega.git/info/refs?service=git-upload-pack"),
+            (Action::Read, repo.clone())
+        );
+        assert_eq!(
+            scope(Method::GET, "/projects/mega.git/info/refs?service=git-receive-pack"),
+            (Action::Write, repo.clone())
+        );
+        assert_eq!(
+            scope(Method::POST, "/projects/mega/git-receive-pack"),
+            (Action::Write, repo.clone())
+        );
+        assert_eq!(
+            scope(Method::POST, "/projects/mega.git/info/lfs/objects/batch"),
+            (Action::Read, repo.clone())
+        );
+        assert_eq!(
+            scope(Method::POST, "/projects/mega/locks/42/unlock"),
+            (Action::Write, repo.clone())
+        );
+        assert_eq!(
+            scope(Method::GET, "/api/v1/tree/commits?repo_path=/projects/mega&path=src"),
+            (
+                Action::Read,
+                vec![String::from("/projects/mega"), String::from("/projects/mega/src")]
+            )
+        );
+        assert_eq!(
+            scope(Method::GET, "/api/v1/tree/commits?repo_path=/projects/mega"),
+            (Action::Read, repo)
+        );
+        assert_eq!(
+            scope(Method::GET, "/api/v1/analytics/hotspots?path=/projects/secret"),
+            (Action::Read, vec![String::from("/projects/secret")])
+        );
+        assert_eq!(
+            scope(Method::GET, "/api/v1/audit/operations"),
+            (Action::Admin, Vec::new())
+        );
+        assert_eq!(
+            scope(Method::POST, "/api/v1/admin/users/keys/revoke"),
+            (Action::Admin, Vec::new())
+        );
+        assert_eq!(scope(Method::GET, "/api/v1/settings"), (Action::Admin, Vec::new()));
+        assert_eq!(scope(Method::POST, "/api/v1/create_file"), (Action::Write, Vec::new()));
+        assert_eq!(scope(Method::GET, "/info/refs"), (Action::Read, root));
+
+        // the routes are only matched as whole trailing segments
+        assert_eq!(
+            scope(Method::GET, "/projects/locksmith/secret/info/refs"),
+            (Action::Read, vec![String::from("/projects/locksmith/secret")])
+        );
+        assert_eq!(
+            scope(Method::GET, "/projects/info/refsecret/objects-x"),
+            (Action::Read, vec![String::from("/projects/info/refsecret/objects-x")])
+        );
+        // only a trailing `.git` is dropped
+        assert_eq!(
+            scope(Method::GET, "/a/.github/b.git/info/refs?service=git-upload-pack"),
+            (Action::Read, vec![String::from("/a/.github/b")])
+        );
+    }
+
+    #[test]
+    fn test_repo_path() {
+        // the repository served is the one authorized
+        assert_eq!(repo_path("/a/.github/b.git/info/refs"), "/a/.github/b");
+        assert_eq!(repo_path("/a/.github/b/git-upload-pack"), "/a/.github/b");
+        assert_eq!(repo_path("/projects/mega.git/git-receive-pack"), "/projects/mega");
+        assert_eq!(repo_path("/projects/mega.github/git-receive-pack"), "/projects/mega.github");
+        assert_eq!(repo_path("/projects/locks/info/refs"), "/projects/locks");
+        assert_eq!(repo_path("/projects/mega/info/lfs/locks/verify"), "/projects/mega");
+        assert_eq!(repo_path("/git-upload-pack"), "/");
+    }
+
+    #[test]
+    fn test_scoped_by_body() {
+        let scoped = |method: Method, uri: &str, content_type: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            scoped_by_body(&method, &uri.parse::<Uri>().unwrap(), &headers)
+        };
+        assert!(scoped(Method::POST, "/api/v1/create_file", "application/json"));
+        // a path in the query does not stop the body from being read
+        assert!(scoped(Method::POST, "/api/v1/create_file?path=/allowed", "application/json"));
+        assert!(scoped(Method::POST, "/api/v1/create_file", "application/vnd.api+json"));
+        assert!(scoped(Method::POST, "/api/v1/create_file", "Application/JSON; charset=utf-8"));
+        assert!(!scoped(Method::POST, "/api/v1/create_file", "text/plain"));
+        assert!(!scoped(Method::GET, "/api/v1/tree", "application/json"));
+        assert!(!scoped(Method::POST, "/projects/mega/git-receive-pack", "application/json"));
+
+        assert!(is_json_content_type("application/problem+json"));
+        assert!(!is_json_content_type("application/jsonx"));
+        assert!(!is_json_content_type("text/json"));
+    }
+
+    #[test]
+    fn test_denied_resource() {
+        let policy = Policy::parse(
+            "allow * * /**
+            deny * write /secret/**",
+        )
+        .unwrap();
+        // the path of the query is allowed, the one of the body is not
+        let mut resources = vec![String::from("/allowed")];
+        resources.extend(body_resource(br#"{"name":"a.txt","path":"/secret"}"#));
+        assert_eq!(
+            denied_resource(&policy, None, Action::Write, &resources),
+            Some("/secret/a.txt")
+        );
+        assert_eq!(
+            denied_resource(&policy, None, Action::Write, &[String::from("/allowed/../secret")]),
+            Some("/allowed/../secret")
+        );
+        assert_eq!(denied_resource(&policy, None, Action::Write, &resources[..1]), None);
+    }
+
+    #[test]
+    fn test_body_resource() {
+        let create_file = br#"{"is_directory":false,"name":"lib.rs","path":"/projects/mega/src"}"#;
+        assert_eq!(
+            body_resource(create_file),
+            Some(String::from("/projects/mega/src/lib.rs"))
+        );
+        assert_eq!(
+            body_resource(br#"{"is_directory":true,"name":"docs","path":"","content":""}"#),
+            Some(String::from("/docs"))
+        );
+        assert_eq!(
+            body_resource(br#"{"repo_path":"/projects/mega","path":"src"}"#),
+            Some(String::from("/projects/mega"))
+        );
+        assert_eq!(body_resource(br#"{"mr_id":1}"#), None);
+        assert_eq!(body_resource(b"not json"), None);
+    }
+}
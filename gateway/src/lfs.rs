@@ -94,6 +94,7 @@ pub async fn lfs_retrieve_lock(
 pub async fn lfs_verify_lock(
     state: State<AppState>,
     config: &LfsConfig,
+    user: Option<&str>,
     req: Request<Body>,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     tracing::info!("req: {:?}", req);
@@ -102,7 +103,7 @@ pub async fn lfs_verify_lock(
         .await
         .unwrap_or_else(|_| Json(VerifiableLockRequest::default()));
 
-    let result = handler::lfs_verify_lock(config, request.0).await;
+    let result = handler::lfs_verify_lock(config, request.0, user).await;
     match result {
         Ok(lock_list) => {
             let body = serde_json::to_string(&lock_list).unwrap_or_default();
@@ -123,13 +124,14 @@ pub async fn lfs_verify_lock(
 pub async fn lfs_create_lock(
     state: State<AppState>,
     config: &LfsConfig,
+    user: Option<&str>,
     req: Request<Body>,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     let request = Json::from_request(req, &state)
         .await
         .unwrap_or_else(|_| Json(LockRequest::default()));
 
-    let result = handler::lfs_create_lock(config, request.0).await;
+    let result = handler::lfs_create_lock(config, request.0, user).await;
     match result {
         Ok(lock) => {
             let lock_response = LockResponse {
@@ -156,6 +158,7 @@ pub async fn lfs_delete_lock(
     state: State<AppState>,
     config: &LfsConfig,
     path: &str,
+    user: Option<&str>,
     req: Request<Body>,
 ) -> Result<Response, (StatusCode, String)> {
     let tokens: Vec<&str> = path.split('/').collect();
@@ -164,7 +167,7 @@ pub async fn lfs_delete_lock(
         .await
         .unwrap_or_else(|_| Json(UnlockRequest::default()));
 
-    let result = handler::lfs_delete_lock(config, id, request.0).await;
+    let result = handler::lfs_delete_lock(config, id, request.0, user).await;
 
     match result {
         Ok(lock) => {
@@ -5,12 +5,30 @@
 //!
 
 mod api_service;
+mod audit;
+mod auth;
+pub mod background;
+mod chatops;
+pub mod daemon_server;
+mod events;
 mod git_protocol;
 pub mod https_server;
 // pub mod init;
 mod lfs;
+mod lifecycle;
 mod model;
+mod proxy;
+mod push_hooks;
+mod release;
+mod replica;
+mod review_notice;
+mod settings;
 pub mod ssh_server;
+mod tls;
+#[cfg(unix)]
+mod unix_socket;
+mod watch;
+mod web_ui;
 
 #[cfg(test)]
 mod tests {}
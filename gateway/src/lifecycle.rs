@@ -0,0 +1,378 @@
+//!
+//! Lifecycle policies keeping the database of long-lived monorepos small: the data of merge
+//! requests closed long ago is moved to the archive storage, and restored on demand. The
+//! branches merged or abandoned are removed according to the cleanup rules of their directory,
+//! one rule per line of the `MEGA_BRANCH_CLEANUP_FILE` file:
+//!
+//! ```text
+//! # directory       options
+//! /projects/**      merged grace_days=7
+//! /third_parts/**   merged stale_days=90 grace_days=14
+//! /projects/legacy  off
+//! ```
+//!
+//! A rule applies to the repositories below the paths its directory matches, `*` matching one
+//! path segment and `**` any number of them, and the last matching rule wins.
+//!
+//! The storage report, listing the largest blobs and the fastest-growing directories to move
+//! to LFS, is computed once a day. The directory of packs of `mega service gc --repack` is
+//! checked for fragmentation, and its small packs merged once it is fragmented.
+//!
+use std::env;
+use std::fs;
+use std::time::Duration;
+
+use chrono::{Months, Utc};
+use serde::Serialize;
+
+use ceres::branch_cleanup::{self, BranchCleanup, CleanupAction, CleanupRule};
+use ceres::fragmentation::{self, RepackPolicy, RepackScheduler};
+use ceres::storage_report::{self, ReportOptions};
+use common::errors::MegaError;
+use jupiter::context::Context;
+use venus::repo::Repo;
+
+use crate::auth::policy::{path_matches, segments};
+
+/// Archives once a day the merge requests closed more than `MEGA_MR_ARCHIVE_AFTER_MONTHS`
+/// ago, nothing is archived when the variable is unset or 0.
+pub fn spawn_mr_archive_task(context: Context) {
+    let months = env::var("MEGA_MR_ARCHIVE_AFTER_MONTHS")
+        .ok()
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(0);
+    if months == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60 * 24));
+        loop {
+            interval.tick().await;
+            archive_closed_mrs(&context, months).await;
+        }
+    });
+}
+
+/// Archives the closed merge requests not updated for `months`, a failed one is retried on
+/// the next run.
+pub async fn archive_closed_mrs(context: &Context, months: u32) {
+    let Some(before) = Utc::now()
+        .naive_utc()
+        .checked_sub_months(Months::new(months))
+    else {
+        return;
+    };
+    let storage = context.services.mega_storage.clone();
+    let mrs = match storage.get_mrs_to_archive(before).await {
+        Ok(mrs) => mrs,
+        Err(err) => {
+            tracing::error!("failed to list the merge requests to archive: {}", err);
+            return;
+        }
+    };
+    for mr in mrs {
+        match storage.archive_mr(&mr).await {
+            Ok(archive) => tracing::info!(
+                "archived merge request {}: {} commits, {} trees",
+                mr.id,
+                archive.commit_count,
+                archive.tree_count
+            ),
+            Err(err) => tracing::error!("failed to archive merge request {}: {}", mr.id, err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BranchCleanupConfig {
+    rules: Vec<(String, CleanupRule)>,
+}
+
+impl BranchCleanupConfig {
+    /// Parses the rules of `text`, blank lines and lines starting with `#` are skipped.
+    pub fn parse(text: &str) -> Result<Self, MegaError> {
+        let mut rules = Vec::new();
+        for (no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = parse_cleanup_rule(line).map_err(|err| {
+                MegaError::with_message(&format!(
+                    "invalid branch cleanup rule at line {}: {}",
+                    no + 1,
+                    err
+                ))
+            })?;
+            rules.push(rule);
+        }
+        Ok(BranchCleanupConfig { rules })
+    }
+
+    /// The rules of the `MEGA_BRANCH_CLEANUP_FILE` file, none when it is unset.
+    pub fn load() -> Result<Self, MegaError> {
+        match env::var("MEGA_BRANCH_CLEANUP_FILE") {
+            Ok(path) if !path.is_empty() => {
+                let text = fs::read_to_string(&path).map_err(|err| {
+                    MegaError::with_message(&format!(
+                        "failed to read branch cleanup file {}: {}",
+                        path, err
+                    ))
+                })?;
+                Self::parse(&text)
+            }
+            _ => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The rule of the repository at `repo_path`.
+    pub fn rule_for(&self, repo_path: &str) -> CleanupRule {
+        let path = segments(repo_path);
+        self.rules
+            .iter()
+            .rev()
+            .find(|(pattern, _)| path_matches(&segments(pattern), &path))
+            .map(|(_, rule)| *rule)
+            .unwrap_or_default()
+    }
+}
+
+fn parse_cleanup_rule(line: &str) -> Result<(String, CleanupRule), String> {
+    let mut fields = line.split_whitespace();
+    let directory = fields.next().unwrap_or_default();
+    if !directory.starts_with('/') {
+        return Err(format!("directory `{}` is not an absolute path", directory));
+    }
+    let mut rule = CleanupRule::default();
+    for option in fields {
+        let days = |value: &str| {
+            value
+                .parse::<u32>()
+                .map_err(|_| format!("invalid number of days in `{}`", option))
+        };
+        match option.split_once('=') {
+            None if option == "merged" => rule.merged = true,
+            None if option == "off" => rule = CleanupRule::default(),
+            Some(("stale_days", value)) => rule.stale_days = days(value)?,
+            Some(("grace_days", value)) => rule.grace_days = days(value)?,
+            _ => return Err(format!("unknown option `{}`", option)),
+        }
+    }
+    Ok((directory.to_owned(), rule))
+}
+
+#[derive(Serialize)]
+struct CleanupNotice<'a> {
+    event: &'static str,
+    #[serde(flatten)]
+    cleanup: &'a BranchCleanup,
+}
+
+/// Applies once a day the rules of `MEGA_BRANCH_CLEANUP_FILE` to the branches. The owners of
+/// the branches scheduled for deletion and of the deleted ones are notified through
+/// `MEGA_BRANCH_CLEANUP_WEBHOOK`. With `MEGA_BRANCH_CLEANUP_DRY_RUN` the job only logs what it
+/// would do.
+pub fn spawn_branch_cleanup_task(context: Context) {
+    let config = match BranchCleanupConfig::load() {
+        Ok(config) if !config.is_empty() => config,
+        Ok(_) => return,
+        Err(err) => {
+            tracing::error!("branch cleanup disabled: {}", err);
+            return;
+        }
+    };
+    let dry_run = env::var("MEGA_BRANCH_CLEANUP_DRY_RUN")
+        .ok()
+        .and_then(|value| value.parse::<bool>().ok())
+        .unwrap_or(false);
+    let webhook = env::var("MEGA_BRANCH_CLEANUP_WEBHOOK")
+        .ok()
+        .filter(|url| !url.is_empty());
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60 * 24));
+        loop {
+            interval.tick().await;
+            for cleanup in cleanup_branches(&context, &config, dry_run).await {
+                tracing::info!(
+                    "branch cleanup{}: {:?} {}{} ({}, owner {})",
+                    if dry_run { " (dry run)" } else { "" },
+                    cleanup.action,
+                    cleanup.repo_path,
+                    cleanup.ref_name,
+                    cleanup.reason.as_str(),
+                    cleanup.owner.as_deref().unwrap_or("unknown")
+                );
+                let notify = matches!(
+                    cleanup.action,
+                    CleanupAction::Notify | CleanupAction::Delete
+                );
+                if let Some(webhook) = webhook.as_ref().filter(|_| notify && !dry_run) {
+                    let notice = CleanupNotice {
+                        event: "branch_cleanup",
+                        cleanup: &cleanup,
+                    };
+                    if let Err(err) = client.post(webhook).json(&notice).send().await {
+                        tracing::error!("failed to deliver branch cleanup to {}: {}", webhook, err);
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Applies `config` to the monorepo and every imported repository, returns what was done. A
+/// repository failing is logged and skipped.
+pub async fn cleanup_branches(
+    context: &Context,
+    config: &BranchCleanupConfig,
+    dry_run: bool,
+) -> Vec<BranchCleanup> {
+    let mut repos = vec![(Repo::empty(), String::from("/"))];
+    match context.services.mega_storage.get_git_repos().await {
+        Ok(models) => repos.extend(models.into_iter().map(|m| {
+            let path = m.repo_path.clone();
+            (Repo::from(m), path)
+        })),
+        Err(err) => tracing::error!("failed to list the repositories: {}", err),
+    }
+    let now = Utc::now().naive_utc();
+    let mut report = Vec::new();
+    for (repo, path) in repos {
+        let rule = config.rule_for(&path);
+        match branch_cleanup::cleanup_branches(context, &repo, &path, &rule, dry_run, now).await {
+            Ok(cleanups) => report.extend(cleanups),
+            Err(err) => tracing::error!("failed to clean up the branches of {}: {}", path, err),
+        }
+    }
+    report
+}
+
+/// Computes the storage report every `MEGA_STORAGE_REPORT_HOURS`, over the growth of the last
+/// `MEGA_STORAGE_REPORT_WINDOW_DAYS`. No report is computed when the interval is 0.
+pub fn spawn_storage_report_task(context: Context) {
+    let hours = env::var("MEGA_STORAGE_REPORT_HOURS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(24);
+    if hours == 0 {
+        return;
+    }
+    let options = ReportOptions {
+        window_days: env::var("MEGA_STORAGE_REPORT_WINDOW_DAYS")
+            .ok()
+            .and_then(|value| value.parse::<u32>().ok())
+            .unwrap_or(storage_report::DEFAULT_WINDOW_DAYS),
+        ..ReportOptions::default()
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60 * hours));
+        loop {
+            interval.tick().await;
+            match storage_report::generate_and_save(&context, options).await {
+                Ok(report) => tracing::info!(
+                    "storage report: {} large blob(s), {} growing path(s)",
+                    report.largest_blobs.len(),
+                    report.growing_paths.len()
+                ),
+                Err(err) => tracing::error!("failed to compute the storage report: {}", err),
+            }
+        }
+    });
+}
+
+/// Checks every `MEGA_REPACK_CHECK_MINS` the fragmentation of the packs of
+/// `MEGA_REPACK_PACK_DIR` against the `MEGA_REPACK_*` thresholds, and merges the small packs
+/// once one is crossed. Nothing is checked when the directory is unset.
+pub fn spawn_repack_task(context: Context) {
+    let Some(pack_dir) = env::var("MEGA_REPACK_PACK_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+    else {
+        return;
+    };
+    let mins = env_or("MEGA_REPACK_CHECK_MINS", 60u64).max(1);
+    let policy = RepackPolicy {
+        max_packs: env_or("MEGA_REPACK_MAX_PACKS", fragmentation::DEFAULT_MAX_PACKS),
+        small_pack_objects: env_or(
+            "MEGA_REPACK_SMALL_PACK_OBJECTS",
+            fragmentation::DEFAULT_SMALL_PACK_OBJECTS,
+        ),
+        max_small_pack_ratio: env_or(
+            "MEGA_REPACK_MAX_SMALL_PACK_RATIO",
+            fragmentation::DEFAULT_MAX_SMALL_PACK_RATIO,
+        ),
+        min_delta_ratio: env_or(
+            "MEGA_REPACK_MIN_DELTA_RATIO",
+            fragmentation::DEFAULT_MIN_DELTA_RATIO,
+        ),
+    };
+    tokio::spawn(async move {
+        let mut scheduler = RepackScheduler::new(pack_dir.into(), policy);
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * mins));
+        loop {
+            interval.tick().await;
+            match scheduler.check(&context).await {
+                Ok(check) => match check.repack {
+                    Some((reason, after)) => tracing::info!(
+                        "repacked {} for {}: {}, now {}",
+                        scheduler.pack_dir.display(),
+                        reason,
+                        check.before,
+                        after
+                    ),
+                    None => tracing::debug!(
+                        "packs of {}: {}",
+                        scheduler.pack_dir.display(),
+                        check.before
+                    ),
+                },
+                Err(err) => {
+                    tracing::error!("failed to repack {}: {}", scheduler.pack_dir.display(), err)
+                }
+            }
+        }
+    });
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse::<T>().ok())
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use ceres::branch_cleanup::CleanupRule;
+
+    use super::BranchCleanupConfig;
+
+    #[test]
+    fn test_branch_cleanup_config() {
+        let config = BranchCleanupConfig::parse(
+            "# directory options
+            /projects/** merged grace_days=7
+            /third_parts/** merged stale_days=90 grace_days=14
+            /projects/legacy off",
+        )
+        .unwrap();
+        let merged = CleanupRule {
+            merged: true,
+            stale_days: 0,
+            grace_days: 7,
+        };
+        assert_eq!(config.rule_for("/projects/mega"), merged);
+        assert_eq!(config.rule_for("/projects/legacy"), CleanupRule::default());
+        assert_eq!(config.rule_for("/third_parts/rust").stale_days, 90);
+        assert!(!config.rule_for("/").is_enabled());
+
+        assert!(BranchCleanupConfig::parse("/projects stale_days=soon").is_err());
+        assert!(BranchCleanupConfig::parse("projects merged").is_err());
+        assert!(BranchCleanupConfig::parse("/projects delete").is_err());
+    }
+}
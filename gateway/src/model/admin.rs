@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+
+use callisto::{mega_ssh_key, mega_user};
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminUser {
+    pub name: String,
+    pub email: Option<String>,
+    pub has_token: bool,
+    // suspended by an admin or by the anomaly detection
+    pub disabled: bool,
+    pub created_at: String,
+}
+
+impl AdminUser {
+    pub fn new(value: mega_user::Model, disabled: bool) -> Self {
+        AdminUser {
+            name: value.name,
+            email: value.email,
+            has_token: value.token_hash.is_some(),
+            disabled,
+            created_at: value.created_at.and_utc().to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SshKey {
+    pub id: i64,
+    pub public_key: String,
+    pub created_at: String,
+}
+
+impl From<mega_ssh_key::Model> for SshKey {
+    fn from(value: mega_ssh_key::Model) -> Self {
+        SshKey {
+            id: value.id,
+            public_key: value.public_key,
+            created_at: value.created_at.and_utc().to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisableUser {
+    pub user_name: String,
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserRequest {
+    pub user_name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RevokeKeys {
+    pub user_name: String,
+    // every key of the user without one
+    pub key_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RevokedKeys {
+    pub user_name: String,
+    pub revoked: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExpiredSessions {
+    pub user_name: String,
+    pub expired_at: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RepoVisibility {
+    pub repo_path: String,
+    // served to anonymous clients by the git daemon
+    pub public: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BlameCacheStats {
+    pub entries: u64,
+    // 0 when the cache is disabled
+    pub max_entries: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClearBlameCache {
+    // every repository without one
+    pub repo_path: Option<String>,
+    // every file of the repository without one
+    pub path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClearedBlameCache {
+    pub removed: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StorageReportRequest {
+    // the defaults of the daily report when unset
+    pub limit: Option<usize>,
+    pub window_days: Option<u32>,
+    pub depth: Option<usize>,
+}
@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+
+use callisto::db_enums::OperationType;
+use callisto::{git_operation, user_suspension};
+
+#[derive(Serialize, Deserialize)]
+pub struct GitOperation {
+    pub id: i64,
+    pub repo_path: String,
+    pub operation: String,
+    pub user_name: Option<String>,
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub token_id: Option<String>,
+    pub refs: Vec<String>,
+    pub bytes_transferred: i64,
+    pub duration_ms: i64,
+    pub created_at: String,
+}
+
+impl From<git_operation::Model> for GitOperation {
+    fn from(value: git_operation::Model) -> Self {
+        let operation = match value.operation {
+            OperationType::Clone => "clone",
+            OperationType::Fetch => "fetch",
+            OperationType::Push => "push",
+        };
+        GitOperation {
+            id: value.id,
+            repo_path: value.repo_path,
+            operation: operation.to_owned(),
+            user_name: value.user_name,
+            client_ip: value.client_ip,
+            user_agent: value.user_agent,
+            token_id: value.token_id,
+            refs: value
+                .refs
+                .split(',')
+                .filter(|r| !r.is_empty())
+                .map(String::from)
+                .collect(),
+            bytes_transferred: value.bytes_transferred,
+            duration_ms: value.duration_ms,
+            created_at: value.created_at.and_utc().to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Suspension {
+    pub user_name: String,
+    /// The suspended token or SSH key, every access of the user is suspended when None.
+    pub token_id: Option<String>,
+    pub reason: String,
+    pub created_at: String,
+}
+
+impl From<user_suspension::Model> for Suspension {
+    fn from(value: user_suspension::Model) -> Self {
+        Suspension {
+            user_name: value.user_name,
+            token_id: value.token_id,
+            reason: value.reason,
+            created_at: value.created_at.and_utc().to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LiftSuspension {
+    pub user_name: String,
+}
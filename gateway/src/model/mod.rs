@@ -1,2 +1,10 @@
+pub mod admin;
+pub mod audit;
+pub mod mr;
 pub mod objects;
+pub mod pin;
 pub mod query;
+pub mod review;
+pub mod replace;
+pub mod settings;
+pub mod watch;
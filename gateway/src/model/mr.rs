@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+
+use callisto::mega_mr_archive;
+
+#[derive(Serialize, Deserialize)]
+pub struct MrArchive {
+    pub mr_id: i64,
+    pub location: String,
+    pub commit_count: i32,
+    pub tree_count: i32,
+    pub archived_at: String,
+}
+
+impl From<mega_mr_archive::Model> for MrArchive {
+    fn from(value: mega_mr_archive::Model) -> Self {
+        MrArchive {
+            mr_id: value.mr_id,
+            location: value.location,
+            commit_count: value.commit_count,
+            tree_count: value.tree_count,
+            archived_at: value.archived_at.and_utc().to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RestoreMr {
+    pub mr_id: i64,
+}
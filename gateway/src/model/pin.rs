@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+
+use callisto::mega_object_pin;
+
+#[derive(Serialize, Deserialize)]
+pub struct ObjectPin {
+    pub object_id: String,
+    // commit or tree
+    pub object_type: String,
+    pub reason: String,
+    pub created_at: String,
+}
+
+impl From<mega_object_pin::Model> for ObjectPin {
+    fn from(value: mega_object_pin::Model) -> Self {
+        ObjectPin {
+            object_id: value.object_id,
+            object_type: value.object_type,
+            reason: value.reason,
+            created_at: value.created_at.and_utc().to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PinRequest {
+    pub repo_path: String,
+    pub object_id: String,
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnpinRequest {
+    pub repo_path: String,
+    pub object_id: String,
+}
@@ -11,3 +11,179 @@ pub struct DirectoryQuery {
 fn default_path() -> String {
     "/".to_string()
 }
+
+fn default_branch() -> String {
+    "main".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OperationQuery {
+    pub user: Option<String>,
+    pub repo_path: Option<String>,
+    pub client_ip: Option<String>,
+    // one of clone, fetch or push
+    pub operation: Option<String>,
+    // RFC 3339 timestamps
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserQuery {
+    pub user: String,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PinQuery {
+    pub repo_path: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReviewQuery {
+    pub mr_id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompareQuery {
+    pub repo_path: String,
+    // commit ids, ref names or branch names
+    pub base: String,
+    pub head: String,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImpactQuery {
+    pub repo_path: String,
+    // commit ids, ref names or branch names
+    pub base: String,
+    pub head: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TreeCommitQuery {
+    pub repo_path: String,
+    // branch, tag or full ref name
+    #[serde(default = "default_ref")]
+    pub refs: String,
+    #[serde(default = "default_path")]
+    pub path: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlameQuery {
+    pub repo_path: String,
+    // commit id, branch, tag or full ref name
+    #[serde(default = "default_ref")]
+    pub refs: String,
+    // the file, relative to the repository
+    pub path: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ArchiveQuery {
+    pub repo_path: String,
+    // commit or tree id, branch, tag or full ref name
+    #[serde(default = "default_ref")]
+    pub refs: String,
+    // one of tar, tar.gz or zip
+    #[serde(default = "default_archive_format")]
+    pub format: String,
+    // prepended to the paths in the archive, e.g. mega-1.0/
+    pub prefix: Option<String>,
+    // only this directory of the tree
+    pub path: Option<String>,
+}
+
+fn default_archive_format() -> String {
+    "tar.gz".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub q: String,
+    // mr or issue, both when unset
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub state: Option<String>,
+    pub author: Option<String>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CodeSearchQuery {
+    #[serde(default)]
+    pub q: String,
+    // the monorepo when unset
+    pub repo_path: Option<String>,
+    #[serde(default = "default_branch")]
+    pub branch: String,
+    pub offset: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ActivityQuery {
+    pub user: String,
+    // id of the last item of the previous page
+    pub before: Option<i64>,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationQuery {
+    // id of the last item of the previous page
+    pub before: Option<i64>,
+    #[serde(default)]
+    pub unread: bool,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ContributionQuery {
+    // comma separated user names
+    pub users: String,
+    // YYYY-MM-DD, the year up to today when unset
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnalyticsQuery {
+    #[serde(default = "default_path")]
+    pub path: String,
+    // YYYY-MM-DD, the year up to today when unset
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CodeIndexQuery {
+    pub repo_path: String,
+    // commit id, branch, tag or full ref name
+    pub refs: String,
+    // scip or lsif
+    pub format: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CodeNavQuery {
+    pub repo_path: String,
+    #[serde(default = "default_ref")]
+    pub refs: String,
+    // relative to the repository, the position is zero-based like in LSP
+    pub path: String,
+    pub line: i32,
+    pub character: i32,
+}
+
+fn default_ref() -> String {
+    "main".to_string()
+}
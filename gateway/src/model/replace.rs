@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct ReplaceRequest {
+    pub repo_path: String,
+    pub object_id: String,
+    // the commit read in place of `object_id`
+    pub replacement: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoveReplacement {
+    pub repo_path: String,
+    pub object_id: String,
+}
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+
+use callisto::db_enums::ReviewVerdict;
+use callisto::{mega_mr_comment, mega_mr_review};
+use ceres::email_reply::InboundEmail;
+
+#[derive(Serialize, Deserialize)]
+pub struct MrComment {
+    pub id: i64,
+    pub user_name: String,
+    // false for a draft, only listed to its author
+    pub published: bool,
+    pub path: Option<String>,
+    pub line: Option<i32>,
+    pub body: String,
+    pub created_at: String,
+}
+
+impl From<mega_mr_comment::Model> for MrComment {
+    fn from(value: mega_mr_comment::Model) -> Self {
+        MrComment {
+            id: value.id,
+            user_name: value.user_name,
+            published: value.review_id.is_some(),
+            path: value.path,
+            line: value.line,
+            body: value.body,
+            created_at: value.created_at.and_utc().to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MrReview {
+    pub id: i64,
+    pub user_name: String,
+    pub verdict: ReviewVerdict,
+    pub summary: String,
+    pub created_at: String,
+}
+
+impl From<mega_mr_review::Model> for MrReview {
+    fn from(value: mega_mr_review::Model) -> Self {
+        MrReview {
+            id: value.id,
+            user_name: value.user_name,
+            verdict: value.verdict,
+            summary: value.summary,
+            created_at: value.created_at.and_utc().to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MrReviews {
+    pub reviews: Vec<MrReview>,
+    pub comments: Vec<MrComment>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DraftComment {
+    pub mr_id: i64,
+    // the file and line commented on, the whole merge request without them
+    pub path: Option<String>,
+    pub line: Option<i32>,
+    pub body: String,
+}
+
+/// A reply to a notification, posted by the webhook of the mail provider.
+#[derive(Serialize, Deserialize)]
+pub struct EmailReply {
+    pub from: String,
+    pub in_reply_to: Option<String>,
+    pub references: Option<String>,
+    pub text: String,
+}
+
+impl From<EmailReply> for InboundEmail {
+    fn from(value: EmailReply) -> Self {
+        InboundEmail {
+            from: value.from,
+            in_reply_to: value.in_reply_to,
+            references: value.references,
+            text: value.text,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoveComment {
+    pub id: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PublishReview {
+    pub mr_id: i64,
+    pub verdict: ReviewVerdict,
+    #[serde(default)]
+    pub summary: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PublishedReview {
+    pub review: MrReview,
+    // the drafts published with the review
+    pub comments: u64,
+}
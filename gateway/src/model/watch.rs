@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct WatchRequest {
+    pub repo_path: String,
+    // a branch name or a full ref name, every ref when unset
+    pub branch: Option<String>,
+    // a directory or a file, the whole tree when unset
+    pub path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoveWatch {
+    pub id: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReadNotifications {
+    pub ids: Vec<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReadCount {
+    pub read: u64,
+}
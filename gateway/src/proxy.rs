@@ -0,0 +1,184 @@
+//!
+//! Clients behind a reverse proxy. `MEGA_TRUSTED_PROXIES` lists the addresses and CIDR ranges of
+//! the proxies in front of mega, separated by commas. For a request coming from one of them the
+//! client is the last address of `X-Forwarded-For` that is not a trusted proxy itself, and the
+//! scheme the client used is taken from `X-Forwarded-Proto`. The headers of other peers are
+//! ignored, anyone can send them.
+//!
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+
+use axum::http::{header, HeaderMap};
+use ipnet::IpNet;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    /// Reads a list like `10.0.0.0/8, 192.168.1.10, ::1`, empty entries are skipped.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let nets = value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("invalid trusted proxy '{}'", entry))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(TrustedProxies { nets })
+    }
+
+    /// The proxies of `MEGA_TRUSTED_PROXIES`, none when it is not set.
+    pub fn from_env() -> Self {
+        let value = env::var("MEGA_TRUSTED_PROXIES").unwrap_or_default();
+        TrustedProxies::parse(&value).unwrap_or_else(|err| panic!("MEGA_TRUSTED_PROXIES: {}", err))
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.nets.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The address of the client of a request received from `peer`. `X-Forwarded-For` is read
+    /// from the right, each trusted proxy appends the address it received the request from.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer.to_canonical();
+        if !self.is_trusted(client) {
+            return client;
+        }
+        let hops: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        for hop in hops.into_iter().rev() {
+            // a hop that cannot be read is not trusted, nor anything left of it
+            let Some(ip) = parse_hop(hop) else {
+                break;
+            };
+            client = ip;
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// The URL the client used to reach mega through a trusted proxy, from `X-Forwarded-Proto`
+    /// and `Host`. None for a direct request.
+    pub fn forwarded_url(&self, peer: IpAddr, headers: &HeaderMap) -> Option<String> {
+        if !self.is_trusted(peer) {
+            return None;
+        }
+        let proto = headers
+            .get(X_FORWARDED_PROTO)?
+            .to_str()
+            .ok()?
+            .split(',')
+            .next()?
+            .trim()
+            .to_ascii_lowercase();
+        if proto != "http" && proto != "https" {
+            return None;
+        }
+        let host = headers.get(header::HOST)?.to_str().ok()?;
+        Some(format!("{}://{}", proto, host))
+    }
+}
+
+/// An address of `X-Forwarded-For`, with or without a port.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use axum::http::{header, HeaderMap};
+
+    use super::TrustedProxies;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn forwarded(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8, 192.168.1.10,,::1").unwrap();
+        assert!(proxies.is_trusted(ip("10.1.2.3")));
+        assert!(proxies.is_trusted(ip("192.168.1.10")));
+        assert!(!proxies.is_trusted(ip("192.168.1.11")));
+        assert!(proxies.is_trusted(ip("::1")));
+        assert!(proxies.is_trusted(ip("::ffff:10.0.0.1")));
+        assert!(TrustedProxies::parse("10.0.0.0/33").is_err());
+        assert!(TrustedProxies::parse("").unwrap().nets.is_empty());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8").unwrap();
+        let headers = forwarded(&["203.0.113.7, 10.0.0.2"]);
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &headers),
+            ip("203.0.113.7")
+        );
+        // the headers of an untrusted peer are ignored
+        assert_eq!(
+            proxies.client_ip(ip("198.51.100.1"), &headers),
+            ip("198.51.100.1")
+        );
+        // a client cannot hide behind an address it made up
+        let headers = forwarded(&["10.9.9.9, 198.51.100.1", "[2001:db8::1]:4711"]);
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &headers),
+            ip("2001:db8::1")
+        );
+        let headers = forwarded(&["1.2.3.4, 198.51.100.1, 10.0.0.3"]);
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &headers),
+            ip("198.51.100.1")
+        );
+        let headers = forwarded(&["unknown, 10.0.0.3"]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.3"));
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_forwarded_url() {
+        let proxies = TrustedProxies::parse("127.0.0.1").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "git.example.com".parse().unwrap());
+        assert_eq!(proxies.forwarded_url(ip("127.0.0.1"), &headers), None);
+        headers.insert("x-forwarded-proto", "HTTPS".parse().unwrap());
+        assert_eq!(
+            proxies.forwarded_url(ip("127.0.0.1"), &headers).as_deref(),
+            Some("https://git.example.com")
+        );
+        assert_eq!(proxies.forwarded_url(ip("10.0.0.1"), &headers), None);
+    }
+}
@@ -0,0 +1,290 @@
+//!
+//! The push hooks of the servers, see [`jupiter::hooks::PushHook`]:
+//!
+//! - the executables `pre-receive`, `update` and `post-receive` of `MEGA_PUSH_HOOKS_DIR`, run
+//!   as git runs its server hooks. `pre-receive` and `post-receive` read a
+//!   `<old-id> <new-id> <ref-name>` line per update on their standard input, `update` gets
+//!   the ref name, old and new ids as arguments. A non-zero exit status of `pre-receive`
+//!   refuses the push, one of `update` the update of its ref, what they print is shown to the
+//!   client. The push options are in `GIT_PUSH_OPTION_COUNT` and `GIT_PUSH_OPTION_<n>`, the
+//!   repository in `MEGA_REPO_PATH` and the user pushing in `MEGA_PUSHER`. A hook running
+//!   longer than `MEGA_PUSH_HOOK_TIMEOUT_SECS` is killed and refuses the push.
+//! - the commit message check: the first line of the message of every commit pushed to a
+//!   branch has to match the regular expression `MEGA_COMMIT_MESSAGE_PATTERN`.
+//!
+//! The objects are not on the disk of the server, a hook needing more than the ids reads them
+//! through the api.
+//!
+use std::env;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use regex::Regex;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use common::errors::MegaError;
+use jupiter::context::Context;
+use jupiter::hooks::{HookResult, Push, PushHook};
+use venus::internal::pack::reference::RefCommand;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+/// Commits refused listed at most by the commit message check.
+const MAX_LISTED_COMMITS: usize = 20;
+
+pub fn register_push_hooks(context: &Context) {
+    if let Some(hooks) = ExecutableHooks::from_env() {
+        context.push_hooks.register(Arc::new(hooks));
+    }
+    if let Some(check) = CommitMessageCheck::from_env() {
+        context.push_hooks.register(Arc::new(check));
+    }
+}
+
+pub struct ExecutableHooks {
+    dir: PathBuf,
+    timeout: Duration,
+}
+
+impl ExecutableHooks {
+    /// The hooks of `MEGA_PUSH_HOOKS_DIR`, None when it is unset.
+    pub fn from_env() -> Option<Self> {
+        let dir = env::var("MEGA_PUSH_HOOKS_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty())?;
+        let secs = env::var("MEGA_PUSH_HOOK_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .filter(|secs| *secs > 0)
+            .unwrap_or(DEFAULT_TIMEOUT_SECS);
+        Some(ExecutableHooks {
+            dir: PathBuf::from(dir),
+            timeout: Duration::from_secs(secs),
+        })
+    }
+
+    /// Runs the executable `name` of the directory, an absent one accepts the push.
+    async fn run(
+        &self,
+        name: &str,
+        args: &[&str],
+        push: &Push,
+        commands: &[RefCommand],
+    ) -> Result<HookResult, MegaError> {
+        let path = self.dir.join(name);
+        if !path.is_file() {
+            return Ok(HookResult::accept());
+        }
+        let mut command = Command::new(&path);
+        command
+            .args(args)
+            .current_dir(&self.dir)
+            .env("GIT_PUSH_OPTION_COUNT", push.push_options.len().to_string())
+            .env("MEGA_REPO_PATH", &push.repo_path)
+            .env("MEGA_PUSHER", push.pusher.as_deref().unwrap_or(""))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        for (index, option) in push.push_options.iter().enumerate() {
+            command.env(format!("GIT_PUSH_OPTION_{}", index), option);
+        }
+        let mut child = command.spawn()?;
+        let input = stdin_lines(commands);
+        let run = async {
+            if let Some(mut stdin) = child.stdin.take() {
+                // a hook not reading its input closes the pipe, which is not an error
+                let _ = stdin.write_all(input.as_bytes()).await;
+            }
+            child.wait_with_output().await
+        };
+        let output = tokio::time::timeout(self.timeout, run)
+            .await
+            .map_err(|_| {
+                MegaError::with_message(&format!(
+                    "{} did not finish in {}s",
+                    path.display(),
+                    self.timeout.as_secs()
+                ))
+            })??;
+        let messages = [&output.stdout, &output.stderr]
+            .into_iter()
+            .flat_map(|out| {
+                String::from_utf8_lossy(out)
+                    .lines()
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let refusal = (!output.status.success()).then(|| format!("{} hook declined", name));
+        Ok(HookResult { messages, refusal })
+    }
+}
+
+#[async_trait]
+impl PushHook for ExecutableHooks {
+    fn name(&self) -> &'static str {
+        "executable"
+    }
+
+    async fn pre_receive(&self, push: &Push) -> Result<HookResult, MegaError> {
+        self.run("pre-receive", &[], push, &push.commands).await
+    }
+
+    async fn update(&self, push: &Push, command: &RefCommand) -> Result<HookResult, MegaError> {
+        let args = [
+            command.ref_name.as_str(),
+            command.old_id.as_str(),
+            command.new_id.as_str(),
+        ];
+        self.run("update", &args, push, &[]).await
+    }
+
+    async fn post_receive(
+        &self,
+        push: &Push,
+        updated: &[RefCommand],
+    ) -> Result<HookResult, MegaError> {
+        self.run("post-receive", &[], push, updated).await
+    }
+}
+
+/// The standard input of `pre-receive` and `post-receive`.
+fn stdin_lines(commands: &[RefCommand]) -> String {
+    commands
+        .iter()
+        .map(|c| format!("{} {} {}\n", c.old_id, c.new_id, c.ref_name))
+        .collect()
+}
+
+pub struct CommitMessageCheck {
+    pattern: Regex,
+}
+
+impl CommitMessageCheck {
+    /// The check of `MEGA_COMMIT_MESSAGE_PATTERN`, None when it is unset or invalid.
+    pub fn from_env() -> Option<Self> {
+        let pattern = env::var("MEGA_COMMIT_MESSAGE_PATTERN")
+            .ok()
+            .filter(|pattern| !pattern.is_empty())?;
+        match Regex::new(&pattern) {
+            Ok(pattern) => Some(CommitMessageCheck { pattern }),
+            Err(err) => {
+                tracing::error!("commit message check disabled: {}", err);
+                None
+            }
+        }
+    }
+
+    /// The short id and first line of the commits of `command` whose first line does not
+    /// match, the oldest first.
+    fn offending(&self, push: &Push, command: &RefCommand) -> Vec<String> {
+        let mut commits = push.commits_of(command);
+        commits.sort_by_key(|commit| commit.committer.timestamp);
+        commits
+            .into_iter()
+            .filter_map(|commit| {
+                let subject = commit.message.trim().lines().next().unwrap_or_default();
+                if self.pattern.is_match(subject) {
+                    return None;
+                }
+                Some(format!("{} {}", &commit.id.to_plain_str()[..7], subject))
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl PushHook for CommitMessageCheck {
+    fn name(&self) -> &'static str {
+        "commit-message"
+    }
+
+    async fn update(&self, push: &Push, command: &RefCommand) -> Result<HookResult, MegaError> {
+        if !command.ref_name.starts_with("refs/heads/") {
+            return Ok(HookResult::accept());
+        }
+        let offending = self.offending(push, command);
+        if offending.is_empty() {
+            return Ok(HookResult::accept());
+        }
+        let mut reason = format!(
+            "{} commit message(s) not matching {}",
+            offending.len(),
+            self.pattern.as_str()
+        );
+        for line in offending.iter().take(MAX_LISTED_COMMITS) {
+            reason.push('\n');
+            reason.push_str(line);
+        }
+        Ok(HookResult::refuse(&reason))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use jupiter::hooks::Push;
+    use venus::internal::object::commit::Commit;
+    use venus::internal::pack::reference::RefCommand;
+    use venus::repo::Repo;
+    use venus::test_support::{self, id};
+
+    use super::{stdin_lines, CommitMessageCheck};
+
+    fn commit(n: u8, parents: &[u8], message: &str) -> Commit {
+        Commit {
+            message: message.to_owned(),
+            ..test_support::commit(n, parents)
+        }
+    }
+
+    #[test]
+    fn test_stdin_lines() {
+        let command = RefCommand::new(
+            id(1).to_plain_str(),
+            id(2).to_plain_str(),
+            String::from("refs/heads/main"),
+        );
+        assert_eq!(
+            stdin_lines(&[command]),
+            format!(
+                "{} {} refs/heads/main\n",
+                id(1).to_plain_str(),
+                id(2).to_plain_str()
+            )
+        );
+    }
+
+    #[test]
+    fn test_commit_message_check() {
+        let check = CommitMessageCheck {
+            pattern: Regex::new(r"^(feat|fix|docs)(\(.+\))?: .+").unwrap(),
+        };
+        let push = Push {
+            repo: Repo::empty(),
+            repo_path: String::from("/"),
+            pusher: None,
+            commands: vec![],
+            push_options: vec![],
+            commits: vec![
+                commit(2, &[1], "\nfeat(ceres): push hooks\n\nbody"),
+                commit(3, &[2], "wip"),
+                commit(4, &[3], "fix: typo"),
+            ],
+        };
+        let command = RefCommand::new(
+            id(1).to_plain_str(),
+            id(4).to_plain_str(),
+            String::from("refs/heads/main"),
+        );
+        assert_eq!(
+            check.offending(&push, &command),
+            vec![format!("{} wip", &id(3).to_plain_str()[..7])]
+        );
+    }
+}
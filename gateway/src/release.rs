@@ -0,0 +1,73 @@
+//!
+//! Tagging of the versions changed by the ref updates, see [`ceres::auto_tag`]. Each release
+//! tagged is posted as JSON to `MEGA_RELEASE_WEBHOOK` when it is set, e.g. for a build system
+//! to publish the version.
+//!
+use std::env;
+
+use async_trait::async_trait;
+use serde::Serialize;
+
+use ceres::auto_tag::{self, Release};
+use common::errors::MegaError;
+use jupiter::context::Context;
+use jupiter::events::{RefUpdateEvent, RefUpdateObserver};
+
+#[derive(Serialize)]
+struct ReleaseNotice<'a> {
+    event: &'static str,
+    #[serde(flatten)]
+    release: &'a Release,
+}
+
+pub struct ReleaseTagger {
+    client: reqwest::Client,
+    webhook: Option<String>,
+}
+
+impl ReleaseTagger {
+    pub fn from_env() -> Self {
+        ReleaseTagger {
+            client: reqwest::Client::new(),
+            webhook: env::var("MEGA_RELEASE_WEBHOOK")
+                .ok()
+                .filter(|url| !url.is_empty()),
+        }
+    }
+}
+
+#[async_trait]
+impl RefUpdateObserver for ReleaseTagger {
+    fn name(&self) -> &'static str {
+        "auto_tag"
+    }
+
+    /// The tags are created before the releases are posted, a webhook failing to take one is
+    /// logged and the tag is kept.
+    async fn on_ref_update(
+        &self,
+        context: &Context,
+        event: &RefUpdateEvent,
+    ) -> Result<(), MegaError> {
+        let releases = auto_tag::tag_releases(context, event).await?;
+        let Some(webhook) = &self.webhook else {
+            return Ok(());
+        };
+        for release in &releases {
+            let notice = ReleaseNotice {
+                event: "release",
+                release,
+            };
+            let res = self.client.post(webhook).json(&notice).send().await;
+            if let Err(err) = res.and_then(|res| res.error_for_status()) {
+                tracing::error!(
+                    "failed to deliver the release {} to {}: {}",
+                    release.tag_name,
+                    webhook,
+                    err
+                );
+            }
+        }
+        Ok(())
+    }
+}
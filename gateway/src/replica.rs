@@ -0,0 +1,121 @@
+//!
+//! Streaming of the ref updates to the replicas of the server, e.g. read-only mirrors in other
+//! regions. Each ref update is posted as JSON to every URL of `MEGA_REPLICA_URLS`, separated
+//! by commas, with `MEGA_REPLICA_TOKEN` as bearer token when it is set. A replica failing to
+//! take an update holds up the stream: the update is delivered again to every replica, which
+//! applies the updates in order and skips those it already applied by their id.
+//!
+use std::env;
+
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use common::errors::MegaError;
+use jupiter::context::Context;
+use jupiter::events::{RefUpdateEvent, RefUpdateObserver};
+
+#[derive(Serialize)]
+struct RefUpdateNotice<'a> {
+    event: &'static str,
+    id: i64,
+    repo_path: &'a str,
+    ref_name: &'a str,
+    old_id: &'a str,
+    new_id: &'a str,
+    pusher: Option<&'a str>,
+    created_at: NaiveDateTime,
+}
+
+pub struct ReplicaStreamer {
+    client: reqwest::Client,
+    replicas: Vec<String>,
+    token: Option<String>,
+}
+
+impl ReplicaStreamer {
+    /// The streamer of the replicas configured, None if there is none.
+    pub fn from_env() -> Option<Self> {
+        let var = |name| env::var(name).ok().filter(|value: &String| !value.is_empty());
+        let urls = var("MEGA_REPLICA_URLS")?;
+        ReplicaStreamer::new(&urls, var("MEGA_REPLICA_TOKEN"))
+    }
+
+    fn new(urls: &str, token: Option<String>) -> Option<Self> {
+        let replicas: Vec<String> = urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if replicas.is_empty() {
+            return None;
+        }
+        Some(ReplicaStreamer {
+            client: reqwest::Client::new(),
+            replicas,
+            token,
+        })
+    }
+}
+
+#[async_trait]
+impl RefUpdateObserver for ReplicaStreamer {
+    fn name(&self) -> &'static str {
+        "replica_stream"
+    }
+
+    async fn on_ref_update(
+        &self,
+        _context: &Context,
+        event: &RefUpdateEvent,
+    ) -> Result<(), MegaError> {
+        let notice = RefUpdateNotice {
+            event: "ref_update",
+            id: event.id,
+            repo_path: &event.repo_path,
+            ref_name: &event.ref_name,
+            old_id: &event.old_id,
+            new_id: &event.new_id,
+            pusher: event.pusher.as_deref(),
+            created_at: event.created_at,
+        };
+        for replica in &self.replicas {
+            let mut request = self.client.post(replica).json(&notice);
+            if let Some(token) = &self.token {
+                request = request.bearer_auth(token);
+            }
+            let res = request.send().await;
+            res.and_then(|res| res.error_for_status()).map_err(|err| {
+                MegaError::with_message(&format!(
+                    "failed to stream the ref update {} to {}: {}",
+                    event.id, replica, err
+                ))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplicaStreamer;
+
+    #[test]
+    fn test_replicas() {
+        assert!(ReplicaStreamer::new("", None).is_none());
+        assert!(ReplicaStreamer::new(" , ", None).is_none());
+        let streamer = ReplicaStreamer::new(
+            "https://eu.mega.dev/replica/events, https://us.mega.dev/replica/events,",
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            streamer.replicas,
+            vec![
+                "https://eu.mega.dev/replica/events",
+                "https://us.mega.dev/replica/events"
+            ]
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//!
+//! Notifications of the comments published on a merge request. Each one is posted as JSON to
+//! `MEGA_REVIEW_WEBHOOK`, for a mailer to send it to the participants of the merge request with
+//! the `Message-ID` and `References` headers it carries. With `MEGA_EMAIL_REPLY_SECRET` set,
+//! they are made by [`email_reply::message_id`] so that the replies to the mail are added to
+//! the merge request, see [`ceres::email_reply`].
+//!
+use std::env;
+
+use serde::Serialize;
+
+use callisto::mega_mr_comment;
+use ceres::email_reply::{self, ReplyTarget};
+
+use crate::model::review::MrComment;
+
+/// The domain of the message ids when `MEGA_EMAIL_REPLY_DOMAIN` is not set.
+const DEFAULT_REPLY_DOMAIN: &str = "mega.localhost";
+
+#[derive(Serialize)]
+struct ReviewNotice {
+    event: &'static str,
+    mr_id: i64,
+    #[serde(flatten)]
+    comment: MrComment,
+    /// The `Message-ID` of the mail, None leaves it to the mailer.
+    message_id: Option<String>,
+    /// The `References` of the mail, the thread of the merge request.
+    references: Option<String>,
+}
+
+pub struct ReviewNotifier {
+    client: reqwest::Client,
+    webhook: Option<String>,
+    /// The secret and the domain of the message ids.
+    reply_ids: Option<(String, String)>,
+}
+
+impl ReviewNotifier {
+    pub fn from_env() -> Self {
+        let var = |name| env::var(name).ok().filter(|value| !value.is_empty());
+        let reply_ids = var("MEGA_EMAIL_REPLY_SECRET").map(|secret| {
+            let domain = var("MEGA_EMAIL_REPLY_DOMAIN")
+                .unwrap_or_else(|| String::from(DEFAULT_REPLY_DOMAIN));
+            (secret, domain)
+        });
+        ReviewNotifier {
+            client: reqwest::Client::new(),
+            webhook: var("MEGA_REVIEW_WEBHOOK"),
+            reply_ids,
+        }
+    }
+
+    /// Posts the notices of `comments` in a task of its own, a webhook failing to take one is
+    /// logged and the comments stay readable through the API.
+    pub fn notify(&self, comments: Vec<mega_mr_comment::Model>) {
+        let Some(webhook) = self.webhook.clone() else {
+            return;
+        };
+        let notices: Vec<ReviewNotice> = comments
+            .into_iter()
+            .map(|comment| self.notice(comment))
+            .collect();
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            for notice in &notices {
+                let res = client.post(&webhook).json(notice).send().await;
+                if let Err(err) = res.and_then(|res| res.error_for_status()) {
+                    tracing::error!(
+                        "failed to deliver a review notification to {}: {}",
+                        webhook,
+                        err
+                    );
+                }
+            }
+        });
+    }
+
+    fn notice(&self, comment: mega_mr_comment::Model) -> ReviewNotice {
+        let mr_id = comment.mr_id;
+        let (message_id, references) = match &self.reply_ids {
+            Some((secret, domain)) => {
+                let id = |comment_id| {
+                    email_reply::message_id(secret, domain, ReplyTarget { mr_id, comment_id })
+                };
+                (Some(id(Some(comment.id))), Some(id(None)))
+            }
+            None => (None, None),
+        };
+        ReviewNotice {
+            event: "mr_comment",
+            mr_id,
+            comment: comment.into(),
+            message_id,
+            references,
+        }
+    }
+}
@@ -0,0 +1,30 @@
+//!
+//! Propagation of the runtime settings changed through the admin API. A change applies at once
+//! to the server that received it, the other servers pick it up from the database.
+//!
+use std::env;
+use std::time::Duration;
+
+use jupiter::context::Context;
+
+/// Reloads the runtime settings from the database every `MEGA_SETTINGS_RELOAD_SECS`, 30 by
+/// default, 0 disables the reload.
+pub fn spawn_reload_task(context: Context) {
+    let secs = env::var("MEGA_SETTINGS_RELOAD_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(30);
+    if secs == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            let storage = &context.services.setting_storage;
+            if let Err(err) = context.settings.reload(storage).await {
+                tracing::error!("failed to reload the runtime settings: {}", err);
+            }
+        }
+    });
+}
@@ -18,10 +18,13 @@ use ed25519_dalek::pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey};
 use ed25519_dalek::SigningKey;
 use russh_keys::key::KeyPair;
 
+use ceres::protocol::v2::ProtocolVersion;
 use common::model::CommonOptions;
 use jupiter::context::Context;
 
-use crate::git_protocol::ssh::SshServer;
+use crate::auth::policy;
+use crate::git_protocol::ssh::{SshServer, DEFAULT_SHARED_USER};
+use crate::{auth, events, push_hooks, settings};
 
 #[derive(Args, Clone, Debug)]
 pub struct SshOptions {
@@ -73,6 +76,13 @@ pub async fn start_server(command: &SshOptions) {
             },
     } = command;
     let context = Context::new(data_source).await;
+    push_hooks::register_push_hooks(&context);
+    settings::spawn_reload_task(context.clone());
+    events::spawn_delivery_task(context.clone());
+    let auth = auth::from_env(&context);
+    let policy = policy::load(&context, auth.is_some())
+        .await
+        .expect("failed to load the authorization policy");
     let sh = SshServer {
         client_pubkey,
         clients: Arc::new(Mutex::new(HashMap::new())),
@@ -80,6 +90,15 @@ pub async fn start_server(command: &SshOptions) {
         context,
         pack_protocol: None,
         data_combined: Vec::new(),
+        receive_pipe: None,
+        client_addr: None,
+        user: None,
+        token_id: None,
+        auth,
+        policy: Arc::new(policy),
+        shared_user: env::var("MEGA_SSH_USER").unwrap_or_else(|_| DEFAULT_SHARED_USER.to_owned()),
+        version: ProtocolVersion::V0,
+        rounds_answered: 0,
     };
     let server_url = format!("{}:{}", host, ssh_port);
     let addr = SocketAddr::from_str(&server_url).unwrap();
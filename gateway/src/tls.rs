@@ -0,0 +1,402 @@
+//!
+//! Native TLS for the HTTP server, so a small deployment needs no proxy in front of it. Every
+//! TLS listener has its own certificate, key and client authentication: with a client CA the
+//! clients present a certificate signed by it, always (`required`) or when they have one
+//! (`optional`). The files are read again when they change, a renewed certificate is used for
+//! the next connections without a restart.
+//!
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use axum::extract::ConnectInfo;
+use axum::{Extension, Router};
+use clap::ValueEnum;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Whether the clients of a listener authenticate with a certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ClientAuth {
+    #[default]
+    None,
+    /// A client may present a certificate, it is then verified against the client CA.
+    Optional,
+    /// Every client presents a certificate signed by the client CA.
+    Required,
+}
+
+/// The TLS settings of a listener.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// CA certificates the client certificates are verified against.
+    pub client_ca_path: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+}
+
+impl TlsConfig {
+    fn paths(&self) -> Vec<&PathBuf> {
+        let mut paths = vec![&self.cert_path, &self.key_path];
+        paths.extend(&self.client_ca_path);
+        paths
+    }
+
+    /// Reads the certificates and the key into the configuration of the server.
+    pub fn server_config(&self) -> Result<ServerConfig, String> {
+        let provider = Arc::new(ring::default_provider());
+        let certs = read_certs(&self.cert_path)?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|err| format!("{}: {}", self.key_path.display(), err))?;
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| err.to_string())?;
+        let builder = match (self.client_auth, &self.client_ca_path) {
+            (ClientAuth::None, _) => builder.with_no_client_auth(),
+            (_, None) => return Err(String::from("client authentication needs a client CA")),
+            (client_auth, Some(ca_path)) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(ca_path)? {
+                    roots
+                        .add(cert)
+                        .map_err(|err| format!("{}: {}", ca_path.display(), err))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider);
+                let verifier = match client_auth {
+                    ClientAuth::Optional => verifier.allow_unauthenticated(),
+                    _ => verifier,
+                };
+                builder.with_client_cert_verifier(verifier.build().map_err(|err| err.to_string())?)
+            }
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|err| format!("{}: {}", self.cert_path.display(), err))?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+fn read_certs(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificate", path.display()));
+    }
+    Ok(certs)
+}
+
+/// The acceptor of a listener, rebuilt when its files change.
+pub struct ReloadingAcceptor {
+    config: TlsConfig,
+    acceptor: RwLock<TlsAcceptor>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl ReloadingAcceptor {
+    pub fn new(config: TlsConfig) -> Result<Self, String> {
+        let acceptor = TlsAcceptor::from(Arc::new(config.server_config()?));
+        Ok(ReloadingAcceptor {
+            modified: Mutex::new(modified_times(&config)),
+            acceptor: RwLock::new(acceptor),
+            config,
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// Reads the files again when one of them changed, returns whether the acceptor was
+    /// replaced. Files that cannot be read, e.g. while they are written, leave the current one.
+    pub fn reload_if_changed(&self) -> bool {
+        let times = modified_times(&self.config);
+        let mut modified = self.modified.lock().unwrap();
+        if *modified == times {
+            return false;
+        }
+        match self.config.server_config() {
+            Ok(config) => {
+                *self.acceptor.write().unwrap() = TlsAcceptor::from(Arc::new(config));
+                *modified = times;
+                tracing::info!(
+                    "reloaded the certificate {}",
+                    self.config.cert_path.display()
+                );
+                true
+            }
+            Err(err) => {
+                tracing::error!("failed to reload the certificate: {}", err);
+                false
+            }
+        }
+    }
+}
+
+fn modified_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    config
+        .paths()
+        .into_iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Checks the files of `acceptor` every `MEGA_TLS_RELOAD_SECS`, 60 by default, 0 disables the
+/// reload.
+pub fn spawn_reload_task(acceptor: Arc<ReloadingAcceptor>) {
+    let secs = env::var("MEGA_TLS_RELOAD_SECS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(60);
+    if secs == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            acceptor.reload_if_changed();
+        }
+    });
+}
+
+/// Serves `app` over TLS on `listener`. The peer address is passed to the handlers like
+/// `into_make_service_with_connect_info` does, a failed handshake only drops its connection.
+pub async fn serve(listener: TcpListener, acceptor: Arc<ReloadingAcceptor>, app: Router) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // e.g. out of file descriptors, the next connections may succeed
+                tracing::error!("failed to accept a connection: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let tls = acceptor.acceptor();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream = match tls.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", addr, err);
+                    return;
+                }
+            };
+            serve_connection(stream, addr, app).await;
+        });
+    }
+}
+
+/// Serves `app` on an accepted connection, HTTP/1 or HTTP/2, as coming from `addr`.
+pub(crate) async fn serve_connection<S>(stream: S, addr: SocketAddr, app: Router)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = TowerToHyperService::new(app.layer(Extension(ConnectInfo::<SocketAddr>(addr))));
+    if let Err(err) = auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+        .await
+    {
+        tracing::debug!("connection with {} closed: {}", addr, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use rustls_pki_types::pem::PemObject;
+    use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    use super::{ClientAuth, ReloadingAcceptor, TlsConfig};
+
+    struct Pki {
+        dir: PathBuf,
+        ca: Certificate,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let dir = PathBuf::from("/tmp/.mega_tls").join(uuid_like());
+            fs::create_dir_all(&dir).unwrap();
+            let mut params = CertificateParams::new(vec![]);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = Certificate::from_params(params).unwrap();
+            fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+            Pki { dir, ca }
+        }
+
+        /// Writes a certificate for `name` signed by the CA, returns the paths of it and its key.
+        fn issue(&self, name: &str) -> (PathBuf, PathBuf) {
+            let cert =
+                Certificate::from_params(CertificateParams::new(vec![name.to_owned()])).unwrap();
+            let (cert_path, key_path) = (
+                self.dir.join(format!("{}.pem", name)),
+                self.dir.join(format!("{}.key", name)),
+            );
+            let pem = cert.serialize_pem_with_signer(&self.ca).unwrap();
+            fs::write(&cert_path, pem).unwrap();
+            fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+            (cert_path, key_path)
+        }
+
+        fn config(&self, client_auth: ClientAuth) -> TlsConfig {
+            let (cert_path, key_path) = self.issue("localhost");
+            TlsConfig {
+                cert_path,
+                key_path,
+                client_ca_path: Some(self.dir.join("ca.pem")),
+                client_auth,
+            }
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn uuid_like() -> String {
+        format!(
+            "{}-{:?}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        )
+    }
+
+    fn client_config(ca: &Path, identity: Option<(PathBuf, PathBuf)>) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(ca).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        match identity {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(
+                    CertificateDer::pem_file_iter(cert)
+                        .unwrap()
+                        .map(Result::unwrap)
+                        .collect(),
+                    PrivateKeyDer::from_pem_file(key).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        }
+    }
+
+    /// Connects to an echo server behind `acceptor`, returns whether a message went through.
+    async fn exchange(acceptor: &ReloadingAcceptor, client: ClientConfig) -> bool {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tls = acceptor.acceptor();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = tls.accept(stream).await.ok()?;
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.ok()?;
+            stream.write_all(&buf).await.ok()
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let result = async {
+            let mut stream = TlsConnector::from(Arc::new(client))
+                .connect(name, stream)
+                .await
+                .ok()?;
+            stream.write_all(b"ping").await.ok()?;
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.ok()?;
+            Some(buf)
+        }
+        .await;
+        let _ = server.await;
+        result == Some(*b"ping")
+    }
+
+    #[tokio::test]
+    async fn test_client_auth() {
+        let pki = Pki::new();
+        let ca = pki.dir.join("ca.pem");
+        let identity = pki.issue("ci-runner");
+
+        let required = ReloadingAcceptor::new(pki.config(ClientAuth::Required)).unwrap();
+        assert!(exchange(&required, client_config(&ca, Some(identity.clone()))).await);
+        assert!(!exchange(&required, client_config(&ca, None)).await);
+
+        let optional = ReloadingAcceptor::new(pki.config(ClientAuth::Optional)).unwrap();
+        assert!(exchange(&optional, client_config(&ca, None)).await);
+        assert!(exchange(&optional, client_config(&ca, Some(identity))).await);
+
+        // a certificate of another CA is refused
+        let other = Pki::new();
+        let stranger = other.issue("stranger");
+        assert!(!exchange(&required, client_config(&ca, Some(stranger))).await);
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let pki = Pki::new();
+        let mut config = pki.config(ClientAuth::Required);
+        config.client_ca_path = None;
+        assert!(config.server_config().is_err());
+
+        let mut config = pki.config(ClientAuth::None);
+        config.key_path = pki.dir.join("missing.key");
+        assert!(config.server_config().is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let pki = Pki::new();
+        let config = pki.config(ClientAuth::None);
+        let acceptor = ReloadingAcceptor::new(config.clone()).unwrap();
+        assert!(!acceptor.reload_if_changed());
+
+        // a half written file keeps the current certificate
+        fs::write(&config.cert_path, "-----BEGIN CERTIFICATE-----\n").unwrap();
+        assert!(!acceptor.reload_if_changed());
+
+        let renewed =
+            Certificate::from_params(CertificateParams::new(vec![String::from("localhost")]))
+                .unwrap();
+        fs::write(
+            &config.cert_path,
+            renewed.serialize_pem_with_signer(&pki.ca).unwrap(),
+        )
+        .unwrap();
+        fs::write(&config.key_path, renewed.serialize_private_key_pem()).unwrap();
+        assert!(acceptor.reload_if_changed());
+        assert!(!acceptor.reload_if_changed());
+    }
+}
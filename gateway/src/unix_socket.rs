@@ -0,0 +1,147 @@
+//!
+//! The HTTP server on a unix domain socket, for a sidecar or the local CLI, without a TCP port.
+//! Who may connect is decided by the permissions of the socket file. A peer has no IP address,
+//! the handlers see it as `127.0.0.1`: a proxy on the socket is trusted like one on the loopback.
+//!
+use std::fs::{self, Permissions};
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::time::Duration;
+
+use axum::Router;
+use tokio::net::UnixListener;
+
+use crate::tls;
+
+/// Reads the permissions of the socket file in octal, like `chmod`: `660`, `0o600`.
+pub fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.strip_prefix("0o").unwrap_or(value);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!(
+            "invalid file mode '{}', expected octal like 660",
+            value
+        )),
+    }
+}
+
+/// Binds the socket at `path` with the permissions `mode`. A socket left by a previous run is
+/// replaced, one still accepting connections or any other file is not.
+pub fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Ok(_) => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("{} is in use by another server", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Serves `app` on `listener`, HTTP/1 or HTTP/2 without TLS.
+pub async fn serve(listener: UnixListener, app: Router) {
+    let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                tracing::error!("failed to accept a connection: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        tokio::spawn(tls::serve_connection(stream, peer, app.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    use axum::extract::ConnectInfo;
+    use axum::routing::get;
+    use axum::Router;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    use super::{bind, parse_mode, serve};
+
+    fn temp_dir() -> PathBuf {
+        let dir = PathBuf::from("/tmp/.mega_unix").join(format!(
+            "{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0o600"), Ok(0o600));
+        assert!(parse_mode("1777").is_err());
+        assert!(parse_mode("689").is_err());
+        assert!(parse_mode("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let dir = temp_dir();
+        let path = dir.join("mega.sock");
+        let listener = bind(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let app = Router::new().route(
+            "/peer",
+            get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { addr.ip().to_string() }),
+        );
+        let server = tokio::spawn(serve(listener, app));
+        // the socket is in use, it is not taken over
+        assert!(bind(&path, 0o600).is_err());
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /peer HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("127.0.0.1"));
+
+        // the socket of a stopped server is replaced
+        server.abort();
+        let _ = server.await;
+        assert!(bind(&path, 0o600).is_ok());
+
+        let file = dir.join("data");
+        fs::write(&file, "keep").unwrap();
+        assert!(bind(&file, 0o600).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+//!
+//! Delivery of the notifications of the watches, see [`ceres::watch`]. The notifications of a
+//! ref update are recorded for their users, and each new one is posted as JSON to
+//! `MEGA_WATCH_WEBHOOK` when it is set, for a mailer or a chat bot to forward it to its user.
+//!
+use std::env;
+
+use async_trait::async_trait;
+use serde::Serialize;
+
+use ceres::watch::{self, WatchNotification};
+use common::errors::MegaError;
+use jupiter::context::Context;
+use jupiter::events::{RefUpdateEvent, RefUpdateObserver};
+
+#[derive(Serialize)]
+struct WatchNotice<'a> {
+    event: &'static str,
+    #[serde(flatten)]
+    notification: &'a WatchNotification,
+}
+
+pub struct WatchNotifier {
+    client: reqwest::Client,
+    webhook: Option<String>,
+}
+
+impl WatchNotifier {
+    pub fn from_env() -> Self {
+        WatchNotifier {
+            client: reqwest::Client::new(),
+            webhook: env::var("MEGA_WATCH_WEBHOOK")
+                .ok()
+                .filter(|url| !url.is_empty()),
+        }
+    }
+}
+
+#[async_trait]
+impl RefUpdateObserver for WatchNotifier {
+    fn name(&self) -> &'static str {
+        "ref_watch"
+    }
+
+    /// The notifications are recorded before they are posted, a webhook failing to take one
+    /// is logged and the notification stays readable through the API.
+    async fn on_ref_update(
+        &self,
+        context: &Context,
+        event: &RefUpdateEvent,
+    ) -> Result<(), MegaError> {
+        let notifications = watch::notify_watchers(context, event).await?;
+        let Some(webhook) = &self.webhook else {
+            return Ok(());
+        };
+        for notification in &notifications {
+            let notice = WatchNotice {
+                event: "ref_watch",
+                notification,
+            };
+            let res = self.client.post(webhook).json(&notice).send().await;
+            if let Err(err) = res.and_then(|res| res.error_for_status()) {
+                tracing::error!(
+                    "failed to deliver a watch notification to {}: {}",
+                    webhook,
+                    err
+                );
+            }
+        }
+        Ok(())
+    }
+}
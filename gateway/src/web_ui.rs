@@ -0,0 +1,186 @@
+//!
+//! The web UI embedded in the binary, built with the `web-ui` feature from the files of
+//! `gateway/web-ui` (the static export of the single-page app) and served under `/ui` with
+//! `--web-ui`. A small install is then one binary, without a frontend deployed beside it.
+//!
+//! The hashed assets of the build are cached for good, the pages are revalidated on each load so
+//! an upgrade of the binary is picked up at once. `/ui/config.json` tells the app where the API
+//! is, `/api/v1` on the same origin unless `MEGA_WEB_UI_API_BASE` is set.
+//!
+use std::env;
+
+use axum::body::Body;
+use axum::extract::Path;
+use axum::http::{header, StatusCode};
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use include_dir::Dir;
+use serde_json::json;
+
+use crate::https_server::AppState;
+
+#[cfg(feature = "web-ui")]
+static ASSETS: Dir = include_dir::include_dir!("$CARGO_MANIFEST_DIR/web-ui");
+#[cfg(not(feature = "web-ui"))]
+static ASSETS: Dir = Dir::new("", &[]);
+
+/// The routes of the web UI, None if the binary was built without it.
+pub fn routers() -> Option<Router<AppState>> {
+    if !cfg!(feature = "web-ui") {
+        tracing::warn!("--web-ui is ignored, this binary was built without the web-ui feature");
+        return None;
+    }
+    // not nested, a nested router does not take `/ui/`
+    let index = get(|| async { asset(&ASSETS, "") });
+    Some(
+        Router::new()
+            .route("/ui", index.clone())
+            .route("/ui/", index)
+            .route("/ui/config.json", get(config))
+            .route(
+                "/ui/*path",
+                get(|Path(path): Path<String>| async move { asset(&ASSETS, &path) }),
+            ),
+    )
+}
+
+async fn config() -> Response {
+    let api_base = env::var("MEGA_WEB_UI_API_BASE")
+        .ok()
+        .filter(|base| !base.is_empty())
+        .unwrap_or_else(|| String::from("/api/v1"));
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from(json!({ "api_base": api_base }).to_string()))
+        .unwrap()
+}
+
+/// The file at `path` of `dir`. The routes of the app have no file of their own, a path without
+/// an extension falls back to its `index.html` for the app to route it in the browser.
+fn asset(dir: &Dir<'static>, path: &str) -> Response {
+    let path = path.trim_matches('/');
+    let file = if path.split('/').any(|segment| segment.starts_with('.')) {
+        None
+    } else if path.is_empty() {
+        dir.get_file("index.html")
+    } else {
+        dir.get_file(path)
+            .or_else(|| dir.get_file(format!("{}.html", path)))
+            .or_else(|| dir.get_file(format!("{}/index.html", path)))
+            .or_else(|| {
+                let last = path.rsplit('/').next().unwrap_or_default();
+                (!last.contains('.'))
+                    .then(|| dir.get_file("index.html"))
+                    .flatten()
+            })
+    };
+    let Some(file) = file else {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap();
+    };
+    let name = file.path().to_str().unwrap_or_default();
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type(name))
+        .header(header::CACHE_CONTROL, cache_control(name))
+        .body(Body::from(file.contents()))
+        .unwrap()
+}
+
+/// The assets of `_next/static` and `assets` are named after their hash, a new build gets new
+/// names.
+fn cache_control(path: &str) -> &'static str {
+    if path.starts_with("_next/static/") || path.starts_with("assets/") {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .unwrap_or_default();
+    match extension {
+        "html" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "map" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "txt" => "text/plain; charset=utf-8",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use include_dir::{DirEntry, File};
+
+    use super::*;
+
+    static APP: Dir = Dir::new(
+        "",
+        &[
+            DirEntry::File(File::new("index.html", b"<html>app</html>")),
+            DirEntry::File(File::new("repos.html", b"<html>repos</html>")),
+            DirEntry::File(File::new(".gitignore", b"*")),
+            DirEntry::Dir(Dir::new(
+                "_next",
+                &[DirEntry::Dir(Dir::new(
+                    "_next/static",
+                    &[DirEntry::File(File::new(
+                        "_next/static/main-3f2a.js",
+                        b"main()",
+                    ))],
+                ))],
+            )),
+        ],
+    );
+
+    fn header_of(res: &Response, name: header::HeaderName) -> &str {
+        res.headers().get(name).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn test_asset() {
+        let res = asset(&APP, "");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header_of(&res, header::CACHE_CONTROL), "no-cache");
+        assert_eq!(
+            header_of(&res, header::CONTENT_TYPE),
+            "text/html; charset=utf-8"
+        );
+
+        let res = asset(&APP, "_next/static/main-3f2a.js");
+        assert_eq!(
+            header_of(&res, header::CACHE_CONTROL),
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(
+            header_of(&res, header::CONTENT_TYPE),
+            "text/javascript; charset=utf-8"
+        );
+
+        // the routes of the app
+        assert_eq!(asset(&APP, "repos").status(), StatusCode::OK);
+        assert_eq!(asset(&APP, "repos/mega/tree").status(), StatusCode::OK);
+        // a missing asset is not the app
+        assert_eq!(
+            asset(&APP, "_next/static/gone.js").status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(asset(&APP, ".gitignore").status(), StatusCode::NOT_FOUND);
+    }
+}
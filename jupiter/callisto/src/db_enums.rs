@@ -1,4 +1,5 @@
 use sea_orm::{DeriveActiveEnum, EnumIter};
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum)]
 #[sea_orm(rs_type = "String", db_type = "String(Some(1))")]
@@ -21,8 +22,9 @@ impl ToString for StorageType {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, Copy)]
+#[derive(Clone, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, Copy, Serialize, Deserialize)]
 #[sea_orm(rs_type = "String", db_type = "String(Some(1))")]
+#[serde(rename_all = "lowercase")]
 pub enum MergeStatus {
     #[sea_orm(string_value = "open")]
     Open,
@@ -40,3 +42,26 @@ pub enum RefType {
     #[sea_orm(string_value = "tag")]
     Tag,
 }
+
+#[derive(Clone, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, Copy)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(1))")]
+pub enum OperationType {
+    #[sea_orm(string_value = "clone")]
+    Clone,
+    #[sea_orm(string_value = "fetch")]
+    Fetch,
+    #[sea_orm(string_value = "push")]
+    Push,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, Copy, Serialize, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(1))")]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewVerdict {
+    #[sea_orm(string_value = "comment")]
+    Comment,
+    #[sea_orm(string_value = "approve")]
+    Approve,
+    #[sea_orm(string_value = "request_changes")]
+    RequestChanges,
+}
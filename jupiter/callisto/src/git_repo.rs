@@ -11,6 +11,7 @@ pub struct Model {
     pub repo_path: String,
     #[sea_orm(column_type = "Text")]
     pub repo_name: String,
+    pub is_public: bool,
     pub created_at: DateTime,
     pub updated_at: DateTime,
 }
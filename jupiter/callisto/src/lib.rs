@@ -6,18 +6,51 @@ pub mod db_enums;
 pub mod git_blob;
 pub mod git_commit;
 pub mod git_issue;
+pub mod git_operation;
 pub mod git_pr;
 pub mod git_repo;
 pub mod git_tag;
 pub mod git_tree;
 pub mod lfs_locks;
 pub mod lfs_objects;
+pub mod mega_analytics_commit;
+pub mod mega_auth_policy;
+pub mod mega_blame_cache;
 pub mod mega_blob;
+pub mod mega_code_index;
+pub mod mega_code_symbol;
+pub mod mega_branch_cleanup;
 pub mod mega_commit;
+pub mod mega_dir_churn;
+pub mod mega_dir_contributor;
+pub mod mega_dir_coupling;
 pub mod mega_issue;
+pub mod mega_lfs_commit_map;
+pub mod mega_lfs_object;
 pub mod mega_mr;
+pub mod mega_mr_archive;
+pub mod mega_mr_comment;
+pub mod mega_mr_review;
+pub mod mega_object_map;
+pub mod mega_object_pin;
+pub mod mega_path_commit;
+pub mod mega_read_lease;
+pub mod mega_ref_event;
+pub mod mega_ref_event_cursor;
+pub mod mega_search_file;
+pub mod mega_search_ref;
+pub mod mega_session_revocation;
+pub mod mega_setting;
 pub mod mega_snapshot;
+pub mod mega_ssh_key;
+pub mod mega_storage_report;
 pub mod mega_tag;
 pub mod mega_tree;
+pub mod mega_user;
+pub mod mega_user_activity;
+pub mod mega_user_contribution;
+pub mod mega_watch;
+pub mod mega_watch_notification;
 pub mod raw_blob;
 pub mod refs;
+pub mod user_suspension;
@@ -3,18 +3,51 @@
 pub use crate::git_blob::Entity as GitBlob;
 pub use crate::git_commit::Entity as GitCommit;
 pub use crate::git_issue::Entity as GitIssue;
+pub use crate::git_operation::Entity as GitOperation;
 pub use crate::git_pr::Entity as GitPr;
 pub use crate::git_repo::Entity as GitRepo;
 pub use crate::git_tag::Entity as GitTag;
 pub use crate::git_tree::Entity as GitTree;
 pub use crate::lfs_locks::Entity as LfsLocks;
 pub use crate::lfs_objects::Entity as LfsObjects;
+pub use crate::mega_analytics_commit::Entity as MegaAnalyticsCommit;
+pub use crate::mega_auth_policy::Entity as MegaAuthPolicy;
+pub use crate::mega_blame_cache::Entity as MegaBlameCache;
 pub use crate::mega_blob::Entity as MegaBlob;
+pub use crate::mega_code_index::Entity as MegaCodeIndex;
+pub use crate::mega_code_symbol::Entity as MegaCodeSymbol;
+pub use crate::mega_branch_cleanup::Entity as MegaBranchCleanup;
 pub use crate::mega_commit::Entity as MegaCommit;
+pub use crate::mega_dir_churn::Entity as MegaDirChurn;
+pub use crate::mega_dir_contributor::Entity as MegaDirContributor;
+pub use crate::mega_dir_coupling::Entity as MegaDirCoupling;
 pub use crate::mega_issue::Entity as MegaIssue;
+pub use crate::mega_lfs_commit_map::Entity as MegaLfsCommitMap;
+pub use crate::mega_lfs_object::Entity as MegaLfsObject;
 pub use crate::mega_mr::Entity as MegaMr;
+pub use crate::mega_mr_archive::Entity as MegaMrArchive;
+pub use crate::mega_mr_comment::Entity as MegaMrComment;
+pub use crate::mega_mr_review::Entity as MegaMrReview;
+pub use crate::mega_object_map::Entity as MegaObjectMap;
+pub use crate::mega_object_pin::Entity as MegaObjectPin;
+pub use crate::mega_path_commit::Entity as MegaPathCommit;
+pub use crate::mega_read_lease::Entity as MegaReadLease;
+pub use crate::mega_ref_event::Entity as MegaRefEvent;
+pub use crate::mega_ref_event_cursor::Entity as MegaRefEventCursor;
+pub use crate::mega_search_file::Entity as MegaSearchFile;
+pub use crate::mega_search_ref::Entity as MegaSearchRef;
+pub use crate::mega_session_revocation::Entity as MegaSessionRevocation;
+pub use crate::mega_setting::Entity as MegaSetting;
 pub use crate::mega_snapshot::Entity as MegaSnapshot;
+pub use crate::mega_ssh_key::Entity as MegaSshKey;
+pub use crate::mega_storage_report::Entity as MegaStorageReport;
 pub use crate::mega_tag::Entity as MegaTag;
 pub use crate::mega_tree::Entity as MegaTree;
+pub use crate::mega_user::Entity as MegaUser;
+pub use crate::mega_user_activity::Entity as MegaUserActivity;
+pub use crate::mega_user_contribution::Entity as MegaUserContribution;
+pub use crate::mega_watch::Entity as MegaWatch;
+pub use crate::mega_watch_notification::Entity as MegaWatchNotification;
 pub use crate::raw_blob::Entity as RawObjects;
 pub use crate::refs::Entity as GitRefs;
+pub use crate::user_suspension::Entity as UserSuspension;
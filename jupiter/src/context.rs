@@ -3,28 +3,52 @@ use std::sync::Arc;
 use common::enums::DataSource;
 use storage::driver::database::{self, mysql_storage::MysqlStorage, storage::ObjectStorage};
 
+use crate::events::RefUpdateBus;
+use crate::hooks::{MergeHooks, PushHooks};
+use crate::settings::{RuntimeSettings, Settings};
 use crate::storage::{
-    git_storage::GitStorage, init::database_connection, lfs_storage::LfsStorage,
-    mega_storage::MegaStorage,
+    activity_storage::ActivityStorage, analytics_storage::AnalyticsStorage,
+    audit_storage::AuditStorage, blame_storage::BlameStorage, code_nav_storage::CodeNavStorage,
+    event_storage::EventStorage, git_storage::GitStorage, init::database_connection,
+    lfs_storage::LfsStorage, mega_storage::MegaStorage, object_map_storage::ObjectMapStorage,
+    review_storage::ReviewStorage, search_storage::SearchStorage, setting_storage::SettingStorage,
+    stats_storage::StatsStorage, user_storage::UserStorage, watch_storage::WatchStorage,
 };
 
 #[derive(Clone)]
 pub struct Context {
     pub services: Arc<Service>,
     pub storage: Arc<dyn ObjectStorage>,
+    pub settings: Arc<RuntimeSettings>,
+    pub events: Arc<RefUpdateBus>,
+    pub hooks: Arc<MergeHooks>,
+    pub push_hooks: Arc<PushHooks>,
 }
 
 impl Context {
     pub async fn new(data_source: &DataSource) -> Self {
+        let services = Service::shared().await;
+        let settings = RuntimeSettings::new(Settings::from_env());
+        if let Err(err) = settings.reload(&services.setting_storage).await {
+            tracing::error!("failed to load the runtime settings: {}", err);
+        }
         Context {
-            services: Service::shared().await,
+            services,
             storage: database::init(data_source).await,
+            settings: Arc::new(settings),
+            events: Arc::new(RefUpdateBus::default()),
+            hooks: Arc::new(MergeHooks::default()),
+            push_hooks: Arc::new(PushHooks::default()),
         }
     }
     pub fn mock() -> Self {
         Context {
             services: Service::mock(),
             storage: Arc::new(MysqlStorage::default()),
+            settings: Arc::new(RuntimeSettings::new(Settings::default())),
+            events: Arc::new(RefUpdateBus::default()),
+            hooks: Arc::new(MergeHooks::default()),
+            push_hooks: Arc::new(PushHooks::default()),
         }
     }
 }
@@ -34,6 +58,19 @@ pub struct Service {
     pub mega_storage: Arc<MegaStorage>,
     pub git_storage: Arc<GitStorage>,
     pub lfs_storage: Arc<LfsStorage>,
+    pub audit_storage: Arc<AuditStorage>,
+    pub user_storage: Arc<UserStorage>,
+    pub setting_storage: Arc<SettingStorage>,
+    pub event_storage: Arc<EventStorage>,
+    pub activity_storage: Arc<ActivityStorage>,
+    pub analytics_storage: Arc<AnalyticsStorage>,
+    pub review_storage: Arc<ReviewStorage>,
+    pub code_nav_storage: Arc<CodeNavStorage>,
+    pub blame_storage: Arc<BlameStorage>,
+    pub search_storage: Arc<SearchStorage>,
+    pub object_map_storage: Arc<ObjectMapStorage>,
+    pub watch_storage: Arc<WatchStorage>,
+    pub stats_storage: Arc<StatsStorage>,
 }
 
 impl Service {
@@ -43,6 +80,19 @@ impl Service {
             mega_storage: Arc::new(MegaStorage::new(connection.clone()).await),
             git_storage: Arc::new(GitStorage::new().await),
             lfs_storage: Arc::new(LfsStorage::new(connection.clone()).await),
+            audit_storage: Arc::new(AuditStorage::new(connection.clone()).await),
+            user_storage: Arc::new(UserStorage::new(connection.clone()).await),
+            setting_storage: Arc::new(SettingStorage::new(connection.clone()).await),
+            event_storage: Arc::new(EventStorage::new(connection.clone()).await),
+            activity_storage: Arc::new(ActivityStorage::new(connection.clone()).await),
+            analytics_storage: Arc::new(AnalyticsStorage::new(connection.clone()).await),
+            review_storage: Arc::new(ReviewStorage::new(connection.clone()).await),
+            code_nav_storage: Arc::new(CodeNavStorage::new(connection.clone()).await),
+            blame_storage: Arc::new(BlameStorage::new(connection.clone()).await),
+            search_storage: Arc::new(SearchStorage::new(connection.clone()).await),
+            object_map_storage: Arc::new(ObjectMapStorage::new(connection.clone()).await),
+            watch_storage: Arc::new(WatchStorage::new(connection.clone()).await),
+            stats_storage: Arc::new(StatsStorage::new(connection.clone()).await),
         }
     }
 
@@ -55,6 +105,19 @@ impl Service {
             mega_storage: Arc::new(MegaStorage::mock()),
             git_storage: Arc::new(GitStorage::mock()),
             lfs_storage: Arc::new(LfsStorage::mock()),
+            audit_storage: Arc::new(AuditStorage::mock()),
+            user_storage: Arc::new(UserStorage::mock()),
+            setting_storage: Arc::new(SettingStorage::mock()),
+            event_storage: Arc::new(EventStorage::mock()),
+            activity_storage: Arc::new(ActivityStorage::mock()),
+            analytics_storage: Arc::new(AnalyticsStorage::mock()),
+            review_storage: Arc::new(ReviewStorage::mock()),
+            code_nav_storage: Arc::new(CodeNavStorage::mock()),
+            blame_storage: Arc::new(BlameStorage::mock()),
+            search_storage: Arc::new(SearchStorage::mock()),
+            object_map_storage: Arc::new(ObjectMapStorage::mock()),
+            watch_storage: Arc::new(WatchStorage::mock()),
+            stats_storage: Arc::new(StatsStorage::mock()),
         })
     }
 }
@@ -0,0 +1,381 @@
+//!
+//! Ref updates delivered to the consumers interested in them. A push records its ref updates
+//! in the `mega_ref_event` table and every registered observer receives them in order, each
+//! observer keeping a cursor of the last event it handled. The cursor only moves once the
+//! observer succeeded, so an event is delivered at least once: after a failure or a restart
+//! the same event is delivered again, and observers have to be idempotent.
+//!
+//! Every server delivers to the same observers, a server leases the cursor of an observer in
+//! the database before delivering to it so that the events of an observer are delivered by one
+//! server at a time, in order. The cursor only moves from the position the lease read, a
+//! server whose lease expired stops there. A new observer starts after the last event
+//! recorded.
+//!
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDateTime, Utc};
+use tokio::sync::Notify;
+
+use callisto::mega_ref_event;
+use common::errors::MegaError;
+use common::utils::generate_id;
+
+use crate::context::Context;
+
+pub type RefUpdateEvent = mega_ref_event::Model;
+
+/// Events are delivered once they are this old, so that the events recorded concurrently by
+/// another server with a smaller id are not skipped.
+const SETTLE_SECS: i64 = 2;
+
+const BATCH_SIZE: u64 = 100;
+
+/// A cursor is leased this long, each event delivered extends the lease. A server that stops
+/// delivering leaves the observer to the others once it expired.
+const LEASE_SECS: i64 = 300;
+
+/// Delivered events are kept this long, see `ceres::restore`.
+pub const RETENTION_DAYS: i64 = 7;
+
+#[async_trait]
+pub trait RefUpdateObserver: Send + Sync {
+    /// Identifies the cursor of the observer, a renamed observer starts after the last event
+    /// recorded.
+    fn name(&self) -> &'static str;
+
+    async fn on_ref_update(
+        &self,
+        context: &Context,
+        event: &RefUpdateEvent,
+    ) -> Result<(), MegaError>;
+}
+
+#[derive(Default)]
+pub struct RefUpdateBus {
+    observers: RwLock<Vec<Arc<dyn RefUpdateObserver>>>,
+    notify: Notify,
+}
+
+impl RefUpdateBus {
+    /// Adds `observer`, an observer with the name of a registered one is ignored.
+    pub fn register(&self, observer: Arc<dyn RefUpdateObserver>) {
+        let mut observers = self.observers.write().unwrap();
+        if observers.iter().all(|o| o.name() != observer.name()) {
+            observers.push(observer);
+        }
+    }
+
+    pub fn observers(&self) -> Vec<Arc<dyn RefUpdateObserver>> {
+        self.observers.read().unwrap().clone()
+    }
+
+    /// Records `events` and wakes up the delivery.
+    pub async fn publish(
+        &self,
+        context: &Context,
+        events: Vec<RefUpdateEvent>,
+    ) -> Result<(), MegaError> {
+        context
+            .services
+            .event_storage
+            .save_ref_events(events)
+            .await?;
+        self.wake();
+        Ok(())
+    }
+
+    /// Wakes up the delivery of events recorded by the caller, e.g. within the transaction of
+    /// a merge, see [`crate::storage::mega_storage::MegaStorage::merge`].
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Waits until events are published.
+    pub async fn published(&self) {
+        self.notify.notified().await
+    }
+
+    /// Delivers the pending events to every observer whose cursor is not leased by another
+    /// delivery. An observer failing stops its delivery until the next call, the other
+    /// observers are not held up.
+    pub async fn deliver(&self, context: &Context) {
+        let storage = &context.services.event_storage;
+        // events are only removed once every observer handled them
+        let mut delivered = Some(i64::MAX);
+        for observer in self.observers() {
+            match self.deliver_to(context, observer.as_ref()).await {
+                Ok(Some(cursor)) => delivered = delivered.map(|up_to| up_to.min(cursor)),
+                // delivered elsewhere, how far is not known
+                Ok(None) => delivered = None,
+                Err(err) => {
+                    tracing::error!(
+                        "failed to deliver ref updates to {}: {}",
+                        observer.name(),
+                        err
+                    );
+                    delivered = None;
+                }
+            }
+        }
+        if let Some(up_to) = delivered.filter(|up_to| *up_to != i64::MAX) {
+            let before = Utc::now().naive_utc() - Duration::days(RETENTION_DAYS);
+            if let Err(err) = storage.remove_ref_events(up_to, before).await {
+                tracing::error!("failed to remove delivered ref updates: {}", err);
+            }
+        }
+    }
+
+    /// Returns the cursor of `observer` after the delivery, None when another delivery holds
+    /// its lease.
+    async fn deliver_to(
+        &self,
+        context: &Context,
+        observer: &dyn RefUpdateObserver,
+    ) -> Result<Option<i64>, MegaError> {
+        let storage = &context.services.event_storage;
+        let lease_id = generate_id();
+        let Some(cursor) = storage
+            .acquire_cursor(observer.name(), lease_id, lease_expiry())
+            .await?
+        else {
+            return Ok(None);
+        };
+        let delivered = self
+            .deliver_from(context, observer, lease_id, cursor)
+            .await;
+        if let Err(err) = storage.release_cursor(observer.name(), lease_id).await {
+            tracing::warn!("failed to release the cursor of {}: {}", observer.name(), err);
+        }
+        delivered.map(Some)
+    }
+
+    /// Delivers the events after `cursor` under the lease `lease_id`, returns the new cursor.
+    async fn deliver_from(
+        &self,
+        context: &Context,
+        observer: &dyn RefUpdateObserver,
+        lease_id: i64,
+        mut cursor: i64,
+    ) -> Result<i64, MegaError> {
+        let storage = &context.services.event_storage;
+        loop {
+            let until = Utc::now().naive_utc() - Duration::seconds(SETTLE_SECS);
+            let events = storage.get_ref_events(cursor, until, BATCH_SIZE).await?;
+            if events.is_empty() {
+                return Ok(cursor);
+            }
+            for event in events {
+                observer.on_ref_update(context, &event).await?;
+                let advanced = storage
+                    .advance_cursor(observer.name(), lease_id, cursor, event.id, lease_expiry())
+                    .await?;
+                if !advanced {
+                    return Err(MegaError::with_message(&format!(
+                        "the lease of the cursor of {} expired",
+                        observer.name()
+                    )));
+                }
+                cursor = event.id;
+            }
+        }
+    }
+}
+
+fn lease_expiry() -> NaiveDateTime {
+    Utc::now().naive_utc() + Duration::seconds(LEASE_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use chrono::Utc;
+    use sea_orm::{DatabaseBackend, MockDatabase, MockExecResult};
+
+    use callisto::{mega_ref_event, mega_ref_event_cursor};
+    use common::errors::MegaError;
+
+    use super::{RefUpdateBus, RefUpdateEvent, RefUpdateObserver};
+    use crate::context::{Context, Service};
+    use crate::storage::event_storage::EventStorage;
+
+    struct Named(&'static str);
+
+    #[async_trait]
+    impl RefUpdateObserver for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        async fn on_ref_update(&self, _: &Context, _: &RefUpdateEvent) -> Result<(), MegaError> {
+            Ok(())
+        }
+    }
+
+    /// Records the events it is delivered, failing the first delivery of `fail_once`.
+    #[derive(Default)]
+    struct Recorder {
+        delivered: Mutex<Vec<i64>>,
+        fail_once: Mutex<Option<i64>>,
+    }
+
+    impl Recorder {
+        fn delivered(&self) -> Vec<i64> {
+            self.delivered.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl RefUpdateObserver for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        async fn on_ref_update(
+            &self,
+            _: &Context,
+            event: &RefUpdateEvent,
+        ) -> Result<(), MegaError> {
+            self.delivered.lock().unwrap().push(event.id);
+            let mut fail_once = self.fail_once.lock().unwrap();
+            if *fail_once == Some(event.id) {
+                *fail_once = None;
+                return Err(MegaError::with_message("observer failed"));
+            }
+            Ok(())
+        }
+    }
+
+    fn event(id: i64) -> mega_ref_event::Model {
+        mega_ref_event::Model {
+            id,
+            repo_id: 1,
+            repo_path: String::from("/project"),
+            ref_name: String::from("refs/heads/main"),
+            old_id: "0".repeat(40),
+            new_id: format!("{:040x}", id),
+            pusher: None,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    fn cursor(event_id: i64) -> mega_ref_event_cursor::Model {
+        mega_ref_event_cursor::Model {
+            observer: String::from("recorder"),
+            event_id,
+            updated_at: Utc::now().naive_utc(),
+            lease_id: Some(1),
+            lease_expires_at: None,
+        }
+    }
+
+    fn rows(rows_affected: u64) -> MockExecResult {
+        MockExecResult {
+            last_insert_id: 0,
+            rows_affected,
+        }
+    }
+
+    fn mock_context(db: MockDatabase) -> Context {
+        let mut context = Context::mock();
+        let services = Service {
+            event_storage: Arc::new(EventStorage {
+                connection: Arc::new(db.into_connection()),
+            }),
+            ..(*context.services).clone()
+        };
+        context.services = Arc::new(services);
+        context
+    }
+
+    #[test]
+    fn test_register() {
+        let bus = RefUpdateBus::default();
+        bus.register(Arc::new(Named("search")));
+        bus.register(Arc::new(Named("stats")));
+        bus.register(Arc::new(Named("search")));
+        let names: Vec<_> = bus.observers().iter().map(|o| o.name()).collect();
+        assert_eq!(names, vec!["search", "stats"]);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_in_order() {
+        // the lease is taken at the cursor 10, the cursor moves after each event and the
+        // lease is released once no event is left
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_exec_results([rows(1)])
+            .append_query_results([vec![cursor(10)]])
+            .append_query_results([vec![event(11), event(12)], vec![event(13)], vec![]])
+            .append_exec_results([rows(1), rows(1), rows(1), rows(1), rows(0)]);
+        let context = mock_context(db);
+        let recorder = Arc::new(Recorder::default());
+        context.events.register(recorder.clone());
+        context.events.deliver(&context).await;
+        assert_eq!(recorder.delivered(), vec![11, 12, 13]);
+    }
+
+    #[tokio::test]
+    async fn test_redeliver_after_failure() {
+        // 12 fails, the cursor stays at 11 and 12 is delivered again by the next call
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_exec_results([rows(1)])
+            .append_query_results([vec![cursor(10)]])
+            .append_query_results([vec![event(11), event(12)]])
+            .append_exec_results([rows(1), rows(1)])
+            .append_exec_results([rows(1)])
+            .append_query_results([vec![cursor(11)]])
+            .append_query_results([vec![event(12)], vec![]])
+            .append_exec_results([rows(1), rows(1), rows(0)]);
+        let context = mock_context(db);
+        let recorder = Arc::new(Recorder {
+            fail_once: Mutex::new(Some(12)),
+            ..Recorder::default()
+        });
+        context.events.register(recorder.clone());
+        context.events.deliver(&context).await;
+        assert_eq!(recorder.delivered(), vec![11, 12]);
+        context.events.deliver(&context).await;
+        assert_eq!(recorder.delivered(), vec![11, 12, 12]);
+    }
+
+    #[tokio::test]
+    async fn test_cursor_lease() {
+        // another server holds the lease, nothing is delivered
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_exec_results([rows(0)])
+            .append_query_results([vec![cursor(10)]]);
+        let context = mock_context(db);
+        let recorder = Arc::new(Recorder::default());
+        context.events.register(recorder.clone());
+        context.events.deliver(&context).await;
+        assert!(recorder.delivered().is_empty());
+
+        // the cursor moved under an expired lease, the delivery stops at the event handled
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_exec_results([rows(1)])
+            .append_query_results([vec![cursor(10)]])
+            .append_query_results([vec![event(11), event(12)]])
+            .append_exec_results([rows(0), rows(0)]);
+        let context = mock_context(db);
+        let recorder = Arc::new(Recorder::default());
+        context.events.register(recorder.clone());
+        context.events.deliver(&context).await;
+        assert_eq!(recorder.delivered(), vec![11]);
+
+        // a new observer starts after the last event recorded
+        let db = MockDatabase::new(DatabaseBackend::Postgres)
+            .append_exec_results([rows(0)])
+            .append_query_results([Vec::<mega_ref_event_cursor::Model>::new()])
+            .append_query_results([vec![event(42)]])
+            .append_exec_results([rows(1)])
+            .append_query_results([Vec::<mega_ref_event::Model>::new()])
+            .append_exec_results([rows(1), rows(0)]);
+        let context = mock_context(db);
+        let recorder = Arc::new(Recorder::default());
+        context.events.register(recorder.clone());
+        context.events.deliver(&context).await;
+        assert!(recorder.delivered().is_empty());
+    }
+}
@@ -0,0 +1,341 @@
+//!
+//! Hooks run when a push is merged. They run inside the transaction of the merge, after the
+//! objects, the merge request and the ref updates are written but before the commit, so a hook
+//! failing either rolls the whole merge back or is only logged, as chosen by the
+//! `merge_hook_rollback` setting. Unlike the observers of [`crate::events`], a hook runs once and
+//! holds up the push, it has to be quick.
+//!
+//! Push hooks run in receive-pack around the ref updates, as the pre-receive, update and
+//! post-receive hooks of git: before any ref is updated, for every ref update that passed the
+//! other checks, and once the accepted updates are saved. The first two can refuse the push or
+//! a single update, the output of all of them is shown to the client.
+//!
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+
+use common::errors::MegaError;
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::internal::pack::reference::RefCommand;
+use venus::mr::MergeRequest;
+use venus::repo::Repo;
+
+use crate::events::RefUpdateEvent;
+
+/// A push being merged: the merge request of its objects and the accepted ref updates.
+pub struct Merge {
+    pub mr: MergeRequest,
+    pub repo: Repo,
+    pub commands: Vec<RefCommand>,
+    pub events: Vec<RefUpdateEvent>,
+}
+
+#[async_trait]
+pub trait PostMergeHook: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn on_merge(&self, merge: &Merge) -> Result<(), MegaError>;
+}
+
+#[derive(Default)]
+pub struct MergeHooks {
+    hooks: RwLock<Vec<Arc<dyn PostMergeHook>>>,
+}
+
+impl MergeHooks {
+    /// Adds `hook`, a hook with the name of a registered one is ignored.
+    pub fn register(&self, hook: Arc<dyn PostMergeHook>) {
+        let mut hooks = self.hooks.write().unwrap();
+        if hooks.iter().all(|h| h.name() != hook.name()) {
+            hooks.push(hook);
+        }
+    }
+
+    pub fn hooks(&self) -> Vec<Arc<dyn PostMergeHook>> {
+        self.hooks.read().unwrap().clone()
+    }
+
+    /// Runs the hooks in the order they were registered, up to the first failing one.
+    pub async fn run(&self, merge: &Merge) -> Result<(), MegaError> {
+        for hook in self.hooks() {
+            hook.on_merge(merge).await.map_err(|err| {
+                MegaError::with_message(&format!("post-merge hook {} failed: {}", hook.name(), err))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// A push being received: the ref updates not refused so far and the commits of its pack.
+#[derive(Debug, Clone)]
+pub struct Push {
+    pub repo: Repo,
+    pub repo_path: String,
+    pub pusher: Option<String>,
+    pub commands: Vec<RefCommand>,
+    /// The options sent with `git push -o`.
+    pub push_options: Vec<String>,
+    pub commits: Vec<Commit>,
+}
+
+impl Push {
+    /// The pushed commits the new id of `command` reaches, the commits the update adds to the
+    /// repository.
+    pub fn commits_of(&self, command: &RefCommand) -> Vec<&Commit> {
+        let commits: HashMap<SHA1, &Commit> = self.commits.iter().map(|c| (c.id, c)).collect();
+        let mut seen = HashSet::new();
+        let mut pending: Vec<SHA1> = SHA1::from_str(&command.new_id).into_iter().collect();
+        let mut reached = Vec::new();
+        while let Some(id) = pending.pop() {
+            let Some(commit) = commits.get(&id).filter(|_| seen.insert(id)) else {
+                continue;
+            };
+            pending.extend(commit.parent_commit_ids.iter().copied());
+            reached.push(*commit);
+        }
+        reached
+    }
+}
+
+/// What a push hook answers: the lines shown to the client, and the reason the updates it
+/// was run for are refused, None to accept them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HookResult {
+    pub messages: Vec<String>,
+    pub refusal: Option<String>,
+}
+
+impl HookResult {
+    pub fn accept() -> Self {
+        HookResult::default()
+    }
+
+    pub fn refuse(reason: &str) -> Self {
+        HookResult {
+            messages: vec![],
+            refusal: Some(reason.to_owned()),
+        }
+    }
+}
+
+/// A check of the pushes, with the default of accepting everything at every step. An error
+/// refuses the updates as the hook could not tell whether to accept them.
+#[async_trait]
+pub trait PushHook: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Runs once before any ref is updated, a refusal refuses every update of the push.
+    async fn pre_receive(&self, _push: &Push) -> Result<HookResult, MegaError> {
+        Ok(HookResult::accept())
+    }
+
+    /// Runs for each ref update accepted so far, a refusal only refuses that update.
+    async fn update(&self, _push: &Push, _command: &RefCommand) -> Result<HookResult, MegaError> {
+        Ok(HookResult::accept())
+    }
+
+    /// Runs once the `updated` refs are saved, it cannot change the push anymore.
+    async fn post_receive(
+        &self,
+        _push: &Push,
+        _updated: &[RefCommand],
+    ) -> Result<HookResult, MegaError> {
+        Ok(HookResult::accept())
+    }
+}
+
+/// A push hook refusing updates, or failing to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookRefusal {
+    pub hook: String,
+    /// The reason given by the hook, None when it failed to run.
+    pub reason: Option<String>,
+}
+
+#[derive(Default)]
+pub struct PushHooks {
+    hooks: RwLock<Vec<Arc<dyn PushHook>>>,
+}
+
+impl PushHooks {
+    /// Adds `hook`, a hook with the name of a registered one is ignored.
+    pub fn register(&self, hook: Arc<dyn PushHook>) {
+        let mut hooks = self.hooks.write().unwrap();
+        if hooks.iter().all(|h| h.name() != hook.name()) {
+            hooks.push(hook);
+        }
+    }
+
+    pub fn hooks(&self) -> Vec<Arc<dyn PushHook>> {
+        self.hooks.read().unwrap().clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.read().unwrap().is_empty()
+    }
+
+    /// Runs the pre-receive step of the hooks in the order they were registered, up to the
+    /// first refusing one. The messages of the hooks run are appended to `messages`.
+    pub async fn pre_receive(
+        &self,
+        push: &Push,
+        messages: &mut Vec<String>,
+    ) -> Result<(), HookRefusal> {
+        for hook in self.hooks() {
+            check(hook.name(), hook.pre_receive(push).await, messages)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the update step of the hooks for `command`, up to the first refusing one.
+    pub async fn update(
+        &self,
+        push: &Push,
+        command: &RefCommand,
+        messages: &mut Vec<String>,
+    ) -> Result<(), HookRefusal> {
+        for hook in self.hooks() {
+            check(hook.name(), hook.update(push, command).await, messages)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the post-receive step of every hook, a failing one is only logged.
+    pub async fn post_receive(
+        &self,
+        push: &Push,
+        updated: &[RefCommand],
+        messages: &mut Vec<String>,
+    ) {
+        for hook in self.hooks() {
+            match hook.post_receive(push, updated).await {
+                Ok(result) => messages.extend(result.messages),
+                Err(err) => tracing::error!("post-receive hook {} failed: {}", hook.name(), err),
+            }
+        }
+    }
+}
+
+fn check(
+    name: &str,
+    result: Result<HookResult, MegaError>,
+    messages: &mut Vec<String>,
+) -> Result<(), HookRefusal> {
+    match result {
+        Ok(result) => {
+            messages.extend(result.messages);
+            match result.refusal {
+                Some(reason) => Err(HookRefusal {
+                    hook: name.to_owned(),
+                    reason: Some(reason),
+                }),
+                None => Ok(()),
+            }
+        }
+        Err(err) => {
+            tracing::error!("push hook {} failed: {}", name, err);
+            Err(HookRefusal {
+                hook: name.to_owned(),
+                reason: None,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+
+    use common::errors::MegaError;
+    use venus::hash::SHA1;
+    use venus::internal::object::commit::Commit;
+    use venus::internal::pack::reference::RefCommand;
+    use venus::repo::Repo;
+    use venus::test_support::{commit, id};
+
+    use super::{HookRefusal, HookResult, Push, PushHook, PushHooks};
+
+    fn push(commits: Vec<Commit>) -> Push {
+        Push {
+            repo: Repo::empty(),
+            repo_path: String::from("/"),
+            pusher: Some(String::from("alice")),
+            commands: vec![],
+            push_options: vec![],
+            commits,
+        }
+    }
+
+    struct Refusing(&'static str);
+
+    #[async_trait]
+    impl PushHook for Refusing {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        async fn update(&self, _: &Push, command: &RefCommand) -> Result<HookResult, MegaError> {
+            if command.ref_name.ends_with("main") {
+                return Ok(HookResult::refuse("main is frozen"));
+            }
+            Ok(HookResult {
+                messages: vec![String::from("checked\n")],
+                refusal: None,
+            })
+        }
+    }
+
+    #[test]
+    fn test_commits_of() {
+        // 1 and 2 are stored already, 3 - 4 - 5 is pushed on top of 2 and 6 on another branch
+        let push = push(vec![
+            commit(3, &[2]),
+            commit(4, &[3]),
+            commit(5, &[4, 3]),
+            commit(6, &[1]),
+        ]);
+        let command = RefCommand::new(
+            id(2).to_plain_str(),
+            id(5).to_plain_str(),
+            String::from("refs/heads/main"),
+        );
+        let mut reached: Vec<SHA1> = push.commits_of(&command).iter().map(|c| c.id).collect();
+        reached.sort_by_key(|id| id.0);
+        assert_eq!(reached, vec![id(3), id(4), id(5)]);
+    }
+
+    #[tokio::test]
+    async fn test_update_refusal() {
+        let hooks = PushHooks::default();
+        hooks.register(Arc::new(Refusing("freeze")));
+        hooks.register(Arc::new(Refusing("freeze")));
+        assert_eq!(hooks.hooks().len(), 1);
+
+        let push = push(vec![]);
+        let mut messages = Vec::new();
+        let dev = RefCommand::new(
+            id(1).to_plain_str(),
+            id(2).to_plain_str(),
+            String::from("refs/heads/dev"),
+        );
+        assert!(hooks.update(&push, &dev, &mut messages).await.is_ok());
+        assert_eq!(messages, vec![String::from("checked\n")]);
+        let main = RefCommand::new(
+            id(1).to_plain_str(),
+            id(2).to_plain_str(),
+            String::from("refs/heads/main"),
+        );
+        assert_eq!(
+            hooks.update(&push, &main, &mut messages).await,
+            Err(HookRefusal {
+                hook: String::from("freeze"),
+                reason: Some(String::from("main is frozen")),
+            })
+        );
+    }
+}
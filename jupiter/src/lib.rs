@@ -1,4 +1,7 @@
 pub mod context;
+pub mod events;
+pub mod hooks;
 pub mod raw_storage;
+pub mod settings;
 pub mod storage;
 pub mod utils;
@@ -0,0 +1,163 @@
+//!
+//! Encryption at rest of the objects of a [`RawStorage`]: the pushed blobs kept out of the
+//! database, the LFS objects and the archives of the merge requests. Every object is sealed in
+//! an envelope of [`storage::driver::database::encrypted_storage`], the refs stay plain.
+//!
+//! An object sealed with a retired key is rewrapped with the current one when it is read, so
+//! the objects nothing lists are rotated as they are used.
+//!
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use callisto::db_enums::StorageType;
+use common::errors::MegaError;
+use storage::driver::database::encrypted_storage::{
+    decrypt, encrypt, is_current, rewrap, KeyProvider,
+};
+
+use crate::raw_storage::RawStorage;
+
+pub struct EncryptedStorage {
+    inner: Arc<dyn RawStorage>,
+    keys: Arc<dyn KeyProvider>,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn RawStorage>, keys: Arc<dyn KeyProvider>) -> Self {
+        EncryptedStorage { inner, keys }
+    }
+}
+
+#[async_trait]
+impl RawStorage for EncryptedStorage {
+    fn get_storage_type(&self) -> StorageType {
+        self.inner.get_storage_type()
+    }
+
+    async fn get_ref(&self, repo_name: &str, ref_name: &str) -> Result<String, MegaError> {
+        self.inner.get_ref(repo_name, ref_name).await
+    }
+
+    async fn put_ref(
+        &self,
+        repo_name: &str,
+        ref_name: &str,
+        ref_hash: &str,
+    ) -> Result<(), MegaError> {
+        self.inner.put_ref(repo_name, ref_name, ref_hash).await
+    }
+
+    async fn delete_ref(&self, repo_name: &str, ref_name: &str) -> Result<(), MegaError> {
+        self.inner.delete_ref(repo_name, ref_name).await
+    }
+
+    async fn update_ref(
+        &self,
+        repo_name: &str,
+        ref_name: &str,
+        ref_hash: &str,
+    ) -> Result<(), MegaError> {
+        self.inner.update_ref(repo_name, ref_name, ref_hash).await
+    }
+
+    async fn get_object(&self, repo_name: &str, object_id: &str) -> Result<Bytes, MegaError> {
+        let data = self.inner.get_object(repo_name, object_id).await?;
+        let plain = decrypt(self.keys.as_ref(), object_id, &data)?;
+        if !is_current(self.keys.as_ref(), &data) {
+            let sealed = rewrap(self.keys.as_ref(), object_id, &data)?;
+            if let Err(e) = self.inner.put_object(repo_name, object_id, &sealed).await {
+                tracing::warn!("Failed to rewrap the object {}: {}", object_id, e);
+            }
+        }
+        Ok(Bytes::from(plain))
+    }
+
+    async fn put_object(
+        &self,
+        repo_name: &str,
+        object_id: &str,
+        body_content: &[u8],
+    ) -> Result<String, MegaError> {
+        let sealed = encrypt(self.keys.as_ref(), object_id, body_content)?;
+        self.inner.put_object(repo_name, object_id, &sealed).await
+    }
+
+    async fn delete_object(&self, repo_name: &str, object_id: &str) -> Result<(), MegaError> {
+        self.inner.delete_object(repo_name, object_id).await
+    }
+
+    async fn rewrap_object(&self, repo_name: &str, object_id: &str) -> Result<bool, MegaError> {
+        let data = self.inner.get_object(repo_name, object_id).await?;
+        if is_current(self.keys.as_ref(), &data) {
+            return Ok(false);
+        }
+        let sealed = rewrap(self.keys.as_ref(), object_id, &data)?;
+        self.inner.put_object(repo_name, object_id, &sealed).await?;
+        Ok(true)
+    }
+
+    fn exist_object(&self, repo_name: &str, object_id: &str) -> bool {
+        self.inner.exist_object(repo_name, object_id)
+    }
+
+    fn transform_path(&self, sha1: &str) -> String {
+        self.inner.transform_path(sha1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::sync::Arc;
+
+    use storage::driver::database::encrypted_storage::LocalKeyProvider;
+
+    use crate::raw_storage::encrypted_storage::EncryptedStorage;
+    use crate::raw_storage::local_storage::LocalStorage;
+    use crate::raw_storage::RawStorage;
+
+    fn provider(current: &str) -> Arc<LocalKeyProvider> {
+        let mut keys = HashMap::new();
+        keys.insert(String::from("k1"), vec![1u8; 32]);
+        keys.insert(String::from("k2"), vec![2u8; 32]);
+        Arc::new(LocalKeyProvider::new(keys, current).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_objects() {
+        let dir = env::temp_dir().join("mega_test_encrypted_storage");
+        let _ = std::fs::remove_dir_all(&dir);
+        let local = Arc::new(LocalStorage::init(dir.clone()));
+        let storage = EncryptedStorage::new(local.clone(), provider("k1"));
+        storage
+            .put_object("repo", "ab12cd", b"content")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_object("repo", "ab12cd").await.unwrap(),
+            "content"
+        );
+        // the backend only holds the ciphertext
+        let sealed = local.get_object("repo", "ab12cd").await.unwrap();
+        assert!(!sealed.windows(7).any(|w| w == b"content"));
+        // an object stored before the encryption was enabled is still read
+        local.put_object("repo", "ef34ab", b"plain").await.unwrap();
+        assert_eq!(storage.get_object("repo", "ef34ab").await.unwrap(), "plain");
+
+        // after a rotation, the objects are rewrapped with the current key
+        let rotated = EncryptedStorage::new(local.clone(), provider("k2"));
+        assert!(rotated.rewrap_object("repo", "ab12cd").await.unwrap());
+        assert!(!rotated.rewrap_object("repo", "ab12cd").await.unwrap());
+        assert_eq!(
+            rotated.get_object("repo", "ab12cd").await.unwrap(),
+            "content"
+        );
+        // the other ones when they are read
+        assert_eq!(rotated.get_object("repo", "ef34ab").await.unwrap(), "plain");
+        assert!(!rotated.rewrap_object("repo", "ef34ab").await.unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+//!
+//! Faults injected in a [`RawStorage`] for the tests, the counterpart of the `FaultStorage` of
+//! the `storage` crate: a failing call returns before it reaches the wrapped storage, so that
+//! nothing is written.
+//!
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use callisto::db_enums::StorageType;
+use common::errors::MegaError;
+use common::fault::FaultInjector;
+
+use crate::raw_storage::RawStorage;
+
+pub struct FaultRawStorage {
+    pub inner: Arc<dyn RawStorage>,
+    pub faults: Arc<FaultInjector>,
+}
+
+impl FaultRawStorage {
+    pub fn new(inner: Arc<dyn RawStorage>, faults: Arc<FaultInjector>) -> Self {
+        FaultRawStorage { inner, faults }
+    }
+
+    async fn inject(&self, operation: &str) -> Result<(), MegaError> {
+        let decision = self.faults.decide(operation);
+        if !decision.delay.is_zero() {
+            tokio::time::sleep(decision.delay).await;
+        }
+        if decision.fail {
+            return Err(FaultInjector::error(operation));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RawStorage for FaultRawStorage {
+    fn get_storage_type(&self) -> StorageType {
+        self.inner.get_storage_type()
+    }
+
+    async fn get_ref(&self, repo_name: &str, ref_name: &str) -> Result<String, MegaError> {
+        self.inject("get_ref").await?;
+        self.inner.get_ref(repo_name, ref_name).await
+    }
+
+    async fn put_ref(
+        &self,
+        repo_name: &str,
+        ref_name: &str,
+        ref_hash: &str,
+    ) -> Result<(), MegaError> {
+        self.inject("put_ref").await?;
+        self.inner.put_ref(repo_name, ref_name, ref_hash).await
+    }
+
+    async fn delete_ref(&self, repo_name: &str, ref_name: &str) -> Result<(), MegaError> {
+        self.inject("delete_ref").await?;
+        self.inner.delete_ref(repo_name, ref_name).await
+    }
+
+    async fn update_ref(
+        &self,
+        repo_name: &str,
+        ref_name: &str,
+        ref_hash: &str,
+    ) -> Result<(), MegaError> {
+        self.inject("update_ref").await?;
+        self.inner.update_ref(repo_name, ref_name, ref_hash).await
+    }
+
+    async fn get_object(&self, repo_name: &str, object_id: &str) -> Result<Bytes, MegaError> {
+        self.inject("get_object").await?;
+        self.inner.get_object(repo_name, object_id).await
+    }
+
+    async fn put_object(
+        &self,
+        repo_name: &str,
+        object_id: &str,
+        body_content: &[u8],
+    ) -> Result<String, MegaError> {
+        self.inject("put_object").await?;
+        self.inner
+            .put_object(repo_name, object_id, body_content)
+            .await
+    }
+
+    async fn delete_object(&self, repo_name: &str, object_id: &str) -> Result<(), MegaError> {
+        self.inject("delete_object").await?;
+        self.inner.delete_object(repo_name, object_id).await
+    }
+
+    fn exist_object(&self, repo_name: &str, object_id: &str) -> bool {
+        self.inner.exist_object(repo_name, object_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use common::fault::{Fault, FaultInjector};
+
+    use crate::raw_storage::fault_storage::FaultRawStorage;
+    use crate::raw_storage::{local_storage::LocalStorage, RawStorage};
+
+    #[tokio::test]
+    async fn test_failed_put_writes_nothing() {
+        let dir = std::env::temp_dir().join(format!("fault_raw_{}", common::utils::generate_id()));
+        let faults = Arc::new(FaultInjector::new(3).with_fault("put_object", Fault::error(1.0)));
+        let storage = FaultRawStorage::new(Arc::new(LocalStorage::init(dir.clone())), faults);
+        let oid = "6ae8a75555209fd6c44157c0aed8016e763ff435a19cf186f76863140143ff72";
+
+        assert!(storage.put_object("repo", oid, b"content").await.is_err());
+        assert!(!storage.exist_object("repo", oid));
+        storage
+            .put_ref("repo", "refs/heads/main", oid)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_ref("repo", "refs/heads/main").await.unwrap(),
+            oid
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,5 @@
-use std::fs::{self, OpenOptions};
-use std::io::prelude::*;
+use std::fs;
+use std::io::{self, prelude::*};
 use std::path::{Path, PathBuf};
 
 use async_trait::async_trait;
@@ -7,6 +7,7 @@ use bytes::Bytes;
 
 use callisto::db_enums::StorageType;
 use common::errors::MegaError;
+use common::fs_utils;
 
 use crate::raw_storage::RawStorage;
 
@@ -30,7 +31,7 @@ impl RawStorage for LocalStorage {
 
     async fn get_ref(&self, repo_name: &str, ref_name: &str) -> Result<String, MegaError> {
         let path = Path::new(&self.base_path).join(repo_name).join(ref_name);
-        let mut file = fs::File::open(path)?;
+        let mut file = fs_utils::open_shared(&path)?;
         let mut buf = String::new();
         file.read_to_string(&mut buf)?;
         Ok(buf)
@@ -45,14 +46,13 @@ impl RawStorage for LocalStorage {
         let path = Path::new(&self.base_path).join(repo_name).join(ref_name);
         let parent = path.parent().unwrap();
         fs::create_dir_all(parent)?;
-        let mut file = fs::File::create(path)?;
-        file.write_all(ref_hash.as_bytes())?;
+        fs_utils::write_atomic(&path, ref_hash.as_bytes())?;
         Ok(())
     }
 
     async fn delete_ref(&self, repo_name: &str, ref_name: &str) -> Result<(), MegaError> {
         let path = Path::new(&self.base_path).join(repo_name).join(ref_name);
-        Ok(fs::remove_file(path)?)
+        Ok(fs_utils::retry(|| fs::remove_file(&path))?)
     }
 
     async fn update_ref(
@@ -62,8 +62,13 @@ impl RawStorage for LocalStorage {
         ref_hash: &str,
     ) -> Result<(), MegaError> {
         let path = Path::new(&self.base_path).join(repo_name).join(ref_name);
-        let mut file = OpenOptions::new().write(true).open(path).unwrap();
-        file.write_all(ref_hash.as_bytes()).unwrap();
+        if !path.exists() {
+            return Err(MegaError::with_message(&format!(
+                "ref {} not found",
+                ref_name
+            )));
+        }
+        fs_utils::write_atomic(&path, ref_hash.as_bytes())?;
         Ok(())
     }
 
@@ -72,10 +77,9 @@ impl RawStorage for LocalStorage {
             .join(repo_name)
             .join("objects")
             .join(self.transform_path(object_id));
-        let mut file =
-            fs::File::open(&path).unwrap_or_else(|_| panic!("Open file:{:?} failed!", path));
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
+        let buffer = fs_utils::read(&path).map_err(|e| {
+            MegaError::with_message(&format!("read object {} failed: {}", object_id, e))
+        })?;
         Ok(Bytes::from(buffer))
     }
 
@@ -90,13 +94,23 @@ impl RawStorage for LocalStorage {
             .join("objects")
             .join(self.transform_path(object_id));
         let dir = path.parent().unwrap();
-        fs::create_dir_all(dir).expect("Create directory failed!");
+        fs::create_dir_all(dir)?;
 
-        let mut file = fs::File::create(&path).expect("Open file failed");
-        file.write_all(body_content).expect("Write file failed");
+        fs_utils::write_atomic(&path, body_content)?;
         Ok(path.to_str().unwrap().to_string())
     }
 
+    async fn delete_object(&self, repo_name: &str, object_id: &str) -> Result<(), MegaError> {
+        let path = Path::new(&self.base_path)
+            .join(repo_name)
+            .join("objects")
+            .join(self.transform_path(object_id));
+        match fs_utils::retry(|| fs::remove_file(&path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
     fn exist_object(&self, repo_name: &str, object_id: &str) -> bool {
         let path = Path::new(&self.base_path)
             .join(repo_name)
@@ -127,6 +141,11 @@ mod tests {
         assert!(local_storage.put_object("", &oid, &content).await.is_ok());
 
         assert!(local_storage.exist_object("", &oid));
+
+        assert!(local_storage.delete_object("", &oid).await.is_ok());
+        assert!(!local_storage.exist_object("", &oid));
+        // an object removed already
+        assert!(local_storage.delete_object("", &oid).await.is_ok());
     }
 
     #[tokio::test]
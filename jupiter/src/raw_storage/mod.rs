@@ -12,11 +12,22 @@ use handlebars::Handlebars;
 
 use callisto::db_enums::StorageType;
 use common::errors::MegaError;
+use storage::driver::database::encrypted_storage::keys_from_env;
 use venus::internal::pack::entry::Entry;
 
+use crate::raw_storage::encrypted_storage::EncryptedStorage;
 use crate::raw_storage::local_storage::LocalStorage;
+use crate::raw_storage::replicated_storage::ReplicatedStorage;
+use crate::raw_storage::s3_storage::S3Storage;
+use crate::raw_storage::sharded_storage::ShardedStorage;
 
+pub mod encrypted_storage;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod fault_storage;
 pub mod local_storage;
+pub mod replicated_storage;
+pub mod s3_storage;
+pub mod sharded_storage;
 
 #[derive(Debug, Clone, Default)]
 pub struct BlobLink {
@@ -57,6 +68,9 @@ pub trait RawStorage: Sync + Send {
         body_content: &[u8],
     ) -> Result<String, MegaError>;
 
+    /// Removes the object, removing an object not there succeeds.
+    async fn delete_object(&self, repo_name: &str, object_id: &str) -> Result<(), MegaError>;
+
     // async fn parse_blob_link(&self, data: Vec<u8>) -> Result<BlobLink, MegaError> {
     //     let mut reader = BufReader::new(data.as_slice());
     //     let mut blink = BlobLink::default();
@@ -103,6 +117,12 @@ pub trait RawStorage: Sync + Send {
         Ok(rendered.into_bytes())
     }
 
+    /// Rewraps the data key of an encrypted object with the current key encryption key,
+    /// returns whether the object was rewritten. Nothing to do for a storage without encryption.
+    async fn rewrap_object(&self, _repo_name: &str, _object_id: &str) -> Result<bool, MegaError> {
+        Ok(false)
+    }
+
     fn exist_object(&self, repo_name: &str, object_id: &str) -> bool;
 
     fn transform_path(&self, sha1: &str) -> String {
@@ -120,14 +140,37 @@ pub trait RawStorage: Sync + Send {
 }
 
 pub async fn init(storage_type: String, path: String) -> Arc<dyn RawStorage> {
-    match storage_type.as_str() {
-        "LOCAL" => {
-            let base_path = PathBuf::from(path);
-            Arc::new(LocalStorage::init(base_path))
+    let storage: Arc<dyn RawStorage> = match storage_type.as_str() {
+        // every replica is one of the other storages
+        "REPLICATED" => {
+            let storage = Arc::new(ReplicatedStorage::init(PathBuf::from(path)).await);
+            ReplicatedStorage::spawn_repair_task(&storage);
+            storage
         }
+        other => init_backend(other, PathBuf::from(path)).await,
+    };
+    with_encryption(storage)
+}
+
+/// Encrypts the objects of `storage` at rest when `MEGA_ENCRYPTION_ENABLED` is set, see
+/// [`EncryptedStorage`].
+pub fn with_encryption(storage: Arc<dyn RawStorage>) -> Arc<dyn RawStorage> {
+    match keys_from_env() {
+        Some(keys) => Arc::new(EncryptedStorage::new(storage, keys)),
+        None => storage,
+    }
+}
+
+/// A storage keeping the objects in one place.
+pub async fn init_backend(storage_type: &str, path: PathBuf) -> Arc<dyn RawStorage> {
+    match storage_type {
+        "LOCAL" => Arc::new(LocalStorage::init(path)),
+        "SHARDED" => Arc::new(ShardedStorage::init(path)),
+        // the local path only holds the cache
+        "S3" => Arc::new(S3Storage::init(path).await),
         // "REMOTE" => Arc::new(RemoteStorage::init(path).await),
         _ => unreachable!(
-            "Not supported config, MEGA_OBJ_STORAGE_TYPE should be 'LOCAL' or 'REMOTE'"
+            "Not supported config, MEGA_RAW_STORAGE should be 'LOCAL', 'SHARDED', 'S3' or 'REPLICATED'"
         ),
     }
 }
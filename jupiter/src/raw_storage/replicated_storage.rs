@@ -0,0 +1,569 @@
+//!
+//! Objects written to several storages, e.g. the local disk and an S3 bucket, so that losing
+//! one of them loses nothing. A write succeeds once `acks` of the replicas took it, the
+//! replicas it missed are recorded and [`ReplicatedStorage::repair`] copies the object to them
+//! later. A read goes to the replicas in order, skipping the ones that are down, and a replica
+//! found without an object is repaired the same way. Configured by:
+//!
+//! - `MEGA_RAW_STORAGE_REPLICAS`: the replicas as `<type>:<path>` separated by commas, e.g.
+//!   `LOCAL:/data/objects,S3:/data/s3-cache`, the type being one of `MEGA_RAW_STORAGE`;
+//! - `MEGA_RAW_STORAGE_ACKS`: the replicas a write waits for, 1 by default.
+//!
+//! The objects to repair are only kept in memory, those missed before a restart are repaired
+//! once they are read.
+//!
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::join_all;
+
+use callisto::db_enums::StorageType;
+use common::errors::MegaError;
+
+use crate::raw_storage::{self, RawStorage};
+
+/// A replica failing this many calls in a row is down, the reads skip it.
+pub const DOWN_AFTER_FAILURES: u32 = 3;
+/// A replica down is tried again this long after its last failure.
+const RETRY_DOWN_AFTER: Duration = Duration::from_secs(30);
+/// Interval of the background repair.
+const REPAIR_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplicaHealth {
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub last_failure: Option<Instant>,
+    pub last_success: Option<Instant>,
+}
+
+impl ReplicaHealth {
+    pub fn is_down(&self) -> bool {
+        self.consecutive_failures >= DOWN_AFTER_FAILURES
+    }
+
+    fn is_usable(&self) -> bool {
+        !self.is_down()
+            || self
+                .last_failure
+                .is_none_or(|at| at.elapsed() >= RETRY_DOWN_AFTER)
+    }
+}
+
+pub struct Replica {
+    pub name: String,
+    pub storage: Arc<dyn RawStorage>,
+    health: Mutex<ReplicaHealth>,
+}
+
+impl Replica {
+    pub fn new(name: &str, storage: Arc<dyn RawStorage>) -> Self {
+        Replica {
+            name: name.to_owned(),
+            storage,
+            health: Mutex::new(ReplicaHealth::default()),
+        }
+    }
+
+    pub fn health(&self) -> ReplicaHealth {
+        self.health.lock().unwrap().clone()
+    }
+
+    fn record<T, E: Display>(&self, result: &Result<T, E>) {
+        let mut health = self.health.lock().unwrap();
+        match result {
+            Ok(_) => {
+                if health.is_down() {
+                    tracing::info!("replica {} is up again", self.name);
+                }
+                health.consecutive_failures = 0;
+                health.last_success = Some(Instant::now());
+            }
+            Err(err) => {
+                health.consecutive_failures += 1;
+                health.last_error = Some(err.to_string());
+                health.last_failure = Some(Instant::now());
+                if health.consecutive_failures == DOWN_AFTER_FAILURES {
+                    tracing::warn!("replica {} is down: {}", self.name, err);
+                }
+            }
+        }
+    }
+}
+
+/// What a repair did, the objects still missing a replica stay queued.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    pub repaired: usize,
+    pub pending: usize,
+}
+
+pub struct ReplicatedStorage {
+    replicas: Vec<Replica>,
+    acks: usize,
+    /// The replicas missing an object, by repository and object id.
+    pending: Mutex<BTreeMap<(String, String), BTreeSet<usize>>>,
+}
+
+impl ReplicatedStorage {
+    /// A storage writing to `replicas`, a write needing `acks` of them, at least one.
+    pub fn new(replicas: Vec<Replica>, acks: usize) -> Result<Self, MegaError> {
+        if replicas.is_empty() {
+            return Err(MegaError::with_message(
+                "a replicated storage needs a replica",
+            ));
+        }
+        if acks == 0 || acks > replicas.len() {
+            return Err(MegaError::with_message(&format!(
+                "a write can not wait for {} of {} replica(s)",
+                acks,
+                replicas.len()
+            )));
+        }
+        Ok(ReplicatedStorage {
+            replicas,
+            acks,
+            pending: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// The storage configured by the environment, see the module documentation. A replica
+    /// without a path is kept under `path`.
+    pub async fn init(path: PathBuf) -> ReplicatedStorage {
+        let specs = env::var("MEGA_RAW_STORAGE_REPLICAS")
+            .expect("MEGA_RAW_STORAGE_REPLICAS not configured");
+        let mut replicas = Vec::new();
+        for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (storage_type, replica_path) = match spec.split_once(':') {
+                Some((storage_type, replica_path)) => (storage_type, PathBuf::from(replica_path)),
+                None => (spec, path.clone()),
+            };
+            let storage = raw_storage::init_backend(storage_type, replica_path).await;
+            replicas.push(Replica::new(spec, storage));
+        }
+        let acks = env::var("MEGA_RAW_STORAGE_ACKS")
+            .ok()
+            .and_then(|acks| acks.parse().ok())
+            .unwrap_or(1);
+        ReplicatedStorage::new(replicas, acks)
+            .unwrap_or_else(|e| panic!("invalid MEGA_RAW_STORAGE_REPLICAS: {}", e))
+    }
+
+    pub fn replicas(&self) -> &[Replica] {
+        &self.replicas
+    }
+
+    /// The number of objects missing a replica.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    fn enqueue(&self, repo_name: &str, object_id: &str, missing: BTreeSet<usize>) {
+        if missing.is_empty() {
+            return;
+        }
+        self.pending
+            .lock()
+            .unwrap()
+            .entry((repo_name.to_owned(), object_id.to_owned()))
+            .or_default()
+            .extend(missing);
+    }
+
+    /// Runs `write` on every replica at once, it succeeds when `acks` of them did. The first
+    /// error is returned otherwise.
+    async fn write_all<'a, F, Fut, T>(
+        &'a self,
+        write: F,
+    ) -> Result<Vec<Result<T, MegaError>>, MegaError>
+    where
+        F: Fn(&'a Replica) -> Fut,
+        Fut: std::future::Future<Output = Result<T, MegaError>>,
+    {
+        let results = join_all(self.replicas.iter().map(&write)).await;
+        for (replica, result) in self.replicas.iter().zip(&results) {
+            replica.record(result);
+        }
+        let acked = results.iter().filter(|result| result.is_ok()).count();
+        if acked < self.acks {
+            let err = results
+                .iter()
+                .find_map(|result| result.as_ref().err())
+                .map(|err| err.to_string())
+                .unwrap_or_default();
+            return Err(MegaError::with_message(&format!(
+                "{} of {} replica(s) acknowledged the write, {} needed: {}",
+                acked,
+                self.replicas.len(),
+                self.acks,
+                err
+            )));
+        }
+        Ok(results)
+    }
+
+    /// Copies the queued objects to the replicas missing them, from a replica that has them.
+    pub async fn repair(&self) -> RepairReport {
+        let queued: Vec<_> = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, missing)| (key.clone(), missing.clone()))
+            .collect();
+        let mut report = RepairReport::default();
+        for ((repo_name, object_id), missing) in queued {
+            let source = self
+                .replicas
+                .iter()
+                .enumerate()
+                .filter(|(index, replica)| !missing.contains(index) && replica.health().is_usable())
+                .map(|(_, replica)| replica);
+            let mut data = None;
+            for replica in source {
+                let read = replica.storage.get_object(&repo_name, &object_id).await;
+                replica.record(&read);
+                if let Ok(read) = read {
+                    data = Some(read);
+                    break;
+                }
+            }
+            let Some(data) = data else {
+                tracing::warn!("no replica to repair {} of {} from", object_id, repo_name);
+                continue;
+            };
+            let mut repaired = BTreeSet::new();
+            for index in missing {
+                let replica = &self.replicas[index];
+                let written = replica
+                    .storage
+                    .put_object(&repo_name, &object_id, &data)
+                    .await;
+                replica.record(&written);
+                if written.is_ok() {
+                    repaired.insert(index);
+                }
+            }
+            report.repaired += repaired.len();
+            let mut pending = self.pending.lock().unwrap();
+            let key = (repo_name, object_id);
+            if let Some(queued) = pending.get_mut(&key) {
+                queued.retain(|index| !repaired.contains(index));
+                if queued.is_empty() {
+                    pending.remove(&key);
+                }
+            }
+        }
+        report.pending = self.pending();
+        report
+    }
+
+    /// Repairs the queued objects every minute, for as long as the storage is in use.
+    pub fn spawn_repair_task(storage: &Arc<ReplicatedStorage>) {
+        let storage = Arc::downgrade(storage);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REPAIR_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(storage) = storage.upgrade() else {
+                    break;
+                };
+                if storage.pending() == 0 {
+                    continue;
+                }
+                let report = storage.repair().await;
+                tracing::info!(
+                    "repaired {} object replica(s), {} object(s) still under-replicated",
+                    report.repaired,
+                    report.pending
+                );
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl RawStorage for ReplicatedStorage {
+    fn get_storage_type(&self) -> StorageType {
+        self.replicas[0].storage.get_storage_type()
+    }
+
+    async fn get_ref(&self, repo_name: &str, ref_name: &str) -> Result<String, MegaError> {
+        let mut last_err = None;
+        for replica in self.replicas.iter().filter(|r| r.health().is_usable()) {
+            let read = replica.storage.get_ref(repo_name, ref_name).await;
+            replica.record(&read);
+            match read {
+                Ok(ref_hash) => return Ok(ref_hash),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or(MegaError::with_message("every replica is down")))
+    }
+
+    async fn put_ref(
+        &self,
+        repo_name: &str,
+        ref_name: &str,
+        ref_hash: &str,
+    ) -> Result<(), MegaError> {
+        self.write_all(|replica| replica.storage.put_ref(repo_name, ref_name, ref_hash))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_ref(&self, repo_name: &str, ref_name: &str) -> Result<(), MegaError> {
+        self.write_all(|replica| replica.storage.delete_ref(repo_name, ref_name))
+            .await?;
+        Ok(())
+    }
+
+    async fn update_ref(
+        &self,
+        repo_name: &str,
+        ref_name: &str,
+        ref_hash: &str,
+    ) -> Result<(), MegaError> {
+        self.write_all(|replica| replica.storage.update_ref(repo_name, ref_name, ref_hash))
+            .await?;
+        Ok(())
+    }
+
+    async fn get_object(&self, repo_name: &str, object_id: &str) -> Result<Bytes, MegaError> {
+        let mut missing = BTreeSet::new();
+        let mut last_err = None;
+        for (index, replica) in self.replicas.iter().enumerate() {
+            if !replica.health().is_usable() {
+                continue;
+            }
+            let read = replica.storage.get_object(repo_name, object_id).await;
+            // a replica without the object is not failing
+            if read.is_err() && !replica.storage.exist_object(repo_name, object_id) {
+                missing.insert(index);
+                continue;
+            }
+            replica.record(&read);
+            match read {
+                Ok(data) => {
+                    self.enqueue(repo_name, object_id, missing);
+                    return Ok(data);
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or(MegaError::with_message(&format!(
+            "object {} not found",
+            object_id
+        ))))
+    }
+
+    async fn put_object(
+        &self,
+        repo_name: &str,
+        object_id: &str,
+        body_content: &[u8],
+    ) -> Result<String, MegaError> {
+        let results = self
+            .write_all(|replica| {
+                replica
+                    .storage
+                    .put_object(repo_name, object_id, body_content)
+            })
+            .await?;
+        let missing = results
+            .iter()
+            .enumerate()
+            .filter(|(_, result)| result.is_err())
+            .map(|(index, _)| index)
+            .collect();
+        self.enqueue(repo_name, object_id, missing);
+        // the location in the first replica that took it
+        Ok(results.into_iter().find_map(Result::ok).unwrap())
+    }
+
+    /// A replica the delete missed keeps its copy, nothing refers to it anymore.
+    async fn delete_object(&self, repo_name: &str, object_id: &str) -> Result<(), MegaError> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(&(repo_name.to_owned(), object_id.to_owned()));
+        self.write_all(|replica| replica.storage.delete_object(repo_name, object_id))
+            .await?;
+        Ok(())
+    }
+
+    fn exist_object(&self, repo_name: &str, object_id: &str) -> bool {
+        self.replicas
+            .iter()
+            .any(|replica| replica.storage.exist_object(repo_name, object_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+
+    use callisto::db_enums::StorageType;
+    use common::errors::MegaError;
+
+    use crate::raw_storage::local_storage::LocalStorage;
+    use crate::raw_storage::replicated_storage::{
+        RepairReport, Replica, ReplicatedStorage, DOWN_AFTER_FAILURES,
+    };
+    use crate::raw_storage::RawStorage;
+
+    /// A local storage whose writes and reads fail while it is down.
+    struct Flaky {
+        inner: LocalStorage,
+        down: AtomicBool,
+    }
+
+    impl Flaky {
+        fn check(&self) -> Result<(), MegaError> {
+            match self.down.load(Ordering::SeqCst) {
+                true => Err(MegaError::with_message("replica unreachable")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl RawStorage for Flaky {
+        fn get_storage_type(&self) -> StorageType {
+            self.inner.get_storage_type()
+        }
+
+        async fn get_ref(&self, repo_name: &str, ref_name: &str) -> Result<String, MegaError> {
+            self.check()?;
+            self.inner.get_ref(repo_name, ref_name).await
+        }
+
+        async fn put_ref(&self, repo: &str, name: &str, hash: &str) -> Result<(), MegaError> {
+            self.check()?;
+            self.inner.put_ref(repo, name, hash).await
+        }
+
+        async fn delete_ref(&self, repo_name: &str, ref_name: &str) -> Result<(), MegaError> {
+            self.check()?;
+            self.inner.delete_ref(repo_name, ref_name).await
+        }
+
+        async fn update_ref(&self, repo: &str, name: &str, hash: &str) -> Result<(), MegaError> {
+            self.check()?;
+            self.inner.update_ref(repo, name, hash).await
+        }
+
+        async fn get_object(&self, repo_name: &str, object_id: &str) -> Result<Bytes, MegaError> {
+            self.check()?;
+            self.inner.get_object(repo_name, object_id).await
+        }
+
+        async fn put_object(
+            &self,
+            repo_name: &str,
+            object_id: &str,
+            body_content: &[u8],
+        ) -> Result<String, MegaError> {
+            self.check()?;
+            self.inner
+                .put_object(repo_name, object_id, body_content)
+                .await
+        }
+
+        async fn delete_object(&self, repo_name: &str, object_id: &str) -> Result<(), MegaError> {
+            self.check()?;
+            self.inner.delete_object(repo_name, object_id).await
+        }
+
+        fn exist_object(&self, repo_name: &str, object_id: &str) -> bool {
+            self.inner.exist_object(repo_name, object_id)
+        }
+    }
+
+    fn flaky(root: &Path, name: &str) -> Arc<Flaky> {
+        Arc::new(Flaky {
+            inner: LocalStorage::init(root.join(name)),
+            down: AtomicBool::new(false),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_write_acks_and_repair() {
+        let root = env::temp_dir().join(format!("mega-replicas-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (a, b) = (flaky(&root, "a"), flaky(&root, "b"));
+        let replicas = vec![Replica::new("a", a.clone()), Replica::new("b", b.clone())];
+        let storage = ReplicatedStorage::new(replicas, 1).unwrap();
+        let oid = "6ae8a75555209fd6c44157c0aed8016e763ff435";
+
+        b.down.store(true, Ordering::SeqCst);
+        storage.put_object("repo", oid, b"content").await.unwrap();
+        assert!(!b.exist_object("repo", oid));
+        assert_eq!(storage.pending(), 1);
+        assert_eq!(
+            storage.repair().await,
+            RepairReport {
+                repaired: 0,
+                pending: 1
+            }
+        );
+        // down after failing in a row, the reads skip it
+        storage.put_object("repo", "ab12", b"other").await.unwrap();
+        let health = storage.replicas()[1].health();
+        assert_eq!(health.consecutive_failures, DOWN_AFTER_FAILURES);
+        assert!(health.is_down());
+        assert_eq!(storage.get_object("repo", oid).await.unwrap(), "content");
+
+        b.down.store(false, Ordering::SeqCst);
+        a.down.store(true, Ordering::SeqCst);
+        // two acks are needed from now on
+        let strict = ReplicatedStorage::new(
+            vec![Replica::new("a", a.clone()), Replica::new("b", b.clone())],
+            2,
+        )
+        .unwrap();
+        assert!(strict.put_object("repo", "cd34", b"lost").await.is_err());
+        a.down.store(false, Ordering::SeqCst);
+
+        let report = storage.repair().await;
+        assert_eq!(report.repaired, 2);
+        assert_eq!(report.pending, 0);
+        assert_eq!(b.get_object("repo", oid).await.unwrap(), "content");
+        assert_eq!(storage.replicas()[1].health().consecutive_failures, 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_repairs_a_missing_object() {
+        let root = env::temp_dir().join(format!("mega-read-repair-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (a, b) = (flaky(&root, "a"), flaky(&root, "b"));
+        let oid = "27dd8d4cf39f3868c6eee38b601bc9e9939304f5";
+        // written before the replica was added
+        b.put_object("repo", oid, b"content").await.unwrap();
+        let replicas = vec![Replica::new("a", a.clone()), Replica::new("b", b.clone())];
+        let storage = ReplicatedStorage::new(replicas, 2).unwrap();
+
+        assert_eq!(storage.get_object("repo", oid).await.unwrap(), "content");
+        assert_eq!(storage.replicas()[0].health().consecutive_failures, 0);
+        assert_eq!(storage.pending(), 1);
+        assert_eq!(storage.repair().await.repaired, 1);
+        assert!(a.exist_object("repo", oid));
+        assert!(storage.get_object("repo", "00ab").await.is_err());
+        assert!(ReplicatedStorage::new(vec![], 1).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,561 @@
+//!
+//! Objects kept in an S3 compatible object storage, AWS S3 or MinIO, for the deployments that
+//! can not keep their packs and large blobs on local disks. The objects are stored under
+//! `<prefix>/<repo_name>/objects/` with the layout of a [`LocalStorage`], the refs under
+//! `<prefix>/<repo_name>/`. Configured by:
+//!
+//! - `MEGA_OBJ_S3_BUCKET`, `MEGA_OBJ_S3_PREFIX`;
+//! - `MEGA_OBJ_S3_REGION`, `MEGA_OBJ_S3_ENDPOINT` for another storage than AWS, and
+//!   `MEGA_OBJ_S3_PATH_STYLE` for MinIO, the credentials being the usual AWS ones;
+//! - `MEGA_OBJ_S3_MULTIPART_MB`: the objects this large are uploaded in parts;
+//! - `MEGA_OBJ_S3_CACHE_MB`: the objects read and written lately are kept on the local disk,
+//!   under the local path of the storage, up to this size.
+//!
+//! [`LocalStorage`]: super::local_storage::LocalStorage
+//!
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::config::Region;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use bytes::Bytes;
+
+use callisto::db_enums::StorageType;
+use common::errors::MegaError;
+use common::fs_utils;
+
+use crate::raw_storage::RawStorage;
+
+/// Size of the parts of a multipart upload, S3 takes parts of 5 MiB at least.
+const PART_SIZE: usize = 16 * 1024 * 1024;
+
+fn s3_error(e: impl std::fmt::Display) -> MegaError {
+    MegaError::with_message(&format!("s3: {}", e))
+}
+
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+    prefix: String,
+    multipart_threshold: usize,
+    cache: Option<DiskCache>,
+}
+
+impl S3Storage {
+    /// The storage configured by the environment, caching under `cache_path`.
+    pub async fn init(cache_path: PathBuf) -> S3Storage {
+        let var = |name| {
+            env::var(name)
+                .ok()
+                .filter(|value: &String| !value.is_empty())
+        };
+        let bucket = var("MEGA_OBJ_S3_BUCKET").expect("MEGA_OBJ_S3_BUCKET not configured");
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = var("MEGA_OBJ_S3_REGION") {
+            loader = loader.region(Region::new(region));
+        }
+        if let Some(endpoint) = var("MEGA_OBJ_S3_ENDPOINT") {
+            loader = loader.endpoint_url(endpoint);
+        }
+        let path_style = var("MEGA_OBJ_S3_PATH_STYLE").is_some_and(|value| value == "true");
+        let config = aws_sdk_s3::config::Builder::from(&loader.load().await)
+            .force_path_style(path_style)
+            .build();
+        let mb = |name, default: usize| {
+            var(name)
+                .and_then(|value| value.parse::<usize>().ok())
+                .unwrap_or(default)
+                * 1024
+                * 1024
+        };
+        let cache_size = mb("MEGA_OBJ_S3_CACHE_MB", 1024);
+        S3Storage {
+            client: Client::from_conf(config),
+            bucket,
+            prefix: var("MEGA_OBJ_S3_PREFIX").unwrap_or_default(),
+            multipart_threshold: mb("MEGA_OBJ_S3_MULTIPART_MB", 64).max(PART_SIZE),
+            cache: (cache_size > 0)
+                .then(|| DiskCache::open(&cache_path.join("s3-cache"), cache_size)),
+        }
+    }
+
+    fn key(&self, path: &str) -> String {
+        let prefix = self.prefix.trim_matches('/');
+        if prefix.is_empty() {
+            path.to_owned()
+        } else {
+            format!("{}/{}", prefix, path)
+        }
+    }
+
+    fn object_key(&self, repo_name: &str, object_id: &str) -> String {
+        self.key(&format!(
+            "{}/objects/{}",
+            repo_name,
+            self.transform_path(object_id).replace('\\', "/")
+        ))
+    }
+
+    fn ref_key(&self, repo_name: &str, ref_name: &str) -> String {
+        self.key(&format!("{}/{}", repo_name, ref_name))
+    }
+
+    /// The content of `key`, None if there is no such object.
+    async fn download(&self, key: &str) -> Result<Option<Bytes>, MegaError> {
+        let res = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await;
+        let output = match res {
+            Ok(output) => output,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+            Err(e) => return Err(s3_error(e)),
+        };
+        let data = output.body.collect().await.map_err(s3_error)?;
+        Ok(Some(data.into_bytes()))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, MegaError> {
+        let res = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await;
+        match res {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+            Err(e) => Err(s3_error(e)),
+        }
+    }
+
+    async fn upload(&self, key: &str, data: &[u8]) -> Result<(), MegaError> {
+        if data.len() >= self.multipart_threshold {
+            return self.upload_multipart(key, data).await;
+        }
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(data.to_vec()))
+            .send()
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    /// Uploads `data` in parts, the upload is aborted if a part fails so that S3 does not keep
+    /// the parts sent.
+    async fn upload_multipart(&self, key: &str, data: &[u8]) -> Result<(), MegaError> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(s3_error)?;
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| s3_error("no id for the multipart upload"))?;
+        let res = self.upload_parts(key, upload_id, data).await;
+        if res.is_err() {
+            let abort = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await;
+            if let Err(e) = abort {
+                tracing::error!("failed to abort the upload of {}: {}", key, e);
+            }
+        }
+        res
+    }
+
+    async fn upload_parts(&self, key: &str, upload_id: &str, data: &[u8]) -> Result<(), MegaError> {
+        let mut parts = Vec::new();
+        for (n, chunk) in data.chunks(PART_SIZE).enumerate() {
+            let part_number = n as i32 + 1;
+            let part = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(chunk.to_vec()))
+                .send()
+                .await
+                .map_err(s3_error)?;
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(part.e_tag().map(str::to_owned))
+                    .part_number(part_number)
+                    .build(),
+            );
+        }
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RawStorage for S3Storage {
+    fn get_storage_type(&self) -> StorageType {
+        StorageType::RemoteUrl
+    }
+
+    async fn get_ref(&self, repo_name: &str, ref_name: &str) -> Result<String, MegaError> {
+        let data = self
+            .download(&self.ref_key(repo_name, ref_name))
+            .await?
+            .ok_or_else(|| MegaError::with_message(&format!("ref {} not found", ref_name)))?;
+        String::from_utf8(data.to_vec()).map_err(s3_error)
+    }
+
+    async fn put_ref(
+        &self,
+        repo_name: &str,
+        ref_name: &str,
+        ref_hash: &str,
+    ) -> Result<(), MegaError> {
+        self.upload(&self.ref_key(repo_name, ref_name), ref_hash.as_bytes())
+            .await
+    }
+
+    async fn delete_ref(&self, repo_name: &str, ref_name: &str) -> Result<(), MegaError> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.ref_key(repo_name, ref_name))
+            .send()
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    async fn update_ref(
+        &self,
+        repo_name: &str,
+        ref_name: &str,
+        ref_hash: &str,
+    ) -> Result<(), MegaError> {
+        let key = self.ref_key(repo_name, ref_name);
+        if !self.exists(&key).await? {
+            return Err(MegaError::with_message(&format!(
+                "ref {} not found",
+                ref_name
+            )));
+        }
+        self.upload(&key, ref_hash.as_bytes()).await
+    }
+
+    async fn get_object(&self, repo_name: &str, object_id: &str) -> Result<Bytes, MegaError> {
+        let key = self.object_key(repo_name, object_id);
+        if let Some(data) = self.cache.as_ref().and_then(|cache| cache.get(&key)) {
+            return Ok(data);
+        }
+        let data = self
+            .download(&key)
+            .await?
+            .ok_or_else(|| MegaError::with_message(&format!("object {} not found", key)))?;
+        if let Some(cache) = &self.cache {
+            cache.insert(&key, &data);
+        }
+        Ok(data)
+    }
+
+    async fn put_object(
+        &self,
+        repo_name: &str,
+        object_id: &str,
+        body_content: &[u8],
+    ) -> Result<String, MegaError> {
+        let key = self.object_key(repo_name, object_id);
+        self.upload(&key, body_content).await?;
+        if let Some(cache) = &self.cache {
+            cache.insert(&key, body_content);
+        }
+        Ok(format!("s3://{}/{}", self.bucket, key))
+    }
+
+    async fn delete_object(&self, repo_name: &str, object_id: &str) -> Result<(), MegaError> {
+        let key = self.object_key(repo_name, object_id);
+        // S3 answers a delete of a missing key with a success
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .send()
+            .await
+            .map_err(s3_error)?;
+        if let Some(cache) = &self.cache {
+            cache.delete(&key);
+        }
+        Ok(())
+    }
+
+    /// Asks the object storage when the object is not cached, blocking the worker thread
+    /// meanwhile: the storage has to run on a multi-threaded runtime.
+    fn exist_object(&self, repo_name: &str, object_id: &str) -> bool {
+        let key = self.object_key(repo_name, object_id);
+        if self
+            .cache
+            .as_ref()
+            .is_some_and(|cache| cache.contains(&key))
+        {
+            return true;
+        }
+        let handle = tokio::runtime::Handle::current();
+        tokio::task::block_in_place(|| handle.block_on(self.exists(&key))).unwrap_or_else(|e| {
+            tracing::error!("failed to look {} up: {}", key, e);
+            false
+        })
+    }
+}
+
+/// The objects read or written lately, in files under `dir`. The least recently used are
+/// removed once they take more than `capacity` bytes, the objects larger than that are not
+/// kept. The objects are never changed once stored, a file cached is never stale.
+pub struct DiskCache {
+    dir: PathBuf,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// The size and the last use of the files by key.
+    entries: HashMap<String, (usize, u64)>,
+    /// The keys by last use.
+    uses: BTreeMap<u64, String>,
+    size: usize,
+    clock: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &str) -> bool {
+        let Some((_, used)) = self.entries.get_mut(key) else {
+            return false;
+        };
+        self.uses.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.uses.insert(self.clock, key.to_owned());
+        true
+    }
+
+    fn insert(&mut self, key: String, size: usize) {
+        if self.touch(&key) {
+            return;
+        }
+        self.clock += 1;
+        self.size += size;
+        self.uses.insert(self.clock, key.clone());
+        self.entries.insert(key, (size, self.clock));
+    }
+
+    /// The least recently used key, removed.
+    fn pop(&mut self) -> Option<String> {
+        let (_, key) = self.uses.pop_first()?;
+        if let Some((size, _)) = self.entries.remove(&key) {
+            self.size -= size;
+        }
+        Some(key)
+    }
+}
+
+impl DiskCache {
+    /// The cache in `dir`, with the files left there by a previous run, the oldest first.
+    pub fn open(dir: &Path, capacity: usize) -> DiskCache {
+        let cache = DiskCache {
+            dir: dir.to_path_buf(),
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        };
+        let mut files = Vec::new();
+        if let Err(e) = cache.scan(dir, &mut files) {
+            tracing::warn!("failed to read the cache {}: {}", dir.display(), e);
+        }
+        files.sort_by_key(|(_, _, modified)| *modified);
+        let mut state = cache.state.lock().unwrap();
+        for (key, size, _) in files {
+            state.insert(key, size);
+        }
+        drop(state);
+        cache.evict();
+        cache
+    }
+
+    fn scan(
+        &self,
+        dir: &Path,
+        files: &mut Vec<(String, usize, std::time::SystemTime)>,
+    ) -> io::Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                self.scan(&entry.path(), files)?;
+            } else if let Ok(key) = entry.path().strip_prefix(&self.dir) {
+                let key = key.to_string_lossy().replace('\\', "/");
+                files.push((key, metadata.len() as usize, metadata.modified()?));
+            }
+        }
+        Ok(())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.state.lock().unwrap().entries.contains_key(key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        if !self.state.lock().unwrap().touch(key) {
+            return None;
+        }
+        match fs_utils::read(&self.path(key)) {
+            Ok(data) => Some(Bytes::from(data)),
+            Err(_) => {
+                // removed behind our back
+                self.remove(key);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &str, data: &[u8]) {
+        if data.len() > self.capacity || self.contains(key) {
+            return;
+        }
+        let path = self.path(key);
+        let written = fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| fs_utils::write_atomic(&path, data));
+        if let Err(e) = written {
+            tracing::warn!("failed to cache {}: {}", key, e);
+            return;
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), data.len());
+        self.evict();
+    }
+
+    fn remove(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((size, used)) = state.entries.remove(key) {
+            state.uses.remove(&used);
+            state.size -= size;
+        }
+    }
+
+    /// Removes `key` and its file, once the object is deleted.
+    pub fn delete(&self, key: &str) {
+        self.remove(key);
+        if let Err(e) = fs::remove_file(self.path(key)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                tracing::warn!("failed to remove {} from the cache: {}", key, e);
+            }
+        }
+    }
+
+    fn evict(&self) {
+        loop {
+            let key = {
+                let mut state = self.state.lock().unwrap();
+                if state.size <= self.capacity {
+                    return;
+                }
+                state.pop()
+            };
+            let Some(key) = key else {
+                return;
+            };
+            if let Err(e) = fs::remove_file(self.path(&key)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    tracing::warn!("failed to remove {} from the cache: {}", key, e);
+                }
+            }
+        }
+    }
+
+    /// The bytes cached.
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use common::utils::generate_id;
+
+    use super::DiskCache;
+
+    #[test]
+    fn test_disk_cache() {
+        let dir = std::env::temp_dir().join(format!("s3_cache_{}", generate_id()));
+        let cache = DiskCache::open(&dir, 10);
+        cache.insert("repo/objects/aa/bb/1", b"1234");
+        cache.insert("repo/objects/aa/bb/2", b"5678");
+        assert_eq!(cache.size(), 8);
+        // 1 is used, 2 is the least recently used then
+        assert_eq!(cache.get("repo/objects/aa/bb/1").unwrap().as_ref(), b"1234");
+        cache.insert("repo/objects/aa/bb/3", b"90");
+        cache.insert("repo/objects/aa/bb/4", b"ab");
+        assert!(!cache.contains("repo/objects/aa/bb/2"));
+        assert!(!dir.join("repo/objects/aa/bb/2").exists());
+        assert!(cache.contains("repo/objects/aa/bb/1"));
+        assert_eq!(cache.size(), 8);
+        // larger than the cache
+        cache.insert("repo/objects/aa/bb/5", &[0; 11]);
+        assert!(cache.get("repo/objects/aa/bb/5").is_none());
+
+        // the files are found again, the oldest removed first
+        let reopened = DiskCache::open(&dir, 6);
+        assert_eq!(reopened.size(), 4);
+        assert!(!reopened.contains("repo/objects/aa/bb/1"));
+        assert_eq!(
+            reopened.get("repo/objects/aa/bb/4").unwrap().as_ref(),
+            b"ab"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
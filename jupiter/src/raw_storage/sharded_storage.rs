@@ -0,0 +1,542 @@
+//!
+//! Objects spread over several shards, e.g. directories on different mounts, by the prefix of
+//! their id. The first `prefix_len` hex digits of an id select one of the `16^prefix_len`
+//! buckets, and the manifest `shards.json` at the root of the storage assigns every bucket to
+//! a shard. Every shard has the layout of a [`LocalStorage`], so a local storage is a storage
+//! of one shard. The refs are kept by the first shard.
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "prefix_len": 2,
+//!   "shards": [
+//!     { "name": "0", "path": "/mnt/a/objects" },
+//!     { "name": "1", "path": "/mnt/b/objects" }
+//!   ],
+//!   "buckets": [0, 1, 0, 1, ...],
+//!   "moving": [{ "bucket": 1, "from": 0, "to": 1 }]
+//! }
+//! ```
+//!
+//! Adding a shard reassigns some buckets to it, as many as balances the shards, and records
+//! them as moving. The objects of a moving bucket are written to its new shard and read from
+//! both until [`ShardedStorage::rebalance`] moved them, while the storage is in use: the
+//! manifest is read again whenever it changes.
+//!
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use callisto::db_enums::StorageType;
+use common::errors::MegaError;
+use common::fs_utils;
+
+use crate::raw_storage::local_storage::LocalStorage;
+use crate::raw_storage::RawStorage;
+
+pub const MANIFEST_FILE: &str = "shards.json";
+pub const MANIFEST_VERSION: u32 = 1;
+/// Hex digits of the id selecting the bucket of an object, 256 buckets.
+pub const DEFAULT_PREFIX_LEN: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// A bucket reassigned to the shard `to`, whose objects are still in the shard `from`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketMove {
+    pub bucket: usize,
+    pub from: usize,
+    pub to: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardManifest {
+    pub version: u32,
+    pub prefix_len: usize,
+    pub shards: Vec<Shard>,
+    /// The shard of every bucket, by the number its prefix stands for.
+    pub buckets: Vec<usize>,
+    #[serde(default)]
+    pub moving: Vec<BucketMove>,
+}
+
+impl ShardManifest {
+    /// The buckets spread evenly over `shards`, with a prefix of 1 to 4 hex digits.
+    pub fn new(shards: Vec<Shard>, prefix_len: usize) -> Result<Self, MegaError> {
+        if shards.is_empty() {
+            return Err(MegaError::with_message("a sharded storage needs a shard"));
+        }
+        if !(1..=4).contains(&prefix_len) {
+            return Err(MegaError::with_message(&format!(
+                "the prefix of the buckets has {} hex digits, not 1 to 4",
+                prefix_len
+            )));
+        }
+        let buckets = (0..1 << (4 * prefix_len))
+            .map(|b| b % shards.len())
+            .collect();
+        Ok(ShardManifest {
+            version: MANIFEST_VERSION,
+            prefix_len,
+            shards,
+            buckets,
+            moving: vec![],
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Self, MegaError> {
+        let manifest: ShardManifest = serde_json::from_slice(&fs_utils::read(path)?)
+            .map_err(|e| MegaError::with_message(&format!("{}: {}", path.display(), e)))?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(MegaError::with_message(&format!(
+                "{}: version {} of the manifest is not supported",
+                path.display(),
+                manifest.version
+            )));
+        }
+        let shards = manifest.shards.len();
+        if manifest.buckets.len() != 1 << (4 * manifest.prefix_len)
+            || manifest.buckets.iter().any(|shard| *shard >= shards)
+            || manifest
+                .moving
+                .iter()
+                .any(|m| m.from >= shards || m.to >= shards)
+        {
+            return Err(MegaError::with_message(&format!(
+                "{}: the buckets do not match the shards",
+                path.display()
+            )));
+        }
+        Ok(manifest)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), MegaError> {
+        let data = serde_json::to_vec_pretty(self).unwrap();
+        Ok(fs_utils::write_atomic(path, &data)?)
+    }
+
+    /// The bucket of `object_id`, the first one for an id too short or not in hex.
+    pub fn bucket(&self, object_id: &str) -> usize {
+        object_id
+            .get(..self.prefix_len)
+            .and_then(|prefix| usize::from_str_radix(prefix, 16).ok())
+            .unwrap_or(0)
+    }
+
+    /// The shard `object_id` is written to.
+    pub fn shard(&self, object_id: &str) -> &Shard {
+        &self.shards[self.buckets[self.bucket(object_id)]]
+    }
+
+    /// The shards `object_id` may be in, the one it is written to first.
+    pub fn locations(&self, object_id: &str) -> Vec<&Shard> {
+        let bucket = self.bucket(object_id);
+        let mut shards = vec![&self.shards[self.buckets[bucket]]];
+        if let Some(m) = self.moving.iter().find(|m| m.bucket == bucket) {
+            shards.push(&self.shards[m.from]);
+        }
+        shards
+    }
+
+    /// The number of buckets of every shard.
+    pub fn bucket_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.shards.len()];
+        for shard in &self.buckets {
+            counts[*shard] += 1;
+        }
+        counts
+    }
+
+    /// Adds `shard` and moves to it buckets of the shards with the most of them, until it has
+    /// as many as they do, give or take one. Refused while buckets are still moving.
+    pub fn add_shard(&mut self, shard: Shard) -> Result<Vec<BucketMove>, MegaError> {
+        if !self.moving.is_empty() {
+            return Err(MegaError::with_message(&format!(
+                "{} bucket(s) are still moving, rebalance the storage first",
+                self.moving.len()
+            )));
+        }
+        if self
+            .shards
+            .iter()
+            .any(|s| s.name == shard.name || s.path == shard.path)
+        {
+            return Err(MegaError::with_message(&format!(
+                "the shard {} is already in the storage",
+                shard.name
+            )));
+        }
+        self.shards.push(shard);
+        let to = self.shards.len() - 1;
+        let target = self.buckets.len() / self.shards.len();
+        let mut counts = self.bucket_counts();
+        let mut moves = vec![];
+        while counts[to] < target {
+            let from = (0..to)
+                .max_by_key(|s| (counts[*s], usize::MAX - s))
+                .unwrap();
+            // the last bucket of the shard, so the others keep theirs in order
+            let bucket = self.buckets.iter().rposition(|s| *s == from).unwrap();
+            self.buckets[bucket] = to;
+            counts[from] -= 1;
+            counts[to] += 1;
+            moves.push(BucketMove { bucket, from, to });
+        }
+        moves.sort_by_key(|m| m.bucket);
+        self.moving = moves.clone();
+        Ok(moves)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RebalanceReport {
+    pub buckets: usize,
+    pub objects: usize,
+}
+
+/// The modification time and the size of a file.
+type FileVersion = (SystemTime, u64);
+
+pub struct ShardedStorage {
+    root: PathBuf,
+    /// The manifest read, with the modification time and the size of its file then.
+    manifest: RwLock<(Option<FileVersion>, Arc<ShardManifest>)>,
+}
+
+impl ShardedStorage {
+    /// Opens the storage whose manifest is in `root`, or makes `root` a storage of one shard.
+    pub fn init(root: PathBuf) -> ShardedStorage {
+        fs::create_dir_all(&root).expect("Create directory failed!");
+        let path = root.join(MANIFEST_FILE);
+        if !path.exists() {
+            let shard = Shard {
+                name: String::from("0"),
+                path: root.clone(),
+            };
+            ShardManifest::new(vec![shard], DEFAULT_PREFIX_LEN)
+                .and_then(|manifest| manifest.write(&path))
+                .expect("Write the manifest of the shards failed!");
+        }
+        let manifest = ShardManifest::read(&path).expect("Read the manifest of the shards failed!");
+        ShardedStorage {
+            root,
+            manifest: RwLock::new((Self::version(&path), Arc::new(manifest))),
+        }
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.root.join(MANIFEST_FILE)
+    }
+
+    fn version(path: &Path) -> Option<FileVersion> {
+        let metadata = fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    /// The manifest, read again when its file changed, e.g. by a shard added elsewhere. The
+    /// last one read is kept while the file can't be read.
+    pub fn manifest(&self) -> Arc<ShardManifest> {
+        let path = self.manifest_path();
+        let version = Self::version(&path);
+        {
+            let current = self.manifest.read().unwrap();
+            if current.0 == version {
+                return current.1.clone();
+            }
+        }
+        let mut current = self.manifest.write().unwrap();
+        match ShardManifest::read(&path) {
+            Ok(manifest) => *current = (version, Arc::new(manifest)),
+            Err(e) => tracing::warn!("{}", e),
+        }
+        current.1.clone()
+    }
+
+    /// Adds `shard` to the storage, see [`ShardManifest::add_shard`]. Its objects are moved by
+    /// [`ShardedStorage::rebalance`].
+    pub fn add_shard(&self, shard: Shard) -> Result<Vec<BucketMove>, MegaError> {
+        let path = self.manifest_path();
+        let mut manifest = ShardManifest::read(&path)?;
+        fs::create_dir_all(&shard.path)?;
+        let moves = manifest.add_shard(shard)?;
+        manifest.write(&path)?;
+        Ok(moves)
+    }
+
+    /// Moves the objects of the moving buckets to their new shard, a bucket at a time, each
+    /// recorded as moved in the manifest once it is empty in its old shard. Stopped, it goes on
+    /// from the first bucket still moving.
+    pub fn rebalance(&self) -> Result<RebalanceReport, MegaError> {
+        let path = self.manifest_path();
+        let mut report = RebalanceReport::default();
+        loop {
+            let mut manifest = ShardManifest::read(&path)?;
+            let Some(m) = manifest.moving.first().copied() else {
+                return Ok(report);
+            };
+            let from = &manifest.shards[m.from].path;
+            let to = &manifest.shards[m.to].path;
+            // an object written meanwhile by a server not aware of the move is moved as well
+            loop {
+                let objects = bucket_objects(&manifest, from, m.bucket)?;
+                if objects.is_empty() {
+                    break;
+                }
+                for (repo_name, object_id) in objects {
+                    let source = object_path(from, &repo_name, &object_id);
+                    let target = object_path(to, &repo_name, &object_id);
+                    if !target.exists() {
+                        fs::create_dir_all(target.parent().unwrap())?;
+                        fs_utils::write_atomic(&target, &fs_utils::read(&source)?)?;
+                    }
+                    fs_utils::retry(|| fs::remove_file(&source))?;
+                    report.objects += 1;
+                }
+            }
+            manifest.moving.remove(0);
+            manifest.write(&path)?;
+            report.buckets += 1;
+        }
+    }
+
+    fn primary(&self) -> LocalStorage {
+        LocalStorage::init(self.manifest().shards[0].path.clone())
+    }
+}
+
+/// Where a shard at `base` keeps `object_id` of `repo_name`, like a [`LocalStorage`].
+fn object_path(base: &Path, repo_name: &str, object_id: &str) -> PathBuf {
+    base.join(repo_name)
+        .join("objects")
+        .join(LocalStorage::default().transform_path(object_id))
+}
+
+/// The repository and the id of the objects of `bucket` kept by the shard at `base`.
+fn bucket_objects(
+    manifest: &ShardManifest,
+    base: &Path,
+    bucket: usize,
+) -> Result<Vec<(String, String)>, MegaError> {
+    let mut objects = vec![];
+    let mut dirs = vec![base.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for dir_entry in read_dir(&dir)? {
+            let path = dir_entry?.path();
+            // another shard may be inside this one, e.g. in the root of a storage of one shard
+            if !path.is_dir() || manifest.shards.iter().any(|shard| shard.path == path) {
+                continue;
+            }
+            if path.file_name().is_some_and(|name| name == "objects") {
+                let repo_name = dir.strip_prefix(base).unwrap().to_string_lossy();
+                for id in object_ids(&path)? {
+                    if manifest.bucket(&id) == bucket {
+                        objects.push((repo_name.to_string(), id));
+                    }
+                }
+            } else {
+                dirs.push(path);
+            }
+        }
+    }
+    Ok(objects)
+}
+
+/// The ids of the objects of an `objects` directory, stored as `xx/yy/rest`.
+fn object_ids(objects: &Path) -> Result<Vec<String>, MegaError> {
+    let mut ids = vec![];
+    for first in read_dir(objects)? {
+        let first = first?;
+        for second in read_dir(&first.path())? {
+            let second = second?;
+            for file in read_dir(&second.path())? {
+                let file = file?;
+                if file.path().is_file() {
+                    ids.push(format!(
+                        "{}{}{}",
+                        first.file_name().to_string_lossy(),
+                        second.file_name().to_string_lossy(),
+                        file.file_name().to_string_lossy()
+                    ));
+                }
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// The entries of `dir`, none when it is not a directory.
+fn read_dir(dir: &Path) -> io::Result<Vec<io::Result<fs::DirEntry>>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    Ok(fs::read_dir(dir)?.collect())
+}
+
+#[async_trait]
+impl RawStorage for ShardedStorage {
+    fn get_storage_type(&self) -> StorageType {
+        StorageType::LocalFs
+    }
+
+    async fn get_ref(&self, repo_name: &str, ref_name: &str) -> Result<String, MegaError> {
+        self.primary().get_ref(repo_name, ref_name).await
+    }
+
+    async fn put_ref(
+        &self,
+        repo_name: &str,
+        ref_name: &str,
+        ref_hash: &str,
+    ) -> Result<(), MegaError> {
+        self.primary().put_ref(repo_name, ref_name, ref_hash).await
+    }
+
+    async fn delete_ref(&self, repo_name: &str, ref_name: &str) -> Result<(), MegaError> {
+        self.primary().delete_ref(repo_name, ref_name).await
+    }
+
+    async fn update_ref(
+        &self,
+        repo_name: &str,
+        ref_name: &str,
+        ref_hash: &str,
+    ) -> Result<(), MegaError> {
+        self.primary()
+            .update_ref(repo_name, ref_name, ref_hash)
+            .await
+    }
+
+    async fn get_object(&self, repo_name: &str, object_id: &str) -> Result<Bytes, MegaError> {
+        let manifest = self.manifest();
+        let mut locations = manifest.locations(object_id);
+        // moved from the old shard to the new one after the new one was looked at
+        locations.push(locations[0]);
+        for shard in locations {
+            match fs_utils::read(&object_path(&shard.path, repo_name, object_id)) {
+                Ok(data) => return Ok(Bytes::from(data)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(MegaError::with_message(&format!(
+            "object {} not found",
+            object_id
+        )))
+    }
+
+    async fn put_object(
+        &self,
+        repo_name: &str,
+        object_id: &str,
+        body_content: &[u8],
+    ) -> Result<String, MegaError> {
+        let path = object_path(&self.manifest().shard(object_id).path, repo_name, object_id);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs_utils::write_atomic(&path, body_content)?;
+        Ok(path.to_str().unwrap().to_string())
+    }
+
+    async fn delete_object(&self, repo_name: &str, object_id: &str) -> Result<(), MegaError> {
+        for shard in self.manifest().locations(object_id) {
+            let path = object_path(&shard.path, repo_name, object_id);
+            match fs_utils::retry(|| fs::remove_file(&path)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn exist_object(&self, repo_name: &str, object_id: &str) -> bool {
+        self.manifest()
+            .locations(object_id)
+            .iter()
+            .any(|shard| object_path(&shard.path, repo_name, object_id).exists())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::raw_storage::sharded_storage::{Shard, ShardManifest, ShardedStorage};
+    use crate::raw_storage::RawStorage;
+
+    fn shard(name: &str, path: PathBuf) -> Shard {
+        Shard {
+            name: name.to_owned(),
+            path,
+        }
+    }
+
+    #[test]
+    fn test_add_shard_balances_the_buckets() {
+        let mut manifest = ShardManifest::new(vec![shard("0", "/a".into())], 2).unwrap();
+        assert_eq!(manifest.bucket_counts(), [256]);
+        assert_eq!(manifest.bucket("ff12"), 255);
+        assert_eq!(manifest.bucket("zz"), 0);
+
+        let moves = manifest.add_shard(shard("1", "/b".into())).unwrap();
+        assert_eq!(moves.len(), 128);
+        assert_eq!(manifest.bucket_counts(), [128, 128]);
+        // refused until the buckets are moved
+        assert!(manifest.add_shard(shard("2", "/c".into())).is_err());
+        manifest.moving.clear();
+
+        let moves = manifest.add_shard(shard("2", "/c".into())).unwrap();
+        assert_eq!(moves.len(), 85);
+        assert!(moves.iter().all(|m| m.to == 2 && m.from < 2));
+        let counts = manifest.bucket_counts();
+        assert_eq!(counts.iter().sum::<usize>(), 256);
+        assert!(counts.iter().all(|c| (85..=86).contains(c)));
+        // a moving bucket is read from its new shard first
+        let id = format!("{:02x}", moves[0].bucket);
+        let locations = manifest.locations(&id);
+        assert_eq!(locations.len(), 2);
+        assert_eq!(locations[0].name, "2");
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_while_reading() {
+        let root = env::temp_dir().join(format!("mega-shards-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let storage = ShardedStorage::init(root.clone());
+        let ids: Vec<String> = (0..32u8)
+            .map(|n| format!("{:02x}{:038x}", n * 8, n))
+            .collect();
+        for id in &ids {
+            storage.put_object("repo", id, id.as_bytes()).await.unwrap();
+        }
+
+        let moves = storage.add_shard(shard("1", root.join("shard-1"))).unwrap();
+        assert_eq!(moves.len(), 128);
+        // readable from the old shard until they are moved
+        for id in &ids {
+            assert!(storage.exist_object("repo", id));
+            assert_eq!(storage.get_object("repo", id).await.unwrap(), id.as_bytes());
+        }
+        let report = storage.rebalance().unwrap();
+        assert_eq!(report.buckets, 128);
+        assert_eq!(report.objects, 16);
+        assert!(storage.manifest().moving.is_empty());
+        for id in &ids {
+            assert_eq!(storage.get_object("repo", id).await.unwrap(), id.as_bytes());
+            let manifest = storage.manifest();
+            assert!(super::object_path(&manifest.shard(id).path, "repo", id).exists());
+        }
+        assert!(storage.get_object("repo", "00ab").await.is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
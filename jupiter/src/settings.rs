@@ -0,0 +1,393 @@
+//!
+//! Settings that can be tuned while the service runs. The defaults come from the environment,
+//! the values changed through the admin API are kept in the `mega_setting` table and take
+//! precedence. Consumers read the current values from a watch channel, so a change applies to
+//! the next push or clone without a restart, except `pack_pin_threads`.
+//!
+use std::collections::BTreeMap;
+use std::env;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::watch;
+
+use common::errors::MegaError;
+
+use crate::storage::setting_storage::SettingStorage;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Largest blob accepted in a push in KB, 0 means no limit.
+    pub max_push_blob_size: usize,
+    /// Memory the decoding of a pushed pack may use in MB.
+    pub pack_decode_mem_limit: usize,
+    /// Longest delta chain of a pushed pack, and its largest object in MB, 0 means no limit.
+    pub pack_max_delta_depth: usize,
+    pub pack_max_object_size: usize,
+    /// Pushed packs larger than this in MB are decoded in the batch lane, which leaves a quarter
+    /// of the decode threads to the smaller ones, 0 decodes them all in the interactive lane.
+    pub pack_batch_size: usize,
+    /// Whether the decode threads are pinned to cores, only with the `pinned-threads` feature
+    /// on Linux. The threads are started with the first pack decoded, a change applies after a
+    /// restart of the service.
+    pub pack_pin_threads: bool,
+    /// Clones, fetches and pushes taking longer than this in seconds are logged as slow, 0
+    /// disables the detection. Past it, a flamegraph of the process is captured for up to
+    /// `slow_op_profile_secs` while the operation runs, with the `profiling` feature.
+    pub slow_op_threshold: u64,
+    pub slow_op_profile_secs: u64,
+    /// Clones and cloned MB of one client within the window of the clone volume detection,
+    /// the detection is disabled when both are 0.
+    pub audit_clone_limit: usize,
+    pub audit_clone_mb_limit: i64,
+    pub audit_clone_window_mins: u64,
+    pub audit_suspend_on_anomaly: bool,
+    /// Whether pushes update the index of the last commit of every path.
+    pub last_commit_index: bool,
+    /// Whether pushes to the branches update the code search index of their files.
+    pub code_search_index: bool,
+    /// Commits a fetch may ask for besides the advertised refs, like the
+    /// `uploadpack.allow*SHA1InWant` options of git: the current tips of the refs, the commits
+    /// reachable from them, or any commit.
+    pub upload_allow_tip_sha1_in_want: bool,
+    pub upload_allow_reachable_sha1_in_want: bool,
+    pub upload_allow_any_sha1_in_want: bool,
+    /// Commits walked to find the wants reachable from the refs, a want not found within them
+    /// is refused, 0 means no limit.
+    pub upload_want_walk_limit: usize,
+    /// Whether the commits reaching the default branch update the churn and coupling statistics
+    /// of their directories, counted down to `code_analytics_depth` path segments.
+    pub code_analytics: bool,
+    pub code_analytics_depth: usize,
+    /// Whether code navigation indexes, SCIP or LSIF, can be uploaded for the commits.
+    pub code_navigation: bool,
+    /// Whether a failing post-merge hook rolls the merge of a push back, otherwise the failure
+    /// is only logged.
+    pub merge_hook_rollback: bool,
+    /// Handlebars template of the message of a merge, given the `id` and the `title` of the
+    /// merge request, see `ceres::merge_message`.
+    pub merge_message_template: String,
+    /// Bandwidth of the packs sent to the clones and fetches of a user in KB/s, e.g.
+    /// `mirror-bot=512,*=8192` in the environment, so that bulk mirroring jobs leave the
+    /// bandwidth to the developers. The clones and fetches of a user at once share it.
+    pub upload_bandwidth_limits: BandwidthLimits,
+    /// Minutes a pack sent over HTTP is kept for the client to resume its download with a
+    /// `Range` request, 0 disables the resume.
+    pub pack_resume_window_mins: u64,
+    /// Blames kept in the cache, by path and commit, so that blaming a file again, or at a
+    /// later commit, only replays the commits after a cached one. The least recently used
+    /// ones are evicted past it, 0 disables the cache.
+    pub blame_cache_max_entries: u64,
+}
+
+/// Caps in KB/s by user name, `*` for the users not listed and the anonymous clients. No cap
+/// or 0 means no limit.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct BandwidthLimits(pub BTreeMap<String, u64>);
+
+impl BandwidthLimits {
+    /// The cap of `user` in KB/s, 0 for none.
+    pub fn get(&self, user: Option<&str>) -> u64 {
+        user.and_then(|user| self.0.get(user))
+            .or_else(|| self.0.get("*"))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+impl FromStr for BandwidthLimits {
+    type Err = String;
+
+    /// Parses `name=kbps` pairs separated by commas.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = BTreeMap::new();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (name, limit) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("{} is not name=kbps", pair))?;
+            let limit = limit
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid bandwidth of {}", name))?;
+            limits.insert(name.trim().to_owned(), limit);
+        }
+        Ok(BandwidthLimits(limits))
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            max_push_blob_size: 0,
+            pack_decode_mem_limit: 4096,
+            pack_max_delta_depth: 4095,
+            pack_max_object_size: 1024,
+            pack_batch_size: 64,
+            pack_pin_threads: false,
+            slow_op_threshold: 30,
+            slow_op_profile_secs: 10,
+            audit_clone_limit: 0,
+            audit_clone_mb_limit: 0,
+            audit_clone_window_mins: 60,
+            audit_suspend_on_anomaly: false,
+            last_commit_index: true,
+            code_search_index: true,
+            upload_allow_tip_sha1_in_want: false,
+            upload_allow_reachable_sha1_in_want: false,
+            upload_allow_any_sha1_in_want: false,
+            upload_want_walk_limit: 100_000,
+            code_analytics: false,
+            code_analytics_depth: 3,
+            code_navigation: false,
+            merge_hook_rollback: true,
+            merge_message_template: String::from(
+                "Merge request !{{id}}{{#if title}}: {{title}}{{/if}}",
+            ),
+            upload_bandwidth_limits: BandwidthLimits::default(),
+            pack_resume_window_mins: 30,
+            blame_cache_max_entries: 10000,
+        }
+    }
+}
+
+impl Settings {
+    pub fn from_env() -> Self {
+        let default = Settings::default();
+        Settings {
+            max_push_blob_size: env_or("MEGA_MAX_PUSH_BLOB_SIZE", default.max_push_blob_size),
+            pack_decode_mem_limit: env_or(
+                "MEGA_PACK_DECODE_MEM_LIMIT",
+                default.pack_decode_mem_limit,
+            ),
+            pack_max_delta_depth: env_or("MEGA_PACK_MAX_DELTA_DEPTH", default.pack_max_delta_depth),
+            pack_max_object_size: env_or("MEGA_PACK_MAX_OBJECT_SIZE", default.pack_max_object_size),
+            pack_batch_size: env_or("MEGA_PACK_BATCH_SIZE", default.pack_batch_size),
+            pack_pin_threads: env_or("MEGA_PACK_PIN_THREADS", default.pack_pin_threads),
+            slow_op_threshold: env_or("MEGA_SLOW_OP_THRESHOLD", default.slow_op_threshold),
+            slow_op_profile_secs: env_or("MEGA_SLOW_OP_PROFILE_SECS", default.slow_op_profile_secs),
+            audit_clone_limit: env_or("MEGA_AUDIT_CLONE_LIMIT", default.audit_clone_limit),
+            audit_clone_mb_limit: env_or("MEGA_AUDIT_CLONE_MB_LIMIT", default.audit_clone_mb_limit),
+            audit_clone_window_mins: env_or(
+                "MEGA_AUDIT_CLONE_WINDOW_MINS",
+                default.audit_clone_window_mins,
+            ),
+            audit_suspend_on_anomaly: env_or(
+                "MEGA_AUDIT_SUSPEND_ON_ANOMALY",
+                default.audit_suspend_on_anomaly,
+            ),
+            last_commit_index: env_or("MEGA_LAST_COMMIT_INDEX", default.last_commit_index),
+            code_search_index: env_or("MEGA_CODE_SEARCH_INDEX", default.code_search_index),
+            upload_allow_tip_sha1_in_want: env_or(
+                "MEGA_UPLOAD_ALLOW_TIP_SHA1_IN_WANT",
+                default.upload_allow_tip_sha1_in_want,
+            ),
+            upload_allow_reachable_sha1_in_want: env_or(
+                "MEGA_UPLOAD_ALLOW_REACHABLE_SHA1_IN_WANT",
+                default.upload_allow_reachable_sha1_in_want,
+            ),
+            upload_allow_any_sha1_in_want: env_or(
+                "MEGA_UPLOAD_ALLOW_ANY_SHA1_IN_WANT",
+                default.upload_allow_any_sha1_in_want,
+            ),
+            upload_want_walk_limit: env_or(
+                "MEGA_UPLOAD_WANT_WALK_LIMIT",
+                default.upload_want_walk_limit,
+            ),
+            code_analytics: env_or("MEGA_CODE_ANALYTICS", default.code_analytics),
+            code_analytics_depth: env_or(
+                "MEGA_CODE_ANALYTICS_DEPTH",
+                default.code_analytics_depth,
+            ),
+            code_navigation: env_or("MEGA_CODE_NAVIGATION", default.code_navigation),
+            merge_hook_rollback: env_or("MEGA_MERGE_HOOK_ROLLBACK", default.merge_hook_rollback),
+            merge_message_template: env_or(
+                "MEGA_MERGE_MESSAGE_TEMPLATE",
+                default.merge_message_template,
+            ),
+            upload_bandwidth_limits: env_or(
+                "MEGA_UPLOAD_BANDWIDTH_LIMITS",
+                default.upload_bandwidth_limits,
+            ),
+            pack_resume_window_mins: env_or(
+                "MEGA_PACK_RESUME_WINDOW_MINS",
+                default.pack_resume_window_mins,
+            ),
+            blame_cache_max_entries: env_or(
+                "MEGA_BLAME_CACHE_MAX_ENTRIES",
+                default.blame_cache_max_entries,
+            ),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse::<T>().ok())
+        .unwrap_or(default)
+}
+
+pub struct RuntimeSettings {
+    defaults: Settings,
+    sender: watch::Sender<Settings>,
+}
+
+impl RuntimeSettings {
+    pub fn new(defaults: Settings) -> Self {
+        RuntimeSettings {
+            sender: watch::Sender::new(defaults.clone()),
+            defaults,
+        }
+    }
+
+    pub fn current(&self) -> Settings {
+        self.sender.borrow().clone()
+    }
+
+    /// A receiver notified of every change of the settings.
+    pub fn subscribe(&self) -> watch::Receiver<Settings> {
+        self.sender.subscribe()
+    }
+
+    /// Applies the values of the database, the receivers are only notified when they changed.
+    /// A stored value that is no longer valid is skipped.
+    pub async fn reload(&self, storage: &SettingStorage) -> Result<Settings, MegaError> {
+        let mut overrides = Map::new();
+        for model in storage.get_settings().await? {
+            match serde_json::from_str(&model.value) {
+                Ok(value) => {
+                    overrides.insert(model.name, value);
+                }
+                Err(err) => tracing::warn!("invalid value of setting {}: {}", model.name, err),
+            }
+        }
+        let settings = match self.merge(overrides.clone()) {
+            Ok(settings) => settings,
+            Err(_) => {
+                overrides.retain(|name, value| {
+                    let valid = self.merge(Map::from_iter([(name.clone(), value.clone())]));
+                    if let Err(err) = &valid {
+                        tracing::warn!("ignoring setting {}: {}", name, err);
+                    }
+                    valid.is_ok()
+                });
+                self.merge(overrides)?
+            }
+        };
+        self.sender.send_if_modified(|current| {
+            let modified = *current != settings;
+            *current = settings.clone();
+            modified
+        });
+        Ok(settings)
+    }
+
+    /// Validates and stores `changes`, a JSON object of setting names and values, then
+    /// applies them.
+    pub async fn update(
+        &self,
+        storage: &SettingStorage,
+        changes: Map<String, Value>,
+    ) -> Result<Settings, MegaError> {
+        let mut merged = to_object(&self.current());
+        merged.extend(changes.clone());
+        self.merge(merged)?;
+        let values = changes
+            .into_iter()
+            .map(|(name, value)| (name, value.to_string()))
+            .collect();
+        storage.save_settings(values).await?;
+        self.reload(storage).await
+    }
+
+    /// Removes the stored values of `names`, the settings fall back to their defaults.
+    pub async fn reset(
+        &self,
+        storage: &SettingStorage,
+        names: Vec<String>,
+    ) -> Result<Settings, MegaError> {
+        storage.remove_settings(names).await?;
+        self.reload(storage).await
+    }
+
+    fn merge(&self, overrides: Map<String, Value>) -> Result<Settings, MegaError> {
+        let mut settings = to_object(&self.defaults);
+        settings.extend(overrides);
+        serde_json::from_value(Value::Object(settings))
+            .map_err(|err| MegaError::with_message(&format!("invalid settings: {}", err)))
+    }
+}
+
+fn to_object(settings: &Settings) -> Map<String, Value> {
+    match serde_json::to_value(settings) {
+        Ok(Value::Object(map)) => map,
+        _ => unreachable!("settings serialize to an object"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map, Value};
+
+    use super::{BandwidthLimits, RuntimeSettings, Settings};
+
+    fn object(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("not an object"),
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let settings = RuntimeSettings::new(Settings::default());
+        let merged = settings
+            .merge(object(
+                json!({"max_push_blob_size": 2048, "last_commit_index": false}),
+            ))
+            .unwrap();
+        assert_eq!(merged.max_push_blob_size, 2048);
+        assert!(!merged.last_commit_index);
+        assert_eq!(merged.pack_decode_mem_limit, 4096);
+
+        assert!(settings
+            .merge(object(json!({"max_push_blob_size": "large"})))
+            .is_err());
+        assert!(settings.merge(object(json!({"unknown": 1}))).is_err());
+    }
+
+    #[test]
+    fn test_bandwidth_limits() {
+        let limits: BandwidthLimits = "mirror-bot=512, *=8192".parse().unwrap();
+        assert_eq!(limits.get(Some("mirror-bot")), 512);
+        assert_eq!(limits.get(Some("alice")), 8192);
+        assert_eq!(limits.get(None), 8192);
+        assert_eq!(BandwidthLimits::default().get(Some("alice")), 0);
+        assert!("mirror-bot".parse::<BandwidthLimits>().is_err());
+        assert!("mirror-bot=fast".parse::<BandwidthLimits>().is_err());
+
+        let settings = RuntimeSettings::new(Settings::default());
+        let merged = settings
+            .merge(object(json!({"upload_bandwidth_limits": {"mirror-bot": 512}})))
+            .unwrap();
+        assert_eq!(merged.upload_bandwidth_limits.get(Some("mirror-bot")), 512);
+        assert!(settings
+            .merge(object(json!({"upload_bandwidth_limits": {"mirror-bot": -1}})))
+            .is_err());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let settings = RuntimeSettings::new(Settings::default());
+        let mut receiver = settings.subscribe();
+        let changed = settings
+            .merge(object(json!({"audit_clone_limit": 50})))
+            .unwrap();
+        settings.sender.send_replace(changed);
+        assert!(receiver.has_changed().unwrap());
+        assert_eq!(receiver.borrow_and_update().audit_clone_limit, 50);
+    }
+}
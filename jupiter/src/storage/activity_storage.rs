@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use chrono::NaiveDate;
+use sea_orm::{
+    sea_query::{Expr, OnConflict},
+    ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder,
+    QuerySelect, TransactionTrait,
+};
+
+use callisto::{mega_user_activity, mega_user_contribution};
+use common::errors::MegaError;
+
+/// The counters of a contribution, added up when a user contributes again on the same day.
+const COUNTERS: [(mega_user_contribution::Column, &str); 3] = [
+    (mega_user_contribution::Column::Commits, "commits"),
+    (mega_user_contribution::Column::Pushes, "pushes"),
+    (mega_user_contribution::Column::Merges, "merges"),
+];
+
+/// The ref updates of every user and their contributions per day.
+#[derive(Clone)]
+pub struct ActivityStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl ActivityStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        ActivityStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        ActivityStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    /// Saves `activity` and adds `contributions` to the counts of their users and days. An
+    /// activity saved before is ignored, together with its contributions, so recording the same
+    /// activity again does not count it twice. Returns whether the activity was new.
+    pub async fn record_activity(
+        &self,
+        activity: mega_user_activity::Model,
+        contributions: Vec<mega_user_contribution::Model>,
+    ) -> Result<bool, MegaError> {
+        let txn = self.get_connection().begin().await?;
+        let inserted = mega_user_activity::Entity::insert(activity.into_active_model())
+            .on_conflict(
+                OnConflict::column(mega_user_activity::Column::Id)
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(&txn)
+            .await?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+        for contribution in contributions {
+            mega_user_contribution::Entity::insert(contribution.into_active_model())
+                .on_conflict(
+                    OnConflict::columns([
+                        mega_user_contribution::Column::UserName,
+                        mega_user_contribution::Column::Day,
+                    ])
+                    .values(COUNTERS.map(|(column, name)| {
+                        let sum = format!(
+                            "\"mega_user_contribution\".\"{0}\" + EXCLUDED.\"{0}\"",
+                            name
+                        );
+                        (column, Expr::cust(sum))
+                    }))
+                    .to_owned(),
+                )
+                .exec(&txn)
+                .await?;
+        }
+        txn.commit().await?;
+        Ok(true)
+    }
+
+    /// The activities of `user_name` before the activity `before`, newest first.
+    pub async fn get_activities(
+        &self,
+        user_name: &str,
+        before: Option<i64>,
+        limit: u64,
+    ) -> Result<Vec<mega_user_activity::Model>, MegaError> {
+        let mut query = mega_user_activity::Entity::find()
+            .filter(mega_user_activity::Column::UserName.eq(user_name));
+        if let Some(before) = before {
+            query = query.filter(mega_user_activity::Column::Id.lt(before));
+        }
+        Ok(query
+            .order_by_desc(mega_user_activity::Column::Id)
+            .limit(limit)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// The contributions of `user_names` from `since` to `until` included, oldest first.
+    pub async fn get_contributions(
+        &self,
+        user_names: Vec<String>,
+        since: NaiveDate,
+        until: NaiveDate,
+    ) -> Result<Vec<mega_user_contribution::Model>, MegaError> {
+        Ok(mega_user_contribution::Entity::find()
+            .filter(mega_user_contribution::Column::UserName.is_in(user_names))
+            .filter(mega_user_contribution::Column::Day.gte(since))
+            .filter(mega_user_contribution::Column::Day.lte(until))
+            .order_by_asc(mega_user_contribution::Column::Day)
+            .all(self.get_connection())
+            .await?)
+    }
+}
@@ -0,0 +1,189 @@
+use std::sync::Arc;
+
+use chrono::{NaiveDate, Utc};
+use sea_orm::{
+    sea_query::{Expr, OnConflict},
+    ColumnTrait, Condition, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter,
+    TransactionTrait,
+};
+
+use callisto::{mega_analytics_commit, mega_dir_churn, mega_dir_contributor, mega_dir_coupling};
+use common::errors::MegaError;
+
+const CHURN_COUNTERS: [(mega_dir_churn::Column, &str); 3] = [
+    (mega_dir_churn::Column::LinesAdded, "lines_added"),
+    (mega_dir_churn::Column::LinesRemoved, "lines_removed"),
+    (mega_dir_churn::Column::Commits, "commits"),
+];
+
+/// The statistics of one commit, added to those of the directories it changed.
+#[derive(Debug, Clone, Default)]
+pub struct CommitStats {
+    pub churn: Vec<mega_dir_churn::Model>,
+    pub contributors: Vec<mega_dir_contributor::Model>,
+    pub coupling: Vec<mega_dir_coupling::Model>,
+}
+
+/// The churn, contributors and coupling of the directories of the monorepo per week.
+#[derive(Clone)]
+pub struct AnalyticsStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl AnalyticsStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        AnalyticsStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        AnalyticsStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    /// Adds the statistics of the commit `commit_id`. A commit is only counted once, returns
+    /// whether it was new.
+    pub async fn record_commit(
+        &self,
+        commit_id: &str,
+        stats: CommitStats,
+    ) -> Result<bool, MegaError> {
+        let txn = self.get_connection().begin().await?;
+        let commit = mega_analytics_commit::Model {
+            commit_id: commit_id.to_owned(),
+            created_at: Utc::now().naive_utc(),
+        };
+        let inserted = mega_analytics_commit::Entity::insert(commit.into_active_model())
+            .on_conflict(
+                OnConflict::column(mega_analytics_commit::Column::CommitId)
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(&txn)
+            .await?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+        for churn in stats.churn {
+            mega_dir_churn::Entity::insert(churn.into_active_model())
+                .on_conflict(
+                    OnConflict::columns([
+                        mega_dir_churn::Column::Path,
+                        mega_dir_churn::Column::Week,
+                    ])
+                    .values(
+                        CHURN_COUNTERS.map(|(column, name)| (column, sum("mega_dir_churn", name))),
+                    )
+                    .to_owned(),
+                )
+                .exec_without_returning(&txn)
+                .await?;
+        }
+        if !stats.contributors.is_empty() {
+            mega_dir_contributor::Entity::insert_many(
+                stats
+                    .contributors
+                    .into_iter()
+                    .map(|c| c.into_active_model()),
+            )
+            .on_conflict(
+                OnConflict::columns([
+                    mega_dir_contributor::Column::Path,
+                    mega_dir_contributor::Column::Week,
+                    mega_dir_contributor::Column::Author,
+                ])
+                .do_nothing()
+                .to_owned(),
+            )
+            .exec_without_returning(&txn)
+            .await?;
+        }
+        for coupling in stats.coupling {
+            mega_dir_coupling::Entity::insert(coupling.into_active_model())
+                .on_conflict(
+                    OnConflict::columns([
+                        mega_dir_coupling::Column::Path,
+                        mega_dir_coupling::Column::CoupledPath,
+                        mega_dir_coupling::Column::Week,
+                    ])
+                    .value(
+                        mega_dir_coupling::Column::Commits,
+                        sum("mega_dir_coupling", "commits"),
+                    )
+                    .to_owned(),
+                )
+                .exec_without_returning(&txn)
+                .await?;
+        }
+        txn.commit().await?;
+        Ok(true)
+    }
+
+    /// The churn of `path` and the directories below it in the weeks from `since` to `until`.
+    pub async fn get_churn(
+        &self,
+        path: &str,
+        since: NaiveDate,
+        until: NaiveDate,
+    ) -> Result<Vec<mega_dir_churn::Model>, MegaError> {
+        Ok(mega_dir_churn::Entity::find()
+            .filter(below(mega_dir_churn::Column::Path, path))
+            .filter(mega_dir_churn::Column::Week.gte(since))
+            .filter(mega_dir_churn::Column::Week.lte(until))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    pub async fn get_contributors(
+        &self,
+        path: &str,
+        since: NaiveDate,
+        until: NaiveDate,
+    ) -> Result<Vec<mega_dir_contributor::Model>, MegaError> {
+        Ok(mega_dir_contributor::Entity::find()
+            .filter(below(mega_dir_contributor::Column::Path, path))
+            .filter(mega_dir_contributor::Column::Week.gte(since))
+            .filter(mega_dir_contributor::Column::Week.lte(until))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// The coupling of the directories below `path` with any other directory.
+    pub async fn get_coupling(
+        &self,
+        path: &str,
+        since: NaiveDate,
+        until: NaiveDate,
+    ) -> Result<Vec<mega_dir_coupling::Model>, MegaError> {
+        Ok(mega_dir_coupling::Entity::find()
+            .filter(
+                Condition::any()
+                    .add(below(mega_dir_coupling::Column::Path, path))
+                    .add(below(mega_dir_coupling::Column::CoupledPath, path)),
+            )
+            .filter(mega_dir_coupling::Column::Week.gte(since))
+            .filter(mega_dir_coupling::Column::Week.lte(until))
+            .all(self.get_connection())
+            .await?)
+    }
+}
+
+/// Adds the value of the row being inserted to the counter `column` of the existing row.
+fn sum(table: &str, column: &str) -> sea_orm::sea_query::SimpleExpr {
+    Expr::cust(format!("\"{0}\".\"{1}\" + EXCLUDED.\"{1}\"", table, column))
+}
+
+/// Matches `path` and the paths below it.
+fn below(column: impl ColumnTrait, path: &str) -> Condition {
+    let path = path.trim_end_matches('/');
+    if path.is_empty() {
+        return Condition::all();
+    }
+    Condition::any()
+        .add(column.eq(path))
+        .add(column.starts_with(format!("{}/", path)))
+}
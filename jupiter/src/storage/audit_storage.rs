@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use chrono::{NaiveDateTime, Utc};
+use sea_orm::{
+    ColumnTrait, Condition, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter,
+    QueryOrder, QuerySelect,
+};
+
+use callisto::db_enums::OperationType;
+use callisto::{git_operation, user_suspension};
+use common::errors::MegaError;
+use common::utils::generate_id;
+
+/// Conditions used to look up recorded git operations, unset fields are not filtered on.
+#[derive(Debug, Clone, Default)]
+pub struct OperationFilter {
+    pub user_name: Option<String>,
+    pub repo_path: Option<String>,
+    pub client_ip: Option<String>,
+    pub operation: Option<OperationType>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct AuditStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl AuditStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        AuditStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        AuditStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    pub async fn save_operation(&self, operation: git_operation::Model) -> Result<(), MegaError> {
+        git_operation::Entity::insert(operation.into_active_model())
+            .exec(self.get_connection())
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the matching operations, newest first.
+    pub async fn get_operations(
+        &self,
+        filter: OperationFilter,
+    ) -> Result<Vec<git_operation::Model>, MegaError> {
+        let mut query = git_operation::Entity::find();
+        if let Some(user_name) = filter.user_name {
+            query = query.filter(git_operation::Column::UserName.eq(user_name));
+        }
+        if let Some(repo_path) = filter.repo_path {
+            query = query.filter(git_operation::Column::RepoPath.eq(repo_path));
+        }
+        if let Some(client_ip) = filter.client_ip {
+            query = query.filter(git_operation::Column::ClientIp.eq(client_ip));
+        }
+        if let Some(operation) = filter.operation {
+            query = query.filter(git_operation::Column::Operation.eq(operation));
+        }
+        if let Some(since) = filter.since {
+            query = query.filter(git_operation::Column::CreatedAt.gte(since));
+        }
+        if let Some(until) = filter.until {
+            query = query.filter(git_operation::Column::CreatedAt.lt(until));
+        }
+        Ok(query
+            .order_by_desc(git_operation::Column::CreatedAt)
+            .offset(filter.offset)
+            .limit(filter.limit)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Removes every operation recorded before `before`, returns the number of deleted rows.
+    pub async fn delete_operations_before(&self, before: NaiveDateTime) -> Result<u64, MegaError> {
+        let res = git_operation::Entity::delete_many()
+            .filter(git_operation::Column::CreatedAt.lt(before))
+            .exec(self.get_connection())
+            .await?;
+        Ok(res.rows_affected)
+    }
+
+    pub async fn suspend_user(&self, user_name: &str, reason: &str) -> Result<(), MegaError> {
+        if self.is_user_suspended(user_name).await? {
+            return Ok(());
+        }
+        self.save_suspension(user_name, None, reason).await
+    }
+
+    /// Suspends a single access token or SSH key of the user, other credentials keep working.
+    pub async fn suspend_token(
+        &self,
+        user_name: &str,
+        token_id: &str,
+        reason: &str,
+    ) -> Result<(), MegaError> {
+        if self.is_suspended(user_name, Some(token_id)).await? {
+            return Ok(());
+        }
+        self.save_suspension(user_name, Some(token_id), reason).await
+    }
+
+    async fn save_suspension(
+        &self,
+        user_name: &str,
+        token_id: Option<&str>,
+        reason: &str,
+    ) -> Result<(), MegaError> {
+        let model = user_suspension::Model {
+            id: generate_id(),
+            user_name: user_name.to_owned(),
+            token_id: token_id.map(str::to_owned),
+            reason: reason.to_owned(),
+            created_at: Utc::now().naive_utc(),
+        };
+        user_suspension::Entity::insert(model.into_active_model())
+            .exec(self.get_connection())
+            .await?;
+        Ok(())
+    }
+
+    /// Whether the user as a whole is suspended, token suspensions are not taken into account.
+    pub async fn is_user_suspended(&self, user_name: &str) -> Result<bool, MegaError> {
+        let result = user_suspension::Entity::find()
+            .filter(user_suspension::Column::UserName.eq(user_name))
+            .filter(user_suspension::Column::TokenId.is_null())
+            .one(self.get_connection())
+            .await?;
+        Ok(result.is_some())
+    }
+
+    /// Whether the user, or the token the user authenticated with, is suspended.
+    pub async fn is_suspended(
+        &self,
+        user_name: &str,
+        token_id: Option<&str>,
+    ) -> Result<bool, MegaError> {
+        let mut scope = Condition::any().add(user_suspension::Column::TokenId.is_null());
+        if let Some(token_id) = token_id {
+            scope = scope.add(user_suspension::Column::TokenId.eq(token_id));
+        }
+        let result = user_suspension::Entity::find()
+            .filter(user_suspension::Column::UserName.eq(user_name))
+            .filter(scope)
+            .one(self.get_connection())
+            .await?;
+        Ok(result.is_some())
+    }
+
+    pub async fn get_suspensions(&self) -> Result<Vec<user_suspension::Model>, MegaError> {
+        Ok(user_suspension::Entity::find()
+            .order_by_desc(user_suspension::Column::CreatedAt)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    pub async fn lift_suspension(&self, user_name: &str) -> Result<(), MegaError> {
+        user_suspension::Entity::delete_many()
+            .filter(user_suspension::Column::UserName.eq(user_name))
+            .exec(self.get_connection())
+            .await?;
+        Ok(())
+    }
+}
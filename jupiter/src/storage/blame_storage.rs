@@ -0,0 +1,150 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use chrono::Utc;
+use sea_orm::{
+    sea_query::{Expr, OnConflict},
+    ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter,
+    QueryOrder, QuerySelect,
+};
+
+use callisto::mega_blame_cache;
+use common::errors::MegaError;
+
+/// Entries removed per statement when the cache is evicted.
+const DELETE_CHUNK: u64 = 1000;
+
+/// The blames computed for a path at a commit, kept so that the next blames of the path only
+/// replay the commits after a cached one. An entry is never stale as long as the history of
+/// its commit is not rewritten, it is dropped when it is the least recently used.
+#[derive(Clone)]
+pub struct BlameStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl BlameStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        BlameStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        BlameStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    /// The blame of `path` at `commit_id`, marked as used.
+    pub async fn get_blame(
+        &self,
+        repo_id: i64,
+        path: &str,
+        commit_id: &str,
+    ) -> Result<Option<mega_blame_cache::Model>, MegaError> {
+        let model = mega_blame_cache::Entity::find()
+            .filter(mega_blame_cache::Column::RepoId.eq(repo_id))
+            .filter(mega_blame_cache::Column::Path.eq(path))
+            .filter(mega_blame_cache::Column::CommitId.eq(commit_id))
+            .one(self.get_connection())
+            .await?;
+        if let Some(model) = &model {
+            mega_blame_cache::Entity::update_many()
+                .col_expr(
+                    mega_blame_cache::Column::LastUsedAt,
+                    Expr::value(Utc::now().naive_utc()),
+                )
+                .filter(mega_blame_cache::Column::Id.eq(model.id))
+                .exec(self.get_connection())
+                .await?;
+        }
+        Ok(model)
+    }
+
+    /// The commits `path` has a cached blame at.
+    pub async fn get_cached_commits(
+        &self,
+        repo_id: i64,
+        path: &str,
+    ) -> Result<HashSet<String>, MegaError> {
+        let commits: Vec<String> = mega_blame_cache::Entity::find()
+            .select_only()
+            .column(mega_blame_cache::Column::CommitId)
+            .filter(mega_blame_cache::Column::RepoId.eq(repo_id))
+            .filter(mega_blame_cache::Column::Path.eq(path))
+            .into_tuple()
+            .all(self.get_connection())
+            .await?;
+        Ok(commits.into_iter().collect())
+    }
+
+    /// Saves a blame, one cached before for the same path and commit is kept.
+    pub async fn save_blame(&self, model: mega_blame_cache::Model) -> Result<(), MegaError> {
+        mega_blame_cache::Entity::insert(model.into_active_model())
+            .on_conflict(
+                OnConflict::columns([
+                    mega_blame_cache::Column::RepoId,
+                    mega_blame_cache::Column::Path,
+                    mega_blame_cache::Column::CommitId,
+                ])
+                .do_nothing()
+                .to_owned(),
+            )
+            .exec_without_returning(self.get_connection())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn count_blames(&self) -> Result<u64, MegaError> {
+        Ok(mega_blame_cache::Entity::find()
+            .count(self.get_connection())
+            .await?)
+    }
+
+    /// Removes the least recently used blames until at most `max_entries` are left, returns
+    /// how many were removed.
+    pub async fn evict(&self, max_entries: u64) -> Result<u64, MegaError> {
+        let mut excess = self.count_blames().await?.saturating_sub(max_entries);
+        let mut removed = 0;
+        while excess > 0 {
+            let ids: Vec<i64> = mega_blame_cache::Entity::find()
+                .select_only()
+                .column(mega_blame_cache::Column::Id)
+                .order_by_asc(mega_blame_cache::Column::LastUsedAt)
+                .limit(excess.min(DELETE_CHUNK))
+                .into_tuple()
+                .all(self.get_connection())
+                .await?;
+            if ids.is_empty() {
+                break;
+            }
+            let res = mega_blame_cache::Entity::delete_many()
+                .filter(mega_blame_cache::Column::Id.is_in(ids))
+                .exec(self.get_connection())
+                .await?;
+            removed += res.rows_affected;
+            excess = excess.saturating_sub(res.rows_affected.max(1));
+        }
+        Ok(removed)
+    }
+
+    /// Removes the blames of `path` in the repository `repo_id`, of all its paths without
+    /// one, or of all the repositories without either. Returns how many were removed.
+    pub async fn remove_blames(
+        &self,
+        repo_id: Option<i64>,
+        path: Option<&str>,
+    ) -> Result<u64, MegaError> {
+        let mut query = mega_blame_cache::Entity::delete_many();
+        if let Some(repo_id) = repo_id {
+            query = query.filter(mega_blame_cache::Column::RepoId.eq(repo_id));
+        }
+        if let Some(path) = path {
+            query = query.filter(mega_blame_cache::Column::Path.eq(path));
+        }
+        let res = query.exec(self.get_connection()).await?;
+        Ok(res.rows_affected)
+    }
+}
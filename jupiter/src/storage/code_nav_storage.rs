@@ -0,0 +1,138 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use sea_orm::{
+    ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder,
+    TransactionTrait,
+};
+
+use callisto::{mega_code_index, mega_code_symbol};
+use common::errors::MegaError;
+use common::utils::generate_id;
+
+/// Symbols inserted per statement, within the bind parameter limit of the databases.
+const INSERT_CHUNK: usize = 1000;
+
+/// The code navigation indexes uploaded for the commits of the repositories, with the ranges
+/// of the symbols they define and reference.
+#[derive(Clone)]
+pub struct CodeNavStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl CodeNavStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        CodeNavStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        CodeNavStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    /// Saves the index of a commit with its `symbols`, their `index_id` and `seq` are set
+    /// here. An index uploaded before for the commit is replaced.
+    pub async fn save_index(
+        &self,
+        repo_id: i64,
+        commit_id: &str,
+        format: &str,
+        symbols: Vec<mega_code_symbol::Model>,
+    ) -> Result<mega_code_index::Model, MegaError> {
+        let index = mega_code_index::Model {
+            id: generate_id(),
+            repo_id,
+            commit_id: commit_id.to_owned(),
+            format: format.to_owned(),
+            occurrences: symbols.len() as i64,
+            created_at: Utc::now().naive_utc(),
+        };
+        let txn = self.get_connection().begin().await?;
+        let old = mega_code_index::Entity::find()
+            .filter(mega_code_index::Column::RepoId.eq(repo_id))
+            .filter(mega_code_index::Column::CommitId.eq(commit_id))
+            .one(&txn)
+            .await?;
+        if let Some(old) = old {
+            mega_code_symbol::Entity::delete_many()
+                .filter(mega_code_symbol::Column::IndexId.eq(old.id))
+                .exec(&txn)
+                .await?;
+            mega_code_index::Entity::delete_by_id(old.id)
+                .exec(&txn)
+                .await?;
+        }
+        mega_code_index::Entity::insert(index.clone().into_active_model())
+            .exec_without_returning(&txn)
+            .await?;
+        let mut seq = 0;
+        for chunk in symbols.chunks(INSERT_CHUNK) {
+            let models = chunk.iter().cloned().map(|mut symbol| {
+                symbol.index_id = index.id;
+                symbol.seq = seq;
+                seq += 1;
+                symbol.into_active_model()
+            });
+            mega_code_symbol::Entity::insert_many(models)
+                .exec_without_returning(&txn)
+                .await?;
+        }
+        txn.commit().await?;
+        Ok(index)
+    }
+
+    pub async fn get_index(
+        &self,
+        repo_id: i64,
+        commit_id: &str,
+    ) -> Result<Option<mega_code_index::Model>, MegaError> {
+        Ok(mega_code_index::Entity::find()
+            .filter(mega_code_index::Column::RepoId.eq(repo_id))
+            .filter(mega_code_index::Column::CommitId.eq(commit_id))
+            .one(self.get_connection())
+            .await?)
+    }
+
+    /// The symbols of the file `path` whose ranges span the line `line`.
+    pub async fn get_symbols_at(
+        &self,
+        index_id: i64,
+        path: &str,
+        line: i32,
+    ) -> Result<Vec<mega_code_symbol::Model>, MegaError> {
+        Ok(mega_code_symbol::Entity::find()
+            .filter(mega_code_symbol::Column::IndexId.eq(index_id))
+            .filter(mega_code_symbol::Column::Path.eq(path))
+            .filter(mega_code_symbol::Column::StartLine.lte(line))
+            .filter(mega_code_symbol::Column::EndLine.gte(line))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// The occurrences of `symbol`, only its definitions when `definitions_only`, ordered by
+    /// path and position.
+    pub async fn get_occurrences(
+        &self,
+        index_id: i64,
+        symbol: &str,
+        definitions_only: bool,
+    ) -> Result<Vec<mega_code_symbol::Model>, MegaError> {
+        let mut query = mega_code_symbol::Entity::find()
+            .filter(mega_code_symbol::Column::IndexId.eq(index_id))
+            .filter(mega_code_symbol::Column::Symbol.eq(symbol));
+        if definitions_only {
+            query = query.filter(mega_code_symbol::Column::IsDefinition.eq(true));
+        }
+        Ok(query
+            .order_by_asc(mega_code_symbol::Column::Path)
+            .order_by_asc(mega_code_symbol::Column::StartLine)
+            .order_by_asc(mega_code_symbol::Column::StartCharacter)
+            .all(self.get_connection())
+            .await?)
+    }
+}
@@ -0,0 +1,198 @@
+use std::sync::Arc;
+
+use chrono::{NaiveDateTime, Utc};
+use sea_orm::{
+    sea_query::{Expr, OnConflict},
+    ColumnTrait, Condition, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter,
+    QueryOrder, QuerySelect,
+};
+
+use callisto::{mega_ref_event, mega_ref_event_cursor};
+use common::errors::MegaError;
+
+/// The ref updates waiting for their observers, and how far each observer got.
+#[derive(Clone)]
+pub struct EventStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl EventStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        EventStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        EventStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    pub async fn save_ref_events(
+        &self,
+        events: Vec<mega_ref_event::Model>,
+    ) -> Result<(), MegaError> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        mega_ref_event::Entity::insert_many(events.into_iter().map(|e| e.into_active_model()))
+            .exec(self.get_connection())
+            .await?;
+        Ok(())
+    }
+
+    /// The events after `after` created no later than `until`, oldest first.
+    pub async fn get_ref_events(
+        &self,
+        after: i64,
+        until: NaiveDateTime,
+        limit: u64,
+    ) -> Result<Vec<mega_ref_event::Model>, MegaError> {
+        Ok(mega_ref_event::Entity::find()
+            .filter(mega_ref_event::Column::Id.gt(after))
+            .filter(mega_ref_event::Column::CreatedAt.lte(until))
+            .order_by_asc(mega_ref_event::Column::Id)
+            .limit(limit)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// The events created after `after`, in the order they were recorded.
+    pub async fn get_ref_events_since(
+        &self,
+        after: NaiveDateTime,
+    ) -> Result<Vec<mega_ref_event::Model>, MegaError> {
+        Ok(mega_ref_event::Entity::find()
+            .filter(mega_ref_event::Column::CreatedAt.gt(after))
+            .order_by_asc(mega_ref_event::Column::CreatedAt)
+            .order_by_asc(mega_ref_event::Column::Id)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Removes the events up to `up_to` created before `before`.
+    pub async fn remove_ref_events(
+        &self,
+        up_to: i64,
+        before: NaiveDateTime,
+    ) -> Result<u64, MegaError> {
+        let res = mega_ref_event::Entity::delete_many()
+            .filter(mega_ref_event::Column::Id.lte(up_to))
+            .filter(mega_ref_event::Column::CreatedAt.lt(before))
+            .exec(self.get_connection())
+            .await?;
+        Ok(res.rows_affected)
+    }
+
+    pub async fn get_cursor(&self, observer: &str) -> Result<Option<i64>, MegaError> {
+        Ok(
+            mega_ref_event_cursor::Entity::find_by_id(observer.to_owned())
+                .one(self.get_connection())
+                .await?
+                .map(|cursor| cursor.event_id),
+        )
+    }
+
+    /// Leases the cursor of `observer` to `lease_id` until `expires_at` and returns it, None
+    /// while another lease of it has not expired. The cursor of a new observer is created at
+    /// the last event recorded, it is only delivered the events recorded after it.
+    pub async fn acquire_cursor(
+        &self,
+        observer: &str,
+        lease_id: i64,
+        expires_at: NaiveDateTime,
+    ) -> Result<Option<i64>, MegaError> {
+        let now = Utc::now().naive_utc();
+        let res = mega_ref_event_cursor::Entity::update_many()
+            .col_expr(mega_ref_event_cursor::Column::LeaseId, Expr::value(lease_id))
+            .col_expr(
+                mega_ref_event_cursor::Column::LeaseExpiresAt,
+                Expr::value(expires_at),
+            )
+            .filter(mega_ref_event_cursor::Column::Observer.eq(observer))
+            .filter(
+                Condition::any()
+                    .add(mega_ref_event_cursor::Column::LeaseExpiresAt.is_null())
+                    .add(mega_ref_event_cursor::Column::LeaseExpiresAt.lt(now)),
+            )
+            .exec(self.get_connection())
+            .await?;
+        if res.rows_affected > 0 {
+            return self.get_cursor(observer).await;
+        }
+        if self.get_cursor(observer).await?.is_some() {
+            return Ok(None);
+        }
+        let last = mega_ref_event::Entity::find()
+            .order_by_desc(mega_ref_event::Column::Id)
+            .one(self.get_connection())
+            .await?
+            .map_or(0, |event| event.id);
+        let cursor = mega_ref_event_cursor::Model {
+            observer: observer.to_owned(),
+            event_id: last,
+            updated_at: now,
+            lease_id: Some(lease_id),
+            lease_expires_at: Some(expires_at),
+        };
+        // another server may create it first, the lease is then its own
+        let inserted = mega_ref_event_cursor::Entity::insert(cursor.into_active_model())
+            .on_conflict(
+                OnConflict::column(mega_ref_event_cursor::Column::Observer)
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(self.get_connection())
+            .await?;
+        Ok((inserted > 0).then_some(last))
+    }
+
+    /// Moves the cursor of `observer` from `from` to `to` and extends its lease until
+    /// `expires_at`, false when `lease_id` no longer holds it.
+    pub async fn advance_cursor(
+        &self,
+        observer: &str,
+        lease_id: i64,
+        from: i64,
+        to: i64,
+        expires_at: NaiveDateTime,
+    ) -> Result<bool, MegaError> {
+        let res = mega_ref_event_cursor::Entity::update_many()
+            .col_expr(mega_ref_event_cursor::Column::EventId, Expr::value(to))
+            .col_expr(
+                mega_ref_event_cursor::Column::UpdatedAt,
+                Expr::value(Utc::now().naive_utc()),
+            )
+            .col_expr(
+                mega_ref_event_cursor::Column::LeaseExpiresAt,
+                Expr::value(expires_at),
+            )
+            .filter(mega_ref_event_cursor::Column::Observer.eq(observer))
+            .filter(mega_ref_event_cursor::Column::LeaseId.eq(lease_id))
+            .filter(mega_ref_event_cursor::Column::EventId.eq(from))
+            .exec(self.get_connection())
+            .await?;
+        Ok(res.rows_affected > 0)
+    }
+
+    /// Ends the lease `lease_id` of the cursor of `observer`, if it still holds it.
+    pub async fn release_cursor(&self, observer: &str, lease_id: i64) -> Result<(), MegaError> {
+        mega_ref_event_cursor::Entity::update_many()
+            .col_expr(
+                mega_ref_event_cursor::Column::LeaseId,
+                Expr::value(Option::<i64>::None),
+            )
+            .col_expr(
+                mega_ref_event_cursor::Column::LeaseExpiresAt,
+                Expr::value(Option::<NaiveDateTime>::None),
+            )
+            .filter(mega_ref_event_cursor::Column::Observer.eq(observer))
+            .filter(mega_ref_event_cursor::Column::LeaseId.eq(lease_id))
+            .exec(self.get_connection())
+            .await?;
+        Ok(())
+    }
+}
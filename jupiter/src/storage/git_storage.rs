@@ -65,6 +65,7 @@ impl GitStorageProvider for GitStorage {
                 obj_type,
                 data: data.to_vec(),
                 hash,
+                crc32: None,
             })
         }
         Ok(res)
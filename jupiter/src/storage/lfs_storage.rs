@@ -1,7 +1,10 @@
 use std::sync::Arc;
 
-use callisto::{lfs_locks, lfs_objects};
-use sea_orm::{DatabaseConnection, EntityTrait, InsertResult, IntoActiveModel};
+use callisto::{lfs_locks, lfs_objects, mega_lfs_commit_map, mega_lfs_object};
+use sea_orm::{
+    sea_query::OnConflict, ColumnTrait, DatabaseConnection, EntityTrait, InsertResult,
+    IntoActiveModel, QueryFilter, QueryOrder, QuerySelect,
+};
 
 use common::errors::MegaError;
 
@@ -47,6 +50,9 @@ impl LfsStorage {
     }
 
     pub async fn delete_lfs_object(&self, oid: String) -> Result<(), MegaError> {
+        mega_lfs_object::Entity::delete_by_id(oid.clone())
+            .exec(self.get_connection())
+            .await?;
         lfs_objects::Entity::delete_by_id(oid)
             .exec(self.get_connection())
             .await
@@ -54,6 +60,62 @@ impl LfsStorage {
         Ok(())
     }
 
+    /// Records where the content of an object was stored, replaces the former location of a
+    /// content uploaded again.
+    pub async fn save_lfs_location(&self, object: mega_lfs_object::Model) -> Result<(), MegaError> {
+        mega_lfs_object::Entity::insert(object.into_active_model())
+            .on_conflict(
+                OnConflict::column(mega_lfs_object::Column::Oid)
+                    .update_columns([
+                        mega_lfs_object::Column::Size,
+                        mega_lfs_object::Column::StorageType,
+                        mega_lfs_object::Column::Location,
+                        mega_lfs_object::Column::CreatedAt,
+                    ])
+                    .to_owned(),
+            )
+            .exec(self.get_connection())
+            .await?;
+        Ok(())
+    }
+
+    /// The oids of the stored LFS objects after `after`, at most `limit` of them, in order.
+    pub async fn get_lfs_oids(&self, after: &str, limit: u64) -> Result<Vec<String>, MegaError> {
+        Ok(mega_lfs_object::Entity::find()
+            .select_only()
+            .column(mega_lfs_object::Column::Oid)
+            .filter(mega_lfs_object::Column::Oid.gt(after))
+            .order_by_asc(mega_lfs_object::Column::Oid)
+            .limit(limit)
+            .into_tuple()
+            .all(self.get_connection())
+            .await?)
+    }
+
+    pub async fn get_lfs_location(
+        &self,
+        oid: &str,
+    ) -> Result<Option<mega_lfs_object::Model>, MegaError> {
+        Ok(mega_lfs_object::Entity::find_by_id(oid)
+            .one(self.get_connection())
+            .await?)
+    }
+
+    /// Records the commits an LFS migration rewrote, see `mega service lfs-migrate`.
+    pub async fn save_commit_map(
+        &self,
+        mappings: Vec<mega_lfs_commit_map::Model>,
+    ) -> Result<(), MegaError> {
+        for chunk in mappings.chunks(1000) {
+            mega_lfs_commit_map::Entity::insert_many(
+                chunk.iter().cloned().map(|m| m.into_active_model()),
+            )
+            .exec_without_returning(self.get_connection())
+            .await?;
+        }
+        Ok(())
+    }
+
     pub async fn new_lock(
         &self,
         lfs_lock: lfs_locks::Model,
@@ -1,23 +1,36 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
 use std::rc::Rc;
 use std::str::FromStr;
 use std::{env, sync::Arc};
 
 use async_trait::async_trait;
 use sea_orm::{
-    ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter,
+    sea_query::{Expr, Func, OnConflict},
+    ActiveModelTrait, ActiveValue, ColumnTrait, Condition, ConnectionTrait, DatabaseConnection,
+    EntityTrait, IntoActiveModel, QueryFilter, QueryOrder, QuerySelect, Set, TransactionTrait,
 };
+use serde::{Deserialize, Serialize};
 
-use callisto::db_enums::MergeStatus;
-use callisto::{git_repo, mega_commit, mega_mr, mega_tree, refs};
+use callisto::db_enums::{MergeStatus, StorageType};
+use callisto::{
+    git_repo, mega_blob, mega_branch_cleanup, mega_commit, mega_issue, mega_mr, mega_mr_archive,
+    mega_object_pin, mega_path_commit, mega_read_lease, mega_tag, mega_tree, raw_blob, refs,
+};
+use chrono::{NaiveDateTime, Utc};
 use common::errors::MegaError;
+use common::utils::generate_id;
 use ganymede::mega_node::MegaNode;
 use ganymede::model::converter::{self, MegaModelConverter};
 use ganymede::model::create_file::CreateFileInfo;
+use storage::driver::database::encrypted_storage::{
+    self, decrypt, encrypt, is_current, rewrap, KeyProvider,
+};
 use storage::driver::database::storage::batch_save_model;
 use venus::hash::SHA1;
 use venus::internal::object::blob::Blob;
 use venus::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+use venus::internal::object::types::ObjectType;
 use venus::internal::pack::entry::MegaModel;
 use venus::internal::pack::reference::CommandType;
 use venus::internal::{
@@ -28,15 +41,256 @@ use venus::mr::MergeRequest;
 use venus::repo::Repo;
 
 use crate::{
+    hooks::{Merge, MergeHooks},
     raw_storage::{self, RawStorage},
     storage::GitStorageProvider,
 };
 
+/// Directory of the archive storage holding the merge request archives.
+const MR_ARCHIVE_DIR: &str = "mr";
+/// Repository of the raw storage holding the content of the blobs not kept in the database,
+/// those whose `raw_blob` row has another storage type.
+pub const RAW_BLOB_DIR: &str = "raw_blob";
+
+/// Everything the database held for a merge request when it was archived.
+#[derive(Serialize, Deserialize)]
+struct MrArchive {
+    mr: mega_mr::Model,
+    commits: Vec<mega_commit::Model>,
+    trees: Vec<mega_tree::Model>,
+}
+
+/// The ids of objects stored in the database, by type.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoredObjects {
+    pub commits: Vec<String>,
+    pub trees: Vec<String>,
+    pub blobs: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+impl StoredObjects {
+    pub fn len(&self) -> usize {
+        self.commits.len() + self.trees.len() + self.blobs.len() + self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Keeps the objects for which `f` returns true.
+    pub fn retain(&mut self, mut f: impl FnMut(&str) -> bool) {
+        for ids in [
+            &mut self.commits,
+            &mut self.trees,
+            &mut self.blobs,
+            &mut self.tags,
+        ] {
+            ids.retain(|id| f(id));
+        }
+    }
+}
+
+fn archive_object_id(mr_id: i64) -> String {
+    format!("{:016x}", mr_id)
+}
+
+/// Matches the rows where `column` contains every one of `terms`, ignoring case.
+fn contains_all(column: impl ColumnTrait, terms: &[String]) -> Condition {
+    terms.iter().fold(Condition::all(), |condition, term| {
+        condition.add(
+            Expr::expr(Func::lower(Expr::col(column.as_column_ref())))
+                .like(format!("%{}%", term.to_lowercase())),
+        )
+    })
+}
+
+/// Inserts `models` in chunks, the rows already saved are skipped. Unlike `batch_save_model`,
+/// a failure is returned, so that the transaction of a merge is rolled back.
+async fn insert_models<A>(
+    connection: &impl ConnectionTrait,
+    models: Vec<A>,
+) -> Result<(), MegaError>
+where
+    A: ActiveModelTrait + Send,
+    <A::Entity as EntityTrait>::Model: IntoActiveModel<A>,
+{
+    for chunk in models.chunks(1000) {
+        A::Entity::insert_many(chunk.iter().cloned())
+            .on_conflict(OnConflict::new().do_nothing().to_owned())
+            .do_nothing()
+            .exec_without_returning(connection)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Inserts the objects of `models` like `insert_models`, but an object already saved has its
+/// `created_at` refreshed, so that a gc which listed it as old does not remove it while a push
+/// references it again.
+async fn insert_objects<A>(
+    connection: &impl ConnectionTrait,
+    models: Vec<A>,
+    id: <A::Entity as EntityTrait>::Column,
+    created_at: <A::Entity as EntityTrait>::Column,
+) -> Result<(), MegaError>
+where
+    A: ActiveModelTrait + Send,
+    <A::Entity as EntityTrait>::Model: IntoActiveModel<A>,
+{
+    for chunk in models.chunks(1000) {
+        A::Entity::insert_many(chunk.iter().cloned())
+            .on_conflict(OnConflict::column(id).update_column(created_at).to_owned())
+            .do_nothing()
+            .exec_without_returning(connection)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Seals the content of `raw` with `keys`, when the encryption at rest is enabled.
+fn seal_raw_blob(
+    keys: Option<&dyn KeyProvider>,
+    raw: &mut raw_blob::ActiveModel,
+) -> Result<(), MegaError> {
+    if let (Some(keys), ActiveValue::Set(Some(data))) = (keys, &raw.data) {
+        raw.data = Set(Some(encrypt(keys, raw.sha1.as_ref(), data)?));
+    }
+    Ok(())
+}
+
+/// Saves `entry_list` as the objects of `mr` in `repo`, the content of the blobs sealed with
+/// `keys`.
+async fn save_entries(
+    connection: &impl ConnectionTrait,
+    mr: &MergeRequest,
+    repo: &Repo,
+    entry_list: Vec<Entry>,
+    keys: Option<&dyn KeyProvider>,
+) -> Result<(), MegaError> {
+    let mut commits = Vec::new();
+    let mut trees = Vec::new();
+    let mut blobs = Vec::new();
+    let mut raw_blobs = Vec::new();
+    let mut tags = Vec::new();
+    for entry in entry_list {
+        let raw_obj = entry.process_entry();
+        let mega_model = raw_obj.convert_to_mega_model(repo.repo_id, mr.id);
+        match mega_model {
+            MegaModel::Commit(commit) => commits.push(commit),
+            MegaModel::Tree(tree) => trees.push(tree),
+            MegaModel::Blob(blob, mut raw) => {
+                seal_raw_blob(keys, &mut raw)?;
+                blobs.push(blob);
+                raw_blobs.push(raw);
+            }
+            MegaModel::Tag(tag) => tags.push(tag),
+        }
+    }
+    insert_objects(
+        connection,
+        commits,
+        mega_commit::Column::CommitId,
+        mega_commit::Column::CreatedAt,
+    )
+    .await?;
+    insert_objects(
+        connection,
+        trees,
+        mega_tree::Column::TreeId,
+        mega_tree::Column::CreatedAt,
+    )
+    .await?;
+    insert_objects(
+        connection,
+        blobs,
+        mega_blob::Column::BlobId,
+        mega_blob::Column::CreatedAt,
+    )
+    .await?;
+    insert_objects(
+        connection,
+        raw_blobs,
+        raw_blob::Column::Sha1,
+        raw_blob::Column::CreatedAt,
+    )
+    .await?;
+    insert_objects(
+        connection,
+        tags,
+        mega_tag::Column::TagId,
+        mega_tag::Column::CreatedAt,
+    )
+    .await
+}
+
+/// Applies `command` to the refs of `repo`.
+async fn apply_ref_command(
+    connection: &impl ConnectionTrait,
+    repo: &Repo,
+    command: &RefCommand,
+) -> Result<(), MegaError> {
+    let by_name = Condition::all()
+        .add(refs::Column::RepoId.eq(repo.repo_id))
+        .add(refs::Column::RefName.eq(command.ref_name.clone()));
+    match command.command_type {
+        CommandType::Create => {
+            let mut model: refs::Model = command.clone().into();
+            model.ref_git_id = command.new_id.clone();
+            model.repo_id = repo.repo_id;
+            refs::Entity::insert(model.into_active_model())
+                .exec_without_returning(connection)
+                .await?;
+        }
+        CommandType::Delete => {
+            let res = refs::Entity::delete_many()
+                .filter(by_name.add(refs::Column::RefGitId.eq(command.old_id.clone())))
+                .exec(connection)
+                .await?;
+            if res.rows_affected == 0 {
+                return Err(stale_ref(command));
+            }
+        }
+        CommandType::Update => {
+            let res = refs::Entity::update_many()
+                .col_expr(refs::Column::RefGitId, Expr::value(command.new_id.clone()))
+                .col_expr(refs::Column::UpdatedAt, Expr::value(Utc::now().naive_utc()))
+                .filter(by_name.add(refs::Column::RefGitId.eq(command.old_id.clone())))
+                .exec(connection)
+                .await?;
+            if res.rows_affected == 0 {
+                return Err(stale_ref(command));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The error of a ref update whose old id is no longer the one stored, the ref was updated
+/// after the checks of the push.
+fn stale_ref(command: &RefCommand) -> MegaError {
+    MegaError::with_message(&format!(
+        "{} is no longer at {}",
+        command.ref_name, command.old_id
+    ))
+}
+
 #[derive(Clone)]
 pub struct MegaStorage {
     pub raw_storage: Arc<dyn RawStorage>,
+    /// Cold storage of the archived merge requests, see [`MegaStorage::archive_mr`].
+    pub archive_storage: Arc<dyn RawStorage>,
     pub connection: Arc<DatabaseConnection>,
     pub raw_obj_threshold: usize,
+    /// The keys sealing the blobs kept in the database, `None` without encryption at rest.
+    pub keys: Option<Arc<dyn KeyProvider>>,
+}
+
+/// What [`MegaStorage::rewrap_objects`] rewrote with the current key encryption key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RewrapReport {
+    pub blobs: usize,
+    pub archives: usize,
 }
 
 #[async_trait]
@@ -155,10 +409,16 @@ impl MegaStorage {
             .unwrap();
         let storage_type = env::var("MEGA_RAW_STORAGE").unwrap();
         let path = env::var("MEGA_OBJ_LOCAL_PATH").unwrap();
+        let archive_path = env::var("MEGA_MR_ARCHIVE_PATH")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .unwrap_or_else(|| format!("{}/archive", path));
         MegaStorage {
             connection,
-            raw_storage: raw_storage::init(storage_type, path).await,
+            raw_storage: raw_storage::init(storage_type.clone(), path).await,
+            archive_storage: raw_storage::init(storage_type, archive_path).await,
             raw_obj_threshold,
+            keys: encrypted_storage::keys_from_env(),
         }
     }
 
@@ -166,7 +426,9 @@ impl MegaStorage {
         MegaStorage {
             connection: Arc::new(DatabaseConnection::default()),
             raw_storage: raw_storage::mock(),
+            archive_storage: raw_storage::mock(),
             raw_obj_threshold: 1024,
+            keys: None,
         }
     }
 
@@ -191,6 +453,597 @@ impl MegaStorage {
             .await?)
     }
 
+    /// Pins a commit or a tree of `repo`, keeping it and everything it reaches across ref
+    /// deletions. Pinning an object again only updates the reason.
+    pub async fn pin_object(
+        &self,
+        repo: &Repo,
+        object_id: &str,
+        object_type: &str,
+        reason: &str,
+    ) -> Result<mega_object_pin::Model, MegaError> {
+        if let Some(pin) = self.get_pin(repo, object_id).await? {
+            let mut pin = pin.into_active_model();
+            pin.reason = Set(reason.to_owned());
+            return Ok(pin.update(self.get_connection()).await?);
+        }
+        let model = mega_object_pin::Model {
+            id: generate_id(),
+            repo_id: repo.repo_id,
+            object_id: object_id.to_owned(),
+            object_type: object_type.to_owned(),
+            reason: reason.to_owned(),
+            created_at: Utc::now().naive_utc(),
+        };
+        mega_object_pin::Entity::insert(model.clone().into_active_model())
+            .exec(self.get_connection())
+            .await?;
+        Ok(model)
+    }
+
+    pub async fn get_pin(
+        &self,
+        repo: &Repo,
+        object_id: &str,
+    ) -> Result<Option<mega_object_pin::Model>, MegaError> {
+        Ok(mega_object_pin::Entity::find()
+            .filter(mega_object_pin::Column::RepoId.eq(repo.repo_id))
+            .filter(mega_object_pin::Column::ObjectId.eq(object_id))
+            .one(self.get_connection())
+            .await?)
+    }
+
+    pub async fn get_pins(&self, repo: &Repo) -> Result<Vec<mega_object_pin::Model>, MegaError> {
+        Ok(mega_object_pin::Entity::find()
+            .filter(mega_object_pin::Column::RepoId.eq(repo.repo_id))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Returns whether a pin was removed.
+    pub async fn unpin_object(&self, repo: &Repo, object_id: &str) -> Result<bool, MegaError> {
+        let res = mega_object_pin::Entity::delete_many()
+            .filter(mega_object_pin::Column::RepoId.eq(repo.repo_id))
+            .filter(mega_object_pin::Column::ObjectId.eq(object_id))
+            .exec(self.get_connection())
+            .await?;
+        Ok(res.rows_affected > 0)
+    }
+
+    /// The branches of `repo` waiting for their scheduled deletion.
+    pub async fn get_branch_cleanups(
+        &self,
+        repo: &Repo,
+    ) -> Result<Vec<mega_branch_cleanup::Model>, MegaError> {
+        Ok(mega_branch_cleanup::Entity::find()
+            .filter(mega_branch_cleanup::Column::RepoId.eq(repo.repo_id))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    pub async fn save_branch_cleanup(
+        &self,
+        model: mega_branch_cleanup::Model,
+    ) -> Result<(), MegaError> {
+        mega_branch_cleanup::Entity::insert(model.into_active_model())
+            .exec(self.get_connection())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_branch_cleanup(&self, id: i64) -> Result<(), MegaError> {
+        mega_branch_cleanup::Entity::delete_by_id(id)
+            .exec(self.get_connection())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_git_repos(&self) -> Result<Vec<git_repo::Model>, MegaError> {
+        Ok(git_repo::Entity::find().all(self.get_connection()).await?)
+    }
+
+    /// Protects `commit_ids` from being archived while a clone or fetch reads them, until the
+    /// returned lease is released or `ttl` has passed.
+    pub async fn acquire_read_lease(
+        &self,
+        repo: &Repo,
+        commit_ids: &[String],
+        ttl: chrono::Duration,
+    ) -> Result<i64, MegaError> {
+        let lease_id = generate_id();
+        let expires_at = Utc::now().naive_utc() + ttl;
+        let models: Vec<mega_read_lease::ActiveModel> = commit_ids
+            .iter()
+            .map(|commit_id| {
+                mega_read_lease::Model {
+                    id: generate_id(),
+                    lease_id,
+                    repo_id: repo.repo_id,
+                    commit_id: commit_id.clone(),
+                    expires_at,
+                }
+                .into_active_model()
+            })
+            .collect();
+        if !models.is_empty() {
+            mega_read_lease::Entity::insert_many(models)
+                .exec(self.get_connection())
+                .await?;
+        }
+        Ok(lease_id)
+    }
+
+    /// Releases a lease, the leases that expired without being released are removed as well.
+    pub async fn release_read_lease(&self, lease_id: i64) -> Result<(), MegaError> {
+        mega_read_lease::Entity::delete_many()
+            .filter(
+                mega_read_lease::Column::LeaseId
+                    .eq(lease_id)
+                    .or(mega_read_lease::Column::ExpiresAt.lt(Utc::now().naive_utc())),
+            )
+            .exec(self.get_connection())
+            .await?;
+        Ok(())
+    }
+
+    /// Whether one of `commit_ids` is held by a read lease that has not expired.
+    pub async fn is_read_leased(&self, commit_ids: Vec<String>) -> Result<bool, MegaError> {
+        let now = Utc::now().naive_utc();
+        for chunk in commit_ids.chunks(1000) {
+            let leased = mega_read_lease::Entity::find()
+                .filter(mega_read_lease::Column::CommitId.is_in(chunk.to_vec()))
+                .filter(mega_read_lease::Column::ExpiresAt.gt(now))
+                .one(self.get_connection())
+                .await?;
+            if leased.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// The objects garbage collection must keep: everything reachable from the refs and the
+    /// pins of `repo`. Objects missing from the database are skipped, they have nothing to keep.
+    pub async fn reachable_objects(&self, repo: &Repo) -> Result<HashSet<String>, MegaError> {
+        let (commits, trees) = self.reachable_roots(repo).await?;
+        let mut reachable = HashSet::new();
+        self.walk_reachable(repo, commits, trees, &mut reachable, usize::MAX)
+            .await?;
+        Ok(reachable.into_iter().map(|id| id.to_plain_str()).collect())
+    }
+
+    /// The commits and the trees of `repo` the walk of [`Self::reachable_objects`] starts from:
+    /// the targets of its refs and its pins.
+    pub async fn reachable_roots(
+        &self,
+        repo: &Repo,
+    ) -> Result<(Vec<String>, Vec<String>), MegaError> {
+        let mut commits: Vec<String> = self
+            .get_repo_refs(repo)
+            .await?
+            .into_iter()
+            .map(|r| r.ref_git_id)
+            .collect();
+        let mut trees: Vec<String> = Vec::new();
+        for pin in self.get_pins(repo).await? {
+            match pin.object_type.as_str() {
+                "tree" => trees.push(pin.object_id),
+                _ => commits.push(pin.object_id),
+            }
+        }
+        Ok((commits, trees))
+    }
+
+    /// Adds to `reachable` the objects of `repo` reachable from `commits` and `trees`, the
+    /// walk stops at the objects already in it. An annotated tag is followed to its object.
+    /// Fails once `reachable` holds more than `limit` objects, rather than running out of
+    /// memory on a huge history.
+    pub async fn walk_reachable(
+        &self,
+        repo: &Repo,
+        commits: Vec<String>,
+        trees: Vec<String>,
+        reachable: &mut HashSet<SHA1>,
+        limit: usize,
+    ) -> Result<(), MegaError> {
+        let parse = |ids: Vec<String>| -> Vec<SHA1> {
+            ids.iter()
+                .filter_map(|id| SHA1::from_str(id).ok())
+                .collect()
+        };
+        let (mut commits, mut trees) = (parse(commits), parse(trees));
+        let insert = |reachable: &mut HashSet<SHA1>, id: SHA1| {
+            if reachable.len() >= limit && !reachable.contains(&id) {
+                return Err(MegaError::with_message(&format!(
+                    "more than {} objects are reachable",
+                    limit
+                )));
+            }
+            Ok(reachable.insert(id))
+        };
+        while let Some(id) = commits.pop() {
+            if !insert(reachable, id)? {
+                continue;
+            }
+            let plain = id.to_plain_str();
+            if let Some(model) = self.get_commit_by_hash(&plain, repo).await? {
+                let commit: Commit = model.into();
+                trees.push(commit.tree_id);
+                commits.extend(commit.parent_commit_ids);
+            } else if let Some(tag) = mega_tag::Entity::find()
+                .filter(mega_tag::Column::TagId.eq(&plain))
+                .one(self.get_connection())
+                .await?
+            {
+                let Ok(object_id) = SHA1::from_str(&tag.object_id) else {
+                    continue;
+                };
+                match tag.object_type.as_str() {
+                    "tree" => trees.push(object_id),
+                    "blob" => {
+                        insert(reachable, object_id)?;
+                    }
+                    _ => commits.push(object_id),
+                }
+            }
+        }
+        while let Some(id) = trees.pop() {
+            if !insert(reachable, id)? {
+                continue;
+            }
+            if let Some(model) = self.get_mega_tree_by_sha(&id.to_plain_str()).await? {
+                let tree: Tree = model.into();
+                for item in tree.tree_items {
+                    match item.mode {
+                        TreeItemMode::Tree => trees.push(item.id),
+                        _ => {
+                            insert(reachable, item.id)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the commit or the annotated tag `id` a ref of `repo` may point to is stored.
+    pub async fn has_ref_target(&self, repo: &Repo, id: &str) -> Result<bool, MegaError> {
+        if self.get_commit_by_hash(id, repo).await?.is_some() {
+            return Ok(true);
+        }
+        Ok(mega_tag::Entity::find()
+            .filter(mega_tag::Column::TagId.eq(id))
+            .one(self.get_connection())
+            .await?
+            .is_some())
+    }
+
+    /// The commits garbage collection must keep besides those of the refs, by repository id:
+    /// the commits carried by an open merge request and those held by a read lease.
+    pub async fn gc_protected_commits(&self) -> Result<HashMap<i64, Vec<String>>, MegaError> {
+        let open_mrs: Vec<i64> = mega_mr::Entity::find()
+            .select_only()
+            .column(mega_mr::Column::Id)
+            .filter(mega_mr::Column::Status.eq(MergeStatus::Open))
+            .into_tuple()
+            .all(self.get_connection())
+            .await?;
+        let mut protected: HashMap<i64, Vec<String>> = HashMap::new();
+        for chunk in open_mrs.chunks(1000) {
+            let commits: Vec<(i64, String)> = mega_commit::Entity::find()
+                .select_only()
+                .columns([mega_commit::Column::RepoId, mega_commit::Column::CommitId])
+                .filter(mega_commit::Column::MrId.is_in(chunk.to_vec()))
+                .into_tuple()
+                .all(self.get_connection())
+                .await?;
+            for (repo_id, commit_id) in commits {
+                protected.entry(repo_id).or_default().push(commit_id);
+            }
+        }
+        let leased: Vec<(i64, String)> = mega_read_lease::Entity::find()
+            .select_only()
+            .columns([
+                mega_read_lease::Column::RepoId,
+                mega_read_lease::Column::CommitId,
+            ])
+            .filter(mega_read_lease::Column::ExpiresAt.gt(Utc::now().naive_utc()))
+            .into_tuple()
+            .all(self.get_connection())
+            .await?;
+        for (repo_id, commit_id) in leased {
+            protected.entry(repo_id).or_default().push(commit_id);
+        }
+        Ok(protected)
+    }
+
+    /// The objects stored before `before`, those carried by an open merge request left out:
+    /// they are the only ones garbage collection may remove.
+    pub async fn stored_objects(&self, before: NaiveDateTime) -> Result<StoredObjects, MegaError> {
+        let open_mrs: Vec<i64> = mega_mr::Entity::find()
+            .select_only()
+            .column(mega_mr::Column::Id)
+            .filter(mega_mr::Column::Status.eq(MergeStatus::Open))
+            .into_tuple()
+            .all(self.get_connection())
+            .await?;
+        let connection = self.get_connection();
+        let commits = mega_commit::Entity::find()
+            .select_only()
+            .column(mega_commit::Column::CommitId)
+            .filter(mega_commit::Column::CreatedAt.lt(before))
+            .filter(mega_commit::Column::MrId.is_not_in(open_mrs.clone()))
+            .into_tuple()
+            .all(connection)
+            .await?;
+        let trees = mega_tree::Entity::find()
+            .select_only()
+            .column(mega_tree::Column::TreeId)
+            .filter(mega_tree::Column::CreatedAt.lt(before))
+            .filter(mega_tree::Column::MrId.is_not_in(open_mrs.clone()))
+            .into_tuple()
+            .all(connection)
+            .await?;
+        // the content of a blob is in raw_blob, mega_blob knows its merge request
+        let open_blobs: HashSet<String> = mega_blob::Entity::find()
+            .select_only()
+            .column(mega_blob::Column::BlobId)
+            .filter(mega_blob::Column::MrId.is_in(open_mrs))
+            .into_tuple::<String>()
+            .all(connection)
+            .await?
+            .into_iter()
+            .collect();
+        let blobs = raw_blob::Entity::find()
+            .select_only()
+            .column(raw_blob::Column::Sha1)
+            .filter(raw_blob::Column::CreatedAt.lt(before))
+            .into_tuple::<String>()
+            .all(connection)
+            .await?
+            .into_iter()
+            .filter(|id| !open_blobs.contains(id))
+            .collect();
+        let tags = mega_tag::Entity::find()
+            .select_only()
+            .column(mega_tag::Column::TagId)
+            .filter(mega_tag::Column::CreatedAt.lt(before))
+            .into_tuple()
+            .all(connection)
+            .await?;
+        Ok(StoredObjects {
+            commits,
+            trees,
+            blobs,
+            tags,
+        })
+    }
+
+    /// All the objects [`get_entries`](Self::get_entries) reads: the commits, the trees and the
+    /// blobs whose content is in the database.
+    pub async fn object_ids(&self) -> Result<StoredObjects, MegaError> {
+        let connection = self.get_connection();
+        let commits = mega_commit::Entity::find()
+            .select_only()
+            .column(mega_commit::Column::CommitId)
+            .into_tuple()
+            .all(connection)
+            .await?;
+        let trees = mega_tree::Entity::find()
+            .select_only()
+            .column(mega_tree::Column::TreeId)
+            .into_tuple()
+            .all(connection)
+            .await?;
+        let blobs = raw_blob::Entity::find()
+            .select_only()
+            .column(raw_blob::Column::Sha1)
+            .filter(raw_blob::Column::StorageType.eq(StorageType::Database))
+            .filter(raw_blob::Column::Data.is_not_null())
+            .into_tuple()
+            .all(connection)
+            .await?;
+        Ok(StoredObjects {
+            commits,
+            trees,
+            blobs,
+            tags: Vec::new(),
+        })
+    }
+
+    /// Removes `objects` stored before `before` from the database, all of them or none, then
+    /// the content of their blobs kept in the raw storage. An object pushed again since it was
+    /// listed has a later `created_at` and is kept. A content left behind by a failed removal is
+    /// only logged, nothing refers to it anymore.
+    pub async fn remove_objects(
+        &self,
+        objects: &StoredObjects,
+        before: NaiveDateTime,
+    ) -> Result<(), MegaError> {
+        let txn = self.get_connection().begin().await?;
+        // the rows are locked, a push cannot refresh a blob whose content is about to go
+        let mut raw_contents: Vec<String> = Vec::new();
+        for chunk in objects.blobs.chunks(1000) {
+            let found: Vec<String> = raw_blob::Entity::find()
+                .select_only()
+                .column(raw_blob::Column::Sha1)
+                .filter(raw_blob::Column::Sha1.is_in(chunk.to_vec()))
+                .filter(raw_blob::Column::CreatedAt.lt(before))
+                .filter(raw_blob::Column::StorageType.ne(StorageType::Database))
+                .lock_exclusive()
+                .into_tuple()
+                .all(&txn)
+                .await?;
+            raw_contents.extend(found);
+        }
+        for chunk in objects.commits.chunks(1000) {
+            mega_commit::Entity::delete_many()
+                .filter(mega_commit::Column::CommitId.is_in(chunk.to_vec()))
+                .filter(mega_commit::Column::CreatedAt.lt(before))
+                .exec(&txn)
+                .await?;
+        }
+        for chunk in objects.trees.chunks(1000) {
+            mega_tree::Entity::delete_many()
+                .filter(mega_tree::Column::TreeId.is_in(chunk.to_vec()))
+                .filter(mega_tree::Column::CreatedAt.lt(before))
+                .exec(&txn)
+                .await?;
+        }
+        for chunk in objects.blobs.chunks(1000) {
+            mega_blob::Entity::delete_many()
+                .filter(mega_blob::Column::BlobId.is_in(chunk.to_vec()))
+                .filter(mega_blob::Column::CreatedAt.lt(before))
+                .exec(&txn)
+                .await?;
+            raw_blob::Entity::delete_many()
+                .filter(raw_blob::Column::Sha1.is_in(chunk.to_vec()))
+                .filter(raw_blob::Column::CreatedAt.lt(before))
+                .exec(&txn)
+                .await?;
+        }
+        for chunk in objects.tags.chunks(1000) {
+            mega_tag::Entity::delete_many()
+                .filter(mega_tag::Column::TagId.is_in(chunk.to_vec()))
+                .filter(mega_tag::Column::CreatedAt.lt(before))
+                .exec(&txn)
+                .await?;
+        }
+        txn.commit().await?;
+        for id in raw_contents {
+            if let Err(e) = self.raw_storage.delete_object(RAW_BLOB_DIR, &id).await {
+                tracing::warn!("Failed to remove the content of the blob {}: {}", id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// The blobs of `ids` whose content is kept in the database.
+    pub async fn get_database_blobs(&self, ids: &[String]) -> Result<Vec<String>, MegaError> {
+        let mut blobs = Vec::new();
+        for chunk in ids.chunks(1000) {
+            let found: Vec<String> = raw_blob::Entity::find()
+                .select_only()
+                .column(raw_blob::Column::Sha1)
+                .filter(raw_blob::Column::Sha1.is_in(chunk.to_vec()))
+                .filter(raw_blob::Column::StorageType.eq(StorageType::Database))
+                .filter(raw_blob::Column::Data.is_not_null())
+                .into_tuple()
+                .all(self.get_connection())
+                .await?;
+            blobs.extend(found);
+        }
+        Ok(blobs)
+    }
+
+    /// The trees of `ids` already stored.
+    pub async fn get_existing_trees(&self, ids: &[String]) -> Result<Vec<String>, MegaError> {
+        let mut trees = Vec::new();
+        for chunk in ids.chunks(1000) {
+            let found: Vec<String> = mega_tree::Entity::find()
+                .select_only()
+                .column(mega_tree::Column::TreeId)
+                .distinct()
+                .filter(mega_tree::Column::TreeId.is_in(chunk.to_vec()))
+                .into_tuple()
+                .all(self.get_connection())
+                .await?;
+            trees.extend(found);
+        }
+        Ok(trees)
+    }
+
+    /// The stored trees of `ids`.
+    pub async fn get_trees_by_hashes(
+        &self,
+        ids: &[String],
+    ) -> Result<Vec<mega_tree::Model>, MegaError> {
+        let mut trees = Vec::new();
+        for chunk in ids.chunks(1000) {
+            let found = mega_tree::Entity::find()
+                .filter(mega_tree::Column::TreeId.is_in(chunk.to_vec()))
+                .all(self.get_connection())
+                .await?;
+            trees.extend(found);
+        }
+        Ok(trees)
+    }
+
+    /// The blobs of `ids` already stored, wherever their content is kept.
+    pub async fn get_existing_blobs(&self, ids: &[String]) -> Result<Vec<String>, MegaError> {
+        let mut blobs = Vec::new();
+        for chunk in ids.chunks(1000) {
+            let found: Vec<String> = raw_blob::Entity::find()
+                .select_only()
+                .column(raw_blob::Column::Sha1)
+                .distinct()
+                .filter(raw_blob::Column::Sha1.is_in(chunk.to_vec()))
+                .into_tuple()
+                .all(self.get_connection())
+                .await?;
+            blobs.extend(found);
+        }
+        Ok(blobs)
+    }
+
+    /// The commits, trees and blobs of `objects` as pack entries, in that order. Tags are
+    /// left out, their tagger is not stored byte for byte, and so are the blobs whose
+    /// content is kept outside the database.
+    pub async fn get_entries(&self, objects: &StoredObjects) -> Result<Vec<Entry>, MegaError> {
+        let connection = self.get_connection();
+        let mut entries = Vec::with_capacity(objects.len());
+        for chunk in objects.commits.chunks(1000) {
+            let commits = mega_commit::Entity::find()
+                .filter(mega_commit::Column::CommitId.is_in(chunk.to_vec()))
+                .all(connection)
+                .await?;
+            for model in commits {
+                let commit: Commit = model.into();
+                entries.push(Entry {
+                    obj_type: ObjectType::Commit,
+                    data: commit
+                        .to_data()
+                        .map_err(|e| MegaError::with_message(&e.to_string()))?,
+                    hash: commit.id,
+                    crc32: None,
+                });
+            }
+        }
+        for chunk in objects.trees.chunks(1000) {
+            let trees = mega_tree::Entity::find()
+                .filter(mega_tree::Column::TreeId.is_in(chunk.to_vec()))
+                .all(connection)
+                .await?;
+            for model in trees {
+                let hash = SHA1::from_str(&model.tree_id).unwrap();
+                entries.push(Entry {
+                    obj_type: ObjectType::Tree,
+                    data: model.sub_trees,
+                    hash,
+                    crc32: None,
+                });
+            }
+        }
+        for chunk in objects.blobs.chunks(1000) {
+            let blobs = self.get_raw_blobs_by_hashes(chunk.to_vec()).await?;
+            for model in blobs {
+                if model.storage_type != StorageType::Database {
+                    continue;
+                }
+                let Some(data) = model.data else {
+                    continue;
+                };
+                entries.push(Entry {
+                    obj_type: ObjectType::Blob,
+                    data,
+                    hash: SHA1::from_str(&model.sha1).unwrap(),
+                    crc32: None,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
     pub async fn save_mr(&self, mr: MergeRequest) -> Result<(), MegaError> {
         let model: mega_mr::Model = mr.into();
         mega_mr::Entity::insert(model.into_active_model())
@@ -206,39 +1059,70 @@ impl MegaStorage {
         repo: &Repo,
         entry_list: Vec<Entry>,
     ) -> Result<(), MegaError> {
-        let mut commits = Vec::new();
-        let mut trees = Vec::new();
-        let mut blobs = Vec::new();
-        let mut raw_blobs = Vec::new();
-        let mut tags = Vec::new();
-        for entry in entry_list {
-            let raw_obj = entry.process_entry();
-            let mega_model = raw_obj.convert_to_mega_model(repo.repo_id, mr.id);
-            // self.save_entry_extend(mega_model);
-            match mega_model {
-                MegaModel::Commit(commit) => commits.push(commit),
-                MegaModel::Tree(tree) => trees.push(tree),
-                MegaModel::Blob(blob, raw) => {
-                    blobs.push(blob);
-                    raw_blobs.push(raw);
-                }
-                MegaModel::Tag(tag) => tags.push(tag),
+        save_entries(
+            self.get_connection(),
+            mr,
+            repo,
+            entry_list,
+            self.keys.as_deref(),
+        )
+        .await
+    }
+
+    /// Saves a push in one transaction: its merge request, the objects read from `entries`, the
+    /// ref updates and their events. The post-merge `hooks` run last, before the commit. When a
+    /// step fails, or a hook fails and `rollback_on_hook_failure` is set, nothing is saved.
+    pub async fn merge<I>(
+        &self,
+        merge: &Merge,
+        entries: I,
+        hooks: &MergeHooks,
+        rollback_on_hook_failure: bool,
+    ) -> Result<(), MegaError>
+    where
+        I: Iterator<Item = io::Result<Entry>> + Send,
+    {
+        let txn = self.get_connection().begin().await?;
+        let model: mega_mr::Model = merge.mr.clone().into();
+        mega_mr::Entity::insert(model.into_active_model())
+            .exec_without_returning(&txn)
+            .await?;
+        let mut entry_list = Vec::new();
+        for entry in entries {
+            entry_list.push(entry?);
+            if entry_list.len() >= 1000 {
+                let entry_list = std::mem::take(&mut entry_list);
+                save_entries(
+                    &txn,
+                    &merge.mr,
+                    &merge.repo,
+                    entry_list,
+                    self.keys.as_deref(),
+                )
+                .await?;
             }
         }
-
-        batch_save_model(self.get_connection(), commits)
-            .await
-            .unwrap();
-        batch_save_model(self.get_connection(), trees)
-            .await
-            .unwrap();
-        batch_save_model(self.get_connection(), blobs)
-            .await
-            .unwrap();
-        batch_save_model(self.get_connection(), raw_blobs)
-            .await
-            .unwrap();
-        batch_save_model(self.get_connection(), tags).await.unwrap();
+        save_entries(
+            &txn,
+            &merge.mr,
+            &merge.repo,
+            entry_list,
+            self.keys.as_deref(),
+        )
+        .await?;
+        for command in &merge.commands {
+            apply_ref_command(&txn, &merge.repo, command).await?;
+        }
+        let events = merge.events.iter().cloned();
+        insert_models(&txn, events.map(|e| e.into_active_model()).collect()).await?;
+        if let Err(err) = hooks.run(merge).await {
+            if rollback_on_hook_failure {
+                txn.rollback().await?;
+                return Err(err);
+            }
+            tracing::warn!("{}, the merge is kept", err);
+        }
+        txn.commit().await?;
         Ok(())
     }
 
@@ -264,7 +1148,10 @@ impl MegaStorage {
         batch_save_model(self.get_connection(), mega_blobs)
             .await
             .unwrap();
-        let raw_blobs = converter.raw_blobs.borrow().values().cloned().collect();
+        let mut raw_blobs: Vec<_> = converter.raw_blobs.borrow().values().cloned().collect();
+        for raw in raw_blobs.iter_mut() {
+            seal_raw_blob(self.keys.as_deref(), raw).unwrap();
+        }
         batch_save_model(self.get_connection(), raw_blobs)
             .await
             .unwrap();
@@ -383,6 +1270,401 @@ impl MegaStorage {
         Ok(())
     }
 
+    /// Records `commit_id` as the last commit touching each of `paths` on `ref_name`.
+    pub async fn save_path_commits(
+        &self,
+        repo: &Repo,
+        ref_name: &str,
+        entries: Vec<(String, String, i64)>,
+    ) -> Result<(), MegaError> {
+        let models: Vec<mega_path_commit::ActiveModel> = entries
+            .into_iter()
+            .map(|(path, commit_id, committed_at)| {
+                mega_path_commit::Model {
+                    id: generate_id(),
+                    repo_id: repo.repo_id,
+                    ref_name: ref_name.to_owned(),
+                    path,
+                    commit_id,
+                    committed_at,
+                }
+                .into_active_model()
+            })
+            .collect();
+        for chunk in models.chunks(1000) {
+            mega_path_commit::Entity::insert_many(chunk.iter().cloned())
+                .on_conflict(
+                    OnConflict::columns([
+                        mega_path_commit::Column::RepoId,
+                        mega_path_commit::Column::RefName,
+                        mega_path_commit::Column::Path,
+                    ])
+                    .update_columns([
+                        mega_path_commit::Column::CommitId,
+                        mega_path_commit::Column::CommittedAt,
+                    ])
+                    .to_owned(),
+                )
+                .exec(self.get_connection())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Removes the given paths of `ref_name` from the index, or all of them when `paths` is None.
+    pub async fn remove_path_commits(
+        &self,
+        repo: &Repo,
+        ref_name: &str,
+        paths: Option<Vec<String>>,
+    ) -> Result<(), MegaError> {
+        let mut query = mega_path_commit::Entity::delete_many()
+            .filter(mega_path_commit::Column::RepoId.eq(repo.repo_id))
+            .filter(mega_path_commit::Column::RefName.eq(ref_name));
+        if let Some(paths) = paths {
+            query = query.filter(mega_path_commit::Column::Path.is_in(paths));
+        }
+        query.exec(self.get_connection()).await?;
+        Ok(())
+    }
+
+    pub async fn get_path_commits(
+        &self,
+        repo: &Repo,
+        ref_name: &str,
+        paths: Vec<String>,
+    ) -> Result<Vec<mega_path_commit::Model>, MegaError> {
+        Ok(mega_path_commit::Entity::find()
+            .filter(mega_path_commit::Column::RepoId.eq(repo.repo_id))
+            .filter(mega_path_commit::Column::RefName.eq(ref_name))
+            .filter(mega_path_commit::Column::Path.is_in(paths))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    pub async fn get_commits_by_hashes(
+        &self,
+        repo: &Repo,
+        hashes: Vec<String>,
+    ) -> Result<Vec<mega_commit::Model>, MegaError> {
+        Ok(mega_commit::Entity::find()
+            .filter(mega_commit::Column::RepoId.eq(repo.repo_id))
+            .filter(mega_commit::Column::CommitId.is_in(hashes))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    pub async fn get_raw_blobs_by_hashes(
+        &self,
+        hashes: Vec<String>,
+    ) -> Result<Vec<raw_blob::Model>, MegaError> {
+        let mut blobs = raw_blob::Entity::find()
+            .filter(raw_blob::Column::Sha1.is_in(hashes))
+            .all(self.get_connection())
+            .await?;
+        if let Some(keys) = self.keys.as_deref() {
+            for blob in blobs.iter_mut() {
+                if let Some(data) = blob.data.as_mut() {
+                    *data = decrypt(keys, &blob.sha1, data)?;
+                }
+            }
+        }
+        Ok(blobs)
+    }
+
+    /// Rewraps the data keys of the blobs kept in the database and of the merge request
+    /// archives with the current key encryption key, after a rotation. The objects sealed with
+    /// it already are left as they are.
+    pub async fn rewrap_objects(&self) -> Result<RewrapReport, MegaError> {
+        let keys = self
+            .keys
+            .as_deref()
+            .ok_or_else(|| MegaError::with_message("encryption at rest is not enabled"))?;
+        let mut report = RewrapReport::default();
+        let mut last_id = i64::MIN;
+        loop {
+            let blobs = raw_blob::Entity::find()
+                .filter(raw_blob::Column::Id.gt(last_id))
+                .filter(raw_blob::Column::StorageType.eq(StorageType::Database))
+                .order_by_asc(raw_blob::Column::Id)
+                .limit(1000)
+                .all(self.get_connection())
+                .await?;
+            let Some(last) = blobs.last() else {
+                break;
+            };
+            last_id = last.id;
+            for blob in blobs {
+                let Some(data) = blob.data.filter(|data| !is_current(keys, data)) else {
+                    continue;
+                };
+                raw_blob::Entity::update_many()
+                    .col_expr(
+                        raw_blob::Column::Data,
+                        Expr::value(rewrap(keys, &blob.sha1, &data)?),
+                    )
+                    .filter(raw_blob::Column::Id.eq(blob.id))
+                    .exec(self.get_connection())
+                    .await?;
+                report.blobs += 1;
+            }
+        }
+        for archive in self.get_mr_archives().await? {
+            let object_id = archive_object_id(archive.mr_id);
+            if self
+                .archive_storage
+                .rewrap_object(MR_ARCHIVE_DIR, &object_id)
+                .await?
+            {
+                report.archives += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    /// The content of the blobs of `ids` kept in the database, the others are left out.
+    pub async fn get_blob_contents(
+        &self,
+        ids: &[SHA1],
+    ) -> Result<HashMap<SHA1, Vec<u8>>, MegaError> {
+        let mut hashes: Vec<String> = ids.iter().map(|id| id.to_plain_str()).collect();
+        hashes.sort();
+        hashes.dedup();
+        let mut contents = HashMap::new();
+        for chunk in hashes.chunks(1000) {
+            for blob in self.get_raw_blobs_by_hashes(chunk.to_vec()).await? {
+                if blob.storage_type != StorageType::Database {
+                    continue;
+                }
+                if let (Ok(id), Some(data)) = (SHA1::from_str(&blob.sha1), blob.data) {
+                    contents.insert(id, data);
+                }
+            }
+        }
+        Ok(contents)
+    }
+
+    /// The content of the blob `id`, None when it is not kept in the database.
+    pub async fn get_blob_content(&self, id: &SHA1) -> Result<Option<Vec<u8>>, MegaError> {
+        Ok(self.get_blob_contents(&[*id]).await?.remove(id))
+    }
+
+    /// The merge requests whose message contains every one of `terms`, ignoring case.
+    pub async fn search_mrs(&self, terms: &[String]) -> Result<Vec<mega_mr::Model>, MegaError> {
+        Ok(mega_mr::Entity::find()
+            .filter(contains_all(mega_mr::Column::MrMsg, terms))
+            .order_by_desc(mega_mr::Column::UpdatedAt)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    pub async fn get_mr(&self, id: i64) -> Result<Option<mega_mr::Model>, MegaError> {
+        Ok(mega_mr::Entity::find_by_id(id)
+            .one(self.get_connection())
+            .await?)
+    }
+
+    /// The path of the repository the merge request `id` was pushed to, `/` for the monorepo.
+    pub async fn get_mr_repo_path(&self, id: i64) -> Result<String, MegaError> {
+        let commit = mega_commit::Entity::find()
+            .filter(mega_commit::Column::MrId.eq(id))
+            .one(self.get_connection())
+            .await?;
+        let repo = match commit {
+            Some(commit) => {
+                git_repo::Entity::find_by_id(commit.repo_id)
+                    .one(self.get_connection())
+                    .await?
+            }
+            None => None,
+        };
+        Ok(repo
+            .map(|repo| repo.repo_path)
+            .unwrap_or_else(|| String::from("/")))
+    }
+
+    /// The merge requests with `status`, or all of them, the last updated first.
+    pub async fn get_mrs(
+        &self,
+        status: Option<MergeStatus>,
+        limit: Option<u64>,
+    ) -> Result<Vec<mega_mr::Model>, MegaError> {
+        let mut query = mega_mr::Entity::find().order_by_desc(mega_mr::Column::UpdatedAt);
+        if let Some(status) = status {
+            query = query.filter(mega_mr::Column::Status.eq(status));
+        }
+        Ok(query.limit(limit).all(self.get_connection()).await?)
+    }
+
+    /// Merges or closes the open merge request `id`, as `status` says.
+    pub async fn set_mr_status(
+        &self,
+        id: i64,
+        status: MergeStatus,
+    ) -> Result<mega_mr::Model, MegaError> {
+        let mr = self
+            .get_mr(id)
+            .await?
+            .ok_or_else(|| MegaError::with_message(&format!("merge request {} not found", id)))?;
+        if mr.status != MergeStatus::Open {
+            return Err(MegaError::with_message(&format!(
+                "merge request {} is not open",
+                id
+            )));
+        }
+        let now = Utc::now().naive_utc();
+        let mut mr = mr.into_active_model();
+        if status == MergeStatus::Merged {
+            mr.merge_date = Set(Some(now));
+        }
+        mr.status = Set(status);
+        mr.updated_at = Set(now);
+        Ok(mr.update(self.get_connection()).await?)
+    }
+
+    /// The issues whose title contains every one of `terms`, ignoring case.
+    pub async fn search_issues(
+        &self,
+        terms: &[String],
+    ) -> Result<Vec<mega_issue::Model>, MegaError> {
+        Ok(mega_issue::Entity::find()
+            .filter(contains_all(mega_issue::Column::Title, terms))
+            .order_by_desc(mega_issue::Column::UpdatedAt)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Closed merge requests last updated before `before` whose data is still in the database.
+    pub async fn get_mrs_to_archive(
+        &self,
+        before: NaiveDateTime,
+    ) -> Result<Vec<mega_mr::Model>, MegaError> {
+        let archived: Vec<i64> = mega_mr_archive::Entity::find()
+            .all(self.get_connection())
+            .await?
+            .into_iter()
+            .map(|a| a.mr_id)
+            .collect();
+        Ok(mega_mr::Entity::find()
+            .filter(mega_mr::Column::Status.eq(MergeStatus::Closed))
+            .filter(mega_mr::Column::UpdatedAt.lt(before))
+            .filter(mega_mr::Column::Id.is_not_in(archived))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    pub async fn get_mr_archives(&self) -> Result<Vec<mega_mr_archive::Model>, MegaError> {
+        Ok(mega_mr_archive::Entity::find()
+            .order_by_desc(mega_mr_archive::Column::ArchivedAt)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Moves the commits and trees carried by a merge request to the archive storage and
+    /// removes them from the database. The merge request itself stays, so it can still be
+    /// listed and restored with [`MegaStorage::restore_mr`].
+    pub async fn archive_mr(
+        &self,
+        mr: &mega_mr::Model,
+    ) -> Result<mega_mr_archive::Model, MegaError> {
+        let commits = mega_commit::Entity::find()
+            .filter(mega_commit::Column::MrId.eq(mr.id))
+            .all(self.get_connection())
+            .await?;
+        let trees = mega_tree::Entity::find()
+            .filter(mega_tree::Column::MrId.eq(mr.id))
+            .all(self.get_connection())
+            .await?;
+        let commit_ids = commits.iter().map(|c| c.commit_id.clone()).collect();
+        if self.is_read_leased(commit_ids).await? {
+            return Err(MegaError::with_message(&format!(
+                "merge request {} is being read by a clone or fetch",
+                mr.id
+            )));
+        }
+        let archive = MrArchive {
+            mr: mr.clone(),
+            commits,
+            trees,
+        };
+        let data = serde_json::to_vec(&archive).map_err(|e| MegaError::new(e.into(), 1))?;
+        let location = self
+            .archive_storage
+            .put_object(MR_ARCHIVE_DIR, &archive_object_id(mr.id), &data)
+            .await?;
+
+        let model = mega_mr_archive::Model {
+            mr_id: mr.id,
+            location,
+            commit_count: archive.commits.len() as i32,
+            tree_count: archive.trees.len() as i32,
+            archived_at: Utc::now().naive_utc(),
+        };
+        // the rows are only removed once the archive is written and recorded
+        let txn = self.get_connection().begin().await?;
+        mega_mr_archive::Entity::insert(model.clone().into_active_model())
+            .exec(&txn)
+            .await?;
+        mega_commit::Entity::delete_many()
+            .filter(mega_commit::Column::MrId.eq(mr.id))
+            .exec(&txn)
+            .await?;
+        mega_tree::Entity::delete_many()
+            .filter(mega_tree::Column::MrId.eq(mr.id))
+            .exec(&txn)
+            .await?;
+        txn.commit().await?;
+        Ok(model)
+    }
+
+    /// Puts the data of an archived merge request back in the database.
+    pub async fn restore_mr(&self, mr_id: i64) -> Result<mega_mr_archive::Model, MegaError> {
+        let record = mega_mr_archive::Entity::find_by_id(mr_id)
+            .one(self.get_connection())
+            .await?
+            .ok_or_else(|| {
+                MegaError::with_message(&format!("merge request {} is not archived", mr_id))
+            })?;
+        let object_id = archive_object_id(mr_id);
+        if !self.archive_storage.exist_object(MR_ARCHIVE_DIR, &object_id) {
+            return Err(MegaError::with_message(&format!(
+                "archive of merge request {} is missing from {}",
+                mr_id, record.location
+            )));
+        }
+        let data = self
+            .archive_storage
+            .get_object(MR_ARCHIVE_DIR, &object_id)
+            .await?;
+        let archive: MrArchive =
+            serde_json::from_slice(&data).map_err(|e| MegaError::new(e.into(), 1))?;
+
+        let txn = self.get_connection().begin().await?;
+        batch_save_model(
+            &txn,
+            archive
+                .commits
+                .into_iter()
+                .map(|c| c.into_active_model())
+                .collect(),
+        )
+        .await?;
+        batch_save_model(
+            &txn,
+            archive
+                .trees
+                .into_iter()
+                .map(|t| t.into_active_model())
+                .collect(),
+        )
+        .await?;
+        mega_mr_archive::Entity::delete_by_id(mr_id)
+            .exec(&txn)
+            .await?;
+        txn.commit().await?;
+        Ok(record)
+    }
+
     pub async fn find_git_repo(
         &self,
         repo_path: &str,
@@ -394,6 +1676,18 @@ impl MegaStorage {
         Ok(result)
     }
 
+    /// Serves the repository at `repo_path` to anonymous clients, or stops it. Returns false
+    /// when there is no such repository.
+    pub async fn set_repo_public(&self, repo_path: &str, public: bool) -> Result<bool, MegaError> {
+        let res = git_repo::Entity::update_many()
+            .col_expr(git_repo::Column::IsPublic, Expr::value(public))
+            .col_expr(git_repo::Column::UpdatedAt, Expr::value(Utc::now().naive_utc()))
+            .filter(git_repo::Column::RepoPath.eq(repo_path))
+            .exec(self.get_connection())
+            .await?;
+        Ok(res.rows_affected > 0)
+    }
+
     #[allow(unused)]
     async fn save_git_repo(&self, repo: Repo) -> Result<(), MegaError> {
         let model: git_repo::Model = repo.into();
@@ -449,6 +1743,15 @@ impl MegaStorage {
             .unwrap())
     }
 
+    /// The id of the object the annotated tag `tag_id` points to.
+    pub async fn get_tag_target(&self, tag_id: &str) -> Result<Option<String>, MegaError> {
+        Ok(mega_tag::Entity::find()
+            .filter(mega_tag::Column::TagId.eq(tag_id))
+            .one(self.get_connection())
+            .await?
+            .map(|model| model.object_id))
+    }
+
     async fn get_mega_tree_by_path(
         &self,
         full_path: &str,
@@ -460,7 +1763,7 @@ impl MegaStorage {
             .unwrap())
     }
 
-    async fn get_mega_tree_by_sha(&self, sha: &str) -> Result<Option<mega_tree::Model>, MegaError> {
+    pub async fn get_mega_tree_by_sha(&self, sha: &str) -> Result<Option<mega_tree::Model>, MegaError> {
         Ok(mega_tree::Entity::find()
             .filter(mega_tree::Column::TreeId.eq(sha))
             .one(self.get_connection())
@@ -486,11 +1789,63 @@ impl MegaStorage {
 #[cfg(test)]
 mod test {
     use std::rc::Rc;
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use chrono::Utc;
+    use sea_orm::{DatabaseBackend, DbErr, MockDatabase, MockExecResult};
 
     use ganymede::mega_node::MegaNode;
     use ganymede::model::create_file::CreateFileInfo;
 
-    use crate::storage::mega_storage::MegaStorage;
+    use callisto::db_enums::MergeStatus;
+    use callisto::{mega_commit, mega_mr};
+    use common::errors::MegaError;
+    use common::fault::{Fault, FaultInjector};
+    use venus::internal::object::blob::Blob;
+    use venus::internal::pack::entry::Entry;
+    use venus::internal::pack::reference::RefCommand;
+    use venus::mr::MergeRequest;
+    use venus::repo::Repo;
+
+    use crate::events::RefUpdateEvent;
+    use crate::hooks::{Merge, MergeHooks, PostMergeHook};
+    use crate::storage::mega_storage::{MegaStorage, MrArchive};
+
+    #[test]
+    pub fn test_mr_archive_roundtrip() {
+        let now = chrono::Utc::now().naive_utc();
+        let archive = MrArchive {
+            mr: mega_mr::Model {
+                id: 42,
+                mr_link: String::from("mr-42"),
+                mr_msg: Some(String::from("abandoned")),
+                merge_date: None,
+                status: MergeStatus::Closed,
+                created_at: now,
+                updated_at: now,
+            },
+            commits: vec![mega_commit::Model {
+                id: 1,
+                repo_id: 0,
+                commit_id: String::from("7bdc783132575d5b3e78400ace9971970ff43a18"),
+                tree: String::from("4b825dc642cb6eb9a060e54bf8d69288fbee4904"),
+                parents_id: vec![],
+                author: None,
+                committer: None,
+                content: Some(String::from("wip")),
+                mr_id: 42,
+                status: MergeStatus::Closed,
+                created_at: now,
+                updated_at: now,
+            }],
+            trees: vec![],
+        };
+        let data = serde_json::to_vec(&archive).unwrap();
+        let restored: MrArchive = serde_json::from_slice(&data).unwrap();
+        assert_eq!(restored.mr, archive.mr);
+        assert_eq!(restored.commits, archive.commits);
+    }
 
     #[test]
     pub fn test_node_tree() {
@@ -547,4 +1902,161 @@ mod test {
             print_tree(child.clone(), depth + 1)
         }
     }
+
+    /// Statements of a merge of one blob: the merge request, the blob and its raw data, the
+    /// ref update and its event.
+    const STATEMENTS: usize = 5;
+
+    struct Failing;
+
+    #[async_trait]
+    impl PostMergeHook for Failing {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        async fn on_merge(&self, _: &Merge) -> Result<(), MegaError> {
+            Err(MegaError::with_message("unavailable"))
+        }
+    }
+
+    /// Fails as decided by the injector.
+    struct Faulty(Arc<FaultInjector>);
+
+    #[async_trait]
+    impl PostMergeHook for Faulty {
+        fn name(&self) -> &'static str {
+            "faulty"
+        }
+
+        async fn on_merge(&self, _: &Merge) -> Result<(), MegaError> {
+            if self.0.decide("on_merge").fail {
+                return Err(FaultInjector::error("on_merge"));
+            }
+            Ok(())
+        }
+    }
+
+    fn sample_merge() -> Merge {
+        let mut mr = MergeRequest::default();
+        mr.merge(None);
+        let repo = Repo {
+            repo_id: 1,
+            repo_path: String::from("/project"),
+            repo_name: String::from("project"),
+        };
+        let command = RefCommand::new(
+            "1".repeat(40),
+            "2".repeat(40),
+            String::from("refs/heads/main"),
+        );
+        let event = RefUpdateEvent {
+            id: 1,
+            repo_id: repo.repo_id,
+            repo_path: repo.repo_path.clone(),
+            ref_name: command.ref_name.clone(),
+            old_id: command.old_id.clone(),
+            new_id: command.new_id.clone(),
+            pusher: None,
+            created_at: Utc::now().naive_utc(),
+        };
+        Merge {
+            mr,
+            repo,
+            commands: vec![command],
+            events: vec![event],
+        }
+    }
+
+    /// Merges `sample_merge` on a database failing the statement `fail_at`, if any, and
+    /// returns the result with the last statement of the transaction. Nothing runs after the
+    /// failing statement.
+    async fn merge(fail_at: Option<usize>, hooks: MergeHooks, rollback: bool) -> (bool, String) {
+        let succeeded = fail_at.map_or(STATEMENTS, |n| n - 1);
+        let mut db = MockDatabase::new(DatabaseBackend::Postgres).append_exec_results(
+            (0..succeeded).map(|_| MockExecResult {
+                last_insert_id: 0,
+                rows_affected: 1,
+            }),
+        );
+        if fail_at.is_some() {
+            db = db.append_exec_errors([DbErr::Custom(String::from("connection lost"))]);
+        }
+        let storage = MegaStorage {
+            connection: Arc::new(db.into_connection()),
+            ..MegaStorage::mock()
+        };
+        let entries = vec![Ok(Entry::from(Blob::from_content("mega")))];
+        let result = storage
+            .merge(&sample_merge(), entries.into_iter(), &hooks, rollback)
+            .await;
+        let connection = Arc::try_unwrap(storage.connection).unwrap();
+        let log = connection.into_transaction_log();
+        assert_eq!(log.len(), 1);
+        // the statements of a mock transaction are only exposed by its debug output
+        let statements: Vec<_> = format!("{:?}", log[0])
+            .split("Statement { sql: \"")
+            .skip(1)
+            .map(|s| s.split('"').next().unwrap().to_owned())
+            .collect();
+        // BEGIN, the statements run, then COMMIT or ROLLBACK
+        assert_eq!(statements.len(), fail_at.unwrap_or(STATEMENTS) + 2);
+        (result.is_ok(), statements.last().unwrap().clone())
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        assert_eq!(
+            merge(None, MergeHooks::default(), true).await,
+            (true, String::from("COMMIT"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_crash_points() {
+        for fail_at in 1..=STATEMENTS {
+            let (succeeded, last) = merge(Some(fail_at), MergeHooks::default(), true).await;
+            assert!(!succeeded, "statement {}", fail_at);
+            assert_eq!(last, "ROLLBACK", "statement {}", fail_at);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merge_hook_failure() {
+        let hooks = MergeHooks::default();
+        hooks.register(Arc::new(Failing));
+        assert_eq!(
+            merge(None, hooks, true).await,
+            (false, String::from("ROLLBACK"))
+        );
+
+        let hooks = MergeHooks::default();
+        hooks.register(Arc::new(Failing));
+        assert_eq!(
+            merge(None, hooks, false).await,
+            (true, String::from("COMMIT"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_under_random_faults() {
+        let mut outcomes = (0, 0);
+        for seed in 0..64 {
+            let faults = Arc::new(FaultInjector::new(seed).with_default(Fault::error(0.15)));
+            let fail_at = (1..=STATEMENTS).find(|_| faults.decide("execute").fail);
+            let hooks = MergeHooks::default();
+            hooks.register(Arc::new(Faulty(faults.clone())));
+            let (succeeded, last) = merge(fail_at, hooks, true).await;
+            // all or nothing, whichever statement or hook fails
+            assert_eq!(succeeded, faults.failed().is_empty(), "seed {}", seed);
+            let expected = if succeeded { "COMMIT" } else { "ROLLBACK" };
+            assert_eq!(last, expected, "seed {}", seed);
+            if succeeded {
+                outcomes.0 += 1;
+            } else {
+                outcomes.1 += 1;
+            }
+        }
+        assert!(outcomes.0 > 0 && outcomes.1 > 0, "{:?}", outcomes);
+    }
 }
@@ -1,7 +1,20 @@
+pub mod activity_storage;
+pub mod analytics_storage;
+pub mod code_nav_storage;
+pub mod audit_storage;
+pub mod blame_storage;
+pub mod event_storage;
 pub mod git_storage;
 pub mod init;
 pub mod lfs_storage;
 pub mod mega_storage;
+pub mod object_map_storage;
+pub mod review_storage;
+pub mod search_storage;
+pub mod setting_storage;
+pub mod stats_storage;
+pub mod user_storage;
+pub mod watch_storage;
 
 use async_trait::async_trait;
 
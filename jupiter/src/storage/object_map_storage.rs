@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use sea_orm::{
+    sea_query::OnConflict, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
+    QueryFilter,
+};
+
+use callisto::mega_object_map;
+use common::errors::MegaError;
+
+/// Ids queried or written per statement, within the bind parameter limit of the databases.
+const CHUNK: usize = 1000;
+
+/// The SHA-256 name of the objects stored with their SHA-1 id, see
+/// [`venus::internal::object::interop`].
+#[derive(Clone)]
+pub struct ObjectMapStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl ObjectMapStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        ObjectMapStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        ObjectMapStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    /// Saves `mappings`, an object already mapped keeps its mapping.
+    pub async fn save_mappings(
+        &self,
+        mappings: Vec<mega_object_map::Model>,
+    ) -> Result<(), MegaError> {
+        for chunk in mappings.chunks(CHUNK) {
+            mega_object_map::Entity::insert_many(
+                chunk.iter().cloned().map(|m| m.into_active_model()),
+            )
+            .on_conflict(
+                OnConflict::column(mega_object_map::Column::Sha1)
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(self.get_connection())
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// The SHA-256 names of the objects of `sha1s` mapped, by SHA-1 id.
+    pub async fn get_sha256(&self, sha1s: &[String]) -> Result<HashMap<String, String>, MegaError> {
+        let mut mapped = HashMap::new();
+        for chunk in sha1s.chunks(CHUNK) {
+            let models = mega_object_map::Entity::find()
+                .filter(mega_object_map::Column::Sha1.is_in(chunk.to_vec()))
+                .all(self.get_connection())
+                .await?;
+            mapped.extend(models.into_iter().map(|m| (m.sha1, m.sha256)));
+        }
+        Ok(mapped)
+    }
+
+    /// The SHA-1 ids of the objects of `sha256s` mapped, by SHA-256 name.
+    pub async fn get_sha1(&self, sha256s: &[String]) -> Result<HashMap<String, String>, MegaError> {
+        let mut mapped = HashMap::new();
+        for chunk in sha256s.chunks(CHUNK) {
+            let models = mega_object_map::Entity::find()
+                .filter(mega_object_map::Column::Sha256.is_in(chunk.to_vec()))
+                .all(self.get_connection())
+                .await?;
+            mapped.extend(models.into_iter().map(|m| (m.sha256, m.sha1)));
+        }
+        Ok(mapped)
+    }
+}
@@ -0,0 +1,285 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, Condition, DatabaseConnection, EntityTrait, IntoActiveModel,
+    QueryFilter, QueryOrder, TransactionTrait,
+};
+
+use callisto::db_enums::ReviewVerdict;
+use callisto::{mega_mr_comment, mega_mr_review};
+use common::errors::MegaError;
+use common::utils::generate_id;
+
+/// The reviews of merge requests and their comments. A comment is a draft seen only by its
+/// author until a review of the author publishes it, together with every other draft of the
+/// author on the same merge request.
+#[derive(Clone)]
+pub struct ReviewStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl ReviewStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        ReviewStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        ReviewStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    pub async fn add_draft_comment(
+        &self,
+        mr_id: i64,
+        user_name: &str,
+        path: Option<String>,
+        line: Option<i32>,
+        body: &str,
+    ) -> Result<mega_mr_comment::Model, MegaError> {
+        let now = Utc::now().naive_utc();
+        let comment = mega_mr_comment::Model {
+            id: generate_id(),
+            mr_id,
+            user_name: user_name.to_owned(),
+            review_id: None,
+            path,
+            line,
+            body: body.to_owned(),
+            created_at: now,
+            updated_at: now,
+        };
+        mega_mr_comment::Entity::insert(comment.clone().into_active_model())
+            .exec(self.get_connection())
+            .await?;
+        Ok(comment)
+    }
+
+    /// Removes a draft of `user_name`, returns false when there is no such draft.
+    pub async fn remove_draft_comment(&self, id: i64, user_name: &str) -> Result<bool, MegaError> {
+        let res = mega_mr_comment::Entity::delete_many()
+            .filter(mega_mr_comment::Column::Id.eq(id))
+            .filter(mega_mr_comment::Column::UserName.eq(user_name))
+            .filter(mega_mr_comment::Column::ReviewId.is_null())
+            .exec(self.get_connection())
+            .await?;
+        Ok(res.rows_affected > 0)
+    }
+
+    /// The published comments of a merge request and the drafts of `user_name`, oldest first.
+    pub async fn get_comments(
+        &self,
+        mr_id: i64,
+        user_name: Option<&str>,
+    ) -> Result<Vec<mega_mr_comment::Model>, MegaError> {
+        let mut visible = Condition::any().add(mega_mr_comment::Column::ReviewId.is_not_null());
+        if let Some(user_name) = user_name {
+            visible = visible.add(mega_mr_comment::Column::UserName.eq(user_name));
+        }
+        Ok(mega_mr_comment::Entity::find()
+            .filter(mega_mr_comment::Column::MrId.eq(mr_id))
+            .filter(visible)
+            .order_by_asc(mega_mr_comment::Column::CreatedAt)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    pub async fn get_comment(&self, id: i64) -> Result<Option<mega_mr_comment::Model>, MegaError> {
+        Ok(mega_mr_comment::Entity::find_by_id(id)
+            .one(self.get_connection())
+            .await?)
+    }
+
+    /// Publishes a comment of `user_name` at once, in a comment review of its own, e.g. a reply
+    /// by email. The drafts of the author stay drafts.
+    pub async fn add_published_comment(
+        &self,
+        mr_id: i64,
+        user_name: &str,
+        path: Option<String>,
+        line: Option<i32>,
+        body: &str,
+    ) -> Result<mega_mr_comment::Model, MegaError> {
+        let now = Utc::now().naive_utc();
+        let review = mega_mr_review::Model {
+            id: generate_id(),
+            mr_id,
+            user_name: user_name.to_owned(),
+            verdict: ReviewVerdict::Comment,
+            summary: String::new(),
+            created_at: now,
+        };
+        let comment = mega_mr_comment::Model {
+            id: generate_id(),
+            mr_id,
+            user_name: user_name.to_owned(),
+            review_id: Some(review.id),
+            path,
+            line,
+            body: body.to_owned(),
+            created_at: now,
+            updated_at: now,
+        };
+        let txn = self.get_connection().begin().await?;
+        mega_mr_review::Entity::insert(review.into_active_model())
+            .exec_without_returning(&txn)
+            .await?;
+        mega_mr_comment::Entity::insert(comment.clone().into_active_model())
+            .exec_without_returning(&txn)
+            .await?;
+        txn.commit().await?;
+        Ok(comment)
+    }
+
+    /// The reviews of a merge request, oldest first.
+    pub async fn get_reviews(&self, mr_id: i64) -> Result<Vec<mega_mr_review::Model>, MegaError> {
+        Ok(mega_mr_review::Entity::find()
+            .filter(mega_mr_review::Column::MrId.eq(mr_id))
+            .order_by_asc(mega_mr_review::Column::CreatedAt)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Saves a review of `user_name` and publishes their drafts on the merge request with it,
+    /// all or nothing. Returns the review and the number of comments it published, or None
+    /// for a plain comment review with neither a summary nor drafts.
+    pub async fn publish_review(
+        &self,
+        mr_id: i64,
+        user_name: &str,
+        verdict: ReviewVerdict,
+        summary: &str,
+    ) -> Result<Option<(mega_mr_review::Model, u64)>, MegaError> {
+        let drafts = Condition::all()
+            .add(mega_mr_comment::Column::MrId.eq(mr_id))
+            .add(mega_mr_comment::Column::UserName.eq(user_name))
+            .add(mega_mr_comment::Column::ReviewId.is_null());
+        let txn = self.get_connection().begin().await?;
+        let review = mega_mr_review::Model {
+            id: generate_id(),
+            mr_id,
+            user_name: user_name.to_owned(),
+            verdict,
+            summary: summary.to_owned(),
+            created_at: Utc::now().naive_utc(),
+        };
+        let published = mega_mr_comment::Entity::update_many()
+            .col_expr(mega_mr_comment::Column::ReviewId, Expr::value(review.id))
+            .filter(drafts)
+            .exec(&txn)
+            .await?
+            .rows_affected;
+        if published == 0 && verdict == ReviewVerdict::Comment && summary.trim().is_empty() {
+            txn.rollback().await?;
+            return Ok(None);
+        }
+        mega_mr_review::Entity::insert(review.clone().into_active_model())
+            .exec_without_returning(&txn)
+            .await?;
+        txn.commit().await?;
+        Ok(Some((review, published)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use sea_orm::{DatabaseBackend, DbErr, MockDatabase, MockExecResult};
+
+    use callisto::db_enums::ReviewVerdict;
+
+    use super::ReviewStorage;
+
+    /// Publishes a review with `drafts` drafts, the review insert failing when `fail`. Returns
+    /// the drafts published and the statements of the transaction.
+    async fn publish(
+        drafts: u64,
+        verdict: ReviewVerdict,
+        summary: &str,
+        fail: bool,
+    ) -> (Result<Option<u64>, ()>, Vec<String>) {
+        let mut db =
+            MockDatabase::new(DatabaseBackend::Postgres).append_exec_results([MockExecResult {
+                last_insert_id: 0,
+                rows_affected: drafts,
+            }]);
+        db = if fail {
+            db.append_exec_errors([DbErr::Custom(String::from("connection lost"))])
+        } else {
+            db.append_exec_results([MockExecResult {
+                last_insert_id: 0,
+                rows_affected: 1,
+            }])
+        };
+        let storage = ReviewStorage {
+            connection: Arc::new(db.into_connection()),
+        };
+        let result = storage
+            .publish_review(1, "alice", verdict, summary)
+            .await
+            .map(|review| review.map(|(_, published)| published))
+            .map_err(|_| ());
+        (result, statements(storage))
+    }
+
+    /// The first word of the statements of the transaction of `storage`.
+    fn statements(storage: ReviewStorage) -> Vec<String> {
+        let connection = Arc::try_unwrap(storage.connection).unwrap();
+        let log = connection.into_transaction_log();
+        // the statements of a mock transaction are only exposed by its debug output
+        format!("{:?}", log[0])
+            .split("Statement { sql: \"")
+            .skip(1)
+            .map(|s| s.split([' ', '"']).next().unwrap().to_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_publish_review() {
+        let (result, statements) = publish(2, ReviewVerdict::RequestChanges, "", false).await;
+        assert_eq!(result, Ok(Some(2)));
+        assert_eq!(statements, ["BEGIN", "UPDATE", "INSERT", "COMMIT"]);
+
+        // an approval needs no comment
+        let (result, _) = publish(0, ReviewVerdict::Approve, "", false).await;
+        assert_eq!(result, Ok(Some(0)));
+
+        // the drafts stay drafts when the review is not saved
+        let (result, statements) = publish(2, ReviewVerdict::Comment, "LGTM", true).await;
+        assert!(result.is_err());
+        assert_eq!(statements, ["BEGIN", "UPDATE", "INSERT", "ROLLBACK"]);
+
+        let (result, statements) = publish(0, ReviewVerdict::Comment, " ", false).await;
+        assert_eq!(result, Ok(None));
+        assert_eq!(statements, ["BEGIN", "UPDATE", "ROLLBACK"]);
+    }
+
+    #[tokio::test]
+    async fn test_add_published_comment() {
+        let db = MockDatabase::new(DatabaseBackend::Postgres).append_exec_results([
+            MockExecResult {
+                last_insert_id: 0,
+                rows_affected: 1,
+            },
+            MockExecResult {
+                last_insert_id: 0,
+                rows_affected: 1,
+            },
+        ]);
+        let storage = ReviewStorage {
+            connection: Arc::new(db.into_connection()),
+        };
+        let comment = storage
+            .add_published_comment(1, "alice", None, None, "LGTM")
+            .await
+            .unwrap();
+        assert!(comment.review_id.is_some());
+        assert_eq!(statements(storage), ["BEGIN", "INSERT", "INSERT", "COMMIT"]);
+    }
+}
@@ -0,0 +1,183 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use sea_orm::{
+    sea_query::{Expr, Func, OnConflict},
+    ColumnTrait, Condition, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter,
+    QueryOrder, QuerySelect, TransactionTrait,
+};
+
+use callisto::{mega_search_file, mega_search_ref};
+use common::errors::MegaError;
+
+/// Rows written per statement, within the bind parameter limit of the databases.
+const INSERT_CHUNK: usize = 1000;
+
+/// The files of the indexed refs with their content, and the commit each ref is indexed at.
+#[derive(Clone)]
+pub struct SearchStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl SearchStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        SearchStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        SearchStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    pub async fn get_indexed_ref(
+        &self,
+        repo_id: i64,
+        ref_name: &str,
+    ) -> Result<Option<mega_search_ref::Model>, MegaError> {
+        Ok(mega_search_ref::Entity::find()
+            .filter(mega_search_ref::Column::RepoId.eq(repo_id))
+            .filter(mega_search_ref::Column::RefName.eq(ref_name))
+            .one(self.get_connection())
+            .await?)
+    }
+
+    pub async fn get_indexed_refs(&self) -> Result<Vec<mega_search_ref::Model>, MegaError> {
+        Ok(mega_search_ref::Entity::find()
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Saves `files`, replacing the files of the same paths.
+    pub async fn save_files(&self, files: Vec<mega_search_file::Model>) -> Result<(), MegaError> {
+        for chunk in files.chunks(INSERT_CHUNK) {
+            mega_search_file::Entity::insert_many(
+                chunk.iter().cloned().map(|file| file.into_active_model()),
+            )
+            .on_conflict(
+                OnConflict::columns([
+                    mega_search_file::Column::RepoId,
+                    mega_search_file::Column::RefName,
+                    mega_search_file::Column::Path,
+                ])
+                .update_columns([
+                    mega_search_file::Column::BlobId,
+                    mega_search_file::Column::CommitId,
+                    mega_search_file::Column::Content,
+                ])
+                .to_owned(),
+            )
+            .exec_without_returning(self.get_connection())
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Records that `ref_name` is indexed at `commit_id` with `index_version` once its files
+    /// are saved, removing the `removed` paths. After a `rebuild`, which saved every file of
+    /// the commit, the files saved for other commits are removed as well.
+    pub async fn update_ref_index(
+        &self,
+        repo_id: i64,
+        ref_name: &str,
+        commit_id: &str,
+        index_version: i32,
+        removed: Vec<String>,
+        rebuild: bool,
+    ) -> Result<(), MegaError> {
+        let of_ref = Condition::all()
+            .add(mega_search_file::Column::RepoId.eq(repo_id))
+            .add(mega_search_file::Column::RefName.eq(ref_name));
+        let txn = self.get_connection().begin().await?;
+        for chunk in removed.chunks(INSERT_CHUNK) {
+            mega_search_file::Entity::delete_many()
+                .filter(of_ref.clone())
+                .filter(mega_search_file::Column::Path.is_in(chunk.to_vec()))
+                .exec(&txn)
+                .await?;
+        }
+        if rebuild {
+            mega_search_file::Entity::delete_many()
+                .filter(of_ref)
+                .filter(mega_search_file::Column::CommitId.ne(commit_id))
+                .exec(&txn)
+                .await?;
+        }
+        let indexed = mega_search_ref::Model {
+            repo_id,
+            ref_name: ref_name.to_owned(),
+            commit_id: commit_id.to_owned(),
+            index_version,
+            updated_at: Utc::now().naive_utc(),
+        };
+        mega_search_ref::Entity::insert(indexed.into_active_model())
+            .on_conflict(
+                OnConflict::columns([
+                    mega_search_ref::Column::RepoId,
+                    mega_search_ref::Column::RefName,
+                ])
+                .update_columns([
+                    mega_search_ref::Column::CommitId,
+                    mega_search_ref::Column::IndexVersion,
+                    mega_search_ref::Column::UpdatedAt,
+                ])
+                .to_owned(),
+            )
+            .exec_without_returning(&txn)
+            .await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
+    pub async fn remove_ref_index(&self, repo_id: i64, ref_name: &str) -> Result<(), MegaError> {
+        let txn = self.get_connection().begin().await?;
+        mega_search_file::Entity::delete_many()
+            .filter(mega_search_file::Column::RepoId.eq(repo_id))
+            .filter(mega_search_file::Column::RefName.eq(ref_name))
+            .exec(&txn)
+            .await?;
+        mega_search_ref::Entity::delete_many()
+            .filter(mega_search_ref::Column::RepoId.eq(repo_id))
+            .filter(mega_search_ref::Column::RefName.eq(ref_name))
+            .exec(&txn)
+            .await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// The files of `ref_name` whose path or content contains every one of `terms`, ignoring
+    /// case, by path.
+    pub async fn search_files(
+        &self,
+        repo_id: i64,
+        ref_name: &str,
+        terms: &[String],
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<mega_search_file::Model>, MegaError> {
+        let lower = |column: mega_search_file::Column| {
+            Expr::expr(Func::lower(Expr::col(column.as_column_ref())))
+        };
+        let matches = terms.iter().fold(Condition::all(), |condition, term| {
+            let pattern = format!("%{}%", term.to_lowercase());
+            condition.add(
+                Condition::any()
+                    .add(lower(mega_search_file::Column::Path).like(pattern.clone()))
+                    .add(lower(mega_search_file::Column::Content).like(pattern)),
+            )
+        });
+        Ok(mega_search_file::Entity::find()
+            .filter(mega_search_file::Column::RepoId.eq(repo_id))
+            .filter(mega_search_file::Column::RefName.eq(ref_name))
+            .filter(matches)
+            .order_by_asc(mega_search_file::Column::Path)
+            .offset(offset)
+            .limit(limit)
+            .all(self.get_connection())
+            .await?)
+    }
+}
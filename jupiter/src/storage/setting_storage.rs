@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use sea_orm::{
+    sea_query::OnConflict, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
+    QueryFilter,
+};
+
+use callisto::mega_setting;
+use common::errors::MegaError;
+
+/// Runtime settings changed through the admin API, each value is kept as JSON.
+#[derive(Clone)]
+pub struct SettingStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl SettingStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        SettingStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        SettingStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    pub async fn get_settings(&self) -> Result<Vec<mega_setting::Model>, MegaError> {
+        Ok(mega_setting::Entity::find()
+            .all(self.get_connection())
+            .await?)
+    }
+
+    pub async fn save_settings(&self, settings: Vec<(String, String)>) -> Result<(), MegaError> {
+        if settings.is_empty() {
+            return Ok(());
+        }
+        let now = Utc::now().naive_utc();
+        let models = settings.into_iter().map(|(name, value)| {
+            mega_setting::Model {
+                name,
+                value,
+                updated_at: now,
+            }
+            .into_active_model()
+        });
+        mega_setting::Entity::insert_many(models)
+            .on_conflict(
+                OnConflict::column(mega_setting::Column::Name)
+                    .update_columns([mega_setting::Column::Value, mega_setting::Column::UpdatedAt])
+                    .to_owned(),
+            )
+            .exec(self.get_connection())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_settings(&self, names: Vec<String>) -> Result<(), MegaError> {
+        mega_setting::Entity::delete_many()
+            .filter(mega_setting::Column::Name.is_in(names))
+            .exec(self.get_connection())
+            .await?;
+        Ok(())
+    }
+}
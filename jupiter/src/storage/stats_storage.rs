@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::Utc;
+use sea_orm::{
+    sea_query::{Expr, SimpleExpr},
+    ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel, Order, QueryFilter, QueryOrder,
+    QuerySelect,
+};
+
+use callisto::{mega_commit, mega_lfs_object, mega_storage_report, mega_tag, mega_tree, raw_blob};
+use common::errors::MegaError;
+use common::utils::generate_id;
+
+/// Ids looked up per statement.
+const QUERY_CHUNK: usize = 1000;
+
+/// The objects of one type and the bytes they take in the database.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectTypeStats {
+    pub object_type: String,
+    pub count: i64,
+    pub size: i64,
+}
+
+/// The sizes of the objects stored, and the storage reports computed from them. The size of
+/// an object is that of the column holding its content: the message of a commit or a tag, the
+/// encoded entries of a tree, the data of a blob kept in the database.
+#[derive(Clone)]
+pub struct StatsStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl StatsStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        StatsStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        StatsStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    /// The count and size of the commits, trees, blobs and tags of every repository, and of
+    /// the LFS objects.
+    pub async fn object_type_stats(&self) -> Result<Vec<ObjectTypeStats>, MegaError> {
+        let connection = self.get_connection();
+        let (commits, commit_size): (i64, i64) = mega_commit::Entity::find()
+            .select_only()
+            .column_as(count(), "count")
+            .column_as(sum("octet_length(content)"), "size")
+            .into_tuple()
+            .one(connection)
+            .await?
+            .unwrap_or_default();
+        let (trees, tree_size): (i64, i64) = mega_tree::Entity::find()
+            .select_only()
+            .column_as(count(), "count")
+            .column_as(sum("octet_length(sub_trees)"), "size")
+            .into_tuple()
+            .one(connection)
+            .await?
+            .unwrap_or_default();
+        let (blobs, blob_size): (i64, i64) = raw_blob::Entity::find()
+            .select_only()
+            .column_as(count(), "count")
+            .column_as(sum("octet_length(data)"), "size")
+            .into_tuple()
+            .one(connection)
+            .await?
+            .unwrap_or_default();
+        let (tags, tag_size): (i64, i64) = mega_tag::Entity::find()
+            .select_only()
+            .column_as(count(), "count")
+            .column_as(sum("octet_length(message)"), "size")
+            .into_tuple()
+            .one(connection)
+            .await?
+            .unwrap_or_default();
+        let (lfs_objects, lfs_size): (i64, i64) = mega_lfs_object::Entity::find()
+            .select_only()
+            .column_as(count(), "count")
+            .column_as(sum("size"), "size")
+            .into_tuple()
+            .one(connection)
+            .await?
+            .unwrap_or_default();
+        Ok([
+            ("commit", commits, commit_size),
+            ("tree", trees, tree_size),
+            ("blob", blobs, blob_size),
+            ("tag", tags, tag_size),
+            ("lfs", lfs_objects, lfs_size),
+        ]
+        .into_iter()
+        .map(|(object_type, count, size)| ObjectTypeStats {
+            object_type: object_type.to_owned(),
+            count,
+            size,
+        })
+        .collect())
+    }
+
+    /// The ids and sizes of the `limit` largest blobs kept in the database, the largest first.
+    pub async fn largest_blobs(&self, limit: u64) -> Result<Vec<(String, i64)>, MegaError> {
+        Ok(raw_blob::Entity::find()
+            .select_only()
+            .column(raw_blob::Column::Sha1)
+            .column_as(blob_size(), "size")
+            .filter(raw_blob::Column::Data.is_not_null())
+            .order_by(blob_size(), Order::Desc)
+            .limit(limit)
+            .into_tuple()
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// The sizes of the blobs of `ids` kept in the database, the others are left out.
+    pub async fn blob_sizes(&self, ids: &[String]) -> Result<HashMap<String, i64>, MegaError> {
+        let mut sizes = HashMap::new();
+        for chunk in ids.chunks(QUERY_CHUNK) {
+            let found: Vec<(String, i64)> = raw_blob::Entity::find()
+                .select_only()
+                .column(raw_blob::Column::Sha1)
+                .column_as(blob_size(), "size")
+                .filter(raw_blob::Column::Sha1.is_in(chunk.to_vec()))
+                .filter(raw_blob::Column::Data.is_not_null())
+                .into_tuple()
+                .all(self.get_connection())
+                .await?;
+            sizes.extend(found);
+        }
+        Ok(sizes)
+    }
+
+    /// Saves the JSON `report` and removes the reports before the `keep` latest.
+    pub async fn save_report(&self, report: String, keep: u64) -> Result<(), MegaError> {
+        let model = mega_storage_report::Model {
+            id: generate_id(),
+            report,
+            created_at: Utc::now().naive_utc(),
+        };
+        mega_storage_report::Entity::insert(model.into_active_model())
+            .exec(self.get_connection())
+            .await?;
+        let stale: Vec<i64> = mega_storage_report::Entity::find()
+            .select_only()
+            .column(mega_storage_report::Column::Id)
+            .order_by_desc(mega_storage_report::Column::CreatedAt)
+            .offset(keep)
+            .into_tuple()
+            .all(self.get_connection())
+            .await?;
+        if !stale.is_empty() {
+            mega_storage_report::Entity::delete_many()
+                .filter(mega_storage_report::Column::Id.is_in(stale))
+                .exec(self.get_connection())
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn latest_report(&self) -> Result<Option<mega_storage_report::Model>, MegaError> {
+        Ok(mega_storage_report::Entity::find()
+            .order_by_desc(mega_storage_report::Column::CreatedAt)
+            .one(self.get_connection())
+            .await?)
+    }
+}
+
+fn count() -> SimpleExpr {
+    Expr::cust("COUNT(*)")
+}
+
+fn sum(size: &str) -> SimpleExpr {
+    Expr::cust(format!("CAST(COALESCE(SUM({}), 0) AS BIGINT)", size))
+}
+
+fn blob_size() -> SimpleExpr {
+    Expr::cust("CAST(octet_length(data) AS BIGINT)")
+}
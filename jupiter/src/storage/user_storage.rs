@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use chrono::{NaiveDateTime, Utc};
+use sea_orm::{
+    sea_query::{Expr, OnConflict},
+    ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder,
+};
+
+use callisto::{mega_auth_policy, mega_session_revocation, mega_ssh_key, mega_user};
+use common::errors::MegaError;
+use common::utils::generate_id;
+
+/// Users and SSH keys of the database authentication provider, and the authorization policy
+/// rules kept in the database.
+#[derive(Clone)]
+pub struct UserStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl UserStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        UserStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        UserStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    pub async fn save_user(
+        &self,
+        name: &str,
+        email: Option<String>,
+        token_hash: Option<String>,
+    ) -> Result<mega_user::Model, MegaError> {
+        let model = mega_user::Model {
+            id: generate_id(),
+            name: name.to_owned(),
+            email,
+            token_hash,
+            created_at: Utc::now().naive_utc(),
+        };
+        mega_user::Entity::insert(model.clone().into_active_model())
+            .exec(self.get_connection())
+            .await?;
+        Ok(model)
+    }
+
+    pub async fn find_user(&self, name: &str) -> Result<Option<mega_user::Model>, MegaError> {
+        Ok(mega_user::Entity::find()
+            .filter(mega_user::Column::Name.eq(name))
+            .one(self.get_connection())
+            .await?)
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<mega_user::Model>, MegaError> {
+        Ok(mega_user::Entity::find()
+            .order_by_asc(mega_user::Column::Name)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// The users with one of `emails`, used to attribute commits to their authors.
+    pub async fn find_users_by_email(
+        &self,
+        emails: Vec<String>,
+    ) -> Result<Vec<mega_user::Model>, MegaError> {
+        Ok(mega_user::Entity::find()
+            .filter(mega_user::Column::Email.is_in(emails))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    pub async fn find_user_by_token(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<mega_user::Model>, MegaError> {
+        Ok(mega_user::Entity::find()
+            .filter(mega_user::Column::TokenHash.eq(token_hash))
+            .one(self.get_connection())
+            .await?)
+    }
+
+    /// Removes the access token of a user, returns whether the user exists.
+    pub async fn revoke_token(&self, name: &str) -> Result<bool, MegaError> {
+        let res = mega_user::Entity::update_many()
+            .col_expr(mega_user::Column::TokenHash, Expr::value(None::<String>))
+            .filter(mega_user::Column::Name.eq(name))
+            .exec(self.get_connection())
+            .await?;
+        Ok(res.rows_affected > 0)
+    }
+
+    pub async fn add_ssh_key(&self, user_name: &str, public_key: &str) -> Result<(), MegaError> {
+        let model = mega_ssh_key::Model {
+            id: generate_id(),
+            user_name: user_name.to_owned(),
+            public_key: public_key.to_owned(),
+            created_at: Utc::now().naive_utc(),
+        };
+        mega_ssh_key::Entity::insert(model.into_active_model())
+            .exec(self.get_connection())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_ssh_keys(&self, user_name: &str) -> Result<Vec<String>, MegaError> {
+        Ok(mega_ssh_key::Entity::find()
+            .filter(mega_ssh_key::Column::UserName.eq(user_name))
+            .all(self.get_connection())
+            .await?
+            .into_iter()
+            .map(|key| key.public_key)
+            .collect())
+    }
+
+    /// The keys of every user, to find the owner of a key.
+    pub async fn list_ssh_keys(&self) -> Result<Vec<mega_ssh_key::Model>, MegaError> {
+        Ok(mega_ssh_key::Entity::find()
+            .all(self.get_connection())
+            .await?)
+    }
+
+    pub async fn find_ssh_keys(
+        &self,
+        user_name: &str,
+    ) -> Result<Vec<mega_ssh_key::Model>, MegaError> {
+        Ok(mega_ssh_key::Entity::find()
+            .filter(mega_ssh_key::Column::UserName.eq(user_name))
+            .order_by_asc(mega_ssh_key::Column::CreatedAt)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Removes the key `id` of a user, or all of them without one. Returns the number of
+    /// removed keys.
+    pub async fn remove_ssh_keys(
+        &self,
+        user_name: &str,
+        id: Option<i64>,
+    ) -> Result<u64, MegaError> {
+        let mut query = mega_ssh_key::Entity::delete_many()
+            .filter(mega_ssh_key::Column::UserName.eq(user_name));
+        if let Some(id) = id {
+            query = query.filter(mega_ssh_key::Column::Id.eq(id));
+        }
+        Ok(query.exec(self.get_connection()).await?.rows_affected)
+    }
+
+    /// Expires the sessions a user holds now, the tokens issued up to this instant are refused.
+    pub async fn expire_sessions(&self, user_name: &str) -> Result<NaiveDateTime, MegaError> {
+        let model = mega_session_revocation::Model {
+            user_name: user_name.to_owned(),
+            revoked_at: Utc::now().naive_utc(),
+        };
+        mega_session_revocation::Entity::insert(model.clone().into_active_model())
+            .on_conflict(
+                OnConflict::column(mega_session_revocation::Column::UserName)
+                    .update_column(mega_session_revocation::Column::RevokedAt)
+                    .to_owned(),
+            )
+            .exec(self.get_connection())
+            .await?;
+        Ok(model.revoked_at)
+    }
+
+    /// When the sessions of a user were last expired.
+    pub async fn sessions_revoked_at(
+        &self,
+        user_name: &str,
+    ) -> Result<Option<NaiveDateTime>, MegaError> {
+        Ok(
+            mega_session_revocation::Entity::find_by_id(user_name.to_owned())
+                .one(self.get_connection())
+                .await?
+                .map(|revocation| revocation.revoked_at),
+        )
+    }
+
+    /// The policy rules of the database, in the order they were added.
+    pub async fn get_policy_rules(&self) -> Result<Vec<String>, MegaError> {
+        Ok(mega_auth_policy::Entity::find()
+            .order_by_asc(mega_auth_policy::Column::CreatedAt)
+            .all(self.get_connection())
+            .await?
+            .into_iter()
+            .map(|policy| policy.rule)
+            .collect())
+    }
+}
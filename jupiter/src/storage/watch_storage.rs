@@ -0,0 +1,159 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use sea_orm::{
+    sea_query::{Expr, OnConflict},
+    ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder,
+    QuerySelect,
+};
+
+use callisto::{mega_watch, mega_watch_notification};
+use common::errors::MegaError;
+use common::utils::generate_id;
+
+/// The branches and paths the users watch, and the notifications of the pushes touching them.
+#[derive(Clone)]
+pub struct WatchStorage {
+    pub connection: Arc<DatabaseConnection>,
+}
+
+impl WatchStorage {
+    pub fn get_connection(&self) -> &DatabaseConnection {
+        &self.connection
+    }
+
+    pub async fn new(connection: Arc<DatabaseConnection>) -> Self {
+        WatchStorage { connection }
+    }
+
+    pub fn mock() -> Self {
+        WatchStorage {
+            connection: Arc::new(DatabaseConnection::default()),
+        }
+    }
+
+    /// Adds a watch of `user_name`, the same watch added again is returned as it is.
+    pub async fn add_watch(
+        &self,
+        user_name: &str,
+        repo_path: &str,
+        ref_name: Option<String>,
+        path: Option<String>,
+    ) -> Result<mega_watch::Model, MegaError> {
+        let existing = self
+            .get_watches(user_name)
+            .await?
+            .into_iter()
+            .find(|w| w.repo_path == repo_path && w.ref_name == ref_name && w.path == path);
+        if let Some(watch) = existing {
+            return Ok(watch);
+        }
+        let watch = mega_watch::Model {
+            id: generate_id(),
+            user_name: user_name.to_owned(),
+            repo_path: repo_path.to_owned(),
+            ref_name,
+            path,
+            created_at: Utc::now().naive_utc(),
+        };
+        mega_watch::Entity::insert(watch.clone().into_active_model())
+            .exec(self.get_connection())
+            .await?;
+        Ok(watch)
+    }
+
+    /// Removes a watch of `user_name`, returns false when there is no such watch.
+    pub async fn remove_watch(&self, id: i64, user_name: &str) -> Result<bool, MegaError> {
+        let res = mega_watch::Entity::delete_many()
+            .filter(mega_watch::Column::Id.eq(id))
+            .filter(mega_watch::Column::UserName.eq(user_name))
+            .exec(self.get_connection())
+            .await?;
+        Ok(res.rows_affected > 0)
+    }
+
+    /// The watches of `user_name`, oldest first.
+    pub async fn get_watches(&self, user_name: &str) -> Result<Vec<mega_watch::Model>, MegaError> {
+        Ok(mega_watch::Entity::find()
+            .filter(mega_watch::Column::UserName.eq(user_name))
+            .order_by_asc(mega_watch::Column::CreatedAt)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// The watches of every user on the repository, or directory of the monorepo, `repo_path`.
+    pub async fn get_repo_watches(
+        &self,
+        repo_path: &str,
+    ) -> Result<Vec<mega_watch::Model>, MegaError> {
+        Ok(mega_watch::Entity::find()
+            .filter(mega_watch::Column::RepoPath.eq(repo_path))
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Saves `notifications`, those of a watch and an event saved before are ignored, so
+    /// delivering the same event again does not notify twice. Returns the new ones.
+    pub async fn save_notifications(
+        &self,
+        notifications: Vec<mega_watch_notification::Model>,
+    ) -> Result<Vec<mega_watch_notification::Model>, MegaError> {
+        let mut saved = Vec::new();
+        for notification in notifications {
+            let inserted =
+                mega_watch_notification::Entity::insert(notification.clone().into_active_model())
+                    .on_conflict(
+                        OnConflict::columns([
+                            mega_watch_notification::Column::WatchId,
+                            mega_watch_notification::Column::EventId,
+                        ])
+                        .do_nothing()
+                        .to_owned(),
+                    )
+                    .exec_without_returning(self.get_connection())
+                    .await?;
+            if inserted > 0 {
+                saved.push(notification);
+            }
+        }
+        Ok(saved)
+    }
+
+    /// The notifications of `user_name` before the notification `before`, newest first.
+    pub async fn get_notifications(
+        &self,
+        user_name: &str,
+        before: Option<i64>,
+        unread: bool,
+        limit: u64,
+    ) -> Result<Vec<mega_watch_notification::Model>, MegaError> {
+        let mut query = mega_watch_notification::Entity::find()
+            .filter(mega_watch_notification::Column::UserName.eq(user_name));
+        if let Some(before) = before {
+            query = query.filter(mega_watch_notification::Column::Id.lt(before));
+        }
+        if unread {
+            query = query.filter(mega_watch_notification::Column::ReadAt.is_null());
+        }
+        Ok(query
+            .order_by_desc(mega_watch_notification::Column::Id)
+            .limit(limit)
+            .all(self.get_connection())
+            .await?)
+    }
+
+    /// Marks the notifications `ids` of `user_name` as read, returns how many were unread.
+    pub async fn mark_read(&self, user_name: &str, ids: Vec<i64>) -> Result<u64, MegaError> {
+        let res = mega_watch_notification::Entity::update_many()
+            .col_expr(
+                mega_watch_notification::Column::ReadAt,
+                Expr::value(Utc::now().naive_utc()),
+            )
+            .filter(mega_watch_notification::Column::UserName.eq(user_name))
+            .filter(mega_watch_notification::Column::Id.is_in(ids))
+            .filter(mega_watch_notification::Column::ReadAt.is_null())
+            .exec(self.get_connection())
+            .await?;
+        Ok(res.rows_affected)
+    }
+}
@@ -0,0 +1,150 @@
+//! Pack decode/encode benchmarks on generated packs, run with
+//! `cargo bench -p mercury --features test-support`.
+//!
+//! Decode covers small/medium/large packs, blob-heavy (no delta) vs delta-heavy (long chains)
+//! packs and a memory-limited vs unlimited cache, to catch regressions in the caches and the waitlist.
+//! The largest pack is also decoded from a file, read through a buffer or memory-mapped.
+//!
+//! The lanes group decodes several packs at once in shared lanes, like the pushes of a server, with
+//! the workers pinned to cores when the `pinned-threads` feature is enabled as well.
+//!
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::task::JoinSet;
+
+use mercury::internal::pack::encode::PackEncoder;
+use mercury::internal::pack::lanes::{Lane, Lanes};
+use mercury::internal::pack::Pack;
+use mercury::test_support::{FixtureConfig, SyntheticRepo};
+
+const SIZES: [(&str, usize, usize); 3] = [("small", 20, 8), ("medium", 200, 32), ("large", 1000, 64)];
+
+fn fixture(commits: usize, files: usize, delta_heavy: bool) -> SyntheticRepo {
+    SyntheticRepo::generate(&FixtureConfig {
+        commits,
+        files,
+        blob_size: if delta_heavy { 4096 } else { 16384 },
+        delta_depth: if delta_heavy { 50 } else { 0 },
+        ..Default::default()
+    })
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    group.sample_size(10);
+    for (size, commits, files) in SIZES {
+        for (shape, delta_heavy) in [("blob-heavy", false), ("delta-heavy", true)] {
+            let repo = fixture(commits, files, delta_heavy);
+            let pack = repo.to_pack();
+            group.throughput(Throughput::Bytes(pack.len() as u64));
+            // the limited cache holds about a quarter of the decoded objects, so objects get spilled to disk,
+            // below a few MB the decoder can't fit its working set and waits forever
+            let decoded: usize = repo.objects.iter().map(|o| o.data.len()).sum();
+            let limited = (decoded / 4).max(4 * 1024 * 1024);
+            for (memory, mem_limit) in [("unlimited", None), ("limited", Some(limited))] {
+                let id = BenchmarkId::new(format!("{}/{}", size, shape), memory);
+                group.bench_with_input(id, &pack, |b, pack| {
+                    b.iter(|| {
+                        let mut p = Pack::new(None, mem_limit, Some(PathBuf::from("/tmp/.cache_bench")));
+                        p.decode(&mut Cursor::new(pack), |_| {}).unwrap();
+                    })
+                });
+            }
+        }
+    }
+    // the largest pack read from a file, buffered or mapped
+    let (size, commits, files) = SIZES[SIZES.len() - 1];
+    let pack = fixture(commits, files, true).to_pack();
+    let path = std::env::temp_dir().join("mercury-bench.pack");
+    std::fs::write(&path, &pack).unwrap();
+    group.throughput(Throughput::Bytes(pack.len() as u64));
+    group.bench_function(BenchmarkId::new(format!("{}/file", size), "buffered"), |b| {
+        b.iter(|| {
+            let file = std::fs::File::open(&path).unwrap();
+            let mut p = Pack::new(None, None, Some(PathBuf::from("/tmp/.cache_bench")));
+            p.decode(&mut std::io::BufReader::new(file), |_| {}).unwrap();
+        })
+    });
+    group.bench_function(BenchmarkId::new(format!("{}/file", size), "mmap"), |b| {
+        b.iter(|| {
+            let mut p = Pack::new(None, None, Some(PathBuf::from("/tmp/.cache_bench")));
+            p.decode_mmap(&path, |_| {}).unwrap();
+        })
+    });
+    std::fs::remove_file(&path).unwrap();
+    group.finish();
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    group.sample_size(10);
+    for (size, commits, files) in &SIZES[..2] {
+        let objects = fixture(*commits, *files, true).objects;
+        group.throughput(Throughput::Elements(objects.len() as u64));
+        for window in [0, 10] {
+            let id = BenchmarkId::new(*size, format!("window-{}", window));
+            group.bench_with_input(id, &objects, |b, objects| {
+                b.iter(|| {
+                    let mut writer = Vec::new();
+                    let mut encoder = PackEncoder::new(objects.len(), window, &mut writer);
+                    let (tx, rx) = mpsc::channel();
+                    for entry in objects.iter() {
+                        tx.send(entry.clone()).unwrap();
+                    }
+                    drop(tx);
+                    encoder.encode(rx).unwrap();
+                    writer
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_lanes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lanes");
+    group.sample_size(10);
+    let (_, commits, files) = SIZES[1];
+    let pack = fixture(commits, files, true).to_pack();
+    let threads = num_cpus::get();
+    // as many packs decoded at once as there are cores, one in the interactive lane
+    let decodes = threads.max(2);
+    group.throughput(Throughput::Bytes((pack.len() * decodes) as u64));
+    #[cfg_attr(
+        not(all(target_os = "linux", feature = "pinned-threads")),
+        allow(unused_mut)
+    )]
+    let mut lanes = vec![("unpinned", Arc::new(Lanes::new(threads, threads * 3 / 4)))];
+    #[cfg(all(target_os = "linux", feature = "pinned-threads"))]
+    lanes.push(("pinned", Arc::new(Lanes::pinned(threads, threads * 3 / 4))));
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    for (name, lanes) in lanes {
+        group.bench_with_input(BenchmarkId::new("delta-heavy", name), &pack, |b, pack| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let mut set = JoinSet::new();
+                    for n in 0..decodes {
+                        let lane = if n == 0 { Lane::Interactive } else { Lane::Batch };
+                        let mut p = Pack::new(None, None, Some(PathBuf::from("/tmp/.cache_bench"))).with_lanes(lanes.clone(), lane);
+                        let pack = pack.clone();
+                        set.spawn(async move {
+                            let (sender, mut receiver) = tokio::sync::mpsc::channel(1024);
+                            let receive = async { while receiver.recv().await.is_some() {} };
+                            tokio::join!(p.decode_tokio(Cursor::new(pack), sender), receive).0.unwrap();
+                        });
+                    }
+                    while let Some(decoded) = set.join_next().await {
+                        decoded.unwrap();
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode, bench_encode, bench_lanes);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+#![no_main]
+
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use libfuzzer_sys::fuzz_target;
+use mercury::internal::pack::Pack;
+
+fuzz_target!(|data: &[u8]| {
+    let mut pack = Pack::new(Some(1), Some(1024 * 1024 * 64), Some(PathBuf::from("/tmp/.cache_fuzz")));
+    // the first object of a pack starts after the 12 bytes header
+    let mut offset = 12;
+    let _ = pack.decode_pack_object(&mut Cursor::new(data), &mut offset);
+});
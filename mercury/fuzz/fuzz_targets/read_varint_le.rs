@@ -0,0 +1,12 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use mercury::internal::pack::utils::read_varint_le;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((_, bytes)) = read_varint_le(&mut Cursor::new(data)) {
+        assert!(bytes <= data.len());
+    }
+});
@@ -0,0 +1,297 @@
+//!
+//! Git bundles, a header naming refs followed by a pack of their objects, as written by
+//! `git bundle create` and read by `git bundle unbundle` or `git clone`. They move the state
+//! of a repository where no network protocol reaches. The prerequisites of an incremental
+//! bundle are commits the receiver must already have, the pack only holds what follows them.
+//!
+//! Both versions are read and written: v2 has no capabilities, v3 adds `@object-format`
+//! and `@filter` lines before the prerequisites. Only SHA-1 object ids are supported.
+//!
+//! ## Reference
+//! 1. Git Bundle [Format](https://git-scm.com/docs/gitformat-bundle)
+//!
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use venus::errors::GitError;
+use venus::hash::SHA1;
+
+const V2_SIGNATURE: &str = "# v2 git bundle";
+const V3_SIGNATURE: &str = "# v3 git bundle";
+
+/// The `@object-format` of the object ids of a bundle.
+pub const OBJECT_FORMAT_SHA1: &str = "sha1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BundleVersion {
+    #[default]
+    V2,
+    V3,
+}
+
+impl BundleVersion {
+    fn signature(self) -> &'static str {
+        match self {
+            BundleVersion::V2 => V2_SIGNATURE,
+            BundleVersion::V3 => V3_SIGNATURE,
+        }
+    }
+}
+
+/// A capability of a v3 bundle, `@<key>` or `@<key>=<value>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleCapability {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+/// A commit the receiver must have, with the subject of its message as a comment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prerequisite {
+    pub id: SHA1,
+    pub comment: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleRef {
+    pub id: SHA1,
+    pub name: String,
+}
+
+/// Everything before the pack of a bundle.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BundleHeader {
+    pub version: BundleVersion,
+    pub capabilities: Vec<BundleCapability>,
+    pub prerequisites: Vec<Prerequisite>,
+    pub references: Vec<BundleRef>,
+}
+
+impl BundleHeader {
+    /// The header of a bundle of `version`, a v3 one declares its SHA-1 object ids.
+    pub fn new(version: BundleVersion) -> Self {
+        let capabilities = match version {
+            BundleVersion::V2 => Vec::new(),
+            BundleVersion::V3 => vec![BundleCapability {
+                key: String::from("object-format"),
+                value: Some(String::from(OBJECT_FORMAT_SHA1)),
+            }],
+        };
+        BundleHeader {
+            version,
+            capabilities,
+            ..Default::default()
+        }
+    }
+
+    /// The `@filter` of a bundle holding a partial pack, as in `git bundle create --filter`.
+    pub fn filter(&self) -> Option<&str> {
+        self.capabilities
+            .iter()
+            .find(|c| c.key == "filter")
+            .and_then(|c| c.value.as_deref())
+    }
+
+    /// Reads the header up to the empty line ending it, `reader` is then at the pack.
+    pub fn read<R: BufRead>(reader: &mut R) -> Result<Self, GitError> {
+        let signature = read_line(reader)?
+            .ok_or_else(|| GitError::InvalidBundle(String::from("empty file")))?;
+        let version = match signature.as_str() {
+            V2_SIGNATURE => BundleVersion::V2,
+            V3_SIGNATURE => BundleVersion::V3,
+            other => return Err(GitError::InvalidBundle(format!("signature {:?}", other))),
+        };
+        let mut header = BundleHeader {
+            version,
+            ..Default::default()
+        };
+        loop {
+            let line = read_line(reader)?
+                .ok_or_else(|| GitError::InvalidBundle(String::from("truncated header")))?;
+            if line.is_empty() {
+                break;
+            }
+            if let Some(capability) = line.strip_prefix('@') {
+                // the capabilities come first and only in v3
+                if version != BundleVersion::V3
+                    || !header.prerequisites.is_empty()
+                    || !header.references.is_empty()
+                {
+                    return Err(GitError::InvalidBundle(format!("capability {:?}", line)));
+                }
+                header.capabilities.push(parse_capability(capability)?);
+            } else if let Some(prerequisite) = line.strip_prefix('-') {
+                let (id, comment) = prerequisite.split_once(' ').unwrap_or((prerequisite, ""));
+                header.prerequisites.push(Prerequisite {
+                    id: parse_id(id)?,
+                    comment: comment.to_owned(),
+                });
+            } else {
+                let (id, name) = line
+                    .split_once(' ')
+                    .ok_or_else(|| GitError::InvalidBundle(format!("reference {:?}", line)))?;
+                header.references.push(BundleRef {
+                    id: parse_id(id)?,
+                    name: name.to_owned(),
+                });
+            }
+        }
+        Ok(header)
+    }
+
+    /// Writes the header, the pack is to be written right after it.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.version == BundleVersion::V2 && !self.capabilities.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a v2 bundle has no capabilities",
+            ));
+        }
+        writeln!(writer, "{}", self.version.signature())?;
+        for capability in &self.capabilities {
+            match &capability.value {
+                Some(value) => writeln!(writer, "@{}={}", capability.key, value)?,
+                None => writeln!(writer, "@{}", capability.key)?,
+            }
+        }
+        for prerequisite in &self.prerequisites {
+            if prerequisite.comment.is_empty() {
+                writeln!(writer, "-{}", prerequisite.id.to_plain_str())?;
+            } else {
+                writeln!(
+                    writer,
+                    "-{} {}",
+                    prerequisite.id.to_plain_str(),
+                    prerequisite.comment
+                )?;
+            }
+        }
+        for reference in &self.references {
+            writeln!(writer, "{} {}", reference.id.to_plain_str(), reference.name)?;
+        }
+        writeln!(writer)
+    }
+}
+
+/// Writes a bundle of `header` and `pack`, the bytes of a pack file.
+pub fn write_bundle<W: Write>(
+    writer: &mut W,
+    header: &BundleHeader,
+    pack: &[u8],
+) -> io::Result<()> {
+    header.write(writer)?;
+    writer.write_all(pack)?;
+    writer.flush()
+}
+
+/// A line of the header without its newline, None at the end of the input.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>, GitError> {
+    let mut line = Vec::new();
+    let read = reader
+        .read_until(b'\n', &mut line)
+        .map_err(|e| GitError::InvalidBundle(e.to_string()))?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(GitError::InvalidBundle(String::from("truncated header")));
+    }
+    Ok(Some(String::from_utf8(line)?))
+}
+
+/// The capabilities git knows, any other one makes the bundle unreadable.
+fn parse_capability(capability: &str) -> Result<BundleCapability, GitError> {
+    let (key, value) = match capability.split_once('=') {
+        Some((key, value)) => (key, Some(value.to_owned())),
+        None => (capability, None),
+    };
+    match (key, value.as_deref()) {
+        ("object-format", Some(OBJECT_FORMAT_SHA1)) | ("filter", Some(_)) => {}
+        _ => {
+            return Err(GitError::InvalidBundle(format!(
+                "unsupported capability @{}",
+                capability
+            )))
+        }
+    }
+    Ok(BundleCapability {
+        key: key.to_owned(),
+        value,
+    })
+}
+
+fn parse_id(id: &str) -> Result<SHA1, GitError> {
+    if id.len() != 40 {
+        return Err(GitError::InvalidBundle(format!("object id {:?}", id)));
+    }
+    SHA1::from_str(id).map_err(|_| GitError::InvalidBundle(format!("object id {:?}", id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read};
+    use std::str::FromStr;
+
+    use venus::hash::SHA1;
+
+    use super::{
+        write_bundle, BundleCapability, BundleHeader, BundleRef, BundleVersion, Prerequisite,
+    };
+
+    fn header(version: BundleVersion) -> BundleHeader {
+        let mut header = BundleHeader::new(version);
+        header.prerequisites.push(Prerequisite {
+            id: SHA1::from_str(&"a".repeat(40)).unwrap(),
+            comment: String::from("Initial commit"),
+        });
+        header.references.push(BundleRef {
+            id: SHA1::from_str(&"b".repeat(40)).unwrap(),
+            name: String::from("refs/heads/main"),
+        });
+        header
+    }
+
+    #[test]
+    fn test_bundle_round_trip() {
+        for version in [BundleVersion::V2, BundleVersion::V3] {
+            let header = header(version);
+            let mut buf = Vec::new();
+            write_bundle(&mut buf, &header, b"PACK....").unwrap();
+
+            let mut reader = BufReader::new(buf.as_slice());
+            assert_eq!(BundleHeader::read(&mut reader).unwrap(), header);
+            let mut pack = Vec::new();
+            reader.read_to_end(&mut pack).unwrap();
+            assert_eq!(pack, b"PACK....");
+        }
+    }
+
+    #[test]
+    fn test_read_bundle_header() {
+        let text = format!(
+            "# v3 git bundle\n@object-format=sha1\n@filter=blob:none\n-{}\n{} HEAD\n\nPACK",
+            "a".repeat(40),
+            "b".repeat(40)
+        );
+        let header = BundleHeader::read(&mut text.as_bytes()).unwrap();
+        assert_eq!(header.filter(), Some("blob:none"));
+        assert_eq!(header.prerequisites[0].comment, "");
+        assert_eq!(header.references[0].name, "HEAD");
+
+        // capabilities are only in v3, and an unknown one cannot be read
+        let v2 = "# v2 git bundle\n@object-format=sha1\n\n";
+        assert!(BundleHeader::read(&mut v2.as_bytes()).is_err());
+        let unknown = "# v3 git bundle\n@object-format=sha256\n\n";
+        assert!(BundleHeader::read(&mut unknown.as_bytes()).is_err());
+        let truncated = format!("# v2 git bundle\n{} refs/heads/main\n", "b".repeat(40));
+        assert!(BundleHeader::read(&mut truncated.as_bytes()).is_err());
+        assert!(BundleHeader::read(&mut "PACK".as_bytes()).is_err());
+
+        let mut v2 = BundleHeader::new(BundleVersion::V2);
+        v2.capabilities.push(BundleCapability {
+            key: String::from("filter"),
+            value: Some(String::from("blob:none")),
+        });
+        assert!(v2.write(&mut Vec::new()).is_err());
+    }
+}
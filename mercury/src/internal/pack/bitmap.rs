@@ -0,0 +1,671 @@
+//!
+//! Reachability bitmaps (`.bitmap`) version 1, written alongside the `.idx` of a pack: for some
+//! selected commits, the set of the objects of the pack they reach, one bit per object in the
+//! order of the pack. The objects of a clone are then counted by OR-ing the bitmaps of the
+//! wanted commits and removing the ones of the commits the client has, without walking the
+//! history or reading a tree. The bitmaps are compressed with EWAH, as git does, so git can
+//! read the ones written here and the other way around.
+//!
+//! ## Reference
+//! 1. Git Pack Bitmap [Format](https://git-scm.com/docs/gitformat-pack#_pack_bitmap_bitmap_files_have_the_following_format)
+//!
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use sha1::{Digest, Sha1};
+
+use venus::errors::GitError;
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::internal::object::tag::Tag;
+use venus::internal::object::tree::{Tree, TreeItemMode};
+use venus::internal::object::types::ObjectType;
+use venus::internal::object::ObjectTrait;
+use venus::internal::revwalk::CommitGraph;
+
+use crate::internal::pack::index::PackIndex;
+use crate::internal::pack::reader::PackReader;
+
+const BITMAP_MAGIC: [u8; 4] = *b"BITM";
+const BITMAP_VERSION: u16 = 1;
+/// The bitmaps cover the whole history of their commits, git ignores the files without.
+const BITMAP_OPT_FULL_DAG: u16 = 1;
+const HEADER_LEN: usize = 32;
+
+/// The longest run of empty or full words of an EWAH marker word.
+const MAX_RUN: usize = u32::MAX as usize;
+/// The most literal words after an EWAH marker word.
+const MAX_LITERALS: usize = (1 << 31) - 1;
+
+/// A commit every this many commits of the history has a bitmap, besides the heads. Git
+/// selects about one in a hundred as well, the others are a short walk away from one.
+pub const DEFAULT_BITMAP_INTERVAL: usize = 100;
+
+/// A set of objects of a pack, by their position in the pack.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    pub fn set(&mut self, position: usize) {
+        let word = position / 64;
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << (position % 64);
+    }
+
+    pub fn contains(&self, position: usize) -> bool {
+        self.words
+            .get(position / 64)
+            .is_some_and(|word| word & (1 << (position % 64)) != 0)
+    }
+
+    /// Adds the objects of `other`.
+    pub fn union(&mut self, other: &Bitmap) {
+        if other.words.len() > self.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    /// Removes the objects of `other`.
+    pub fn difference(&mut self, other: &Bitmap) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= !other;
+        }
+    }
+
+    /// Keeps the objects also in `other`.
+    pub fn intersection(&mut self, other: &Bitmap) {
+        self.words.truncate(other.words.len());
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= other;
+        }
+    }
+
+    fn symmetric_difference(&mut self, other: &Bitmap) {
+        if other.words.len() > self.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word ^= other;
+        }
+    }
+
+    /// The number of objects.
+    pub fn len(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|word| *word == 0)
+    }
+
+    /// The positions of the objects, in the order of the pack.
+    pub fn positions(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(n, word)| {
+            let mut word = *word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(n * 64 + bit)
+            })
+        })
+    }
+
+    /// Appends the bitmap compressed with EWAH: the words are grouped behind marker words,
+    /// each holding the length of a run of empty or full words and the number of the literal
+    /// words following it.
+    fn encode_ewah(&self, data: &mut Vec<u8>) {
+        let len = self
+            .words
+            .iter()
+            .rposition(|word| *word != 0)
+            .map_or(0, |n| n + 1);
+        let words = &self.words[..len];
+        let bits = words
+            .last()
+            .map_or(0, |last| len * 64 - last.leading_zeros() as usize);
+
+        let mut buffer: Vec<u64> = Vec::new();
+        let mut last_marker;
+        let mut n = 0;
+        loop {
+            let full = words.get(n) == Some(&u64::MAX);
+            let fill = if full { u64::MAX } else { 0 };
+            let run_start = n;
+            while n < len && n - run_start < MAX_RUN && words[n] == fill {
+                n += 1;
+            }
+            let literal_start = n;
+            while n < len
+                && n - literal_start < MAX_LITERALS
+                && words[n] != 0
+                && words[n] != u64::MAX
+            {
+                n += 1;
+            }
+            last_marker = buffer.len();
+            buffer.push(
+                full as u64
+                    | ((literal_start - run_start) as u64) << 1
+                    | ((n - literal_start) as u64) << 33,
+            );
+            buffer.extend_from_slice(&words[literal_start..n]);
+            if n >= len {
+                break;
+            }
+        }
+
+        data.extend_from_slice(&(bits as u32).to_be_bytes());
+        data.extend_from_slice(&(buffer.len() as u32).to_be_bytes());
+        for word in buffer {
+            data.extend_from_slice(&word.to_be_bytes());
+        }
+        data.extend_from_slice(&(last_marker as u32).to_be_bytes());
+    }
+
+    /// Reads an EWAH bitmap at `at` of `data` of the objects of a pack of `objects` objects,
+    /// moving `at` past it. Git rounds the number of bits up to whole words.
+    fn decode_ewah(data: &[u8], at: &mut usize, objects: usize) -> Option<Bitmap> {
+        let bits = read_u32(data, *at)? as usize;
+        let count = read_u32(data, *at + 4)? as usize;
+        let start = *at + 8;
+        let end = start.checked_add(count.checked_mul(8)?)?;
+        read_u32(data, end)?;
+        let max_words = bits.div_ceil(64);
+        if max_words > objects.div_ceil(64) {
+            return None;
+        }
+        let buffer: Vec<u64> = data[start..end]
+            .chunks_exact(8)
+            .map(|word| u64::from_be_bytes(word.try_into().unwrap()))
+            .collect();
+
+        let mut words = Vec::with_capacity(max_words);
+        let mut n = 0;
+        while n < buffer.len() {
+            let marker = buffer[n];
+            let run = (marker >> 1) as u32 as usize;
+            let literals = (marker >> 33) as usize;
+            let literal_end = n + 1 + literals;
+            if words.len() + run + literals > max_words || literal_end > buffer.len() {
+                return None;
+            }
+            let fill = if marker & 1 == 1 { u64::MAX } else { 0 };
+            words.resize(words.len() + run, fill);
+            words.extend_from_slice(&buffer[n + 1..literal_end]);
+            n = literal_end;
+        }
+        *at = end + 4;
+        Some(Bitmap { words })
+    }
+}
+
+/// The bitmaps of a pack.
+#[derive(Debug, Clone)]
+pub struct PackBitmap {
+    pub pack_hash: SHA1,
+    /// The objects with their offset, in the order of the pack: the positions of the bits.
+    objects: Vec<(SHA1, u64)>,
+    /// The objects of each type: commits, trees, blobs and tags.
+    types: [Bitmap; 4],
+    /// The objects reached by the selected commits.
+    bitmaps: HashMap<SHA1, Bitmap>,
+}
+
+impl PackBitmap {
+    /// Computes the bitmaps of the pack of `reader` for the heads of its history, the commits
+    /// no other commit of the pack has as parent, for the commits of `tips`, tags peeled, and
+    /// for one commit every `interval` commits of the history. Every object is read once to
+    /// find the ones it refers to, the bitmap of a commit reuses the ones of its ancestors.
+    pub fn build(reader: &PackReader, tips: &[SHA1], interval: usize) -> Result<Self, GitError> {
+        let objects = pack_order(reader.index());
+        let positions: HashMap<SHA1, usize> = objects
+            .iter()
+            .enumerate()
+            .map(|(n, (hash, _))| (*hash, n))
+            .collect();
+
+        let mut types: [Bitmap; 4] = Default::default();
+        let mut links: Vec<Vec<usize>> = Vec::with_capacity(objects.len());
+        let mut commits = Vec::new();
+        let mut tags = HashMap::new();
+        for (n, (hash, offset)) in objects.iter().enumerate() {
+            let entry = reader.get_at(hash, *offset)?;
+            types[type_slot(entry.obj_type)?].set(n);
+            let refers_to: Vec<SHA1> = match entry.obj_type {
+                ObjectType::Commit => {
+                    let commit = Commit::from_bytes(entry.data, entry.hash)?;
+                    let mut refers_to = vec![commit.tree_id];
+                    refers_to.extend(&commit.parent_commit_ids);
+                    commits.push(commit);
+                    refers_to
+                }
+                ObjectType::Tree => Tree::from_bytes(entry.data, entry.hash)?
+                    .tree_items
+                    .into_iter()
+                    // the commits of submodules are not part of the repository
+                    .filter(|item| item.mode != TreeItemMode::Commit)
+                    .map(|item| item.id)
+                    .collect(),
+                ObjectType::Tag => {
+                    let tag = Tag::from_bytes(entry.data, entry.hash)?;
+                    tags.insert(tag.id, tag.object_hash);
+                    vec![tag.object_hash]
+                }
+                _ => Vec::new(),
+            };
+            // the objects missing from the pack, e.g. in another one, are not in its bitmaps
+            links.push(
+                refers_to
+                    .iter()
+                    .filter_map(|id| positions.get(id).copied())
+                    .collect(),
+            );
+        }
+
+        let parents: HashSet<SHA1> = commits
+            .iter()
+            .flat_map(|commit| commit.parent_commit_ids.iter().copied())
+            .collect();
+        let mut heads: Vec<SHA1> = commits
+            .iter()
+            .map(|commit| commit.id)
+            .filter(|id| !parents.contains(id))
+            .collect();
+        let graph = CommitGraph::new(commits);
+        for tip in tips {
+            let mut tip = *tip;
+            let mut peeled = 0;
+            while let Some(object) = tags.get(&tip) {
+                tip = *object;
+                peeled += 1;
+                if peeled > tags.len() {
+                    break;
+                }
+            }
+            if graph.contains(&tip) {
+                heads.push(tip);
+            }
+        }
+        let interval = interval.max(1);
+        let mut selected: HashSet<SHA1> = heads.iter().copied().collect();
+        selected.extend(
+            graph
+                .walk(&heads, &[])
+                .into_iter()
+                .skip(interval - 1)
+                .step_by(interval),
+        );
+        // the ancestors first, their bitmaps are then reused by their descendants
+        let mut selected: Vec<SHA1> = selected.into_iter().collect();
+        selected.sort_by_key(|id| (graph.generation(id), *id));
+
+        let mut reached: HashMap<usize, Bitmap> = HashMap::new();
+        let mut bitmaps = HashMap::new();
+        for id in selected {
+            let start = positions[&id];
+            let mut bitmap = Bitmap::default();
+            let mut stack = vec![start];
+            while let Some(n) = stack.pop() {
+                if bitmap.contains(n) {
+                    continue;
+                }
+                if let Some(ancestor) = reached.get(&n) {
+                    bitmap.union(ancestor);
+                    continue;
+                }
+                bitmap.set(n);
+                stack.extend(&links[n]);
+            }
+            reached.insert(start, bitmap.clone());
+            bitmaps.insert(id, bitmap);
+        }
+        Ok(PackBitmap {
+            pack_hash: reader.index().pack_hash,
+            objects,
+            types,
+            bitmaps,
+        })
+    }
+
+    /// Reads the bitmaps of the pack indexed by `index`. The bitmaps XOR-ed with a previous
+    /// one by git are read as well, the extensions after them are ignored.
+    pub fn from_bytes(data: &[u8], index: &PackIndex) -> Result<Self, GitError> {
+        let invalid = |msg: &str| GitError::InvalidBitmapFile(msg.to_owned());
+        if data.len() < HEADER_LEN + 20 {
+            return Err(invalid("too short"));
+        }
+        let (content, checksum) = data.split_at(data.len() - 20);
+        if Sha1::digest(content).as_slice() != checksum {
+            return Err(invalid("checksum mismatch"));
+        }
+        if data[..4] != BITMAP_MAGIC || data[4..6] != BITMAP_VERSION.to_be_bytes() {
+            return Err(invalid("not a version 1 bitmap"));
+        }
+        let options = u16::from_be_bytes([data[6], data[7]]);
+        if options & BITMAP_OPT_FULL_DAG == 0 {
+            return Err(invalid("not a full bitmap"));
+        }
+        let count = read_u32(data, 8).unwrap() as usize;
+        let pack_hash = SHA1::from_bytes(&data[12..32]);
+        if pack_hash != index.pack_hash {
+            return Err(invalid("the bitmap of another pack"));
+        }
+
+        let objects = pack_order(index);
+        let mut at = HEADER_LEN;
+        let mut types: [Bitmap; 4] = Default::default();
+        for bitmap in types.iter_mut() {
+            *bitmap = Bitmap::decode_ewah(content, &mut at, objects.len())
+                .ok_or_else(|| invalid("invalid type bitmap"))?;
+        }
+        let mut decoded: Vec<Bitmap> = Vec::with_capacity(count);
+        let mut bitmaps = HashMap::with_capacity(count);
+        for _ in 0..count {
+            let position = read_u32(content, at).ok_or_else(|| invalid("truncated"))?;
+            let xor_offset = *content.get(at + 4).ok_or_else(|| invalid("truncated"))? as usize;
+            at += 6;
+            let mut bitmap = Bitmap::decode_ewah(content, &mut at, objects.len())
+                .ok_or_else(|| invalid("invalid commit bitmap"))?;
+            if xor_offset > 0 {
+                let base = decoded
+                    .len()
+                    .checked_sub(xor_offset)
+                    .map(|n| &decoded[n])
+                    .ok_or_else(|| invalid("XOR with a missing bitmap"))?;
+                bitmap.symmetric_difference(base);
+            }
+            let commit = index
+                .entries()
+                .get(position as usize)
+                .ok_or_else(|| invalid("commit out of the index"))?;
+            decoded.push(bitmap.clone());
+            bitmaps.insert(commit.hash, bitmap);
+        }
+        Ok(PackBitmap {
+            pack_hash,
+            objects,
+            types,
+            bitmaps,
+        })
+    }
+
+    /// Reads the `.bitmap` at `path` of the pack indexed by `index`.
+    pub fn read(path: &Path, index: &PackIndex) -> Result<Self, GitError> {
+        let data = fs::read(path)
+            .map_err(|e| GitError::InvalidBitmapFile(format!("{}: {}", path.display(), e)))?;
+        PackBitmap::from_bytes(&data, index)
+    }
+
+    /// Encodes the bitmaps, every one in full.
+    pub fn encode(&self) -> Vec<u8> {
+        // the commits are numbered by their position in the `.idx`
+        let mut sorted: Vec<SHA1> = self.objects.iter().map(|(hash, _)| *hash).collect();
+        sorted.sort();
+        let mut commits: Vec<(u32, &Bitmap)> = self
+            .bitmaps
+            .iter()
+            .map(|(id, bitmap)| (sorted.binary_search(id).unwrap() as u32, bitmap))
+            .collect();
+        commits.sort_by_key(|(position, _)| *position);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&BITMAP_MAGIC);
+        data.extend_from_slice(&BITMAP_VERSION.to_be_bytes());
+        data.extend_from_slice(&BITMAP_OPT_FULL_DAG.to_be_bytes());
+        data.extend_from_slice(&(commits.len() as u32).to_be_bytes());
+        data.extend_from_slice(&self.pack_hash.0);
+        for bitmap in &self.types {
+            bitmap.encode_ewah(&mut data);
+        }
+        for (position, bitmap) in commits {
+            data.extend_from_slice(&position.to_be_bytes());
+            // no XOR with a previous bitmap, no flags
+            data.extend_from_slice(&[0, 0]);
+            bitmap.encode_ewah(&mut data);
+        }
+        let checksum = Sha1::digest(&data);
+        data.extend_from_slice(&checksum);
+        data
+    }
+
+    /// The number of commits with a bitmap.
+    pub fn len(&self) -> usize {
+        self.bitmaps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bitmaps.is_empty()
+    }
+
+    pub fn contains(&self, commit: &SHA1) -> bool {
+        self.bitmaps.contains_key(commit)
+    }
+
+    /// The objects reachable from `wants` and not from `haves`. `None` when a want has no
+    /// bitmap, the objects are then counted by a walk of the history. The haves without one
+    /// are ignored, the client then receives some objects it already has.
+    pub fn reachable(&self, wants: &[SHA1], haves: &[SHA1]) -> Option<Bitmap> {
+        let mut result = Bitmap::default();
+        for want in wants {
+            result.union(self.bitmaps.get(want)?);
+        }
+        for have in haves {
+            if let Some(bitmap) = self.bitmaps.get(have) {
+                result.difference(bitmap);
+            }
+        }
+        Some(result)
+    }
+
+    /// The objects of the pack of type `obj_type`, to drop them from a bitmap with
+    /// [`Bitmap::difference`], e.g. the blobs of a partial clone.
+    pub fn objects_of_type(&self, obj_type: ObjectType) -> Option<&Bitmap> {
+        type_slot(obj_type).ok().map(|slot| &self.types[slot])
+    }
+
+    /// The ids and offsets of the objects of `bitmap`, in the order of the pack.
+    pub fn objects<'a>(&'a self, bitmap: &'a Bitmap) -> impl Iterator<Item = (SHA1, u64)> + 'a {
+        bitmap
+            .positions()
+            .map_while(|position| self.objects.get(position).copied())
+    }
+}
+
+/// Computes the bitmaps of the pack at `pack_path`, see [`PackBitmap::build`], and writes
+/// them next to its `.idx`. Returns the bitmaps written.
+pub fn write_bitmap(
+    pack_path: &Path,
+    tips: &[SHA1],
+    interval: usize,
+) -> Result<PackBitmap, GitError> {
+    let reader = PackReader::open(pack_path, &pack_path.with_extension("idx"))?;
+    let bitmap = PackBitmap::build(&reader, tips, interval)?;
+    let io_error = |path: &Path, e: std::io::Error| {
+        GitError::InvalidBitmapFile(format!("{}: {}", path.display(), e))
+    };
+    // replaced at once, a reader never sees a partial file
+    let path = pack_path.with_extension("bitmap");
+    let lock = pack_path.with_extension("bitmap.lock");
+    fs::write(&lock, bitmap.encode()).map_err(|e| io_error(&lock, e))?;
+    fs::rename(&lock, &path).map_err(|e| io_error(&path, e))?;
+    Ok(bitmap)
+}
+
+/// The objects of `index` in the order of the pack.
+fn pack_order(index: &PackIndex) -> Vec<(SHA1, u64)> {
+    let mut objects: Vec<(SHA1, u64)> = index
+        .entries()
+        .iter()
+        .map(|entry| (entry.hash, entry.offset))
+        .collect();
+    objects.sort_by_key(|(_, offset)| *offset);
+    objects
+}
+
+/// The type bitmaps are the ones of the commits, trees, blobs and tags, in this order.
+fn type_slot(obj_type: ObjectType) -> Result<usize, GitError> {
+    match obj_type {
+        ObjectType::Commit => Ok(0),
+        ObjectType::Tree => Ok(1),
+        ObjectType::Blob => Ok(2),
+        ObjectType::Tag => Ok(3),
+        other => Err(GitError::InvalidBitmapFile(format!(
+            "no bitmap of the {} objects",
+            other
+        ))),
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use sha1::{Digest, Sha1};
+
+    use venus::hash::SHA1;
+    use venus::internal::object::types::ObjectType;
+
+    use crate::internal::pack::index::PackIndex;
+    use crate::internal::pack::Pack;
+    use crate::test_support::{FixtureConfig, SyntheticRepo};
+
+    use super::{write_bitmap, Bitmap, PackBitmap};
+
+    fn bitmap(positions: impl IntoIterator<Item = usize>) -> Bitmap {
+        let mut bitmap = Bitmap::default();
+        for position in positions {
+            bitmap.set(position);
+        }
+        bitmap
+    }
+
+    #[test]
+    fn test_ewah_roundtrip() {
+        // one marker word without run and one literal word, then the last marker position
+        let mut data = Vec::new();
+        bitmap([0, 1, 2]).encode_ewah(&mut data);
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&3u32.to_be_bytes());
+        expected.extend_from_slice(&2u32.to_be_bytes());
+        expected.extend_from_slice(&(1u64 << 33).to_be_bytes());
+        expected.extend_from_slice(&7u64.to_be_bytes());
+        expected.extend_from_slice(&0u32.to_be_bytes());
+        assert_eq!(data, expected);
+
+        let cases = [
+            Bitmap::default(),
+            bitmap([5]),
+            bitmap(0..640),
+            bitmap((0..64 * 40).filter(|n| *n < 64 * 10 || *n % 7 == 0 || *n > 64 * 30)),
+            bitmap([1, 64 * 1000, 64 * 1000 + 3]),
+        ];
+        for case in cases {
+            let mut data = Vec::new();
+            case.encode_ewah(&mut data);
+            let mut at = 0;
+            let decoded = Bitmap::decode_ewah(&data, &mut at, 64 * 2000).unwrap();
+            assert_eq!(at, data.len());
+            assert_eq!(
+                decoded.positions().collect::<Vec<_>>(),
+                case.positions().collect::<Vec<_>>()
+            );
+            // more words than the pack has objects
+            assert!(
+                case.positions().all(|n| n < 64) || Bitmap::decode_ewah(&data, &mut 0, 4).is_none()
+            );
+        }
+        // runs are compressed
+        let mut data = Vec::new();
+        bitmap(0..64 * 1000).encode_ewah(&mut data);
+        assert_eq!(data.len(), 4 + 4 + 8 + 4);
+    }
+
+    #[test]
+    fn test_bitmaps_of_a_pack() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 5,
+            ..Default::default()
+        });
+        let dir = PathBuf::from("/tmp/.cache_temp").join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let (pack_path, index_path) = (dir.join("test.pack"), dir.join("test.idx"));
+        let pack = repo.to_pack();
+        fs::write(&pack_path, &pack).unwrap();
+        let mut p = Pack::new(None, Some(1024 * 1024 * 20), Some(dir.clone()));
+        p.decode_with_index(&mut Cursor::new(pack), &index_path, |_| {})
+            .unwrap();
+
+        let built = write_bitmap(&pack_path, &[], 3).unwrap();
+        let index = PackIndex::read(&index_path).unwrap();
+        let bitmaps = PackBitmap::read(&dir.join("test.bitmap"), &index).unwrap();
+        assert!(!dir.join("test.bitmap.lock").exists());
+        assert_eq!(bitmaps.bitmaps, built.bitmaps);
+        assert_eq!(bitmaps.types, built.types);
+
+        let commits: Vec<SHA1> = repo
+            .objects
+            .iter()
+            .filter(|object| object.obj_type == ObjectType::Commit)
+            .map(|object| object.hash)
+            .collect();
+        // the head and every third commit down from it
+        assert_eq!(bitmaps.len(), 4);
+        for n in [9, 7, 4, 1] {
+            assert!(bitmaps.contains(&commits[n]), "commit {}", n);
+        }
+        assert_eq!(
+            bitmaps.objects_of_type(ObjectType::Commit).unwrap().len(),
+            commits.len()
+        );
+
+        // a clone has every object, a fetch the ones added after the commit it has
+        let all = bitmaps.reachable(&[repo.head], &[]).unwrap();
+        assert_eq!(all.len(), repo.objects.len());
+        let fetched = bitmaps.reachable(&[repo.head], &[commits[4]]).unwrap();
+        let after = repo
+            .objects
+            .iter()
+            .position(|object| object.hash == commits[4])
+            .unwrap();
+        let expected: HashSet<SHA1> = repo.objects[after + 1..]
+            .iter()
+            .map(|object| object.hash)
+            .collect();
+        let hashes: HashSet<SHA1> = bitmaps.objects(&fetched).map(|(hash, _)| hash).collect();
+        assert_eq!(hashes, expected);
+        // the haves without a bitmap are ignored, the wants are not
+        assert_eq!(bitmaps.reachable(&[repo.head], &[commits[5]]), Some(all));
+        assert!(bitmaps.reachable(&[commits[5]], &[]).is_none());
+
+        // the bitmap of another pack is refused
+        let mut other = bitmaps.encode();
+        other.truncate(other.len() - 20);
+        other[12] ^= 1;
+        let checksum = Sha1::digest(&other);
+        other.extend_from_slice(&checksum);
+        assert!(PackBitmap::from_bytes(&other, &index).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
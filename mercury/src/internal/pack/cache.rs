@@ -13,28 +13,53 @@ use std::thread::sleep;
 use std::{fs, io};
 
 use crate::internal::pack::cache_object::{ArcWrapper, CacheObject, MemSizeRecorder};
+use crate::internal::pack::scheduler::Scheduler;
 use crate::time_it;
+use common::fs_utils;
 use dashmap::{DashMap, DashSet};
-use lru_mem::LruCache;
-use threadpool::ThreadPool;
+use lru_mem::{LruCache, MemSize};
 use venus::hash::SHA1;
 
 use super::cache_object::FileLoadStore;
 
 
-pub trait _Cache {
-    fn new(mem_size: Option<usize>, tmp_path: PathBuf, thread_num: usize) -> Self
-    where
-        Self: Sized;
+/// Where [`Pack`](super::Pack) keeps the decoded objects, the bases of the deltas to come.
+/// [`Caches`] spills them to temporary files above its memory limit, [`MemoryCache`] keeps
+/// them all in memory. Another backend, e.g. a key-value store, is passed to
+/// [`Pack::new_with_cache`](super::Pack::new_with_cache).
+///
+/// An object is looked up by its offset in the pack or by its hash, from many threads at once.
+/// Memory of the objects alive is recorded by the objects themselves, see
+/// [`MemSizeRecorder`]: a backend above the memory limit of the pack has to let some go.
+pub trait CacheStorage: Send + Sync {
     fn get_hash(&self, offset: usize) -> Option<SHA1>;
     fn insert(&self, offset: usize, hash: SHA1, obj: CacheObject) -> Arc<CacheObject>;
     fn get_by_offset(&self, offset: usize) -> Option<Arc<CacheObject>>;
     fn get_by_hash(&self, h: SHA1) -> Option<Arc<CacheObject>>;
     fn total_inserted(&self) -> usize;
+    /// Memory used by the backend, the objects it holds included.
     fn memory_used(&self) -> usize;
+    /// Memory used by the backend besides the objects.
+    fn memory_used_index(&self) -> usize;
+    /// Tasks of the backend not started yet, e.g. writes to disk.
+    fn queued_tasks(&self) -> usize {
+        0
+    }
+    /// Bytes written to disk, called once the pack is decoded, before [`CacheStorage::clear`].
+    fn spilled_bytes(&self) -> u64 {
+        0
+    }
+    /// Drops every object, called once the pack is decoded or the decode stopped.
     fn clear(&self);
 }
 
+/// Bytes allocated by the hash tables of a [`DashMap`] of `T` entries holding `capacity` of
+/// them: one in eight buckets is kept free, each with a control byte besides the entry.
+pub(crate) fn table_size<T>(capacity: usize) -> usize {
+    let buckets = capacity * 8 / 7;
+    buckets * (std::mem::size_of::<T>() + 1)
+}
+
 pub struct Caches {
     map_offset: DashMap<usize, SHA1>, // offset to hash
     hash_set: DashSet<SHA1>,          // item in the cache
@@ -45,11 +70,46 @@ pub struct Caches {
     lru_cache: Mutex<LruCache<String, ArcWrapper<CacheObject>>>, // *lru_cache require the key to implement lru::MemSize trait, so didn't use SHA1 as the key*
     mem_size: Option<usize>,
     tmp_path: PathBuf,
-    pool: Arc<ThreadPool>,
+    compression_level: Option<i32>, // zstd level of the spilled objects, None writes them raw
+    pool: Arc<Scheduler>, // writes the objects evicted to the tmp files
+    writing: Arc<DashMap<PathBuf, Arc<CacheObject>>>, // the objects evicted until they are written
     complete_signal: Arc<AtomicBool>,
 }
 
 impl Caches {
+    /// @param size: the size of the memory lru cache. **None means no limit**
+    /// @param tmp_path: the path to store the cache object in the tmp file
+    pub fn new(mem_size: Option<usize>, tmp_path: PathBuf, thread_num: usize) -> Self {
+        Self::new_with_scheduler(mem_size, tmp_path, Arc::new(Scheduler::new(thread_num)))
+    }
+
+    /// Like [`Caches::new`], the objects are written on the workers of `pool`, e.g. those of
+    /// the [`Pack`](super::Pack) rebuilding the deltas.
+    pub fn new_with_scheduler(mem_size: Option<usize>, tmp_path: PathBuf, pool: Arc<Scheduler>) -> Self {
+        fs::create_dir_all(&tmp_path).unwrap();
+
+        Caches {
+            map_offset: DashMap::new(),
+            hash_set: DashSet::new(),
+            lru_cache: Mutex::new(LruCache::new(mem_size.unwrap_or(usize::MAX))),
+            mem_size,
+            tmp_path,
+            compression_level: None,
+            pool,
+            writing: Arc::new(DashMap::new()),
+            complete_signal: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Compresses the objects spilled to the tmp files with zstd at `level`, clamped to the
+    /// levels zstd supports: deltified packs mostly hold text, which shrinks several times, at
+    /// the cost of CPU on every spill and reload.
+    pub fn with_compression(mut self, level: i32) -> Self {
+        let range = zstd::compression_level_range();
+        self.compression_level = Some(level.clamp(*range.start(), *range.end()));
+        self
+    }
+
     /// only get object from memory, not from tmp file
     fn try_get(&self, hash: SHA1) -> Option<Arc<CacheObject>> {
         let mut map = self.lru_cache.lock().unwrap();
@@ -59,13 +119,19 @@ impl Caches {
     /// !IMPORTANT: because of the process of pack, the file must be written / be writing before, so it won't be dead lock
     /// fall back to temp to get item. **invoker should ensure the hash is in the cache, or it will block forever**
     fn get_fallback(&self, hash: SHA1) -> io::Result<Arc<CacheObject>> {
-        // read from tmp file
+        let path = Caches::generate_temp_path(&self.tmp_path, hash);
         let obj = {
             loop {
+                // its write is still queued, maybe behind the job asking for it on the same worker
+                if let Some(obj) = self.writing.get(&path) {
+                    break obj.clone();
+                }
+                // read from tmp file
                 match self.read_from_temp(hash) {
-                    Ok(x) => break x,
-                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                        sleep(std::time::Duration::from_millis(10)); //TODO 有没有更好办法
+                    Ok(x) => break Arc::new(x),
+                    // being replaced on Windows
+                    Err(e) if e.kind() == io::ErrorKind::NotFound || fs_utils::is_transient(&e) => {
+                        sleep(std::time::Duration::from_millis(10));
                         continue;
                     }
                     Err(e) => return Err(e), // other error
@@ -74,17 +140,19 @@ impl Caches {
         };
 
         let mut map = self.lru_cache.lock().unwrap();
-        let obj = Arc::new(obj);
-        let mut x = ArcWrapper::new(
-            obj.clone(),
-            self.complete_signal.clone(),
-            Some(self.pool.clone()),
-        );
-        x.set_store_path(Caches::generate_temp_path(&self.tmp_path, hash));
-        let _ = map.insert(hash.to_plain_str(), x); // handle the error
+        let _ = map.insert(hash.to_plain_str(), self.wrap(obj.clone(), hash)); // handle the error
         Ok(obj)
     }
 
+    /// The entry of `obj` in the lru cache, written to its tmp file when evicted.
+    fn wrap(&self, obj: Arc<CacheObject>, hash: SHA1) -> ArcWrapper<CacheObject> {
+        let mut x = ArcWrapper::new(obj, self.complete_signal.clone(), Some(self.pool.clone()));
+        x.set_store_path(Caches::generate_temp_path(&self.tmp_path, hash));
+        x.set_compression_level(self.compression_level);
+        x.set_writing(self.writing.clone());
+        x
+    }
+
     /// generate the temp file path, hex string of the hash
     fn generate_temp_path(tmp_path: &Path, hash: SHA1) -> PathBuf {
         let mut path = tmp_path.to_path_buf();
@@ -94,44 +162,18 @@ impl Caches {
 
     fn read_from_temp(&self, hash: SHA1) -> io::Result<CacheObject> {
         let path = Self::generate_temp_path(&self.tmp_path, hash);
-        let obj = CacheObject::f_load(&path)?;
+        let obj = match self.compression_level {
+            Some(_) => CacheObject::f_load_zstd(&path)?,
+            None => CacheObject::f_load(&path)?,
+        };
         // Deserializing will also create an object but without Construction outside and `::new()`
         // So if you want to do sth. while Constructing, impl Deserialize trait yourself
         obj.record_mem_size();
         Ok(obj)
     }
-
-    pub fn queued_tasks(&self) -> usize {
-        self.pool.queued_count()
-    }
-
-    /// memory used by the index (exclude lru_cache which is contained in [CacheObject::get_mem_size()])
-    pub fn memory_used_index(&self) -> usize {
-        self.map_offset.capacity() * (std::mem::size_of::<usize>() + std::mem::size_of::<SHA1>())
-        + self.hash_set.capacity() * (std::mem::size_of::<SHA1>())
-    }
 }
 
-impl _Cache for Caches {
-    /// @param size: the size of the memory lru cache. **None means no limit**
-    /// @param tmp_path: the path to store the cache object in the tmp file
-    fn new(mem_size: Option<usize>, tmp_path: PathBuf, thread_num: usize) -> Self
-    where
-        Self: Sized,
-    {
-        fs::create_dir_all(&tmp_path).unwrap();
-
-        Caches {
-            map_offset: DashMap::new(),
-            hash_set: DashSet::new(),
-            lru_cache: Mutex::new(LruCache::new(mem_size.unwrap_or(usize::MAX))),
-            mem_size,
-            tmp_path,
-            pool: Arc::new(ThreadPool::new(thread_num)),
-            complete_signal: Arc::new(AtomicBool::new(false)),
-        }
-    }
-
+impl CacheStorage for Caches {
     fn get_hash(&self, offset: usize) -> Option<SHA1> {
         self.map_offset.get(&offset).map(|x| *x)
     }
@@ -141,13 +183,7 @@ impl _Cache for Caches {
         {
             // ? whether insert to cache directly or only write to tmp file
             let mut map = self.lru_cache.lock().unwrap();
-            let mut a_obj = ArcWrapper::new(
-                obj_arc.clone(),
-                self.complete_signal.clone(),
-                Some(self.pool.clone()),
-            );
-            a_obj.set_store_path(Caches::generate_temp_path(&self.tmp_path, hash));
-            let _ = map.insert(hash.to_plain_str(), a_obj);
+            let _ = map.insert(hash.to_plain_str(), self.wrap(obj_arc.clone(), hash));
         }
         //order maters as for reading in 'get_by_offset()'
         self.hash_set.insert(hash);
@@ -187,29 +223,98 @@ impl _Cache for Caches {
         self.hash_set.len()
     }
     fn memory_used(&self) -> usize {
-        self.lru_cache.lock().unwrap().current_size()
-        + self.map_offset.capacity() * (std::mem::size_of::<usize>() + std::mem::size_of::<SHA1>())
-        + self.hash_set.capacity() * (std::mem::size_of::<SHA1>())
+        self.lru_cache.lock().unwrap().current_size() + self.memory_used_index()
+    }
+    /// memory used by the index (exclude lru_cache which is contained in [CacheObject::get_mem_size()])
+    /// and by the paths of the objects being written, themselves counted until they are written
+    fn memory_used_index(&self) -> usize {
+        let path_len = self.tmp_path.as_os_str().len() + 41; // a separator and the hash
+        table_size::<(usize, SHA1)>(self.map_offset.capacity())
+            + table_size::<(SHA1, ())>(self.hash_set.capacity())
+            + table_size::<(PathBuf, Arc<CacheObject>)>(self.writing.capacity())
+            + self.writing.len() * path_len
+    }
+    fn queued_tasks(&self) -> usize {
+        self.pool.pending()
+    }
+    /// Size of the tmp files, once the writes queued are done.
+    fn spilled_bytes(&self) -> u64 {
+        self.pool.join();
+        fs::read_dir(&self.tmp_path)
+            .map(|dir| {
+                dir.filter_map(|entry| entry.and_then(|e| e.metadata()).ok())
+                    .map(|metadata| metadata.len())
+                    .sum()
+            })
+            .unwrap_or(0)
     }
     fn clear(&self) {
         time_it!("Caches clear", {
             self.complete_signal.store(true, Ordering::SeqCst);
             self.pool.join();
             self.lru_cache.lock().unwrap().clear();
+            self.writing.clear();
             self.hash_set.clear();
             self.map_offset.clear();
         });
 
         time_it!("Remove tmp dir", {
-            fs::remove_dir_all(&self.tmp_path).unwrap(); //very slow
+            fs_utils::remove_dir_all(&self.tmp_path).unwrap(); //very slow
         });
 
-        assert_eq!(self.pool.queued_count(), 0);
-        assert_eq!(self.pool.active_count(), 0);
+        assert_eq!(self.pool.pending(), 0);
         assert_eq!(self.lru_cache.lock().unwrap().len(), 0);
     }
 }
 
+/// Keeps every object in memory, nothing is written to disk: for packs known to fit in memory,
+/// with no memory limit on the [`Pack`](super::Pack).
+#[derive(Default)]
+pub struct MemoryCache {
+    map_offset: DashMap<usize, SHA1>,
+    objects: DashMap<SHA1, Arc<CacheObject>>,
+}
+
+impl CacheStorage for MemoryCache {
+    fn get_hash(&self, offset: usize) -> Option<SHA1> {
+        self.map_offset.get(&offset).map(|x| *x)
+    }
+
+    fn insert(&self, offset: usize, hash: SHA1, obj: CacheObject) -> Arc<CacheObject> {
+        let obj = Arc::new(obj);
+        // the object first, a reader finding the offset finds the object
+        self.objects.insert(hash, obj.clone());
+        self.map_offset.insert(offset, hash);
+        obj
+    }
+
+    fn get_by_offset(&self, offset: usize) -> Option<Arc<CacheObject>> {
+        self.get_hash(offset).and_then(|hash| self.get_by_hash(hash))
+    }
+
+    fn get_by_hash(&self, h: SHA1) -> Option<Arc<CacheObject>> {
+        self.objects.get(&h).map(|x| x.clone())
+    }
+
+    fn total_inserted(&self) -> usize {
+        self.objects.len()
+    }
+
+    fn memory_used(&self) -> usize {
+        self.objects.iter().map(|x| x.value().as_ref().mem_size()).sum::<usize>() + self.memory_used_index()
+    }
+
+    fn memory_used_index(&self) -> usize {
+        table_size::<(usize, SHA1)>(self.map_offset.capacity())
+            + table_size::<(SHA1, Arc<CacheObject>)>(self.objects.capacity())
+    }
+
+    fn clear(&self) {
+        self.objects.clear();
+        self.map_offset.clear();
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::env;
@@ -262,4 +367,59 @@ mod test {
         assert!(cache.try_get(c.hash).is_none());
         assert!(cache.get_by_hash(c.hash).is_some());
     }
+
+    #[test]
+    fn test_cache_compression() {
+        let source = PathBuf::from(env::current_dir().unwrap().parent().unwrap());
+        let tmp = source.join("tests/.cache_tmp_zstd");
+        let cache = Caches::new(Some(2048), tmp.clone(), 1).with_compression(3);
+        let a = CacheObject {
+            data_decompress: b"fn main() {}\n".repeat(100),
+            offset: 12,
+            hash: SHA1::new(&String::from("a").into_bytes()),
+            mem_recorder: None,
+            ..Default::default()
+        };
+        let b = CacheObject {
+            data_decompress: vec![1; 1600],
+            hash: SHA1::new(&String::from("b").into_bytes()),
+            mem_recorder: None,
+            ..Default::default()
+        };
+        cache.insert(a.offset, a.hash, a.clone());
+        // spills a
+        cache.insert(b.offset, b.hash, b.clone());
+        cache.pool.join();
+        let spilled = fs::metadata(Caches::generate_temp_path(&tmp, a.hash)).unwrap();
+        assert!((spilled.len() as usize) < a.data_decompress.len() / 4);
+
+        // read back transparently
+        assert!(cache.try_get(a.hash).is_none());
+        let loaded = cache.get_by_offset(12).unwrap();
+        assert_eq!(loaded.data_decompress, a.data_decompress);
+        cache.clear();
+    }
+
+    #[test]
+    fn test_memory_cache() {
+        let cache = MemoryCache::default();
+        let a = CacheObject {
+            data_decompress: vec![0; 1024],
+            offset: 12,
+            hash: SHA1::new(&String::from("a").into_bytes()),
+            mem_recorder: None,
+            ..Default::default()
+        };
+        cache.insert(a.offset, a.hash, a.clone());
+        assert_eq!(cache.get_hash(12), Some(a.hash));
+        assert_eq!(cache.get_by_offset(12).unwrap().data_decompress, a.data_decompress);
+        assert!(cache.get_by_hash(a.hash).is_some());
+        assert!(cache.get_by_offset(13).is_none());
+        assert_eq!(cache.total_inserted(), 1);
+        assert!(cache.memory_used() > 1024);
+
+        cache.clear();
+        assert!(cache.get_by_hash(a.hash).is_none());
+        assert_eq!(cache.total_inserted(), 0);
+    }
 }
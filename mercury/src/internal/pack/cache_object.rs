@@ -1,14 +1,14 @@
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::{fs, io};
 use std::{ops::Deref, sync::Arc};
 
+use crate::internal::pack::scheduler::Scheduler;
 use crate::internal::pack::utils;
+use common::fs_utils;
+use dashmap::DashMap;
 use lru_mem::{HeapSize, MemSize};
 use serde::{Deserialize, Serialize};
-use threadpool::ThreadPool;
 use venus::{hash::SHA1, internal::object::types::ObjectType};
 
 /// record heap-size of all CacheObjects, used for memory limit.
@@ -18,11 +18,15 @@ use venus::{hash::SHA1, internal::object::types::ObjectType};
 pub trait FileLoadStore: Serialize + for<'a> Deserialize<'a> {
     fn f_load(path: &Path) -> Result<Self, io::Error>;
     fn f_save(&self, path: &Path) -> Result<(), io::Error>;
+    /// Like `f_load`, for a file written by `f_save_zstd`.
+    fn f_load_zstd(path: &Path) -> Result<Self, io::Error>;
+    /// Like `f_save`, the file is compressed with zstd at `level`.
+    fn f_save_zstd(&self, path: &Path, level: i32) -> Result<(), io::Error>;
 }
 // trait alias, so that impl FileLoadStore == impl Serialize + Deserialize
 impl<T: Serialize + for<'a> Deserialize<'a>> FileLoadStore for T {
     fn f_load(path: &Path) -> Result<T, io::Error> {
-        let data = fs::read(path)?;
+        let data = fs_utils::read(path)?;
         let obj: T =
             bincode::deserialize(&data).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
         Ok(obj)
@@ -32,17 +36,19 @@ impl<T: Serialize + for<'a> Deserialize<'a>> FileLoadStore for T {
             return Ok(());
         }
         let data = bincode::serialize(&self).unwrap();
-        let path = path.with_extension("temp");
-        {
-            let mut file = OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(path.clone())?;
-            file.write_all(&data)?;
+        // the same object can be saved by several threads, and read meanwhile
+        fs_utils::write_atomic(path, &data)
+    }
+    fn f_load_zstd(path: &Path) -> Result<T, io::Error> {
+        let data = zstd::decode_all(fs_utils::read(path)?.as_slice())?;
+        bincode::deserialize(&data).map_err(io::Error::other)
+    }
+    fn f_save_zstd(&self, path: &Path, level: i32) -> Result<(), io::Error> {
+        if path.exists() {
+            return Ok(());
         }
-        let final_path = path.with_extension("");
-        fs::rename(&path, final_path.clone())?;
-        Ok(())
+        let data = bincode::serialize(&self).unwrap();
+        fs_utils::write_atomic(path, &zstd::encode_all(data.as_slice(), level)?)
     }
 }
 #[derive(Debug, Serialize, Deserialize)]
@@ -53,6 +59,10 @@ pub struct CacheObject {
     pub data_decompress: Vec<u8>,
     pub offset: usize,
     pub hash: SHA1,
+    pub delta_depth: usize, // deltas rebuilt to get the object, 0 for an undeltified one
+    /// CRC32 of the object as packed: its header, base and compressed data. A delta rebuilt
+    /// keeps the one of the delta, which is what the pack holds at its offset.
+    pub crc32: u32,
     pub mem_recorder: Option<Arc<AtomicUsize>> // record mem-size of all CacheObjects of a Pack
 }
 
@@ -65,6 +75,8 @@ impl Clone for CacheObject {
             data_decompress: self.data_decompress.clone(),
             offset: self.offset,
             hash: self.hash,
+            delta_depth: self.delta_depth,
+            crc32: self.crc32,
             mem_recorder: self.mem_recorder.clone(),
         };
         obj.record_mem_size();
@@ -84,6 +96,8 @@ impl Default for CacheObject {
             obj_type: ObjectType::Blob,
             offset: 0,
             hash: SHA1::default(),
+            delta_depth: 0,
+            crc32: 0,
             mem_recorder: None,
         };
         obj.record_mem_size();
@@ -91,12 +105,22 @@ impl Default for CacheObject {
     }
 }
 
+/// Bytes the allocator holds for the buffer of `data`: its capacity rounded up to the size
+/// class of the allocation, which `Vec::heap_size` misses, e.g. 1153 bytes take 1280.
+pub fn allocated_size(data: &Vec<u8>) -> usize {
+    if data.capacity() == 0 {
+        return 0;
+    }
+    // SAFETY: a buffer with a capacity was allocated by the global allocator, `GLOBAL`
+    unsafe { crate::GLOBAL.usable_size(data.as_ptr()) }
+}
+
 // ! used by lru_mem to calculate the size of the object, limit the memory usage.
-// ! the implementation of HeapSize is not accurate, only calculate the size of the data_decompress
+// ! only the data_decompress is on the heap, counted as the allocator holds it
 // Note that: mem_size == value_size + heap_size, and we only need to impl HeapSize because value_size is known
 impl HeapSize for CacheObject {
     fn heap_size(&self) -> usize {
-        self.data_decompress.heap_size()
+        allocated_size(&self.data_decompress)
     }
 }
 
@@ -164,6 +188,7 @@ impl CacheObject {
                     obj_type: self.obj_type,
                     data: self.data_decompress.clone(),
                     hash: self.hash,
+                    crc32: Some(self.crc32),
                 }
             }
             _ => {
@@ -179,28 +204,55 @@ pub trait ArcWrapperBounds: HeapSize + Serialize + for<'a> Deserialize<'a> + Sen
 // Or, `T` will not satisfy `Alias Trait` even if it satisfies the Original traits
 impl<T: HeapSize + Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static> ArcWrapperBounds for T {}
 
+/// Jobs pending on the pool above which an evicted object is written by the evicting thread,
+/// which bounds the memory held by the writes queued.
+const MAX_PENDING_WRITES: usize = 2000;
+
 /// !Implementing encapsulation of Arc<T> to enable third-party Trait HeapSize implementation for the Arc type
 /// !Because of use Arc<T> in LruCache, the LruCache is not clear whether a pointer will drop the referenced
 /// ! content when it is ejected from the cache, the actual memory usage is not accurate
 pub struct ArcWrapper<T: ArcWrapperBounds> {
     pub data: Arc<T>,
     complete_signal: Arc<AtomicBool>,
-    pool: Option<Arc<ThreadPool>>,
+    pool: Option<Arc<Scheduler>>,
     pub store_path: Option<PathBuf>, // path to store when drop
+    compression_level: Option<i32>, // zstd level of the stored file, None stores it raw
+    writing: Option<Arc<DashMap<PathBuf, Arc<T>>>>, // the data by path while its write is queued on `pool`
 }
 impl<T: ArcWrapperBounds> ArcWrapper<T> {
     /// Create a new ArcWrapper
-    pub fn new(data: Arc<T>, share_flag: Arc<AtomicBool>, pool: Option<Arc<ThreadPool>>) -> Self {
+    pub fn new(data: Arc<T>, share_flag: Arc<AtomicBool>, pool: Option<Arc<Scheduler>>) -> Self {
         ArcWrapper {
             data,
             complete_signal: share_flag,
             pool,
             store_path: None,
+            compression_level: None,
+            writing: None,
         }
     }
     pub fn set_store_path(&mut self, path: PathBuf) {
         self.store_path = Some(path);
     }
+    /// Compresses the stored file with zstd at `level`, see [`FileLoadStore::f_save_zstd`].
+    pub fn set_compression_level(&mut self, level: Option<i32>) {
+        self.compression_level = level;
+    }
+    /// Keeps the data in `writing` until it is stored, to be read from there meanwhile instead
+    /// of waiting for the file.
+    pub fn set_writing(&mut self, writing: Arc<DashMap<PathBuf, Arc<T>>>) {
+        self.writing = Some(writing);
+    }
+
+    fn save(data: &T, path: &Path, level: Option<i32>) {
+        let res = match level {
+            Some(level) => data.f_save_zstd(path, level),
+            None => data.f_save(path),
+        };
+        if let Err(e) = res {
+            println!("[f_save] {:?} error: {:?}", path, e);
+        }
+    }
 }
 
 impl<T: ArcWrapperBounds> HeapSize for ArcWrapper<T> {
@@ -217,6 +269,8 @@ impl<T: ArcWrapperBounds> Clone for ArcWrapper<T> {
             complete_signal: self.complete_signal.clone(),
             pool: self.pool.clone(),
             store_path: None,
+            compression_level: None,
+            writing: None,
         }
     }
 }
@@ -234,30 +288,27 @@ impl<T: ArcWrapperBounds> Drop for ArcWrapper<T> {
         if !self.complete_signal.load(Ordering::SeqCst) {
             if let Some(path) = &self.store_path {
                 match &self.pool {
-                    Some(pool) => {
+                    // too many writes behind, the thread evicting the object writes it itself,
+                    // which holds back whatever it is doing, e.g. rebuilding more deltas
+                    Some(pool) if pool.pending() <= MAX_PENDING_WRITES => {
                         let data_copy = self.data.clone();
                         let path_copy = path.clone();
+                        let level = self.compression_level;
                         let complete_signal = self.complete_signal.clone();
-                        // block entire process, wait for IO, Control Memory
-                        // queue size will influence the Memory usage
-                        while pool.queued_count() > 2000 {
-                            std::thread::yield_now();
+                        let writing = self.writing.clone();
+                        if let Some(writing) = &writing {
+                            writing.insert(path_copy.clone(), data_copy.clone());
                         }
                         pool.execute(move || {
                             if !complete_signal.load(Ordering::SeqCst) {
-                                let res = data_copy.f_save(&path_copy);
-                                if let Err(e) = res {
-                                    println!("[f_save] {:?} error: {:?}", path_copy, e);
-                                }
+                                Self::save(&data_copy, &path_copy, level);
+                            }
+                            if let Some(writing) = writing {
+                                writing.remove(&path_copy);
                             }
                         });
                     }
-                    None => {
-                        let res = self.data.f_save(path);
-                        if let Err(e) = res {
-                            println!("[f_save] {:?} error: {:?}", path, e);
-                        }
-                    }
+                    _ => Self::save(&self.data, path, self.compression_level),
                 }
             }
         }
@@ -297,6 +348,8 @@ mod test {
             obj_type: ObjectType::Blob,
             offset: 0,
             hash: SHA1::new(&vec![0; 20]),
+            delta_depth: 0,
+            crc32: 0,
             mem_recorder: None,
         };
         assert!(a.heap_size() == 1024);
@@ -305,6 +358,21 @@ mod test {
         let b = ArcWrapper::new(Arc::new(a.clone()), Arc::new(AtomicBool::new(false)), None);
         assert!(b.heap_size() == 1024);
     }
+    #[test]
+    fn test_heap_size_of_the_allocation() {
+        let a = CacheObject {
+            data_decompress: vec![0; 1153],
+            mem_recorder: None,
+            ..Default::default()
+        };
+        // rounded up to the size class of the allocator
+        assert_eq!(a.heap_size(), 1280);
+        let mut data = Vec::with_capacity(100_000);
+        data.push(1);
+        assert!(allocated_size(&data) >= data.capacity());
+        assert_eq!(allocated_size(&Vec::new()), 0);
+    }
+
     #[test]
     fn test_chache_object_with_lru() {
         let mut cache = LruCache::new(2048);
@@ -315,6 +383,8 @@ mod test {
             obj_type: ObjectType::Blob,
             offset: 0,
             hash: SHA1::new(&vec![0; 20]),
+            delta_depth: 0,
+            crc32: 0,
             mem_recorder: None,
         };
         println!("a.heap_size() = {}", a.heap_size());
@@ -326,6 +396,8 @@ mod test {
             obj_type: ObjectType::Blob,
             offset: 0,
             hash: SHA1::new(&vec![1; 20]),
+            delta_depth: 0,
+            crc32: 0,
             mem_recorder: None,
         };
         {
@@ -430,6 +502,8 @@ mod test {
             obj_type: ObjectType::Blob,
             offset: 0,
             hash: SHA1::new(&vec![0; 20]),
+            delta_depth: 0,
+            crc32: 0,
             mem_recorder: None,
         };
         let s = bincode::serialize(&a).unwrap();
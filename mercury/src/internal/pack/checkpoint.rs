@@ -0,0 +1,190 @@
+//!
+//! Checkpoints of a decode, to resume it after a crash instead of reading the pack again from
+//! its first object, see [`Pack::with_checkpoint`](super::Pack::with_checkpoint) and
+//! [`Pack::resume`](super::Pack::resume).
+//!
+//! A checkpoint directory holds:
+//! - `objects/<hash>`: every object rebuilt, written once, as the cache writes its tmp files;
+//! - `resolved`: the offset and the hash of these objects, in the order they were written;
+//! - `checkpoint`: the [`DecodeCheckpoint`], replaced at once, it tells how many records of
+//!   `resolved` are complete, those written after it are ignored.
+//!
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use common::fs_utils;
+use venus::errors::GitError;
+use venus::hash::SHA1;
+
+use crate::internal::pack::cache::CacheStorage;
+use crate::internal::pack::cache_object::{CacheObject, FileLoadStore};
+
+const STATE_FILE: &str = "checkpoint";
+const RESOLVED_FILE: &str = "resolved";
+const OBJECTS_DIR: &str = "objects";
+/// The offset in big endian then the hash, per object of `resolved`.
+const RECORD_LEN: usize = 8 + 20;
+
+/// Where a decode stopped: the objects before `offset` are read, every one of them either
+/// rebuilt and recorded in the directory of the checkpoint, or a delta in `waiting`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DecodeCheckpoint {
+    /// Objects of the pack, from its header.
+    pub number: usize,
+    /// Offset of the next object to read.
+    pub offset: usize,
+    /// Rank of the next object to read, from 1.
+    pub next: usize,
+    /// Hash of the bytes of the pack before `offset`, the pack resumed has to start with them.
+    pub prefix_hash: SHA1,
+    /// Complete records of the `resolved` file.
+    pub resolved: usize,
+    /// The deltas waiting for their base.
+    pub waiting: Vec<CacheObject>,
+    /// Objects read between two checkpoints.
+    pub interval: usize,
+    /// Figures of the decode so far, see [`DecodeStats`](super::decode::DecodeStats).
+    pub offset_deltas: usize,
+    pub hash_deltas: usize,
+    pub commits: usize,
+    pub trees: usize,
+    pub blobs: usize,
+    pub tags: usize,
+    pub max_delta_depth: usize,
+}
+
+impl DecodeCheckpoint {
+    /// Reads the last checkpoint saved in `dir`.
+    pub fn load(dir: &Path) -> Result<Self, GitError> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            GitError::InvalidPackFile(format!("Checkpoint {}: {}", dir.display(), e))
+        };
+        let data = fs_utils::read(&dir.join(STATE_FILE)).map_err(|e| invalid(&e))?;
+        bincode::deserialize(&data).map_err(|e| invalid(&e))
+    }
+}
+
+/// Saves the checkpoints of a decode in a directory.
+pub(crate) struct Checkpointer {
+    dir: PathBuf,
+    interval: usize,
+    /// Objects read when the decode started or resumed, no checkpoint is due until more are.
+    since: usize,
+    log: File,
+    resolved: usize,
+    /// Offsets of the objects read not rebuilt at the last checkpoint.
+    pending: Vec<usize>,
+}
+
+impl Checkpointer {
+    /// Starts the checkpoints of a decode in `dir`, a checkpoint there is removed.
+    pub(crate) fn create(dir: &Path, interval: usize) -> io::Result<Self> {
+        if dir.exists() {
+            fs_utils::remove_dir_all(dir)?;
+        }
+        fs::create_dir_all(dir.join(OBJECTS_DIR))?;
+        let log = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(dir.join(RESOLVED_FILE))?;
+        Ok(Checkpointer {
+            dir: dir.to_path_buf(),
+            interval: interval.max(1),
+            since: 0,
+            log,
+            resolved: 0,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Continues the checkpoints of `dir` after `state`, the records written after it dropped.
+    pub(crate) fn open(dir: &Path, state: &DecodeCheckpoint) -> io::Result<Self> {
+        let mut log = OpenOptions::new()
+            .write(true)
+            .open(dir.join(RESOLVED_FILE))?;
+        log.set_len((state.resolved * RECORD_LEN) as u64)?;
+        log.seek(SeekFrom::End(0))?;
+        Ok(Checkpointer {
+            dir: dir.to_path_buf(),
+            interval: state.interval.max(1),
+            since: state.next - 1,
+            log,
+            resolved: state.resolved,
+            pending: state.waiting.iter().map(|obj| obj.offset).collect(),
+        })
+    }
+
+    /// Records the object read at `offset`.
+    pub(crate) fn read(&mut self, offset: usize) {
+        self.pending.push(offset);
+    }
+
+    /// Whether a checkpoint is due once `read` objects are read.
+    pub(crate) fn due(&self, read: usize) -> bool {
+        read > self.since && read.is_multiple_of(self.interval)
+    }
+
+    /// Writes the objects rebuilt since the last checkpoint, then `state`. Nothing may be
+    /// processing meanwhile, every object read is either in `caches` or in `state.waiting`.
+    pub(crate) fn save(
+        &mut self,
+        caches: &dyn CacheStorage,
+        mut state: DecodeCheckpoint,
+    ) -> io::Result<()> {
+        let mut records = Vec::new();
+        let mut pending = Vec::new();
+        for offset in std::mem::take(&mut self.pending) {
+            let Some(hash) = caches.get_hash(offset) else {
+                pending.push(offset);
+                continue;
+            };
+            let obj = caches
+                .get_by_hash(hash)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, hash.to_plain_str()))?;
+            obj.as_ref().f_save(&self.object_path(hash))?;
+            records.extend((offset as u64).to_be_bytes());
+            records.extend(hash.0);
+        }
+        self.pending = pending;
+        self.log.write_all(&records)?;
+        self.log.sync_data()?;
+        self.resolved += records.len() / RECORD_LEN;
+
+        state.resolved = self.resolved;
+        state.interval = self.interval;
+        let data = bincode::serialize(&state).map_err(io::Error::other)?;
+        fs_utils::write_atomic(&self.dir.join(STATE_FILE), &data)
+    }
+
+    /// The offset and the hash of the objects rebuilt before `state`.
+    pub(crate) fn resolved(dir: &Path, state: &DecodeCheckpoint) -> io::Result<Vec<(usize, SHA1)>> {
+        let mut data = vec![0; state.resolved * RECORD_LEN];
+        File::open(dir.join(RESOLVED_FILE))?.read_exact(&mut data)?;
+        Ok(data
+            .chunks_exact(RECORD_LEN)
+            .map(|record| {
+                let offset = u64::from_be_bytes(record[..8].try_into().unwrap());
+                (offset as usize, SHA1::from_bytes(&record[8..]))
+            })
+            .collect())
+    }
+
+    /// Reads the object `hash` written in `dir`.
+    pub(crate) fn load_object(dir: &Path, hash: SHA1) -> io::Result<CacheObject> {
+        CacheObject::f_load(&dir.join(OBJECTS_DIR).join(hash.to_plain_str()))
+    }
+
+    /// Removes the checkpoint, once the pack is decoded.
+    pub(crate) fn remove(self) -> io::Result<()> {
+        drop(self.log);
+        fs_utils::remove_dir_all(&self.dir)
+    }
+
+    fn object_path(&self, hash: SHA1) -> PathBuf {
+        self.dir.join(OBJECTS_DIR).join(hash.to_plain_str())
+    }
+}
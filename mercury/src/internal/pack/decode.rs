@@ -4,48 +4,263 @@
 //!
 //!
 //!
-use std::io::{self, BufRead, Cursor, ErrorKind, Read, Seek};
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::Sender;
 use std::thread::{self, JoinHandle, sleep};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use flate2::bufread::ZlibDecoder;
-use threadpool::ThreadPool;
+use flate2::{Crc, Decompress, FlushDecompress, Status};
+use flate2::write::ZlibEncoder;
+#[cfg(unix)]
+use memmap2::Advice;
+use memmap2::Mmap;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, JoinError, JoinSet};
+use tokio_util::io::SyncIoBridge;
+use tokio_util::sync::CancellationToken;
 
 use venus::errors::GitError;
 use venus::hash::SHA1;
 use venus::internal::object::types::ObjectType;
 
-use super::cache::_Cache;
-use crate::internal::pack::cache::Caches;
+use crate::internal::pack::cache::{CacheStorage, Caches};
 use crate::internal::pack::cache_object::{CacheObject, MemSizeRecorder};
+use crate::internal::pack::checkpoint::{Checkpointer, DecodeCheckpoint};
+use crate::internal::pack::encode::encode_object_header;
+use crate::internal::pack::index::{self, IndexEntry};
+use crate::internal::pack::lanes::{Lane, Lanes};
+use crate::internal::pack::scheduler::Scheduler;
 use crate::internal::pack::waitlist::Waitlist;
-use crate::internal::pack::wrapper::Wrapper;
+use crate::internal::pack::wrapper::{CrcReader, Wrapper};
 use crate::internal::pack::{utils, Pack};
 use uuid::Uuid;
 use venus::internal::pack::entry::Entry;
 
+/// Jobs queued or running above which no more object is read, whatever the memory used.
+const MAX_PENDING_JOBS: usize = 2000;
+/// Bytes reserved for the rebuilt object of a delta beyond the data of the delta, the result
+/// size it claims is only reserved up to there.
+const MAX_DELTA_RESERVE: usize = 16 * 1024 * 1024;
+
 /// For Convenient to pass Params
 struct SharedParams {
-    pub pool: Arc<ThreadPool>,
+    pub pool: Arc<Scheduler>,
     pub waitlist: Arc<Waitlist>,
-    pub caches: Arc<Caches>,
+    pub caches: Arc<dyn CacheStorage>,
     pub cache_objs_mem_size: Arc<AtomicUsize>,
-    pub callback: Arc<dyn Fn(Entry) + Sync + Send>
+    pub callback: Arc<dyn Fn(Entry) + Sync + Send>,
+    pub counters: Arc<Counters>,
+    pub max_delta_depth: usize,
+    pub failure: Arc<Mutex<Option<GitError>>>, // the first error of the threads of the pool
+}
+
+impl SharedParams {
+    /// Records the error of a thread, it is returned by the decode, which stops.
+    fn fail(&self, error: GitError) {
+        self.failure.lock().unwrap().get_or_insert(error);
+    }
+}
+
+/// Figures of a decode returned by [`Pack::decode`] and its variants, e.g. for metrics or to
+/// tune the `mem_limit` of [`Pack::new`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    /// Objects of the pack by their type once rebuilt, the deltas included.
+    pub commits: usize,
+    pub trees: usize,
+    pub blobs: usize,
+    pub tags: usize,
+    /// Objects of the pack stored as a delta on an offset or on a hash.
+    pub offset_deltas: usize,
+    pub hash_deltas: usize,
+    /// Longest chain of deltas rebuilt to get an object.
+    pub max_delta_depth: usize,
+    /// Bytes of the pack read, the header and the trailer included.
+    pub bytes_read: usize,
+    pub elapsed: Duration,
+    /// Most memory used by the decoded objects and the index of the cache at once, sampled
+    /// before every object is read.
+    pub peak_memory: usize,
+    /// Bytes the cache wrote to disk, see [`CacheStorage::spilled_bytes`].
+    pub spilled_bytes: u64,
+}
+
+/// Counts the objects as they are rebuilt, by the threads of the pool.
+#[derive(Default)]
+struct Counters {
+    commits: AtomicUsize,
+    trees: AtomicUsize,
+    blobs: AtomicUsize,
+    tags: AtomicUsize,
+    max_delta_depth: AtomicUsize,
+}
+
+impl Counters {
+    /// The counters of a decode resumed at `checkpoint`.
+    fn resumed(checkpoint: &DecodeCheckpoint) -> Self {
+        Counters {
+            commits: AtomicUsize::new(checkpoint.commits),
+            trees: AtomicUsize::new(checkpoint.trees),
+            blobs: AtomicUsize::new(checkpoint.blobs),
+            tags: AtomicUsize::new(checkpoint.tags),
+            max_delta_depth: AtomicUsize::new(checkpoint.max_delta_depth),
+        }
+    }
+
+    fn record(&self, obj: &CacheObject) {
+        let counter = match obj.obj_type {
+            ObjectType::Commit => &self.commits,
+            ObjectType::Tree => &self.trees,
+            ObjectType::Blob => &self.blobs,
+            ObjectType::Tag => &self.tags,
+            ObjectType::OffsetDelta | ObjectType::HashDelta => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.max_delta_depth.fetch_max(obj.delta_depth, Ordering::Relaxed);
+    }
+}
+
+/// What [`Pack::decode_and_index`] does besides decoding.
+#[derive(Default)]
+struct DecodeOptions<'a> {
+    /// Writes the v2 index of the pack here.
+    index_path: Option<&'a Path>,
+    /// The pack has to be the whole input, nothing may follow the trailer.
+    check_eof: bool,
+    /// Looks up the bases of `HashDelta` objects the pack does not have, a thin pack.
+    resolver: Option<&'a dyn Fn(SHA1) -> Option<Vec<u8>>>,
+    /// Writes the pack completed with the bases from `resolver` here.
+    fixed_pack: Option<&'a Path>,
+    /// Stops the decode when cancelled.
+    cancel: Option<&'a CancellationToken>,
+    /// Goes on after this checkpoint of `Pack::checkpoint`, instead of the first object.
+    resume: Option<DecodeCheckpoint>,
+}
+
+fn checkpoint_error(e: io::Error) -> GitError {
+    GitError::InvalidPackFile(format!("Checkpoint error: {}", e))
+}
+
+/// Copies everything read from `inner` to `copy`.
+struct Tee<'a, R> {
+    inner: R,
+    copy: Option<&'a mut File>,
+}
+
+impl<R: Read> Read for Tee<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(copy) = self.copy.as_mut() {
+            copy.write_all(&buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+/// Reads a pack from an async `inner`, hashing what is read, so that no thread waits for
+/// the bytes of a slow client.
+struct AsyncPackReader<R> {
+    inner: BufReader<R>,
+    hash: Sha1,
+    /// The CRC32 of the object being read.
+    crc: Crc,
+    offset: usize,
+}
+
+impl<R: AsyncRead + Unpin> AsyncPackReader<R> {
+    fn new(inner: R) -> Self {
+        AsyncPackReader {
+            inner: BufReader::new(inner),
+            hash: Sha1::new(),
+            crc: Crc::new(),
+            offset: 0,
+        }
+    }
+
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), GitError> {
+        tokio::io::AsyncReadExt::read_exact(&mut self.inner, buf).await
+            .map_err(|e| GitError::InvalidPackFile(format!("Read error: {}", e)))?;
+        self.hash.update(&*buf);
+        self.crc.update(buf);
+        self.offset += buf.len();
+        Ok(())
+    }
+
+    /// Reads the bytes of a varint, up to the one without the continuation bit.
+    async fn read_varint_bytes(&mut self) -> Result<Vec<u8>, GitError> {
+        let mut bytes = Vec::new();
+        // longer than any u64, the parse fails on them
+        while bytes.len() < 16 {
+            let mut byte = [0];
+            self.read_exact(&mut byte).await?;
+            bytes.push(byte[0]);
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Inflates the zlib stream of an object of `size` bytes as it arrives.
+    async fn inflate(&mut self, size: usize) -> Result<Vec<u8>, GitError> {
+        let mut data = Vec::new();
+        // one byte more is enough to detect a size mismatch
+        data.try_reserve_exact(size + 1).map_err(|e| {
+            GitError::InvalidPackFile(format!("Invalid object size {}: {}", size, e))
+        })?;
+        let mut deflate = Decompress::new(true);
+        loop {
+            let input = self.inner.fill_buf().await
+                .map_err(|e| GitError::InvalidPackFile(format!("Read error: {}", e)))?;
+            if input.is_empty() {
+                return Err(GitError::InvalidPackFile(String::from("Decompression error: unexpected end of the pack")));
+            }
+            let total_in = deflate.total_in();
+            let status = deflate.decompress_vec(input, &mut data, FlushDecompress::None)
+                .map_err(|e| GitError::InvalidPackFile(format!("Decompression error: {}", e)))?;
+            let consumed = (deflate.total_in() - total_in) as usize;
+            self.hash.update(&input[..consumed]);
+            self.crc.update(&input[..consumed]);
+            self.inner.consume(consumed);
+            self.offset += consumed;
+            if status == Status::StreamEnd || data.len() > size || (consumed == 0 && data.len() == data.capacity()) {
+                break;
+            }
+        }
+        if data.len() != size {
+            return Err(GitError::InvalidPackFile(format!(
+                "The object size {} does not match the expected size {}",
+                data.len(),
+                size
+            )));
+        }
+        Ok(data)
+    }
+
+    /// The hash of what was read so far.
+    fn hash(&self) -> SHA1 {
+        SHA1::from_bytes(&self.hash.clone().finalize())
+    }
 }
 
 impl Pack {
     /// # Parameters
     /// - `thread_num`: The number of threads to use for decoding and cache, `None` mean use the number of logical CPUs.
-    /// It can't be zero, or panic <br>
+    ///   It can't be zero, or panic <br>
+    ///   The same threads rebuild the deltas and write the objects spilled by the [Caches] <br>
     /// - `mem_limit`: The maximum size of the memory cache in bytes, or None for unlimited.
-    /// The 80% of it will be used for [Caches]  <br>
-    ///     **Not very accurate, because of memory alignment and other reasons, overuse about 15%** <br>
+    ///   The 80% of it will be used for [Caches]  <br>
+    ///   The objects are counted as the allocator holds them, their buffers rounded up to a size class,
+    ///   with the tables indexing them. The resident memory of the process is above the limit by the
+    ///   threads, the buffers of a few objects being rebuilt or written and the pages of the allocator <br>
     /// - `temp_path`: The path to a directory for temporary files, default is "./.cache_temp" <br>
-    /// For example, thread_num = 4 will use up to 8 threads (4 for decoding and 4 for cache) <br>
     ///
     /// # !IMPORTANT:
     /// Can't decode in multi-tasking, because memory limit use shared static variable but different cache, cause "deadlock".
@@ -54,18 +269,70 @@ impl Pack {
         temp_path.push(Uuid::new_v4().to_string()); //maybe Snowflake or ULID is better (less collision)
         let thread_num = thread_num.unwrap_or_else(num_cpus::get);
         let cache_mem_size = mem_limit.map(|mem_limit| mem_limit * 4 / 5);
+        let pool = Arc::new(Scheduler::new(thread_num));
+        let caches = Arc::new(Caches::new_with_scheduler(cache_mem_size, temp_path, pool.clone()));
+        Self::new_with_pool(pool, mem_limit, caches)
+    }
+
+    /// Like [`Pack::new`], with the decoded objects kept in `caches` instead of the default
+    /// [`Caches`], e.g. a [`MemoryCache`](crate::internal::pack::cache::MemoryCache).
+    /// `mem_limit` still bounds the memory of the objects alive, `caches` has to release some
+    /// to decode a pack larger than the limit.
+    pub fn new_with_cache(thread_num: Option<usize>, mem_limit: Option<usize>, caches: Arc<dyn CacheStorage>) -> Self {
+        let thread_num = thread_num.unwrap_or_else(num_cpus::get);
+        Self::new_with_pool(Arc::new(Scheduler::new(thread_num)), mem_limit, caches)
+    }
+
+    fn new_with_pool(pool: Arc<Scheduler>, mem_limit: Option<usize>, caches: Arc<dyn CacheStorage>) -> Self {
         Pack {
             number: 0,
             signature: SHA1::default(),
             objects: Vec::new(),
-            pool: Arc::new(ThreadPool::new(thread_num)),
+            pool,
             waitlist: Arc::new(Waitlist::new()),
-            caches:  Arc::new(Caches::new(cache_mem_size, temp_path, thread_num)),
+            caches,
             mem_limit: mem_limit.unwrap_or(usize::MAX),
             cache_objs_mem: Arc::new(AtomicUsize::default()),
+            max_delta_depth: usize::MAX,
+            max_object_size: usize::MAX,
+            lanes: None,
+            checkpoint: None,
         }
     }
 
+    /// Refuses the packs with a delta chain longer than `depth`, e.g. 4095 like the `--depth`
+    /// of `git pack-objects` at most, with a [`GitError::DeltaObjectError`].
+    /// Unlimited by default.
+    pub fn with_max_delta_depth(mut self, depth: usize) -> Self {
+        self.max_delta_depth = depth;
+        self
+    }
+
+    /// Refuses the packs with an object larger than `size` bytes, before it is decompressed or
+    /// rebuilt. Unlimited by default.
+    pub fn with_max_object_size(mut self, size: usize) -> Self {
+        self.max_object_size = size;
+        self
+    }
+
+    /// Makes [`Pack::decode_tokio`] rebuild the deltas on the workers of `lanes`, in `lane`,
+    /// so that the decodes sharing them are scheduled together, at most as many deltas at once
+    /// as there are workers.
+    pub fn with_lanes(mut self, lanes: Arc<Lanes>, lane: Lane) -> Self {
+        self.lanes = Some((lanes, lane));
+        self
+    }
+
+    /// Makes the decodes read through a buffered reader or a memory map save a checkpoint in
+    /// `dir` every `interval` objects, see [`checkpoint`](super::checkpoint), so that a decode
+    /// stopped by a crash goes on with [`Pack::resume`]. The objects are written to `dir` once
+    /// rebuilt, on top of the tmp files of the cache. The checkpoint is removed once the pack
+    /// is decoded, and kept when the decode fails.
+    pub fn with_checkpoint(mut self, dir: PathBuf, interval: usize) -> Self {
+        self.checkpoint = Some((dir, interval));
+        self
+    }
+
     /// Checks and reads the header of a Git pack file.
     ///
     /// This function reads the first 12 bytes of a pack file, which include the "PACK" magic identifier,
@@ -89,7 +356,7 @@ impl Pack {
     /// * If the pack file does not start with the "PACK" magic identifier.
     /// * If the pack file's version number is not 2.
     /// * If there are any issues reading from the provided `pack` source.
-    pub fn check_header(pack: &mut (impl Read + BufRead)) -> Result<(u32, Vec<u8>), GitError> {
+    pub fn check_header(pack: &mut impl BufRead) -> Result<(u32, Vec<u8>), GitError> {
         // A vector to store the header data for hashing later
         let mut header_data = Vec::new();
 
@@ -183,14 +450,18 @@ impl Pack {
     ///   and the total number of input bytes processed,
     /// * Or a `GitError` in case of a mismatch in expected size or any other reading error.
     ///
-    pub fn decompress_data(&mut self, pack: &mut (impl Read + BufRead + Send), expected_size: usize, ) -> Result<(Vec<u8>, usize), GitError> {
-        // Create a buffer with the expected size for the decompressed data
-        let mut buf = Vec::with_capacity(expected_size);
+    pub fn decompress_data(&mut self, pack: &mut (impl BufRead + Send), expected_size: usize, ) -> Result<(Vec<u8>, usize), GitError> {
+        // Create a buffer with the expected size for the decompressed data,
+        // the size comes from the pack so an absurd value must not abort the process
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(expected_size).map_err(|e| {
+            GitError::InvalidPackFile(format!("Invalid object size {}: {}", expected_size, e))
+        })?;
         // Create a new Zlib decoder with the original data
         let mut deflate = ZlibDecoder::new(pack);
 
-        // Attempt to read data to the end of the buffer
-        match deflate.read_to_end(&mut buf) {
+        // Attempt to read data to the end of the buffer, one byte more is enough to detect a size mismatch
+        match (&mut deflate).take(expected_size as u64 + 1).read_to_end(&mut buf) {
             Ok(_) => {
                 // Check if the length of the buffer matches the expected size
                 if buf.len() != expected_size {
@@ -223,7 +494,15 @@ impl Pack {
     /// * A tuple of the next offset in the pack and the original compressed data as `Vec<u8>`,
     /// * Or a `GitError` in case of any reading or decompression error.
     ///
-    pub fn decode_pack_object(&mut self, pack: &mut (impl Read + BufRead + Send), offset: &mut usize) -> Result<CacheObject, GitError> {
+    pub fn decode_pack_object(&mut self, pack: &mut (impl BufRead + Send), offset: &mut usize) -> Result<CacheObject, GitError> {
+        let mut pack = CrcReader::new(pack);
+        let mut obj = self.read_pack_object(&mut pack, offset)?;
+        obj.crc32 = pack.crc32();
+        Ok(obj)
+    }
+
+    /// Reads the object at `offset` for [`Pack::decode_pack_object`].
+    fn read_pack_object(&mut self, pack: &mut (impl BufRead + Send), offset: &mut usize) -> Result<CacheObject, GitError> {
         let init_offset = *offset;
 
         // Attempt to read the type and size, handle potential errors
@@ -238,21 +517,7 @@ impl Pack {
 
         // Check if the object type is valid
         let t = ObjectType::from_u8(type_bits)?;
-
-        // util lambda: return data with result capacity after rebuilding, for Memory Control
-        let reserve_delta_data = |data: Vec<u8>| -> Vec<u8> {
-            let result_size = { // Read `result-size` of delta_obj
-                let mut reader = Cursor::new(&data);
-                let _ = utils::read_varint_le(&mut reader).unwrap().0; // base_size
-                utils::read_varint_le(&mut reader).unwrap().0 // size after rebuilding
-            };
-            // capacity() == result_size, len() == data.len()
-            // just for accurate Memory Control (rely on `heap_size()` that based on capacity)
-            // Seems wasteful temporarily, but for final memory limit.
-            let mut data_result_cap = Vec::with_capacity(result_size as usize);
-            data_result_cap.extend(data);
-            data_result_cap
-        };
+        self.check_object_size(t, size, init_offset)?;
 
         match t {
             ObjectType::Commit | ObjectType::Tree | ObjectType::Blob | ObjectType::Tag => {
@@ -261,23 +526,16 @@ impl Pack {
                 Ok(CacheObject::new_for_undeltified(t, data, init_offset))
             },
             ObjectType::OffsetDelta => {
-                let (delta_offset, bytes) = utils::read_offset_encoding(pack).unwrap();
+                let (delta_offset, bytes) = utils::read_offset_encoding(pack)
+                    .map_err(|e| GitError::InvalidPackFile(format!("Read error: {}", e)))?;
                 *offset += bytes;
 
                 let (data, raw_size) = self.decompress_data(pack, size)?;
                 *offset += raw_size;
 
-                // Count the base object offset: the current offset - delta offset
-                let base_offset = init_offset
-                    .checked_sub(delta_offset as usize)
-                    .ok_or_else(|| {
-                        GitError::InvalidObjectInfo("Invalid OffsetDelta offset".to_string())
-                    })
-                    .unwrap();
-
                 Ok(CacheObject {
-                    base_offset,
-                    data_decompress: reserve_delta_data(data),
+                    base_offset: Self::base_offset(init_offset, delta_offset)?,
+                    data_decompress: self.reserve_delta_data(data, init_offset)?,
                     obj_type: t,
                     offset: init_offset,
                     mem_recorder: None,
@@ -287,7 +545,8 @@ impl Pack {
             ObjectType::HashDelta => {
                 // Read 20 bytes to get the reference object SHA1 hash
                 let mut buf_ref = [0; 20];
-                pack.read_exact(&mut buf_ref).unwrap();
+                pack.read_exact(&mut buf_ref)
+                    .map_err(|e| GitError::InvalidPackFile(format!("Read error: {}", e)))?;
                 let ref_sha1 = SHA1::from_bytes(buf_ref.as_ref()); //TODO SHA1::from_stream()
                 // Offset is incremented by 20 bytes
                 *offset += 20; //TODO 改为常量
@@ -297,7 +556,7 @@ impl Pack {
 
                 Ok(CacheObject {
                     base_ref: ref_sha1,
-                    data_decompress: reserve_delta_data(data),
+                    data_decompress: self.reserve_delta_data(data, init_offset)?,
                     obj_type: t,
                     offset: init_offset,
                     mem_recorder: None,
@@ -307,18 +566,236 @@ impl Pack {
         }
     }
 
+    /// Refuses an object of `size` bytes at `offset` above the `max_object_size` of the pack.
+    fn check_object_size(&self, t: ObjectType, size: usize, offset: usize) -> Result<(), GitError> {
+        if size <= self.max_object_size {
+            return Ok(());
+        }
+        let message = format!(
+            "The object at offset {} has {} bytes, more than the limit {}",
+            offset, size, self.max_object_size
+        );
+        Err(match t {
+            ObjectType::OffsetDelta | ObjectType::HashDelta => GitError::DeltaObjectError(message),
+            _ => GitError::InvalidPackFile(message),
+        })
+    }
+
+    /// The offset of the base of the `OffsetDelta` at `offset`: the current offset - delta offset
+    fn base_offset(offset: usize, delta_offset: u64) -> Result<usize, GitError> {
+        offset
+            .checked_sub(delta_offset as usize)
+            .filter(|_| delta_offset != 0)
+            .ok_or_else(|| GitError::InvalidObjectInfo("Invalid OffsetDelta offset".to_string()))
+    }
+
+    /// Returns the `data` of the delta at `offset` with the capacity of the object it rebuilds, for Memory Control.
+    /// The capacity is at most [`MAX_DELTA_RESERVE`] above the data, a delta claiming a larger result does
+    /// not reserve it before it is rebuilt.
+    fn reserve_delta_data(&self, data: Vec<u8>, offset: usize) -> Result<Vec<u8>, GitError> {
+        let invalid = |e: io::Error| GitError::InvalidPackFile(format!("Invalid delta header: {}", e));
+        let result_size = { // Read `result-size` of delta_obj
+            let mut reader = Cursor::new(&data);
+            let _ = utils::read_varint_le(&mut reader).map_err(invalid)?.0; // base_size
+            utils::read_varint_le(&mut reader).map_err(invalid)?.0 // size after rebuilding
+        };
+        if result_size > self.max_object_size as u64 {
+            return Err(GitError::DeltaObjectError(format!(
+                "The delta at offset {} rebuilds {} bytes, more than the limit {}",
+                offset, result_size, self.max_object_size
+            )));
+        }
+        // capacity() == result_size, len() == data.len()
+        // just for accurate Memory Control (rely on `heap_size()`, the size of the allocation)
+        // Seems wasteful temporarily, but for final memory limit.
+        let mut data_result_cap = Vec::new();
+        let reserve = (result_size as usize).min(data.len().saturating_add(MAX_DELTA_RESERVE));
+        data_result_cap
+            .try_reserve_exact(reserve.max(data.len()))
+            .map_err(|e| GitError::InvalidPackFile(format!("Invalid delta result size {}: {}", result_size, e)))?;
+        data_result_cap.extend(data);
+        Ok(data_result_cap)
+    }
+
     /// Decodes a pack file from a given Read and BufRead source and get a vec of objects.
     ///
     ///
-    pub fn decode<F>(&mut self, pack: &mut (impl Read + BufRead + Seek + Send), callback: F) -> Result<(), GitError>
+    pub fn decode<F>(&mut self, pack: &mut (impl BufRead + Send), callback: F) -> Result<DecodeStats, GitError>
+    where
+        F: Fn(Entry) + Sync + Send + 'static
+    {
+        self.decode_and_index(pack, callback, DecodeOptions {
+            check_eof: true,
+            ..Default::default()
+        })
+    }
+
+    /// Decodes a pack as it is read from `stream`, e.g. a socket or the body of a push, without
+    /// waiting for the whole pack. Nothing is read back: the hash is computed along the way and
+    /// checked against the trailer once the last object is read. Unlike [`Pack::decode`] the
+    /// stream does not have to end with the pack, so a connection kept open is not waited for.
+    pub fn decode_stream<F>(&mut self, stream: &mut (impl Read + Send), callback: F) -> Result<DecodeStats, GitError>
+    where
+        F: Fn(Entry) + Sync + Send + 'static
+    {
+        self.decode_and_index(stream, callback, DecodeOptions::default())
+    }
+
+    /// Decodes a pack file like [`Pack::decode`], then writes its v2 index to `index_path`,
+    /// the `pack-<hash>.idx` next to the pack for git to use it.
+    pub fn decode_with_index<F>(
+        &mut self,
+        pack: &mut (impl BufRead + Send),
+        index_path: &Path,
+        callback: F,
+    ) -> Result<DecodeStats, GitError>
+    where
+        F: Fn(Entry) + Sync + Send + 'static
+    {
+        self.decode_and_index(pack, callback, DecodeOptions {
+            index_path: Some(index_path),
+            check_eof: true,
+            ..Default::default()
+        })
+    }
+
+    /// Decodes a thin pack, whose `HashDelta` objects may have their base outside of the pack,
+    /// as sent by `git push`. `resolver` returns such a base from the object database, in the
+    /// loose object format `<type> <size>\0<content>`, or `None` when it is not there either.
+    /// The bases are not passed to `callback`, only the objects of the pack.
+    ///
+    /// With `fixed_pack`, the pack is also written there completed with the bases, so that it
+    /// can be stored and read without the object database. `self.number` and `self.signature`
+    /// stay those of the pack as received.
+    pub fn decode_thin<F, R>(
+        &mut self,
+        pack: &mut (impl Read + Send),
+        resolver: R,
+        fixed_pack: Option<&Path>,
+        callback: F,
+    ) -> Result<DecodeStats, GitError>
+    where
+        F: Fn(Entry) + Sync + Send + 'static,
+        R: Fn(SHA1) -> Option<Vec<u8>>,
+    {
+        self.decode_and_index(pack, callback, DecodeOptions {
+            resolver: Some(&resolver),
+            fixed_pack,
+            ..Default::default()
+        })
+    }
+
+    /// Decodes a pack file like [`Pack::decode`] until `cancel` fires, e.g. when the push it
+    /// comes with is aborted. No more object is read then: the objects already read are
+    /// processed, the cached ones and their temporary files removed, and an error returned.
+    pub fn decode_with_cancel<F>(
+        &mut self,
+        pack: &mut (impl BufRead + Send),
+        callback: F,
+        cancel: CancellationToken,
+    ) -> Result<DecodeStats, GitError>
+    where
+        F: Fn(Entry) + Sync + Send + 'static
+    {
+        self.decode_and_index(pack, callback, DecodeOptions {
+            check_eof: true,
+            cancel: Some(&cancel),
+            ..Default::default()
+        })
+    }
+
+    /// Decodes the pack file at `path` like [`Pack::decode`], read through a memory map instead
+    /// of a buffered reader: the objects are inflated straight from the mapped pages of the
+    /// file, without a read call nor a copy of their compressed data.
+    ///
+    /// The file must not be changed while it is decoded, a file truncated meanwhile may kill
+    /// the process with a `SIGBUS`: e.g. a pack of the storage, not one being received.
+    pub fn decode_mmap<F>(&mut self, path: &Path, callback: F) -> Result<DecodeStats, GitError>
+    where
+        F: Fn(Entry) + Sync + Send + 'static
+    {
+        let file = File::open(path).map_err(|e| GitError::InvalidPackFile(format!("Read error: {}", e)))?;
+        // SAFETY: the caller does not change the file while it is decoded, see above
+        let map = unsafe { Mmap::map(&file) }.map_err(|e| GitError::InvalidPackFile(format!("Read error: {}", e)))?;
+        // the pages are read once and in order, the kernel can read ahead and drop them early
+        #[cfg(unix)]
+        let _ = map.advise(Advice::Sequential);
+        let (stats, _) = self.decode_from(Wrapper::new(&map[..]), callback, DecodeOptions {
+            check_eof: true,
+            ..Default::default()
+        })?;
+        Ok(stats)
+    }
+
+    /// Goes on with a decode stopped after a checkpoint saved in `dir`, see
+    /// [`Pack::with_checkpoint`], instead of decoding `pack` again from its first object.
+    /// `pack` is read from its start, the bytes before the checkpoint only hashed, and has to
+    /// be the pack of the checkpoint.
+    ///
+    /// The objects rebuilt before the checkpoint are not passed to `callback`, those rebuilt
+    /// after it may have been passed to the callback of the decode stopped and are passed
+    /// again. The checkpoints are still saved in `dir`. The figures returned are those of the
+    /// whole pack, but `elapsed` and `peak_memory` which are those of the decode resumed.
+    pub fn resume<F>(
+        &mut self,
+        dir: &Path,
+        pack: &mut (impl BufRead + Send),
+        callback: F,
+    ) -> Result<DecodeStats, GitError>
+    where
+        F: Fn(Entry) + Sync + Send + 'static
+    {
+        let checkpoint = DecodeCheckpoint::load(dir)?;
+        self.checkpoint = Some((dir.to_path_buf(), checkpoint.interval));
+        self.decode_and_index(pack, callback, DecodeOptions {
+            check_eof: true,
+            resume: Some(checkpoint),
+            ..Default::default()
+        })
+    }
+
+    fn decode_and_index<F>(
+        &mut self,
+        pack: &mut (impl Read + Send),
+        callback: F,
+        mut options: DecodeOptions,
+    ) -> Result<DecodeStats, GitError>
+    where
+        F: Fn(Entry) + Sync + Send + 'static
+    {
+        let mut copy = options.fixed_pack.take()
+            .map(|path| OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path))
+            .transpose()
+            .map_err(|e| GitError::InvalidPackFile(format!("Write error: {}", e)))?;
+        let reader = Wrapper::new(io::BufReader::new(Tee { inner: pack, copy: copy.as_mut() }));
+        let (stats, bases) = self.decode_from(reader, callback, options)?;
+        if let Some(copy) = copy.as_mut() {
+            Self::complete_thin_pack(copy, stats.bytes_read - 20, self.number, &bases).map_err(|e| {
+                GitError::InvalidPackFile(format!("Write error: {}", e))
+            })?;
+        }
+        Ok(stats)
+    }
+
+    /// Decodes the pack read from `reader`, returns the bases of a thin pack found by the
+    /// resolver of the `options` with the figures of the decode.
+    fn decode_from<F>(
+        &mut self,
+        mut reader: Wrapper<impl BufRead + Send>,
+        callback: F,
+        mut options: DecodeOptions,
+    ) -> Result<(DecodeStats, Vec<Arc<CacheObject>>), GitError>
     where
         F: Fn(Entry) + Sync + Send + 'static
     {
         let time = Instant::now();
         let callback = Arc::new(callback);
+        let counters = Arc::new(options.resume.as_ref().map_or_else(Counters::default, Counters::resumed));
+        let failure = Arc::new(Mutex::new(None));
+        let failed = || failure.lock().unwrap().take();
+        let mut stats = DecodeStats::default();
 
         let caches = self.caches.clone();
-        let mut reader = Wrapper::new(io::BufReader::new(pack));
 
         let result = Pack::check_header(&mut reader);
         match result {
@@ -329,10 +806,27 @@ impl Pack {
                 return Err(e);
             }
         }
-        println!("The pack file has {} objects", self.number);
+        tracing::debug!("The pack file has {} objects", self.number);
 
         let mut offset: usize = 12;
         let i = Arc::new(AtomicUsize::new(1));
+        // offset and CRC32 of every object, for the index
+        let mut crcs: Vec<(usize, u32)> = Vec::new();
+        // a thin pack is not checkpointed, its bases are resolved at the end
+        let mut checkpointer = match options.resume.take() {
+            Some(checkpoint) => match self.restore(&mut reader, checkpoint, &mut stats, &mut offset, &i) {
+                Ok(checkpointer) => Some(checkpointer),
+                Err(e) => {
+                    self.abort();
+                    return Err(e);
+                }
+            },
+            None => self.checkpoint.as_ref()
+                .filter(|_| options.resolver.is_none())
+                .map(|(dir, interval)| Checkpointer::create(dir, *interval))
+                .transpose()
+                .map_err(checkpoint_error)?,
+        };
         
         // debug log thread g   
         #[cfg(debug_assertions)]
@@ -351,8 +845,8 @@ impl Pack {
                     if log_stop.load(Ordering::Relaxed) {
                         break;
                     }
-                    println!("time {:?} s \t pass: {:?}, \t dec-num: {} \t cah-num: {} \t Objs: {} MB \t CacheUsed: {} MB",
-                    time.elapsed().as_millis() as f64 / 1000.0, log_i.load(Ordering::Relaxed), log_pool.queued_count(), log_cache.queued_tasks(),
+                    tracing::debug!("time {:?} s \t pass: {:?}, \t dec-num: {} \t cah-num: {} \t Objs: {} MB \t CacheUsed: {} MB",
+                    time.elapsed().as_millis() as f64 / 1000.0, log_i.load(Ordering::Relaxed), log_pool.pending(), log_cache.queued_tasks(),
                              cache_objs_mem.load(Ordering::Relaxed) / 1024 / 1024,
                              log_cache.memory_used() / 1024 / 1024);
 
@@ -362,16 +856,56 @@ impl Pack {
         } // LOG
 
         while i.load(Ordering::Relaxed) <= self.number {
-            // 3 parts: Waitlist + TheadPool + Caches
-            // hardcode the limit of the tasks of threads_pool queue, to limit memory
-            while self.memory_used() > self.mem_limit || self.pool.queued_count() > 2000 {
-                thread::yield_now();
+            if let Some(checkpointer) = checkpointer.as_mut().filter(|c| c.due(i.load(Ordering::Relaxed) - 1)) {
+                // once the jobs end, every object read is rebuilt or waiting for its base
+                self.pool.join();
+                if failure.lock().unwrap().is_none() {
+                    let state = self.checkpoint_state(offset, i.load(Ordering::Relaxed), reader.final_hash(), &counters, &stats);
+                    if let Err(e) = checkpointer.save(&*self.caches, state) {
+                        self.abort();
+                        #[cfg(debug_assertions)]
+                        stop.store(true, Ordering::Relaxed);
+                        return Err(checkpoint_error(e));
+                    }
+                }
+            }
+            // 3 parts: Waitlist + Scheduler + Caches
+            let cancelled = || options.cancel.is_some_and(|cancel| cancel.is_cancelled());
+            // above the limits, wait for the jobs pending to release memory, if any: the
+            // objects they hold are dropped or spilled when they end
+            self.pool.wait_while(|pending| {
+                !cancelled()
+                    && ((self.memory_used() > self.mem_limit && pending > 0) || pending > MAX_PENDING_JOBS)
+            });
+            stats.peak_memory = stats.peak_memory.max(self.memory_used());
+            if cancelled() {
+                self.abort();
+                #[cfg(debug_assertions)]
+                stop.store(true, Ordering::Relaxed);
+                return Err(GitError::PackDecodeCancelled(self.number - i.load(Ordering::Relaxed) + 1));
+            }
+            if let Some(e) = failed() {
+                self.abort();
+                #[cfg(debug_assertions)]
+                stop.store(true, Ordering::Relaxed);
+                return Err(e);
             }
             let r: Result<CacheObject, GitError> = self.decode_pack_object(&mut reader, &mut offset);
             match r {
                 Ok(mut obj) => {
+                    match obj.obj_type {
+                        ObjectType::OffsetDelta => stats.offset_deltas += 1,
+                        ObjectType::HashDelta => stats.hash_deltas += 1,
+                        _ => {}
+                    }
+                    if options.index_path.is_some() {
+                        crcs.push((obj.offset, obj.crc32));
+                    }
                     obj.set_mem_recorder(self.cache_objs_mem.clone());
                     obj.record_mem_size();
+                    if let Some(checkpointer) = checkpointer.as_mut() {
+                        checkpointer.read(obj.offset);
+                    }
 
                     // Wrapper of Arc Params, for convenience to pass
                     let params = Arc::new(SharedParams {
@@ -379,7 +913,10 @@ impl Pack {
                         waitlist: self.waitlist.clone(),
                         caches: self.caches.clone(),
                         cache_objs_mem_size: self.cache_objs_mem.clone(),
-                        callback: callback.clone()
+                        callback: callback.clone(),
+                        counters: counters.clone(),
+                        max_delta_depth: self.max_delta_depth,
+                        failure: failure.clone(),
                     });
 
                     let caches = caches.clone();
@@ -418,6 +955,9 @@ impl Pack {
                     });
                 },
                 Err(e) => {
+                    self.abort();
+                    #[cfg(debug_assertions)]
+                    stop.store(true, Ordering::Relaxed);
                     return Err(e);
                 }
             }
@@ -426,7 +966,9 @@ impl Pack {
 
         let render_hash = reader.final_hash();
         let mut trailer_buf = [0; 20];
-        reader.read_exact(&mut trailer_buf).unwrap();
+        reader.read_exact(&mut trailer_buf).map_err(|e| {
+            GitError::InvalidPackFile(format!("Read error: {}", e))
+        })?;
         self.signature = SHA1::from_bytes(trailer_buf.as_ref());
 
         if render_hash != self.signature {
@@ -437,34 +979,267 @@ impl Pack {
             )));
         }
 
-        let end = utils::is_eof(&mut reader);
-        if !end {
+        if options.check_eof && !utils::is_eof(&mut reader) {
             return Err(GitError::InvalidPackFile(
                 "The pack file is not at the end".to_string()
             ));
         }
+        drop(reader);
 
         self.pool.join(); // wait for all threads to finish
+        let params = Arc::new(SharedParams {
+            pool: self.pool.clone(),
+            waitlist: self.waitlist.clone(),
+            caches: self.caches.clone(),
+            cache_objs_mem_size: self.cache_objs_mem.clone(),
+            callback: callback.clone(),
+            counters: counters.clone(),
+            max_delta_depth: self.max_delta_depth,
+            failure: failure.clone(),
+        });
+        let bases = failed().map_or_else(|| self.resolve_missing_bases(params, options.resolver), Err);
+        // a delta failing while the missing bases are resolved leaves the ones based on it waiting
+        let bases = match failed().map_or(bases, Err) {
+            Ok(bases) => bases,
+            Err(e) => {
+                self.abort();
+                #[cfg(debug_assertions)]
+                stop.store(true, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
         // !Attention: Caches threadpool may not stop, but it's not a problem (garbage file data)
         // So that files != self.number
         assert_eq!(self.waitlist.map_offset.len(), 0);
         assert_eq!(self.waitlist.map_ref.len(), 0);
         assert_eq!(self.number, caches.total_inserted());
-        println!("The pack file has been decoded successfully");
-        println!("Pack decode takes: [ {:?} ]", time.elapsed());
+        tracing::debug!("The pack file has been decoded successfully");
+        tracing::debug!("Pack decode takes: [ {:?} ]", time.elapsed());
+
+        if let Some(index_path) = options.index_path {
+            let entries = crcs
+                .into_iter()
+                .map(|(offset, crc32)| IndexEntry {
+                    hash: caches.get_hash(offset).expect("every decoded object is cached"),
+                    crc32,
+                    offset: offset as u64,
+                })
+                .collect();
+            index::write_index(index_path, entries, &self.signature)?;
+        }
 
+        stats.peak_memory = stats.peak_memory.max(self.memory_used());
+        stats.spilled_bytes = self.caches.spilled_bytes();
         self.caches.clear(); // clear cached objects & stop threads
         assert_eq!(self.cache_objs_mem_used(), 0); // all the objs should be dropped until here
         
         #[cfg(debug_assertions)]
         stop.store(true, Ordering::Relaxed);
-        
-        Ok(())
+
+        stats.commits = counters.commits.load(Ordering::Relaxed);
+        stats.trees = counters.trees.load(Ordering::Relaxed);
+        stats.blobs = counters.blobs.load(Ordering::Relaxed);
+        stats.tags = counters.tags.load(Ordering::Relaxed);
+        stats.max_delta_depth = counters.max_delta_depth.load(Ordering::Relaxed);
+        stats.bytes_read = offset + 20;
+        stats.elapsed = time.elapsed();
+        if let Some(checkpointer) = checkpointer {
+            if let Err(e) = checkpointer.remove() {
+                tracing::warn!("Failed to remove the checkpoint of the pack: {}", e);
+            }
+        }
+        Ok((stats, bases))
+    }
+
+    /// Where the decode is, before the object `next` at `offset`: nothing may be processing.
+    fn checkpoint_state(
+        &self,
+        offset: usize,
+        next: usize,
+        prefix_hash: SHA1,
+        counters: &Counters,
+        stats: &DecodeStats,
+    ) -> DecodeCheckpoint {
+        let mut waiting = Vec::new();
+        for list in self.waitlist.map_offset.iter() {
+            waiting.extend(list.value().iter().cloned());
+        }
+        for list in self.waitlist.map_ref.iter() {
+            waiting.extend(list.value().iter().cloned());
+        }
+        DecodeCheckpoint {
+            number: self.number,
+            offset,
+            next,
+            prefix_hash,
+            waiting,
+            offset_deltas: stats.offset_deltas,
+            hash_deltas: stats.hash_deltas,
+            commits: counters.commits.load(Ordering::Relaxed),
+            trees: counters.trees.load(Ordering::Relaxed),
+            blobs: counters.blobs.load(Ordering::Relaxed),
+            tags: counters.tags.load(Ordering::Relaxed),
+            max_delta_depth: counters.max_delta_depth.load(Ordering::Relaxed),
+            ..Default::default()
+        }
+    }
+
+    /// Goes back to `checkpoint` in the directory of `self.checkpoint`: reads the pack up to
+    /// its offset, only hashed, and puts back the objects rebuilt and the deltas waiting.
+    /// Returns what saves the next checkpoints there.
+    fn restore(
+        &mut self,
+        reader: &mut Wrapper<impl BufRead>,
+        checkpoint: DecodeCheckpoint,
+        stats: &mut DecodeStats,
+        offset: &mut usize,
+        i: &AtomicUsize,
+    ) -> Result<Checkpointer, GitError> {
+        let dir = self.checkpoint.as_ref().map(|(dir, _)| dir.clone()).expect("set by Pack::resume");
+        if checkpoint.number != self.number {
+            return Err(GitError::InvalidPackFile(format!(
+                "The pack has {} objects, the pack of the checkpoint {}",
+                self.number, checkpoint.number
+            )));
+        }
+        let prefix = (checkpoint.offset - 12) as u64;
+        let read = io::copy(&mut reader.by_ref().take(prefix), &mut io::sink())
+            .map_err(|e| GitError::InvalidPackFile(format!("Read error: {}", e)))?;
+        if read != prefix || reader.final_hash() != checkpoint.prefix_hash {
+            return Err(GitError::InvalidPackFile(
+                "The pack does not start like the pack of the checkpoint".to_string()
+            ));
+        }
+
+        let checkpointer = Checkpointer::open(&dir, &checkpoint).map_err(checkpoint_error)?;
+        for (obj_offset, hash) in Checkpointer::resolved(&dir, &checkpoint).map_err(checkpoint_error)? {
+            let mut obj = Checkpointer::load_object(&dir, hash).map_err(checkpoint_error)?;
+            obj.set_mem_recorder(self.cache_objs_mem.clone());
+            obj.record_mem_size();
+            self.caches.insert(obj_offset, hash, obj);
+            // the objects evicted by the cache are written by the pool
+            self.pool.wait_while(|pending| self.memory_used() > self.mem_limit && pending > 0);
+        }
+        for mut obj in checkpoint.waiting {
+            obj.set_mem_recorder(self.cache_objs_mem.clone());
+            obj.record_mem_size();
+            match obj.obj_type {
+                ObjectType::OffsetDelta => self.waitlist.insert_offset(obj.base_offset, obj),
+                _ => self.waitlist.insert_ref(obj.base_ref, obj),
+            }
+        }
+        stats.offset_deltas = checkpoint.offset_deltas;
+        stats.hash_deltas = checkpoint.hash_deltas;
+        *offset = checkpoint.offset;
+        i.store(checkpoint.next, Ordering::Relaxed);
+        Ok(checkpointer)
+    }
+
+    /// Stops a decode: waits for the objects being processed, then drops every object left.
+    fn abort(&self) {
+        self.pool.join();
+        self.waitlist.map_offset.clear();
+        self.waitlist.map_ref.clear();
+        self.caches.clear();
+    }
+
+    /// Rebuilds the deltas still waiting for a base once the whole pack is decoded, with the
+    /// bases looked up by `resolver`. Returns the bases used, in the order they were found.
+    fn resolve_missing_bases(
+        &self,
+        params: Arc<SharedParams>,
+        resolver: Option<&dyn Fn(SHA1) -> Option<Vec<u8>>>,
+    ) -> Result<Vec<Arc<CacheObject>>, GitError> {
+        let mut bases = Vec::new();
+        loop {
+            let missing: Vec<SHA1> = self.waitlist.map_ref.iter().map(|item| *item.key()).collect();
+            let Some(first) = missing.first() else {
+                return Ok(bases);
+            };
+            let not_found = GitError::InvalidPackFile(format!(
+                "The base object {} of a delta is missing",
+                first.to_plain_str()
+            ));
+            let Some(resolver) = resolver else {
+                return Err(not_found);
+            };
+            let mut progress = false;
+            for hash in missing {
+                // a delta in the pack may be waiting for another one, rebuilt on the way
+                if !self.waitlist.map_ref.contains_key(&hash) {
+                    continue;
+                }
+                let Some(data) = resolver(hash) else {
+                    continue;
+                };
+                let base = Arc::new(Self::parse_loose_object(hash, &data)?);
+                Self::process_waitlist(params.clone(), base.clone());
+                self.pool.join();
+                bases.push(base);
+                progress = true;
+            }
+            if !progress {
+                return Err(not_found);
+            }
+        }
+    }
+
+    /// Reads an object given as `<type> <size>\0<content>`, which must have the id `hash`.
+    fn parse_loose_object(hash: SHA1, data: &[u8]) -> Result<CacheObject, GitError> {
+        let invalid = || GitError::InvalidObjectInfo(format!("The base object {}", hash.to_plain_str()));
+        let header_end = data.iter().position(|b| *b == b'\0').ok_or_else(invalid)?;
+        let header = std::str::from_utf8(&data[..header_end]).map_err(|_| invalid())?;
+        let (obj_type, size) = header.split_once(' ').ok_or_else(invalid)?;
+        let obj_type = ObjectType::from_string(obj_type)?;
+        let content = data[header_end + 1..].to_vec();
+        if size.parse::<usize>().ok() != Some(content.len()) {
+            return Err(invalid());
+        }
+        let obj = CacheObject::new_for_undeltified(obj_type, content, 0);
+        if obj.hash != hash {
+            return Err(GitError::InvalidObjectInfo(format!(
+                "The base object {} is read as {}",
+                hash.to_plain_str(),
+                obj.hash.to_plain_str()
+            )));
+        }
+        Ok(obj)
+    }
+
+    /// Appends `bases` to the copy of a thin pack of `number` objects ending at `end`,
+    /// then updates the number of objects of the header and the trailer.
+    fn complete_thin_pack(file: &mut File, end: usize, number: usize, bases: &[Arc<CacheObject>]) -> io::Result<()> {
+        // the copy may go past the trailer, whatever was buffered is in it
+        file.set_len(end as u64)?;
+        file.seek(SeekFrom::End(0))?;
+        for base in bases {
+            let data = &base.data_decompress;
+            file.write_all(&encode_object_header(base.obj_type.to_u8(), data.len()))?;
+            let mut encoder = ZlibEncoder::new(&mut *file, flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?;
+        }
+        file.seek(SeekFrom::Start(8))?;
+        file.write_all(&((number + bases.len()) as u32).to_be_bytes())?;
+
+        file.seek(SeekFrom::Start(0))?;
+        let mut hash = Sha1::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hash.update(&buf[..n]);
+        }
+        let trailer: [u8; 20] = hash.finalize().into();
+        file.write_all(&trailer)?;
+        file.flush()
     }
 
     /// Decode Pack in a new thread and send the CacheObjects while decoding.
     /// <br> Attention: It will consume the `pack` and return in JoinHandle
-    pub fn decode_async(mut self, mut pack: (impl Read + BufRead + Seek + Send + 'static), sender: Sender<Entry>) -> JoinHandle<Pack> {
+    pub fn decode_async(mut self, mut pack: impl BufRead + Send + 'static, sender: Sender<Entry>) -> JoinHandle<Pack> {
         thread::spawn(move || {
             self.decode(&mut pack, move |entry| {
                 sender.send(entry).unwrap();
@@ -473,9 +1248,261 @@ impl Pack {
         })
     }
 
-    /// CacheObjects + Index size of Caches
+    /// Decodes a pack read from an async `stream` with [`Pack::decode_stream`], on a blocking
+    /// thread of the tokio runtime, and sends the objects while decoding.
+    pub fn decode_stream_async(
+        mut self,
+        stream: impl AsyncRead + Unpin + Send + 'static,
+        sender: Sender<Entry>,
+    ) -> task::JoinHandle<Result<Pack, GitError>> {
+        task::spawn_blocking(move || {
+            let mut stream = SyncIoBridge::new(stream);
+            self.decode_stream(&mut stream, move |entry| {
+                // the receiver may stop listening, e.g. on a rejected push
+                let _ = sender.send(entry);
+            })?;
+            Ok(self)
+        })
+    }
+
+    /// Decodes a pack read from an async `reader` on the current task, and sends the objects
+    /// to `sender` while decoding. Unlike [`Pack::decode_stream_async`] no thread waits for the
+    /// pack: it is read and inflated as it arrives, only the deltas are rebuilt on the blocking
+    /// threads of the runtime, as many at once as the threads of the pack. The workers of the
+    /// pack only write the objects spilled by the cache. Like [`Pack::decode_stream`], the reader does not have to end with
+    /// the pack, and every delta must have its base in the pack.
+    pub async fn decode_tokio(
+        &mut self,
+        reader: impl AsyncRead + Unpin + Send,
+        sender: mpsc::Sender<Entry>,
+    ) -> Result<DecodeStats, GitError> {
+        let time = Instant::now();
+        let mut stats = DecodeStats::default();
+        let counters = Counters::default();
+        let mut reader = AsyncPackReader::new(reader);
+        let mut rebuilds = JoinSet::new();
+
+        let result = self.decode_tokio_objects(&mut reader, &sender, &mut rebuilds, &counters, &mut stats).await;
+        if let Err(e) = result {
+            // the deltas being rebuilt can't be stopped, wait for them to drop every object
+            rebuilds.shutdown().await;
+            self.abort();
+            return Err(e);
+        }
+        tracing::debug!("Pack decode takes: [ {:?} ]", time.elapsed());
+
+        stats.peak_memory = stats.peak_memory.max(self.memory_used());
+        stats.spilled_bytes = self.caches.spilled_bytes();
+        self.caches.clear();
+        assert_eq!(self.cache_objs_mem_used(), 0); // all the objs should be dropped until here
+
+        stats.commits = counters.commits.load(Ordering::Relaxed);
+        stats.trees = counters.trees.load(Ordering::Relaxed);
+        stats.blobs = counters.blobs.load(Ordering::Relaxed);
+        stats.tags = counters.tags.load(Ordering::Relaxed);
+        stats.max_delta_depth = counters.max_delta_depth.load(Ordering::Relaxed);
+        stats.bytes_read = reader.offset;
+        stats.elapsed = time.elapsed();
+        Ok(stats)
+    }
+
+    async fn decode_tokio_objects<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut AsyncPackReader<R>,
+        sender: &mpsc::Sender<Entry>,
+        rebuilds: &mut JoinSet<Result<CacheObject, GitError>>,
+        counters: &Counters,
+        stats: &mut DecodeStats,
+    ) -> Result<(), GitError> {
+        let mut header = [0; 12];
+        reader.read_exact(&mut header).await?;
+        let (object_num, _) = Pack::check_header(&mut Cursor::new(header))?;
+        self.number = object_num as usize;
+        tracing::debug!("The pack file has {} objects", self.number);
+        let max_rebuilds = match &self.lanes {
+            Some((lanes, _)) => lanes.threads(),
+            None => self.pool.threads(),
+        };
+
+        for _ in 0..self.number {
+            // above the limits, wait for the deltas being rebuilt to release memory, if any
+            while (self.memory_used() > self.mem_limit || rebuilds.len() >= max_rebuilds) && !rebuilds.is_empty() {
+                let rebuilt = rebuilds.join_next().await.expect("the set is not empty");
+                self.rebuilt(rebuilt, sender, rebuilds, counters).await?;
+            }
+            stats.peak_memory = stats.peak_memory.max(self.memory_used());
+
+            let mut obj = self.read_pack_object_async(reader).await?;
+            obj.set_mem_recorder(self.cache_objs_mem.clone());
+            obj.record_mem_size();
+            match obj.obj_type {
+                ObjectType::OffsetDelta => {
+                    stats.offset_deltas += 1;
+                    // one task inserts the objects and the deltas waiting, nothing is missed
+                    match self.caches.get_by_offset(obj.base_offset) {
+                        Some(base_obj) => self.spawn_rebuild(rebuilds, obj, base_obj)?,
+                        None => self.waitlist.insert_offset(obj.base_offset, obj),
+                    }
+                }
+                ObjectType::HashDelta => {
+                    stats.hash_deltas += 1;
+                    match self.caches.get_by_hash(obj.base_ref) {
+                        Some(base_obj) => self.spawn_rebuild(rebuilds, obj, base_obj)?,
+                        None => self.waitlist.insert_ref(obj.base_ref, obj),
+                    }
+                }
+                _ => self.cache_obj_async(obj, sender, rebuilds, counters).await?,
+            }
+            while let Some(rebuilt) = rebuilds.try_join_next() {
+                self.rebuilt(rebuilt, sender, rebuilds, counters).await?;
+            }
+        }
+
+        let render_hash = reader.hash();
+        let mut trailer_buf = [0; 20];
+        reader.read_exact(&mut trailer_buf).await?;
+        self.signature = SHA1::from_bytes(trailer_buf.as_ref());
+        if render_hash != self.signature {
+            return Err(GitError::InvalidPackFile(format!(
+                "The pack file hash {} does not match the trailer hash {}",
+                render_hash.to_plain_str(),
+                self.signature.to_plain_str()
+            )));
+        }
+
+        while let Some(rebuilt) = rebuilds.join_next().await {
+            self.rebuilt(rebuilt, sender, rebuilds, counters).await?;
+        }
+        if let Some(item) = self.waitlist.map_ref.iter().next() {
+            return Err(GitError::InvalidPackFile(format!(
+                "The base object {} of a delta is missing",
+                item.key().to_plain_str()
+            )));
+        }
+        assert_eq!(self.waitlist.map_offset.len(), 0);
+        assert_eq!(self.number, self.caches.total_inserted());
+        Ok(())
+    }
+
+    /// Reads the next object of the pack like [`Pack::decode_pack_object`], from an async reader.
+    async fn read_pack_object_async<R: AsyncRead + Unpin>(&self, reader: &mut AsyncPackReader<R>) -> Result<CacheObject, GitError> {
+        let init_offset = reader.offset;
+        reader.crc.reset();
+        let header = reader.read_varint_bytes().await?;
+        let (type_bits, size) = utils::read_type_and_varint_size(&mut Cursor::new(header), &mut 0)
+            .map_err(|e| GitError::InvalidPackFile(format!("Read error: {}", e)))?;
+        let t = ObjectType::from_u8(type_bits)?;
+        self.check_object_size(t, size, init_offset)?;
+
+        let mut obj = match t {
+            ObjectType::Commit | ObjectType::Tree | ObjectType::Blob | ObjectType::Tag => {
+                let data = reader.inflate(size).await?;
+                CacheObject::new_for_undeltified(t, data, init_offset)
+            },
+            ObjectType::OffsetDelta => {
+                let encoding = reader.read_varint_bytes().await?;
+                let (delta_offset, _) = utils::read_offset_encoding(&mut Cursor::new(encoding))
+                    .map_err(|e| GitError::InvalidPackFile(format!("Read error: {}", e)))?;
+                let data = reader.inflate(size).await?;
+                CacheObject {
+                    base_offset: Self::base_offset(init_offset, delta_offset)?,
+                    data_decompress: self.reserve_delta_data(data, init_offset)?,
+                    obj_type: t,
+                    offset: init_offset,
+                    mem_recorder: None,
+                    ..Default::default()
+                }
+            },
+            ObjectType::HashDelta => {
+                let mut buf_ref = [0; 20];
+                reader.read_exact(&mut buf_ref).await?;
+                let data = reader.inflate(size).await?;
+                CacheObject {
+                    base_ref: SHA1::from_bytes(buf_ref.as_ref()),
+                    data_decompress: self.reserve_delta_data(data, init_offset)?,
+                    obj_type: t,
+                    offset: init_offset,
+                    mem_recorder: None,
+                    ..Default::default()
+                }
+            }
+        };
+        obj.crc32 = reader.crc.sum();
+        Ok(obj)
+    }
+
+    /// Rebuilds `delta_obj` in the lane of the pack, or on a blocking thread of the runtime.
+    fn spawn_rebuild(
+        &self,
+        rebuilds: &mut JoinSet<Result<CacheObject, GitError>>,
+        delta_obj: CacheObject,
+        base_obj: Arc<CacheObject>,
+    ) -> Result<(), GitError> {
+        if base_obj.delta_depth >= self.max_delta_depth {
+            return Err(GitError::DeltaObjectError(format!(
+                "The delta chain of the object at offset {} is longer than the limit {}",
+                delta_obj.offset, self.max_delta_depth
+            )));
+        }
+        let cache_objs_mem = self.cache_objs_mem.clone();
+        let rebuild = move || {
+            let mut new_obj = Pack::rebuild_delta(delta_obj, base_obj)?;
+            new_obj.set_mem_recorder(cache_objs_mem);
+            new_obj.record_mem_size();
+            Ok(new_obj)
+        };
+        match &self.lanes {
+            Some((lanes, lane)) => {
+                let (tx, rx) = oneshot::channel();
+                lanes.execute(*lane, move || {
+                    let _ = tx.send(rebuild());
+                });
+                rebuilds.spawn(async move {
+                    // the sender is dropped without a result if the rebuild panicked
+                    rx.await.unwrap_or_else(|_| {
+                        Err(GitError::DeltaObjectError("The rebuild of a delta panicked".to_string()))
+                    })
+                });
+            }
+            None => {
+                rebuilds.spawn_blocking(rebuild);
+            }
+        }
+        Ok(())
+    }
+
+    async fn rebuilt(
+        &self,
+        rebuilt: Result<Result<CacheObject, GitError>, JoinError>,
+        sender: &mpsc::Sender<Entry>,
+        rebuilds: &mut JoinSet<Result<CacheObject, GitError>>,
+        counters: &Counters,
+    ) -> Result<(), GitError> {
+        let new_obj = rebuilt.map_err(|e| GitError::DeltaObjectError(e.to_string()))??;
+        self.cache_obj_async(new_obj, sender, rebuilds, counters).await
+    }
+
+    /// Sends and caches the new object, then rebuilds the deltas waiting for it.
+    async fn cache_obj_async(
+        &self,
+        new_obj: CacheObject,
+        sender: &mpsc::Sender<Entry>,
+        rebuilds: &mut JoinSet<Result<CacheObject, GitError>>,
+        counters: &Counters,
+    ) -> Result<(), GitError> {
+        // the receiver may stop listening, e.g. on a rejected push
+        let _ = sender.send(new_obj.to_entry()).await;
+        counters.record(&new_obj);
+        let new_obj = self.caches.insert(new_obj.offset, new_obj.hash, new_obj);
+        for delta_obj in self.waitlist.take(new_obj.offset, new_obj.hash) {
+            self.spawn_rebuild(rebuilds, delta_obj, new_obj.clone())?;
+        }
+        Ok(())
+    }
+
+    /// CacheObjects + Index size of Caches and Waitlist
     fn memory_used(&self) -> usize {
-        self.cache_objs_mem_used() + self.caches.memory_used_index()
+        self.cache_objs_mem_used() + self.caches.memory_used_index() + self.waitlist.memory_used_index()
     }
 
     /// The total memory used by the CacheObjects of this Pack
@@ -486,8 +1513,18 @@ impl Pack {
     /// Rebuild the Delta Object in a new thread & process the objects waiting for it recursively.
     /// <br> This function must be *static*, because [&self] can't be moved into a new thread.
     fn process_delta(shared_params: Arc<SharedParams>, delta_obj: CacheObject, base_obj: Arc<CacheObject>) {
+        if base_obj.delta_depth >= shared_params.max_delta_depth {
+            shared_params.fail(GitError::DeltaObjectError(format!(
+                "The delta chain of the object at offset {} is longer than the limit {}",
+                delta_obj.offset, shared_params.max_delta_depth
+            )));
+            return;
+        }
         shared_params.pool.clone().execute(move || {
-            let mut new_obj = Pack::rebuild_delta(delta_obj, base_obj);
+            let mut new_obj = match Pack::rebuild_delta(delta_obj, base_obj) {
+                Ok(new_obj) => new_obj,
+                Err(e) => return shared_params.fail(e),
+            };
             new_obj.set_mem_recorder(shared_params.cache_objs_mem_size.clone());
             new_obj.record_mem_size();
             Self::cache_obj_and_process_waitlist(shared_params, new_obj); //Indirect Recursion
@@ -497,6 +1534,7 @@ impl Pack {
     /// Cache the new object & process the objects waiting for it (in multi-threading).
     fn cache_obj_and_process_waitlist(shared_params: Arc<SharedParams>, new_obj: CacheObject) {
         (shared_params.callback)(new_obj.to_entry());
+        shared_params.counters.record(&new_obj);
         let new_obj = shared_params.caches.insert(new_obj.offset, new_obj.hash, new_obj);
         Self::process_waitlist(shared_params, new_obj);
     }
@@ -510,23 +1548,29 @@ impl Pack {
     }
 
     /// Reconstruct the Delta Object based on the "base object"
-    /// and return a New object.
-    pub fn rebuild_delta(delta_obj: CacheObject, base_obj: Arc<CacheObject>) -> CacheObject {
+    /// and return a New object, or a [`GitError::DeltaObjectError`] when the delta does not
+    /// apply to the base.
+    pub fn rebuild_delta(delta_obj: CacheObject, base_obj: Arc<CacheObject>) -> Result<CacheObject, GitError> {
         const COPY_INSTRUCTION_FLAG: u8 = 1 << 7;
         const COPY_OFFSET_BYTES: u8 = 4;
         const COPY_SIZE_BYTES: u8 = 3;
         const COPY_ZERO_SIZE: usize = 0x10000;
 
+        let invalid = |message: String| {
+            GitError::DeltaObjectError(format!("The delta at offset {}: {}", delta_obj.offset, message))
+        };
         let mut stream = Cursor::new(&delta_obj.data_decompress);
 
         // Read the base object size & Result Size
         // (Size Encoding)
-        let base_size = utils::read_varint_le(&mut stream).unwrap().0;
-        let result_size = utils::read_varint_le(&mut stream).unwrap().0;
+        let base_size = utils::read_varint_le(&mut stream).map_err(|e| invalid(e.to_string()))?.0;
+        let result_size = utils::read_varint_le(&mut stream).map_err(|e| invalid(e.to_string()))?.0;
 
         //Get the base object row data
         let base_info = &base_obj.data_decompress;
-        assert_eq!(base_info.len() as u64, base_size);
+        if base_info.len() as u64 != base_size {
+            return Err(invalid(format!("the base has {} bytes, not {}", base_info.len(), base_size)));
+        }
 
         let mut result = Vec::with_capacity(result_size as usize);
 
@@ -535,27 +1579,19 @@ impl Pack {
             let instruction = match utils::read_bytes(&mut stream) {
                 Ok([instruction]) => instruction,
                 Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
-                Err(err) => {
-                    panic!(
-                        "{}",
-                        GitError::DeltaObjectError(format!("Wrong instruction in delta :{}", err))
-                    );
-                }
+                Err(err) => return Err(invalid(format!("wrong instruction: {}", err))),
             };
 
             if instruction & COPY_INSTRUCTION_FLAG == 0 {
                 // Data instruction; the instruction byte specifies the number of data bytes
                 if instruction == 0 {
                     // Appending 0 bytes doesn't make sense, so git disallows it
-                    panic!(
-                        "{}",
-                        GitError::DeltaObjectError(String::from("Invalid data instruction"))
-                    );
+                    return Err(invalid(String::from("invalid data instruction")));
                 }
 
                 // Append the provided bytes
                 let mut data = vec![0; instruction as usize];
-                stream.read_exact(&mut data).unwrap();
+                stream.read_exact(&mut data).map_err(|e| invalid(e.to_string()))?;
                 result.extend_from_slice(&data);
             } else {
                 // Copy instruction
@@ -563,61 +1599,83 @@ impl Pack {
                 // | 1xxxxxxx | offset1 | offset2 | offset3 | offset4 | size1 | size2 | size3 |
                 // +----------+---------+---------+---------+---------+-------+-------+-------+
                 let mut nonzero_bytes = instruction;
-                let offset = utils::read_partial_int(&mut stream, COPY_OFFSET_BYTES, &mut nonzero_bytes).unwrap();
-                let mut size = utils::read_partial_int(&mut stream, COPY_SIZE_BYTES, &mut nonzero_bytes).unwrap();
+                let offset = utils::read_partial_int(&mut stream, COPY_OFFSET_BYTES, &mut nonzero_bytes)
+                    .map_err(|e| invalid(e.to_string()))?;
+                let mut size = utils::read_partial_int(&mut stream, COPY_SIZE_BYTES, &mut nonzero_bytes)
+                    .map_err(|e| invalid(e.to_string()))?;
                 if size == 0 {
                     // Copying 0 bytes doesn't make sense, so git assumes a different size
                     size = COPY_ZERO_SIZE;
                 }
                 // Copy bytes from the base object
-                let base_data = base_info.get(offset..(offset + size)).ok_or_else(|| {
-                    GitError::DeltaObjectError("Invalid copy instruction".to_string())
-                });
-
-                match base_data {
-                    Ok(data) => result.extend_from_slice(data),
-                    Err(e) => panic!("{}", e),
-                }
+                let data = base_info
+                    .get(offset..(offset + size))
+                    .ok_or_else(|| invalid(String::from("invalid copy instruction")))?;
+                result.extend_from_slice(data);
             }
         }
-        assert_eq!(result_size, result.len() as u64);
+        if result_size != result.len() as u64 {
+            return Err(invalid(format!("rebuilt {} bytes, not {}", result.len(), result_size)));
+        }
 
         let hash = utils::calculate_object_hash(base_obj.obj_type, &result);
         // create new obj from `delta_obj` & `result` instead of modifying `delta_obj` for heap-size recording
-        CacheObject {
+        Ok(CacheObject {
             data_decompress: result,
             obj_type: base_obj.obj_type, // Same as the Type of base object
             hash,
+            delta_depth: base_obj.delta_depth + 1,
             mem_recorder: None, // This filed(Arc) can't be moved from `delta_obj` by `struct update syntax`
             ..delta_obj // This syntax is actually move `delta_obj` to `new_obj`
-        } // Canonical form (Complete Object)
+        }) // Canonical form (Complete Object)
         // mem_size recorder will be set later outside, to keep this func param clear
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::{HashMap, HashSet};
     use std::fs;
     use std::io::prelude::*;
-    use std::io::BufReader;
-    use std::io::Cursor;
-    use std::{env, path::PathBuf};
+    use std::io::{self, Cursor};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
 
     use flate2::write::ZlibEncoder;
     use flate2::Compression;
-
-    use crate::internal::pack::Pack;
+    use proptest::prelude::*;
+    use tokio_util::sync::CancellationToken;
+    use venus::errors::GitError;
+    use venus::hash::SHA1;
+    use venus::internal::object::types::ObjectType;
+    use venus::internal::pack::entry::Entry;
+
+    use crate::internal::pack::cache::MemoryCache;
+    use crate::internal::pack::cache_object::CacheObject;
+    use crate::internal::pack::lanes::{Lane, Lanes};
+    use crate::internal::pack::{utils, Pack};
+    use crate::test_support::{Corruption, DeltaKind, FixtureConfig, SyntheticRepo};
+
+    /// About 3000 objects with long delta chains, stands in for a real world pack.
+    fn large_pack() -> Vec<u8> {
+        SyntheticRepo::generate(&FixtureConfig {
+            commits: 1000,
+            files: 64,
+            blob_size: 4096,
+            delta_depth: 50,
+            ..Default::default()
+        })
+        .to_pack()
+    }
 
     #[test]
     fn test_pack_check_header() {
-        let mut source = PathBuf::from(env::current_dir().unwrap().parent().unwrap());
-        source.push("tests/data/packs/git-2d187177923cd618a75da6c6db45bb89d92bd504.pack");
+        let repo = SyntheticRepo::generate(&FixtureConfig::default());
+        let mut reader = Cursor::new(repo.to_pack());
+        let (object_num, _) = Pack::check_header(&mut reader).unwrap();
 
-        let f = std::fs::File::open(source).unwrap();
-        let mut buf_reader = BufReader::new(f);
-        let (object_num, _) = Pack::check_header(&mut buf_reader).unwrap();
-
-        assert_eq!(object_num, 358109);
+        assert_eq!(object_num as usize, repo.objects.len());
     }
 
     #[test]
@@ -644,41 +1702,576 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_decode_pack_object_crc32() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            ..Default::default()
+        });
+        let pack = repo.to_pack();
+
+        // the CRC32 of the bytes of every object
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+        let mut reader = Cursor::new(pack.clone());
+        let (number, _) = Pack::check_header(&mut reader).unwrap();
+        let mut offset = 12;
+        let mut crcs = HashSet::new();
+        for _ in 0..number {
+            let start = offset;
+            let obj = p.decode_pack_object(&mut reader, &mut offset).unwrap();
+            let mut crc = flate2::Crc::new();
+            crc.update(&pack[start..offset]);
+            assert_eq!(obj.crc32, crc.sum());
+            crcs.insert(obj.crc32);
+        }
+
+        // the objects rebuilt from a delta keep it, the async decode gives the same
+        let decoded = Arc::new(Mutex::new(HashMap::new()));
+        let entries = decoded.clone();
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+        p.decode(&mut Cursor::new(pack.clone()), move |entry: Entry| {
+            entries.lock().unwrap().insert(entry.hash, entry.crc32);
+        }).unwrap();
+        let decoded = Arc::try_unwrap(decoded).unwrap().into_inner().unwrap();
+        assert_eq!(decoded.len(), repo.objects.len());
+        assert!(decoded.values().all(|crc| crc.is_some_and(|crc| crcs.contains(&crc))));
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let p = Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+        let handle = runtime.spawn(async move {
+            p.decode_stream_async(Cursor::new(pack), sender).await
+        });
+        runtime.block_on(handle).unwrap().unwrap().unwrap();
+        let streamed: HashMap<SHA1, Option<u32>> = receiver.iter().map(|entry| (entry.hash, entry.crc32)).collect();
+        assert_eq!(streamed, decoded);
+    }
+
     #[test]
     fn test_pack_decode_without_delta() {
-        let mut source = PathBuf::from(env::current_dir().unwrap().parent().unwrap());
-        source.push("tests/data/packs/pack-1d0e6c14760c956c173ede71cb28f33d921e232f.pack");
+        let pack = SyntheticRepo::generate(&FixtureConfig::default()).to_pack();
 
         let tmp = PathBuf::from("/tmp/.cache_temp");
 
-        let f = std::fs::File::open(source).unwrap();
-        let mut buffered = BufReader::new(f);
+        let mut buffered = Cursor::new(pack);
         let mut p = Pack::new(None, Some(1024*1024*20), Some(tmp));
         p.decode(&mut buffered, |_|{}).unwrap();
     }
 
+    #[test]
+    fn test_pack_decode_stats() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            ..Default::default()
+        });
+        let pack = repo.to_pack();
+        // a small limit, so that objects are spilled to disk
+        let mut p = Pack::new(None, Some(1024*16), Some(PathBuf::from("/tmp/.cache_temp")));
+        let stats = p.decode(&mut Cursor::new(pack.clone()), |_|{}).unwrap();
+
+        let count = |t: ObjectType| repo.objects.iter().filter(|entry| entry.obj_type == t).count();
+        assert_eq!(stats.commits, count(ObjectType::Commit));
+        assert_eq!(stats.trees, count(ObjectType::Tree));
+        assert_eq!(stats.blobs, count(ObjectType::Blob));
+        assert_eq!(stats.tags, 0);
+        assert_eq!(stats.offset_deltas, repo.delta_count());
+        assert_eq!(stats.hash_deltas, 0);
+        let depth = |mut i: usize| {
+            let mut depth = 0;
+            while let Some(base) = repo.bases[i] {
+                depth += 1;
+                i = base;
+            }
+            depth
+        };
+        let max_depth = (0..repo.objects.len()).map(depth).max().unwrap();
+        assert!(max_depth > 1);
+        assert_eq!(stats.max_delta_depth, max_depth);
+        assert_eq!(stats.bytes_read, pack.len());
+        assert!(stats.peak_memory > 0);
+        assert!(stats.spilled_bytes > 0);
+    }
+
+    #[test]
+    fn test_pack_decode_limits() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            ..Default::default()
+        });
+        let pack = repo.to_pack();
+        let decode = |p: Pack| {
+            let mut p = p;
+            let result = p.decode(&mut Cursor::new(pack.clone()), |_| {});
+            // nothing is left behind by a refused pack
+            assert_eq!(p.cache_objs_mem_used(), 0);
+            result
+        };
+        let new_pack = || Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+
+        let stats = decode(new_pack().with_max_delta_depth(10)).unwrap();
+        assert!(stats.max_delta_depth <= 10);
+        let result = decode(new_pack().with_max_delta_depth(stats.max_delta_depth - 1));
+        assert!(matches!(result, Err(GitError::DeltaObjectError(_))), "{:?}", result);
+
+        let largest = repo.objects.iter().map(|entry| entry.data.len()).max().unwrap();
+        assert!(decode(new_pack().with_max_object_size(largest)).is_ok());
+        let result = decode(new_pack().with_max_object_size(largest - 1));
+        assert!(matches!(result, Err(GitError::InvalidPackFile(_) | GitError::DeltaObjectError(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn test_reserve_delta_data() {
+        let varint = |mut value: u64| {
+            let mut bytes = Vec::new();
+            while value >= 0x80 {
+                bytes.push((value as u8) | 0x80);
+                value >>= 7;
+            }
+            bytes.push(value as u8);
+            bytes
+        };
+        let pack = Pack::new(None, Some(1024 * 1024 * 20), Some(PathBuf::from("/tmp/.cache_temp")));
+
+        // a small delta is given the capacity of its result
+        let mut delta = varint(10);
+        delta.extend(varint(4096));
+        let data = pack.reserve_delta_data(delta.clone(), 12).unwrap();
+        assert_eq!(data, delta);
+        assert!(data.capacity() >= 4096);
+
+        // a few bytes claiming a terabyte do not reserve it
+        let mut delta = varint(10);
+        delta.extend(varint(1 << 40));
+        let data = pack.reserve_delta_data(delta.clone(), 12).unwrap();
+        assert_eq!(data, delta);
+        assert!(data.capacity() <= delta.len() + super::MAX_DELTA_RESERVE);
+    }
+
+    #[test]
+    fn test_pack_decode_with_index() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            ..Default::default()
+        });
+        let pack = repo.to_pack();
+        let dir = PathBuf::from("/tmp/.cache_temp").join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let index_path = dir.join("pack.idx");
+
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+        p.decode_with_index(&mut Cursor::new(pack.clone()), &index_path, |_|{}).unwrap();
+        let index = fs::read(&index_path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let total = repo.objects.len();
+        let fanout_end = 8 + 256 * 4;
+        assert_eq!(&index[..8], b"\xfftOc\0\0\0\x02");
+        let read_u32 = |at: usize| u32::from_be_bytes(index[at..at + 4].try_into().unwrap());
+        assert_eq!(read_u32(fanout_end - 4) as usize, total);
+        assert_eq!(index.len(), fanout_end + total * 28 + 40);
+        assert_eq!(&index[index.len() - 40..index.len() - 20], &pack[pack.len() - 20..]);
+
+        let mut hashes: Vec<[u8; 20]> = repo.objects.iter().map(|entry| entry.hash.0).collect();
+        hashes.sort();
+        let names: Vec<[u8; 20]> = index[fanout_end..fanout_end + total * 20]
+            .chunks(20)
+            .map(|name| name.try_into().unwrap())
+            .collect();
+        assert_eq!(names, hashes);
+
+        // the first object of the pack starts right after the header
+        let offsets = fanout_end + total * 24;
+        let first = names.iter().position(|name| *name == repo.objects[0].hash.0).unwrap();
+        assert_eq!(read_u32(offsets + first * 4), 12);
+    }
+
+    #[test]
+    fn test_pack_decode_mmap() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            ..Default::default()
+        });
+        let mut pack = repo.to_pack();
+        let dir = PathBuf::from("/tmp/.cache_temp").join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pack.pack");
+        fs::write(&path, &pack).unwrap();
+
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+        let expected = p.decode(&mut Cursor::new(pack.clone()), |_| {}).unwrap();
+        let decoded = Arc::new(AtomicUsize::new(0));
+        let counter = decoded.clone();
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+        let stats = p.decode_mmap(&path, move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }).unwrap();
+        assert_eq!(decoded.load(Ordering::Relaxed), repo.objects.len());
+        assert_eq!((stats.commits, stats.trees, stats.blobs), (expected.commits, expected.trees, expected.blobs));
+        assert_eq!(stats.bytes_read, pack.len());
+        assert_eq!(p.signature, SHA1::from_bytes(&pack[pack.len() - 20..]));
+
+        // like a file, the mapping must end with the pack
+        pack.push(0);
+        fs::write(&path, &pack).unwrap();
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+        assert!(p.decode_mmap(&path, |_| {}).is_err());
+        assert!(p.decode_mmap(&dir.join("missing.pack"), |_| {}).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A socket: data comes in small pieces, nothing can be read twice and the peer may keep
+    /// the connection open after the pack.
+    struct Trickle {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(7).min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_pack_decode_stream() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            ..Default::default()
+        });
+        let mut data = repo.to_pack();
+        data.extend_from_slice(b"0000");
+        let decoded = Arc::new(AtomicUsize::new(0));
+        let counter = decoded.clone();
+
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+        p.decode_stream(&mut Trickle { data: data.clone(), pos: 0 }, move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }).unwrap();
+        assert_eq!(decoded.load(Ordering::Relaxed), repo.objects.len());
+
+        // a damaged trailer is still detected
+        let trailer = data.len() - 5;
+        data[trailer] ^= 1;
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+        assert!(p.decode_stream(&mut Trickle { data, pos: 0 }, |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_pack_decode_stream_async() {
+        let repo = SyntheticRepo::generate(&FixtureConfig::default());
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        let p = Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+        let handle = runtime.spawn(async move {
+            p.decode_stream_async(Cursor::new(repo.to_pack()), sender).await
+        });
+        let pack = runtime.block_on(handle).unwrap().unwrap().unwrap();
+        assert_eq!(pack.number, receiver.iter().count());
+    }
+
+    /// Gives the pack a few bytes at a time, like a slow client.
+    struct TrickleReader(Cursor<Vec<u8>>);
+
+    impl tokio::io::AsyncRead for TrickleReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            let mut chunk = [0; 7];
+            let n = self.0.read(&mut chunk)?;
+            buf.put_slice(&chunk[..n]);
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_pack_decode_tokio() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let decode = |pack: Vec<u8>, mem_limit: usize| {
+            let (sender, mut receiver) = tokio::sync::mpsc::channel::<Entry>(16);
+            let mut p = Pack::new(Some(2), Some(mem_limit), Some(PathBuf::from("/tmp/.cache_temp")));
+            let (result, mut hashes) = runtime.block_on(async {
+                let receive = async {
+                    let mut hashes = Vec::new();
+                    while let Some(entry) = receiver.recv().await {
+                        hashes.push(entry.hash);
+                    }
+                    hashes
+                };
+                tokio::join!(p.decode_tokio(TrickleReader(Cursor::new(pack)), sender), receive)
+            });
+            // nothing is left behind, even by a refused pack
+            assert_eq!(p.cache_objs_mem_used(), 0);
+            hashes.sort();
+            (result, hashes)
+        };
+
+        for delta_kind in [DeltaKind::Offset, DeltaKind::Ref] {
+            let repo = SyntheticRepo::generate(&FixtureConfig {
+                delta_depth: 10,
+                delta_kind,
+                ..Default::default()
+            });
+            let pack = repo.to_pack();
+            let mut p = Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+            let expected = p.decode(&mut Cursor::new(pack.clone()), |_| {}).unwrap();
+            // a small limit, so that objects are spilled to disk
+            let (stats, hashes) = decode(pack.clone(), 1024*16);
+            let stats = stats.unwrap();
+            let mut expected_hashes: Vec<_> = repo.objects.iter().map(|entry| entry.hash).collect();
+            expected_hashes.sort();
+            assert_eq!(hashes, expected_hashes);
+            assert_eq!((stats.commits, stats.trees, stats.blobs), (expected.commits, expected.trees, expected.blobs));
+            assert_eq!((stats.offset_deltas, stats.hash_deltas), (expected.offset_deltas, expected.hash_deltas));
+            assert_eq!(stats.max_delta_depth, expected.max_delta_depth);
+            assert_eq!(stats.bytes_read, pack.len());
+
+            let (result, _) = decode(pack[..pack.len() / 2].to_vec(), 1024*1024*20);
+            assert!(result.is_err());
+        }
+
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            delta_kind: DeltaKind::Ref,
+            ..Default::default()
+        });
+        let (thin, _) = repo.to_thin_pack();
+        let (result, _) = decode(thin, 1024*1024*20);
+        assert!(matches!(result, Err(GitError::InvalidPackFile(_))), "{:?}", result);
+        let (result, _) = decode(repo.to_corrupted_pack(Corruption::Signature), 1024*1024*20);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_pack_decode_tokio_in_lanes() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let lanes = Arc::new(Lanes::new(2, 1));
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            ..Default::default()
+        });
+        let pack = repo.to_pack();
+        let mut expected: Vec<_> = repo.objects.iter().map(|entry| entry.hash).collect();
+        expected.sort();
+        let decode = |lane: Lane| {
+            let mut p = Pack::new(Some(2), Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")))
+                .with_lanes(lanes.clone(), lane);
+            let pack = pack.clone();
+            async move {
+                let (sender, mut receiver) = tokio::sync::mpsc::channel::<Entry>(16);
+                let receive = async {
+                    let mut hashes = Vec::new();
+                    while let Some(entry) = receiver.recv().await {
+                        hashes.push(entry.hash);
+                    }
+                    hashes
+                };
+                let (result, mut hashes) = tokio::join!(p.decode_tokio(TrickleReader(Cursor::new(pack)), sender), receive);
+                result.unwrap();
+                hashes.sort();
+                hashes
+            }
+        };
+        // a large pack and a small one decoded together, each in its own lane
+        let (batch, interactive) = runtime.block_on(async { tokio::join!(decode(Lane::Batch), decode(Lane::Interactive)) });
+        assert_eq!(batch, expected);
+        assert_eq!(interactive, expected);
+    }
+
+    #[test]
+    fn test_pack_decode_with_memory_cache() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            ..Default::default()
+        });
+        let decoded = Arc::new(AtomicUsize::new(0));
+        let counter = decoded.clone();
+        // a memory cache never lets objects go, the limit cannot hold the decode forever
+        let mut p = Pack::new_with_cache(None, Some(1024), Arc::new(MemoryCache::default()));
+        p.decode(&mut Cursor::new(repo.to_pack()), move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }).unwrap();
+        assert_eq!(decoded.load(Ordering::Relaxed), repo.objects.len());
+        assert_eq!(p.caches.total_inserted(), 0);
+    }
+
+    #[test]
+    fn test_pack_decode_with_cancel() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            ..Default::default()
+        });
+        let pack = repo.to_pack();
+        let tmp = PathBuf::from("/tmp/.cache_temp").join(uuid::Uuid::new_v4().to_string());
+
+        /// Cancels the decode once half of the pack is read.
+        struct Aborted {
+            inner: Trickle,
+            cancel: CancellationToken,
+        }
+
+        impl Read for Aborted {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.inner.pos >= self.inner.data.len() / 2 {
+                    self.cancel.cancel();
+                }
+                self.inner.read(buf)
+            }
+        }
+
+        let cancel = CancellationToken::new();
+        let mut reader = io::BufReader::new(Aborted {
+            inner: Trickle { data: pack, pos: 0 },
+            cancel: cancel.clone(),
+        });
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(tmp.clone()));
+        let result = p.decode_with_cancel(&mut reader, |_| {}, cancel);
+        match result {
+            Err(GitError::PackDecodeCancelled(unread)) => assert!(unread > 0 && unread < repo.objects.len()),
+            other => panic!("the decode is not cancelled: {:?}", other),
+        }
+        assert_eq!(p.pool.pending(), 0);
+        assert_eq!(p.waitlist.map_offset.len() + p.waitlist.map_ref.len(), 0);
+        // the temporary files of the cache are removed
+        assert_eq!(fs::read_dir(&tmp).unwrap().count(), 0);
+        fs::remove_dir_all(&tmp).unwrap();
+
+        let cancel = CancellationToken::new();
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(PathBuf::from("/tmp/.cache_temp")));
+        p.decode_with_cancel(&mut Cursor::new(repo.to_pack()), |_| {}, cancel).unwrap();
+    }
+
+    #[test]
+    fn test_pack_decode_resume() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            ..Default::default()
+        });
+        let pack = repo.to_pack();
+        let checkpoint = PathBuf::from("/tmp/.cache_temp").join(uuid::Uuid::new_v4().to_string());
+        let decoded = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let record = |decoded: &Arc<std::sync::Mutex<Vec<SHA1>>>| {
+            let decoded = decoded.clone();
+            move |entry: Entry| decoded.lock().unwrap().push(entry.hash)
+        };
+
+        // the process stops two thirds through the pack, a small limit spills the objects
+        let mut p = Pack::new(None, Some(1024*16), Some(PathBuf::from("/tmp/.cache_temp")))
+            .with_checkpoint(checkpoint.clone(), 16);
+        let truncated = &pack[..pack.len() * 2 / 3];
+        assert!(p.decode(&mut Cursor::new(truncated), record(&decoded)).is_err());
+        assert!(checkpoint.join("checkpoint").exists());
+        let before = decoded.lock().unwrap().len();
+        assert!(before > 0);
+
+        // another pack is refused, the checkpoint is kept
+        let other = SyntheticRepo::generate(&FixtureConfig {
+            seed: 7,
+            ..Default::default()
+        }).to_pack();
+        let mut p = Pack::new(None, Some(1024*16), Some(PathBuf::from("/tmp/.cache_temp")));
+        assert!(p.resume(&checkpoint, &mut Cursor::new(other), |_| {}).is_err());
+
+        let resumed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut p = Pack::new(None, Some(1024*16), Some(PathBuf::from("/tmp/.cache_temp")));
+        let stats = p.resume(&checkpoint, &mut Cursor::new(pack.clone()), record(&resumed)).unwrap();
+        let full = Pack::new(None, None, Some(PathBuf::from("/tmp/.cache_temp")))
+            .decode(&mut Cursor::new(pack.clone()), |_| {})
+            .unwrap();
+        assert_eq!(stats.commits, full.commits);
+        assert_eq!(stats.trees, full.trees);
+        assert_eq!(stats.blobs, full.blobs);
+        assert_eq!(stats.offset_deltas, full.offset_deltas);
+        assert_eq!(stats.max_delta_depth, full.max_delta_depth);
+        assert_eq!(stats.bytes_read, pack.len());
+        // the objects before the checkpoint are not decoded again
+        let resumed = resumed.lock().unwrap();
+        assert!(resumed.len() < repo.objects.len());
+        let mut all: Vec<SHA1> = decoded.lock().unwrap().iter().chain(resumed.iter()).copied().collect();
+        all.sort();
+        all.dedup();
+        let mut expected: Vec<SHA1> = repo.objects.iter().map(|entry| entry.hash).collect();
+        expected.sort();
+        expected.dedup();
+        assert_eq!(all, expected);
+        assert!(!checkpoint.exists());
+    }
+
+    #[test]
+    fn test_pack_decode_thin() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            delta_kind: DeltaKind::Ref,
+            ..Default::default()
+        });
+        let (pack, left_out) = repo.to_thin_pack();
+        assert!(!left_out.is_empty());
+        let tmp = PathBuf::from("/tmp/.cache_temp");
+
+        // a base missing from the pack is an error, not a panic
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(tmp.clone()));
+        assert!(p.decode(&mut Cursor::new(pack.clone()), |_| {}).is_err());
+
+        let database: HashMap<SHA1, Vec<u8>> = left_out
+            .iter()
+            .map(|entry| {
+                let mut data = format!("{} {}\0", entry.obj_type, entry.data.len()).into_bytes();
+                data.extend_from_slice(&entry.data);
+                (entry.hash, data)
+            })
+            .collect();
+        let dir = tmp.join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let fixed = dir.join("fixed.pack");
+        let decoded = Arc::new(AtomicUsize::new(0));
+        let counter = decoded.clone();
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(tmp.clone()));
+        p.decode_thin(&mut Cursor::new(pack.clone()), |hash| database.get(&hash).cloned(), Some(&fixed), move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }).unwrap();
+        assert_eq!(decoded.load(Ordering::Relaxed), repo.objects.len() - left_out.len());
+
+        // the completed pack has every object and stands on its own
+        let decoded = Arc::new(AtomicUsize::new(0));
+        let counter = decoded.clone();
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(tmp.clone()));
+        p.decode(&mut Cursor::new(fs::read(&fixed).unwrap()), move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }).unwrap();
+        assert_eq!(decoded.load(Ordering::Relaxed), repo.objects.len());
+
+        // a base that is not the object asked for is refused
+        let mut p = Pack::new(None, Some(1024*1024*20), Some(tmp));
+        let wrong = database.values().next().unwrap().clone();
+        assert!(p.decode_thin(&mut Cursor::new(pack), |_| Some(wrong.clone()), None, |_| {}).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_pack_decode_with_ref_delta() {
-        let mut source = PathBuf::from(env::current_dir().unwrap().parent().unwrap());
-        source.push("tests/data/packs/ref-delta-65d47638aa7cb7c39f1bd1d5011a415439b887a8.pack");
+        let pack = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            delta_kind: DeltaKind::Ref,
+            ..Default::default()
+        }).to_pack();
 
         let tmp = PathBuf::from("/tmp/.cache_temp");
 
-        let f = std::fs::File::open(source).unwrap();
-        let mut buffered = BufReader::new(f);
+        let mut buffered = Cursor::new(pack);
         let mut p = Pack::new(None, Some(1024*1024*20), Some(tmp));
         p.decode(&mut buffered,|_|{}).unwrap();
     }
 
     #[test]
     fn test_pack_decode_with_large_file_with_delta_without_ref() {
-        let mut source = PathBuf::from(env::current_dir().unwrap().parent().unwrap());
-        source.push("tests/data/packs/git-2d187177923cd618a75da6c6db45bb89d92bd504.pack");
-
         let tmp = PathBuf::from("/tmp/.cache_temp");
 
-        let f = std::fs::File::open(source).unwrap();
-        let mut buffered = BufReader::new(f);
+        let mut buffered = Cursor::new(large_pack());
         // let mut p = Pack::default(); //Pack::new(2);
         let mut p = Pack::new(Some(20), Some(1024*1024*1024*2), Some(tmp.clone()));
         let rt = p.decode(&mut buffered, |_obj|{
@@ -692,12 +2285,8 @@ mod tests {
 
     #[test]
     fn test_decode_large_file_async() {
-        let mut source = PathBuf::from(env::current_dir().unwrap().parent().unwrap());
-        source.push("tests/data/packs/git-2d187177923cd618a75da6c6db45bb89d92bd504.pack");
-
         let tmp = PathBuf::from("/tmp/.cache_temp");
-        let f = fs::File::open(source).unwrap();
-        let buffered = BufReader::new(f);
+        let buffered = Cursor::new(large_pack());
         let p = Pack::new(Some(20), Some(1024*1024*1024*2), Some(tmp.clone()));
 
         let (tx, rx) = std::sync::mpsc::channel();
@@ -712,13 +2301,14 @@ mod tests {
 
     #[test]
     fn test_pack_decode_with_delta_without_ref() {
-        let mut source = PathBuf::from(env::current_dir().unwrap().parent().unwrap());
-        source.push("tests/data/packs/pack-d50df695086eea6253a237cb5ac44af1629e7ced.pack");
+        let pack = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            ..Default::default()
+        }).to_pack();
 
         let tmp = PathBuf::from("/tmp/.cache_temp");
 
-        let f = std::fs::File::open(source).unwrap();
-        let mut buffered = BufReader::new(f);
+        let mut buffered = Cursor::new(pack);
         let mut p = Pack::new(None, Some(1024*1024*20), Some(tmp));
         p.decode(&mut buffered, |_|{}).unwrap();
     }
@@ -735,4 +2325,115 @@ mod tests {
         task1.join().unwrap();
         task2.join().unwrap();
     }
+    /// `base` with `cut` bytes removed at `at` and `insert` spliced in, like an edited file
+    fn edit(base: &[u8], at: prop::sample::Index, cut: usize, insert: &[u8]) -> Vec<u8> {
+        let at = at.index(base.len() + 1);
+        let end = (at + cut).min(base.len());
+        [&base[..at], insert, &base[end..]].concat()
+    }
+
+    fn assert_rebuild(base: Vec<u8>, target: Vec<u8>) -> Result<(), TestCaseError> {
+        // `CacheObject` implements `Drop`, so no struct update syntax here
+        let mut delta = CacheObject::default();
+        delta.data_decompress = delta::encode(&base, &target);
+        delta.obj_type = ObjectType::OffsetDelta;
+        let base = Arc::new(CacheObject::new_for_undeltified(ObjectType::Blob, base, 0));
+        let rebuilt = Pack::rebuild_delta(delta, base).unwrap();
+        prop_assert_eq!(rebuilt.obj_type, ObjectType::Blob);
+        prop_assert_eq!(rebuilt.hash, utils::calculate_object_hash(ObjectType::Blob, &target));
+        prop_assert_eq!(&rebuilt.data_decompress, &target);
+        Ok(())
+    }
+
+    /// A field of `/proc/self/status` in bytes, e.g. `VmHWM`, the peak resident set size.
+    #[cfg(target_os = "linux")]
+    fn proc_status_bytes(field: &str) -> usize {
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        let kb: usize = status
+            .lines()
+            .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+            .and_then(|value| value.trim().trim_end_matches(" kB").parse().ok())
+            .unwrap();
+        kb * 1024
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pack_decode_under_rss_cap() {
+        let mem_limit = 4 * 1024 * 1024;
+        // decoded in a process of its own, which allocates nothing else meanwhile
+        let Some(path) = std::env::var_os("MERCURY_RSS_CAP_PACK") else {
+            let repo = SyntheticRepo::generate(&FixtureConfig {
+                commits: 400,
+                files: 64,
+                blob_size: 65536,
+                delta_depth: 50,
+                ..Default::default()
+            });
+            let decoded: usize = repo.objects.iter().map(|o| o.data.len()).sum();
+            assert!(decoded > 4 * mem_limit);
+            let dir = PathBuf::from("/tmp/.cache_temp").join(uuid::Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("pack.pack");
+            fs::write(&path, repo.to_pack()).unwrap();
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["internal::pack::decode::tests::test_pack_decode_under_rss_cap", "--exact", "--nocapture"])
+                .env("MERCURY_RSS_CAP_PACK", &path)
+                .output()
+                .unwrap();
+            fs::remove_dir_all(&dir).unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return;
+        };
+
+        let mut p = Pack::new(None, Some(mem_limit), Some(PathBuf::from("/tmp/.cache_temp")));
+        let mut reader = io::BufReader::new(fs::File::open(path).unwrap());
+        // the peak is reset to the resident set of now
+        fs::write("/proc/self/clear_refs", "5").unwrap();
+        let baseline = proc_status_bytes("VmHWM");
+        let stats = p.decode(&mut reader, |_| {}).unwrap();
+        let peak = proc_status_bytes("VmHWM") - baseline;
+        assert!(
+            stats.peak_memory <= mem_limit,
+            "accounted {} over the limit {}",
+            stats.peak_memory,
+            mem_limit
+        );
+        // besides the objects: the threads, the code run for the first time, and the pages the
+        // allocator keeps for every size class and for a while once freed
+        let cap = 2 * mem_limit + 8 * 1024 * 1024;
+        assert!(peak <= cap, "resident {} over the cap {}", peak, cap);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_rebuild_delta_of_edit(
+            base in proptest::collection::vec(any::<u8>(), 1..4096),
+            at in any::<prop::sample::Index>(),
+            cut in 0..512usize,
+            insert in proptest::collection::vec(any::<u8>(), 0..512),
+        ) {
+            let target = edit(&base, at, cut, &insert);
+            prop_assume!(!target.is_empty());
+            assert_rebuild(base, target)?;
+        }
+
+        #[test]
+        fn prop_rebuild_delta_of_unrelated_data(
+            base in proptest::collection::vec(any::<u8>(), 1..1024),
+            target in proptest::collection::vec(any::<u8>(), 1..1024),
+        ) {
+            assert_rebuild(base, target)?;
+        }
+
+        #[test]
+        fn prop_decode_pack_object_never_panics(data in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let mut p = Pack::new(Some(1), None, Some(PathBuf::from("/tmp/.cache_temp")));
+            let _ = p.decode_pack_object(&mut Cursor::new(data), &mut 12);
+        }
+    }
 }
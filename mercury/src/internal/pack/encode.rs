@@ -1,123 +1,216 @@
 //!
-//!
+//! Encodes entries into a v2 pack file, the objects served by `git clone` and `git fetch`.
+//! Each entry is compared with the entries before it in a sliding window, and stored as an
+//! offset delta of the most similar one when the delta is small enough.
 //!
 
 use flate2::write::ZlibEncoder;
-use sha1::{Digest, Sha1};
 use std::collections::VecDeque;
 use std::{io::Write, sync::mpsc};
+use venus::hash::{HashKind, Hasher};
 use venus::internal::object::types::ObjectType;
 use venus::{errors::GitError, hash::SHA1, internal::pack::entry::Entry};
 
+use crate::internal::pack::bitmap::PackBitmap;
+use crate::internal::pack::reader::PackReader;
+
 const MIN_DELTA_RATE: f64 = 0.5; // minimum delta rate can accept
+const MAX_DELTA_DEPTH: usize = 50; // the default `pack.depth` of git, longer chains decode slowly
 
 pub struct PackEncoder<W: Write> {
     object_number: usize,
     process_index: usize,
     window_size: usize,
-    window: VecDeque<(Entry, usize)>, // entry and offset
+    window: VecDeque<(Entry, usize, usize)>, // entry, offset and depth of its delta chain
     writer: W,
     inner_offset: usize, // offset of current entry
-    inner_hash: Sha1,    // Not SHA1 because need update trait
-    final_hash: Option<SHA1>,
+    inner_hash: Hasher,  // the trailer, of the object format of the pack
+    final_hash: Option<Vec<u8>>,
 }
 
 /// encode header of pack file (12 byte)<br>
 /// include: 'PACK', Version(2), number of objects
-fn encode_header(object_number: usize) -> Vec<u8> {
+fn encode_header(object_number: usize) -> Result<Vec<u8>, GitError> {
     let mut result: Vec<u8> = vec![
         b'P', b'A', b'C', b'K', // The logotype of the Pack File
         0, 0, 0, 2, // generates version 2 only.
     ];
-    assert_ne!(object_number, 0); // guarantee self.number_of_objects!=0
-    assert!(object_number < (1 << 32));
-    //TODO: GitError:numbers of objects should < 4G ,
-    result.append((object_number as u32).to_be_bytes().to_vec().as_mut()); // to 4 bytes (network byte order aka. big-endian)
-    result
+    let number = u32::try_from(object_number).map_err(|_| {
+        GitError::InvalidPackHeader(format!("{} objects, a pack holds less than 4G", object_number))
+    })?;
+    result.extend_from_slice(&number.to_be_bytes()); // network byte order aka. big-endian
+    Ok(result)
+}
+
+/// encode the type and the size of an object, the header of every object in the pack
+pub(crate) fn encode_object_header(obj_type_number: u8, obj_data_len: usize) -> Vec<u8> {
+    let mut header_data = vec![(0x80 | (obj_type_number << 4)) + (obj_data_len & 0x0f) as u8];
+    let mut size = obj_data_len >> 4; // 4 bit has been used in first byte
+    if size > 0 {
+        while size > 0 {
+            if size >> 7 > 0 {
+                header_data.push((0x80 | size) as u8);
+                size >>= 7;
+            } else {
+                header_data.push(size as u8);
+                break;
+            }
+        }
+    } else {
+        header_data.push(0);
+    }
+    header_data
 }
 
 /// encode offset of delta object
-fn encode_offset(mut value: usize) -> Vec<u8> {
+pub(crate) fn encode_offset(mut value: usize) -> Vec<u8> {
     assert_ne!(value, 0, "offset can't be zero");
-    let mut bytes = Vec::new();
-    let mut first_byte = true;
-    while value != 0 || first_byte {
-        let mut byte = (value & 0x7F) as u8; // 获取当前值的最低7位
-        value >>= 7; // 右移7位准备处理下一个字节
-        if first_byte {
-            first_byte = false;
-        } else {
-            byte -= 1; // sub 1
-            byte |= 0x80; // set first bit one
-        }
-        bytes.push(byte);
+    let mut bytes = vec![(value & 0x7F) as u8]; // 获取当前值的最低7位
+    value >>= 7; // 右移7位准备处理下一个字节
+    while value != 0 {
+        value -= 1; // sub 1, the decoder adds it back for every continuation
+        bytes.push(0x80 | (value & 0x7F) as u8); // set first bit one
+        value >>= 7;
     }
     bytes.reverse();
     bytes
 }
 
 impl<W: Write> PackEncoder<W> {
-    pub fn new(object_number: usize, window_size: usize, mut writer: W) -> Self {
-        let head = encode_header(object_number);
-        writer.write_all(&head).unwrap();
-        let mut hash = Sha1::new();
-        hash.update(&head);
+    /// The header is written by the first call to [`PackEncoder::encode_entries`].
+    pub fn new(object_number: usize, window_size: usize, writer: W) -> Self {
         PackEncoder {
             object_number,
             window_size,
             process_index: 0,
             window: VecDeque::with_capacity(window_size),
             writer,
-            inner_offset: 12, // 12 bytes header
-            inner_hash: hash,
+            inner_offset: 0,
+            inner_hash: HashKind::Sha1.hasher(),
             final_hash: None,
         }
     }
 
-    /// get the hash of the pack file. if the pack file is not finished, return None
+    /// Sets the object format of the pack, the hash function of its trailer, SHA-1 by default.
+    /// The entries are written as they are, they have to be named in `kind` already, see
+    /// [`venus::internal::object::interop`].
+    pub fn with_hash_kind(mut self, kind: HashKind) -> Self {
+        self.inner_hash = kind.hasher();
+        self
+    }
+
+    /// get the hash of the pack file. if the pack file is not finished, or is not a SHA-1
+    /// pack, return None
     pub fn get_hash(&self) -> Option<SHA1> {
         self.final_hash
+            .as_ref()
+            .filter(|hash| hash.len() == HashKind::Sha1.size())
+            .map(|hash| SHA1::from_bytes(hash))
+    }
+
+    /// The trailer of the pack, in its object format, once it is encoded.
+    pub fn trailer(&self) -> Option<&[u8]> {
+        self.final_hash.as_deref()
     }
 
     /// encode entries to a pack file with delta objects, write to writer
     pub fn encode(&mut self, rx: mpsc::Receiver<Entry>) -> Result<(), GitError> {
-        loop {
-            match rx.recv() {
-                Ok(entry) => {
-                    self.process_index += 1;
-                    // push window after encode to void diff by self
-                    let offset = self.inner_offset;
-                    self.encode_one_object(&entry)?;
-                    self.window.push_back((entry, offset));
-                    if self.window.len() > self.window_size {
-                        self.window.pop_front();
-                    }
-                }
-                Err(_) => {
-                    if self.process_index != self.object_number {
-                        panic!("not all objects are encoded");
-                    }
-                    break;
-                }
+        self.encode_entries(rx)
+    }
+
+    /// Encodes `entries` and the trailing checksum, writing every object as soon as it is
+    /// encoded. Fails when they are not as many as the `object_number` of the header.
+    pub fn encode_entries(
+        &mut self,
+        entries: impl IntoIterator<Item = Entry>,
+    ) -> Result<(), GitError> {
+        if self.final_hash.is_some() {
+            return Err(GitError::UnCompletedPackObject(String::from(
+                "the pack is already encoded",
+            )));
+        }
+        if self.inner_offset == 0 {
+            let head = encode_header(self.object_number)?;
+            self.write_all_and_update(&head)?;
+        }
+        for entry in entries {
+            self.process_index += 1;
+            if self.process_index > self.object_number {
+                return Err(GitError::UnCompletedPackObject(format!(
+                    "more objects than the {} of the header",
+                    self.object_number
+                )));
+            }
+            // push window after encode to void diff by self
+            let offset = self.inner_offset;
+            let depth = self.encode_one_object(&entry)?;
+            self.window.push_back((entry, offset, depth));
+            if self.window.len() > self.window_size {
+                self.window.pop_front();
             }
         }
+        if self.process_index != self.object_number {
+            return Err(GitError::UnCompletedPackObject(format!(
+                "{} of the {} objects of the header",
+                self.process_index, self.object_number
+            )));
+        }
 
         // hash signature
         let hash_result = self.inner_hash.clone().finalize();
-        self.final_hash = Some(SHA1::new(&hash_result.to_vec()));
-        self.writer.write_all(&hash_result).unwrap();
+        self.writer.write_all(&hash_result).map_err(write_error)?;
+        self.final_hash = Some(hash_result);
+        self.writer.flush().map_err(write_error)?;
         Ok(())
     }
 
+    /// Encodes the objects of the pack of `reader` reachable from `wants` and not from
+    /// `haves`, counted with the `bitmaps` of the pack: the header is written with their
+    /// number without walking the history first, and the objects are read from the pack in
+    /// its order. `None`, with nothing written, when a want has no bitmap.
+    pub fn encode_reachable(
+        reader: &PackReader,
+        bitmaps: &PackBitmap,
+        wants: &[SHA1],
+        haves: &[SHA1],
+        window_size: usize,
+        writer: W,
+    ) -> Result<Option<Self>, GitError> {
+        if bitmaps.pack_hash != reader.index().pack_hash {
+            return Err(GitError::InvalidBitmapFile(String::from(
+                "the bitmap of another pack",
+            )));
+        }
+        let Some(reachable) = bitmaps.reachable(wants, haves) else {
+            return Ok(None);
+        };
+        let mut encoder = PackEncoder::new(reachable.len(), window_size, writer);
+        let mut error = None;
+        let entries = bitmaps
+            .objects(&reachable)
+            .map_while(|(hash, offset)| match reader.get_at(&hash, offset) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    error = Some(e);
+                    None
+                }
+            });
+        let result = encoder.encode_entries(entries);
+        if let Some(e) = error {
+            return Err(e);
+        }
+        result.map(|_| Some(encoder))
+    }
+
     /// try to encode as delta using objects in window
     /// # Returns
-    /// return (delta entry, offset) if success make delta
-    /// return (origin Entry,None) if didn't delta,
-    fn try_as_offset_delta(&mut self, entry: &Entry) -> (Entry, Option<usize>) {
-        let mut best_base: Option<&(Entry, usize)> = None;
+    /// return (delta entry, offset, depth) if success make delta
+    /// return (origin Entry, None, 0) if didn't delta,
+    fn try_as_offset_delta(&mut self, entry: &Entry) -> (Entry, Option<usize>, usize) {
+        let mut best_base: Option<&(Entry, usize, usize)> = None;
         let mut best_rate: f64 = 0.0;
         for try_base in self.window.iter() {
-            if try_base.0.obj_type != entry.obj_type {
+            if try_base.0.obj_type != entry.obj_type || try_base.2 >= MAX_DELTA_DEPTH {
                 continue;
             }
             let rate = delta::encode_rate(&try_base.0.data, &entry.data);
@@ -126,59 +219,48 @@ impl<W: Write> PackEncoder<W> {
                 best_base = Some(try_base);
             }
         }
-        if best_rate > 0.0 {
-            let best_base = best_base.unwrap(); // must some if best rate > 0
-            let delta = delta::encode(&best_base.0.data, &entry.data);
-            let offset = self.inner_offset - best_base.1;
-            (
+        // a delta is only worth it when it is smaller than the object, the rate is an estimate
+        let delta = best_base
+            .map(|base| (delta::encode(&base.0.data, &entry.data), base))
+            .filter(|(delta, _)| delta.len() < entry.data.len() / 2);
+        match delta {
+            Some((delta, base)) => (
                 Entry {
                     data: delta,
                     obj_type: ObjectType::OffsetDelta,
                     ..entry.clone()
                 },
-                Some(offset),
-            )
-        } else {
-            (entry.clone(), None)
+                Some(self.inner_offset - base.1),
+                base.2 + 1,
+            ),
+            None => (entry.clone(), None, 0),
         }
     }
 
-    fn write_all_and_update(&mut self, data: &[u8]) {
+    fn write_all_and_update(&mut self, data: &[u8]) -> Result<(), GitError> {
         self.inner_hash.update(data);
         self.inner_offset += data.len();
-        self.writer.write_all(data).unwrap();
+        self.writer.write_all(data).map_err(write_error)
     }
 
     /// encode one object, and update the hash
-    fn encode_one_object(&mut self, entry: &Entry) -> Result<(), GitError> {
+    /// # Returns
+    /// the depth of its delta chain, 0 when stored in full
+    fn encode_one_object(&mut self, entry: &Entry) -> Result<usize, GitError> {
         // try encode as delta
-        let (entry, offset) = self.try_as_offset_delta(entry);
+        let (entry, offset, depth) = self.try_as_offset_delta(entry);
         let obj_data = entry.data;
         let obj_data_len = obj_data.len();
         let obj_type_number = entry.obj_type.to_u8();
 
         // **header** encoding
-        let mut header_data = vec![(0x80 | (obj_type_number << 4)) + (obj_data_len & 0x0f) as u8];
-        let mut size = obj_data_len >> 4; // 4 bit has been used in first byte
-        if size > 0 {
-            while size > 0 {
-                if size >> 7 > 0 {
-                    header_data.push((0x80 | size) as u8);
-                    size >>= 7;
-                } else {
-                    header_data.push(size as u8);
-                    break;
-                }
-            }
-        } else {
-            header_data.push(0);
-        }
-        self.write_all_and_update(&header_data);
+        let header_data = encode_object_header(obj_type_number, obj_data_len);
+        self.write_all_and_update(&header_data)?;
 
         // **offset** encoding
         if entry.obj_type == ObjectType::OffsetDelta {
             let offset_data = encode_offset(offset.unwrap());
-            self.write_all_and_update(&offset_data);
+            self.write_all_and_update(&offset_data)?;
         } else if entry.obj_type == ObjectType::HashDelta {
             unreachable!("unsupported type")
         }
@@ -189,18 +271,25 @@ impl<W: Write> PackEncoder<W> {
             .expect("zlib compress should never failed");
         inflate.flush().expect("zlib flush should never failed");
         let compressed_data = inflate.finish().expect("zlib compress should never failed");
-        self.write_all_and_update(&compressed_data);
-        Ok(())
+        self.write_all_and_update(&compressed_data)?;
+        Ok(depth)
     }
 }
 
+fn write_error(err: std::io::Error) -> GitError {
+    GitError::UnCompletedPackObject(format!("Write error: {}", err))
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{io::Cursor, path::PathBuf, usize};
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::{io::Cursor, path::PathBuf};
 
     use venus::internal::object::blob::Blob;
 
-    use crate::internal::pack::Pack;
+    use crate::internal::pack::{utils, Pack};
+    use crate::test_support::{FixtureConfig, SyntheticRepo};
 
     use super::*;
     #[test]
@@ -210,10 +299,12 @@ mod tests {
             let mut writer: Vec<u8> = Vec::new();
             // make some different objects, or decode will fail
             let str_vec = vec!["hello, code,", "hello, world.", "!", "123141251251"];
+            // long enough for a delta to be smaller than the object
+            let base = "a line of text shared by every version\n".repeat(20);
             let mut encoder = PackEncoder::new(str_vec.len(), window_size, &mut writer);
             let (tx, rx) = mpsc::channel::<Entry>();
             for str in str_vec {
-                let blob = Blob::from_content(str);
+                let blob = Blob::from_content(&format!("{}{}", base, str));
                 let entry: Entry = blob.into();
                 tx.send(entry).unwrap();
             }
@@ -238,10 +329,119 @@ mod tests {
 
         // with delta
         let pack_with_delta = encode_once(3);
-        assert_ne!(pack_with_delta.len(), pack_without_delta_size);
+        assert!(pack_with_delta.len() < pack_without_delta_size);
         check_format(pack_with_delta);
     }
 
+    #[test]
+    fn test_encode_entries() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            commits: 20,
+            ..Default::default()
+        });
+        let encode_with = |window_size: usize| {
+            let mut writer: Vec<u8> = Vec::new();
+            let mut encoder = PackEncoder::new(repo.objects.len(), window_size, &mut writer);
+            encoder.encode_entries(repo.objects.clone()).unwrap();
+            let hash = encoder.get_hash().unwrap();
+            assert_eq!(&writer[writer.len() - 20..], hash.0.as_slice());
+            writer
+        };
+        let pack_without_delta = encode_with(0);
+        let pack = encode_with(10);
+        assert!(pack.len() < pack_without_delta.len());
+
+        let decoded = Arc::new(Mutex::new(HashSet::new()));
+        let hashes = decoded.clone();
+        let tmp = PathBuf::from("/tmp/.cache_temp");
+        let mut p = Pack::new(None, Some(1024 * 1024 * 20), Some(tmp));
+        p.decode(&mut Cursor::new(pack), move |entry| {
+            hashes.lock().unwrap().insert(entry.hash);
+        })
+        .expect("pack file format error");
+        let expected: HashSet<SHA1> = repo.objects.iter().map(|entry| entry.hash).collect();
+        assert_eq!(*decoded.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_encode_reachable() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 5,
+            ..Default::default()
+        });
+        let dir = PathBuf::from("/tmp/.cache_temp").join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let (pack_path, index_path) = (dir.join("test.pack"), dir.join("test.idx"));
+        let pack = repo.to_pack();
+        std::fs::write(&pack_path, &pack).unwrap();
+        let mut p = Pack::new(None, Some(1024 * 1024 * 20), Some(dir.clone()));
+        p.decode_with_index(&mut Cursor::new(pack), &index_path, |_| {})
+            .unwrap();
+        let reader = PackReader::open(&pack_path, &index_path).unwrap();
+        let bitmaps = PackBitmap::build(&reader, &[], 1).unwrap();
+
+        let mut writer: Vec<u8> = Vec::new();
+        let encoder =
+            PackEncoder::encode_reachable(&reader, &bitmaps, &[repo.head], &[], 10, &mut writer)
+                .unwrap()
+                .unwrap();
+        assert!(encoder.get_hash().is_some());
+        let decoded = Arc::new(Mutex::new(HashSet::new()));
+        let hashes = decoded.clone();
+        let mut p = Pack::new(None, Some(1024 * 1024 * 20), Some(dir.clone()));
+        p.decode(&mut Cursor::new(writer), move |entry| {
+            hashes.lock().unwrap().insert(entry.hash);
+        })
+        .expect("pack file format error");
+        let expected: HashSet<SHA1> = repo.objects.iter().map(|entry| entry.hash).collect();
+        assert_eq!(*decoded.lock().unwrap(), expected);
+
+        // a want without a bitmap is left to a walk
+        let mut writer: Vec<u8> = Vec::new();
+        let unknown = SHA1([0x12; 20]);
+        assert!(
+            PackEncoder::encode_reachable(&reader, &bitmaps, &[unknown], &[], 10, &mut writer)
+                .unwrap()
+                .is_none()
+        );
+        assert!(writer.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encode_sha256_trailer() {
+        let entries: Vec<Entry> = ["a", "b"].iter().map(|s| Blob::from_content(s).into()).collect();
+        let mut writer: Vec<u8> = Vec::new();
+        let mut encoder = PackEncoder::new(2, 10, &mut writer).with_hash_kind(HashKind::Sha256);
+        encoder.encode_entries(entries).unwrap();
+        assert!(encoder.get_hash().is_none());
+        let trailer = encoder.trailer().unwrap().to_vec();
+        assert_eq!(trailer.len(), 32);
+        let (body, tail) = writer.split_at(writer.len() - 32);
+        assert_eq!(tail, trailer.as_slice());
+        assert_eq!(HashKind::Sha256.digest(body), trailer);
+    }
+
+    #[test]
+    fn test_encode_entries_count() {
+        let entries: Vec<Entry> = ["a", "b"].iter().map(|s| Blob::from_content(s).into()).collect();
+        let mut writer: Vec<u8> = Vec::new();
+        let mut encoder = PackEncoder::new(3, 10, &mut writer);
+        assert!(encoder.encode_entries(entries.clone()).is_err());
+        assert!(encoder.get_hash().is_none());
+
+        let mut writer: Vec<u8> = Vec::new();
+        let mut encoder = PackEncoder::new(1, 10, &mut writer);
+        assert!(encoder.encode_entries(entries).is_err());
+
+        // an empty pack is valid, git sends one when there is nothing to fetch
+        let mut writer: Vec<u8> = Vec::new();
+        let mut encoder = PackEncoder::new(0, 10, &mut writer);
+        encoder.encode_entries(Vec::new()).unwrap();
+        assert_eq!(&writer[..12], b"PACK\0\0\0\x02\0\0\0\0");
+        assert_eq!(writer.len(), 32);
+    }
+
     #[test]
     fn test_encode_offset() {
         let value = 11013;
@@ -250,5 +450,10 @@ mod tests {
         assert_eq!(data.len(), 2);
         assert_eq!(data[0], 0b_1101_0101);
         assert_eq!(data[1], 0b_0000_0101);
+
+        // a zero 7 bit group in the middle
+        let data = encode_offset(16384);
+        let (value, _) = utils::read_offset_encoding(&mut Cursor::new(data)).unwrap();
+        assert_eq!(value, 16384);
     }
 }
@@ -0,0 +1,202 @@
+//!
+//! Partial clones, the `--filter` of `git clone` and `git fetch`: the client asks for a pack
+//! without some of the objects, e.g. the blobs, and fetches them later when it needs them.
+//! The [`FilterSpec`] is parsed from the `filter` line of the upload-pack request, and the
+//! objects it omits are dropped before the entries are given to the
+//! [`PackEncoder`](crate::internal::pack::encode::PackEncoder).
+//!
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use venus::hash::SHA1;
+use venus::internal::object::commit::Commit;
+use venus::internal::object::tree::{Tree, TreeItemMode};
+use venus::internal::object::types::ObjectType;
+use venus::internal::object::ObjectTrait;
+use venus::internal::pack::entry::Entry;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterSpec {
+    /// `blob:none`, omits every blob.
+    BlobNone,
+    /// `blob:limit=<n>[kmg]`, omits the blobs of `n` bytes or more.
+    BlobLimit(usize),
+    /// `tree:<depth>`, omits the trees and blobs at `depth` or deeper, the root trees of the
+    /// commits being at depth 0.
+    TreeDepth(usize),
+}
+
+impl FromStr for FilterSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid filter-spec '{}'", s);
+        if s == "blob:none" {
+            Ok(FilterSpec::BlobNone)
+        } else if let Some(limit) = s.strip_prefix("blob:limit=") {
+            let (digits, unit) = match limit.char_indices().last() {
+                Some((i, 'k' | 'K')) => (&limit[..i], 1 << 10),
+                Some((i, 'm' | 'M')) => (&limit[..i], 1 << 20),
+                Some((i, 'g' | 'G')) => (&limit[..i], 1 << 30),
+                _ => (limit, 1),
+            };
+            let limit: usize = digits.parse().map_err(|_| invalid())?;
+            limit.checked_mul(unit).map(FilterSpec::BlobLimit).ok_or_else(invalid)
+        } else if let Some(depth) = s.strip_prefix("tree:") {
+            depth.parse().map(FilterSpec::TreeDepth).map_err(|_| invalid())
+        } else {
+            Err(format!("unsupported filter-spec '{}'", s))
+        }
+    }
+}
+
+impl Display for FilterSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterSpec::BlobNone => write!(f, "blob:none"),
+            FilterSpec::BlobLimit(limit) => write!(f, "blob:limit={}", limit),
+            FilterSpec::TreeDepth(depth) => write!(f, "tree:{}", depth),
+        }
+    }
+}
+
+impl FilterSpec {
+    /// Whether a tree at `depth` is sent, the objects below one that is not are not either.
+    pub fn keeps_tree(&self, depth: usize) -> bool {
+        match *self {
+            FilterSpec::TreeDepth(max_depth) => depth < max_depth,
+            _ => true,
+        }
+    }
+
+    /// Whether a blob of `size` bytes at `depth` is sent.
+    pub fn keeps_blob(&self, size: usize, depth: usize) -> bool {
+        match *self {
+            FilterSpec::BlobNone => false,
+            FilterSpec::BlobLimit(limit) => size < limit,
+            FilterSpec::TreeDepth(max_depth) => depth < max_depth,
+        }
+    }
+
+    /// Drops the objects of `entries` the filter omits. The depth of a tree or a blob is the
+    /// shortest path to it from the root tree of a commit of `entries`, the objects reached
+    /// from none, e.g. wanted directly or through a tag, are kept.
+    pub fn filter(&self, entries: Vec<Entry>) -> Vec<Entry> {
+        match *self {
+            FilterSpec::BlobNone => entries
+                .into_iter()
+                .filter(|entry| entry.obj_type != ObjectType::Blob)
+                .collect(),
+            FilterSpec::BlobLimit(limit) => entries
+                .into_iter()
+                .filter(|entry| entry.obj_type != ObjectType::Blob || entry.data.len() < limit)
+                .collect(),
+            FilterSpec::TreeDepth(max_depth) => {
+                let depths = Self::depths(&entries);
+                entries
+                    .into_iter()
+                    .filter(|entry| depths.get(&entry.hash).is_none_or(|depth| *depth < max_depth))
+                    .collect()
+            }
+        }
+    }
+
+    /// The depth of the trees and blobs reached from the commits of `entries`.
+    fn depths(entries: &[Entry]) -> HashMap<SHA1, usize> {
+        let trees: HashMap<SHA1, &Entry> = entries
+            .iter()
+            .filter(|entry| entry.obj_type == ObjectType::Tree)
+            .map(|entry| (entry.hash, entry))
+            .collect();
+        let mut depths = HashMap::new();
+        let mut queue = VecDeque::new();
+        for entry in entries.iter().filter(|entry| entry.obj_type == ObjectType::Commit) {
+            if let Ok(commit) = Commit::from_bytes(entry.data.clone(), entry.hash) {
+                queue.push_back((commit.tree_id, 0));
+            }
+        }
+        // breadth first, the first depth found is the shortest
+        while let Some((id, depth)) = queue.pop_front() {
+            if depths.contains_key(&id) {
+                continue;
+            }
+            depths.insert(id, depth);
+            let Some(entry) = trees.get(&id) else {
+                continue;
+            };
+            if let Ok(tree) = Tree::from_bytes(entry.data.clone(), entry.hash) {
+                for item in tree.tree_items {
+                    // the commits of submodules are not part of the repository
+                    if item.mode != TreeItemMode::Commit {
+                        queue.push_back((item.id, depth + 1));
+                    }
+                }
+            }
+        }
+        depths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use venus::internal::object::types::ObjectType;
+
+    use crate::internal::pack::filter::FilterSpec;
+    use crate::test_support::{FixtureConfig, SyntheticRepo};
+
+    #[test]
+    fn test_parse_filter_spec() {
+        for (spec, filter) in [
+            ("blob:none", FilterSpec::BlobNone),
+            ("blob:limit=100", FilterSpec::BlobLimit(100)),
+            ("blob:limit=1k", FilterSpec::BlobLimit(1024)),
+            ("blob:limit=2m", FilterSpec::BlobLimit(2 << 20)),
+            ("tree:0", FilterSpec::TreeDepth(0)),
+        ] {
+            assert_eq!(spec.parse(), Ok(filter));
+        }
+        assert_eq!(FilterSpec::BlobLimit(1024).to_string(), "blob:limit=1024");
+        for spec in ["blob:limit=", "blob:limit=1x", "tree:-1", "sparse:oid=HEAD", ""] {
+            assert!(spec.parse::<FilterSpec>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            blob_size: 1024,
+            ..Default::default()
+        });
+        let count = |filter: FilterSpec, obj_type: ObjectType| {
+            filter
+                .filter(repo.objects.clone())
+                .iter()
+                .filter(|entry| entry.obj_type == obj_type)
+                .count()
+        };
+        let total = |obj_type: ObjectType| {
+            repo.objects.iter().filter(|entry| entry.obj_type == obj_type).count()
+        };
+        assert_eq!(count(FilterSpec::BlobNone, ObjectType::Blob), 0);
+        assert_eq!(count(FilterSpec::BlobNone, ObjectType::Tree), total(ObjectType::Tree));
+        assert_eq!(count(FilterSpec::BlobLimit(1), ObjectType::Blob), 0);
+        assert_eq!(count(FilterSpec::BlobLimit(usize::MAX), ObjectType::Blob), total(ObjectType::Blob));
+
+        // the root trees hold the directories, the directories hold the blobs
+        let commits = total(ObjectType::Commit);
+        assert_eq!(count(FilterSpec::TreeDepth(0), ObjectType::Tree), 0);
+        assert_eq!(count(FilterSpec::TreeDepth(0), ObjectType::Commit), commits);
+        assert_eq!(count(FilterSpec::TreeDepth(1), ObjectType::Tree), commits);
+        assert_eq!(count(FilterSpec::TreeDepth(2), ObjectType::Blob), 0);
+        assert_eq!(count(FilterSpec::TreeDepth(2), ObjectType::Tree), total(ObjectType::Tree));
+        assert_eq!(count(FilterSpec::TreeDepth(3), ObjectType::Blob), total(ObjectType::Blob));
+
+        // the same, object by object
+        assert!(FilterSpec::TreeDepth(1).keeps_tree(0) && !FilterSpec::TreeDepth(1).keeps_tree(1));
+        assert!(FilterSpec::BlobNone.keeps_tree(5) && !FilterSpec::BlobNone.keeps_blob(0, 1));
+        assert!(FilterSpec::BlobLimit(10).keeps_blob(9, 1));
+        assert!(!FilterSpec::BlobLimit(10).keeps_blob(10, 1));
+        assert!(!FilterSpec::TreeDepth(2).keeps_blob(0, 2));
+    }
+}
@@ -0,0 +1,246 @@
+//!
+//! Pack index (`.idx`) version 2, written alongside a decoded pack so git and other readers can
+//! find the objects in the pack without decoding it again.
+//!
+//! ## Reference
+//! 1. Git Pack-Format [Introduce](https://git-scm.com/docs/pack-format)
+//!
+use std::fs;
+use std::path::Path;
+
+use sha1::{Digest, Sha1};
+
+use venus::errors::GitError;
+use venus::hash::SHA1;
+
+const IDX_MAGIC: [u8; 4] = [0xff, b't', b'O', b'c'];
+const IDX_VERSION: u32 = 2;
+/// Offsets from this one on are stored in the 64-bit offset table.
+const LARGE_OFFSET: u64 = 0x8000_0000;
+
+/// The location of an object in the pack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub hash: SHA1,
+    /// The CRC32 of the packed object: its header, base and compressed data.
+    pub crc32: u32,
+    pub offset: u64,
+}
+
+/// Encodes the index of the pack with the checksum `pack_hash` and the objects `entries`.
+pub fn encode_index(mut entries: Vec<IndexEntry>, pack_hash: &SHA1) -> Vec<u8> {
+    entries.sort_by_key(|entry| entry.hash.0);
+
+    let mut data = Vec::with_capacity(4 + 4 + 256 * 4 + entries.len() * 28 + 40);
+    data.extend_from_slice(&IDX_MAGIC);
+    data.extend_from_slice(&IDX_VERSION.to_be_bytes());
+
+    // fan-out: the number of objects whose first byte is at most the index
+    let mut fanout = [0u32; 256];
+    for entry in &entries {
+        fanout[entry.hash.0[0] as usize] += 1;
+    }
+    let mut total = 0;
+    for count in fanout {
+        total += count;
+        data.extend_from_slice(&total.to_be_bytes());
+    }
+
+    for entry in &entries {
+        data.extend_from_slice(&entry.hash.0);
+    }
+    for entry in &entries {
+        data.extend_from_slice(&entry.crc32.to_be_bytes());
+    }
+    let mut large_offsets = Vec::new();
+    for entry in &entries {
+        let offset = if entry.offset < LARGE_OFFSET {
+            entry.offset as u32
+        } else {
+            large_offsets.push(entry.offset);
+            LARGE_OFFSET as u32 | (large_offsets.len() - 1) as u32
+        };
+        data.extend_from_slice(&offset.to_be_bytes());
+    }
+    for offset in large_offsets {
+        data.extend_from_slice(&offset.to_be_bytes());
+    }
+
+    data.extend_from_slice(&pack_hash.0);
+    let checksum = Sha1::digest(&data);
+    data.extend_from_slice(&checksum);
+    data
+}
+
+/// Writes the index of the pack to `path`.
+pub fn write_index(
+    path: &Path,
+    entries: Vec<IndexEntry>,
+    pack_hash: &SHA1,
+) -> Result<(), GitError> {
+    fs::write(path, encode_index(entries, pack_hash))
+        .map_err(|e| GitError::InvalidIdxFile(format!("{}: {}", path.display(), e)))
+}
+
+/// A pack index read back, to find the objects of the pack by hash.
+#[derive(Debug, Clone)]
+pub struct PackIndex {
+    /// Sorted by hash.
+    entries: Vec<IndexEntry>,
+    pub pack_hash: SHA1,
+}
+
+impl PackIndex {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, GitError> {
+        let invalid = |msg: &str| GitError::InvalidIdxFile(msg.to_owned());
+        let header_len = 8 + 256 * 4;
+        if data.len() < header_len + 40 {
+            return Err(invalid("too short"));
+        }
+        if data[..4] != IDX_MAGIC || read_u32(data, 4) != IDX_VERSION {
+            return Err(invalid("not a version 2 index"));
+        }
+        let (content, checksum) = data.split_at(data.len() - 20);
+        if Sha1::digest(content).as_slice() != checksum {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let count = read_u32(data, header_len - 4) as usize;
+        let names = header_len;
+        let crcs = names + count * 20;
+        let offsets = crcs + count * 4;
+        let large_offsets = offsets + count * 4;
+        if content.len() < large_offsets + 20 {
+            return Err(invalid("truncated"));
+        }
+        let mut entries = Vec::with_capacity(count);
+        for n in 0..count {
+            let offset = read_u32(data, offsets + n * 4);
+            let offset = if offset & LARGE_OFFSET as u32 == 0 {
+                offset as u64
+            } else {
+                let at = large_offsets + (offset & !(LARGE_OFFSET as u32)) as usize * 8;
+                let bytes = content
+                    .get(at..at + 8)
+                    .filter(|_| at + 8 <= content.len() - 20)
+                    .ok_or_else(|| invalid("large offset out of range"))?;
+                u64::from_be_bytes(bytes.try_into().unwrap())
+            };
+            entries.push(IndexEntry {
+                hash: SHA1::from_bytes(&data[names + n * 20..names + n * 20 + 20]),
+                crc32: read_u32(data, crcs + n * 4),
+                offset,
+            });
+        }
+        if entries.windows(2).any(|w| w[0].hash.0 >= w[1].hash.0) {
+            return Err(invalid("object names not sorted"));
+        }
+        Ok(PackIndex {
+            entries,
+            pack_hash: SHA1::from_bytes(&content[content.len() - 20..]),
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Self, GitError> {
+        let data = fs::read(path)
+            .map_err(|e| GitError::InvalidIdxFile(format!("{}: {}", path.display(), e)))?;
+        PackIndex::from_bytes(&data)
+    }
+
+    pub fn find(&self, hash: &SHA1) -> Option<&IndexEntry> {
+        self.entries
+            .binary_search_by(|entry| entry.hash.0.cmp(&hash.0))
+            .ok()
+            .map(|n| &self.entries[n])
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use venus::hash::SHA1;
+
+    use super::{encode_index, read_u32, IndexEntry, PackIndex};
+
+    fn entry(first: u8, offset: u64) -> IndexEntry {
+        let mut hash = [0x11; 20];
+        hash[0] = first;
+        IndexEntry {
+            hash: SHA1(hash),
+            crc32: offset as u32,
+            offset,
+        }
+    }
+
+    #[test]
+    fn test_encode_index() {
+        let entries = vec![
+            entry(0xff, 12),
+            entry(0x00, 0x1_0000_0000),
+            entry(0x10, 300),
+        ];
+        let pack_hash = SHA1([0xab; 20]);
+        let data = encode_index(entries, &pack_hash);
+
+        assert_eq!(&data[..8], b"\xfftOc\0\0\0\x02");
+        let fanout = |n: usize| read_u32(&data, 8 + n * 4);
+        assert_eq!(
+            (fanout(0x00), fanout(0x0f), fanout(0x10), fanout(0xfe)),
+            (1, 1, 2, 2)
+        );
+        assert_eq!(fanout(0xff), 3);
+
+        // sorted by hash
+        let names = 8 + 256 * 4;
+        let firsts: Vec<u8> = (0..3).map(|n| data[names + n * 20]).collect();
+        assert_eq!(firsts, vec![0x00, 0x10, 0xff]);
+        let crcs = names + 3 * 20;
+        assert_eq!(read_u32(&data, crcs + 4), 300);
+        let offsets = crcs + 3 * 4;
+        assert_eq!(read_u32(&data, offsets), 0x8000_0000);
+        assert_eq!(read_u32(&data, offsets + 4), 300);
+        assert_eq!(read_u32(&data, offsets + 8), 12);
+        let large = offsets + 3 * 4;
+        assert_eq!(&data[large..large + 8], &0x1_0000_0000u64.to_be_bytes());
+        assert_eq!(&data[large + 8..large + 28], &pack_hash.0);
+        assert_eq!(data.len(), large + 8 + 40);
+    }
+    #[test]
+    fn test_read_index() {
+        let entries = vec![
+            entry(0xff, 12),
+            entry(0x00, 0x1_0000_0000),
+            entry(0x10, 300),
+        ];
+        let pack_hash = SHA1([0xab; 20]);
+        let mut data = encode_index(entries.clone(), &pack_hash);
+
+        let index = PackIndex::from_bytes(&data).unwrap();
+        assert_eq!(index.pack_hash, pack_hash);
+        assert_eq!(index.len(), 3);
+        for entry in &entries {
+            assert_eq!(index.find(&entry.hash), Some(entry));
+        }
+        assert!(index.find(&SHA1([0x12; 20])).is_none());
+
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(PackIndex::from_bytes(&data).is_err());
+        assert!(PackIndex::from_bytes(&data[..100]).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+//!
+//! Worker threads shared by the decodes of a process for their CPU bound work, the rebuilding
+//! of the deltas, in two lanes: the interactive lane for the small packs a client waits for,
+//! and the batch lane for the large ones. The interactive jobs are always run first, and the
+//! batch jobs never take every worker, so a small pack is not queued behind the thousands of
+//! deltas of a large one.
+//!
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    Interactive,
+    Batch,
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Default)]
+struct Queues {
+    interactive: VecDeque<Job>,
+    batch: VecDeque<Job>,
+    /// Batch jobs being run.
+    batch_running: usize,
+    stopped: bool,
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    available: Condvar,
+    batch_threads: usize,
+}
+
+pub struct Lanes {
+    shared: Arc<Shared>,
+    threads: usize,
+}
+
+impl Lanes {
+    /// Starts `threads` workers, at most `batch_threads` of them run batch jobs at once, the
+    /// others are kept for the interactive jobs. Both are at least 1.
+    pub fn new(threads: usize, batch_threads: usize) -> Self {
+        Self::start(threads, batch_threads, &[])
+    }
+
+    /// Like [`Lanes::new`], with each worker pinned to a core of the process, in the order of
+    /// their ids, and once all are taken from the first again. What a worker allocates, e.g. the
+    /// objects it rebuilds, is then kept local to its NUMA node by the first-touch policy of
+    /// Linux, instead of following the thread across the nodes.
+    #[cfg(all(target_os = "linux", feature = "pinned-threads"))]
+    pub fn pinned(threads: usize, batch_threads: usize) -> Self {
+        Self::start(threads, batch_threads, &affinity::cpus())
+    }
+
+    fn start(threads: usize, batch_threads: usize, cpus: &[usize]) -> Self {
+        let threads = threads.max(1);
+        let shared = Arc::new(Shared {
+            queues: Mutex::new(Queues::default()),
+            available: Condvar::new(),
+            batch_threads: batch_threads.clamp(1, threads),
+        });
+        for n in 0..threads {
+            let shared = shared.clone();
+            let cpu = (!cpus.is_empty()).then(|| cpus[n % cpus.len()]);
+            thread::Builder::new()
+                .name(format!("pack-lane-{}", n))
+                .spawn(move || {
+                    if let Some(cpu) = cpu {
+                        Self::pin(cpu);
+                    }
+                    Self::work(&shared)
+                })
+                .expect("failed to start a worker thread");
+        }
+        Lanes { shared, threads }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "pinned-threads"))]
+    fn pin(cpu: usize) {
+        if let Err(e) = affinity::pin(cpu) {
+            tracing::warn!("failed to pin a decode worker to the cpu {}: {}", cpu, e);
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "pinned-threads")))]
+    fn pin(_cpu: usize) {}
+
+    /// The number of worker threads.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn execute<F>(&self, lane: Lane, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut queues = self.shared.queues.lock().unwrap();
+        match lane {
+            Lane::Interactive => queues.interactive.push_back(Box::new(job)),
+            Lane::Batch => queues.batch.push_back(Box::new(job)),
+        }
+        self.shared.available.notify_one();
+    }
+
+    /// The jobs of `lane` waiting for a worker.
+    pub fn queued(&self, lane: Lane) -> usize {
+        let queues = self.shared.queues.lock().unwrap();
+        match lane {
+            Lane::Interactive => queues.interactive.len(),
+            Lane::Batch => queues.batch.len(),
+        }
+    }
+
+    fn work(shared: &Shared) {
+        let mut queues = shared.queues.lock().unwrap();
+        loop {
+            let (job, lane) = if let Some(job) = queues.interactive.pop_front() {
+                (job, Lane::Interactive)
+            } else if queues.batch_running < shared.batch_threads && !queues.batch.is_empty() {
+                queues.batch_running += 1;
+                (queues.batch.pop_front().unwrap(), Lane::Batch)
+            } else if queues.stopped {
+                return;
+            } else {
+                queues = shared.available.wait(queues).unwrap();
+                continue;
+            };
+            drop(queues);
+            // a failing job is the business of whoever submitted it, not of the worker
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            queues = shared.queues.lock().unwrap();
+            if lane == Lane::Batch {
+                queues.batch_running -= 1;
+                // a worker may be waiting for a batch slot
+                shared.available.notify_one();
+            }
+        }
+    }
+}
+
+impl Drop for Lanes {
+    /// The workers stop once the jobs queued are run.
+    fn drop(&mut self) {
+        self.shared.queues.lock().unwrap().stopped = true;
+        self.shared.available.notify_all();
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "pinned-threads"))]
+mod affinity {
+    use std::io;
+    use std::mem;
+
+    /// The cpus the process is allowed to run on.
+    pub fn cpus() -> Vec<usize> {
+        // SAFETY: a zeroed `cpu_set_t` is an empty set, filled in by the kernel
+        unsafe {
+            let mut set: libc::cpu_set_t = mem::zeroed();
+            if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+                return Vec::new();
+            }
+            (0..libc::CPU_SETSIZE as usize)
+                .filter(|cpu| libc::CPU_ISSET(*cpu, &set))
+                .collect()
+        }
+    }
+
+    /// Pins the calling thread to `cpu`.
+    pub fn pin(cpu: usize) -> io::Result<()> {
+        // SAFETY: the set only holds `cpu`, below `CPU_SETSIZE` as returned by `cpus`
+        unsafe {
+            let mut set: libc::cpu_set_t = mem::zeroed();
+            libc::CPU_SET(cpu, &mut set);
+            if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::sync::{Arc, Barrier};
+    use std::time::Duration;
+
+    use crate::internal::pack::lanes::{Lane, Lanes};
+
+    #[test]
+    fn test_interactive_jobs_are_not_starved() {
+        let lanes = Lanes::new(2, 1);
+        // the batch jobs take one worker at most, however many are queued
+        let barrier = Arc::new(Barrier::new(2));
+        let (sender, receiver) = mpsc::channel();
+        for n in 0..100 {
+            let barrier = barrier.clone();
+            let sender = sender.clone();
+            lanes.execute(Lane::Batch, move || {
+                if n == 0 {
+                    barrier.wait();
+                }
+                sender.send(Lane::Batch).unwrap();
+            });
+        }
+        let interactive = sender.clone();
+        lanes.execute(Lane::Interactive, move || interactive.send(Lane::Interactive).unwrap());
+        // run while the first batch job blocks its worker
+        let first = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(first, Lane::Interactive);
+        assert!(lanes.queued(Lane::Batch) >= 99);
+        barrier.wait();
+        drop(sender);
+        drop(lanes);
+        assert_eq!(receiver.iter().filter(|lane| *lane == Lane::Batch).count(), 100);
+    }
+
+    #[test]
+    fn test_lanes_survive_a_panic() {
+        let lanes = Lanes::new(1, 1);
+        lanes.execute(Lane::Batch, || panic!("a failing job"));
+        let (sender, receiver) = mpsc::channel();
+        lanes.execute(Lane::Batch, move || sender.send(()).unwrap());
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[cfg(all(target_os = "linux", feature = "pinned-threads"))]
+    #[test]
+    fn test_pinned_lanes() {
+        let lanes = Lanes::pinned(2, 1);
+        let cpus = super::affinity::cpus();
+        let (sender, receiver) = mpsc::channel();
+        for lane in [Lane::Interactive, Lane::Batch] {
+            let sender = sender.clone();
+            lanes.execute(lane, move || sender.send(super::affinity::cpus()).unwrap());
+        }
+        for _ in 0..2 {
+            let pinned = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(pinned.len(), 1);
+            assert!(cpus.contains(&pinned[0]));
+        }
+    }
+}
@@ -0,0 +1,507 @@
+//!
+//! Multi-pack-index (`multi-pack-index`) version 1, one index for all the packs of a directory:
+//! an object is found with a single fan-out and binary search instead of a search in the
+//! `.idx` of each pack, so the lookups stay O(log n) however many packs accumulate.
+//! An object stored in several packs is indexed once, in the pack preferred by the writer.
+//!
+//! ## Reference
+//! 1. Git Multi-Pack-Index [Format](https://git-scm.com/docs/gitformat-pack#_multi_pack_index_midx_files_have_the_following_format)
+//!
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use sha1::{Digest, Sha1};
+
+use venus::errors::GitError;
+use venus::hash::SHA1;
+use venus::internal::pack::entry::Entry;
+
+use crate::internal::pack::index::PackIndex;
+use crate::internal::pack::reader::PackReader;
+
+/// The name of the multi-pack-index in the directory of the packs, as git names it.
+pub const MULTI_PACK_INDEX: &str = "multi-pack-index";
+
+const MIDX_MAGIC: [u8; 4] = *b"MIDX";
+const MIDX_VERSION: u8 = 1;
+/// SHA-1 object ids.
+const OID_VERSION: u8 = 1;
+const HEADER_LEN: usize = 12;
+
+const CHUNK_PACK_NAMES: [u8; 4] = *b"PNAM";
+const CHUNK_OID_FANOUT: [u8; 4] = *b"OIDF";
+const CHUNK_OID_LOOKUP: [u8; 4] = *b"OIDL";
+const CHUNK_OBJECT_OFFSETS: [u8; 4] = *b"OOFF";
+const CHUNK_LARGE_OFFSETS: [u8; 4] = *b"LOFF";
+/// With large offsets, the offsets from this one on are stored in the large offsets chunk.
+const LARGE_OFFSET: u64 = 0x8000_0000;
+
+/// The location of an object in the packs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultiPackEntry {
+    pub hash: SHA1,
+    /// The position of the pack in [`MultiPackIndex::pack_names`].
+    pub pack: u32,
+    pub offset: u64,
+}
+
+/// Encodes the multi-pack-index of `packs`, the name of the `.idx` of each pack with its
+/// content, in the order of preference: an object in several packs is indexed in the first.
+pub fn encode_multi_pack_index(packs: &[(String, &PackIndex)]) -> Vec<u8> {
+    // the packs are numbered in the order of their names
+    let mut order: Vec<usize> = (0..packs.len()).collect();
+    order.sort_by(|a, b| packs[*a].0.cmp(&packs[*b].0));
+    let mut pack_ids = vec![0; packs.len()];
+    for (id, n) in order.iter().enumerate() {
+        pack_ids[*n] = id as u32;
+    }
+
+    let mut seen = HashSet::new();
+    let mut entries = Vec::new();
+    for (n, (_, index)) in packs.iter().enumerate() {
+        for entry in index.entries() {
+            if seen.insert(entry.hash) {
+                entries.push(MultiPackEntry {
+                    hash: entry.hash,
+                    pack: pack_ids[n],
+                    offset: entry.offset,
+                });
+            }
+        }
+    }
+    entries.sort_by_key(|entry| entry.hash.0);
+
+    let mut names = Vec::new();
+    for n in &order {
+        names.extend_from_slice(packs[*n].0.as_bytes());
+        names.push(0);
+    }
+    names.resize(names.len().next_multiple_of(4), 0);
+
+    let mut fanout = Vec::with_capacity(256 * 4);
+    let mut counts = [0u32; 256];
+    for entry in &entries {
+        counts[entry.hash.0[0] as usize] += 1;
+    }
+    let mut total = 0;
+    for count in counts {
+        total += count;
+        fanout.extend_from_slice(&total.to_be_bytes());
+    }
+
+    let mut lookup = Vec::with_capacity(entries.len() * 20);
+    for entry in &entries {
+        lookup.extend_from_slice(&entry.hash.0);
+    }
+
+    // like git, the large offsets are only used when an offset does not fit in 32 bits
+    let large = entries.iter().any(|entry| entry.offset > u32::MAX as u64);
+    let mut offsets = Vec::with_capacity(entries.len() * 8);
+    let mut large_offsets = Vec::new();
+    for entry in &entries {
+        offsets.extend_from_slice(&entry.pack.to_be_bytes());
+        let offset = if large && entry.offset >= LARGE_OFFSET {
+            large_offsets.extend_from_slice(&entry.offset.to_be_bytes());
+            LARGE_OFFSET as u32 | (large_offsets.len() / 8 - 1) as u32
+        } else {
+            entry.offset as u32
+        };
+        offsets.extend_from_slice(&offset.to_be_bytes());
+    }
+
+    let mut chunks = vec![
+        (CHUNK_PACK_NAMES, names),
+        (CHUNK_OID_FANOUT, fanout),
+        (CHUNK_OID_LOOKUP, lookup),
+        (CHUNK_OBJECT_OFFSETS, offsets),
+    ];
+    if large {
+        chunks.push((CHUNK_LARGE_OFFSETS, large_offsets));
+    }
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&MIDX_MAGIC);
+    data.extend_from_slice(&[MIDX_VERSION, OID_VERSION, chunks.len() as u8, 0]);
+    data.extend_from_slice(&(packs.len() as u32).to_be_bytes());
+    // the table of contents ends with a zero id at the end of the last chunk
+    let mut offset = (HEADER_LEN + (chunks.len() + 1) * 12) as u64;
+    for (id, chunk) in &chunks {
+        data.extend_from_slice(id);
+        data.extend_from_slice(&offset.to_be_bytes());
+        offset += chunk.len() as u64;
+    }
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&offset.to_be_bytes());
+    for (_, chunk) in &chunks {
+        data.extend_from_slice(chunk);
+    }
+    let checksum = Sha1::digest(&data);
+    data.extend_from_slice(&checksum);
+    data
+}
+
+/// Writes the multi-pack-index of the packs of `pack_dir`, those with both their `.pack` and
+/// `.idx`, the most recent first when an object is in several. Returns the index written.
+pub fn write_multi_pack_index(pack_dir: &Path) -> Result<MultiPackIndex, GitError> {
+    let io_error = |path: &Path, e: std::io::Error| {
+        GitError::InvalidMultiPackIndex(format!("{}: {}", path.display(), e))
+    };
+    let mut packs = Vec::new();
+    for dir_entry in fs::read_dir(pack_dir).map_err(|e| io_error(pack_dir, e))? {
+        let path = dir_entry.map_err(|e| io_error(pack_dir, e))?.path();
+        if path.extension().is_none_or(|ext| ext != "idx") || !path.with_extension("pack").is_file()
+        {
+            continue;
+        }
+        let modified = fs::metadata(path.with_extension("pack"))
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        packs.push((modified, name, PackIndex::read(&path)?));
+    }
+    packs.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+    let packs: Vec<(String, &PackIndex)> = packs
+        .iter()
+        .map(|(_, name, index)| (name.clone(), index))
+        .collect();
+    let data = encode_multi_pack_index(&packs);
+    // replaced at once, a reader never sees a partial index
+    let path = pack_dir.join(MULTI_PACK_INDEX);
+    let lock = pack_dir.join(format!("{}.lock", MULTI_PACK_INDEX));
+    fs::write(&lock, &data).map_err(|e| io_error(&lock, e))?;
+    fs::rename(&lock, &path).map_err(|e| io_error(&path, e))?;
+    MultiPackIndex::from_bytes(&data)
+}
+
+/// A multi-pack-index read back, to find the objects of the packs by hash.
+#[derive(Debug, Clone)]
+pub struct MultiPackIndex {
+    /// The names of the `.idx` of the packs, sorted.
+    pack_names: Vec<String>,
+    fanout: [u32; 256],
+    /// Sorted by hash.
+    entries: Vec<MultiPackEntry>,
+}
+
+impl MultiPackIndex {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, GitError> {
+        let invalid = |msg: &str| GitError::InvalidMultiPackIndex(msg.to_owned());
+        if data.len() < HEADER_LEN + 12 + 20 {
+            return Err(invalid("too short"));
+        }
+        if data[..4] != MIDX_MAGIC || data[4] != MIDX_VERSION {
+            return Err(invalid("not a version 1 multi-pack-index"));
+        }
+        if data[5] != OID_VERSION {
+            return Err(invalid("not a SHA-1 multi-pack-index"));
+        }
+        if data[7] != 0 {
+            return Err(invalid("incremental multi-pack-index"));
+        }
+        let (content, checksum) = data.split_at(data.len() - 20);
+        if Sha1::digest(content).as_slice() != checksum {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let chunk_count = data[6] as usize;
+        let pack_count = read_u32(data, 8) as usize;
+        if content.len() < HEADER_LEN + (chunk_count + 1) * 12 {
+            return Err(invalid("truncated"));
+        }
+        let table = |n: usize| {
+            let at = HEADER_LEN + n * 12;
+            let offset = u64::from_be_bytes(data[at + 4..at + 12].try_into().unwrap());
+            (&data[at..at + 4], offset as usize)
+        };
+        let chunk = |id: [u8; 4]| -> Result<Option<&[u8]>, GitError> {
+            let Some(n) = (0..chunk_count).find(|n| table(*n).0 == id) else {
+                return Ok(None);
+            };
+            let (start, end) = (table(n).1, table(n + 1).1);
+            content
+                .get(start..end)
+                .filter(|_| start <= end)
+                .map(Some)
+                .ok_or_else(|| invalid("chunk out of range"))
+        };
+        let required = |id: [u8; 4]| {
+            chunk(id)?
+                .ok_or_else(|| invalid(&format!("missing chunk {}", String::from_utf8_lossy(&id))))
+        };
+
+        let names = required(CHUNK_PACK_NAMES)?;
+        let pack_names: Vec<String> = names
+            .split(|byte| *byte == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        if pack_names.len() != pack_count {
+            return Err(invalid("pack count mismatch"));
+        }
+
+        let fanout_chunk = required(CHUNK_OID_FANOUT)?;
+        if fanout_chunk.len() != 256 * 4 {
+            return Err(invalid("fan-out of the wrong size"));
+        }
+        let mut fanout = [0; 256];
+        for (n, count) in fanout.iter_mut().enumerate() {
+            *count = read_u32(fanout_chunk, n * 4);
+        }
+        if fanout.windows(2).any(|w| w[0] > w[1]) {
+            return Err(invalid("fan-out not sorted"));
+        }
+        let count = fanout[255] as usize;
+        let lookup = required(CHUNK_OID_LOOKUP)?;
+        let offsets = required(CHUNK_OBJECT_OFFSETS)?;
+        if lookup.len() != count * 20 || offsets.len() != count * 8 {
+            return Err(invalid("object count mismatch"));
+        }
+        let large_offsets = chunk(CHUNK_LARGE_OFFSETS)?;
+
+        let mut entries = Vec::with_capacity(count);
+        for n in 0..count {
+            let pack = read_u32(offsets, n * 8);
+            if pack as usize >= pack_count {
+                return Err(invalid("pack id out of range"));
+            }
+            let offset = read_u32(offsets, n * 8 + 4);
+            let offset = match large_offsets {
+                Some(large_offsets) if offset & LARGE_OFFSET as u32 != 0 => {
+                    let at = (offset & !(LARGE_OFFSET as u32)) as usize * 8;
+                    let bytes = large_offsets
+                        .get(at..at + 8)
+                        .ok_or_else(|| invalid("large offset out of range"))?;
+                    u64::from_be_bytes(bytes.try_into().unwrap())
+                }
+                _ => offset as u64,
+            };
+            entries.push(MultiPackEntry {
+                hash: SHA1::from_bytes(&lookup[n * 20..n * 20 + 20]),
+                pack,
+                offset,
+            });
+        }
+        if entries.windows(2).any(|w| w[0].hash.0 >= w[1].hash.0) {
+            return Err(invalid("object names not sorted"));
+        }
+        Ok(MultiPackIndex {
+            pack_names,
+            fanout,
+            entries,
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Self, GitError> {
+        let data = fs::read(path)
+            .map_err(|e| GitError::InvalidMultiPackIndex(format!("{}: {}", path.display(), e)))?;
+        MultiPackIndex::from_bytes(&data)
+    }
+
+    /// Finds `hash` among the objects sharing its first byte.
+    pub fn find(&self, hash: &SHA1) -> Option<&MultiPackEntry> {
+        let first = hash.0[0] as usize;
+        let start = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1] as usize
+        };
+        let candidates = &self.entries[start..self.fanout[first] as usize];
+        candidates
+            .binary_search_by(|entry| entry.hash.0.cmp(&hash.0))
+            .ok()
+            .map(|n| &candidates[n])
+    }
+
+    /// The names of the `.idx` of the packs, an entry refers to its pack by position.
+    pub fn pack_names(&self) -> &[String] {
+        &self.pack_names
+    }
+
+    pub fn entries(&self) -> &[MultiPackEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Reads the objects of the packs of a directory through its multi-pack-index.
+pub struct MultiPackReader {
+    index: MultiPackIndex,
+    /// In the order of [`MultiPackIndex::pack_names`].
+    packs: Vec<PackReader>,
+}
+
+impl MultiPackReader {
+    /// Opens the packs of `pack_dir` listed in its multi-pack-index.
+    pub fn open(pack_dir: &Path) -> Result<Self, GitError> {
+        let index = MultiPackIndex::read(&pack_dir.join(MULTI_PACK_INDEX))?;
+        let packs = index
+            .pack_names()
+            .iter()
+            .map(|name| {
+                let index_path = pack_dir.join(name);
+                PackReader::open(&index_path.with_extension("pack"), &index_path)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(MultiPackReader { index, packs })
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn contains(&self, hash: &SHA1) -> bool {
+        self.index.find(hash).is_some()
+    }
+
+    /// Reads the object `hash`, `None` when no pack has it.
+    pub fn get(&self, hash: &SHA1) -> Result<Option<Entry>, GitError> {
+        let Some(entry) = self.index.find(hash) else {
+            return Ok(None);
+        };
+        self.packs[entry.pack as usize]
+            .get_at(hash, entry.offset)
+            .map(Some)
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use venus::hash::SHA1;
+
+    use crate::internal::pack::index::{encode_index, IndexEntry, PackIndex};
+    use crate::internal::pack::Pack;
+    use crate::test_support::{FixtureConfig, SyntheticRepo};
+
+    use super::{
+        encode_multi_pack_index, write_multi_pack_index, MultiPackIndex, MultiPackReader,
+        MULTI_PACK_INDEX,
+    };
+
+    fn index(entries: &[(u8, u64)], pack_hash: u8) -> PackIndex {
+        let entries = entries
+            .iter()
+            .map(|(first, offset)| {
+                let mut hash = [0x11; 20];
+                hash[0] = *first;
+                IndexEntry {
+                    hash: SHA1(hash),
+                    crc32: 0,
+                    offset: *offset,
+                }
+            })
+            .collect();
+        PackIndex::from_bytes(&encode_index(entries, &SHA1([pack_hash; 20]))).unwrap()
+    }
+
+    #[test]
+    fn test_encode_multi_pack_index() {
+        let newer = index(&[(0x10, 12), (0xff, 0x1_0000_0000)], 1);
+        let older = index(&[(0x10, 40), (0x00, 0x8000_0000)], 2);
+        let data = encode_multi_pack_index(&[
+            ("pack-b.idx".to_owned(), &newer),
+            ("pack-a.idx".to_owned(), &older),
+        ]);
+        assert_eq!(&data[..12], b"MIDX\x01\x01\x05\x00\x00\x00\x00\x02");
+
+        let midx = MultiPackIndex::from_bytes(&data).unwrap();
+        assert_eq!(midx.pack_names(), ["pack-a.idx", "pack-b.idx"]);
+        assert_eq!(midx.len(), 3);
+        let find = |first: u8| {
+            let mut hash = [0x11; 20];
+            hash[0] = first;
+            midx.find(&SHA1(hash))
+                .map(|entry| (entry.pack, entry.offset))
+        };
+        // in both packs, indexed in the preferred one
+        assert_eq!(find(0x10), Some((1, 12)));
+        assert_eq!(find(0x00), Some((0, 0x8000_0000)));
+        assert_eq!(find(0xff), Some((1, 0x1_0000_0000)));
+        assert_eq!(find(0x12), None);
+
+        // without large offsets, offsets up to 4 GiB are stored as they are
+        let small = encode_multi_pack_index(&[("pack-a.idx".to_owned(), &older)]);
+        assert_eq!(small[6], 4);
+        let midx = MultiPackIndex::from_bytes(&small).unwrap();
+        assert_eq!(midx.entries()[0].offset, 0x8000_0000);
+
+        let mut corrupted = data.clone();
+        corrupted[40] ^= 1;
+        assert!(MultiPackIndex::from_bytes(&corrupted).is_err());
+        assert!(MultiPackIndex::from_bytes(&data[..60]).is_err());
+    }
+
+    #[test]
+    fn test_read_through_multi_pack_index() {
+        let dir = PathBuf::from("/tmp/.cache_temp").join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let repos: Vec<SyntheticRepo> = (0..3)
+            .map(|seed| {
+                SyntheticRepo::generate(&FixtureConfig {
+                    seed,
+                    delta_depth: 5,
+                    ..Default::default()
+                })
+            })
+            .collect();
+        for (n, repo) in repos.iter().enumerate() {
+            let pack = repo.to_pack();
+            fs::write(dir.join(format!("pack-{}.pack", n)), &pack).unwrap();
+            let mut p = Pack::new(None, Some(1024 * 1024 * 20), Some(dir.clone()));
+            p.decode_with_index(
+                &mut Cursor::new(pack),
+                &dir.join(format!("pack-{}.idx", n)),
+                |_| {},
+            )
+            .unwrap();
+        }
+        // an index without its pack is left out
+        fs::copy(dir.join("pack-0.idx"), dir.join("pack-9.idx")).unwrap();
+
+        let midx = write_multi_pack_index(&dir).unwrap();
+        assert_eq!(
+            midx.pack_names(),
+            ["pack-0.idx", "pack-1.idx", "pack-2.idx"]
+        );
+        assert!(!dir.join(format!("{}.lock", MULTI_PACK_INDEX)).exists());
+
+        let reader = MultiPackReader::open(&dir).unwrap();
+        let mut hashes: Vec<SHA1> = repos
+            .iter()
+            .flat_map(|repo| repo.objects.iter().map(|object| object.hash))
+            .collect();
+        hashes.sort_by_key(|hash| hash.0);
+        hashes.dedup();
+        assert_eq!(reader.len(), hashes.len());
+        for repo in &repos {
+            for object in &repo.objects {
+                let entry = reader.get(&object.hash).unwrap().unwrap();
+                assert_eq!(entry.obj_type, object.obj_type);
+                assert_eq!(entry.data, object.data);
+            }
+        }
+        assert!(reader.get(&SHA1([0x12; 20])).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -9,15 +9,26 @@ pub mod utils;
 pub mod cache;
 pub mod waitlist;
 pub mod cache_object;
+pub mod index;
+pub mod reader;
+pub mod verify;
+pub mod lanes;
+pub mod filter;
+pub mod midx;
+pub mod bitmap;
+pub mod scheduler;
+pub mod checkpoint;
 
 use venus::hash::SHA1;
-use threadpool::ThreadPool;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
 use venus::internal::object::ObjectTrait;
+use crate::internal::pack::lanes::{Lane, Lanes};
+use crate::internal::pack::scheduler::Scheduler;
 use crate::internal::pack::waitlist::Waitlist;
 
-use self::cache::Caches;
+use self::cache::CacheStorage;
 
 ///
 /// 
@@ -27,11 +38,15 @@ pub struct Pack {
     pub number: usize,
     pub signature: SHA1,
     pub objects: Vec<Box<dyn ObjectTrait>>,
-    pub pool: Arc<ThreadPool>,
+    pub pool: Arc<Scheduler>, // rebuilds the deltas, and writes the objects spilled by the default `Caches`
     pub waitlist: Arc<Waitlist>,
-    pub caches: Arc<Caches>,
+    pub caches: Arc<dyn CacheStorage>,
     pub mem_limit: usize,
-    pub cache_objs_mem: Arc<AtomicUsize> // the memory size of CacheObjects in this Pack
+    pub cache_objs_mem: Arc<AtomicUsize>, // the memory size of CacheObjects in this Pack
+    pub max_delta_depth: usize, // deltas allowed between an object and its undeltified base
+    pub max_object_size: usize, // in bytes, of an object and of the data of a delta
+    pub lanes: Option<(Arc<Lanes>, Lane)>, // where `decode_tokio` rebuilds the deltas, if not on the blocking threads of the runtime
+    pub checkpoint: Option<(PathBuf, usize)>, // the directory of the checkpoints of a decode and the objects read between two
 }
 
 #[cfg(test)]
@@ -0,0 +1,296 @@
+//!
+//! Random access to the objects of a stored pack through its `.idx`, for serving fetches
+//! without decoding the whole pack. An object is read from the file when it is asked for, and
+//! the bases of a delta are read and applied on the way.
+//!
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use flate2::read::ZlibDecoder;
+
+use venus::errors::GitError;
+use venus::hash::SHA1;
+use venus::internal::object::types::ObjectType;
+use venus::internal::pack::entry::Entry;
+
+use crate::internal::pack::cache_object::CacheObject;
+use crate::internal::pack::index::PackIndex;
+use crate::internal::pack::{utils, Pack};
+
+/// Where the base of a packed object is.
+enum Base {
+    Offset(u64),
+    Hash(SHA1),
+}
+
+/// An object as stored in the pack, a delta is not applied yet.
+struct PackedObject {
+    obj_type: ObjectType,
+    base: Option<Base>,
+    data: Vec<u8>,
+}
+
+pub struct PackReader {
+    file: Mutex<File>,
+    index: PackIndex,
+    /// The offsets of the objects, sorted, an object ends where the next one starts.
+    offsets: Vec<u64>,
+    /// The offset of the trailing checksum.
+    end: u64,
+}
+
+impl PackReader {
+    /// Opens the pack at `pack_path` with its index at `index_path`, they must belong together.
+    pub fn open(pack_path: &Path, index_path: &Path) -> Result<Self, GitError> {
+        let index = PackIndex::read(index_path)?;
+        let mut file = File::open(pack_path).map_err(read_error)?;
+        let len = file.metadata().map_err(read_error)?.len();
+        if len < 32 {
+            return Err(GitError::InvalidPackFile(format!("{} bytes", len)));
+        }
+
+        let mut header = [0; 12];
+        file.read_exact(&mut header).map_err(read_error)?;
+        let (object_num, _) = Pack::check_header(&mut Cursor::new(header))?;
+        if object_num as usize != index.len() {
+            return Err(GitError::InvalidPackFile(format!(
+                "{} objects, the index has {}",
+                object_num,
+                index.len()
+            )));
+        }
+        let mut trailer = [0; 20];
+        file.seek(SeekFrom::Start(len - 20)).map_err(read_error)?;
+        file.read_exact(&mut trailer).map_err(read_error)?;
+        if SHA1::from_bytes(&trailer) != index.pack_hash {
+            return Err(GitError::InvalidPackFile(format!(
+                "The pack file hash {} does not match the index",
+                SHA1::from_bytes(&trailer).to_plain_str()
+            )));
+        }
+
+        let mut offsets: Vec<u64> = index.entries().iter().map(|e| e.offset).collect();
+        offsets.sort_unstable();
+        if offsets.first().is_some_and(|first| *first < 12) || offsets.last() >= Some(&(len - 20)) {
+            return Err(GitError::InvalidIdxFile(String::from(
+                "offset out of the pack",
+            )));
+        }
+        Ok(PackReader {
+            file: Mutex::new(file),
+            index,
+            offsets,
+            end: len - 20,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn contains(&self, hash: &SHA1) -> bool {
+        self.index.find(hash).is_some()
+    }
+
+    /// The index of the pack, read when it is opened.
+    pub fn index(&self) -> &PackIndex {
+        &self.index
+    }
+
+    /// The ids of the objects of the pack, sorted.
+    pub fn hashes(&self) -> impl Iterator<Item = &SHA1> {
+        self.index.entries().iter().map(|entry| &entry.hash)
+    }
+
+    /// Reads the object `hash`, `None` when the pack does not have it.
+    pub fn get(&self, hash: &SHA1) -> Result<Option<Entry>, GitError> {
+        let Some(entry) = self.index.find(hash) else {
+            return Ok(None);
+        };
+        self.get_at(hash, entry.offset).map(Some)
+    }
+
+    /// Reads the object `hash` at `offset`, found in another index of the pack, e.g. a
+    /// multi-pack-index.
+    pub(crate) fn get_at(&self, hash: &SHA1, offset: u64) -> Result<Entry, GitError> {
+        let entry = self.read_at(offset)?;
+        if entry.hash != *hash {
+            return Err(GitError::InvalidPackFile(format!(
+                "The object {} is read as {}",
+                hash.to_plain_str(),
+                entry.hash.to_plain_str()
+            )));
+        }
+        Ok(entry)
+    }
+
+    /// Reads the object at `offset`, following its delta chain down to the full object.
+    fn read_at(&self, offset: u64) -> Result<Entry, GitError> {
+        let mut deltas = Vec::new();
+        let mut next = offset;
+        let base = loop {
+            let object = self.read_packed(next)?;
+            next = match &object.base {
+                None => break object,
+                Some(Base::Offset(base)) => *base,
+                Some(Base::Hash(hash)) => {
+                    self.index
+                        .find(hash)
+                        .ok_or_else(|| GitError::NotFountHashValue(hash.to_plain_str()))?
+                        .offset
+                }
+            };
+            deltas.push(object);
+            // every object of the pack is in a chain at most once
+            if deltas.len() > self.len() {
+                return Err(GitError::DeltaObjectError(String::from("delta chain loop")));
+            }
+        };
+
+        let mut current = Arc::new(CacheObject::new_for_undeltified(
+            base.obj_type,
+            base.data,
+            0,
+        ));
+        for delta in deltas.into_iter().rev() {
+            let mut object = CacheObject::default();
+            object.obj_type = delta.obj_type;
+            object.data_decompress = delta.data;
+            let delta = object;
+            current = Arc::new(Pack::rebuild_delta(delta, current)?);
+        }
+        Ok(current.to_entry())
+    }
+
+    /// Reads the object at `offset` as it is stored.
+    fn read_packed(&self, offset: u64) -> Result<PackedObject, GitError> {
+        let end = match self.offsets.binary_search(&offset) {
+            Ok(n) => self.offsets.get(n + 1).copied().unwrap_or(self.end),
+            Err(_) => {
+                return Err(GitError::InvalidPackFile(format!(
+                    "no object at offset {}",
+                    offset
+                )))
+            }
+        };
+        let mut raw = vec![0; (end - offset) as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(offset)).map_err(read_error)?;
+            file.read_exact(&mut raw).map_err(read_error)?;
+        }
+
+        let mut reader = Cursor::new(raw);
+        let mut header_len = 0;
+        let (type_bits, size) =
+            utils::read_type_and_varint_size(&mut reader, &mut header_len).map_err(read_error)?;
+        let obj_type = ObjectType::from_u8(type_bits)?;
+        let base = match obj_type {
+            ObjectType::OffsetDelta => {
+                let (distance, _) = utils::read_offset_encoding(&mut reader).map_err(read_error)?;
+                let base = offset
+                    .checked_sub(distance)
+                    .filter(|_| distance != 0)
+                    .ok_or_else(|| {
+                        GitError::InvalidObjectInfo("Invalid OffsetDelta offset".to_string())
+                    })?;
+                Some(Base::Offset(base))
+            }
+            ObjectType::HashDelta => {
+                let mut hash = [0; 20];
+                reader.read_exact(&mut hash).map_err(read_error)?;
+                Some(Base::Hash(SHA1::from_bytes(&hash)))
+            }
+            _ => None,
+        };
+
+        let mut data = Vec::new();
+        data.try_reserve_exact(size).map_err(|e| {
+            GitError::InvalidPackFile(format!("Invalid object size {}: {}", size, e))
+        })?;
+        ZlibDecoder::new(reader)
+            .take(size as u64 + 1)
+            .read_to_end(&mut data)
+            .map_err(|e| GitError::InvalidPackFile(format!("Decompression error: {}", e)))?;
+        if data.len() != size {
+            return Err(GitError::InvalidPackFile(format!(
+                "The object size {} does not match the expected size {}",
+                data.len(),
+                size
+            )));
+        }
+        Ok(PackedObject {
+            obj_type,
+            base,
+            data,
+        })
+    }
+}
+
+fn read_error(err: std::io::Error) -> GitError {
+    GitError::InvalidPackFile(format!("Read error: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use venus::hash::SHA1;
+
+    use crate::internal::pack::Pack;
+    use crate::test_support::{DeltaKind, FixtureConfig, SyntheticRepo};
+
+    use super::PackReader;
+
+    fn read_all(delta_kind: DeltaKind) {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 10,
+            delta_kind,
+            ..Default::default()
+        });
+        let dir = PathBuf::from("/tmp/.cache_temp").join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let (pack_path, index_path) = (dir.join("test.pack"), dir.join("test.idx"));
+        let pack = repo.to_pack();
+        fs::write(&pack_path, &pack).unwrap();
+        let mut p = Pack::new(None, Some(1024 * 1024 * 20), Some(dir.clone()));
+        p.decode_with_index(&mut Cursor::new(pack), &index_path, |_| {})
+            .unwrap();
+
+        let reader = PackReader::open(&pack_path, &index_path).unwrap();
+        assert_eq!(reader.len(), repo.objects.len());
+        for object in repo.objects.iter().rev() {
+            let entry = reader.get(&object.hash).unwrap().unwrap();
+            assert_eq!(entry.obj_type, object.obj_type);
+            assert_eq!(entry.data, object.data);
+        }
+        assert!(reader.get(&SHA1([0x12; 20])).unwrap().is_none());
+
+        // an index of another pack is refused
+        fs::write(
+            &pack_path,
+            SyntheticRepo::generate(&FixtureConfig::default()).to_pack(),
+        )
+        .unwrap();
+        assert!(PackReader::open(&pack_path, &index_path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_offset_deltas() {
+        read_all(DeltaKind::Offset);
+    }
+
+    #[test]
+    fn test_read_ref_deltas() {
+        read_all(DeltaKind::Ref);
+    }
+}
@@ -0,0 +1,139 @@
+//!
+//! The worker threads of a decode, shared by the rebuilding of the deltas and the writes of the
+//! objects the cache spills to disk. The jobs run on a work-stealing pool: a job queued by a
+//! worker, e.g. a delta whose base it just rebuilt, is run next by the same worker unless an
+//! idle one steals it, so the chains of deltas are resolved depth first and their bases can be
+//! dropped early. The jobs queued or running are counted, whoever queues them can wait for the
+//! count to go down without spinning.
+//!
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+
+struct State {
+    /// Jobs queued or running.
+    pending: Mutex<usize>,
+    /// Notified every time a job ends.
+    done: Condvar,
+}
+
+pub struct Scheduler {
+    pool: rayon::ThreadPool,
+    state: Arc<State>,
+}
+
+impl Scheduler {
+    /// Starts `threads` workers, at least 1.
+    pub fn new(threads: usize) -> Self {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.max(1))
+            .thread_name(|n| format!("pack-worker-{}", n))
+            .build()
+            .expect("failed to start the worker threads");
+        Scheduler {
+            pool,
+            state: Arc::new(State {
+                pending: Mutex::new(0),
+                done: Condvar::new(),
+            }),
+        }
+    }
+
+    /// The number of worker threads.
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        *self.state.pending.lock().unwrap() += 1;
+        let state = self.state.clone();
+        self.pool.spawn(move || {
+            // a failing job is the business of whoever queued it, not of the worker
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            *state.pending.lock().unwrap() -= 1;
+            state.done.notify_all();
+        });
+    }
+
+    /// The jobs queued or running.
+    pub fn pending(&self) -> usize {
+        *self.state.pending.lock().unwrap()
+    }
+
+    /// Blocks while `blocked` returns true for the number of jobs pending, checked again every
+    /// time a job ends. `blocked` has to be false once no job is pending, or this never returns.
+    /// Must not be called from a job, which may wait for itself.
+    pub fn wait_while(&self, mut blocked: impl FnMut(usize) -> bool) {
+        let pending = self.state.pending.lock().unwrap();
+        let _unused = self
+            .state
+            .done
+            .wait_while(pending, |pending| blocked(*pending))
+            .unwrap();
+    }
+
+    /// Blocks until every job queued is done, those they queue included.
+    pub fn join(&self) {
+        self.wait_while(|pending| pending > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Barrier};
+    use std::time::Duration;
+
+    use crate::internal::pack::scheduler::Scheduler;
+
+    #[test]
+    fn test_join_waits_for_nested_jobs() {
+        let scheduler = Arc::new(Scheduler::new(2));
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let (inner, done) = (scheduler.clone(), done.clone());
+            scheduler.execute(move || {
+                for _ in 0..10 {
+                    let done = done.clone();
+                    inner.execute(move || {
+                        done.fetch_add(1, Ordering::Relaxed);
+                    });
+                }
+            });
+        }
+        scheduler.join();
+        assert_eq!(done.load(Ordering::Relaxed), 100);
+        assert_eq!(scheduler.pending(), 0);
+    }
+
+    #[test]
+    fn test_wait_while_a_job_runs() {
+        let scheduler = Scheduler::new(1);
+        let barrier = Arc::new(Barrier::new(2));
+        let started = barrier.clone();
+        scheduler.execute(move || {
+            started.wait();
+        });
+        scheduler.execute(|| {});
+        assert_eq!(scheduler.pending(), 2);
+        barrier.wait();
+        // woken up by the end of the jobs, not by polling
+        scheduler.wait_while(|pending| pending > 1);
+        scheduler.join();
+        assert_eq!(scheduler.pending(), 0);
+    }
+
+    #[test]
+    fn test_scheduler_survives_a_panic() {
+        let scheduler = Scheduler::new(1);
+        scheduler.execute(|| panic!("a failing job"));
+        let (sender, receiver) = mpsc::channel();
+        scheduler.execute(move || sender.send(()).unwrap());
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+        scheduler.join();
+        assert_eq!(scheduler.pending(), 0);
+    }
+}
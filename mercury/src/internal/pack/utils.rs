@@ -97,6 +97,10 @@ pub fn read_type_and_varint_size<R: Read>(stream: &mut R, offset: &mut usize) ->
         // Increment the offset by one byte
         *offset += 1;
 
+        if shift > 63 {
+            // Size too long for u64
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Object size too long"));
+        }
         size |= (next_byte as u64) << shift;
         shift += 7; // Each subsequent byte contributes 7 more bits
         more_bytes = continuation;
@@ -181,12 +185,19 @@ pub fn read_offset_encoding<R: Read>(stream: &mut R) -> io::Result<(u64, usize)>
     loop {
         let (byte_value, more_bytes) = read_byte_and_check_continuation(stream)?;
         offset += 1;
+        if value > u64::MAX >> 7 {
+            // Offset too long for u64
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Offset too long"));
+        }
         value = (value << 7) | byte_value as u64;
         if !more_bytes {
             return Ok((value, offset));
         }
 
-        value += 1; //important!: for n >= 2 adding 2^7 + 2^14 + ... + 2^(7*(n-1)) to the result
+        //important!: for n >= 2 adding 2^7 + 2^14 + ... + 2^(7*(n-1)) to the result
+        value = value.checked_add(1).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Offset too long")
+        })?;
     }
 }
 
@@ -242,7 +253,7 @@ pub fn read_partial_int<R: Read>(
 /// Calculate the SHA1 hash of the given object.
 /// <br> "`<type> <size>\0<content>`"
 /// <br> data: The decompressed content of the object
-pub fn calculate_object_hash(obj_type: ObjectType, data: &Vec<u8>) -> SHA1 {
+pub fn calculate_object_hash(obj_type: ObjectType, data: &[u8]) -> SHA1 {
     let mut hash = Sha1::new();
     // Header: "<type> <size>\0"
     hash.update(obj_type.to_bytes());
@@ -302,13 +313,29 @@ mod tests {
     use std::io;
     use std::io::Cursor;
     use std::io::Read;
+    use proptest::prelude::*;
     use venus::internal::object::types::ObjectType;
 
+    use crate::internal::pack::encode::encode_offset;
     use crate::internal::pack::utils::*;
 
+    /// size encoding of the delta header, the inverse of [read_varint_le]
+    fn write_varint_le(mut value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
     #[test]
     fn test_calc_obj_hash() {
-        let hash = calculate_object_hash(ObjectType::Blob, &b"a".to_vec());
+        let hash = calculate_object_hash(ObjectType::Blob, b"a");
         assert_eq!(hash.to_plain_str(), "2e65efe2a145dda7ee51d1741299f848e5bf752e");
     }
 
@@ -495,4 +522,35 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), (11013, 2));
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_read_offset_encoding_too_long() {
+        let data = vec![0xff; 12];
+        let result = read_offset_encoding(&mut Cursor::new(data));
+        assert!(result.is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_read_varint_le_round_trip(value in any::<u64>()) {
+            let data = write_varint_le(value);
+            let (decoded, bytes) = read_varint_le(&mut Cursor::new(&data)).unwrap();
+            prop_assert_eq!(decoded, value);
+            prop_assert_eq!(bytes, data.len());
+        }
+
+        #[test]
+        fn prop_read_offset_encoding_round_trip(value in 1..u64::MAX >> 8) {
+            let data = encode_offset(value as usize);
+            let (decoded, bytes) = read_offset_encoding(&mut Cursor::new(&data)).unwrap();
+            prop_assert_eq!(decoded, value);
+            prop_assert_eq!(bytes, data.len());
+        }
+
+        #[test]
+        fn prop_readers_never_panic(data in proptest::collection::vec(any::<u8>(), 0..32)) {
+            let _ = read_varint_le(&mut Cursor::new(&data));
+            let _ = read_offset_encoding(&mut Cursor::new(&data));
+            let _ = read_type_and_varint_size(&mut Cursor::new(&data), &mut 0);
+        }
+    }
+}
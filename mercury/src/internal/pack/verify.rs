@@ -0,0 +1,554 @@
+//!
+//! Checks a pack like `git verify-pack` and the object checks of `git fsck` do, and reports
+//! everything found wrong instead of stopping at the first error: the checksum of the pack,
+//! the objects against an index, the delta chains, the format of the trees, commits and tags,
+//! and the links between the objects.
+//!
+//! The objects are read one after the other, so a broken delta is reported and the rest of
+//! the pack still checked. Only a pack that can't be read any further, e.g. truncated or with a
+//! broken zlib stream, stops the checks, as the next object can't be found.
+//!
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use venus::hash::SHA1;
+use venus::internal::object::tree::TreeItemMode;
+use venus::internal::object::types::ObjectType;
+
+use crate::internal::pack::cache_object::CacheObject;
+use crate::internal::pack::index::{IndexEntry, PackIndex};
+use crate::internal::pack::wrapper::Wrapper;
+use crate::internal::pack::Pack;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FindingKind {
+    /// The pack can't be read from here on.
+    Pack,
+    /// The trailer is not the hash of the pack.
+    Checksum,
+    /// The pack and its index disagree.
+    Index,
+    /// A delta can't be rebuilt: broken, too deep or its base missing.
+    Delta,
+    Tree,
+    Commit,
+    Tag,
+    /// An object refers to one of another type, or, with
+    /// [`VerifyOptions::check_connectivity`], to one the pack does not have.
+    Link,
+}
+
+impl Display for FindingKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FindingKind::Pack => "pack",
+            FindingKind::Checksum => "checksum",
+            FindingKind::Index => "index",
+            FindingKind::Delta => "delta",
+            FindingKind::Tree => "tree",
+            FindingKind::Commit => "commit",
+            FindingKind::Tag => "tag",
+            FindingKind::Link => "link",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something wrong with a pack, at the object at `offset` or with the id `hash` when known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub kind: FindingKind,
+    pub offset: Option<usize>,
+    pub hash: Option<SHA1>,
+    pub message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(hash) = self.hash {
+            write!(f, " {}", hash.to_plain_str())?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// What [`Pack::verify`] checks besides the pack itself.
+#[derive(Default)]
+pub struct VerifyOptions<'a> {
+    /// The index of the pack, every object must be in it with the same offset and CRC32.
+    pub index: Option<&'a PackIndex>,
+    /// Reports the commits, trees and tags referring to an object the pack does not have.
+    /// Off for the packs of a push, whose oldest commits have their parents elsewhere.
+    pub check_connectivity: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Objects of the pack, as its header says.
+    pub objects: usize,
+    /// Objects rebuilt and checked, the deltas included.
+    pub checked: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    fn add(&mut self, kind: FindingKind, offset: Option<usize>, hash: Option<SHA1>, message: String) {
+        self.findings.push(Finding { kind, offset, hash, message });
+    }
+}
+
+/// A reference from an object to another one, which must have the type `to_type`.
+struct Link {
+    from: SHA1,
+    to: SHA1,
+    to_type: ObjectType,
+}
+
+/// What is gathered while the objects are read, checked once the whole pack is.
+#[derive(Default)]
+struct VerifyState {
+    report: VerifyReport,
+    /// The type of every object checked.
+    types: HashMap<SHA1, ObjectType>,
+    /// The offsets of every object read.
+    offsets: HashSet<usize>,
+    links: Vec<Link>,
+}
+
+impl Pack {
+    /// Checks the pack read from `pack`, and its index with [`VerifyOptions::index`], and
+    /// returns what is wrong with them. Nothing is passed to a callback: the objects are
+    /// dropped once checked, the bases of the deltas are kept in the caches of the pack
+    /// meanwhile, within its `mem_limit`. Like in a decode, the chains longer than
+    /// [`Pack::with_max_delta_depth`] and the objects larger than
+    /// [`Pack::with_max_object_size`] are refused.
+    pub fn verify(&mut self, pack: &mut (impl Read + Send), options: VerifyOptions) -> VerifyReport {
+        let mut state = VerifyState::default();
+        let mut reader = Wrapper::new(io::BufReader::new(pack));
+        let index: HashMap<u64, &IndexEntry> = options
+            .index
+            .map(|index| index.entries().iter().map(|entry| (entry.offset, entry)).collect())
+            .unwrap_or_default();
+
+        match Pack::check_header(&mut reader) {
+            Ok((number, _)) => self.number = number as usize,
+            Err(e) => {
+                state.report.add(FindingKind::Pack, Some(0), None, e.to_string());
+                return state.report;
+            }
+        }
+        state.report.objects = self.number;
+        if let Some(pack_index) = options.index {
+            if pack_index.len() != self.number {
+                let message = format!("{} objects, the index has {}", self.number, pack_index.len());
+                state.report.add(FindingKind::Index, None, None, message);
+            }
+        }
+
+        let mut offset = 12;
+        let mut complete = true;
+        for _ in 0..self.number {
+            let start = offset;
+            let obj = match self.decode_pack_object(&mut reader, &mut offset) {
+                Ok(obj) => obj,
+                Err(e) => {
+                    state.report.add(FindingKind::Pack, Some(start), None, e.to_string());
+                    complete = false;
+                    break;
+                }
+            };
+            state.offsets.insert(start);
+            if !index.is_empty() {
+                match index.get(&(start as u64)) {
+                    Some(entry) if entry.crc32 != obj.crc32 => {
+                        let message = String::from("the CRC32 does not match the index");
+                        state.report.add(FindingKind::Index, Some(start), Some(entry.hash), message);
+                    }
+                    Some(_) => {}
+                    None => {
+                        let message = String::from("not in the index");
+                        state.report.add(FindingKind::Index, Some(start), None, message);
+                    }
+                }
+            }
+            match obj.obj_type {
+                ObjectType::OffsetDelta => {
+                    if let Some(base) = self.caches.get_by_offset(obj.base_offset) {
+                        self.verify_delta(&mut state, &index, obj, base);
+                    } else if state.offsets.contains(&obj.base_offset) {
+                        // the base is broken, or waiting for its own base
+                        self.waitlist.insert_offset(obj.base_offset, obj);
+                    } else {
+                        let message = format!("no object at the offset {} of its base", obj.base_offset);
+                        state.report.add(FindingKind::Delta, Some(start), None, message);
+                    }
+                }
+                ObjectType::HashDelta => {
+                    if let Some(base) = self.caches.get_by_hash(obj.base_ref) {
+                        self.verify_delta(&mut state, &index, obj, base);
+                    } else {
+                        self.waitlist.insert_ref(obj.base_ref, obj);
+                    }
+                }
+                _ => self.verify_object(&mut state, &index, obj),
+            }
+        }
+
+        if complete {
+            let hash = reader.final_hash();
+            let mut trailer = [0; 20];
+            match reader.read_exact(&mut trailer) {
+                Ok(()) => {
+                    self.signature = SHA1::from_bytes(&trailer);
+                    if hash != self.signature {
+                        let message = format!("the trailer is {}, not the hash of the pack {}", self.signature.to_plain_str(), hash.to_plain_str());
+                        state.report.add(FindingKind::Checksum, None, None, message);
+                    }
+                    if options.index.is_some_and(|index| index.pack_hash != self.signature) {
+                        let message = String::from("the trailer does not match the index");
+                        state.report.add(FindingKind::Index, None, None, message);
+                    }
+                }
+                Err(e) => state.report.add(FindingKind::Pack, Some(offset), None, format!("no trailer: {}", e)),
+            }
+            for entry in index.values() {
+                if !state.offsets.contains(&(entry.offset as usize)) {
+                    let message = format!("in the index at offset {}, not in the pack", entry.offset);
+                    state.report.add(FindingKind::Index, None, Some(entry.hash), message);
+                }
+            }
+        }
+
+        // the deltas left have a base missing from the pack, or broken
+        let mut waiting: Vec<(usize, String)> = self.waitlist.map_offset.iter()
+            .flat_map(|item| item.value().iter().map(|obj| (obj.offset, format!("the base at offset {} is broken", obj.base_offset))).collect::<Vec<_>>())
+            .chain(self.waitlist.map_ref.iter()
+                .flat_map(|item| item.value().iter().map(|obj| (obj.offset, format!("the base {} is missing or broken", obj.base_ref.to_plain_str()))).collect::<Vec<_>>()))
+            .collect();
+        waiting.sort();
+        for (offset, message) in waiting {
+            state.report.add(FindingKind::Delta, Some(offset), None, message);
+        }
+        self.waitlist.map_offset.clear();
+        self.waitlist.map_ref.clear();
+        self.caches.clear();
+
+        for link in &state.links {
+            let message = match state.types.get(&link.to) {
+                Some(to_type) if *to_type != link.to_type => {
+                    format!("refers to the {} {} as a {}", to_type, link.to.to_plain_str(), link.to_type)
+                }
+                None if options.check_connectivity => {
+                    format!("refers to the {} {} not in the pack", link.to_type, link.to.to_plain_str())
+                }
+                _ => continue,
+            };
+            state.report.add(FindingKind::Link, None, Some(link.from), message);
+        }
+        state.report
+    }
+
+    /// Rebuilds `delta` on `base`, then checks it and the deltas waiting for it.
+    fn verify_delta(&self, state: &mut VerifyState, index: &HashMap<u64, &IndexEntry>, delta: CacheObject, base: Arc<CacheObject>) {
+        if let Some(obj) = Self::apply_delta(state, self.max_delta_depth, delta, base) {
+            self.verify_object(state, index, obj);
+        }
+    }
+
+    fn apply_delta(state: &mut VerifyState, max_depth: usize, delta: CacheObject, base: Arc<CacheObject>) -> Option<CacheObject> {
+        let offset = delta.offset;
+        if base.delta_depth >= max_depth {
+            let message = format!("the delta chain is longer than the limit {}", max_depth);
+            state.report.add(FindingKind::Delta, Some(offset), None, message);
+            return None;
+        }
+        Pack::rebuild_delta(delta, base)
+            .map_err(|e| state.report.add(FindingKind::Delta, Some(offset), None, e.to_string()))
+            .ok()
+    }
+
+    /// Checks an undeltified object, then the deltas based on it, it is kept for them.
+    fn verify_object(&self, state: &mut VerifyState, index: &HashMap<u64, &IndexEntry>, obj: CacheObject) {
+        // a stack rather than a recursion, the delta chains may be thousands of objects long
+        let mut pending = vec![obj];
+        while let Some(obj) = pending.pop() {
+            let (offset, hash) = (obj.offset, obj.hash);
+            check_object(state, index, &obj);
+            let obj = self.caches.insert(offset, hash, obj);
+            for delta in self.waitlist.take(offset, hash) {
+                pending.extend(Self::apply_delta(state, self.max_delta_depth, delta, obj.clone()));
+            }
+        }
+    }
+}
+
+fn check_object(state: &mut VerifyState, index: &HashMap<u64, &IndexEntry>, obj: &CacheObject) {
+    let (offset, hash) = (obj.offset, obj.hash);
+    state.report.checked += 1;
+    state.types.insert(hash, obj.obj_type);
+    if let Some(entry) = index.get(&(offset as u64)) {
+        if entry.hash != hash {
+            let message = format!("the index has {} at this offset", entry.hash.to_plain_str());
+            state.report.add(FindingKind::Index, Some(offset), Some(hash), message);
+        }
+    }
+    let result = match obj.obj_type {
+        ObjectType::Commit => check_commit(&obj.data_decompress).map(|(tree, parents)| {
+            let parents = parents.into_iter().map(|parent| (parent, ObjectType::Commit));
+            std::iter::once((tree, ObjectType::Tree)).chain(parents).collect()
+        }),
+        ObjectType::Tree => check_tree(&obj.data_decompress),
+        ObjectType::Tag => check_tag(&obj.data_decompress).map(|target| vec![target]),
+        _ => Ok(Vec::new()),
+    };
+    match result {
+        Ok(links) => state.links.extend(links.into_iter().map(|(to, to_type)| Link { from: hash, to, to_type })),
+        Err(message) => {
+            let kind = match obj.obj_type {
+                ObjectType::Commit => FindingKind::Commit,
+                ObjectType::Tree => FindingKind::Tree,
+                _ => FindingKind::Tag,
+            };
+            state.report.add(kind, Some(offset), Some(hash), message);
+        }
+    }
+}
+
+/// Reads the `<key> <id>` header line at the start of `data`, returns the id and the rest.
+fn header_id<'a>(data: &'a [u8], key: &str) -> Result<(SHA1, &'a [u8]), String> {
+    let (line, rest) = split_line(data).ok_or_else(|| format!("no {} line", key))?;
+    let id = line
+        .strip_prefix(key.as_bytes())
+        .and_then(|id| id.strip_prefix(b" "))
+        .and_then(|id| std::str::from_utf8(id).ok())
+        .filter(|id| id.len() == 40)
+        .and_then(|id| SHA1::from_str(id).ok())
+        .ok_or_else(|| format!("invalid {} line", key))?;
+    Ok((id, rest))
+}
+
+fn split_line(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = data.iter().position(|b| *b == b'\n')?;
+    Some((&data[..end], &data[end + 1..]))
+}
+
+/// An `author` or a `committer` line: `<key> <name> <<email>> <timestamp> <timezone>`.
+fn check_signature(line: &[u8], key: &str) -> Result<(), String> {
+    let invalid = || format!("invalid {} line", key);
+    let line = line.strip_prefix(key.as_bytes()).and_then(|line| line.strip_prefix(b" ")).ok_or_else(invalid)?;
+    let email_end = line.iter().rposition(|b| *b == b'>').ok_or_else(invalid)?;
+    if !line[..email_end].contains(&b'<') {
+        return Err(invalid());
+    }
+    let date = std::str::from_utf8(&line[email_end + 1..]).map_err(|_| invalid())?;
+    match date.trim_start().split(' ').collect::<Vec<_>>()[..] {
+        [timestamp, timezone] if timestamp.parse::<u64>().is_ok()
+            && timezone.len() == 5
+            && timezone.starts_with(['+', '-'])
+            && timezone[1..].bytes().all(|b| b.is_ascii_digit()) => Ok(()),
+        _ => Err(invalid()),
+    }
+}
+
+/// Checks the headers of a commit, returns its tree and its parents.
+fn check_commit(data: &[u8]) -> Result<(SHA1, Vec<SHA1>), String> {
+    let (tree, mut rest) = header_id(data, "tree")?;
+    let mut parents = Vec::new();
+    while rest.starts_with(b"parent ") {
+        let (parent, next) = header_id(rest, "parent")?;
+        parents.push(parent);
+        rest = next;
+    }
+    for key in ["author", "committer"] {
+        let (line, next) = split_line(rest).ok_or_else(|| format!("no {} line", key))?;
+        check_signature(line, key)?;
+        rest = next;
+    }
+    Ok((tree, parents))
+}
+
+/// Checks the headers of a tag, returns the object it tags and its type.
+fn check_tag(data: &[u8]) -> Result<(SHA1, ObjectType), String> {
+    let (object, rest) = header_id(data, "object")?;
+    let (line, rest) = split_line(rest).ok_or("no type line")?;
+    let obj_type = line
+        .strip_prefix(b"type ")
+        .and_then(|name| std::str::from_utf8(name).ok())
+        .and_then(|name| ObjectType::from_string(name).ok())
+        .ok_or("invalid type line")?;
+    let (line, _) = split_line(rest).ok_or("no tag line")?;
+    if !line.starts_with(b"tag ") || line.len() == 4 {
+        return Err(String::from("invalid tag line"));
+    }
+    Ok((object, obj_type))
+}
+
+/// Checks the entries of a tree, `<mode> <name>\0<id>` each, sorted like git does with a
+/// tree named as if it ended with `/`. Returns the objects of the entries, but the commits
+/// of the submodules, which are in other repositories.
+fn check_tree(data: &[u8]) -> Result<Vec<(SHA1, ObjectType)>, String> {
+    let mut entries = Vec::new();
+    let mut previous: Option<Vec<u8>> = None;
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|b| *b == b' ').ok_or("truncated entry")?;
+        let mode = &rest[..space];
+        if mode.is_empty() || !mode.iter().all(|b| b.is_ascii_digit()) {
+            return Err(format!("invalid mode {}", String::from_utf8_lossy(mode)));
+        }
+        let mode = TreeItemMode::tree_item_type_from_bytes(mode).map_err(|e| e.to_string())?;
+        let rest_name = &rest[space + 1..];
+        let nul = rest_name.iter().position(|b| *b == b'\0').ok_or("truncated entry")?;
+        let name = &rest_name[..nul];
+        let display = String::from_utf8_lossy(name);
+        if name.is_empty() || name.contains(&b'/') || name == b"." || name == b".." || name == b".git" {
+            return Err(format!("invalid name '{}'", display));
+        }
+        let id = rest_name.get(nul + 1..nul + 21).ok_or("truncated entry")?;
+        rest = &rest_name[nul + 21..];
+
+        let mut key = name.to_vec();
+        if mode == TreeItemMode::Tree {
+            key.push(b'/');
+        }
+        if let Some(previous) = &previous {
+            if *previous == key || previous.strip_suffix(b"/") == Some(name) || key.strip_suffix(b"/") == Some(previous) {
+                return Err(format!("duplicate entry '{}'", display));
+            }
+            if *previous > key {
+                return Err(format!("entry '{}' not sorted", display));
+            }
+        }
+        previous = Some(key);
+
+        let obj_type = match mode {
+            TreeItemMode::Tree => ObjectType::Tree,
+            TreeItemMode::Commit => continue,
+            _ => ObjectType::Blob,
+        };
+        entries.push((SHA1::from_bytes(id), obj_type));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use venus::hash::SHA1;
+
+    use crate::internal::pack::index::{self, IndexEntry, PackIndex};
+    use crate::internal::pack::verify::{check_commit, check_tree, FindingKind, VerifyOptions};
+    use crate::internal::pack::Pack;
+    use crate::test_support::{corrupt, Corruption, DeltaKind, FixtureConfig, SyntheticRepo};
+
+    fn pack() -> Pack {
+        Pack::new(Some(2), Some(1024 * 1024 * 20), Some(PathBuf::from("/tmp/.cache_temp")))
+    }
+
+    fn kinds(data: &[u8], options: VerifyOptions) -> Vec<FindingKind> {
+        let report = pack().verify(&mut Cursor::new(data), options);
+        report.findings.iter().map(|finding| finding.kind).collect()
+    }
+
+    #[test]
+    fn test_verify_pack() {
+        let repo = SyntheticRepo::generate(&FixtureConfig { delta_depth: 5, ..Default::default() });
+        let data = repo.to_pack();
+        let report = pack().verify(&mut Cursor::new(&data), VerifyOptions { check_connectivity: true, ..Default::default() });
+        assert!(report.is_ok(), "{:?}", report.findings);
+        assert_eq!(report.objects, repo.objects.len());
+        assert_eq!(report.checked, repo.objects.len());
+
+        // a broken checksum is reported, every object is still checked
+        let mut broken = data.clone();
+        corrupt(&mut broken, Corruption::Signature);
+        let report = pack().verify(&mut Cursor::new(&broken), VerifyOptions::default());
+        assert_eq!(report.checked, repo.objects.len());
+        assert_eq!(report.findings.len(), 1);
+        assert_eq!(report.findings[0].kind, FindingKind::Checksum);
+
+        let truncated = &data[..data.len() / 2];
+        assert_eq!(kinds(truncated, VerifyOptions::default()), vec![FindingKind::Pack]);
+    }
+
+    #[test]
+    fn test_verify_delta_and_connectivity() {
+        let repo = SyntheticRepo::generate(&FixtureConfig {
+            delta_depth: 5,
+            delta_kind: DeltaKind::Ref,
+            ..Default::default()
+        });
+        // without their bases, the deltas of a thin pack are reported, and with the
+        // connectivity the links to the objects left out
+        let (thin, _) = repo.to_thin_pack();
+        let findings = kinds(&thin, VerifyOptions::default());
+        assert!(!findings.is_empty());
+        assert!(findings.iter().all(|kind| *kind == FindingKind::Delta));
+        let findings = kinds(&thin, VerifyOptions { check_connectivity: true, ..Default::default() });
+        assert!(findings.contains(&FindingKind::Link));
+
+        let data = repo.to_pack();
+        let mut p = pack().with_max_delta_depth(1);
+        let report = p.verify(&mut Cursor::new(&data), VerifyOptions::default());
+        assert!(!report.is_ok());
+        assert!(report.findings.iter().all(|finding| finding.kind == FindingKind::Delta));
+    }
+
+    #[test]
+    fn test_verify_with_index() {
+        let repo = SyntheticRepo::generate(&FixtureConfig::default());
+        let data = repo.to_pack();
+        let path = PathBuf::from("/tmp/.cache_temp/test_verify_with_index.idx");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        pack().decode_with_index(&mut Cursor::new(&data), &path, |_| {}).unwrap();
+        let good = PackIndex::read(&path).unwrap();
+        let report = pack().verify(&mut Cursor::new(&data), VerifyOptions { index: Some(&good), ..Default::default() });
+        assert!(report.is_ok(), "{:?}", report.findings);
+
+        // the index of another pack disagrees on everything
+        let mut entries: Vec<IndexEntry> = good.entries().to_vec();
+        entries[0].crc32 ^= 1;
+        entries.pop();
+        let other = PackIndex::from_bytes(&index::encode_index(entries, &SHA1::default())).unwrap();
+        let findings = kinds(&data, VerifyOptions { index: Some(&other), ..Default::default() });
+        assert_eq!(findings.iter().filter(|kind| **kind == FindingKind::Index).count(), 4);
+    }
+
+    #[test]
+    fn test_check_objects() {
+        let id = "8ab686eafeb1f44702738c8b0f24f2567c36da6d";
+        let commit = format!("tree {id}\nparent {id}\nauthor A <a@b.c> 1700000000 +0800\ncommitter A <a@b.c> 1700000000 +0800\n\nmessage\n");
+        let (tree, parents) = check_commit(commit.as_bytes()).unwrap();
+        assert_eq!(tree.to_plain_str(), id);
+        assert_eq!(parents.len(), 1);
+        assert!(check_commit(commit.replace("parent", "parnet").as_bytes()).is_err());
+        assert!(check_commit(commit.replace("+0800", "CST").as_bytes()).is_err());
+
+        let entry = |mode: &str, name: &str| [format!("{} {}\0", mode, name).as_bytes(), &[0; 20]].concat();
+        // `a-b` sorts before the tree `a`, which is compared as `a/`
+        let tree = [entry("100644", "a-b"), entry("40000", "a"), entry("160000", "c")].concat();
+        assert_eq!(check_tree(&tree).unwrap().len(), 2);
+        for tree in [
+            [entry("40000", "a"), entry("100644", "a-b")].concat(),
+            [entry("100644", "a"), entry("40000", "a")].concat(),
+            entry("100645", "a"),
+            entry("100644", "a/b"),
+            entry("100644", "a")[..10].to_vec(),
+        ] {
+            assert!(check_tree(&tree).is_err());
+        }
+    }
+}
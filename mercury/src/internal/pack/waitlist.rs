@@ -1,5 +1,6 @@
 use dashmap::DashMap;
 use venus::hash::SHA1;
+use crate::internal::pack::cache::table_size;
 use crate::internal::pack::cache_object::CacheObject;
 
 /// Waitlist for Delta objects while the Base object is not ready.
@@ -35,4 +36,11 @@ impl Waitlist {
         }
         res
     }
+
+    /// Memory used by the tables and the lists of the waitlist, the objects waiting record
+    /// their own.
+    pub fn memory_used_index(&self) -> usize {
+        table_size::<(usize, Vec<CacheObject>)>(self.map_offset.capacity())
+            + table_size::<(SHA1, Vec<CacheObject>)>(self.map_ref.capacity())
+    }
 }
\ No newline at end of file
@@ -5,6 +5,7 @@
 //! 
 use std::io::{self, Read, BufRead};
 
+use flate2::Crc;
 use sha1::{Sha1, Digest};
 
 use venus::hash::SHA1;
@@ -85,6 +86,47 @@ where
     }
 }
 
+/// `CrcReader` computes the CRC32 of the data read through it: the bytes of one packed object,
+/// which a v2 pack index records.
+pub struct CrcReader<R> {
+    inner: R,
+    crc: Crc,
+}
+
+impl<R: BufRead> CrcReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            crc: Crc::new(),
+        }
+    }
+
+    /// Returns the CRC32 of the data read so far.
+    pub fn crc32(&self) -> u32 {
+        self.crc.sum()
+    }
+}
+
+impl<R: BufRead> BufRead for CrcReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let buffer = self.inner.fill_buf().expect("Failed to fill buffer");
+        self.crc.update(&buffer[..amt]);
+        self.inner.consume(amt);
+    }
+}
+
+impl<R: BufRead> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let o = self.inner.read(buf)?;
+        self.crc.update(&buf[..o]);
+        Ok(o)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::{self, Read, Cursor, BufReader};
@@ -11,6 +11,8 @@ static GLOBAL: MiMalloc = MiMalloc;
 
 pub mod cache;
 pub mod internal;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
 #[cfg(test)]
 mod tests {}
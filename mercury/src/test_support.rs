@@ -0,0 +1,388 @@
+//!
+//! Deterministic fixtures for pack tests: builds a synthetic repository from a seed and
+//! writes it as a pack file, optionally with delta chains or injected corruption, so tests
+//! don't rely on the binary packs under `tests/data`.
+//!
+//! Enabled for the unit tests of this crate, other crates use the `test-support` feature.
+//!
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+
+use flate2::write::ZlibEncoder;
+use sha1::{Digest, Sha1};
+
+use venus::hash::SHA1;
+use venus::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+use venus::internal::object::types::ObjectType;
+use venus::internal::pack::entry::Entry;
+
+use crate::internal::pack::encode::encode_offset;
+use crate::internal::pack::utils::calculate_object_hash;
+
+/// How a deltified object refers to its base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaKind {
+    Offset,
+    Ref,
+}
+
+#[derive(Debug, Clone)]
+pub struct FixtureConfig {
+    /// Same seed, same repository, same pack bytes.
+    pub seed: u64,
+    pub commits: usize,
+    pub files: usize,
+    /// Approximate size of every blob in bytes.
+    pub blob_size: usize,
+    /// Maximum length of a delta chain, 0 stores every object in full.
+    pub delta_depth: usize,
+    pub delta_kind: DeltaKind,
+}
+
+impl Default for FixtureConfig {
+    fn default() -> Self {
+        FixtureConfig {
+            seed: 0x6d65_6761,
+            commits: 10,
+            files: 8,
+            blob_size: 1024,
+            delta_depth: 0,
+            delta_kind: DeltaKind::Offset,
+        }
+    }
+}
+
+/// Damage applied to an encoded pack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    /// Breaks the `PACK` magic.
+    Header,
+    /// Flips the last byte of the trailing checksum.
+    Signature,
+    /// Flips every bit of the byte at the offset.
+    FlipByte(usize),
+    /// Cuts the pack to the given length.
+    Truncate(usize),
+}
+
+pub struct SyntheticRepo {
+    /// Objects in pack order: every blob and tree comes before the commit referencing it.
+    pub objects: Vec<Entry>,
+    /// Index of the delta base in `objects` for objects stored as delta.
+    pub bases: Vec<Option<usize>>,
+    pub head: SHA1,
+    delta_kind: DeltaKind,
+}
+
+/// SplitMix64, small and stable across platforms and releases.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+const WORDS: [&str; 16] = [
+    "mega", "mono", "repo", "pack", "delta", "tree", "blob", "commit", "merge", "branch",
+    "object", "index", "hash", "ref", "tag", "zlib",
+];
+
+fn random_line(rng: &mut Rng) -> String {
+    let mut line = String::new();
+    for _ in 0..8 {
+        line.push_str(WORDS[rng.below(WORDS.len())]);
+        line.push(' ');
+    }
+    line.push('\n');
+    line
+}
+
+impl SyntheticRepo {
+    pub fn generate(config: &FixtureConfig) -> Self {
+        assert!(config.commits > 0 && config.files > 0);
+        let mut rng = Rng(config.seed);
+        let mut repo = SyntheticRepo {
+            objects: Vec::new(),
+            bases: Vec::new(),
+            head: SHA1::default(),
+            delta_kind: config.delta_kind,
+        };
+        let mut seen: HashSet<SHA1> = HashSet::new();
+
+        let lines_per_file = (config.blob_size / 64).max(1);
+        let mut files: Vec<Vec<String>> = (0..config.files)
+            .map(|_| (0..lines_per_file).map(|_| random_line(&mut rng)).collect())
+            .collect();
+        // per file: index of the latest version in `objects` and its delta depth
+        let mut versions: Vec<Option<(usize, usize)>> = vec![None; config.files];
+        let dirs = (config.files / 4).max(1);
+        let mut parent: Option<SHA1> = None;
+
+        for n in 0..config.commits {
+            let changed: Vec<usize> = if n == 0 {
+                (0..config.files).collect()
+            } else {
+                let file = rng.below(config.files);
+                let line = rng.below(lines_per_file);
+                files[file][line] = format!("commit {} {}", n, random_line(&mut rng));
+                vec![file]
+            };
+
+            for file in changed {
+                let data = files[file].concat().into_bytes();
+                let hash = calculate_object_hash(ObjectType::Blob, &data);
+                if !seen.insert(hash) {
+                    continue;
+                }
+                let base = match versions[file] {
+                    Some((index, depth)) if depth < config.delta_depth => Some((index, depth + 1)),
+                    _ => None,
+                };
+                versions[file] = Some((repo.objects.len(), base.map_or(0, |b| b.1)));
+                repo.push(ObjectType::Blob, data, hash, base.map(|b| b.0));
+            }
+
+            let mut dir_items: BTreeMap<usize, Vec<TreeItem>> = BTreeMap::new();
+            for (file, version) in versions.iter().enumerate() {
+                let (index, _) = version.expect("every file is written by the first commit");
+                dir_items.entry(file % dirs).or_default().push(TreeItem::new(
+                    TreeItemMode::Blob,
+                    repo.objects[index].hash,
+                    format!("file-{:04}.txt", file),
+                ));
+            }
+            let mut root_items = Vec::new();
+            for (dir, items) in dir_items {
+                let id = repo.push_tree(items, &mut seen);
+                root_items.push(TreeItem::new(TreeItemMode::Tree, id, format!("dir-{:04}", dir)));
+            }
+            let root = repo.push_tree(root_items, &mut seen);
+
+            let timestamp = 1_700_000_000 + n * 60;
+            let mut data = format!("tree {}\n", root.to_plain_str());
+            if let Some(parent) = parent {
+                data.push_str(&format!("parent {}\n", parent.to_plain_str()));
+            }
+            data.push_str(&format!(
+                "author Mega Fixture <fixture@mega.dev> {0} +0000\ncommitter Mega Fixture <fixture@mega.dev> {0} +0000\n\ncommit {1}\n",
+                timestamp, n
+            ));
+            let data = data.into_bytes();
+            let hash = calculate_object_hash(ObjectType::Commit, &data);
+            repo.push(ObjectType::Commit, data, hash, None);
+            parent = Some(hash);
+        }
+        repo.head = parent.unwrap();
+        repo
+    }
+
+    fn push(&mut self, obj_type: ObjectType, data: Vec<u8>, hash: SHA1, base: Option<usize>) {
+        self.objects.push(Entry {
+            obj_type,
+            data,
+            hash,
+            crc32: None,
+        });
+        self.bases.push(base);
+    }
+
+    fn push_tree(&mut self, mut items: Vec<TreeItem>, seen: &mut HashSet<SHA1>) -> SHA1 {
+        items.sort_by(|a, b| a.name.cmp(&b.name));
+        let tree = Tree::from_tree_items(items).unwrap();
+        if seen.insert(tree.id) {
+            self.push(ObjectType::Tree, tree.to_data().unwrap(), tree.id, None);
+        }
+        tree.id
+    }
+
+    /// Number of objects stored as delta in the pack.
+    pub fn delta_count(&self) -> usize {
+        self.bases.iter().filter(|b| b.is_some()).count()
+    }
+
+    /// Encodes the repository as a version 2 pack.
+    pub fn to_pack(&self) -> Vec<u8> {
+        self.encode(&HashSet::new())
+    }
+
+    /// Encodes the repository as a thin pack, without the full objects that are the base of a
+    /// delta. Returns the pack and the objects left out. Only for [`DeltaKind::Ref`].
+    pub fn to_thin_pack(&self) -> (Vec<u8>, Vec<Entry>) {
+        assert_eq!(self.delta_kind, DeltaKind::Ref, "an offset cannot point out of the pack");
+        let skip: HashSet<usize> = self
+            .bases
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|base| self.bases[*base].is_none())
+            .collect();
+        let left_out = skip.iter().map(|index| self.objects[*index].clone()).collect();
+        (self.encode(&skip), left_out)
+    }
+
+    fn encode(&self, skip: &HashSet<usize>) -> Vec<u8> {
+        let mut pack = Vec::new();
+        pack.extend_from_slice(b"PACK");
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        pack.extend_from_slice(&((self.objects.len() - skip.len()) as u32).to_be_bytes());
+
+        let mut offsets: HashMap<usize, usize> = HashMap::new();
+        for (index, entry) in self.objects.iter().enumerate() {
+            if skip.contains(&index) {
+                continue;
+            }
+            let offset = pack.len();
+            offsets.insert(index, offset);
+            match self.bases[index] {
+                Some(base) => {
+                    let base_entry = &self.objects[base];
+                    let delta = delta::encode(&base_entry.data, &entry.data);
+                    match self.delta_kind {
+                        DeltaKind::Offset => {
+                            write_object_header(&mut pack, ObjectType::OffsetDelta, delta.len());
+                            pack.extend(encode_offset(offset - offsets[&base]));
+                        }
+                        DeltaKind::Ref => {
+                            write_object_header(&mut pack, ObjectType::HashDelta, delta.len());
+                            pack.extend_from_slice(&base_entry.hash.0);
+                        }
+                    }
+                    pack.extend(compress(&delta));
+                }
+                None => {
+                    write_object_header(&mut pack, entry.obj_type, entry.data.len());
+                    pack.extend(compress(&entry.data));
+                }
+            }
+        }
+        let checksum: [u8; 20] = Sha1::digest(&pack).into();
+        pack.extend_from_slice(&checksum);
+        pack
+    }
+
+    pub fn to_corrupted_pack(&self, corruption: Corruption) -> Vec<u8> {
+        let mut pack = self.to_pack();
+        corrupt(&mut pack, corruption);
+        pack
+    }
+}
+
+pub fn corrupt(pack: &mut Vec<u8>, corruption: Corruption) {
+    match corruption {
+        Corruption::Header => pack[0] = b'K',
+        Corruption::Signature => {
+            let last = pack.len() - 1;
+            pack[last] ^= 0xff;
+        }
+        Corruption::FlipByte(offset) => pack[offset] ^= 0xff,
+        Corruption::Truncate(len) => pack.truncate(len),
+    }
+}
+
+/// Type and size of an object, the size continues over 7 bit groups after the first 4 bits.
+fn write_object_header(pack: &mut Vec<u8>, obj_type: ObjectType, size: usize) {
+    let mut byte = (obj_type.to_u8() << 4) | (size & 0x0f) as u8;
+    let mut size = size >> 4;
+    while size > 0 {
+        pack.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    pack.push(byte);
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::internal::pack::Pack;
+
+    use super::{Corruption, DeltaKind, FixtureConfig, SyntheticRepo};
+
+    fn decode(pack: Vec<u8>) -> Result<usize, venus::errors::GitError> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut p = Pack::new(None, Some(1024 * 1024 * 20), Some(PathBuf::from("/tmp/.cache_temp")));
+        p.decode(&mut Cursor::new(pack), move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        })?;
+        Ok(count.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn test_generate_is_deterministic() {
+        let config = FixtureConfig {
+            delta_depth: 5,
+            ..Default::default()
+        };
+        let first = SyntheticRepo::generate(&config);
+        let second = SyntheticRepo::generate(&config);
+        assert_eq!(first.head, second.head);
+        assert_eq!(first.to_pack(), second.to_pack());
+
+        let other = SyntheticRepo::generate(&FixtureConfig {
+            seed: 1,
+            ..config
+        });
+        assert_ne!(first.head, other.head);
+    }
+
+    #[test]
+    fn test_delta_depth() {
+        let config = FixtureConfig {
+            commits: 30,
+            files: 2,
+            delta_depth: 3,
+            ..Default::default()
+        };
+        let repo = SyntheticRepo::generate(&config);
+        assert!(repo.delta_count() > 0);
+        for index in 0..repo.objects.len() {
+            let mut depth = 0;
+            let mut current = index;
+            while let Some(base) = repo.bases[current] {
+                depth += 1;
+                current = base;
+            }
+            assert!(depth <= config.delta_depth);
+        }
+    }
+
+    #[test]
+    fn test_decode_generated_packs() {
+        for kind in [DeltaKind::Offset, DeltaKind::Ref] {
+            let repo = SyntheticRepo::generate(&FixtureConfig {
+                delta_depth: 4,
+                delta_kind: kind,
+                commits: 20,
+                ..Default::default()
+            });
+            assert_eq!(decode(repo.to_pack()).unwrap(), repo.objects.len());
+        }
+    }
+
+    #[test]
+    fn test_decode_corrupted_packs() {
+        let repo = SyntheticRepo::generate(&FixtureConfig::default());
+        assert!(decode(repo.to_corrupted_pack(Corruption::Header)).is_err());
+        assert!(decode(repo.to_corrupted_pack(Corruption::Signature)).is_err());
+    }
+}
@@ -106,6 +106,7 @@ CREATE TABLE IF NOT EXISTS "git_repo" (
   "id" BIGINT PRIMARY KEY,
   "repo_path" TEXT NOT NULL,
   "repo_name" TEXT NOT NULL,
+  "is_public" BOOLEAN NOT NULL DEFAULT FALSE,
   "created_at" TIMESTAMP NOT NULL,
   "updated_at" TIMESTAMP NOT NULL,
   CONSTRAINT uniq_ir_path UNIQUE (repo_path)
@@ -221,4 +222,316 @@ CREATE TABLE IF NOT EXISTS "lfs_objects" (
   "oid" VARCHAR(64) PRIMARY KEY,
   "size" BIGINT NOT NULL,
   "exist" BOOLEAN NOT NULL
-);
\ No newline at end of file
+);
+CREATE TABLE IF NOT EXISTS "mega_lfs_object" (
+  "oid" VARCHAR(64) PRIMARY KEY,
+  "size" BIGINT NOT NULL,
+  "storage_type" VARCHAR(20) NOT NULL,
+  "location" TEXT NOT NULL,
+  "created_at" TIMESTAMP NOT NULL
+);
+CREATE TABLE IF NOT EXISTS "git_operation" (
+  "id" BIGINT PRIMARY KEY,
+  "repo_path" TEXT NOT NULL,
+  "operation" VARCHAR(20) NOT NULL,
+  "user_name" VARCHAR(255),
+  "client_ip" VARCHAR(64),
+  "user_agent" TEXT,
+  "token_id" VARCHAR(255),
+  "refs" TEXT NOT NULL,
+  "bytes_transferred" BIGINT NOT NULL,
+  "duration_ms" BIGINT NOT NULL,
+  "created_at" TIMESTAMP NOT NULL
+);
+CREATE INDEX "idx_go_user_name" ON "git_operation" ("user_name");
+CREATE INDEX "idx_go_created_at" ON "git_operation" ("created_at");
+CREATE TABLE IF NOT EXISTS "user_suspension" (
+  "id" BIGINT PRIMARY KEY,
+  "user_name" VARCHAR(255) NOT NULL,
+  "token_id" VARCHAR(255),
+  "reason" TEXT NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  CONSTRAINT uniq_us_user_name UNIQUE (user_name, token_id)
+);
+CREATE TABLE IF NOT EXISTS "mega_object_pin" (
+  "id" BIGINT PRIMARY KEY,
+  "repo_id" BIGINT NOT NULL,
+  "object_id" VARCHAR(40) NOT NULL,
+  "object_type" VARCHAR(20) NOT NULL,
+  "reason" TEXT NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  CONSTRAINT uniq_mop_object UNIQUE (repo_id, object_id)
+);
+CREATE TABLE IF NOT EXISTS "mega_mr_archive" (
+  "mr_id" BIGINT PRIMARY KEY,
+  "location" TEXT NOT NULL,
+  "commit_count" INTEGER NOT NULL,
+  "tree_count" INTEGER NOT NULL,
+  "archived_at" TIMESTAMP NOT NULL
+);
+CREATE TABLE IF NOT EXISTS "mega_path_commit" (
+  "id" BIGINT PRIMARY KEY,
+  "repo_id" BIGINT NOT NULL,
+  "ref_name" TEXT NOT NULL,
+  "path" TEXT NOT NULL,
+  "commit_id" VARCHAR(40) NOT NULL,
+  "committed_at" BIGINT NOT NULL,
+  CONSTRAINT uniq_mpc_path UNIQUE (repo_id, ref_name, path)
+);
+CREATE TABLE IF NOT EXISTS "mega_read_lease" (
+  "id" BIGINT PRIMARY KEY,
+  "lease_id" BIGINT NOT NULL,
+  "repo_id" BIGINT NOT NULL,
+  "commit_id" VARCHAR(40) NOT NULL,
+  "expires_at" TIMESTAMP NOT NULL
+);
+CREATE INDEX "idx_mrl_lease_id" ON "mega_read_lease" ("lease_id");
+CREATE INDEX "idx_mrl_commit_id" ON "mega_read_lease" ("commit_id");
+CREATE TABLE IF NOT EXISTS "mega_user" (
+  "id" BIGINT PRIMARY KEY,
+  "name" VARCHAR(255) NOT NULL,
+  "email" VARCHAR(255),
+  "token_hash" VARCHAR(64),
+  "created_at" TIMESTAMP NOT NULL,
+  CONSTRAINT uniq_mu_name UNIQUE (name)
+);
+CREATE INDEX "idx_mu_token_hash" ON "mega_user" ("token_hash");
+CREATE TABLE IF NOT EXISTS "mega_ssh_key" (
+  "id" BIGINT PRIMARY KEY,
+  "user_name" VARCHAR(255) NOT NULL,
+  "public_key" TEXT NOT NULL,
+  "created_at" TIMESTAMP NOT NULL
+);
+CREATE INDEX "idx_msk_user_name" ON "mega_ssh_key" ("user_name");
+CREATE TABLE IF NOT EXISTS "mega_session_revocation" (
+  "user_name" VARCHAR(255) PRIMARY KEY,
+  "revoked_at" TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS "mega_auth_policy" (
+  "id" BIGINT PRIMARY KEY,
+  "rule" TEXT NOT NULL,
+  "created_at" TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS "mega_setting" (
+  "name" VARCHAR(64) PRIMARY KEY,
+  "value" TEXT NOT NULL,
+  "updated_at" TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS "mega_ref_event" (
+  "id" BIGINT PRIMARY KEY,
+  "repo_id" BIGINT NOT NULL,
+  "repo_path" TEXT NOT NULL,
+  "ref_name" TEXT NOT NULL,
+  "old_id" VARCHAR(40) NOT NULL,
+  "new_id" VARCHAR(40) NOT NULL,
+  "pusher" VARCHAR(255),
+  "created_at" TIMESTAMP NOT NULL
+);
+CREATE INDEX "idx_mre_created_at" ON "mega_ref_event" ("created_at");
+
+CREATE TABLE IF NOT EXISTS "mega_ref_event_cursor" (
+  "observer" VARCHAR(64) PRIMARY KEY,
+  "event_id" BIGINT NOT NULL,
+  "updated_at" TIMESTAMP NOT NULL,
+  "lease_id" BIGINT,
+  "lease_expires_at" TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS "mega_branch_cleanup" (
+  "id" BIGINT PRIMARY KEY,
+  "repo_id" BIGINT NOT NULL,
+  "ref_name" TEXT NOT NULL,
+  "ref_git_id" VARCHAR(40) NOT NULL,
+  "reason" VARCHAR(20) NOT NULL,
+  "owner" VARCHAR(255),
+  "delete_after" TIMESTAMP NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  CONSTRAINT uniq_mbc_ref UNIQUE (repo_id, ref_name)
+);
+
+CREATE TABLE IF NOT EXISTS "mega_user_activity" (
+  "id" BIGINT PRIMARY KEY,
+  "user_name" VARCHAR(255),
+  "repo_path" TEXT NOT NULL,
+  "ref_name" TEXT NOT NULL,
+  "old_id" VARCHAR(40) NOT NULL,
+  "new_id" VARCHAR(40) NOT NULL,
+  "commit_count" INTEGER NOT NULL,
+  "created_at" TIMESTAMP NOT NULL
+);
+CREATE INDEX "idx_mua_user_name" ON "mega_user_activity" ("user_name", "id");
+
+CREATE TABLE IF NOT EXISTS "mega_user_contribution" (
+  "user_name" VARCHAR(255) NOT NULL,
+  "day" DATE NOT NULL,
+  "commits" INTEGER NOT NULL,
+  "pushes" INTEGER NOT NULL,
+  "merges" INTEGER NOT NULL,
+  PRIMARY KEY ("user_name", "day")
+);
+
+CREATE TABLE IF NOT EXISTS "mega_analytics_commit" (
+  "commit_id" VARCHAR(40) PRIMARY KEY,
+  "created_at" TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS "mega_dir_churn" (
+  "path" TEXT NOT NULL,
+  "week" DATE NOT NULL,
+  "lines_added" BIGINT NOT NULL,
+  "lines_removed" BIGINT NOT NULL,
+  "commits" INTEGER NOT NULL,
+  PRIMARY KEY ("path", "week")
+);
+
+CREATE TABLE IF NOT EXISTS "mega_dir_contributor" (
+  "path" TEXT NOT NULL,
+  "week" DATE NOT NULL,
+  "author" VARCHAR(255) NOT NULL,
+  PRIMARY KEY ("path", "week", "author")
+);
+
+CREATE TABLE IF NOT EXISTS "mega_dir_coupling" (
+  "path" TEXT NOT NULL,
+  "coupled_path" TEXT NOT NULL,
+  "week" DATE NOT NULL,
+  "commits" INTEGER NOT NULL,
+  PRIMARY KEY ("path", "coupled_path", "week")
+);
+
+CREATE TABLE IF NOT EXISTS "mega_mr_review" (
+  "id" BIGINT PRIMARY KEY,
+  "mr_id" BIGINT NOT NULL,
+  "user_name" VARCHAR(255) NOT NULL,
+  "verdict" VARCHAR(20) NOT NULL,
+  "summary" TEXT NOT NULL,
+  "created_at" TIMESTAMP NOT NULL
+);
+CREATE INDEX "idx_mmr_mr_id" ON "mega_mr_review" ("mr_id");
+
+CREATE TABLE IF NOT EXISTS "mega_mr_comment" (
+  "id" BIGINT PRIMARY KEY,
+  "mr_id" BIGINT NOT NULL,
+  "user_name" VARCHAR(255) NOT NULL,
+  "review_id" BIGINT,
+  "path" TEXT,
+  "line" INTEGER,
+  "body" TEXT NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  "updated_at" TIMESTAMP NOT NULL
+);
+CREATE INDEX "idx_mmc_mr_id" ON "mega_mr_comment" ("mr_id");
+
+CREATE TABLE IF NOT EXISTS "mega_code_index" (
+  "id" BIGINT PRIMARY KEY,
+  "repo_id" BIGINT NOT NULL,
+  "commit_id" VARCHAR(40) NOT NULL,
+  "format" VARCHAR(20) NOT NULL,
+  "occurrences" BIGINT NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  CONSTRAINT uniq_mci_commit UNIQUE (repo_id, commit_id)
+);
+
+CREATE TABLE IF NOT EXISTS "mega_code_symbol" (
+  "index_id" BIGINT NOT NULL,
+  "seq" INTEGER NOT NULL,
+  "path" TEXT NOT NULL,
+  "symbol" TEXT NOT NULL,
+  "start_line" INTEGER NOT NULL,
+  "start_character" INTEGER NOT NULL,
+  "end_line" INTEGER NOT NULL,
+  "end_character" INTEGER NOT NULL,
+  "is_definition" BOOLEAN NOT NULL,
+  PRIMARY KEY ("index_id", "seq")
+);
+CREATE INDEX "idx_mcs_path" ON "mega_code_symbol" ("index_id", "path", "start_line");
+CREATE INDEX "idx_mcs_symbol" ON "mega_code_symbol" ("index_id", "symbol");
+
+CREATE TABLE IF NOT EXISTS "mega_search_ref" (
+  "repo_id" BIGINT NOT NULL,
+  "ref_name" TEXT NOT NULL,
+  "commit_id" VARCHAR(40) NOT NULL,
+  "index_version" INTEGER NOT NULL,
+  "updated_at" TIMESTAMP NOT NULL,
+  PRIMARY KEY ("repo_id", "ref_name")
+);
+
+CREATE TABLE IF NOT EXISTS "mega_search_file" (
+  "repo_id" BIGINT NOT NULL,
+  "ref_name" TEXT NOT NULL,
+  "path" TEXT NOT NULL,
+  "blob_id" VARCHAR(40) NOT NULL,
+  "commit_id" VARCHAR(40) NOT NULL,
+  "content" TEXT,
+  PRIMARY KEY ("repo_id", "ref_name", "path")
+);
+
+CREATE TABLE IF NOT EXISTS "mega_object_map" (
+  "sha1" VARCHAR(40) PRIMARY KEY,
+  "sha256" VARCHAR(64) NOT NULL,
+  "object_type" VARCHAR(16) NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  CONSTRAINT uniq_mom_sha256 UNIQUE (sha256)
+);
+
+CREATE TABLE IF NOT EXISTS "mega_watch" (
+  "id" BIGINT PRIMARY KEY,
+  "user_name" VARCHAR(255) NOT NULL,
+  "repo_path" TEXT NOT NULL,
+  "ref_name" TEXT,
+  "path" TEXT,
+  "created_at" TIMESTAMP NOT NULL
+);
+CREATE INDEX "idx_mw_user_name" ON "mega_watch" ("user_name");
+CREATE INDEX "idx_mw_repo_path" ON "mega_watch" ("repo_path");
+
+CREATE TABLE IF NOT EXISTS "mega_watch_notification" (
+  "id" BIGINT PRIMARY KEY,
+  "user_name" VARCHAR(255) NOT NULL,
+  "watch_id" BIGINT NOT NULL,
+  "event_id" BIGINT NOT NULL,
+  "repo_path" TEXT NOT NULL,
+  "ref_name" TEXT NOT NULL,
+  "old_id" VARCHAR(40) NOT NULL,
+  "new_id" VARCHAR(40) NOT NULL,
+  "pusher" VARCHAR(255),
+  "paths" TEXT NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  "read_at" TIMESTAMP,
+  CONSTRAINT uniq_mwn_watch_event UNIQUE (watch_id, event_id)
+);
+CREATE INDEX "idx_mwn_user_name" ON "mega_watch_notification" ("user_name", "id");
+
+CREATE TABLE IF NOT EXISTS "mega_blame_cache" (
+  "id" BIGINT PRIMARY KEY,
+  "repo_id" BIGINT NOT NULL,
+  "path" TEXT NOT NULL,
+  "commit_id" VARCHAR(40) NOT NULL,
+  "blob_id" VARCHAR(40) NOT NULL,
+  "hunks" TEXT NOT NULL,
+  "created_at" TIMESTAMP NOT NULL,
+  "last_used_at" TIMESTAMP NOT NULL,
+  CONSTRAINT uniq_mbc_path_commit UNIQUE (repo_id, path, commit_id)
+);
+CREATE INDEX "idx_mbc_last_used_at" ON "mega_blame_cache" ("last_used_at");
+
+CREATE TABLE IF NOT EXISTS "mega_storage_report" (
+  "id" BIGINT PRIMARY KEY,
+  "report" TEXT NOT NULL,
+  "created_at" TIMESTAMP NOT NULL
+);
+CREATE INDEX "idx_msr_created_at" ON "mega_storage_report" ("created_at");
+
+CREATE TABLE IF NOT EXISTS "mega_lfs_commit_map" (
+  "id" BIGINT PRIMARY KEY,
+  "migration_id" BIGINT NOT NULL,
+  "repo_id" BIGINT NOT NULL,
+  "old_id" VARCHAR(40) NOT NULL,
+  "new_id" VARCHAR(40) NOT NULL,
+  "created_at" TIMESTAMP NOT NULL
+);
+CREATE INDEX "idx_mlcm_old_id" ON "mega_lfs_commit_map" ("repo_id", "old_id");
+CREATE INDEX "idx_mlcm_migration_id" ON "mega_lfs_commit_map" ("migration_id");
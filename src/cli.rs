@@ -9,6 +9,7 @@ use serde::Deserialize;
 
 use crate::commands::{builtin, builtin_exec};
 use common::errors::{MegaError, MegaResult};
+use common::i18n::Locale;
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct Config {}
@@ -59,7 +60,7 @@ fn cli() -> Command {
             Arg::new("config")
                 .short('c')
                 .long("config")
-                .help("Sets a config file work directory"),
+                .help(Locale::from_env().tr("cli-help-config", &[])),
         )
 }
 
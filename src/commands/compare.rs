@@ -0,0 +1,66 @@
+//!
+//!
+//!
+//!
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use ceres::compare;
+use common::enums::DataSource;
+use common::errors::{MegaError, MegaResult};
+use common::i18n::Locale;
+use jupiter::context::Context;
+
+use crate::cli::Config;
+
+#[derive(Args, Clone, Debug)]
+pub struct CompareOptions {
+    /// Commit id, ref name or branch name the changes are compared against
+    base: String,
+
+    /// Commit id, ref name or branch name holding the changes
+    head: String,
+
+    #[arg(long, default_value_t = String::from("/"))]
+    repo_path: String,
+
+    /// Number of commits to skip, to page through large divergences
+    #[arg(long, default_value_t = 0)]
+    offset: usize,
+
+    #[arg(long)]
+    limit: Option<usize>,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+pub fn cli() -> Command {
+    CompareOptions::augment_args_for_update(
+        Command::new("compare").about(Locale::from_env().tr("cli-about-compare", &[])),
+    )
+}
+
+#[tokio::main]
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let options = CompareOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let context = Context::new(&options.data_source).await;
+    let comparison = compare::compare(
+        &context,
+        &options.repo_path,
+        &options.base,
+        &options.head,
+        options.offset,
+        options.limit,
+    )
+    .await?;
+    let output =
+        serde_json::to_string_pretty(&comparison).map_err(|e| MegaError::new(e.into(), 1))?;
+    println!("{}", output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {}
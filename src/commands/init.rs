@@ -6,12 +6,13 @@
 use crate::{cli::Config, commands::init};
 use clap::{ArgMatches, Args, Command, FromArgMatches};
 use common::errors::MegaResult;
+use common::i18n::Locale;
 
 use gateway::init::{init_dir, InitOptions};
 
 pub fn cli() -> Command {
     InitOptions::augment_args_for_update(
-        Command::new("init").about("Initialize the directory structure "),
+        Command::new("init").about(Locale::from_env().tr("cli-about-init", &[])),
     )
 }
 
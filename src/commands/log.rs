@@ -0,0 +1,259 @@
+//!
+//! Prints the history of a repository straight from the storage of the server, like
+//! `git log`, with the placeholders of `git log --format` or as JSON.
+//!
+use chrono::{DateTime, FixedOffset};
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use ceres::history::{self, CommitDetails, Person};
+use common::enums::DataSource;
+use common::errors::{MegaError, MegaResult};
+use common::i18n::Locale;
+use jupiter::context::Context;
+
+use crate::cli::Config;
+
+/// Length of the abbreviated ids of `%h`, `%t` and `%p`.
+const SHORT_ID_LEN: usize = 7;
+
+#[derive(Args, Clone, Debug)]
+pub struct LogOptions {
+    /// Commit id, ref name or branch name the history starts from
+    #[arg(default_value = "main")]
+    rev: String,
+
+    /// Only the commits changing this path of the repository
+    #[arg(long)]
+    path: Option<String>,
+
+    #[arg(long, default_value_t = String::from("/"))]
+    repo_path: String,
+
+    #[arg(short = 'n', long, default_value_t = 20)]
+    limit: usize,
+
+    /// Format of each commit, with the placeholders of `git log --format`: %H %h %T %t %P %p
+    /// %an %ae %at %ad %cn %ce %ct %cd %s %b %B %n %%
+    #[arg(long, conflicts_with = "json")]
+    format: Option<String>,
+
+    /// Print the commits as JSON
+    #[arg(long)]
+    json: bool,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+pub fn cli() -> Command {
+    LogOptions::augment_args_for_update(
+        Command::new("log").about(Locale::from_env().tr("cli-about-log", &[])),
+    )
+}
+
+#[tokio::main]
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let options = LogOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let context = Context::new(&options.data_source).await;
+    let commits = history::log(
+        &context,
+        &options.repo_path,
+        &options.rev,
+        options.path.as_deref(),
+        options.limit,
+    )
+    .await?;
+    if options.json {
+        let output =
+            serde_json::to_string_pretty(&commits).map_err(|e| MegaError::new(e.into(), 1))?;
+        println!("{}", output);
+        return Ok(());
+    }
+    for commit in &commits {
+        match &options.format {
+            Some(format) => println!("{}", format_commit(format, commit)),
+            None => println!("{}", format_medium(commit)),
+        }
+    }
+    Ok(())
+}
+
+/// The `medium` format of git, the default of `mega log` and `mega show`.
+pub(crate) fn format_medium(commit: &CommitDetails) -> String {
+    let mut format = String::from("commit %H%n");
+    if commit.parents.len() > 1 {
+        format.push_str("Merge: %p%n");
+    }
+    format.push_str("Author: %an <%ae>%nDate:   %ad%n");
+    let mut output = format_commit(&format, commit);
+    output.push('\n');
+    for line in commit.message.lines() {
+        if line.is_empty() {
+            output.push('\n');
+        } else {
+            output.push_str(&format!("    {}\n", line));
+        }
+    }
+    output
+}
+
+/// Replaces the placeholders of `format` with the fields of `commit`, the unknown ones are
+/// left as they are.
+pub(crate) fn format_commit(format: &str, commit: &CommitDetails) -> String {
+    let mut output = String::new();
+    let mut rest = format;
+    while let Some(at) = rest.find('%') {
+        output.push_str(&rest[..at]);
+        rest = &rest[at + 1..];
+        match placeholder(rest, commit) {
+            Some((value, length)) => {
+                output.push_str(&value);
+                rest = &rest[length..];
+            }
+            None => output.push('%'),
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// The value of the placeholder `spec` starts with, and its length without the `%`.
+fn placeholder(spec: &str, commit: &CommitDetails) -> Option<(String, usize)> {
+    let mut chars = spec.chars();
+    let value = match chars.next()? {
+        'H' => commit.id.clone(),
+        'h' => short(&commit.id),
+        'T' => commit.tree.clone(),
+        't' => short(&commit.tree),
+        'P' => commit.parents.join(" "),
+        'p' => commit
+            .parents
+            .iter()
+            .map(|id| short(id))
+            .collect::<Vec<_>>()
+            .join(" "),
+        's' => commit.message.lines().next().unwrap_or("").to_owned(),
+        'b' => commit
+            .message
+            .split_once("\n\n")
+            .map(|(_, body)| body.trim().to_owned())
+            .unwrap_or_default(),
+        'B' => commit.message.clone(),
+        'n' => String::from("\n"),
+        '%' => String::from("%"),
+        'a' => return person(&commit.author, chars.next()?).map(|value| (value, 2)),
+        'c' => return person(&commit.committer, chars.next()?).map(|value| (value, 2)),
+        _ => return None,
+    };
+    Some((value, 1))
+}
+
+fn person(person: &Person, field: char) -> Option<String> {
+    match field {
+        'n' => Some(person.name.clone()),
+        'e' => Some(person.email.clone()),
+        't' => Some(person.timestamp.to_string()),
+        'd' => Some(format_date(person.timestamp, &person.timezone)),
+        _ => None,
+    }
+}
+
+fn short(id: &str) -> String {
+    id.chars().take(SHORT_ID_LEN).collect()
+}
+
+/// The date of git, e.g. `Mon Mar 4 10:20:30 2024 +0800`, in the timezone of the signature.
+/// An offset that cannot be read is taken as UTC.
+fn format_date(timestamp: usize, timezone: &str) -> String {
+    let offset = parse_offset(timezone).unwrap_or(FixedOffset::east_opt(0).unwrap());
+    match DateTime::from_timestamp(timestamp as i64, 0) {
+        Some(date) => date
+            .with_timezone(&offset)
+            .format("%a %b %-d %H:%M:%S %Y %z")
+            .to_string(),
+        None => format!("{} {}", timestamp, timezone),
+    }
+}
+
+/// The offset of a `+hhmm` or `-hhmm` timezone.
+fn parse_offset(timezone: &str) -> Option<FixedOffset> {
+    let (sign, digits) = match timezone.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    if digits.len() != 4 {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+#[cfg(test)]
+mod tests {
+    use ceres::history::{CommitDetails, Person};
+
+    use super::{format_commit, format_date, format_medium};
+
+    fn commit() -> CommitDetails {
+        let person = Person {
+            name: String::from("alice"),
+            email: String::from("alice@example.com"),
+            timestamp: 1709518830,
+            timezone: String::from("+0800"),
+        };
+        CommitDetails {
+            id: String::from("7bdc783132575d5b3e78400ace9971970ff43a18"),
+            tree: String::from("4b825dc642cb6eb9a060e54bf8d69288fbee4904"),
+            parents: vec![String::from("8ab686eafeb1f44702738c8b0f24f2567c36da6d")],
+            author: person.clone(),
+            committer: person,
+            message: String::from("Fix the build\n\nThe lock file was stale."),
+        }
+    }
+
+    #[test]
+    fn test_format_commit() {
+        let commit = commit();
+        assert_eq!(
+            format_commit("%h %s (%an, %at)", &commit),
+            "7bdc783 Fix the build (alice, 1709518830)"
+        );
+        assert_eq!(
+            format_commit("%p%n%b", &commit),
+            "8ab686e\nThe lock file was stale."
+        );
+        // unknown placeholders are kept
+        assert_eq!(format_commit("100%% %x %a", &commit), "100% %x %a");
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(
+            format_date(1709518830, "+0800"),
+            "Mon Mar 4 10:20:30 2024 +0800"
+        );
+        assert_eq!(
+            format_date(1709518830, "-0130"),
+            "Mon Mar 4 00:50:30 2024 -0130"
+        );
+        assert_eq!(
+            format_date(1709518830, "bogus"),
+            "Mon Mar 4 02:20:30 2024 +0000"
+        );
+    }
+
+    #[test]
+    fn test_format_medium() {
+        assert_eq!(
+            format_medium(&commit()),
+            "commit 7bdc783132575d5b3e78400ace9971970ff43a18\n\
+             Author: alice <alice@example.com>\n\
+             Date:   Mon Mar 4 10:20:30 2024 +0800\n\
+             \n    Fix the build\n\n    The lock file was stale.\n"
+        );
+    }
+}
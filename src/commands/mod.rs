@@ -3,7 +3,12 @@
 //!
 //!
 //!
+mod compare;
+mod log;
+mod mr;
+mod object;
 mod service;
+mod show;
 
 use clap::{ArgMatches, Command};
 
@@ -13,12 +18,22 @@ use common::errors::MegaResult;
 pub fn builtin() -> Vec<Command> {
     vec![
         service::cli(),
+        compare::cli(),
+        object::cli(),
+        mr::cli(),
+        log::cli(),
+        show::cli(),
     ]
 }
 
 pub(crate) fn builtin_exec(cmd: &str) -> Option<fn(Config, &ArgMatches) -> MegaResult> {
     let f = match cmd {
         "service" => service::exec,
+        "compare" => compare::exec,
+        "object" => object::exec,
+        "mr" => mr::exec,
+        "log" => log::exec,
+        "show" => show::exec,
         _ => return None,
     };
 
@@ -0,0 +1,79 @@
+//!
+//! Reads the merge requests straight from the storage of the server, so no service has to be
+//! running, e.g. during maintenance.
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches, ValueEnum};
+
+use callisto::db_enums::MergeStatus;
+use common::enums::DataSource;
+use common::errors::{MegaError, MegaResult};
+use common::i18n::Locale;
+use jupiter::context::Context;
+
+use crate::cli::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Status {
+    Open,
+    Merged,
+    Closed,
+}
+
+impl From<Status> for MergeStatus {
+    fn from(value: Status) -> Self {
+        match value {
+            Status::Open => MergeStatus::Open,
+            Status::Merged => MergeStatus::Merged,
+            Status::Closed => MergeStatus::Closed,
+        }
+    }
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ListOptions {
+    /// Only the merge requests with this status
+    #[arg(long, value_enum)]
+    status: Option<Status>,
+
+    #[arg(long)]
+    limit: Option<u64>,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+pub fn cli() -> Command {
+    let locale = Locale::from_env();
+    Command::new("mr")
+        .about(locale.tr("cli-about-mr", &[]))
+        .subcommand(ListOptions::augment_args_for_update(
+            Command::new("list").about(locale.tr("cli-about-mr-list", &[])),
+        ))
+}
+
+#[tokio::main]
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    match args.subcommand() {
+        Some(("list", args)) => list(args).await,
+        _ => Ok(()),
+    }
+}
+
+/// Prints the merge requests as JSON, the last updated first.
+async fn list(args: &ArgMatches) -> MegaResult {
+    let options = ListOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let context = Context::new(&options.data_source).await;
+    let mrs = context
+        .services
+        .mega_storage
+        .get_mrs(options.status.map(MergeStatus::from), options.limit)
+        .await?;
+    let output = serde_json::to_string_pretty(&mrs).map_err(|e| MegaError::new(e.into(), 1))?;
+    println!("{}", output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {}
@@ -0,0 +1,117 @@
+//!
+//! Reads the objects straight from the storage of the server, like the `/api/v1/blob` API does,
+//! so no service has to be running, e.g. during maintenance.
+//!
+use std::io::Write;
+use std::str::FromStr;
+
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use common::enums::DataSource;
+use common::errors::{MegaError, MegaResult};
+use common::i18n::Locale;
+use jupiter::context::Context;
+use venus::hash::SHA1;
+use venus::internal::object::tree::{Tree, TreeItemMode};
+use venus::internal::object::ObjectTrait;
+
+use crate::cli::Config;
+
+#[derive(Args, Clone, Debug)]
+pub struct ShowOptions {
+    /// Id of the object
+    object_id: String,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+pub fn cli() -> Command {
+    let locale = Locale::from_env();
+    Command::new("object")
+        .about(locale.tr("cli-about-object", &[]))
+        .subcommand(ShowOptions::augment_args_for_update(
+            Command::new("show").about(locale.tr("cli-about-object-show", &[])),
+        ))
+}
+
+#[tokio::main]
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    match args.subcommand() {
+        Some(("show", args)) => show(args).await,
+        _ => Ok(()),
+    }
+}
+
+/// Prints the content of the object like `git cat-file -p`.
+async fn show(args: &ArgMatches) -> MegaResult {
+    let options = ShowOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let context = Context::new(&options.data_source).await;
+    let object = context
+        .storage
+        .get_obj_data_by_id(&options.object_id)
+        .await?
+        .ok_or_else(|| {
+            MegaError::with_message(&format!("object {} not found", options.object_id))
+        })?;
+    let output = if object.object_type == "tree" {
+        format_tree(&object.git_id, object.data)?
+    } else {
+        object.data
+    };
+    std::io::stdout()
+        .write_all(&output)
+        .map_err(|e| MegaError::new(e.into(), 1))
+}
+
+/// The entries of a tree, one `<mode> <type> <id>\t<name>` line each.
+fn format_tree(id: &str, data: Vec<u8>) -> Result<Vec<u8>, MegaError> {
+    let hash = SHA1::from_str(id).map_err(|e| MegaError::with_message(&e))?;
+    let tree = Tree::from_bytes(data, hash).map_err(|e| MegaError::new(e.into(), 1))?;
+    let mut output = String::new();
+    for item in tree.tree_items {
+        let obj_type = match item.mode {
+            TreeItemMode::Tree => "tree",
+            TreeItemMode::Commit => "commit",
+            _ => "blob",
+        };
+        output.push_str(&format!(
+            "{:0>6} {} {}\t{}\n",
+            String::from_utf8_lossy(item.mode.to_bytes()),
+            obj_type,
+            item.id.to_plain_str(),
+            item.name
+        ));
+    }
+    Ok(output.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use venus::hash::SHA1;
+    use venus::internal::object::tree::{Tree, TreeItem, TreeItemMode};
+
+    use super::format_tree;
+
+    #[test]
+    fn test_format_tree() {
+        let blob = SHA1::from_str("8ab686eafeb1f44702738c8b0f24f2567c36da6d").unwrap();
+        let tree = Tree::from_tree_items(vec![
+            TreeItem::new(TreeItemMode::Blob, blob, String::from("README.md")),
+            TreeItem::new(TreeItemMode::Tree, blob, String::from("src")),
+        ])
+        .unwrap();
+        let output = format_tree(&tree.id.to_plain_str(), tree.to_data().unwrap()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "100644 blob {0}\tREADME.md\n040000 tree {0}\tsrc\n",
+                blob.to_plain_str()
+            )
+        );
+    }
+}
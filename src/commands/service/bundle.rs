@@ -0,0 +1,157 @@
+//!
+//! Moves repositories in and out of mega as git bundles, see [`ceres::bundle`], for the
+//! air-gapped machines. `create` writes the refs of a repository and their objects to a file
+//! that `git clone` or `git fetch` read, `unbundle` stores a bundle made by git and updates
+//! the refs as a push would, printing the status of each of them.
+//!
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+
+use clap::{ArgMatches, Args, Command, FromArgMatches, ValueEnum};
+
+use ceres::bundle;
+use common::enums::DataSource;
+use common::errors::{MegaError, MegaResult};
+use common::i18n::Locale;
+use jupiter::context::Context;
+use mercury::internal::bundle::BundleVersion;
+
+use crate::cli::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Version {
+    #[value(name = "2")]
+    V2,
+    #[value(name = "3")]
+    V3,
+}
+
+impl From<Version> for BundleVersion {
+    fn from(value: Version) -> Self {
+        match value {
+            Version::V2 => BundleVersion::V2,
+            Version::V3 => BundleVersion::V3,
+        }
+    }
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct CreateOptions {
+    /// The repository, or directory of the monorepo, to bundle
+    #[arg(required = true)]
+    repo_path: String,
+
+    /// The bundle file to write
+    #[arg(required = true)]
+    file: PathBuf,
+
+    /// Only bundle this ref, a full name or the name of a branch or a tag
+    #[arg(long = "ref")]
+    refs: Vec<String>,
+
+    /// A commit the receiver already has, only the objects on top of it are bundled
+    #[arg(long)]
+    basis: Vec<String>,
+
+    /// The version of the bundle format
+    #[arg(long, value_enum, default_value = "2")]
+    version: Version,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct UnbundleOptions {
+    /// The repository, or directory of the monorepo, to update
+    #[arg(required = true)]
+    repo_path: String,
+
+    /// The bundle file to read
+    #[arg(required = true)]
+    file: PathBuf,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+pub fn cli() -> Command {
+    let locale = Locale::from_env();
+    Command::new("bundle")
+        .about(locale.tr("cli-about-bundle", &[]))
+        .subcommand(CreateOptions::augment_args_for_update(
+            Command::new("create").about(locale.tr("cli-about-bundle-create", &[])),
+        ))
+        .subcommand(UnbundleOptions::augment_args_for_update(
+            Command::new("unbundle").about(locale.tr("cli-about-bundle-unbundle", &[])),
+        ))
+}
+
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    match args.subcommand() {
+        Some(("create", args)) => create(args).await,
+        Some(("unbundle", args)) => unbundle(args).await,
+        _ => Ok(()),
+    }
+}
+
+async fn create(args: &ArgMatches) -> MegaResult {
+    let options = CreateOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let context = Context::new(&options.data_source).await;
+    let mut writer = BufWriter::new(File::create(&options.file)?);
+    let res = bundle::create_bundle(
+        &context,
+        &options.repo_path,
+        &options.refs,
+        &options.basis,
+        options.version.into(),
+        &mut writer,
+    )
+    .await;
+    let header = match res {
+        Ok(header) => header,
+        Err(err) => {
+            drop(writer);
+            let _ = std::fs::remove_file(&options.file);
+            return Err(err);
+        }
+    };
+    for reference in &header.references {
+        println!("{} {}", reference.id.to_plain_str(), reference.name);
+    }
+    println!(
+        "{}: {} ref(s), {} prerequisite(s)",
+        options.file.display(),
+        header.references.len(),
+        header.prerequisites.len()
+    );
+    Ok(())
+}
+
+async fn unbundle(args: &ArgMatches) -> MegaResult {
+    let options = UnbundleOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let context = Context::new(&options.data_source).await;
+    let mut reader = BufReader::new(File::open(&options.file)?);
+    let report = bundle::unbundle(&context, &options.repo_path, &mut reader).await?;
+    for r in &report.refs {
+        let status = match &r.error {
+            _ if r.old_id == r.new_id => String::from("up to date"),
+            None => String::from("updated"),
+            Some(error) => format!("rejected: {}", error),
+        };
+        println!("{} {} -> {}: {}", r.ref_name, r.old_id, r.new_id, status);
+    }
+    let rejected = report.refs.iter().filter(|r| r.error.is_some()).count();
+    if rejected > 0 {
+        return Err(MegaError::with_message(&format!(
+            "{} ref(s) of the bundle rejected",
+            rejected
+        )));
+    }
+    Ok(())
+}
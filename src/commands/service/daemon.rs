@@ -0,0 +1,31 @@
+//!
+//!
+//!
+//!
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use common::errors::MegaResult;
+use common::i18n::Locale;
+use gateway::daemon_server::{self, DaemonOptions};
+
+use crate::cli::Config;
+
+pub fn cli() -> Command {
+    DaemonOptions::augment_args_for_update(
+        Command::new("daemon").about(Locale::from_env().tr("cli-about-daemon", &[])),
+    )
+}
+
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let server_matchers = DaemonOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+
+    println!("{server_matchers:#?}");
+    daemon_server::start_server(&server_matchers).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {}
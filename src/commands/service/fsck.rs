@@ -0,0 +1,96 @@
+//!
+//! Checks the pack files kept on the disk of the server, e.g. the quarantined or completed
+//! packs of the pushes, with [`Pack::verify`]: everything found wrong is printed, one line
+//! each, and the command fails when anything is.
+//!
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use common::errors::{MegaError, MegaResult};
+use common::i18n::Locale;
+use jupiter::settings::Settings;
+use mercury::internal::pack::index::PackIndex;
+use mercury::internal::pack::verify::VerifyOptions;
+use mercury::internal::pack::Pack;
+
+use crate::cli::Config;
+
+#[derive(Args, Clone, Debug)]
+pub struct FsckOptions {
+    /// The pack files, each checked against the `.idx` next to it when there is one
+    #[arg(required = true)]
+    packs: Vec<PathBuf>,
+
+    /// Also report the objects referring to one the pack does not have
+    #[arg(long)]
+    connectivity: bool,
+}
+
+pub fn cli() -> Command {
+    FsckOptions::augment_args_for_update(
+        Command::new("fsck").about(Locale::from_env().tr("cli-about-fsck", &[])),
+    )
+}
+
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let options = FsckOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    // the limits of the decode of a push, a pack the server accepted is within them
+    let settings = Settings::from_env();
+    let mut failed = 0;
+    for path in &options.packs {
+        let findings = verify(path, &settings, options.connectivity)?;
+        for finding in &findings {
+            println!("{}: {}", path.display(), finding);
+        }
+        if findings.is_empty() {
+            println!("{}: ok", path.display());
+        } else {
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(MegaError::with_message(&format!(
+            "{} of {} pack(s) are corrupted",
+            failed,
+            options.packs.len()
+        )));
+    }
+    Ok(())
+}
+
+fn verify(path: &Path, settings: &Settings, connectivity: bool) -> Result<Vec<String>, MegaError> {
+    let file = File::open(path)
+        .map_err(|e| MegaError::with_message(&format!("{}: {}", path.display(), e)))?;
+    let index_path = path.with_extension("idx");
+    let index = if index_path.is_file() {
+        match PackIndex::read(&index_path) {
+            Ok(index) => Some(index),
+            Err(e) => return Ok(vec![format!("index: {}", e)]),
+        }
+    } else {
+        None
+    };
+    let mem_limit = settings.pack_decode_mem_limit * 1024 * 1024;
+    let mut pack =
+        Pack::new(None, Some(mem_limit), None).with_max_delta_depth(settings.pack_max_delta_depth);
+    if settings.pack_max_object_size > 0 {
+        pack = pack.with_max_object_size(settings.pack_max_object_size * 1024 * 1024);
+    }
+    let report = pack.verify(
+        &mut BufReader::new(file),
+        VerifyOptions {
+            index: index.as_ref(),
+            check_connectivity: connectivity,
+        },
+    );
+    Ok(report
+        .findings
+        .iter()
+        .map(|finding| finding.to_string())
+        .collect())
+}
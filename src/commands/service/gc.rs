@@ -0,0 +1,213 @@
+//!
+//! Housekeeping of a directory of packs kept on the disk of the server. With `--write-midx`,
+//! the `.idx` of the packs are consolidated in a multi-pack-index, so an object is found with
+//! one lookup instead of one per pack. Otherwise a multi-pack-index naming a pack that is no
+//! longer there is removed, the packs are then read through their own `.idx`.
+//!
+//! With `--write-bitmap`, the reachability bitmaps of every pack are written next to its
+//! `.idx`, so the objects of a clone are counted without walking the history. The bitmaps
+//! of a pack no longer there are removed either way.
+//!
+//! With `--prune`, the objects of the database no ref, pin, open merge request or read lease
+//! reaches are removed once older than `--expire-days`. With `--repack`, the reachable ones
+//! are written to new packs of the directory, replacing the packs they cover, see
+//! [`ceres::gc`]. Both only report what they would do with `--dry-run`, which skips the
+//! multi-pack-index and the bitmaps.
+//!
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use ceres::gc::{self, DEFAULT_EXPIRE_DAYS, DEFAULT_MAX_REACHABLE};
+use common::enums::DataSource;
+use common::errors::{MegaError, MegaResult};
+use common::i18n::Locale;
+use jupiter::context::Context;
+use mercury::internal::pack::bitmap::{self, DEFAULT_BITMAP_INTERVAL};
+use mercury::internal::pack::midx::{self, MultiPackIndex, MULTI_PACK_INDEX};
+
+use crate::cli::Config;
+
+#[derive(Args, Clone, Debug)]
+pub struct GcOptions {
+    /// The directory of the `.pack` and `.idx` files
+    #[arg(required = true)]
+    pack_dir: PathBuf,
+
+    /// Write the multi-pack-index of the packs of the directory
+    #[arg(long)]
+    write_midx: bool,
+
+    /// Write the reachability bitmaps of the packs of the directory
+    #[arg(long)]
+    write_bitmap: bool,
+
+    /// Remove the objects of the database nothing reaches anymore
+    #[arg(long)]
+    prune: bool,
+
+    /// Write the objects of the database reachable from a ref to new packs of the directory
+    #[arg(long)]
+    repack: bool,
+
+    /// Report the objects to remove and the packs to write without changing anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Days an unreachable object is kept after it was stored
+    #[arg(long, default_value_t = DEFAULT_EXPIRE_DAYS)]
+    expire_days: u32,
+
+    /// Reachable objects the walk may hold in memory, it stops with an error past them
+    #[arg(long, default_value_t = DEFAULT_MAX_REACHABLE)]
+    max_reachable: usize,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+pub fn cli() -> Command {
+    GcOptions::augment_args_for_update(
+        Command::new("gc").about(Locale::from_env().tr("cli-about-gc", &[])),
+    )
+}
+
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let options = GcOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    if options.prune || options.repack {
+        collect(&options).await?;
+    }
+    if options.dry_run {
+        return Ok(());
+    }
+    if options.write_midx {
+        let index = midx::write_multi_pack_index(&options.pack_dir)
+            .map_err(|e| MegaError::with_message(&e.to_string()))?;
+        println!(
+            "{}: {} object(s) of {} pack(s)",
+            options.pack_dir.join(MULTI_PACK_INDEX).display(),
+            index.len(),
+            index.pack_names().len()
+        );
+    } else {
+        remove_stale_midx(&options.pack_dir)?;
+    }
+    if options.write_bitmap {
+        write_bitmaps(&options.pack_dir)?;
+    } else {
+        remove_stale_bitmaps(&options.pack_dir)?;
+    }
+    Ok(())
+}
+
+async fn collect(options: &GcOptions) -> MegaResult {
+    let context = Context::new(&options.data_source).await;
+    let prefix = if options.dry_run { "dry run: " } else { "" };
+    if options.prune {
+        let report = gc::prune(
+            &context,
+            options.expire_days,
+            options.max_reachable,
+            options.dry_run,
+        )
+        .await?;
+        let removed = &report.unreachable;
+        println!(
+            "{}{} reachable object(s) in {} repositories, {} unreachable: \
+             {} commit(s), {} tree(s), {} blob(s), {} tag(s)",
+            prefix,
+            report.reachable,
+            report.repositories,
+            removed.len(),
+            removed.commits.len(),
+            removed.trees.len(),
+            removed.blobs.len(),
+            removed.tags.len()
+        );
+    }
+    if options.repack {
+        let report = gc::repack(
+            &context,
+            &options.pack_dir,
+            options.max_reachable,
+            options.dry_run,
+        )
+        .await?;
+        for (index, objects) in report.packs.iter().enumerate() {
+            match report.written.get(index) {
+                Some(path) => println!("{}: {} object(s)", path.display(), objects),
+                None => println!("{}pack {}: {} object(s)", prefix, index + 1, objects),
+            }
+        }
+        for path in &report.replaced {
+            println!("{}{}: replaced", prefix, path.display());
+        }
+    }
+    Ok(())
+}
+
+/// The files of `pack_dir` with the extension `ext`.
+fn files_with_extension(pack_dir: &Path, ext: &str) -> Result<Vec<PathBuf>, MegaError> {
+    let io_error =
+        |e: std::io::Error| MegaError::with_message(&format!("{}: {}", pack_dir.display(), e));
+    let mut files = Vec::new();
+    for dir_entry in fs::read_dir(pack_dir).map_err(io_error)? {
+        let path = dir_entry.map_err(io_error)?.path();
+        if path.extension().is_some_and(|e| e == ext) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn write_bitmaps(pack_dir: &Path) -> MegaResult {
+    for pack in files_with_extension(pack_dir, "pack")? {
+        if !pack.with_extension("idx").is_file() {
+            continue;
+        }
+        let bitmaps = bitmap::write_bitmap(&pack, &[], DEFAULT_BITMAP_INTERVAL)
+            .map_err(|e| MegaError::with_message(&e.to_string()))?;
+        println!(
+            "{}: {} bitmap(s)",
+            pack.with_extension("bitmap").display(),
+            bitmaps.len()
+        );
+    }
+    Ok(())
+}
+
+fn remove_stale_bitmaps(pack_dir: &Path) -> MegaResult {
+    for path in files_with_extension(pack_dir, "bitmap")? {
+        if path.with_extension("pack").is_file() && path.with_extension("idx").is_file() {
+            continue;
+        }
+        fs::remove_file(&path)
+            .map_err(|e| MegaError::with_message(&format!("{}: {}", path.display(), e)))?;
+        println!("{}: removed", path.display());
+    }
+    Ok(())
+}
+
+fn remove_stale_midx(pack_dir: &Path) -> MegaResult {
+    let path = pack_dir.join(MULTI_PACK_INDEX);
+    if !path.is_file() {
+        return Ok(());
+    }
+    let stale = match MultiPackIndex::read(&path) {
+        Ok(index) => index.pack_names().iter().any(|name| {
+            let index_path = pack_dir.join(name);
+            !index_path.is_file() || !index_path.with_extension("pack").is_file()
+        }),
+        Err(_) => true,
+    };
+    if stale {
+        fs::remove_file(&path)
+            .map_err(|e| MegaError::with_message(&format!("{}: {}", path.display(), e)))?;
+        println!("{}: removed", path.display());
+    }
+    Ok(())
+}
@@ -6,12 +6,14 @@
 use clap::{ArgMatches, Args, Command, FromArgMatches};
 
 use common::errors::MegaResult;
+use common::i18n::Locale;
+use gateway::background;
 use gateway::https_server::{self, HttpOptions};
 
 use crate::cli::Config;
 
 pub fn cli() -> Command {
-    HttpOptions::augment_args_for_update(Command::new("https").about("Start Git HTTPS server"))
+    HttpOptions::augment_args_for_update(Command::new("https").about(Locale::from_env().tr("cli-about-https", &[])))
 }
 
 pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
@@ -20,6 +22,7 @@ pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
         .unwrap();
 
     println!("{server_matchers:#?}");
+    background::start(&server_matchers.common).await;
     https_server::start_server(&server_matchers).await;
     Ok(())
 }
@@ -0,0 +1,134 @@
+//!
+//! Moves the large files of a repository to LFS, see [`ceres::lfs::migrate`]: the blobs at the
+//! paths matching `--path` with at least `--threshold` bytes are uploaded to the LFS store and
+//! replaced with their pointers. The history of the branches is rewritten, or with
+//! `--future-only` a commit converting the files is added on top of each of them. The commits
+//! created are printed next to the ones they stand for, as they are recorded.
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use ceres::lfs::migrate::{self, MigrateMode, MigrateOptions, MigrateReport};
+use common::enums::DataSource;
+use common::errors::{MegaError, MegaResult};
+use common::i18n::Locale;
+use jupiter::context::Context;
+
+use crate::cli::Config;
+
+#[derive(Args, Clone, Debug)]
+pub struct LfsMigrateOptions {
+    /// The files to move, a pattern of .gitattributes such as `*.psd` or `assets/**`
+    #[arg(long = "path", required = true)]
+    patterns: Vec<String>,
+
+    /// Size from which a file is moved, in bytes or with a k, m or g suffix
+    #[arg(long, value_parser = parse_size)]
+    threshold: i64,
+
+    /// The repository to migrate, `/` for the monorepo
+    #[arg(long, default_value = "/")]
+    repo: String,
+
+    /// Only migrate this branch, a full name or the name of a branch
+    #[arg(long = "branch")]
+    branches: Vec<String>,
+
+    /// Keep the history, add a commit converting the files on top of each branch
+    #[arg(long)]
+    future_only: bool,
+
+    /// Print what would be moved and rewritten, without storing anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+pub fn cli() -> Command {
+    LfsMigrateOptions::augment_args_for_update(
+        Command::new("lfs-migrate").about(Locale::from_env().tr("cli-about-lfs-migrate", &[])),
+    )
+}
+
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let options = LfsMigrateOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let context = Context::new(&options.data_source).await;
+    let migrate_options = MigrateOptions {
+        patterns: options.patterns,
+        threshold: options.threshold,
+        mode: if options.future_only {
+            MigrateMode::FutureOnly
+        } else {
+            MigrateMode::Rewrite
+        },
+        branches: options.branches,
+        dry_run: options.dry_run,
+    };
+    let report = migrate::migrate(&context, &options.repo, &migrate_options).await?;
+    if options.json {
+        let json = serde_json::to_string_pretty(&report)
+            .map_err(|e| MegaError::with_message(&e.to_string()))?;
+        println!("{}", json);
+    } else {
+        print_report(&report);
+    }
+    if report.refs.iter().any(|r| r.error.is_some()) {
+        return Err(MegaError::with_message("some refs were not updated"));
+    }
+    Ok(())
+}
+
+fn print_report(report: &MigrateReport) {
+    let prefix = if report.dry_run { "(dry run) " } else { "" };
+    println!("{}{} blob(s) moved to LFS:", prefix, report.blobs.len());
+    for blob in &report.blobs {
+        println!("  {} {:>12} {}", blob.blob_id, blob.size, blob.path);
+    }
+    println!("{}{} commit(s) created:", prefix, report.commits.len());
+    for mapping in &report.commits {
+        println!("  {} -> {}", mapping.old_id, mapping.new_id);
+    }
+    for r in &report.refs {
+        match &r.error {
+            None => println!("{}{}: {} -> {}", prefix, r.ref_name, r.old_id, r.new_id),
+            Some(error) => println!("{}: not updated, {}", r.ref_name, error),
+        }
+    }
+    for tag in &report.skipped_tags {
+        println!("{}: not rewritten", tag);
+    }
+    if !report.dry_run && !report.commits.is_empty() {
+        println!("migration {} recorded", report.migration_id);
+    }
+}
+
+/// A size in bytes, with an optional `k`, `m` or `g` suffix for the binary units, as in the
+/// configuration of git.
+fn parse_size(value: &str) -> Result<i64, String> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+        Some((index, _)) => value.split_at(index),
+        None => (value, ""),
+    };
+    let multiplier: i64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        _ => return Err(format!("unknown size unit: {}", unit)),
+    };
+    number
+        .trim()
+        .parse::<i64>()
+        .ok()
+        .filter(|n| *n > 0)
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("not a size: {}", value))
+}
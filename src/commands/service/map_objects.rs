@@ -0,0 +1,69 @@
+//!
+//! Records the SHA-256 name of the objects reachable from the refs of the monorepo and the
+//! imported repositories, see [`ceres::interop`], so that they are served to the clients of
+//! the SHA-256 format. The objects mapped already are skipped, it can be run again after
+//! every push.
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use ceres::interop;
+use common::enums::DataSource;
+use common::errors::{MegaError, MegaResult};
+use common::i18n::Locale;
+use jupiter::context::Context;
+use venus::repo::Repo;
+
+use crate::cli::Config;
+
+#[derive(Args, Clone, Debug)]
+pub struct MapObjectsOptions {
+    /// Only map the objects of the repository at this path, `/` for the monorepo
+    #[arg(long)]
+    repo: Option<String>,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+pub fn cli() -> Command {
+    MapObjectsOptions::augment_args_for_update(
+        Command::new("map-objects").about(Locale::from_env().tr("cli-about-map-objects", &[])),
+    )
+}
+
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let options = MapObjectsOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let context = Context::new(&options.data_source).await;
+    let mut repos = vec![(Repo::empty(), String::from("/"))];
+    repos.extend(
+        context
+            .services
+            .mega_storage
+            .get_git_repos()
+            .await?
+            .into_iter()
+            .map(|m| {
+                let path = m.repo_path.clone();
+                (Repo::from(m), path)
+            }),
+    );
+    if let Some(repo_path) = &options.repo {
+        repos.retain(|(_, path)| path == repo_path);
+        if repos.is_empty() {
+            return Err(MegaError::with_message(&format!(
+                "no repository at {}",
+                repo_path
+            )));
+        }
+    }
+    for (repo, path) in repos {
+        let report = interop::map_objects(&context, &repo).await?;
+        println!(
+            "{}: {} object(s) mapped, {} mapped already, {} left unmapped",
+            path, report.mapped, report.known, report.unmapped
+        );
+    }
+    Ok(())
+}
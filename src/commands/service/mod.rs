@@ -6,18 +6,44 @@
 use clap::{ArgMatches, Command};
 
 use common::errors::MegaResult;
+use common::i18n::Locale;
 
 use crate::cli::Config;
 
+mod bundle;
+mod daemon;
+mod fsck;
+mod gc;
 mod https;
+mod lfs_migrate;
+mod map_objects;
 mod p2p;
+mod restore_refs;
+mod rotate_keys;
+mod shards;
 mod ssh;
 mod start;
+mod storage_report;
 
 pub fn cli() -> Command {
-    let subcommands = vec![https::cli(), ssh::cli(), p2p::cli(), start::cli()];
+    let subcommands = vec![
+        https::cli(),
+        ssh::cli(),
+        daemon::cli(),
+        p2p::cli(),
+        start::cli(),
+        fsck::cli(),
+        gc::cli(),
+        shards::cli(),
+        restore_refs::cli(),
+        map_objects::cli(),
+        bundle::cli(),
+        storage_report::cli(),
+        lfs_migrate::cli(),
+        rotate_keys::cli(),
+    ];
     Command::new("service")
-        .about("Start different kinds of server: for example https, ssh, p2p")
+        .about(Locale::from_env().tr("cli-about-service", &[]))
         .subcommands(subcommands)
 }
 
@@ -33,8 +59,18 @@ pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
     match cmd {
         "https" => https::exec(_config, subcommand_args).await,
         "ssh" => ssh::exec(_config, subcommand_args).await,
+        "daemon" => daemon::exec(_config, subcommand_args).await,
         "p2p" => p2p::exec(_config, subcommand_args).await,
         "start" => start::exec(_config, subcommand_args).await,
+        "fsck" => fsck::exec(_config, subcommand_args).await,
+        "gc" => gc::exec(_config, subcommand_args).await,
+        "shards" => shards::exec(_config, subcommand_args).await,
+        "restore-refs" => restore_refs::exec(_config, subcommand_args).await,
+        "map-objects" => map_objects::exec(_config, subcommand_args).await,
+        "bundle" => bundle::exec(_config, subcommand_args).await,
+        "storage-report" => storage_report::exec(_config, subcommand_args).await,
+        "lfs-migrate" => lfs_migrate::exec(_config, subcommand_args).await,
+        "rotate-keys" => rotate_keys::exec(_config, subcommand_args).await,
         _ => Ok(()),
     }
 }
@@ -7,11 +7,12 @@ use clap::{ArgMatches, Args, Command, FromArgMatches};
 
 use crate::cli::Config;
 use common::errors::MegaResult;
+use common::i18n::Locale;
 
 use p2p::peer;
 
 pub fn cli() -> Command {
-    peer::P2pOptions::augment_args_for_update(Command::new("p2p").about("Start p2p node"))
+    peer::P2pOptions::augment_args_for_update(Command::new("p2p").about(Locale::from_env().tr("cli-about-p2p", &[])))
 }
 
 pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
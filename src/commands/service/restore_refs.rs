@@ -0,0 +1,74 @@
+//!
+//! Computes the refs of the monorepo and the imported repositories at a past instant from the
+//! log of the ref updates, see [`ceres::restore`], and prints every ref that differs from it
+//! with its tip now and then. Nothing changes unless `--apply` is given, the refs whose tip
+//! is no longer stored are left as they are either way.
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use ceres::restore;
+use common::enums::DataSource;
+use common::errors::MegaResult;
+use common::i18n::Locale;
+use jupiter::context::Context;
+
+use crate::cli::Config;
+
+#[derive(Args, Clone, Debug)]
+pub struct RestoreRefsOptions {
+    /// The instant to restore, RFC 3339 (e.g. 2024-05-01T12:00:00Z) or seconds since the epoch
+    #[arg(long, required = true)]
+    at: String,
+
+    /// Only restore the refs of the repository at this path, `/` for the monorepo
+    #[arg(long)]
+    repo: Option<String>,
+
+    /// Update the refs, otherwise they are only reported
+    #[arg(long)]
+    apply: bool,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+pub fn cli() -> Command {
+    RestoreRefsOptions::augment_args_for_update(
+        Command::new("restore-refs").about(Locale::from_env().tr("cli-about-restore-refs", &[])),
+    )
+}
+
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let options = RestoreRefsOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let at = restore::parse_instant(&options.at)?;
+    let context = Context::new(&options.data_source).await;
+    let report =
+        restore::restore_refs(&context, at, options.repo.as_deref(), !options.apply).await?;
+    let prefix = if report.dry_run { "dry run: " } else { "" };
+    for restore in &report.refs {
+        println!(
+            "{}{} {}: {} -> {}{}",
+            prefix,
+            restore.repo_path,
+            restore.ref_name,
+            restore.current.as_deref().unwrap_or("(none)"),
+            restore.target.as_deref().unwrap_or("(none)"),
+            if restore.restorable {
+                ""
+            } else {
+                " (object missing, skipped)"
+            }
+        );
+    }
+    let restorable = report.refs.iter().filter(|r| r.restorable).count();
+    println!(
+        "{}{} ref(s) differ from {}, {} restorable",
+        prefix,
+        report.refs.len(),
+        report.at.and_utc().to_rfc3339(),
+        restorable
+    );
+    Ok(())
+}
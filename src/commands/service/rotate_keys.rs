@@ -0,0 +1,42 @@
+//!
+//! Rewraps the data keys of the objects encrypted at rest with the current key encryption
+//! key, `MEGA_ENCRYPTION_CURRENT_KEY`, after a rotation: the blobs kept in the database, the
+//! archives of the merge requests and the LFS objects. The objects themselves are not
+//! decrypted. The other objects of the raw storage, e.g. the commit-graphs, are rewrapped when
+//! they are read, a retired key stays in `MEGA_ENCRYPTION_KEYS` to read them until then.
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use ceres::lfs;
+use common::enums::DataSource;
+use common::errors::MegaResult;
+use common::i18n::Locale;
+use jupiter::context::Context;
+
+use crate::cli::Config;
+
+#[derive(Args, Clone, Debug)]
+pub struct RotateKeysOptions {
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+pub fn cli() -> Command {
+    RotateKeysOptions::augment_args_for_update(
+        Command::new("rotate-keys").about(Locale::from_env().tr("cli-about-rotate-keys", &[])),
+    )
+}
+
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let options = RotateKeysOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let context = Context::new(&options.data_source).await;
+    let report = context.services.mega_storage.rewrap_objects().await?;
+    let lfs_objects = lfs::rewrap_contents(&context).await?;
+    println!(
+        "rewrapped {} blob(s), {} merge request archive(s) and {} LFS object(s)",
+        report.blobs, report.archives, lfs_objects
+    );
+    Ok(())
+}
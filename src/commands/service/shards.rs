@@ -0,0 +1,78 @@
+//!
+//! Manages a storage spreading its objects over shards by the prefix of their id, see
+//! [`jupiter::raw_storage::sharded_storage`]. With `--add`, a shard is added and the buckets
+//! it takes over are recorded as moving; with `--rebalance`, their objects are moved, while
+//! the servers keep using the storage. The shards and their buckets are printed either way.
+//!
+use std::path::PathBuf;
+
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use common::errors::MegaResult;
+use common::i18n::Locale;
+use jupiter::raw_storage::sharded_storage::{Shard, ShardedStorage};
+
+use crate::cli::Config;
+
+#[derive(Args, Clone, Debug)]
+pub struct ShardsOptions {
+    /// The root of the storage, with the manifest of its shards
+    #[arg(required = true)]
+    root: PathBuf,
+
+    /// Add a shard of this name, kept in `--path`
+    #[arg(long, requires = "path")]
+    add: Option<String>,
+
+    /// The directory of the shard added
+    #[arg(long)]
+    path: Option<PathBuf>,
+
+    /// Move the objects of the buckets assigned to another shard
+    #[arg(long)]
+    rebalance: bool,
+}
+
+pub fn cli() -> Command {
+    ShardsOptions::augment_args_for_update(
+        Command::new("shards").about(Locale::from_env().tr("cli-about-shards", &[])),
+    )
+}
+
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let options = ShardsOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let storage = ShardedStorage::init(options.root);
+    if let (Some(name), Some(path)) = (options.add, options.path) {
+        let moves = storage.add_shard(Shard {
+            name: name.clone(),
+            path,
+        })?;
+        println!("{}: {} bucket(s) to move", name, moves.len());
+    }
+    if options.rebalance {
+        let report = storage.rebalance()?;
+        println!(
+            "{} object(s) of {} bucket(s) moved",
+            report.objects, report.buckets
+        );
+    }
+    print_shards(&storage)
+}
+
+fn print_shards(storage: &ShardedStorage) -> MegaResult {
+    let manifest = storage.manifest();
+    let counts = manifest.bucket_counts();
+    for (index, shard) in manifest.shards.iter().enumerate() {
+        let moving = manifest.moving.iter().filter(|m| m.to == index).count();
+        println!(
+            "{}\t{}\t{} bucket(s), {} moving",
+            shard.name,
+            shard.path.display(),
+            counts[index],
+            moving
+        );
+    }
+    Ok(())
+}
@@ -6,6 +6,8 @@
 use clap::{ArgMatches, Args, Command, FromArgMatches};
 
 use common::errors::MegaResult;
+use common::i18n::Locale;
+use gateway::background;
 use gateway::ssh_server::start_server;
 use gateway::ssh_server::SshOptions;
 
@@ -13,7 +15,7 @@ use crate::cli::Config;
 use crate::commands::service::ssh;
 
 pub fn cli() -> Command {
-    SshOptions::augment_args_for_update(Command::new("ssh").about("Start Git SSH server"))
+    SshOptions::augment_args_for_update(Command::new("ssh").about(Locale::from_env().tr("cli-about-ssh", &[])))
 }
 
 pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
@@ -21,6 +23,7 @@ pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
         .map_err(|err| err.exit())
         .unwrap();
     println!("{server_matchers:#?}");
+    background::start(&server_matchers.common).await;
     ssh::start_server(&server_matchers).await;
     Ok(())
 }
@@ -1,7 +1,9 @@
 use clap::{ArgMatches, Args, Command, FromArgMatches, ValueEnum};
 
-use common::{errors::MegaResult, model::CommonOptions};
+use common::{errors::MegaResult, i18n::Locale, model::CommonOptions};
 use gateway::{
+    background,
+    daemon_server::{self, DaemonCustom, DaemonOptions},
     https_server::{self, HttpCustom, HttpOptions},
     ssh_server::{self, SshCustom, SshOptions},
 };
@@ -14,6 +16,7 @@ pub enum StartCommand {
     Http,
     Https,
     Ssh,
+    Daemon,
     P2p,
 }
 
@@ -30,13 +33,16 @@ pub struct StartOptions {
     #[clap(flatten)]
     pub ssh: SshCustom,
 
+    #[clap(flatten)]
+    pub daemon: DaemonCustom,
+
     #[clap(flatten)]
     pub p2p: P2pCustom,
 }
 
 pub fn cli() -> Command {
     StartOptions::augment_args_for_update(
-        Command::new("start").about("Start multiple server by given params"),
+        Command::new("start").about(Locale::from_env().tr("cli-about-start", &[])),
     )
 }
 
@@ -49,6 +55,14 @@ pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
 
     let service_type = server_matchers.service;
 
+    // the background jobs run once for the servers sharing this process
+    if [StartCommand::Http, StartCommand::Https, StartCommand::Ssh]
+        .iter()
+        .any(|service| service_type.contains(service))
+    {
+        background::start(&server_matchers.common).await;
+    }
+
     let http_server = if service_type.contains(&StartCommand::Http)
         || service_type.contains(&StartCommand::Https)
     {
@@ -71,6 +85,16 @@ pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
         tokio::task::spawn(async {})
     };
 
+    let daemon_server = if service_type.contains(&StartCommand::Daemon) {
+        let daemon = DaemonOptions {
+            common: server_matchers.common.clone(),
+            custom: server_matchers.daemon,
+        };
+        tokio::spawn(async move { daemon_server::start_server(&daemon).await })
+    } else {
+        tokio::task::spawn(async {})
+    };
+
     let p2p_server = if service_type.contains(&StartCommand::P2p) {
         let p2p = P2pOptions {
             common: server_matchers.common.clone(),
@@ -83,7 +107,7 @@ pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
         tokio::task::spawn(async {})
     };
 
-    let _ = tokio::join!(http_server, ssh_server, p2p_server);
+    let _ = tokio::join!(http_server, ssh_server, daemon_server, p2p_server);
 
     Ok(())
 }
@@ -0,0 +1,132 @@
+//!
+//! Prints the storage report of the monorepo and the imported repositories, see
+//! [`ceres::storage_report`]: the objects stored by type, the largest blobs with their paths
+//! on the default branches and the directories that grew the most over `--window-days`, the
+//! files to consider moving to LFS. With `--save` the report also becomes the latest one of
+//! the admin api, as if the daily task had computed it.
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use ceres::storage_report::{
+    self, ReportOptions, StorageReport, DEFAULT_DEPTH, DEFAULT_LIMIT, DEFAULT_WINDOW_DAYS,
+};
+use common::enums::DataSource;
+use common::errors::{MegaError, MegaResult};
+use common::i18n::Locale;
+use jupiter::context::Context;
+
+use crate::cli::Config;
+
+#[derive(Args, Clone, Debug)]
+pub struct StorageReportOptions {
+    /// Largest blobs and fastest-growing directories listed
+    #[arg(long, default_value_t = DEFAULT_LIMIT)]
+    limit: usize,
+
+    /// Days over which the growth of the directories is measured
+    #[arg(long, default_value_t = DEFAULT_WINDOW_DAYS)]
+    window_days: u32,
+
+    /// Path segments the growth of the directories is summed up to
+    #[arg(long, default_value_t = DEFAULT_DEPTH)]
+    depth: usize,
+
+    /// Keep the report as the latest one served by the admin api
+    #[arg(long)]
+    save: bool,
+
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+pub fn cli() -> Command {
+    StorageReportOptions::augment_args_for_update(
+        Command::new("storage-report")
+            .about(Locale::from_env().tr("cli-about-storage-report", &[])),
+    )
+}
+
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let options = StorageReportOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let context = Context::new(&options.data_source).await;
+    let report_options = ReportOptions {
+        limit: options.limit.max(1),
+        window_days: options.window_days,
+        depth: options.depth,
+    };
+    let report = if options.save {
+        storage_report::generate_and_save(&context, report_options).await?
+    } else {
+        storage_report::generate(&context, report_options).await?
+    };
+    if options.json {
+        let json = serde_json::to_string_pretty(&report)
+            .map_err(|e| MegaError::with_message(&e.to_string()))?;
+        println!("{}", json);
+    } else {
+        print_report(&report);
+    }
+    Ok(())
+}
+
+fn print_report(report: &StorageReport) {
+    println!("objects by type:");
+    for stats in &report.types {
+        println!(
+            "  {:<8} {:>12} object(s) {:>12}",
+            stats.object_type,
+            stats.count,
+            format_size(stats.size)
+        );
+    }
+    println!("largest blobs:");
+    for blob in &report.largest_blobs {
+        let paths: Vec<&str> = blob.paths.iter().map(|p| p.path.as_str()).collect();
+        println!(
+            "  {} {:>12} {}",
+            blob.id,
+            format_size(blob.size),
+            if paths.is_empty() {
+                String::from("(not on a default branch)")
+            } else {
+                paths.join(", ")
+            }
+        );
+    }
+    println!("fastest-growing paths over {} day(s):", report.window_days);
+    for growth in &report.growing_paths {
+        println!(
+            "  {:>12} {} (+{} ~{} -{} file(s))",
+            format_size(growth.growth),
+            growth.path,
+            growth.files_added,
+            growth.files_changed,
+            growth.files_removed
+        );
+    }
+}
+
+/// `size` in the largest binary unit it is at least one of.
+fn format_size(size: i64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if value.abs() < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    if unit == "B" {
+        format!("{} B", size)
+    } else {
+        format!("{:.1} {}", value, unit)
+    }
+}
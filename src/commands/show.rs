@@ -0,0 +1,93 @@
+//!
+//! Prints a commit and the files it changes, like `git show --name-status`, with the
+//! placeholders of `mega log --format` or as JSON.
+//!
+use clap::{ArgMatches, Args, Command, FromArgMatches};
+
+use ceres::compare::{ChangeStatus, FileChange};
+use ceres::history;
+use common::enums::DataSource;
+use common::errors::{MegaError, MegaResult};
+use common::i18n::Locale;
+use jupiter::context::Context;
+
+use super::log::{format_commit, format_medium};
+use crate::cli::Config;
+
+#[derive(Args, Clone, Debug)]
+pub struct ShowOptions {
+    /// Commit id, ref name or branch name of the commit
+    #[arg(default_value = "main")]
+    rev: String,
+
+    #[arg(long, default_value_t = String::from("/"))]
+    repo_path: String,
+
+    /// Format of the commit, with the placeholders of `mega log --format`
+    #[arg(long, conflicts_with = "json")]
+    format: Option<String>,
+
+    /// Print the commit and its changes as JSON
+    #[arg(long)]
+    json: bool,
+
+    #[arg(short, long, value_enum, default_value = "postgres")]
+    data_source: DataSource,
+}
+
+pub fn cli() -> Command {
+    ShowOptions::augment_args_for_update(
+        Command::new("show").about(Locale::from_env().tr("cli-about-show", &[])),
+    )
+}
+
+#[tokio::main]
+pub(crate) async fn exec(_config: Config, args: &ArgMatches) -> MegaResult {
+    let options = ShowOptions::from_arg_matches(args)
+        .map_err(|err| err.exit())
+        .unwrap();
+    let context = Context::new(&options.data_source).await;
+    let changes = history::show(&context, &options.repo_path, &options.rev).await?;
+    if options.json {
+        let output =
+            serde_json::to_string_pretty(&changes).map_err(|e| MegaError::new(e.into(), 1))?;
+        println!("{}", output);
+        return Ok(());
+    }
+    match &options.format {
+        Some(format) => println!("{}", format_commit(format, &changes.commit)),
+        None => println!("{}", format_medium(&changes.commit)),
+    }
+    for file in &changes.files {
+        println!("{}", name_status(file));
+    }
+    Ok(())
+}
+
+/// The line of `git show --name-status` for a changed file.
+fn name_status(file: &FileChange) -> String {
+    let status = match file.status {
+        ChangeStatus::Added => 'A',
+        ChangeStatus::Modified => 'M',
+        ChangeStatus::Deleted => 'D',
+    };
+    format!("{}\t{}", status, file.path)
+}
+
+#[cfg(test)]
+mod tests {
+    use ceres::compare::{ChangeStatus, FileChange};
+
+    use super::name_status;
+
+    #[test]
+    fn test_name_status() {
+        let file = FileChange {
+            path: String::from("src/main.rs"),
+            status: ChangeStatus::Deleted,
+            old_id: None,
+            new_id: None,
+        };
+        assert_eq!(name_status(&file), "D\tsrc/main.rs");
+    }
+}
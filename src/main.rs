@@ -8,15 +8,17 @@
 use std::env;
 mod cli;
 mod commands;
+mod telemetry;
 mod utils;
 
 fn main() {
     env::set_var("RUST_LOG", "debug");
-    tracing_subscriber::fmt::init();
     dotenvy::dotenv().ok();
+    let provider = telemetry::init();
 
     // Parse the command line arguments
     let result = cli::parse();
+    telemetry::shutdown(provider);
 
     // If there was an error, print it
     if let Err(e) = result {
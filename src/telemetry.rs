@@ -0,0 +1,70 @@
+//!
+//! Logs, and traces exported to an OpenTelemetry collector (Jaeger, Tempo...) over OTLP/HTTP
+//! when `MEGA_OTLP_ENDPOINT` is set. A push is traced as its transport, decode, policy and
+//! merge spans, a clone or fetch as its negotiation and pack.
+//!
+use std::env;
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Installs the subscriber of the process, returns the provider of the exported traces, to be
+/// shut down before exiting so that the last spans are sent.
+pub fn init() -> Option<SdkTracerProvider> {
+    let endpoint = env::var("MEGA_OTLP_ENDPOINT").unwrap_or_default();
+    let provider = if endpoint.is_empty() {
+        None
+    } else {
+        let ratio = env::var("MEGA_OTLP_SAMPLE_RATIO")
+            .ok()
+            .and_then(|ratio| ratio.parse().ok())
+            .unwrap_or(1.0);
+        match provider(&endpoint, ratio) {
+            Ok(provider) => Some(provider),
+            Err(e) => {
+                eprintln!("failed to export the traces to {}: {}", endpoint, e);
+                None
+            }
+        }
+    };
+    let otel = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("mega")));
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel)
+        .init();
+    provider
+}
+
+pub fn shutdown(provider: Option<SdkTracerProvider>) {
+    if let Some(provider) = provider {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("failed to flush the traces: {}", e);
+        }
+    }
+}
+
+/// Sends the traces to `endpoint`, the traces of `ratio` of the requests are kept.
+fn provider(
+    endpoint: &str,
+    ratio: f64,
+) -> Result<SdkTracerProvider, opentelemetry_otlp::ExporterBuildError> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            ratio,
+        ))))
+        .with_resource(Resource::builder().with_service_name("mega").build())
+        .build())
+}
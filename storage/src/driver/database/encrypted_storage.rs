@@ -0,0 +1,419 @@
+//!
+//! Encryption at rest for the git objects saved through an [`ObjectStorage`].
+//!
+//! Every object is sealed with its own random data key (AES-256-GCM), the data key is then
+//! wrapped by a key encryption key handed out by a [`KeyProvider`] and stored next to the
+//! ciphertext (envelope encryption). The id of the object is authenticated with the
+//! ciphertext, so a ciphertext copied over another object fails to open. Rotating the key encryption key only requires rewrapping
+//! the data keys, see [`rewrap`]. A KMS is integrated by implementing [`KeyProvider`].
+//!
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sea_orm::{DatabaseConnection, DatabaseTransaction, IntoActiveModel, TryIntoModel};
+
+use common::errors::MegaError;
+use entity::{commit, objects, refs};
+
+use crate::driver::database::storage::ObjectStorage;
+
+const MAGIC: &[u8; 8] = b"MEGAENC1";
+const NONCE_LEN: usize = 12;
+
+/// The keys of the encryption at rest when `MEGA_ENCRYPTION_ENABLED` is set, `None` otherwise.
+/// Panics on a broken key configuration rather than storing plain data.
+pub fn keys_from_env() -> Option<Arc<dyn KeyProvider>> {
+    let enabled = env::var("MEGA_ENCRYPTION_ENABLED")
+        .map(|v| v.parse::<bool>().unwrap_or(false))
+        .unwrap_or(false);
+    if !enabled {
+        return None;
+    }
+    let keys = LocalKeyProvider::from_env().expect("invalid encryption key configuration");
+    Some(Arc::new(keys))
+}
+
+/// Source of the key encryption keys, implement it to delegate the wrapping to a KMS.
+pub trait KeyProvider: Send + Sync {
+    /// Id of the key used to wrap the data keys of new objects.
+    fn current_key_id(&self) -> String;
+
+    fn wrap_key(&self, key_id: &str, data_key: &[u8]) -> Result<Vec<u8>, MegaError>;
+
+    fn unwrap_key(&self, key_id: &str, wrapped_key: &[u8]) -> Result<Vec<u8>, MegaError>;
+}
+
+/// Keeps the key encryption keys in memory, they are read from `MEGA_ENCRYPTION_KEYS`
+/// (`id:base64-key` pairs separated by commas) and `MEGA_ENCRYPTION_CURRENT_KEY`.
+/// Older keys stay in the list after a rotation so existing objects can still be read.
+pub struct LocalKeyProvider {
+    keys: HashMap<String, Vec<u8>>,
+    current: String,
+}
+
+impl LocalKeyProvider {
+    pub fn new(keys: HashMap<String, Vec<u8>>, current: &str) -> Result<Self, MegaError> {
+        for (id, key) in keys.iter() {
+            if id.len() > u8::MAX as usize {
+                return Err(MegaError::with_message(&format!(
+                    "encryption key id {} is longer than {} bytes",
+                    id,
+                    u8::MAX
+                )));
+            }
+            if key.len() != 32 {
+                return Err(MegaError::with_message(&format!(
+                    "encryption key {} must be 32 bytes",
+                    id
+                )));
+            }
+        }
+        if !keys.contains_key(current) {
+            return Err(MegaError::with_message(&format!(
+                "unknown current encryption key {}",
+                current
+            )));
+        }
+        Ok(LocalKeyProvider {
+            keys,
+            current: current.to_owned(),
+        })
+    }
+
+    pub fn from_env() -> Result<Self, MegaError> {
+        let value = env::var("MEGA_ENCRYPTION_KEYS")
+            .map_err(|_| MegaError::with_message("MEGA_ENCRYPTION_KEYS not configured"))?;
+        let mut keys = HashMap::new();
+        for pair in value.split(',').filter(|p| !p.trim().is_empty()) {
+            let (id, key) = pair
+                .trim()
+                .split_once(':')
+                .ok_or_else(|| MegaError::with_message("encryption key should be id:base64"))?;
+            let key = STANDARD
+                .decode(key)
+                .map_err(|e| MegaError::with_message(&e.to_string()))?;
+            keys.insert(id.to_owned(), key);
+        }
+        let current = env::var("MEGA_ENCRYPTION_CURRENT_KEY")
+            .map_err(|_| MegaError::with_message("MEGA_ENCRYPTION_CURRENT_KEY not configured"))?;
+        LocalKeyProvider::new(keys, &current)
+    }
+
+    fn key(&self, key_id: &str) -> Result<&[u8], MegaError> {
+        self.keys
+            .get(key_id)
+            .map(|k| k.as_slice())
+            .ok_or_else(|| MegaError::with_message(&format!("unknown encryption key {}", key_id)))
+    }
+}
+
+impl KeyProvider for LocalKeyProvider {
+    fn current_key_id(&self) -> String {
+        self.current.clone()
+    }
+
+    fn wrap_key(&self, key_id: &str, data_key: &[u8]) -> Result<Vec<u8>, MegaError> {
+        seal(self.key(key_id)?, &[], data_key)
+    }
+
+    fn unwrap_key(&self, key_id: &str, wrapped_key: &[u8]) -> Result<Vec<u8>, MegaError> {
+        open(self.key(key_id)?, &[], wrapped_key)
+    }
+}
+
+/// AES-256-GCM with a random nonce prepended to the ciphertext, `aad` is authenticated but not
+/// encrypted and must be given again to [`open`] it.
+fn seal(key: &[u8], aad: &[u8], plain: &[u8]) -> Result<Vec<u8>, MegaError> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        cipher
+            .encrypt(&nonce, Payload { msg: plain, aad })
+            .map_err(|_| MegaError::with_message("encryption failed"))?,
+    );
+    Ok(sealed)
+}
+
+fn open(key: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, MegaError> {
+    if sealed.len() < NONCE_LEN {
+        return Err(MegaError::with_message("truncated ciphertext"));
+    }
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let (nonce, data) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: data, aad })
+        .map_err(|_| MegaError::with_message("decryption failed"))
+}
+
+/// Layout: magic | key id length (u8) | key id | wrapped key length (u16 BE) | wrapped key | sealed data
+struct Envelope<'a> {
+    key_id: &'a str,
+    wrapped_key: &'a [u8],
+    sealed: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    fn parse(data: &'a [u8]) -> Result<Option<Self>, MegaError> {
+        let Some(rest) = data.strip_prefix(MAGIC) else {
+            return Ok(None);
+        };
+        let broken = || MegaError::with_message("broken encryption envelope");
+        let (&id_len, rest) = rest.split_first().ok_or_else(broken)?;
+        let (key_id, rest) = rest.split_at_checked(id_len as usize).ok_or_else(broken)?;
+        let key_id = std::str::from_utf8(key_id).map_err(|_| broken())?;
+        let (wrapped_len, rest) = rest.split_first_chunk::<2>().ok_or_else(broken)?;
+        let wrapped_len = u16::from_be_bytes(*wrapped_len) as usize;
+        let (wrapped_key, sealed) = rest.split_at_checked(wrapped_len).ok_or_else(broken)?;
+        Ok(Some(Envelope {
+            key_id,
+            wrapped_key,
+            sealed,
+        }))
+    }
+
+    /// Fails on a key id or a wrapped key too long for their length field, a [`KeyProvider`]
+    /// other than [`LocalKeyProvider`] may hand out either.
+    fn to_bytes(&self) -> Result<Vec<u8>, MegaError> {
+        let id_len = u8::try_from(self.key_id.len()).map_err(|_| {
+            MegaError::with_message(&format!("encryption key id {} is too long", self.key_id))
+        })?;
+        let wrapped_len = u16::try_from(self.wrapped_key.len())
+            .map_err(|_| MegaError::with_message("wrapped encryption key is too long"))?;
+        let mut buf = Vec::with_capacity(
+            MAGIC.len() + 3 + self.key_id.len() + self.wrapped_key.len() + self.sealed.len(),
+        );
+        buf.extend_from_slice(MAGIC);
+        buf.push(id_len);
+        buf.extend_from_slice(self.key_id.as_bytes());
+        buf.extend_from_slice(&wrapped_len.to_be_bytes());
+        buf.extend_from_slice(self.wrapped_key);
+        buf.extend_from_slice(self.sealed);
+        Ok(buf)
+    }
+}
+
+/// Seals `plain`, the content of the object `id`.
+pub fn encrypt(keys: &dyn KeyProvider, id: &str, plain: &[u8]) -> Result<Vec<u8>, MegaError> {
+    let data_key = Aes256Gcm::generate_key(&mut OsRng);
+    let key_id = keys.current_key_id();
+    let wrapped_key = keys.wrap_key(&key_id, &data_key)?;
+    let sealed = seal(&data_key, id.as_bytes(), plain)?;
+    Envelope {
+        key_id: &key_id,
+        wrapped_key: &wrapped_key,
+        sealed: &sealed,
+    }
+    .to_bytes()
+}
+
+/// Opens `data` as the content of the object `id`, it fails on the content of another object.
+/// Data written before the encryption was enabled is returned unchanged.
+pub fn decrypt(keys: &dyn KeyProvider, id: &str, data: &[u8]) -> Result<Vec<u8>, MegaError> {
+    match Envelope::parse(data)? {
+        Some(envelope) => {
+            let data_key = keys.unwrap_key(envelope.key_id, envelope.wrapped_key)?;
+            open(&data_key, id.as_bytes(), envelope.sealed)
+        }
+        None => Ok(data.to_vec()),
+    }
+}
+
+/// Whether `data` is sealed with the current key, so that [`rewrap`] leaves it unchanged.
+pub fn is_current(keys: &dyn KeyProvider, data: &[u8]) -> bool {
+    matches!(Envelope::parse(data), Ok(Some(envelope)) if envelope.key_id == keys.current_key_id())
+}
+
+/// Rewraps the data key of an envelope with the current key, the object itself is not
+/// decrypted. Plain data is encrypted on the way, as the content of the object `id`.
+pub fn rewrap(keys: &dyn KeyProvider, id: &str, data: &[u8]) -> Result<Vec<u8>, MegaError> {
+    match Envelope::parse(data)? {
+        Some(envelope) => {
+            let key_id = keys.current_key_id();
+            if envelope.key_id == key_id {
+                return Ok(data.to_vec());
+            }
+            let data_key = keys.unwrap_key(envelope.key_id, envelope.wrapped_key)?;
+            let wrapped_key = keys.wrap_key(&key_id, &data_key)?;
+            Envelope {
+                key_id: &key_id,
+                wrapped_key: &wrapped_key,
+                sealed: envelope.sealed,
+            }
+            .to_bytes()
+        }
+        None => encrypt(keys, id, data),
+    }
+}
+
+/// Wraps another `ObjectStorage` and encrypts the object data before it reaches the
+/// database or the file storage.
+pub struct EncryptedStorage {
+    pub inner: Arc<dyn ObjectStorage>,
+    pub keys: Arc<dyn KeyProvider>,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn ObjectStorage>, keys: Arc<dyn KeyProvider>) -> Self {
+        EncryptedStorage { inner, keys }
+    }
+
+    fn encrypt_models(
+        &self,
+        obj_data: Vec<objects::ActiveModel>,
+    ) -> Result<Vec<objects::ActiveModel>, MegaError> {
+        obj_data
+            .into_iter()
+            .map(|model| {
+                let mut obj = model.try_into_model()?;
+                obj.data = encrypt(self.keys.as_ref(), &obj.git_id, &obj.data)?;
+                Ok(obj.into_active_model())
+            })
+            .collect()
+    }
+
+    fn decrypt_model(&self, mut obj: objects::Model) -> Result<objects::Model, MegaError> {
+        obj.data = decrypt(self.keys.as_ref(), &obj.git_id, &obj.data)?;
+        Ok(obj)
+    }
+}
+
+#[async_trait]
+impl ObjectStorage for EncryptedStorage {
+    fn get_connection(&self) -> &DatabaseConnection {
+        self.inner.get_connection()
+    }
+
+    async fn save_obj_data(
+        &self,
+        txn: Option<&DatabaseTransaction>,
+        obj_data: Vec<objects::ActiveModel>,
+    ) -> Result<bool, MegaError> {
+        let obj_data = self.encrypt_models(obj_data)?;
+        self.inner.save_obj_data(txn, obj_data).await
+    }
+
+    async fn save_obj_data_to_db(
+        &self,
+        txn: Option<&DatabaseTransaction>,
+        obj_data: Vec<objects::ActiveModel>,
+    ) -> Result<bool, MegaError> {
+        let obj_data = self.encrypt_models(obj_data)?;
+        self.inner.save_obj_data_to_db(txn, obj_data).await
+    }
+
+    async fn get_obj_data_by_ids(
+        &self,
+        git_ids: Vec<String>,
+    ) -> Result<Vec<objects::Model>, MegaError> {
+        self.inner
+            .get_obj_data_by_ids(git_ids)
+            .await?
+            .into_iter()
+            .map(|obj| self.decrypt_model(obj))
+            .collect()
+    }
+
+    async fn get_obj_data_by_id(&self, git_id: &str) -> Result<Option<objects::Model>, MegaError> {
+        self.inner
+            .get_obj_data_by_id(git_id)
+            .await?
+            .map(|obj| self.decrypt_model(obj))
+            .transpose()
+    }
+
+    async fn search_refs(&self, path_str: &str) -> Result<Vec<refs::Model>, MegaError> {
+        self.inner.search_refs(path_str).await
+    }
+
+    async fn search_commits(&self, path_str: &str) -> Result<Vec<commit::Model>, MegaError> {
+        self.inner.search_commits(path_str).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{decrypt, encrypt, is_current, rewrap, Envelope, KeyProvider, LocalKeyProvider};
+
+    const BLOB: &str = "95d09f2b10159347eece71399a7e2e907ea3df4f";
+    const TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+    fn provider(current: &str) -> LocalKeyProvider {
+        let mut keys = HashMap::new();
+        keys.insert(String::from("k1"), vec![1u8; 32]);
+        keys.insert(String::from("k2"), vec![2u8; 32]);
+        LocalKeyProvider::new(keys, current).unwrap()
+    }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let keys = provider("k1");
+        let plain = b"blob 11\0hello world".to_vec();
+        let sealed = encrypt(&keys, BLOB, &plain).unwrap();
+        assert_ne!(sealed, plain);
+        assert_eq!(decrypt(&keys, BLOB, &sealed).unwrap(), plain);
+        // objects saved before encryption was enabled
+        assert_eq!(decrypt(&keys, BLOB, &plain).unwrap(), plain);
+    }
+
+    #[test]
+    fn test_decrypt_under_another_id() {
+        let keys = provider("k1");
+        let sealed = encrypt(&keys, BLOB, b"blob 11\0hello world").unwrap();
+        assert!(decrypt(&keys, TREE, &sealed).is_err());
+        // the id stays bound after a rotation
+        let rotated = provider("k2");
+        let rewrapped = rewrap(&rotated, BLOB, &sealed).unwrap();
+        assert!(decrypt(&rotated, TREE, &rewrapped).is_err());
+    }
+
+    #[test]
+    fn test_rewrap_after_rotation() {
+        let plain = b"tree 0\0".to_vec();
+        let sealed = encrypt(&provider("k1"), TREE, &plain).unwrap();
+
+        let rotated = provider("k2");
+        assert!(is_current(&provider("k1"), &sealed));
+        assert!(!is_current(&rotated, &sealed));
+        assert!(!is_current(&rotated, &plain));
+        let rewrapped = rewrap(&rotated, TREE, &sealed).unwrap();
+        assert!(is_current(&rotated, &rewrapped));
+        let envelope = Envelope::parse(&rewrapped).unwrap().unwrap();
+        assert_eq!(envelope.key_id, rotated.current_key_id());
+        assert_eq!(decrypt(&rotated, TREE, &rewrapped).unwrap(), plain);
+    }
+
+    #[test]
+    fn test_tampered_data() {
+        let keys = provider("k1");
+        let mut sealed = encrypt(&keys, BLOB, b"commit").unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 0xff;
+        assert!(decrypt(&keys, BLOB, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_truncated_envelope() {
+        let keys = provider("k1");
+        let sealed = encrypt(&keys, BLOB, b"commit").unwrap();
+        // cut in the key id, in the wrapped key length and in the wrapped key
+        for len in [9, 11, 12, 20] {
+            assert!(Envelope::parse(&sealed[..len]).is_err());
+        }
+        assert!(decrypt(&keys, BLOB, &sealed[..12]).is_err());
+    }
+
+    #[test]
+    fn test_long_key_id() {
+        let keys = HashMap::from([("k".repeat(256), vec![1u8; 32])]);
+        assert!(LocalKeyProvider::new(keys, &"k".repeat(256)).is_err());
+    }
+}
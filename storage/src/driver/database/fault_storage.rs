@@ -0,0 +1,160 @@
+//!
+//! Faults injected in an [`ObjectStorage`] for the tests: the calls of the wrapped storage fail
+//! or are delayed as decided by a [`FaultInjector`], before they reach the database. Only the
+//! reads and writes of the objects, commits, nodes and refs are faulted, the other methods of
+//! the trait run on the connection of the wrapped storage as they are.
+//!
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use sea_orm::{DatabaseConnection, DatabaseTransaction};
+
+use common::errors::MegaError;
+use common::fault::FaultInjector;
+use entity::{commit, mr, node, objects, refs};
+
+use crate::driver::database::storage::ObjectStorage;
+
+pub struct FaultStorage {
+    pub inner: Arc<dyn ObjectStorage>,
+    pub faults: Arc<FaultInjector>,
+}
+
+impl FaultStorage {
+    pub fn new(inner: Arc<dyn ObjectStorage>, faults: Arc<FaultInjector>) -> Self {
+        FaultStorage { inner, faults }
+    }
+
+    async fn inject(&self, operation: &str) -> Result<(), MegaError> {
+        let decision = self.faults.decide(operation);
+        if !decision.delay.is_zero() {
+            tokio::time::sleep(decision.delay).await;
+        }
+        if decision.fail {
+            return Err(FaultInjector::error(operation));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ObjectStorage for FaultStorage {
+    fn get_connection(&self) -> &DatabaseConnection {
+        self.inner.get_connection()
+    }
+
+    async fn save_mr_objects(
+        &self,
+        txn: Option<&DatabaseTransaction>,
+        objects: Vec<mr::ActiveModel>,
+    ) -> Result<bool, MegaError> {
+        self.inject("save_mr_objects").await?;
+        self.inner.save_mr_objects(txn, objects).await
+    }
+
+    async fn save_obj_data(
+        &self,
+        txn: Option<&DatabaseTransaction>,
+        obj_data: Vec<objects::ActiveModel>,
+    ) -> Result<bool, MegaError> {
+        self.inject("save_obj_data").await?;
+        self.inner.save_obj_data(txn, obj_data).await
+    }
+
+    async fn save_obj_data_to_db(
+        &self,
+        txn: Option<&DatabaseTransaction>,
+        obj_data: Vec<objects::ActiveModel>,
+    ) -> Result<bool, MegaError> {
+        self.inject("save_obj_data_to_db").await?;
+        self.inner.save_obj_data_to_db(txn, obj_data).await
+    }
+
+    async fn get_obj_data_by_ids(
+        &self,
+        git_ids: Vec<String>,
+    ) -> Result<Vec<objects::Model>, MegaError> {
+        self.inject("get_obj_data_by_ids").await?;
+        self.inner.get_obj_data_by_ids(git_ids).await
+    }
+
+    async fn get_obj_data_by_id(&self, git_id: &str) -> Result<Option<objects::Model>, MegaError> {
+        self.inject("get_obj_data_by_id").await?;
+        self.inner.get_obj_data_by_id(git_id).await
+    }
+
+    async fn get_commit_by_hash(
+        &self,
+        hash: &str,
+        repo_path: &str,
+    ) -> Result<Option<commit::Model>, MegaError> {
+        self.inject("get_commit_by_hash").await?;
+        self.inner.get_commit_by_hash(hash, repo_path).await
+    }
+
+    async fn search_refs(&self, path_str: &str) -> Result<Vec<refs::Model>, MegaError> {
+        self.inject("search_refs").await?;
+        self.inner.search_refs(path_str).await
+    }
+
+    async fn search_commits(&self, path_str: &str) -> Result<Vec<commit::Model>, MegaError> {
+        self.inject("search_commits").await?;
+        self.inner.search_commits(path_str).await
+    }
+
+    async fn save_refs(&self, save_models: Vec<refs::ActiveModel>) -> Result<bool, MegaError> {
+        self.inject("save_refs").await?;
+        self.inner.save_refs(save_models).await
+    }
+
+    async fn save_nodes(
+        &self,
+        txn: Option<&DatabaseTransaction>,
+        nodes: Vec<node::ActiveModel>,
+    ) -> Result<bool, MegaError> {
+        self.inject("save_nodes").await?;
+        self.inner.save_nodes(txn, nodes).await
+    }
+
+    async fn save_commits(
+        &self,
+        txn: Option<&DatabaseTransaction>,
+        commits: Vec<commit::ActiveModel>,
+    ) -> Result<bool, MegaError> {
+        self.inject("save_commits").await?;
+        self.inner.save_commits(txn, commits).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use common::fault::{Fault, FaultInjector};
+
+    use crate::driver::database::mysql_storage::MysqlStorage;
+    use crate::driver::database::storage::ObjectStorage;
+
+    use super::FaultStorage;
+
+    #[tokio::test]
+    async fn test_injected_faults() {
+        let faults = Arc::new(
+            FaultInjector::new(1)
+                .with_fault("save_refs", Fault::error(1.0))
+                .with_fault("search_refs", Fault::delay(1.0, Duration::from_millis(50))),
+        );
+        // disconnected, a call that is not failed by the injector fails in the database
+        let storage = FaultStorage::new(Arc::new(MysqlStorage::default()), faults.clone());
+
+        let err = storage.save_refs(vec![]).await.unwrap_err();
+        assert!(err.to_string().contains("injected fault in save_refs"));
+        let started_at = Instant::now();
+        let err = storage.search_refs("/").await.unwrap_err();
+        assert!(!err.to_string().contains("injected"));
+        assert!(started_at.elapsed() >= Duration::from_millis(50));
+        assert_eq!(faults.failed(), vec![String::from("save_refs")]);
+        assert_eq!(faults.calls("search_refs"), 1);
+    }
+}
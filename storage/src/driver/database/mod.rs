@@ -3,10 +3,18 @@ use std::{env, sync::Arc, time::Duration};
 use sea_orm::{ConnectOptions, Database};
 use tracing::log;
 
-use self::{mysql_storage::MysqlStorage, pg_storage::PgStorage, storage::ObjectStorage};
+use self::{
+    encrypted_storage::EncryptedStorage,
+    mysql_storage::MysqlStorage,
+    pg_storage::PgStorage,
+    storage::ObjectStorage,
+};
 use crate::utils::id_generator;
 use common::enums::DataSource;
 
+pub mod encrypted_storage;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod fault_storage;
 pub mod mysql_storage;
 pub mod pg_storage;
 pub mod storage;
@@ -49,8 +57,12 @@ pub async fn init(data_source: &DataSource) -> Arc<dyn ObjectStorage> {
     let connection = Database::connect(opt)
         .await
         .expect("Database connection failed");
-    match data_source {
+    let storage: Arc<dyn ObjectStorage> = match data_source {
         DataSource::Mysql => Arc::new(MysqlStorage { connection }),
         DataSource::Postgres => Arc::new(PgStorage { connection }),
+    };
+    match encrypted_storage::keys_from_env() {
+        Some(keys) => Arc::new(EncryptedStorage::new(storage, keys)),
+        None => storage,
     }
 }
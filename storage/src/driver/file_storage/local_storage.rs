@@ -1,5 +1,4 @@
 use std::fs;
-use std::io::prelude::*;
 use std::path;
 use std::path::PathBuf;
 
@@ -7,6 +6,7 @@ use async_trait::async_trait;
 use bytes::Bytes;
 
 use common::errors::MegaError;
+use common::fs_utils;
 
 use crate::driver::file_storage::FileStorage;
 
@@ -33,9 +33,7 @@ impl LocalStorage {
 impl FileStorage for LocalStorage {
     async fn get(&self, object_id: &str) -> Result<Bytes, MegaError> {
         let path = path::Path::new(&self.base_path).join(self.transform_path(object_id));
-        let mut file = fs::File::open(&path).unwrap_or_else(|_| panic!("Open file:{:?} failed!", path));
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
+        let buffer = fs_utils::read(&path).unwrap_or_else(|_| panic!("Open file:{:?} failed!", path));
         Ok(Bytes::from(buffer))
     }
 
@@ -49,11 +47,10 @@ impl FileStorage for LocalStorage {
         let dir = path.parent().unwrap();
         fs::create_dir_all(dir).expect("Create directory failed!");
 
-        let mut file = fs::File::create(&path).expect("Open file failed");
-        let lenght_written = file.write(body_content).expect("Write file failed");
-        if lenght_written as i64 != size {
+        if body_content.len() as i64 != size {
             return Err(MegaError::with_message("size not correct"));
         }
+        fs_utils::write_atomic(&path, body_content).expect("Write file failed");
         Ok(path.to_str().unwrap().to_string())
     }
 
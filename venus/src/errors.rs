@@ -37,9 +37,24 @@ pub enum GitError {
     #[error("The `{0}` is not a valid idx file.")]
     InvalidIdxFile(String),
 
+    #[error("The `{0}` is not a valid multi-pack-index file.")]
+    InvalidMultiPackIndex(String),
+
+    #[error("The `{0}` is not a valid bitmap file.")]
+    InvalidBitmapFile(String),
+
+    #[error("The `{0}` is not a valid commit-graph file.")]
+    InvalidCommitGraph(String),
+
     #[error("The `{0}` is not a valid pack file.")]
     InvalidPackFile(String),
 
+    #[error("The `{0}` is not a valid bundle file.")]
+    InvalidBundle(String),
+
+    #[error("The pack decode is cancelled, {0} objects are not read.")]
+    PackDecodeCancelled(usize),
+
     #[error("The `{0}` is not a valid pack header.")]
     InvalidPackHeader(String),
 
@@ -61,6 +76,9 @@ pub enum GitError {
     #[error("Can't encode the object which id [{0}] to bytes")]
     EncodeObjectError(String),
 
+    #[error("The object `{0}` has no id in the other object format.")]
+    UnmappedObjectId(String),
+
     #[error("UTF-8 conversion error: {0}")]
     ConversionError(String),
 }
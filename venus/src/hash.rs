@@ -8,6 +8,7 @@ use std::fmt::Display;
 use colored::Colorize;
 use serde::{Deserialize, Serialize};
 use sha1_smol::Digest;
+use sha2::Digest as _;
 
 use crate::internal::object::types::ObjectType;
 
@@ -133,6 +134,119 @@ impl SHA1 {
     }
 }
 
+/// The object formats of Git, the hash function naming its objects, e.g. the `--object-format`
+/// of `git init`. The objects of a repository are all named with the same one, the hashes of
+/// different kinds never name the same object.
+///
+/// Only [`HashKind::Sha1`] is stored: the objects, the packs and the database hold 20-byte
+/// [`SHA1`] ids. The kind is what the protocol advertises as the `object-format` capability,
+/// [`HashKind::Sha256`] to the clients read through [`crate::internal::object::interop`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashKind {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl HashKind {
+    /// The length in bytes of a hash, and of the trailer of a pack.
+    pub fn size(self) -> usize {
+        match self {
+            HashKind::Sha1 => 20,
+            HashKind::Sha256 => 32,
+        }
+    }
+
+    /// The length of a hash as a hexadecimal string.
+    pub fn hex_len(self) -> usize {
+        self.size() * 2
+    }
+
+    /// The name of the kind in Git, `sha1` or `sha256`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HashKind::Sha1 => "sha1",
+            HashKind::Sha256 => "sha256",
+        }
+    }
+
+    /// Calculates the hash of `data`.
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashKind::Sha1 => sha1::Sha1::digest(data).to_vec(),
+            HashKind::Sha256 => sha2::Sha256::digest(data).to_vec(),
+        }
+    }
+
+    /// An incremental hasher of the kind, e.g. for the trailer of a pack as it is written.
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashKind::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            HashKind::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    /// Calculates the id of an object, the hash of "`<type> <size>\0<content>`".
+    pub fn object_hash(self, object_type: ObjectType, data: &[u8]) -> Vec<u8> {
+        let mut d = Vec::with_capacity(data.len() + 32);
+        d.extend(object_type.to_bytes());
+        d.push(b' ');
+        d.extend(data.len().to_string().as_bytes());
+        d.push(b'\x00');
+        d.extend(data);
+        self.digest(&d)
+    }
+}
+
+/// The state of a hash of either [`HashKind`] fed data by parts, see [`HashKind::hasher`].
+#[derive(Clone)]
+pub enum Hasher {
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub fn kind(&self) -> HashKind {
+        match self {
+            Hasher::Sha1(_) => HashKind::Sha1,
+            Hasher::Sha256(_) => HashKind::Sha256,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+impl Display for HashKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for HashKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha1" => Ok(HashKind::Sha1),
+            "sha256" => Ok(HashKind::Sha256),
+            _ => Err(format!("unknown object format: {}", s)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::BufReader;
@@ -142,7 +256,8 @@ mod tests {
     use std::str::FromStr;
     use std::{env, path::PathBuf};
 
-    use crate::hash::SHA1;
+    use crate::hash::{HashKind, SHA1};
+    use crate::internal::object::types::ObjectType;
 
     #[test]
     fn test_sha1_new() {
@@ -236,4 +351,27 @@ mod tests {
             Err(e) => println!("Error: {}", e),
         }
     }
+
+    #[test]
+    fn test_hash_kind_object_hash() {
+        let data = b"hello\n";
+        for (kind, id) in [
+            (HashKind::Sha1, "ce013625030ba8dba906f756967f9e9ca394464a"),
+            (
+                HashKind::Sha256,
+                "2cf8d83d9ee29543b34a87727421fdecb7e3f3a183d337639025de576db9ebb4",
+            ),
+        ] {
+            let hash = kind.object_hash(ObjectType::Blob, data);
+            assert_eq!(hash.len(), kind.size());
+            assert_eq!(hex::encode(&hash), id);
+            assert_eq!(id.len(), kind.hex_len());
+            assert_eq!(kind.as_str().parse::<HashKind>(), Ok(kind));
+        }
+        assert_eq!(
+            HashKind::Sha1.object_hash(ObjectType::Blob, data),
+            SHA1::from_type_and_data(ObjectType::Blob, &data.to_vec()).to_data()
+        );
+        assert!("md5".parse::<HashKind>().is_err());
+    }
 }
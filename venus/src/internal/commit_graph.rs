@@ -0,0 +1,370 @@
+//!
+//! Commit-graph files (`commit-graph`) version 1: the parents, root tree, generation number and
+//! committer date of every commit of a history, sorted by id, so a [`CommitGraph`] is loaded
+//! without reading and parsing the commits. Merge bases and ahead/behind counts of the
+//! monorepo then run on the file alone. The layout is the one of git, which reads the files
+//! written here and the other way around.
+//!
+//! ## Reference
+//! 1. Git Commit-Graph [Format](https://git-scm.com/docs/gitformat-commit-graph)
+//!
+use std::fs;
+use std::path::Path;
+
+use sha1::{Digest, Sha1};
+
+use crate::errors::GitError;
+use crate::hash::SHA1;
+use crate::internal::revwalk::CommitGraph;
+
+/// The name of the commit-graph file, as git names it.
+pub const COMMIT_GRAPH: &str = "commit-graph";
+
+const GRAPH_MAGIC: [u8; 4] = *b"CGPH";
+const GRAPH_VERSION: u8 = 1;
+/// SHA-1 object ids.
+const OID_VERSION: u8 = 1;
+const HEADER_LEN: usize = 8;
+
+const CHUNK_OID_FANOUT: [u8; 4] = *b"OIDF";
+const CHUNK_OID_LOOKUP: [u8; 4] = *b"OIDL";
+const CHUNK_COMMIT_DATA: [u8; 4] = *b"CDAT";
+const CHUNK_EXTRA_EDGES: [u8; 4] = *b"EDGE";
+
+const COMMIT_DATA_LEN: usize = 20 + 4 + 4 + 8;
+/// The parent position of a commit without this parent.
+const PARENT_NONE: u32 = 0x7000_0000;
+/// Set on the second parent position of an octopus merge, the rest is the index of its
+/// parents from the second on in the extra edges chunk. Set on the last of them there.
+const OCTOPUS_EDGE: u32 = 0x8000_0000;
+/// The generation numbers are stored on 30 bits, the higher ones are capped.
+const GENERATION_MAX: usize = 0x3fff_ffff;
+/// The committer dates are stored on 34 bits.
+const COMMIT_TIME_MAX: u64 = (1 << 34) - 1;
+
+/// A commit as recorded in the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphCommit {
+    pub id: SHA1,
+    pub tree: SHA1,
+    /// The parents in the file, in the order of the commit.
+    pub parents: Vec<SHA1>,
+    /// The topological level: 1 for a root, else one more than the highest parent.
+    pub generation: u32,
+    pub commit_time: u64,
+}
+
+/// Encodes the commit-graph of the commits of `graph`, their parents out of it are left out.
+pub fn encode_commit_graph(graph: &CommitGraph) -> Vec<u8> {
+    let mut ids: Vec<SHA1> = graph.ids().copied().collect();
+    ids.sort();
+    let position = |id: &SHA1| ids.binary_search(id).unwrap() as u32;
+
+    let mut fanout = Vec::with_capacity(256 * 4);
+    let mut counts = [0u32; 256];
+    for id in &ids {
+        counts[id.0[0] as usize] += 1;
+    }
+    let mut total = 0;
+    for count in counts {
+        total += count;
+        fanout.extend_from_slice(&total.to_be_bytes());
+    }
+    let lookup: Vec<u8> = ids.iter().flat_map(|id| id.0).collect();
+
+    let mut commit_data = Vec::with_capacity(ids.len() * COMMIT_DATA_LEN);
+    let mut extra_edges: Vec<u32> = Vec::new();
+    for id in &ids {
+        let parents: Vec<u32> = graph.parents_of(id).iter().map(position).collect();
+        commit_data.extend_from_slice(&graph.tree(id).unwrap().0);
+        commit_data.extend_from_slice(
+            &parents
+                .first()
+                .copied()
+                .unwrap_or(PARENT_NONE)
+                .to_be_bytes(),
+        );
+        let second = match parents.len() {
+            0 | 1 => PARENT_NONE,
+            2 => parents[1],
+            _ => {
+                let at = extra_edges.len() as u32;
+                extra_edges.extend_from_slice(&parents[1..]);
+                *extra_edges.last_mut().unwrap() |= OCTOPUS_EDGE;
+                at | OCTOPUS_EDGE
+            }
+        };
+        commit_data.extend_from_slice(&second.to_be_bytes());
+        let generation = graph.generation(id).unwrap().min(GENERATION_MAX) as u64;
+        let commit_time = (graph.committer_date(id).unwrap() as u64).min(COMMIT_TIME_MAX);
+        commit_data.extend_from_slice(&(generation << 34 | commit_time).to_be_bytes());
+    }
+
+    let mut chunks = vec![
+        (CHUNK_OID_FANOUT, fanout),
+        (CHUNK_OID_LOOKUP, lookup),
+        (CHUNK_COMMIT_DATA, commit_data),
+    ];
+    if !extra_edges.is_empty() {
+        let edges = extra_edges.iter().flat_map(|e| e.to_be_bytes()).collect();
+        chunks.push((CHUNK_EXTRA_EDGES, edges));
+    }
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&GRAPH_MAGIC);
+    data.extend_from_slice(&[GRAPH_VERSION, OID_VERSION, chunks.len() as u8, 0]);
+    // the table of contents ends with an entry pointing after the last chunk
+    let mut offset = (HEADER_LEN + (chunks.len() + 1) * 12) as u64;
+    for (id, chunk) in &chunks {
+        data.extend_from_slice(id);
+        data.extend_from_slice(&offset.to_be_bytes());
+        offset += chunk.len() as u64;
+    }
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&offset.to_be_bytes());
+    for (_, chunk) in chunks {
+        data.extend_from_slice(&chunk);
+    }
+    let checksum = Sha1::digest(&data);
+    data.extend_from_slice(&checksum);
+    data
+}
+
+/// Writes the commit-graph of `graph` at `path`, see [`encode_commit_graph`].
+pub fn write_commit_graph(path: &Path, graph: &CommitGraph) -> Result<(), GitError> {
+    let io_error = |path: &Path, e: std::io::Error| {
+        GitError::InvalidCommitGraph(format!("{}: {}", path.display(), e))
+    };
+    // replaced at once, a reader never sees a partial file
+    let lock = path.with_extension("lock");
+    fs::write(&lock, encode_commit_graph(graph)).map_err(|e| io_error(&lock, e))?;
+    fs::rename(&lock, path).map_err(|e| io_error(path, e))
+}
+
+/// A commit-graph file read back.
+#[derive(Debug, Clone)]
+pub struct CommitGraphFile {
+    /// Sorted by id.
+    commits: Vec<GraphCommit>,
+}
+
+impl CommitGraphFile {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, GitError> {
+        let invalid = |msg: &str| GitError::InvalidCommitGraph(msg.to_owned());
+        if data.len() < HEADER_LEN + 12 + 20 {
+            return Err(invalid("too short"));
+        }
+        let (content, checksum) = data.split_at(data.len() - 20);
+        if Sha1::digest(content).as_slice() != checksum {
+            return Err(invalid("checksum mismatch"));
+        }
+        if data[..4] != GRAPH_MAGIC || data[4] != GRAPH_VERSION || data[5] != OID_VERSION {
+            return Err(invalid("not a version 1 commit-graph of SHA-1 ids"));
+        }
+        if data[7] != 0 {
+            return Err(invalid("split commit-graphs are not supported"));
+        }
+
+        // the chunks, each ending where the next one starts
+        let chunk_count = data[6] as usize;
+        let mut chunks: Vec<([u8; 4], usize)> = Vec::with_capacity(chunk_count + 1);
+        for n in 0..=chunk_count {
+            let at = HEADER_LEN + n * 12;
+            let entry = content
+                .get(at..at + 12)
+                .ok_or_else(|| invalid("truncated table of contents"))?;
+            let offset = u64::from_be_bytes(entry[4..].try_into().unwrap());
+            if offset > content.len() as u64 || chunks.last().is_some_and(|c| c.1 as u64 > offset) {
+                return Err(invalid("chunk out of the file"));
+            }
+            chunks.push((entry[..4].try_into().unwrap(), offset as usize));
+        }
+        let chunk = |id: [u8; 4]| {
+            chunks
+                .windows(2)
+                .find(|w| w[0].0 == id)
+                .map(|w| &content[w[0].1..w[1].1])
+        };
+        let fanout = chunk(CHUNK_OID_FANOUT).ok_or_else(|| invalid("no OID fanout"))?;
+        let lookup = chunk(CHUNK_OID_LOOKUP).ok_or_else(|| invalid("no OID lookup"))?;
+        let commit_data = chunk(CHUNK_COMMIT_DATA).ok_or_else(|| invalid("no commit data"))?;
+        let extra_edges = chunk(CHUNK_EXTRA_EDGES).unwrap_or_default();
+
+        if fanout.len() != 256 * 4 {
+            return Err(invalid("invalid OID fanout"));
+        }
+        let count = read_u32(fanout, 255 * 4) as usize;
+        if lookup.len() != count * 20 || commit_data.len() != count * COMMIT_DATA_LEN {
+            return Err(invalid("the chunks do not have the same number of commits"));
+        }
+        let ids: Vec<SHA1> = lookup.chunks_exact(20).map(SHA1::from_bytes).collect();
+        if ids.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(invalid("commit ids not sorted"));
+        }
+        let mut expected = 0;
+        for (first, count) in (0..256).map(|n| (n, read_u32(fanout, n * 4) as usize)) {
+            expected += ids[expected..]
+                .iter()
+                .take_while(|id| id.0[0] as usize == first)
+                .count();
+            if count != expected {
+                return Err(invalid("invalid OID fanout"));
+            }
+        }
+
+        let parent = |position: u32| {
+            ids.get(position as usize)
+                .copied()
+                .ok_or_else(|| invalid("parent out of the graph"))
+        };
+        let mut commits = Vec::with_capacity(count);
+        for (id, data) in ids.iter().zip(commit_data.chunks_exact(COMMIT_DATA_LEN)) {
+            let mut parents = Vec::new();
+            let first = read_u32(data, 20);
+            if first != PARENT_NONE {
+                parents.push(parent(first)?);
+            }
+            let second = read_u32(data, 24);
+            if second & OCTOPUS_EDGE != 0 {
+                let mut at = (second & !OCTOPUS_EDGE) as usize;
+                loop {
+                    let edge = extra_edges
+                        .get(at * 4..at * 4 + 4)
+                        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+                        .ok_or_else(|| invalid("extra edge out of the chunk"))?;
+                    parents.push(parent(edge & !OCTOPUS_EDGE)?);
+                    if edge & OCTOPUS_EDGE != 0 {
+                        break;
+                    }
+                    at += 1;
+                }
+            } else if second != PARENT_NONE {
+                parents.push(parent(second)?);
+            }
+            let packed = u64::from_be_bytes(data[28..36].try_into().unwrap());
+            commits.push(GraphCommit {
+                id: *id,
+                tree: SHA1::from_bytes(&data[..20]),
+                parents,
+                generation: (packed >> 34) as u32,
+                commit_time: packed & COMMIT_TIME_MAX,
+            });
+        }
+        Ok(CommitGraphFile { commits })
+    }
+
+    pub fn read(path: &Path) -> Result<Self, GitError> {
+        let data = fs::read(path)
+            .map_err(|e| GitError::InvalidCommitGraph(format!("{}: {}", path.display(), e)))?;
+        CommitGraphFile::from_bytes(&data)
+    }
+
+    pub fn find(&self, id: &SHA1) -> Option<&GraphCommit> {
+        self.commits
+            .binary_search_by(|commit| commit.id.cmp(id))
+            .ok()
+            .map(|n| &self.commits[n])
+    }
+
+    pub fn contains(&self, id: &SHA1) -> bool {
+        self.find(id).is_some()
+    }
+
+    /// The commits, sorted by id.
+    pub fn commits(&self) -> &[GraphCommit] {
+        &self.commits
+    }
+
+    pub fn len(&self) -> usize {
+        self.commits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commits.is_empty()
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::internal::revwalk::CommitGraph;
+    use crate::test_support::{commit_at, id};
+
+    use super::{encode_commit_graph, CommitGraphFile};
+
+    /// 1 - 2 - 3 ------- 6 - 7
+    ///      \           /
+    ///       4 - 5 ----
+    ///      \         /
+    ///       8 -------
+    /// 6 is an octopus merge of 3, 5 and 8, and 3 is dated before its parent
+    fn history() -> CommitGraph {
+        CommitGraph::new(vec![
+            commit_at(1, &[], 1000),
+            commit_at(2, &[1], 1001),
+            commit_at(3, &[2], 900),
+            commit_at(4, &[2], 1003),
+            commit_at(5, &[4], 1004),
+            commit_at(8, &[2], 1005),
+            commit_at(6, &[3, 5, 8], 1006),
+            // the parent 9 is not in the graph
+            commit_at(7, &[6, 9], 1007),
+        ])
+    }
+
+    #[test]
+    fn test_commit_graph_roundtrip() {
+        let graph = history();
+        let data = encode_commit_graph(&graph);
+        assert_eq!(&data[..8], b"CGPH\x01\x01\x04\x00");
+
+        let file = CommitGraphFile::from_bytes(&data).unwrap();
+        assert_eq!(file.len(), 8);
+        let octopus = file.find(&id(6)).unwrap();
+        assert_eq!(octopus.parents, vec![id(3), id(5), id(8)]);
+        assert_eq!(octopus.generation, 5);
+        assert_eq!(octopus.commit_time, 1006);
+        assert_eq!(file.find(&id(7)).unwrap().parents, vec![id(6)]);
+        assert!(file.find(&id(1)).unwrap().parents.is_empty());
+        assert!(!file.contains(&id(9)));
+
+        // the same answers from the file, without a commit
+        let loaded = CommitGraph::from_file(&file);
+        assert!(loaded.commit(&id(7)).is_none());
+        assert_eq!(loaded.tree(&id(7)), graph.tree(&id(7)));
+        for (a, b) in [(3, 5), (7, 4), (8, 3), (1, 7)] {
+            assert_eq!(
+                loaded.merge_base(&id(a), &id(b)),
+                graph.merge_base(&id(a), &id(b))
+            );
+            assert_eq!(
+                loaded.ahead_behind(&id(a), &id(b)),
+                graph.ahead_behind(&id(a), &id(b))
+            );
+        }
+        assert_eq!(loaded.walk(&[id(7)], &[]), graph.walk(&[id(7)], &[]));
+        assert_eq!(loaded.corrected_date(&id(3)), Some(1002));
+        assert_eq!(encode_commit_graph(&loaded), data);
+
+        let mut corrupted = data.clone();
+        corrupted[100] ^= 1;
+        assert!(CommitGraphFile::from_bytes(&corrupted).is_err());
+        assert!(CommitGraphFile::from_bytes(&data[..40]).is_err());
+    }
+
+    #[test]
+    fn test_commit_graph_without_octopus() {
+        let graph = CommitGraph::new(vec![commit_at(1, &[], 1000), commit_at(2, &[1], 1001)]);
+        let data = encode_commit_graph(&graph);
+        // no extra edges chunk
+        assert_eq!(data[6], 3);
+        let file = CommitGraphFile::from_bytes(&data).unwrap();
+        assert_eq!(file.find(&id(2)).unwrap().parents, vec![id(1)]);
+
+        let empty = encode_commit_graph(&CommitGraph::new(Vec::new()));
+        assert!(CommitGraphFile::from_bytes(&empty).unwrap().is_empty());
+    }
+}
@@ -1,3 +1,5 @@
+pub mod commit_graph;
 pub mod object;
 pub mod pack;
+pub mod revwalk;
 pub mod zlib;
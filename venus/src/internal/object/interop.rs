@@ -0,0 +1,238 @@
+//! Translation of the objects between the object formats, after the hash function transition
+//! plan of Git: an object has a name in every format, the hash of its content in that format,
+//! and the ids of the objects it refers to are names in the same format. A blob reads the same
+//! in every format, a tree, a commit or a tag has the ids it holds replaced by their names in
+//! the other format, which have to be known first: the objects are translated from the blobs
+//! up to the commits and the tags.
+//!
+//! The signatures of the commits and the tags are kept as they are, they sign the content in
+//! the format the object was written in.
+//!
+use std::collections::HashMap;
+
+use crate::errors::GitError;
+use crate::hash::HashKind;
+use crate::internal::object::types::ObjectType;
+
+/// Knows the name of an object in the other format.
+pub trait ObjectIdMap {
+    /// The name of the object `id` in the other format, None if it is not known.
+    fn translate(&self, id: &[u8]) -> Option<Vec<u8>>;
+}
+
+impl ObjectIdMap for HashMap<Vec<u8>, Vec<u8>> {
+    fn translate(&self, id: &[u8]) -> Option<Vec<u8>> {
+        self.get(id).cloned()
+    }
+}
+
+/// The content of the object `data` named in `from` as it reads in `to`. Fails with
+/// [`GitError::UnmappedObjectId`] if `map` does not know an object it refers to.
+pub fn translate_object(
+    object_type: ObjectType,
+    data: &[u8],
+    from: HashKind,
+    to: HashKind,
+    map: &impl ObjectIdMap,
+) -> Result<Vec<u8>, GitError> {
+    rewrite_ids(object_type, data, from, |id| {
+        let translated = map
+            .translate(id)
+            .ok_or_else(|| GitError::UnmappedObjectId(hex::encode(id)))?;
+        if translated.len() != to.size() {
+            return Err(GitError::InvalidHashValue(hex::encode(&translated)));
+        }
+        Ok(translated)
+    })
+}
+
+/// The ids the object `data` refers to, the objects to translate before it.
+pub fn referenced_ids(
+    object_type: ObjectType,
+    data: &[u8],
+    kind: HashKind,
+) -> Result<Vec<Vec<u8>>, GitError> {
+    let mut ids = Vec::new();
+    rewrite_ids(object_type, data, kind, |id| {
+        ids.push(id.to_vec());
+        Ok(id.to_vec())
+    })?;
+    Ok(ids)
+}
+
+/// Copies `data`, every id it holds replaced by `replace` of it.
+fn rewrite_ids(
+    object_type: ObjectType,
+    data: &[u8],
+    kind: HashKind,
+    mut replace: impl FnMut(&[u8]) -> Result<Vec<u8>, GitError>,
+) -> Result<Vec<u8>, GitError> {
+    match object_type {
+        ObjectType::Blob => Ok(data.to_vec()),
+        // "<mode> <name>\0<binary id>" per item
+        ObjectType::Tree => {
+            let mut out = Vec::with_capacity(data.len() + data.len() / 2);
+            let mut rest = data;
+            while !rest.is_empty() {
+                let invalid =
+                    || GitError::InvalidTreeObject(hex::encode(&rest[..rest.len().min(64)]));
+                let nul = rest.iter().position(|b| *b == 0).ok_or_else(invalid)?;
+                let end = nul + 1 + kind.size();
+                if end > rest.len() {
+                    return Err(invalid());
+                }
+                out.extend(&rest[..=nul]);
+                out.extend(replace(&rest[nul + 1..end])?);
+                rest = &rest[end..];
+            }
+            Ok(out)
+        }
+        // the ids are in hexadecimal in the headers, before the first empty line
+        ObjectType::Commit | ObjectType::Tag => {
+            let keys: &[&[u8]] = match object_type {
+                ObjectType::Commit => &[b"tree ", b"parent "],
+                _ => &[b"object "],
+            };
+            let header_end = data
+                .windows(2)
+                .position(|w| w == b"\n\n")
+                .map_or(data.len(), |p| p + 1);
+            let mut out = Vec::with_capacity(data.len() + 64);
+            for line in data[..header_end].split_inclusive(|b| *b == b'\n') {
+                let Some(key) = keys.iter().find(|key| line.starts_with(key)) else {
+                    out.extend(line);
+                    continue;
+                };
+                let hex_id = line[key.len()..]
+                    .strip_suffix(b"\n")
+                    .unwrap_or(&line[key.len()..]);
+                let id = hex::decode(hex_id)
+                    .ok()
+                    .filter(|id| id.len() == kind.size())
+                    .ok_or_else(|| {
+                        let line = String::from_utf8_lossy(line).into_owned();
+                        match object_type {
+                            ObjectType::Commit => GitError::InvalidCommitObject(line),
+                            _ => GitError::InvalidTagObject(line),
+                        }
+                    })?;
+                out.extend(*key);
+                out.extend(hex::encode(replace(&id)?).as_bytes());
+                if line.ends_with(b"\n") {
+                    out.push(b'\n');
+                }
+            }
+            out.extend(&data[header_end..]);
+            Ok(out)
+        }
+        _ => Err(GitError::InvalidObjectType(object_type.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::hash::HashKind;
+    use crate::internal::object::interop::{referenced_ids, translate_object};
+    use crate::internal::object::types::ObjectType;
+
+    /// Translates `data` and names it in SHA-256, the name is recorded in `map`.
+    fn translate(
+        map: &mut HashMap<Vec<u8>, Vec<u8>>,
+        object_type: ObjectType,
+        data: &[u8],
+    ) -> (Vec<u8>, String) {
+        let translated =
+            translate_object(object_type, data, HashKind::Sha1, HashKind::Sha256, map).unwrap();
+        let sha1 = HashKind::Sha1.object_hash(object_type, data);
+        let sha256 = HashKind::Sha256.object_hash(object_type, &translated);
+        map.insert(sha1, sha256.clone());
+        (translated, hex::encode(sha256))
+    }
+
+    // the ids of the same repository created with `git init --object-format=sha1` and `sha256`
+    #[test]
+    fn test_translate_to_sha256() {
+        let mut map = HashMap::new();
+        let (_, blob) = translate(&mut map, ObjectType::Blob, b"hello\n");
+        assert_eq!(
+            blob,
+            "2cf8d83d9ee29543b34a87727421fdecb7e3f3a183d337639025de576db9ebb4"
+        );
+
+        let mut tree = b"100644 a.txt\0".to_vec();
+        tree.extend(hex::decode("ce013625030ba8dba906f756967f9e9ca394464a").unwrap());
+        let (translated, tree_id) = translate(&mut map, ObjectType::Tree, &tree);
+        assert_eq!(translated.len(), tree.len() + 12);
+        assert_eq!(
+            tree_id,
+            "371aac45c3ba401d13c2ce98c9537b4e53c6049b950bf8a33cbc400712d9f4fa"
+        );
+
+        let commit = b"tree 2e81171448eb9f2ee3821e3d447aa6b2fe3ddba1\n\
+            author Mega <mega@mega.dev> 1700000000 +0000\n\
+            committer Mega <mega@mega.dev> 1700000000 +0000\n\ninit\n";
+        let (_, commit_id) = translate(&mut map, ObjectType::Commit, commit);
+        assert_eq!(
+            commit_id,
+            "6497f598fd8dc66bb8e66bcd4c3df6254978cfbc064444314ff90491c3297d8c"
+        );
+
+        let tag = b"object 837c2c6c1ffc45d356b9735b9acd61206357ed5d\ntype commit\ntag v1\n\
+            tagger Mega <mega@mega.dev> 1700000000 +0000\n\nv1\n";
+        let (_, tag_id) = translate(&mut map, ObjectType::Tag, tag);
+        assert_eq!(
+            tag_id,
+            "58442659c9d1971b443a1877bb6e3c0e1b90f465a66c40d9e0007fefa6695b46"
+        );
+
+        // and back
+        let reverse: HashMap<Vec<u8>, Vec<u8>> =
+            map.iter().map(|(k, v)| (v.clone(), k.clone())).collect();
+        let sha256_tag =
+            translate_object(ObjectType::Tag, tag, HashKind::Sha1, HashKind::Sha256, &map).unwrap();
+        let back = translate_object(
+            ObjectType::Tag,
+            &sha256_tag,
+            HashKind::Sha256,
+            HashKind::Sha1,
+            &reverse,
+        )
+        .unwrap();
+        assert_eq!(back, tag);
+    }
+
+    #[test]
+    fn test_translate_unmapped_or_invalid() {
+        let map = HashMap::new();
+        let commit = b"tree 2e81171448eb9f2ee3821e3d447aa6b2fe3ddba1\n\ninit\n";
+        assert!(translate_object(
+            ObjectType::Commit,
+            commit,
+            HashKind::Sha1,
+            HashKind::Sha256,
+            &map
+        )
+        .is_err());
+        let ids = referenced_ids(ObjectType::Commit, commit, HashKind::Sha1).unwrap();
+        assert_eq!(
+            ids,
+            vec![hex::decode("2e81171448eb9f2ee3821e3d447aa6b2fe3ddba1").unwrap()]
+        );
+
+        // a parent line in the message is not a header
+        let message = b"tree 2e81171448eb9f2ee3821e3d447aa6b2fe3ddba1\n\nparent nothing\n";
+        assert_eq!(
+            referenced_ids(ObjectType::Commit, message, HashKind::Sha1)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(referenced_ids(ObjectType::Commit, b"tree 2e81\n\n", HashKind::Sha1).is_err());
+        assert!(
+            referenced_ids(ObjectType::Tree, b"100644 a.txt\0\x01\x02", HashKind::Sha1).is_err()
+        );
+        assert!(referenced_ids(ObjectType::OffsetDelta, b"", HashKind::Sha1).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 pub mod blob;
 pub mod commit;
+pub mod interop;
 pub mod signature;
 pub mod tag;
 pub mod tree;
@@ -69,6 +69,27 @@ impl Display for Tag {
 }
 
 impl Tag {
+    /// An annotated tag of `object_hash`. The message is kept as [`ObjectTrait::from_bytes`]
+    /// reads it, after the newline separating it from the tagger.
+    pub fn new(
+        object_hash: SHA1,
+        object_type: ObjectType,
+        tag_name: String,
+        tagger: Signature,
+        message: &str,
+    ) -> Result<Tag, GitError> {
+        let mut tag = Tag {
+            id: SHA1::default(),
+            object_hash,
+            object_type,
+            tag_name,
+            tagger,
+            message: format!("\n{}", message),
+        };
+        tag.id = SHA1::from_type_and_data(ObjectType::Tag, &tag.to_data()?);
+        Ok(tag)
+    }
+
     // pub fn new_from_meta(meta: Meta) -> Result<Tag, GitError> {
     //     Ok(Tag::new_from_data(meta.data))
     // }
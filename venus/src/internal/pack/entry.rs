@@ -17,6 +17,8 @@ pub struct Entry {
     pub obj_type: ObjectType,
     pub data: Vec<u8>,
     pub hash: SHA1,
+    /// CRC32 of the object as packed, for the pack index, when it was read from a pack.
+    pub crc32: Option<u32>,
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -93,6 +95,18 @@ impl From<Blob> for Entry {
             obj_type: ObjectType::Blob,
             data: value.data,
             hash: value.id,
+            crc32: None,
+        }
+    }
+}
+
+impl From<Tag> for Entry {
+    fn from(value: Tag) -> Self {
+        Self {
+            obj_type: ObjectType::Tag,
+            data: value.to_data().unwrap(),
+            hash: value.id,
+            crc32: None,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,523 @@
+//! Walks over the commit history without trusting committer dates.
+//!
+//! Committer clocks can be wrong, so a commit may claim to be older than its parent. Ordering by
+//! the raw date then lists a parent before its child, and a walk that stops at "anything older
+//! than X" misses commits. `CommitGraph` computes for every commit:
+//!
+//! - the generation number: 1 for a root, else one more than the highest parent. A commit can only
+//!   reach commits of a lower generation, which bounds reachability walks without any date.
+//! - the corrected commit date: the committer date, raised to one second after the newest parent
+//!   if it is not already later. Ordering by it is close to the date order for well-behaved
+//!   histories and never puts a parent before its child.
+//!
+//! The boundaries of shallow clones are computed on it as well: the commits sent without their
+//! parents, found by depth or by a filter on the commits, and the walks stopping at them.
+//!
+//! The graph is built from the commits, or read from a [`CommitGraphFile`] without parsing a
+//! single commit: the walks then have the parents and dates, [`CommitGraph::commit`] has none.
+//!
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+
+use crate::hash::SHA1;
+use crate::internal::commit_graph::CommitGraphFile;
+use crate::internal::object::commit::Commit;
+
+/// A commit older than its parent by more than this many seconds is reported as skewed,
+/// small differences are common between machines and not worth a warning.
+pub const SKEW_TOLERANCE: usize = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Node {
+    generation: usize,
+    corrected_date: usize,
+}
+
+/// The boundary of a shallow clone: the commits sent without their parents, and the ones sent
+/// with them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Boundary {
+    pub shallow: HashSet<SHA1>,
+    pub deepened: HashSet<SHA1>,
+}
+
+/// What the walks need of a commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Edges {
+    tree: SHA1,
+    parents: Vec<SHA1>,
+    timestamp: usize,
+}
+
+/// The commits of a history, parents missing from the graph are treated as roots.
+pub struct CommitGraph {
+    edges: HashMap<SHA1, Edges>,
+    nodes: HashMap<SHA1, Node>,
+    /// The commits the graph is built from, none when it is read from a file.
+    commits: HashMap<SHA1, Commit>,
+}
+
+impl CommitGraph {
+    pub fn new(commits: impl IntoIterator<Item = Commit>) -> Self {
+        let commits: HashMap<SHA1, Commit> = commits.into_iter().map(|c| (c.id, c)).collect();
+        let edges = commits
+            .values()
+            .map(|c| {
+                let edges = Edges {
+                    tree: c.tree_id,
+                    parents: c.parent_commit_ids.clone(),
+                    timestamp: c.committer.timestamp,
+                };
+                (c.id, edges)
+            })
+            .collect();
+        Self::with_edges(edges, commits)
+    }
+
+    /// The graph of the commits of `file`.
+    pub fn from_file(file: &CommitGraphFile) -> Self {
+        let edges = file
+            .commits()
+            .iter()
+            .map(|c| {
+                let edges = Edges {
+                    tree: c.tree,
+                    parents: c.parents.clone(),
+                    timestamp: c.commit_time as usize,
+                };
+                (c.id, edges)
+            })
+            .collect();
+        Self::with_edges(edges, HashMap::new())
+    }
+
+    fn with_edges(edges: HashMap<SHA1, Edges>, commits: HashMap<SHA1, Commit>) -> Self {
+        let mut graph = CommitGraph {
+            edges,
+            nodes: HashMap::new(),
+            commits,
+        };
+        let ids: Vec<SHA1> = graph.edges.keys().copied().collect();
+        for id in ids {
+            graph.compute(id);
+        }
+        graph
+    }
+
+    /// Fills the nodes of `id` and its ancestors, iteratively as histories can be deep.
+    fn compute(&mut self, id: SHA1) {
+        let mut stack = vec![id];
+        while let Some(&id) = stack.last() {
+            if self.nodes.contains_key(&id) {
+                stack.pop();
+                continue;
+            }
+            let pending: Vec<SHA1> = self
+                .parents(&id)
+                .filter(|p| !self.nodes.contains_key(p))
+                .collect();
+            if !pending.is_empty() {
+                stack.extend(pending);
+                continue;
+            }
+            let parents: Vec<Node> = self.parents(&id).map(|p| self.nodes[&p]).collect();
+            let node = Node {
+                generation: 1 + parents.iter().map(|n| n.generation).max().unwrap_or(0),
+                corrected_date: parents
+                    .iter()
+                    .map(|n| n.corrected_date + 1)
+                    .fold(self.edges[&id].timestamp, usize::max),
+            };
+            self.nodes.insert(id, node);
+            stack.pop();
+        }
+    }
+
+    /// The parents of `id` in the graph.
+    fn parents<'a>(&'a self, id: &SHA1) -> impl Iterator<Item = SHA1> + 'a {
+        self.edges
+            .get(id)
+            .into_iter()
+            .flat_map(|e| e.parents.iter().copied())
+            .filter(|p| self.edges.contains_key(p))
+    }
+
+    pub fn contains(&self, id: &SHA1) -> bool {
+        self.edges.contains_key(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.edges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edges.is_empty()
+    }
+
+    /// The ids of the commits, in no particular order.
+    pub fn ids(&self) -> impl Iterator<Item = &SHA1> {
+        self.edges.keys()
+    }
+
+    /// The commit `id`, `None` when the graph is read from a file.
+    pub fn commit(&self, id: &SHA1) -> Option<&Commit> {
+        self.commits.get(id)
+    }
+
+    /// The root tree of the commit `id`.
+    pub fn tree(&self, id: &SHA1) -> Option<SHA1> {
+        self.edges.get(id).map(|e| e.tree)
+    }
+
+    /// The parents of the commit `id` in the graph, in the order of the commit.
+    pub fn parents_of(&self, id: &SHA1) -> Vec<SHA1> {
+        self.parents(id).collect()
+    }
+
+    /// The committer date of the commit `id`, as recorded.
+    pub fn committer_date(&self, id: &SHA1) -> Option<usize> {
+        self.edges.get(id).map(|e| e.timestamp)
+    }
+
+    pub fn generation(&self, id: &SHA1) -> Option<usize> {
+        self.nodes.get(id).map(|n| n.generation)
+    }
+
+    pub fn corrected_date(&self, id: &SHA1) -> Option<usize> {
+        self.nodes.get(id).map(|n| n.corrected_date)
+    }
+
+    /// Commits dated earlier than one of their parents by more than [`SKEW_TOLERANCE`].
+    pub fn skewed_commits(&self) -> Vec<SHA1> {
+        let mut skewed: Vec<SHA1> = self
+            .edges
+            .iter()
+            .filter(|(id, e)| {
+                self.parents(id)
+                    .any(|p| self.edges[&p].timestamp > e.timestamp + SKEW_TOLERANCE)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        skewed.sort();
+        skewed
+    }
+
+    fn order_key(&self, id: SHA1) -> (usize, usize, SHA1) {
+        let node = self.nodes[&id];
+        (node.corrected_date, node.generation, id)
+    }
+
+    /// Commits reachable from `tips` but not from `hidden`, newest first. A commit always comes
+    /// before its parents whatever its committer date.
+    pub fn walk(&self, tips: &[SHA1], hidden: &[SHA1]) -> Vec<SHA1> {
+        self.walk_shallow(tips, hidden, &HashSet::new(), &HashSet::new())
+    }
+
+    /// Like [`CommitGraph::walk`] for a shallow clone: the parents of the commits of `shallow`,
+    /// the boundary of the client once it has the commits, are not walked, and the history the
+    /// client has stops at `client_shallow`, its boundary before.
+    pub fn walk_shallow(
+        &self,
+        tips: &[SHA1],
+        hidden: &[SHA1],
+        client_shallow: &HashSet<SHA1>,
+        shallow: &HashSet<SHA1>,
+    ) -> Vec<SHA1> {
+        let excluded = self.ancestors_within(hidden, 0, client_shallow);
+        let mut seen: HashSet<SHA1> = HashSet::new();
+        let mut queue = BinaryHeap::new();
+        for &tip in tips.iter().filter(|t| self.contains(t)) {
+            if seen.insert(tip) {
+                queue.push(self.order_key(tip));
+            }
+        }
+        let mut result = Vec::new();
+        while let Some((_, _, id)) = queue.pop() {
+            if excluded.contains(&id) {
+                // the commits behind the shallow ones of the client are found through it
+                if client_shallow.is_empty() {
+                    continue;
+                }
+            } else {
+                result.push(id);
+            }
+            if shallow.contains(&id) {
+                continue;
+            }
+            for parent in self.parents(&id) {
+                if seen.insert(parent) {
+                    queue.push(self.order_key(parent));
+                }
+            }
+        }
+        result
+    }
+
+    /// `from` and its ancestors with a generation of at least `min_generation`.
+    fn ancestors(&self, from: &[SHA1], min_generation: usize) -> HashSet<SHA1> {
+        self.ancestors_within(from, min_generation, &HashSet::new())
+    }
+
+    /// Like [`CommitGraph::ancestors`], without the parents of the commits of `shallow`.
+    fn ancestors_within(
+        &self,
+        from: &[SHA1],
+        min_generation: usize,
+        shallow: &HashSet<SHA1>,
+    ) -> HashSet<SHA1> {
+        let mut result = HashSet::new();
+        let mut stack: Vec<SHA1> = from.iter().copied().filter(|f| self.contains(f)).collect();
+        while let Some(id) = stack.pop() {
+            if self.nodes[&id].generation < min_generation || !result.insert(id) {
+                continue;
+            }
+            if !shallow.contains(&id) {
+                stack.extend(self.parents(&id));
+            }
+        }
+        result
+    }
+
+    /// Whether `target` is `from` or one of its ancestors. Only commits of a generation above
+    /// the one of `target` are visited.
+    pub fn is_ancestor(&self, target: &SHA1, from: &SHA1) -> bool {
+        match self.generation(target) {
+            Some(generation) if self.contains(from) => {
+                self.ancestors(&[*from], generation).contains(target)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether at least one of `targets` is reachable from `from`.
+    pub fn reaches_any(&self, from: &SHA1, targets: &[SHA1]) -> bool {
+        let min_generation = targets.iter().filter_map(|t| self.generation(t)).min();
+        match min_generation {
+            Some(generation) if self.contains(from) => {
+                let reachable = self.ancestors(&[*from], generation);
+                targets.iter().any(|t| reachable.contains(t))
+            }
+            _ => false,
+        }
+    }
+
+    /// The best common ancestor of `a` and `b`. Of all common ancestors the one with the highest
+    /// generation can't be an ancestor of another, ties are broken by the corrected date.
+    pub fn merge_base(&self, a: &SHA1, b: &SHA1) -> Option<SHA1> {
+        let from_a = self.ancestors(&[*a], 0);
+        self.ancestors(&[*b], 0)
+            .into_iter()
+            .filter(|id| from_a.contains(id))
+            .max_by_key(|id| {
+                let node = self.nodes[id];
+                (node.generation, node.corrected_date, *id)
+            })
+    }
+
+    /// Number of commits only reachable from `a` and only reachable from `b`.
+    pub fn ahead_behind(&self, a: &SHA1, b: &SHA1) -> (usize, usize) {
+        (self.walk(&[*a], &[*b]).len(), self.walk(&[*b], &[*a]).len())
+    }
+
+    /// The boundary `depth` commits deep from `heads`, a head being at depth 1. A commit reached
+    /// along several paths counts at its shortest one, as git does.
+    pub fn boundary_by_depth(&self, heads: &[SHA1], depth: usize) -> Boundary {
+        let mut boundary = Boundary::default();
+        let mut visited = HashSet::new();
+        let mut queue: VecDeque<(SHA1, usize)> = heads.iter().map(|id| (*id, 1)).collect();
+        while let Some((id, level)) = queue.pop_front() {
+            if !visited.insert(id) {
+                continue;
+            }
+            if !self.contains(&id) {
+                continue;
+            }
+            if level >= depth {
+                boundary.shallow.insert(id);
+                continue;
+            }
+            boundary.deepened.insert(id);
+            queue.extend(self.parents(&id).map(|p| (p, level + 1)));
+        }
+        boundary
+    }
+
+    /// The boundary of the commits reachable from `heads` and accepted by `include`: an
+    /// included commit is shallow when one of its parents is not included. `None` when no
+    /// commit is, as for a graph read from a file, which has no commit to give `include`.
+    pub fn boundary_by_rev_list(
+        &self,
+        heads: &[SHA1],
+        include: impl Fn(&Commit) -> bool,
+    ) -> Option<Boundary> {
+        let included: HashSet<SHA1> = self
+            .walk(heads, &[])
+            .into_iter()
+            .filter(|id| self.commit(id).is_some_and(&include))
+            .collect();
+        // the walk goes through excluded commits, only keep what the heads reach through included ones
+        let mut reached = HashSet::new();
+        let mut stack: Vec<SHA1> = heads
+            .iter()
+            .filter(|id| included.contains(id))
+            .copied()
+            .collect();
+        while let Some(id) = stack.pop() {
+            if reached.insert(id) {
+                let parents = &self.edges[&id].parents;
+                stack.extend(parents.iter().filter(|p| included.contains(p)));
+            }
+        }
+        if reached.is_empty() {
+            return None;
+        }
+
+        let mut boundary = Boundary::default();
+        for id in reached {
+            let parents = &self.edges[&id].parents;
+            if parents.iter().all(|p| included.contains(p)) {
+                boundary.deepened.insert(id);
+            } else {
+                boundary.shallow.insert(id);
+            }
+        }
+        Some(boundary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::hash::SHA1;
+    use crate::test_support::{commit_at, id};
+
+    use super::CommitGraph;
+
+    /// 1 - 2 - 3 - 5
+    ///      \     /
+    ///       4 ---
+    /// with 3 committed on a machine whose clock was two days behind
+    fn skewed_history() -> CommitGraph {
+        CommitGraph::new(vec![
+            commit_at(1, &[], 1_000_000),
+            commit_at(2, &[1], 1_000_100),
+            commit_at(3, &[2], 1_000_200 - 2 * 86400),
+            commit_at(4, &[2], 1_000_300),
+            commit_at(5, &[3, 4], 1_000_400),
+        ])
+    }
+
+    #[test]
+    fn test_generation_and_corrected_date() {
+        let graph = skewed_history();
+        assert_eq!(graph.generation(&id(1)), Some(1));
+        assert_eq!(graph.generation(&id(3)), Some(3));
+        assert_eq!(graph.generation(&id(5)), Some(4));
+        assert_eq!(graph.corrected_date(&id(3)), Some(1_000_101));
+        assert_eq!(graph.skewed_commits(), vec![id(3)]);
+    }
+
+    #[test]
+    fn test_walk_keeps_children_before_parents() {
+        let graph = skewed_history();
+        assert_eq!(
+            graph.walk(&[id(5)], &[]),
+            vec![id(5), id(4), id(3), id(2), id(1)]
+        );
+        assert_eq!(graph.walk(&[id(5)], &[id(3)]), vec![id(5), id(4)]);
+    }
+
+    #[test]
+    fn test_reachability_ignores_dates() {
+        let graph = skewed_history();
+        // 3 looks older than 1 by its date, but is not its ancestor
+        assert!(graph.is_ancestor(&id(1), &id(3)));
+        assert!(!graph.is_ancestor(&id(3), &id(4)));
+        assert!(graph.reaches_any(&id(5), &[id(3)]));
+        assert!(!graph.reaches_any(&id(4), &[id(3)]));
+        assert_eq!(graph.ahead_behind(&id(3), &id(4)), (1, 1));
+        assert_eq!(graph.ahead_behind(&id(5), &id(1)), (4, 0));
+    }
+
+    #[test]
+    fn test_merge_base() {
+        let graph = skewed_history();
+        // 3 is dated two days before 2, the generation still makes 2 the best base of 3 and 4
+        assert_eq!(graph.merge_base(&id(3), &id(4)), Some(id(2)));
+        assert_eq!(graph.merge_base(&id(5), &id(4)), Some(id(4)));
+        assert_eq!(graph.merge_base(&id(1), &id(6)), None);
+    }
+
+    /// 1 - 2 - 3 - 5 - 6
+    ///      \     /
+    ///       4 ---
+    fn merged_history() -> CommitGraph {
+        CommitGraph::new(vec![
+            commit_at(1, &[], 1001),
+            commit_at(2, &[1], 1002),
+            commit_at(3, &[2], 1003),
+            commit_at(4, &[2], 1004),
+            commit_at(5, &[3, 4], 1005),
+            commit_at(6, &[5], 1006),
+        ])
+    }
+
+    fn ids(list: &[u8]) -> HashSet<SHA1> {
+        list.iter().map(|n| id(*n)).collect()
+    }
+
+    #[test]
+    fn test_boundary_by_depth() {
+        let graph = merged_history();
+        let boundary = graph.boundary_by_depth(&[id(6)], 3);
+        assert_eq!(boundary.shallow, ids(&[3, 4]));
+        assert_eq!(boundary.deepened, ids(&[5, 6]));
+
+        // the whole history is shorter than the depth
+        let boundary = graph.boundary_by_depth(&[id(6)], 10);
+        assert!(boundary.shallow.is_empty());
+        assert_eq!(boundary.deepened, ids(&[1, 2, 3, 4, 5, 6]));
+    }
+
+    #[test]
+    fn test_boundary_by_rev_list() {
+        let graph = merged_history();
+        // committed at 1003 or later
+        let boundary = graph
+            .boundary_by_rev_list(&[id(6)], |c| c.committer.timestamp >= 1003)
+            .unwrap();
+        assert_eq!(boundary.shallow, ids(&[3, 4]));
+        assert_eq!(boundary.deepened, ids(&[5, 6]));
+
+        // not reachable from 3
+        let excluded = graph.walk(&[id(3)], &[]);
+        let boundary = graph
+            .boundary_by_rev_list(&[id(6)], |c| !excluded.contains(&c.id))
+            .unwrap();
+        assert_eq!(boundary.shallow, ids(&[4, 5]));
+        assert_eq!(boundary.deepened, ids(&[6]));
+
+        assert!(graph
+            .boundary_by_rev_list(&[id(6)], |c| c.committer.timestamp > 2000)
+            .is_none());
+    }
+
+    #[test]
+    fn test_walk_shallow() {
+        let graph = merged_history();
+        // a clone of depth 2
+        assert_eq!(
+            graph.walk_shallow(&[id(6)], &[], &ids(&[]), &ids(&[5])),
+            vec![id(6), id(5)]
+        );
+        // deepened by one more commit, the client has 6 and 5 without the parents of 5
+        assert_eq!(
+            graph.walk_shallow(&[id(6)], &[id(6)], &ids(&[5]), &ids(&[3, 4])),
+            vec![id(4), id(3)]
+        );
+        // without its shallow commits, the history of the client hides everything
+        assert!(graph
+            .walk_shallow(&[id(6)], &[id(6)], &ids(&[]), &ids(&[3, 4]))
+            .is_empty());
+    }
+}
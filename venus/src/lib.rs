@@ -4,3 +4,5 @@ pub mod internal;
 pub mod model;
 pub mod mr;
 pub mod repo;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
@@ -34,6 +34,7 @@ impl From<Repo> for git_repo::Model {
             id: value.repo_id,
             repo_path: value.repo_path,
             repo_name: value.repo_name,
+            is_public: false,
             created_at: chrono::Utc::now().naive_utc(),
             updated_at: chrono::Utc::now().naive_utc(),
 
@@ -0,0 +1,41 @@
+//!
+//! Commit fixtures for the tests of the history walks: `id(n)` is a fake object id and
+//! `commit(n, parents)` a commit on top of the commits `parents`, so a history is written as a
+//! list of small numbers.
+//!
+//! Enabled for the unit tests of this crate, other crates use the `test-support` feature.
+//!
+use std::str::FromStr;
+
+use crate::hash::SHA1;
+use crate::internal::object::commit::Commit;
+use crate::internal::object::signature::{Signature, SignatureType};
+
+/// The id `n`, its first byte is `n` too so that the ids spread over the fanout of an index.
+pub fn id(n: u8) -> SHA1 {
+    SHA1::from_str(&format!("{:02x}{:038x}", n, n)).unwrap()
+}
+
+/// The commit `n` of the parents `parents`, committed at `1000 + n`.
+pub fn commit(n: u8, parents: &[u8]) -> Commit {
+    commit_at(n, parents, 1000 + n as usize)
+}
+
+/// The commit `n` of the parents `parents`, committed at `timestamp`.
+pub fn commit_at(n: u8, parents: &[u8], timestamp: usize) -> Commit {
+    let signature = Signature {
+        signature_type: SignatureType::Committer,
+        name: String::from("alice"),
+        email: String::from("alice@example.com"),
+        timestamp,
+        timezone: String::from("+0000"),
+    };
+    Commit {
+        id: id(n),
+        tree_id: id(n),
+        parent_commit_ids: parents.iter().map(|p| id(*p)).collect(),
+        author: signature.clone(),
+        committer: signature,
+        message: format!("\ncommit {}\n", n),
+    }
+}